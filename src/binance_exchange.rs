@@ -1,24 +1,1270 @@
 use {
-    crate::{exchange::*, token::MaybeToken, token::Token},
+    crate::{
+        exchange::*,
+        rate_limit::RateLimiter,
+        request_metrics,
+        settings::{self, Setting},
+        stream_session::{StreamSession, StreamSessionStore},
+        token::MaybeToken,
+        token::Token,
+        two_factor::TwoFactorCodeProvider,
+    },
     async_trait::async_trait,
-    chrono::{Local, TimeZone},
+    chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc},
+    hmac::{Hmac, Mac},
+    serde::{de::DeserializeOwned, Deserialize},
+    sha2::Sha256,
     solana_sdk::pubkey::Pubkey,
     std::{
-        collections::HashMap,
+        collections::{BTreeMap, HashMap},
         str::FromStr,
-        time::{SystemTime, UNIX_EPOCH},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime, UNIX_EPOCH},
     },
+    tokio::sync::OnceCell,
 };
 
 pub struct BinanceExchangeClient {
     account: binance::account::Account,
+    general: binance::general::General,
     market: binance::market::Market,
     wallet: binance::wallet::Wallet,
-    preferred_solusd_pair: &'static str,
+    exchange: Exchange,
+    rest_api_endpoint: String,
+    api_key: String,
+    secret: String,
+    bnb_fees_in_usd: bool,
+    wallet_warning_shown: AtomicBool,
+    pair_infos: OnceCell<Vec<PairInfo>>,
+    rate_limiter: Arc<RateLimiter>,
+    stream_session: Arc<StreamSessionStore>,
+    http_client: reqwest::Client,
+    request_timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct FundingAsset {
+    asset: String,
+    free: String,
+    locked: String,
+    freeze: String,
+    withdrawing: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListenKey {
+    listen_key: String,
+}
+
+// A listen key lapses this long after it was created or last kept alive
+const LISTEN_KEY_VALIDITY_MINUTES: i64 = 60;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SubAccountList {
+    sub_accounts: Vec<SubAccount>,
+}
+
+#[derive(Deserialize)]
+struct SubAccount {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct SubAccountAssets {
+    balances: Vec<SubAccountAsset>,
+}
+
+#[derive(Deserialize)]
+struct SubAccountAsset {
+    asset: String,
+    free: f64,
+    locked: f64,
+}
+
+#[derive(Deserialize)]
+struct EarnPositions {
+    rows: Vec<EarnPosition>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EarnPosition {
+    asset: String,
+    // Flexible positions report `totalAmount`, locked positions `amount`
+    total_amount: Option<String>,
+    amount: Option<String>,
+    product_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FlexibleProducts {
+    rows: Vec<FlexibleProduct>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FlexibleProduct {
+    asset: String,
+    // A fraction, 0.05 for 5%
+    latest_annual_percentage_rate: String,
+}
+
+// Simple Earn flexible products stand in for lending: the flexible position is offered, and the
+// locked position locked.  Binance publishes no previous rate, so the latest stands in for it
+fn flexible_lending_info(
+    products: Vec<FlexibleProduct>,
+    spot: &HashMap<String, ExchangeBalance>,
+    flexible: &HashMap<String, ExchangeBalance>,
+    locked: &HashMap<String, ExchangeBalance>,
+) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>> {
+    let total = |balances: &HashMap<String, ExchangeBalance>, asset: &str| {
+        balances
+            .get(asset)
+            .map(|balance| balance.total)
+            .unwrap_or_default()
+    };
+
+    let mut all_lending_info = HashMap::new();
+    for product in products {
+        let lendable = spot
+            .get(&product.asset)
+            .map(|balance| balance.available)
+            .unwrap_or_default();
+        let offered = total(flexible, &product.asset);
+        if lendable <= 0. && offered <= 0. {
+            continue;
+        }
+        let rate = product.latest_annual_percentage_rate.parse::<f64>()? * 100.;
+        let locked = total(locked, &product.asset);
+        all_lending_info.insert(
+            product.asset,
+            LendingInfo {
+                lendable,
+                offered,
+                locked,
+                estimate_rate: rate,
+                previous_rate: rate,
+            },
+        );
+    }
+    Ok(all_lending_info)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceTrade {
+    price: String,
+    commission: String,
+    commission_asset: String,
+    time: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceOrder {
+    symbol: String,
+    order_id: u64,
+    client_order_id: String,
+    price: String,
+    orig_qty: String,
+    executed_qty: String,
+    cummulative_quote_qty: String,
+    status: String,
+    time_in_force: String,
+    side: String,
+    time: u64,
+    update_time: u64,
+}
+
+const EXPIRED_BY_EXCHANGE: &str = "expired by the exchange";
+
+// Binance closes an order it cancels itself as EXPIRED, or EXPIRED_IN_MATCH when self-trade
+// prevention cancels it.  The unfilled remainder of an IOC order also expires, by design
+fn order_cancellation(status: &str, time_in_force: &str) -> Option<OrderCancellation> {
+    match status {
+        "CANCELED" => Some(OrderCancellation::User),
+        "EXPIRED" if time_in_force != "IOC" => Some(OrderCancellation::Exchange {
+            reason: EXPIRED_BY_EXCHANGE.into(),
+        }),
+        "EXPIRED_IN_MATCH" => Some(OrderCancellation::Exchange {
+            reason: "self-trade prevention".into(),
+        }),
+        _ => None,
+    }
+}
+
+// `/api/v3/allOrders` spans at most a day per request
+const ORDER_HISTORY_WINDOW_HOURS: i64 = 24;
+
+// Longest span of deposit, conversion and Simple Earn reward history per request
+const DEPOSIT_HISTORY_WINDOW_DAYS: i64 = 90;
+const CONVERT_HISTORY_WINDOW_DAYS: i64 = 30;
+const EARN_REWARDS_WINDOW_DAYS: i64 = 90;
+
+// Simple Earn rewards are only served for the last six months
+const EARN_REWARDS_LOOKBACK_DAYS: i64 = 180;
+
+// Largest page of deposit history and Simple Earn rewards returned per request
+const DEPOSIT_HISTORY_LIMIT: usize = 1000;
+const EARN_REWARDS_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceDepositRecord {
+    coin: String,
+    amount: String,
+    tx_id: String,
+    insert_time: i64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceWithdrawalRecord {
+    id: String,
+    coin: String,
+    // What left for the address, with `transaction_fee` charged on top
+    amount: String,
+    transaction_fee: String,
+    status: u8,
+    apply_time: String,
+    withdraw_order_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConvertTradeFlow {
+    list: Vec<ConvertTrade>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConvertTrade {
+    order_id: u64,
+    order_status: String,
+    from_asset: String,
+    from_amount: String,
+    to_asset: String,
+    to_amount: String,
+    create_time: i64,
+}
+
+#[derive(Deserialize)]
+struct EarnRewards {
+    rows: Vec<EarnReward>,
+}
+
+#[derive(Deserialize)]
+struct EarnReward {
+    asset: String,
+    // Flexible rewards report `rewards`, locked rewards `amount`
+    rewards: Option<String>,
+    amount: Option<String>,
+    #[serde(rename = "type")]
+    kind: String,
+    time: i64,
+}
+
+// Consecutive windows of at most `days` covering `start` to `end`
+fn time_windows(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    days: i64,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut windows = vec![];
+    let mut window_start = start;
+    while window_start < end {
+        let window_end = (window_start + chrono::Duration::days(days)).min(end);
+        windows.push((window_start, window_end));
+        window_start = window_end;
+    }
+    windows
+}
+
+// Binance spot VIP tiers by 30-day volume in USD, with maker and taker rates.  Higher tiers also
+// require a BNB balance, which is not considered
+const VOLUME_TIERS: &[(f64, f64, f64)] = &[
+    (0., 0.001, 0.001),
+    (1_000_000., 0.0009, 0.001),
+    (5_000_000., 0.0008, 0.001),
+    (20_000_000., 0.00042, 0.0006),
+    (100_000_000., 0.00042, 0.00054),
+    (150_000_000., 0.00036, 0.00048),
+    (400_000_000., 0.0003, 0.00042),
+    (800_000_000., 0.00024, 0.00036),
+    (2_000_000_000., 0.00018, 0.0003),
+    (4_000_000_000., 0.00012, 0.00024),
+];
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BinanceAccountCommission {
+    // Basis points
+    maker_commission: u32,
+    taker_commission: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ApiRestrictions {
+    ip_restrict: bool,
+    enable_withdrawals: bool,
+    enable_spot_and_margin_trading: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BnbBurnStatus {
+    #[serde(rename = "spotBNBBurn", default)]
+    spot_bnb_burn: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DelistSchedule {
+    delist_time: i64,
+    symbols: Vec<String>,
+}
+
+fn symbol_status(status: &binance::rest_model::SymbolStatus) -> SystemStatus {
+    match status {
+        binance::rest_model::SymbolStatus::Trading => SystemStatus::Online,
+        // Delisted, or suspended until further notice
+        binance::rest_model::SymbolStatus::Break => SystemStatus::Maintenance,
+        // Halted, or outside of trading hours, when open orders can still be cancelled
+        _ => SystemStatus::CancelOnly,
+    }
+}
+
+// Error body returned by the REST API, such as `{"code":-2015,"msg":"Invalid API-key"}`
+#[derive(Deserialize)]
+struct BinanceErrorBody {
+    code: i64,
+    msg: String,
+}
+
+fn api_error(exchange: Exchange, endpoint: &str, http_status: u16, body: &str) -> ExchangeError {
+    let (code, message) = match serde_json::from_str::<BinanceErrorBody>(body) {
+        Ok(error_body) => (error_body.code.to_string(), error_body.msg),
+        Err(_) => ("unknown".into(), body.to_string()),
+    };
+    ExchangeError::Api {
+        exchange,
+        endpoint: endpoint.into(),
+        http_status: Some(http_status),
+        code,
+        message,
+    }
+}
+
+// Request weight of each endpoint used, per the API documentation.  Unlisted endpoints weigh 1
+fn request_weight(endpoint: &str) -> u32 {
+    match endpoint {
+        "/sapi/v1/spot/delist-schedule" => 100,
+        "/sapi/v1/simple-earn/flexible/history/rewardsRecord"
+        | "/sapi/v1/simple-earn/locked/history/rewardsRecord"
+        | "/sapi/v1/simple-earn/flexible/list" => 150,
+        // Open orders on every symbol; a single symbol's weigh 6
+        "/api/v3/openOrders" => 80,
+        "/api/v3/account" | "/api/v3/allOrders" | "/api/v3/exchangeInfo" | "/api/v3/myTrades" => 20,
+        "/sapi/v1/capital/config/getall" | "/sapi/v1/capital/deposit/address" => 10,
+        // Querying an order; placing or cancelling one weighs 1
+        "/api/v3/order" => 4,
+        "/api/v3/klines"
+        | "/api/v3/avgPrice"
+        | "/api/v3/ticker/24hr"
+        | "/api/v3/userDataStream" => 2,
+        // Every market's book ticker; a single market's weighs 2
+        "/api/v3/ticker/bookTicker" => 4,
+        _ => 1,
+    }
+}
+
+// Order book requests weigh more the deeper they go
+fn depth_weight(limit: usize) -> u32 {
+    match limit {
+        0..=100 => 5,
+        101..=500 => 25,
+        501..=1000 => 50,
+        _ => 250,
+    }
+}
+
+// Binance's network identifier for Solana and SPL tokens
+const SOLANA_NETWORK: &str = "SOL";
+
+struct SolanaNetworkInfo {
+    deposit_enable: bool,
+    deposit_desc: String,
+    // Confirmations before a deposit is credited
+    min_confirm: u32,
+    withdraw_enable: bool,
+    withdraw_desc: String,
+    withdraw_min: f64,
+    withdraw_fee: f64,
+}
+
+// Withdrawal amounts are accepted with up to eight decimal places
+const WITHDRAW_DECIMALS: u8 = 8;
+
+// Paying fees in BNB discounts them by 25%
+const BNB_FEE_DISCOUNT: f64 = 0.25;
+
+// Largest page of withdrawal history returned per request
+const WITHDRAW_HISTORY_LIMIT: u64 = 1000;
+
+// Largest page of klines returned per request
+const KLINES_LIMIT: usize = 1000;
+
+// Order book depths accepted by the depth endpoint
+const DEPTH_LIMITS: &[usize] = &[5, 10, 20, 50, 100, 500, 1000, 5000];
+
+// Supported kline intervals, in minutes
+const KLINE_INTERVALS: &[(u32, &str)] = &[
+    (1, "1m"),
+    (3, "3m"),
+    (5, "5m"),
+    (15, "15m"),
+    (30, "30m"),
+    (60, "1h"),
+    (120, "2h"),
+    (240, "4h"),
+    (360, "6h"),
+    (480, "8h"),
+    (720, "12h"),
+    (1440, "1d"),
+    (4320, "3d"),
+    (10080, "1w"),
+];
+
+impl BinanceExchangeClient {
+    // Markets with an announced delisting, by exchange symbol.  Binance US publishes no schedule
+    async fn delist_times(
+        &self,
+    ) -> Result<HashMap<String, DateTime<Utc>>, Box<dyn std::error::Error>> {
+        if self.exchange == Exchange::BinanceUs {
+            return Ok(HashMap::default());
+        }
+
+        self.throttle("/sapi/v1/spot/delist-schedule").await;
+        Ok(self
+            .signed_get::<Vec<DelistSchedule>>("/sapi/v1/spot/delist-schedule", &[])
+            .await?
+            .into_iter()
+            .flat_map(|delist_schedule| {
+                let delist_time = Utc.timestamp_millis(delist_schedule.delist_time);
+                delist_schedule
+                    .symbols
+                    .into_iter()
+                    .map(move |symbol| (symbol, delist_time))
+            })
+            .collect())
+    }
+
+    // Waits for rate budget to call `endpoint`
+    async fn throttle(&self, endpoint: &str) {
+        self.rate_limiter.acquire(request_weight(endpoint)).await;
+    }
+
+    // Requests made through `binance-rs-async`, which has no timeout of its own
+    async fn sdk_request<T, E: Into<Box<dyn std::error::Error>>>(
+        &self,
+        endpoint: &str,
+        request: impl std::future::Future<Output = Result<T, E>>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        self.sdk_request_with_timeout(endpoint, self.request_timeout, request)
+            .await
+    }
+
+    async fn sdk_request_with_timeout<T, E: Into<Box<dyn std::error::Error>>>(
+        &self,
+        endpoint: &str,
+        timeout: Duration,
+        request: impl std::future::Future<Output = Result<T, E>>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        request_metrics::instrument(self.exchange, endpoint, async {
+            with_timeout(self.exchange, endpoint, timeout, request)
+                .await?
+                .map_err(Into::into)
+        })
+        .await
+    }
+
+    // Public endpoints are idempotent, so a request that times out is sent again
+    async fn public_get<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        url: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        request_metrics::instrument(self.exchange, endpoint, self.send_public_get(endpoint, url))
+            .await
+    }
+
+    async fn send_public_get<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        url: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let body = retry_idempotent(|| async {
+            let response = self
+                .http_client
+                .get(url)
+                .send()
+                .await
+                .map_err(|err| request_error(self.exchange, endpoint, err))?;
+            response
+                .text()
+                .await
+                .map_err(|err| request_error(self.exchange, endpoint, err))
+        })
+        .await?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    // Signed endpoints not covered by `binance-rs-async`
+    async fn signed_get<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        params: &[(&str, String)],
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        retry_idempotent(|| self.signed_request(reqwest::Method::GET, endpoint, params)).await
+    }
+
+    async fn signed_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        params: &[(&str, String)],
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        self.throttle(endpoint).await;
+        request_metrics::instrument(
+            self.exchange,
+            endpoint,
+            self.send_signed_request(method, endpoint, params),
+        )
+        .await
+    }
+
+    async fn send_signed_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        params: &[(&str, String)],
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let mut query = params.to_vec();
+        query.push((
+            "timestamp",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)?
+                .as_millis()
+                .to_string(),
+        ));
+        let query = serde_urlencoded::to_string(&query)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .map_err(|err| format!("Invalid Binance secret: {err}"))?;
+        mac.update(query.as_bytes());
+        let signature = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        let response = self
+            .http_client
+            .request(
+                method,
+                format!(
+                    "{}{endpoint}?{query}&signature={signature}",
+                    self.rest_api_endpoint
+                ),
+            )
+            .header("X-MBX-APIKEY", &self.api_key)
+            .send()
+            .await
+            .map_err(|err| request_error(self.exchange, endpoint, err))?;
+        let http_status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|err| request_error(self.exchange, endpoint, err))?;
+        if !http_status.is_success() {
+            return Err(api_error(self.exchange, endpoint, http_status.as_u16(), &body).into());
+        }
+        Ok(serde_json::from_str::<T>(&body)?)
+    }
+
+    // User data stream endpoints take the API key alone, without a signature
+    async fn api_key_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        params: &[(&str, String)],
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        self.throttle(endpoint).await;
+        request_metrics::instrument(
+            self.exchange,
+            endpoint,
+            self.send_api_key_request(method, endpoint, params),
+        )
+        .await
+    }
+
+    async fn send_api_key_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        endpoint: &str,
+        params: &[(&str, String)],
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let query = serde_urlencoded::to_string(params)?;
+        let response = self
+            .http_client
+            .request(
+                method,
+                format!("{}{endpoint}?{query}", self.rest_api_endpoint),
+            )
+            .header("X-MBX-APIKEY", &self.api_key)
+            .send()
+            .await
+            .map_err(|err| request_error(self.exchange, endpoint, err))?;
+        let http_status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|err| request_error(self.exchange, endpoint, err))?;
+        if !http_status.is_success() {
+            return Err(api_error(self.exchange, endpoint, http_status.as_u16(), &body).into());
+        }
+        Ok(serde_json::from_str::<T>(&body)?)
+    }
+
+    async fn deposit_history(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        let mut entries = vec![];
+        for (window_start, window_end) in time_windows(start, end, DEPOSIT_HISTORY_WINDOW_DAYS) {
+            let mut offset = 0;
+            loop {
+                let records = self
+                    .signed_get::<Vec<BinanceDepositRecord>>(
+                        "/sapi/v1/capital/deposit/hisrec",
+                        &[
+                            // Credited deposits only
+                            ("status", "1".into()),
+                            ("startTime", window_start.timestamp_millis().to_string()),
+                            ("endTime", window_end.timestamp_millis().to_string()),
+                            ("offset", offset.to_string()),
+                            ("limit", DEPOSIT_HISTORY_LIMIT.to_string()),
+                        ],
+                    )
+                    .await?;
+                let page_len = records.len();
+                for record in records {
+                    entries.push(AccountHistoryEntry {
+                        time: Utc.timestamp_millis(record.insert_time),
+                        coin: record.coin,
+                        amount: record.amount.parse::<f64>()?,
+                        fee: 0.,
+                        reference: record.tx_id,
+                    });
+                }
+                if page_len < DEPOSIT_HISTORY_LIMIT {
+                    break;
+                }
+                offset += page_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn withdrawal_history(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        let mut entries = vec![];
+        // Withdrawal history queries may span at most 90 days
+        for (window_start, window_end) in time_windows(start, end, 90) {
+            let mut offset = 0;
+            loop {
+                let records = self
+                    .signed_get::<Vec<BinanceWithdrawalRecord>>(
+                        "/sapi/v1/capital/withdraw/history",
+                        &[
+                            ("startTime", window_start.timestamp_millis().to_string()),
+                            ("endTime", window_end.timestamp_millis().to_string()),
+                            ("offset", offset.to_string()),
+                            ("limit", WITHDRAW_HISTORY_LIMIT.to_string()),
+                        ],
+                    )
+                    .await?;
+                let page_len = records.len();
+                for record in records {
+                    // Cancelled, rejected and failed withdrawals leave the balance as it was
+                    if [1, 3, 5].contains(&record.status) {
+                        continue;
+                    }
+                    // `applyTime` is reported in UTC as "YYYY-MM-DD HH:MM:SS"
+                    let time =
+                        NaiveDateTime::parse_from_str(&record.apply_time, "%Y-%m-%d %H:%M:%S")
+                            .map(|time| DateTime::<Utc>::from_utc(time, Utc))?;
+                    entries.push(AccountHistoryEntry {
+                        time,
+                        coin: record.coin,
+                        amount: -record.amount.parse::<f64>()?,
+                        fee: record.transaction_fee.parse::<f64>()?,
+                        reference: record
+                            .withdraw_order_id
+                            .filter(|withdraw_order_id| !withdraw_order_id.is_empty())
+                            .unwrap_or(record.id),
+                    });
+                }
+                if page_len < WITHDRAW_HISTORY_LIMIT as usize {
+                    break;
+                }
+                offset += page_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    // Fills of orders in every market of SOL or a known token
+    async fn trade_history(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        let mut entries = vec![];
+        for pair_info in self.pair_infos().await? {
+            let pair = pair_info.pair;
+            if pair.base != "SOL" && Token::from_str(&pair.base).is_err() {
+                continue;
+            }
+            for order in self.order_history(&pair, start, end).await? {
+                let status = order.status;
+                if status.filled_amount < f64::EPSILON {
+                    continue;
+                }
+                let sign = match status.side {
+                    OrderSide::Buy => 1.,
+                    OrderSide::Sell => -1.,
+                };
+                let quote_amount = status
+                    .cumulative_quote_amount
+                    .unwrap_or(status.filled_amount * status.fill_price());
+                let entry = |coin: &str, amount, fee| AccountHistoryEntry {
+                    time: order.creation_time,
+                    coin: coin.to_string(),
+                    amount,
+                    fee,
+                    reference: order.order_id.clone(),
+                };
+                entries.push(entry(&pair.base, sign * status.filled_amount, 0.));
+                entries.push(entry(&pair.quote, -sign * quote_amount, 0.));
+                if let Some((fee, coin)) = &status.fee {
+                    entries.push(entry(coin, 0., *fee));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn conversion_history(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        let mut entries = vec![];
+        for (window_start, window_end) in time_windows(start, end, CONVERT_HISTORY_WINDOW_DAYS) {
+            let trade_flow = self
+                .signed_get::<ConvertTradeFlow>(
+                    "/sapi/v1/convert/tradeFlow",
+                    &[
+                        ("startTime", window_start.timestamp_millis().to_string()),
+                        ("endTime", window_end.timestamp_millis().to_string()),
+                        ("limit", "1000".into()),
+                    ],
+                )
+                .await?;
+            for trade in trade_flow.list {
+                if trade.order_status != "SUCCESS" {
+                    continue;
+                }
+                let time = Utc.timestamp_millis(trade.create_time);
+                entries.push(AccountHistoryEntry {
+                    time,
+                    coin: trade.from_asset,
+                    amount: -trade.from_amount.parse::<f64>()?,
+                    fee: 0.,
+                    reference: trade.order_id.to_string(),
+                });
+                entries.push(AccountHistoryEntry {
+                    time,
+                    coin: trade.to_asset,
+                    amount: trade.to_amount.parse::<f64>()?,
+                    fee: 0.,
+                    reference: trade.order_id.to_string(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    // Simple Earn rewards, flexible and locked
+    async fn earn_reward_history(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        let queries = [
+            (
+                "/sapi/v1/simple-earn/flexible/history/rewardsRecord",
+                "BONUS",
+            ),
+            (
+                "/sapi/v1/simple-earn/flexible/history/rewardsRecord",
+                "REALTIME",
+            ),
+            (
+                "/sapi/v1/simple-earn/flexible/history/rewardsRecord",
+                "REWARDS",
+            ),
+            ("/sapi/v1/simple-earn/locked/history/rewardsRecord", ""),
+        ];
+
+        let mut entries = vec![];
+        for (window_start, window_end) in time_windows(start, end, EARN_REWARDS_WINDOW_DAYS) {
+            for (endpoint, reward_type) in queries {
+                let mut current = 1;
+                loop {
+                    let mut params = vec![
+                        ("startTime", window_start.timestamp_millis().to_string()),
+                        ("endTime", window_end.timestamp_millis().to_string()),
+                        ("current", current.to_string()),
+                        ("size", EARN_REWARDS_LIMIT.to_string()),
+                    ];
+                    if !reward_type.is_empty() {
+                        params.push(("type", reward_type.into()));
+                    }
+                    let rewards = self.signed_get::<EarnRewards>(endpoint, &params).await?;
+                    let page_len = rewards.rows.len();
+                    for reward in rewards.rows {
+                        let amount = reward
+                            .rewards
+                            .or(reward.amount)
+                            .ok_or("Simple Earn reward without an amount")?;
+                        entries.push(AccountHistoryEntry {
+                            time: Utc.timestamp_millis(reward.time),
+                            reference: format!("{}:{}:{}", reward.kind, reward.asset, reward.time),
+                            coin: reward.asset,
+                            amount: amount.parse::<f64>()?,
+                            fee: 0.,
+                        });
+                    }
+                    if page_len < EARN_REWARDS_LIMIT {
+                        break;
+                    }
+                    current += 1;
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    // Every order in `symbol` created between `start` and `end`
+    async fn all_orders(
+        &self,
+        symbol: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<BinanceOrder>, Box<dyn std::error::Error>> {
+        let mut orders = vec![];
+        let mut window_start = start;
+        while window_start < end {
+            let window_end =
+                (window_start + chrono::Duration::hours(ORDER_HISTORY_WINDOW_HOURS)).min(end);
+            let mut window_orders = self
+                .signed_get::<Vec<BinanceOrder>>(
+                    "/api/v3/allOrders",
+                    &[
+                        ("symbol", symbol.to_string()),
+                        ("startTime", window_start.timestamp_millis().to_string()),
+                        ("endTime", window_end.timestamp_millis().to_string()),
+                        ("limit", "1000".into()),
+                    ],
+                )
+                .await?;
+            // A full page may have been truncated, so resume from the last order returned
+            window_start = match window_orders.last() {
+                Some(last_order) if window_orders.len() == 1000 => {
+                    Utc.timestamp_millis(last_order.time as i64 + 1)
+                }
+                _ => window_end,
+            };
+            orders.append(&mut window_orders);
+        }
+        Ok(orders)
+    }
+
+    // The coin config for the token's Solana network, which must exist to deposit or withdraw it
+    async fn solana_network_info(
+        &self,
+        token: MaybeToken,
+    ) -> Result<SolanaNetworkInfo, Box<dyn std::error::Error>> {
+        self.throttle("/sapi/v1/capital/config/getall").await;
+        let coin_info = self
+            .sdk_request(
+                "/sapi/v1/capital/config/getall",
+                self.wallet.all_coin_info(),
+            )
+            .await?
+            .into_iter()
+            .find(|ci| ci.coin == token.name())
+            .ok_or_else(|| format!("{token} not found in Binance coin list"))?;
+
+        coin_info
+            .network_list
+            .into_iter()
+            .find(|network_info| network_info.network == SOLANA_NETWORK)
+            .map(|network_info| SolanaNetworkInfo {
+                deposit_enable: network_info.deposit_enable,
+                deposit_desc: network_info.deposit_desc.to_string(),
+                min_confirm: network_info.min_confirm,
+                withdraw_enable: network_info.withdraw_enable,
+                withdraw_desc: network_info.withdraw_desc.to_string(),
+                withdraw_min: network_info.withdraw_min,
+                withdraw_fee: network_info.withdraw_fee,
+            })
+            .ok_or_else(|| format!("Binance has no Solana network route for {token}").into())
+    }
+
+    async fn token_supports(
+        &self,
+    ) -> Result<Vec<(MaybeToken, TokenSupport)>, Box<dyn std::error::Error>> {
+        let mut token_supports = vec![];
+        self.throttle("/sapi/v1/capital/config/getall").await;
+        for coin_info in self
+            .sdk_request(
+                "/sapi/v1/capital/config/getall",
+                self.wallet.all_coin_info(),
+            )
+            .await?
+        {
+            let token = if coin_info.coin == "SOL" {
+                MaybeToken::SOL()
+            } else if let Ok(token) = Token::from_str(&coin_info.coin) {
+                token.into()
+            } else {
+                continue;
+            };
+            let token_support = coin_info
+                .network_list
+                .iter()
+                .find(|network_info| network_info.network == SOLANA_NETWORK)
+                .map(|network_info| TokenSupport {
+                    listed: true,
+                    // SPL token deposits are not supported, see `deposit_address()`
+                    deposits_enabled: network_info.deposit_enable && token.is_sol(),
+                    withdrawals_enabled: network_info.withdraw_enable,
+                })
+                .unwrap_or(TokenSupport {
+                    listed: true,
+                    ..TokenSupport::default()
+                });
+            token_supports.push((token, token_support));
+        }
+        Ok(token_supports)
+    }
+
+    async fn spot_balances(
+        &self,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        self.throttle("/api/v3/account").await;
+        let account = self
+            .sdk_request("/api/v3/account", self.account.get_account())
+            .await?;
+
+        let mut balances = HashMap::new();
+        for balance in account
+            .balances
+            .iter()
+            .filter(|b| b.asset == "SOL" || is_usd_equivalent(&b.asset) || is_fiat(&b.asset))
+        {
+            let available = balance.free;
+            let total = available + balance.locked;
+
+            balances.insert(balance.asset.clone(), ExchangeBalance { available, total });
+        }
+
+        Ok(balances)
+    }
+
+    async fn funding_balances(
+        &self,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        let funding_assets = self
+            .signed_request::<Vec<FundingAsset>>(
+                reqwest::Method::POST,
+                "/sapi/v1/asset/get-funding-asset",
+                &[],
+            )
+            .await?;
+
+        let mut balances = HashMap::new();
+        for funding_asset in funding_assets
+            .into_iter()
+            .filter(|b| b.asset == "SOL" || is_usd_equivalent(&b.asset) || is_fiat(&b.asset))
+        {
+            let available = funding_asset.free.parse::<f64>()?;
+            let total = available
+                + funding_asset.locked.parse::<f64>()?
+                + funding_asset.freeze.parse::<f64>()?
+                + funding_asset.withdrawing.parse::<f64>()?;
+
+            balances.insert(funding_asset.asset, ExchangeBalance { available, total });
+        }
+        Ok(balances)
+    }
+
+    // Flexible positions can be redeemed at any time, locked positions not until they mature
+    async fn earn_balances(
+        &self,
+        locked: bool,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        const PAGE_SIZE: usize = 100;
+
+        let endpoint = if locked {
+            "/sapi/v1/simple-earn/locked/position"
+        } else {
+            "/sapi/v1/simple-earn/flexible/position"
+        };
+
+        let mut balances = HashMap::<String, ExchangeBalance>::new();
+        for current in 1.. {
+            let positions = self
+                .signed_get::<EarnPositions>(
+                    endpoint,
+                    &[
+                        ("current", current.to_string()),
+                        ("size", PAGE_SIZE.to_string()),
+                    ],
+                )
+                .await?;
+
+            for position in &positions.rows {
+                if position.asset != "SOL"
+                    && !is_usd_equivalent(&position.asset)
+                    && !is_fiat(&position.asset)
+                {
+                    continue;
+                }
+                let amount = position
+                    .total_amount
+                    .as_ref()
+                    .or(position.amount.as_ref())
+                    .ok_or_else(|| format!("{endpoint}: position missing an amount"))?
+                    .parse::<f64>()?;
+
+                let balance = balances.entry(position.asset.clone()).or_default();
+                balance.total += amount;
+                if !locked {
+                    balance.available += amount;
+                }
+            }
+
+            if positions.rows.len() < PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(balances)
+    }
+
+    async fn flexible_products(&self) -> Result<Vec<FlexibleProduct>, Box<dyn std::error::Error>> {
+        const PAGE_SIZE: usize = 100;
+
+        let mut products = vec![];
+        for current in 1.. {
+            let mut page = self
+                .signed_get::<FlexibleProducts>(
+                    "/sapi/v1/simple-earn/flexible/list",
+                    &[
+                        ("current", current.to_string()),
+                        ("size", PAGE_SIZE.to_string()),
+                    ],
+                )
+                .await?
+                .rows;
+            let page_len = page.len();
+            products.append(&mut page);
+            if page_len < PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(products)
+    }
+
+    pub async fn bnb_fee_discount_enabled(&self) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.exchange == Exchange::BinanceUs {
+            return Ok(false);
+        }
+        Ok(self
+            .signed_get::<BnbBurnStatus>("/sapi/v1/bnbBurn", &[])
+            .await?
+            .spot_bnb_burn)
+    }
+
+    // BNB price in USD at the given time, from the one minute kline containing it
+    async fn bnb_usd_price(&self, time: u64) -> Result<f64, Box<dyn std::error::Error>> {
+        let pair = if self.exchange == Exchange::BinanceUs {
+            TradingPair::new("BNB", "USD")
+        } else {
+            TradingPair::new("BNB", "USDT")
+        };
+        self.throttle("/api/v3/klines").await;
+        let klines = self
+            .public_get::<Vec<Vec<serde_json::Value>>>(
+                "/api/v3/klines",
+                &format!(
+                    "{}/api/v3/klines?symbol={}&interval=1m&startTime={}&limit=1",
+                    self.rest_api_endpoint,
+                    pair.to_exchange_symbol(self.exchange),
+                    time - time % 60_000,
+                ),
+            )
+            .await?;
+
+        klines
+            .first()
+            .and_then(|kline| kline.get(1))
+            .and_then(|open| open.as_str())
+            .and_then(|open| open.parse::<f64>().ok())
+            .ok_or_else(|| format!("No {pair} price available at {time}").into())
+    }
+
+    // The fee charged for an order, from the commission of each of its fills.
+    //
+    // `OrderStatus` carries a single fee currency, so fees charged in more than one asset (such as
+    // when the BNB balance runs out part way through an order) are converted to USD
+    async fn order_fee(
+        &self,
+        pair: &TradingPair,
+        order_id: u64,
+    ) -> Result<Option<(f64, String)>, Box<dyn std::error::Error>> {
+        let trades = self
+            .signed_get::<Vec<BinanceTrade>>(
+                "/api/v3/myTrades",
+                &[
+                    ("symbol", pair.to_exchange_symbol(self.exchange)),
+                    ("orderId", order_id.to_string()),
+                ],
+            )
+            .await?;
+
+        let fee_asset = |trade: &BinanceTrade| {
+            if is_usd_equivalent(&trade.commission_asset) {
+                "USD".to_string()
+            } else {
+                trade.commission_asset.clone()
+            }
+        };
+
+        let mut fees = BTreeMap::<String, f64>::default();
+        for trade in &trades {
+            *fees.entry(fee_asset(trade)).or_default() += trade.commission.parse::<f64>()?;
+        }
+
+        if fees.len() <= 1 && !(self.bnb_fees_in_usd && fees.contains_key("BNB")) {
+            return Ok(fees.into_iter().next());
+        }
+
+        let mut usd_fee = 0.;
+        for trade in &trades {
+            let commission = trade.commission.parse::<f64>()?;
+            let asset = fee_asset(trade);
+            usd_fee += if asset == "USD" {
+                commission
+            } else if asset == "BNB" {
+                commission * self.bnb_usd_price(trade.time).await?
+            } else if asset == pair.base && is_usd_equivalent(&pair.quote) {
+                commission * trade.price.parse::<f64>()?
+            } else {
+                return Err(format!("Unable to value the {asset} fee in USD").into());
+            };
+        }
+        Ok(Some((usd_fee, "USD".into())))
+    }
+
+    fn limit_order_request(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+    ) -> binance::account::OrderRequest {
+        binance::account::OrderRequest {
+            symbol: pair.to_exchange_symbol(self.exchange),
+            side: match side {
+                OrderSide::Buy => binance::rest_model::OrderSide::Buy,
+                OrderSide::Sell => binance::rest_model::OrderSide::Sell,
+            },
+            price: Some(price),
+            quantity: Some(amount),
+            new_client_order_id: Some(client_order_id.into()),
+            new_order_resp_type: Some(binance::rest_model::OrderResponse::Full),
+            ..binance::account::OrderRequest::default()
+        }
+    }
 }
 
 #[async_trait]
 impl ExchangeClient for BinanceExchangeClient {
+    async fn supported_tokens(&self) -> Result<Vec<MaybeToken>, Box<dyn std::error::Error>> {
+        Ok(self
+            .token_supports()
+            .await?
+            .into_iter()
+            .filter(|(_, token_support)| {
+                token_support.deposits_enabled || token_support.withdrawals_enabled
+            })
+            .map(|(token, _)| token)
+            .collect())
+    }
+
+    async fn supports_token(
+        &self,
+        token: MaybeToken,
+    ) -> Result<TokenSupport, Box<dyn std::error::Error>> {
+        Ok(self
+            .token_supports()
+            .await?
+            .into_iter()
+            .find(|(supported_token, _)| *supported_token == token)
+            .map(|(_, token_support)| token_support)
+            .unwrap_or_default())
+    }
+
+    async fn deposit_requirements(
+        &self,
+        token: MaybeToken,
+    ) -> Result<DepositRequirements, Box<dyn std::error::Error>> {
+        let sol_network_info = self.solana_network_info(token).await?;
+        Ok(DepositRequirements {
+            finality: DepositFinality::Confirmations(sol_network_info.min_confirm),
+            minimum: None,
+            // SPL token deposits are not supported, see `deposit_address()`
+            deposits_enabled: sol_network_info.deposit_enable && token.is_sol(),
+            processing_time_secs: None,
+        })
+    }
+
     async fn deposit_address(
         &self,
         token: MaybeToken,
@@ -27,16 +1273,34 @@ impl ExchangeClient for BinanceExchangeClient {
             return Err(format!("{token} deposits are not supported").into());
         }
 
-        if !self.account.get_account().await?.can_deposit {
+        self.throttle("/api/v3/account").await;
+        if !self
+            .sdk_request("/api/v3/account", self.account.get_account())
+            .await?
+            .can_deposit
+        {
             return Err("deposits not available".into());
         }
 
+        let sol_network_info = self.solana_network_info(token).await?;
+        if !sol_network_info.deposit_enable {
+            return Err(format!(
+                "Binance {token} deposits disabled: {}",
+                sol_network_info.deposit_desc
+            )
+            .into());
+        }
+
+        self.throttle("/sapi/v1/capital/deposit/address").await;
         Ok(self
-            .wallet
-            .deposit_address(binance::rest_model::DepositAddressQuery {
-                coin: "SOL".into(),
-                network: None,
-            })
+            .sdk_request(
+                "/sapi/v1/capital/deposit/address",
+                self.wallet
+                    .deposit_address(binance::rest_model::DepositAddressQuery {
+                        coin: token.name().into(),
+                        network: Some(SOLANA_NETWORK.into()),
+                    }),
+            )
             .await?
             .address
             .parse::<Pubkey>()?)
@@ -45,278 +1309,785 @@ impl ExchangeClient for BinanceExchangeClient {
     async fn recent_deposits(
         &self,
     ) -> Result<Option<Vec<DepositInfo>>, Box<dyn std::error::Error>> {
+        self.throttle("/sapi/v1/capital/deposit/hisrec").await;
         Ok(Some(
-            self.wallet
-                .deposit_history(&binance::rest_model::DepositHistoryQuery::default())
-                .await?
-                .into_iter()
-                .filter_map(|dr| {
-                    /* status codes: 0 = pending, 6 = credited but cannot withdraw, 1 = success */
-                    if dr.status == 1 {
-                        Some(DepositInfo {
-                            tx_id: dr.tx_id,
-                            amount: dr.amount,
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect(),
-        ))
-    }
-
-    async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
-        Ok(self
-            .wallet
-            .withdraw_history(&binance::rest_model::WithdrawalHistoryQuery::default())
+            self.sdk_request(
+                "/sapi/v1/capital/deposit/hisrec",
+                self.wallet
+                    .deposit_history(&binance::rest_model::DepositHistoryQuery::default()),
+            )
             .await?
             .into_iter()
-            .map(|wr| {
-                /* status codes: 0 = email sent, 1 = canceled,   2 =  awaiting approval,
-                3 = rejected,   4 = processing, 5 = failure,
-                6 = completed */
-                let (completed, tx_id) = match wr.status {
-                    6 => (true, Some(wr.tx_id.expect("transaction id"))),
-                    1 => (true, None),
-                    _ => (false, None),
-                };
-
-                let token = if &wr.coin == "SOL" {
-                    None
+            .filter_map(|dr| {
+                /* status codes: 0 = pending, 6 = credited but cannot withdraw, 1 = success */
+                if dr.status == 1 {
+                    Some(DepositInfo {
+                        tx_id: dr.tx_id,
+                        amount: dr.amount,
+                        token: if dr.coin == "SOL" {
+                            Some(MaybeToken::SOL())
+                        } else {
+                            Token::from_str(&dr.coin).ok().map(MaybeToken::from)
+                        },
+                        time: dr
+                            .insert_time
+                            .map(|insert_time| Utc.timestamp_millis(insert_time as i64)),
+                    })
                 } else {
-                    Token::from_str(&wr.coin).ok()
-                };
-                WithdrawalInfo {
-                    address: wr.address.parse::<Pubkey>().unwrap_or_default(),
-                    token: token.into(),
-                    amount: wr.amount,
-                    tag: wr.withdraw_order_id.unwrap_or_default(),
-                    completed,
-                    tx_id,
+                    None
                 }
             })
-            .collect())
+            .collect(),
+        ))
+    }
+
+    async fn withdrawals(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
+        let mut withdrawals = vec![];
+
+        // Withdrawal history queries may span at most 90 days
+        for (window_start, window_end) in date_range_windows(start, end, 90) {
+            let mut offset = 0;
+            loop {
+                self.throttle("/sapi/v1/capital/withdraw/history").await;
+                let withdraw_records = self
+                    .sdk_request_with_timeout(
+                        "/sapi/v1/capital/withdraw/history",
+                        HISTORY_REQUEST_TIMEOUT,
+                        self.wallet.withdraw_history(
+                            &binance::rest_model::WithdrawalHistoryQuery {
+                                start_time: Some(window_start.timestamp_millis() as u64),
+                                // Inclusive, where the window's end is not
+                                end_time: Some(window_end.timestamp_millis() as u64 - 1),
+                                offset: Some(offset),
+                                limit: Some(WITHDRAW_HISTORY_LIMIT),
+                                ..binance::rest_model::WithdrawalHistoryQuery::default()
+                            },
+                        ),
+                    )
+                    .await?;
+                let page_len = withdraw_records.len();
+
+                withdrawals.extend(withdraw_records.into_iter().map(|wr| {
+                    /* status codes: 0 = email sent, 1 = canceled,   2 =  awaiting approval,
+                    3 = rejected,   4 = processing, 5 = failure,
+                    6 = completed */
+                    let (completed, tx_id) = match wr.status {
+                        6 => (true, Some(wr.tx_id.expect("transaction id"))),
+                        1 => (true, None),
+                        _ => (false, None),
+                    };
+
+                    let token = if &wr.coin == "SOL" {
+                        None
+                    } else {
+                        Token::from_str(&wr.coin).ok()
+                    };
+                    WithdrawalInfo {
+                        address: wr.address.parse::<Pubkey>().unwrap_or_default(),
+                        token: token.into(),
+                        amount: wr.amount,
+                        tag: wr.withdraw_order_id.clone().unwrap_or_default(),
+                        completed,
+                        tx_id,
+                        fee: Some(wr.transaction_fee),
+                        client_reference: wr.withdraw_order_id,
+                        // `applyTime` is reported in UTC as "YYYY-MM-DD HH:MM:SS"
+                        time: NaiveDateTime::parse_from_str(&wr.apply_time, "%Y-%m-%d %H:%M:%S")
+                            .ok()
+                            .map(|time| DateTime::from_utc(time, Utc)),
+                    }
+                }));
+
+                if page_len < WITHDRAW_HISTORY_LIMIT as usize {
+                    break;
+                }
+                offset += WITHDRAW_HISTORY_LIMIT;
+            }
+        }
+
+        sort_and_dedup_withdrawals(&mut withdrawals);
+        Ok(withdrawals)
     }
 
     async fn request_withdraw(
         &self,
         address: Pubkey,
         token: MaybeToken,
-        amount: f64,
+        amount: u64,
         _withdrawal_password: Option<String>,
-        _withdrawal_code: Option<String>,
+        _withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+        client_reference: &str,
     ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>
     {
-        if token != MaybeToken::SOL() {
-            return Err(format!("{token} deposits are not supported").into());
+        let sol_network_info = self.solana_network_info(token).await?;
+
+        if !sol_network_info.withdraw_enable {
+            return Err(format!(
+                "Binance {token} withdrawals disabled: {}",
+                sol_network_info.withdraw_desc
+            )
+            .into());
+        }
+
+        if token.ui_amount(amount) < sol_network_info.withdraw_min {
+            return Err(format!(
+                "Withdrawal request is below the minimum of {} {token}",
+                sol_network_info.withdraw_min
+            )
+            .into());
+        }
+
+        let withdraw_fee = sol_network_info.withdraw_fee;
+        let withdraw_order_id = client_reference.to_string();
+
+        // Signed directly rather than through the SDK, which only takes the amount as an f64
+        self.signed_request::<serde_json::Value>(
+            reqwest::Method::POST,
+            "/sapi/v1/capital/withdraw/apply",
+            &[
+                ("coin", token.name().into()),
+                ("network", SOLANA_NETWORK.into()),
+                ("withdrawOrderId", withdraw_order_id.clone()),
+                ("address", address.to_string()),
+                (
+                    "amount",
+                    format_native_amount(token, amount, self.withdrawal_decimals(token)),
+                ),
+            ],
+        )
+        .await?;
+
+        Ok((withdraw_order_id, withdraw_fee))
+    }
+
+    fn withdrawal_decimals(&self, token: MaybeToken) -> u8 {
+        token.decimals().min(WITHDRAW_DECIMALS)
+    }
+
+    async fn withdrawal_limits(
+        &self,
+        token: MaybeToken,
+    ) -> Result<Option<WithdrawalLimits>, Box<dyn std::error::Error>> {
+        let sol_network_info = self.solana_network_info(token).await?;
+        Ok(Some(WithdrawalLimits {
+            minimum: sol_network_info.withdraw_min,
+            fee: sol_network_info.withdraw_fee,
+        }))
+    }
+
+    async fn balances(
+        &self,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        let mut balances = HashMap::<String, ExchangeBalance>::new();
+        for (wallet, wallet_balances) in self.wallet_balances().await? {
+            for (coin, wallet_balance) in wallet_balances {
+                let balance = balances.entry(coin).or_default();
+                balance.total += wallet_balance.total;
+                // Only spot balances can be traded or withdrawn directly
+                if wallet == "spot" {
+                    balance.available += wallet_balance.available;
+                }
+            }
+        }
+        Ok(balances)
+    }
+
+    // Requires a master account API key.  Subaccounts are identified by their email address
+    async fn subaccounts(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let endpoint = if self.exchange == Exchange::BinanceUs {
+            "/sapi/v3/sub-account/list"
+        } else {
+            "/sapi/v1/sub-account/list"
+        };
+        Ok(self
+            .signed_get::<SubAccountList>(endpoint, &[])
+            .await?
+            .sub_accounts
+            .into_iter()
+            .map(|sub_account| sub_account.email)
+            .collect())
+    }
+
+    async fn subaccount_balances(
+        &self,
+        name: &str,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        Ok(self
+            .signed_get::<SubAccountAssets>(
+                "/sapi/v3/sub-account/assets",
+                &[("email", name.into())],
+            )
+            .await?
+            .balances
+            .into_iter()
+            .filter(|b| b.asset == "SOL" || is_usd_equivalent(&b.asset) || is_fiat(&b.asset))
+            .map(|b| {
+                (
+                    b.asset,
+                    ExchangeBalance {
+                        available: b.free,
+                        total: b.free + b.locked,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    async fn wallet_balances(
+        &self,
+    ) -> Result<BTreeMap<String, HashMap<String, ExchangeBalance>>, Box<dyn std::error::Error>>
+    {
+        let mut wallets = BTreeMap::from([("spot".to_string(), self.spot_balances().await?)]);
+        if self.exchange == Exchange::BinanceUs {
+            return Ok(wallets);
+        }
+
+        // These require additional API key permissions, so are skipped when unavailable
+        for (wallet, result) in [
+            ("funding", self.funding_balances().await),
+            ("earn-flexible", self.earn_balances(false).await),
+            ("earn-locked", self.earn_balances(true).await),
+        ] {
+            match result {
+                Ok(balances) => {
+                    if !balances.is_empty() {
+                        wallets.insert(wallet.to_string(), balances);
+                    }
+                }
+                Err(err) => {
+                    if !self.wallet_warning_shown.swap(true, Ordering::Relaxed) {
+                        eprintln!("Unable to fetch Binance {wallet} balances: {err}");
+                    }
+                }
+            }
+        }
+        Ok(wallets)
+    }
+
+    async fn print_market_info(
+        &self,
+        pair: &TradingPair,
+        format: MarketInfoFormat,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let MarketInfoFormat::Candles(range) = format {
+            return print_candles(range, &self.candles(pair, range).await?);
+        }
+
+        let all = format == MarketInfoFormat::All;
+        let ticker_stats = self.ticker_stats(pair).await?;
+        let pair_info = self.pair_info(pair).await.ok();
+        let usd_rate = if all {
+            quote_usd_rate(self, &pair.quote).await.ok()
+        } else {
+            None
+        };
+        print_ticker_stats(&ticker_stats, pair_info.as_ref(), usd_rate, format)?;
+
+        if all {
+            let symbol = pair.to_exchange_symbol(self.exchange);
+            self.throttle("/api/v3/avgPrice").await;
+            let average_price = self
+                .sdk_request("/api/v3/avgPrice", self.market.get_average_price(&symbol))
+                .await?;
+            println!(
+                "Last {} minute average: {}",
+                average_price.mins,
+                format_quote_amount(
+                    &format_increment(
+                        average_price.price,
+                        pair_info.and_then(|pair_info| pair_info.tick_size),
+                        true
+                    ),
+                    &pair.quote
+                )
+            );
+        }
+        Ok(())
+    }
+
+    async fn server_time(&self) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct ServerTime {
+            server_time: i64,
+        }
+
+        self.throttle("/api/v3/time").await;
+        let server_time = self
+            .public_get::<ServerTime>(
+                "/api/v3/time",
+                &format!("{}/api/v3/time", self.rest_api_endpoint),
+            )
+            .await?
+            .server_time;
+        Ok(Utc.timestamp_millis(server_time))
+    }
+
+    async fn system_status(
+        &self,
+        pair: Option<&TradingPair>,
+    ) -> Result<SystemStatus, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct BinanceSystemStatus {
+            status: u8, // 0 = normal, 1 = system maintenance
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct BinanceSymbolInfo {
+            symbols: Vec<BinanceSymbolStatus>,
         }
 
-        let sol_info = self
-            .wallet
-            .all_coin_info()
+        #[derive(Debug, Deserialize)]
+        struct BinanceSymbolStatus {
+            status: binance::rest_model::SymbolStatus,
+        }
+
+        self.throttle("/sapi/v1/system/status").await;
+        let response = self
+            .public_get::<BinanceSystemStatus>(
+                "/sapi/v1/system/status",
+                &format!("{}/sapi/v1/system/status", self.rest_api_endpoint),
+            )
+            .await?;
+        let system_status = match response.status {
+            0 => SystemStatus::Online,
+            _ => SystemStatus::Maintenance,
+        };
+        let pair = match pair {
+            Some(pair) => pair,
+            None => return Ok(system_status),
+        };
+
+        self.throttle("/api/v3/exchangeInfo").await;
+        let pair_status = self
+            .public_get::<BinanceSymbolInfo>(
+                "/api/v3/exchangeInfo",
+                &format!(
+                    "{}/api/v3/exchangeInfo?symbol={}",
+                    self.rest_api_endpoint,
+                    pair.to_exchange_symbol(self.exchange),
+                ),
+            )
             .await?
+            .symbols
             .into_iter()
-            .find(|ci| ci.coin == "SOL")
-            .ok_or("SOL not found in Binance coin list")?;
+            .next()
+            .ok_or_else(|| format!("{pair} not found in Binance exchange info"))?
+            .status;
+        Ok(system_status.max(symbol_status(&pair_status)))
+    }
+
+    async fn api_permissions(&self) -> Result<ApiPermissions, Box<dyn std::error::Error>> {
+        let api_restrictions = self
+            .signed_get::<ApiRestrictions>("/sapi/v1/account/apiRestrictions", &[])
+            .await?;
+        Ok(ApiPermissions {
+            trade: api_restrictions.enable_spot_and_margin_trading,
+            withdraw: api_restrictions.enable_withdrawals,
+            ip_restricted: Some(api_restrictions.ip_restrict),
+        })
+    }
 
-        if !sol_info.deposit_all_enable {
-            return Err("SOL deposits not enabled".into());
+    async fn order_book(
+        &self,
+        pair: &TradingPair,
+        depth: usize,
+    ) -> Result<OrderBook, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct Depth {
+            bids: Vec<(String, String)>,
+            asks: Vec<(String, String)>,
         }
 
-        let sol_network_info = &sol_info.network_list[0];
-        assert_eq!(&sol_network_info.network, "SOL");
-        assert_eq!(&sol_network_info.name, "Solana");
-        assert_eq!(&sol_network_info.coin, "SOL");
+        self.validate_pair(pair).await?;
+        let limit = DEPTH_LIMITS
+            .iter()
+            .find(|limit| **limit >= depth)
+            .unwrap_or(&DEPTH_LIMITS[DEPTH_LIMITS.len() - 1]);
 
-        if !sol_network_info.deposit_enable {
-            return Err(format!(
-                "Binance deposits disabled: {}",
-                sol_network_info.deposit_desc
+        self.rate_limiter.acquire(depth_weight(*limit)).await;
+        let time = Utc::now();
+        let response = self
+            .public_get::<Depth>(
+                "/api/v3/depth",
+                &format!(
+                    "{}/api/v3/depth?symbol={}&limit={limit}",
+                    self.rest_api_endpoint,
+                    pair.to_exchange_symbol(self.exchange),
+                ),
             )
-            .into());
-        }
+            .await?;
 
-        if !sol_network_info.withdraw_enable {
-            return Err(format!(
-                "Binance withdrawals disabled: {}",
-                sol_network_info.withdraw_desc
+        let levels = |levels: &[(String, String)]| {
+            parse_order_book_levels(
+                levels
+                    .iter()
+                    .map(|(price, amount)| (price.as_str(), amount.as_str())),
+                depth,
             )
-            .into());
-        }
+        };
+        Ok(OrderBook {
+            bids: levels(&response.bids)?,
+            asks: levels(&response.asks)?,
+            time,
+        })
+    }
 
-        if amount < sol_network_info.withdraw_min {
-            return Err(format!(
-                "Withdrawal request is below the minimum of {} SOL",
-                sol_network_info.withdraw_min
+    async fn ticker_stats(
+        &self,
+        pair: &TradingPair,
+    ) -> Result<TickerStats, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        let symbol = pair.to_exchange_symbol(self.exchange);
+        self.throttle("/api/v3/ticker/24hr").await;
+        let price_stats = self
+            .sdk_request(
+                "/api/v3/ticker/24hr",
+                self.market.get_24h_price_stats(&symbol),
             )
-            .into());
-        }
+            .await?;
 
-        let withdraw_fee = sol_network_info.withdraw_fee;
+        Ok(TickerStats {
+            pair: pair.to_string(),
+            last_price: price_stats.last_price,
+            bid_price: price_stats.bid_price,
+            ask_price: price_stats.ask_price,
+            open_24h: price_stats.open_price,
+            high_24h: price_stats.high_price,
+            low_24h: price_stats.low_price,
+            base_volume_24h: price_stats.volume,
+            quote_volume_24h: price_stats.quote_volume,
+            price_change_percent_24h: price_stats.price_change_percent,
+            weighted_average_price_24h: Some(price_stats.weighted_avg_price),
+        })
+    }
 
-        let withdraw_order_id = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-            .to_string();
-
-        self.wallet
-            .withdraw(binance::rest_model::CoinWithdrawalQuery {
-                coin: token.to_string(),
-                network: Some("SOL".into()),
-                withdraw_order_id: Some(withdraw_order_id.clone()),
-                address: address.to_string(),
-                amount,
-                ..binance::rest_model::CoinWithdrawalQuery::default()
-            })
+    async fn bid_ask(&self, pair: &TradingPair) -> Result<BidAsk, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        self.throttle("/api/v3/ticker/24hr").await;
+        let binance::rest_model::PriceStats {
+            ask_price,
+            ask_qty,
+            bid_price,
+            bid_qty,
+            ..
+        } = self
+            .sdk_request(
+                "/api/v3/ticker/24hr",
+                self.market
+                    .get_24h_price_stats(pair.to_exchange_symbol(self.exchange)),
+            )
             .await?;
 
-        Ok((withdraw_order_id, withdraw_fee))
+        Ok(BidAsk {
+            bid_price,
+            ask_price,
+            bid_amount: Some(bid_qty),
+            ask_amount: Some(ask_qty),
+        })
     }
 
-    async fn balances(
-        &self,
-    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
-        let account = self.account.get_account().await?;
+    async fn bid_asks(&self) -> Result<HashMap<TradingPair, BidAsk>, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct BookTicker {
+            symbol: String,
+            bid_price: String,
+            bid_qty: String,
+            ask_price: String,
+            ask_qty: String,
+        }
 
-        let mut balances = HashMap::new();
-        for coin in ["SOL"].iter().chain(USD_COINS) {
-            if let Some(balance) = account.balances.iter().find(|b| b.asset == *coin) {
-                let available = balance.free;
-                let total = available + balance.locked;
+        let pairs = self
+            .pair_infos()
+            .await?
+            .into_iter()
+            .map(|pair_info| {
+                (
+                    pair_info.pair.to_exchange_symbol(self.exchange),
+                    pair_info.pair,
+                )
+            })
+            .collect::<HashMap<_, _>>();
 
-                balances.insert(coin.to_string(), ExchangeBalance { available, total });
+        self.throttle("/api/v3/ticker/bookTicker").await;
+        let book_tickers = self
+            .public_get::<Vec<BookTicker>>(
+                "/api/v3/ticker/bookTicker",
+                &format!("{}/api/v3/ticker/bookTicker", self.rest_api_endpoint),
+            )
+            .await?;
+
+        let mut bid_asks = HashMap::default();
+        for book_ticker in book_tickers {
+            let pair = match pairs.get(&book_ticker.symbol) {
+                Some(pair) => pair.clone(),
+                None => continue,
+            };
+            let bid_price = book_ticker.bid_price.parse::<f64>()?;
+            let ask_price = book_ticker.ask_price.parse::<f64>()?;
+            // Markets that aren't trading report an empty book
+            if bid_price > 0. && ask_price > 0. {
+                bid_asks.insert(
+                    pair,
+                    BidAsk {
+                        bid_price,
+                        ask_price,
+                        bid_amount: book_ticker.bid_qty.parse::<f64>().ok(),
+                        ask_amount: book_ticker.ask_qty.parse::<f64>().ok(),
+                    },
+                );
             }
         }
-
-        Ok(balances)
+        Ok(bid_asks)
     }
 
-    async fn print_market_info(
+    async fn candles(
         &self,
-        pair: &str,
-        format: MarketInfoFormat,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let ticker_price = self.market.get_24h_price_stats(pair).await?;
-
-        match format {
-            MarketInfoFormat::All => {
-                println!("Pair: {pair}");
-                println!(
-                    "Ask: ${}, Bid: ${}, High: ${}, Low: ${}, ",
-                    ticker_price.ask_price,
-                    ticker_price.bid_price,
-                    ticker_price.high_price,
-                    ticker_price.low_price
-                );
+        pair: &TradingPair,
+        range: CandleRange,
+    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        range.validate(
+            self.exchange,
+            &KLINE_INTERVALS
+                .iter()
+                .map(|(minutes, _)| *minutes)
+                .collect::<Vec<_>>(),
+        )?;
+        let interval = KLINE_INTERVALS
+            .iter()
+            .find(|(minutes, _)| *minutes == range.interval_minutes)
+            .map(|(_, interval)| interval)
+            .unwrap();
 
-                let average_price = self.market.get_average_price(pair).await?;
-
-                println!(
-                    "Last {} minute average: ${}",
-                    average_price.mins, average_price.price
-                );
-                println!(
-                    "Last 24h change: ${} ({}%)",
-                    ticker_price.price_change, ticker_price.price_change_percent
-                );
-                println!(
-                    "Weighted 24h average price: ${}",
-                    ticker_price.weighted_avg_price
-                );
+        // Pages are fetched backwards from the most recent kline
+        let mut candles = vec![];
+        let mut end_time = None;
+        while candles.len() < range.count {
+            let limit = (range.count - candles.len()).min(KLINES_LIMIT);
+            let mut url = format!(
+                "{}/api/v3/klines?symbol={}&interval={interval}&limit={limit}",
+                self.rest_api_endpoint,
+                pair.to_exchange_symbol(self.exchange),
+            );
+            if let Some(end_time) = end_time {
+                url.push_str(&format!("&endTime={end_time}"));
             }
-            MarketInfoFormat::Ask => {
-                println!("{}", ticker_price.ask_price);
+            self.throttle("/api/v3/klines").await;
+            let klines = self
+                .public_get::<Vec<Vec<serde_json::Value>>>("/api/v3/klines", &url)
+                .await?;
+
+            // [open time, open, high, low, close, volume, ...]
+            let mut page = vec![];
+            for kline in &klines {
+                let field = |index: usize| -> Result<f64, Box<dyn std::error::Error>> {
+                    Ok(kline
+                        .get(index)
+                        .and_then(|value| value.as_str())
+                        .ok_or("Malformed kline")?
+                        .parse::<f64>()?)
+                };
+                let open_time = kline
+                    .first()
+                    .and_then(|time| time.as_i64())
+                    .ok_or("Malformed kline")?;
+                page.push(Candle {
+                    time: Utc.timestamp_millis(open_time),
+                    high: field(2)?,
+                    low: field(3)?,
+                    volume: field(5)?,
+                });
             }
-            MarketInfoFormat::Weighted24hAveragePrice => {
-                println!("{}", ticker_price.weighted_avg_price);
+
+            match page.first() {
+                Some(oldest) => end_time = Some(oldest.time.timestamp_millis() - 1),
+                None => break,
             }
-            MarketInfoFormat::Hourly => {
-                return Err("Hourly market info currently supported for Binance".into())
+            let exhausted = page.len() < limit;
+            page.append(&mut candles);
+            candles = page;
+            if exhausted {
+                break;
             }
         }
-        Ok(())
+        Ok(candles)
     }
 
-    async fn bid_ask(&self, pair: &str) -> Result<BidAsk, Box<dyn std::error::Error>> {
-        let binance::rest_model::PriceStats {
-            ask_price,
-            bid_price,
-            ..
-        } = self.market.get_24h_price_stats(pair).await?;
+    async fn price_on(
+        &self,
+        pair: &TradingPair,
+        date: NaiveDate,
+    ) -> Result<f64, Box<dyn std::error::Error>> {
+        let open_time = date.and_hms(0, 0, 0).timestamp_millis();
+        self.throttle("/api/v3/klines").await;
+        let klines = self
+            .public_get::<Vec<Vec<serde_json::Value>>>(
+                "/api/v3/klines",
+                &format!(
+                    "{}/api/v3/klines?symbol={}&interval=1d&startTime={open_time}&limit=1",
+                    self.rest_api_endpoint,
+                    pair.to_exchange_symbol(self.exchange),
+                ),
+            )
+            .await?;
 
-        Ok(BidAsk {
-            bid_price,
-            ask_price,
-        })
+        // Before the pair listed, the first kline returned is from a later day
+        klines
+            .first()
+            .filter(|kline| kline.first().and_then(|time| time.as_i64()) == Some(open_time))
+            .and_then(|kline| kline.get(4))
+            .and_then(|close| close.as_str())
+            .and_then(|close| close.parse::<f64>().ok())
+            .ok_or_else(|| format!("No {pair} price available on {date}").into())
     }
 
     async fn place_order(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         side: OrderSide,
         price: f64,
         amount: f64,
+        client_order_id: &str,
     ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+
         // Minimum notional value for orders is $10 USD
         if price * amount < 10. {
             return Err("Total order amount must be 10 or greater".into());
         }
 
+        self.rate_limiter.acquire(1).await;
         Ok(self
-            .account
-            .place_order(binance::account::OrderRequest {
-                symbol: pair.into(),
-                side: match side {
-                    OrderSide::Buy => binance::rest_model::OrderSide::Buy,
-                    OrderSide::Sell => binance::rest_model::OrderSide::Sell,
-                },
-                order_type: binance::rest_model::OrderType::LimitMaker,
-                price: Some(price),
-                quantity: Some(amount),
-                new_order_resp_type: Some(binance::rest_model::OrderResponse::Full),
-                ..binance::account::OrderRequest::default()
-            })
+            .sdk_request(
+                "/api/v3/order",
+                self.account.place_order(binance::account::OrderRequest {
+                    order_type: binance::rest_model::OrderType::LimitMaker,
+                    ..self.limit_order_request(pair, side, price, amount, client_order_id)
+                }),
+            )
+            .await?
+            .client_order_id)
+    }
+
+    async fn place_taker_order(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+
+        self.rate_limiter.acquire(1).await;
+        Ok(self
+            .sdk_request(
+                "/api/v3/order",
+                self.account.place_order(binance::account::OrderRequest {
+                    order_type: binance::rest_model::OrderType::Limit,
+                    time_in_force: Some(binance::rest_model::TimeInForce::IOC),
+                    ..self.limit_order_request(pair, side, price, amount, client_order_id)
+                }),
+            )
             .await?
             .client_order_id)
     }
 
+    async fn find_order_by_client_id(
+        &self,
+        pair: &TradingPair,
+        client_order_id: &str,
+    ) -> Result<Option<OrderId>, Box<dyn std::error::Error>> {
+        self.throttle("/api/v3/order").await;
+        match self
+            .sdk_request(
+                "/api/v3/order",
+                self.account
+                    .order_status(binance::account::OrderStatusRequest {
+                        symbol: pair.to_exchange_symbol(self.exchange),
+                        orig_client_order_id: Some(client_order_id.into()),
+                        ..binance::account::OrderStatusRequest::default()
+                    }),
+            )
+            .await
+        {
+            Ok(order) => Ok(Some(order.client_order_id)),
+            Err(err) if err.to_string().contains("Order does not exist") => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     async fn cancel_order(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         order_id: &OrderId,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        self.account
-            .cancel_order(binance::account::OrderCancellation {
-                symbol: pair.into(),
-                order_id: None,
-                orig_client_order_id: Some(order_id.into()),
-                new_client_order_id: None,
-                recv_window: None,
-            })
-            .await?;
+        self.rate_limiter.acquire(1).await;
+        self.sdk_request(
+            "/api/v3/order",
+            self.account
+                .cancel_order(binance::account::OrderCancellation {
+                    symbol: pair.to_exchange_symbol(self.exchange),
+                    order_id: None,
+                    orig_client_order_id: Some(order_id.into()),
+                    new_client_order_id: None,
+                    recv_window: None,
+                }),
+        )
+        .await?;
 
         Ok(())
     }
 
     async fn order_status(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         order_id: &OrderId,
     ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+        let symbol = pair.to_exchange_symbol(self.exchange);
+        self.throttle("/api/v3/order").await;
         let order = self
-            .account
-            .order_status(binance::account::OrderStatusRequest {
-                symbol: pair.into(),
-                orig_client_order_id: Some(order_id.into()),
-                ..binance::account::OrderStatusRequest::default()
-            })
+            .sdk_request(
+                "/api/v3/order",
+                self.account
+                    .order_status(binance::account::OrderStatusRequest {
+                        symbol: symbol.clone(),
+                        orig_client_order_id: Some(order_id.into()),
+                        ..binance::account::OrderStatusRequest::default()
+                    }),
+            )
             .await?;
 
-        assert_eq!(order.order_type, binance::rest_model::OrderType::LimitMaker);
-        assert_eq!(order.time_in_force, binance::rest_model::TimeInForce::GTC);
-        assert_eq!(&order.symbol, pair);
-        assert_eq!(order.client_order_id, *order_id);
+        // `place_order()` places limit-maker orders, and `place_taker_order()` IOC limit orders
+        if !matches!(
+            (&order.order_type, &order.time_in_force),
+            (
+                binance::rest_model::OrderType::LimitMaker,
+                binance::rest_model::TimeInForce::GTC
+            ) | (
+                binance::rest_model::OrderType::Limit,
+                binance::rest_model::TimeInForce::IOC
+            )
+        ) {
+            return Err(format!(
+                "Order {order_id} is an unsupported {:?} {:?} order",
+                order.order_type, order.time_in_force
+            )
+            .into());
+        }
+        if order.symbol != symbol || order.client_order_id != *order_id {
+            return Err(format!(
+                "Order {order_id} on {symbol} was answered with order {} on {}",
+                order.client_order_id, order.symbol
+            )
+            .into());
+        }
 
         let last_update = Local
             .timestamp((order.update_time / 1000) as i64, 0)
@@ -328,20 +2099,24 @@ impl ExchangeClient for BinanceExchangeClient {
             binance::rest_model::OrderSide::Buy => OrderSide::Buy,
         };
 
-        let trade_fees = self.wallet.trade_fees(Some(pair.to_string())).await?;
-
-        let fee = trade_fees.first().map(|trade_fee| {
-            assert_eq!(&trade_fee.symbol, pair);
-            (trade_fee.maker_commission * order.executed_qty, {
-                // TODO: Avoid hard code and support pairs generically...
-                assert!(matches!(trade_fee.symbol.as_str(), "SOLUSD" | "SOLBUSD"));
-                if side == OrderSide::Sell {
-                    "USD".into()
-                } else {
-                    "SOL".into()
-                }
-            })
-        });
+        let fee = if order.executed_qty > 0. {
+            self.order_fee(pair, order.order_id).await?
+        } else {
+            None
+        };
+        let (average_fill_price, cumulative_quote_amount) =
+            fill_totals(order.executed_qty, order.cummulative_quote_qty);
+        let cancellation = match order.status {
+            binance::rest_model::OrderStatus::Canceled => Some(OrderCancellation::User),
+            binance::rest_model::OrderStatus::Expired
+                if !matches!(order.time_in_force, binance::rest_model::TimeInForce::IOC) =>
+            {
+                Some(OrderCancellation::Exchange {
+                    reason: EXPIRED_BY_EXCHANGE.into(),
+                })
+            }
+            _ => None,
+        };
 
         Ok(OrderStatus {
             open: matches!(
@@ -353,16 +2128,104 @@ impl ExchangeClient for BinanceExchangeClient {
             price: order.price,
             amount: order.orig_qty,
             filled_amount: order.executed_qty,
+            average_fill_price,
+            cumulative_quote_amount,
             last_update,
             fee,
+            cancellation,
         })
     }
 
+    async fn open_orders(&self) -> Result<Vec<ExchangeOpenOrder>, Box<dyn std::error::Error>> {
+        let mut open_orders = vec![];
+        for order in self
+            .signed_get::<Vec<BinanceOrder>>("/api/v3/openOrders", &[])
+            .await?
+        {
+            open_orders.push(ExchangeOpenOrder {
+                pair: TradingPair::from_exchange_symbol(self.exchange, &order.symbol)?,
+                order_id: order.client_order_id,
+                side: match order.side.as_str() {
+                    "SELL" => OrderSide::Sell,
+                    "BUY" => OrderSide::Buy,
+                    side => return Err(format!("Invalid order side: {side}").into()),
+                },
+                price: order.price.parse::<f64>()?,
+                amount: order.orig_qty.parse::<f64>()?,
+            });
+        }
+        Ok(open_orders)
+    }
+
+    async fn order_history(
+        &self,
+        pair: &TradingPair,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<HistoricalOrder>, Box<dyn std::error::Error>> {
+        let symbol = pair.to_exchange_symbol(self.exchange);
+
+        let mut history = vec![];
+        for order in self.all_orders(&symbol, start, end).await? {
+            if order.symbol != symbol {
+                continue;
+            }
+            let filled_amount = order.executed_qty.parse::<f64>()?;
+            let fee = if filled_amount > 0. {
+                self.order_fee(pair, order.order_id).await?
+            } else {
+                None
+            };
+            let (average_fill_price, cumulative_quote_amount) =
+                fill_totals(filled_amount, order.cummulative_quote_qty.parse::<f64>()?);
+
+            history.push(HistoricalOrder {
+                order_id: order.client_order_id.clone(),
+                creation_time: Utc.timestamp_millis(order.time as i64),
+                client_order_id: Some(order.client_order_id),
+                status: OrderStatus {
+                    open: ["NEW", "PARTIALLY_FILLED"].contains(&order.status.as_str()),
+                    side: match order.side.as_str() {
+                        "SELL" => OrderSide::Sell,
+                        "BUY" => OrderSide::Buy,
+                        side => return Err(format!("Invalid order side: {side}").into()),
+                    },
+                    price: order.price.parse::<f64>()?,
+                    amount: order.orig_qty.parse::<f64>()?,
+                    filled_amount,
+                    average_fill_price,
+                    cumulative_quote_amount,
+                    last_update: Utc
+                        .timestamp_millis(order.update_time as i64)
+                        .date()
+                        .naive_local(),
+                    fee,
+                    cancellation: order_cancellation(&order.status, &order.time_in_force),
+                },
+            });
+        }
+        Ok(history)
+    }
+
     async fn get_lending_info(
         &self,
-        _coin: &str,
+        coin: &str,
     ) -> Result<Option<LendingInfo>, Box<dyn std::error::Error>> {
-        Err("Lending not currently supported for Binance".into())
+        Ok(self.all_lending_info().await?.remove(coin))
+    }
+
+    async fn all_lending_info(
+        &self,
+    ) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>> {
+        if self.exchange == Exchange::BinanceUs {
+            return Err("Lending not currently supported for BinanceUs".into());
+        }
+        flexible_lending_info(
+            self.flexible_products().await?,
+            &self.spot_balances().await?,
+            &self.earn_balances(false).await?,
+            &self.earn_balances(true).await?,
+        )
     }
 
     async fn get_lending_history(
@@ -380,8 +2243,254 @@ impl ExchangeClient for BinanceExchangeClient {
         Err("Lending not currently supported for Binance".into())
     }
 
-    fn preferred_solusd_pair(&self) -> &'static str {
-        self.preferred_solusd_pair
+    // Redeems flexible Simple Earn positions into the spot wallet.  Locked positions can't be
+    // redeemed before they mature
+    async fn redeem_lending(
+        &self,
+        coin: &str,
+        amount: f64,
+    ) -> Result<LendingRedemption, Box<dyn std::error::Error>> {
+        const ENDPOINT: &str = "/sapi/v1/simple-earn/flexible/position";
+        if self.exchange == Exchange::BinanceUs {
+            return Err("Lending not currently supported for BinanceUs".into());
+        }
+
+        let positions = self
+            .signed_get::<EarnPositions>(
+                ENDPOINT,
+                &[("asset", coin.to_string()), ("size", "100".into())],
+            )
+            .await?
+            .rows;
+        let mut redeemed = 0.;
+        for position in positions
+            .into_iter()
+            .filter(|position| position.asset == coin)
+        {
+            if redeemed >= amount {
+                break;
+            }
+            let product_id = position
+                .product_id
+                .ok_or_else(|| format!("{ENDPOINT}: position missing a product id"))?;
+            let total = position
+                .total_amount
+                .ok_or_else(|| format!("{ENDPOINT}: position missing an amount"))?
+                .parse::<f64>()?;
+            let redemption = total.min(amount - redeemed);
+
+            let mut params = vec![("productId", product_id), ("destAccount", "SPOT".into())];
+            if redemption < total {
+                params.push(("amount", redemption.to_string()));
+            } else {
+                params.push(("redeemAll", "true".into()));
+            }
+            self.signed_request::<serde_json::Value>(
+                reqwest::Method::POST,
+                "/sapi/v1/simple-earn/flexible/redeem",
+                &params,
+            )
+            .await?;
+            redeemed += redemption;
+        }
+        Ok(LendingRedemption {
+            redeemed,
+            forfeited_interest: None,
+        })
+    }
+
+    fn history_lookback(&self, category: HistoryCategory) -> Option<chrono::Duration> {
+        (category == HistoryCategory::Interest)
+            .then(|| chrono::Duration::days(EARN_REWARDS_LOOKBACK_DAYS))
+    }
+
+    async fn account_history(
+        &self,
+        category: HistoryCategory,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        let mut entries = match category {
+            HistoryCategory::Deposits => self.deposit_history(start, end).await?,
+            HistoryCategory::Withdrawals => self.withdrawal_history(start, end).await?,
+            HistoryCategory::Trades => self.trade_history(start, end).await?,
+            HistoryCategory::Conversions if self.exchange == Exchange::Binance => {
+                self.conversion_history(start, end).await?
+            }
+            HistoryCategory::Interest if self.exchange == Exchange::Binance => {
+                self.earn_reward_history(start, end).await?
+            }
+            _ => {
+                return Err(format!(
+                    "Backfilling {category} not supported by {:?}",
+                    self.exchange
+                )
+                .into())
+            }
+        };
+        entries.sort_by_key(|entry| entry.time);
+        Ok(entries)
+    }
+
+    fn preferred_solusd_pair(&self) -> TradingPair {
+        if self.exchange == Exchange::BinanceUs {
+            TradingPair::new("SOL", "USD")
+        } else {
+            TradingPair::new("SOL", "BUSD")
+        }
+    }
+
+    fn maker_fee_rate(&self) -> f64 {
+        0.001
+    }
+
+    fn taker_fee_rate(&self) -> f64 {
+        0.001
+    }
+
+    async fn fee_schedule(&self) -> Result<FeeSchedule, Box<dyn std::error::Error>> {
+        let bnb_fee_discount = self.bnb_fee_discount_enabled().await?;
+        let discount = if bnb_fee_discount {
+            1. - BNB_FEE_DISCOUNT
+        } else {
+            1.
+        };
+        Ok(FeeSchedule {
+            maker_rate: self.maker_fee_rate() * discount,
+            taker_rate: self.taker_fee_rate() * discount,
+            discount: bnb_fee_discount.then(|| "fees paid in BNB".into()),
+        })
+    }
+
+    async fn stream_token(&self) -> Result<String, Box<dyn std::error::Error>> {
+        self.stream_session
+            .token(|expiring_session| async move {
+                let endpoint = "/api/v3/userDataStream";
+                let listen_key = match expiring_session {
+                    // Keeping the key alive extends it, and open streams stay subscribed
+                    Some(session) => match self
+                        .api_key_request::<serde_json::Value>(
+                            reqwest::Method::PUT,
+                            endpoint,
+                            &[("listenKey", session.token.clone())],
+                        )
+                        .await
+                    {
+                        Ok(_) => session.token,
+                        Err(err) => {
+                            log::warn!("{:?} listen key keepalive failed: {err}", self.exchange);
+                            self.api_key_request::<ListenKey>(reqwest::Method::POST, endpoint, &[])
+                                .await?
+                                .listen_key
+                        }
+                    },
+                    None => {
+                        self.api_key_request::<ListenKey>(reqwest::Method::POST, endpoint, &[])
+                            .await?
+                            .listen_key
+                    }
+                };
+                Ok(StreamSession {
+                    token: listen_key,
+                    expires_at: Utc::now() + chrono::Duration::minutes(LISTEN_KEY_VALIDITY_MINUTES),
+                })
+            })
+            .await
+    }
+
+    fn invalidate_stream_token(&self) {
+        self.stream_session.invalidate();
+    }
+
+    async fn volume_tier_info(&self) -> Result<VolumeTierInfo, Box<dyn std::error::Error>> {
+        let commission = self
+            .signed_get::<BinanceAccountCommission>("/api/v3/account", &[])
+            .await?;
+
+        // The API doesn't report 30-day volume, so it's summed from the SOL markets `sys` trades
+        let end = Utc::now();
+        let start = end - chrono::Duration::days(30);
+        let mut volume_30d = 0.;
+        for pair in self.trading_pairs().await? {
+            if pair.base != "SOL" || !is_usd_equivalent(&pair.quote) {
+                continue;
+            }
+            for order in self
+                .all_orders(&pair.to_exchange_symbol(self.exchange), start, end)
+                .await?
+            {
+                volume_30d += order.cummulative_quote_qty.parse::<f64>()?;
+            }
+        }
+
+        // Binance US does not publish its tiers in a form that can be tracked here
+        let next_tier = if self.exchange == Exchange::Binance {
+            VOLUME_TIERS
+                .iter()
+                .find(|(volume_threshold, _, _)| *volume_threshold > volume_30d)
+                .map(|(volume_threshold, maker_rate, taker_rate)| VolumeTier {
+                    volume_threshold: *volume_threshold,
+                    maker_rate: *maker_rate,
+                    taker_rate: *taker_rate,
+                })
+        } else {
+            None
+        };
+
+        Ok(VolumeTierInfo {
+            volume_30d,
+            maker_rate: f64::from(commission.maker_commission) / 10_000.,
+            taker_rate: f64::from(commission.taker_commission) / 10_000.,
+            next_tier,
+        })
+    }
+
+    async fn pair_infos(&self) -> Result<Vec<PairInfo>, Box<dyn std::error::Error>> {
+        Ok(self
+            .pair_infos
+            .get_or_try_init(|| async {
+                // Best effort, the markets are still listed without their delisting dates
+                let delist_times = self.delist_times().await.unwrap_or_else(|err| {
+                    log::debug!("{:?} delist schedule unavailable: {err}", self.exchange);
+                    HashMap::default()
+                });
+                self.throttle("/api/v3/exchangeInfo").await;
+                Ok::<_, Box<dyn std::error::Error>>(
+                    self.sdk_request("/api/v3/exchangeInfo", self.general.exchange_info())
+                        .await?
+                        .symbols
+                        .into_iter()
+                        .map(|symbol| {
+                            let mut pair_info = PairInfo {
+                                pair: TradingPair::new(&symbol.base_asset, &symbol.quote_asset),
+                                tick_size: None,
+                                step_size: None,
+                                min_notional: None,
+                                status: symbol_status(&symbol.status),
+                                delist_time: delist_times.get(&symbol.symbol).copied(),
+                            };
+                            for filter in symbol.filters {
+                                match filter {
+                                    binance::rest_model::Filters::PriceFilter {
+                                        tick_size, ..
+                                    } => pair_info.tick_size = Some(tick_size),
+                                    binance::rest_model::Filters::LotSize { step_size, .. } => {
+                                        pair_info.step_size = Some(step_size)
+                                    }
+                                    binance::rest_model::Filters::MinNotional {
+                                        min_notional,
+                                        ..
+                                    } => pair_info.min_notional = min_notional,
+                                    _ => {}
+                                }
+                            }
+                            pair_info
+                        })
+                        .collect(),
+                )
+            })
+            .await?
+            .clone())
     }
 }
 
@@ -391,19 +2500,43 @@ fn _new(
         secret,
         subaccount,
     }: ExchangeCredentials,
+    options: ExchangeClientOptions,
     binance_us: bool,
 ) -> Result<BinanceExchangeClient, Box<dyn std::error::Error>> {
+    let exchange = if binance_us {
+        Exchange::BinanceUs
+    } else {
+        Exchange::Binance
+    };
+    // No proxy, as binance-rs-async builds an HTTP client of its own that can't be configured
+    options.check_supported(
+        exchange,
+        &["sandbox", "base_url", "timeout", "connect_timeout"],
+    )?;
     if subaccount.is_some() {
         return Err("subaccounts not supported".into());
     }
 
-    let config = binance::config::Config {
-        rest_api_endpoint: if binance_us {
-            "https://api.binance.us"
-        } else {
-            "https://api.binance.com"
+    let http_client = options.http_client()?;
+    let request_timeout = options.request_timeout();
+    let rate_limiter = RateLimiter::shared(exchange, &api_key, options.rate_limit_dir.as_deref());
+    let stream_session =
+        StreamSessionStore::shared(exchange, &api_key, options.stream_session_dir.as_deref());
+
+    let rest_api_endpoint = match options.base_url {
+        Some(base_url) => base_url,
+        None if options.sandbox => {
+            if binance_us {
+                return Err("Binance US has no sandbox".into());
+            }
+            "https://testnet.binance.vision".to_string()
         }
-        .into(),
+        None if binance_us => "https://api.binance.us".to_string(),
+        None => "https://api.binance.com".to_string(),
+    };
+
+    let config = binance::config::Config {
+        rest_api_endpoint: rest_api_endpoint.clone(),
         binance_us_api: binance_us,
         ..binance::config::Config::default()
     };
@@ -414,30 +2547,239 @@ fn _new(
         &config,
     );
 
+    let general = binance::api::Binance::new_with_config(None, None, &config);
+
     let market = binance::api::Binance::new_with_config(
         Some(api_key.clone()),
         Some(secret.clone()),
         &config,
     );
-    let wallet: binance::wallet::Wallet =
-        binance::api::Binance::new_with_config(Some(api_key), Some(secret), &config);
+    let wallet: binance::wallet::Wallet = binance::api::Binance::new_with_config(
+        Some(api_key.clone()),
+        Some(secret.clone()),
+        &config,
+    );
 
     Ok(BinanceExchangeClient {
         account,
+        general,
         market,
         wallet,
-        preferred_solusd_pair: if binance_us { "SOLUSD" } else { "SOLBUSD" },
+        exchange,
+        rest_api_endpoint,
+        api_key,
+        secret,
+        // Report BNB fees in USD, valued at the time of the trade
+        bnb_fees_in_usd: settings::enabled(Setting::BinanceBnbFeesInUsd),
+        wallet_warning_shown: AtomicBool::new(false),
+        pair_infos: OnceCell::new(),
+        rate_limiter,
+        stream_session,
+        http_client,
+        request_timeout,
     })
 }
 
 pub fn new(
     exchange_credentials: ExchangeCredentials,
+    options: ExchangeClientOptions,
 ) -> Result<BinanceExchangeClient, Box<dyn std::error::Error>> {
-    _new(exchange_credentials, false)
+    _new(exchange_credentials, options, false)
 }
 
 pub fn new_us(
     exchange_credentials: ExchangeCredentials,
+    options: ExchangeClientOptions,
 ) -> Result<BinanceExchangeClient, Box<dyn std::error::Error>> {
-    _new(exchange_credentials, true)
+    _new(exchange_credentials, options, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The coin config lists SOL's Solana network, and the withdrawal matches only if it names it
+    const SOL_WITHDRAWAL: &str = r#"
+==> 2024-03-21T15:04:21Z GET https://api.binance.com/sapi/v1/capital/config/getall?timestamp=1
+X-MBX-APIKEY: <redacted>
+<== 200 in 102ms
+[{"coin":"SOL","depositAllEnable":true,"free":"10.5","freeze":"0","ipoable":"0","ipoing":"0",
+"isLegalMoney":false,"locked":"0","name":"Solana","storage":"0","trading":true,
+"withdrawAllEnable":true,"withdrawing":"0","networkList":[{
+"addressRegex":"^[1-9A-HJ-NP-Za-km-z]{32,44}$","coin":"SOL","depositDesc":"","depositEnable":true,
+"isDefault":true,"memoRegex":"","minConfirm":1,
+"name":"Solana","network":"SOL","resetAddressStatus":false,"specialTips":"","unLockConfirm":0,
+"withdrawDesc":"","withdrawEnable":true,"withdrawFee":"0.01","withdrawIntegerMultiple":"0.00000001",
+"withdrawMax":"9999999","withdrawMin":"0.1","sameAddress":false,"estimatedArrivalTime":1,
+"busy":false,"contractAddressUrl":"","contractAddress":""}]}]
+
+==> 2024-03-21T15:04:22Z POST https://api.binance.com/sapi/v1/capital/withdraw/apply
+X-MBX-APIKEY: <redacted>
+
+coin=SOL&network=SOL&withdrawOrderId=sys-withdraw
+<== 200 in 98ms
+{"id":"7213fea8e94b4a5593d507237e5a555b"}
+"#;
+
+    #[tokio::test]
+    async fn withdrawals_name_the_solana_network() {
+        let server = crate::conformance::ReplayServer::start(SOL_WITHDRAWAL)
+            .await
+            .unwrap();
+        let client = crate::conformance::replay_client(Exchange::Binance, &server).unwrap();
+        let address = Pubkey::from_str("CiwjEStzDKR8j1uQgVvU6hFtt8thAK3aZMDN3X6yzW5q").unwrap();
+
+        let (withdraw_id, withdraw_fee) = client
+            .request_withdraw(
+                address,
+                MaybeToken::SOL(),
+                1_000_000_000,
+                None,
+                None,
+                "sys-withdraw",
+            )
+            .await
+            .unwrap();
+        assert_eq!(withdraw_id, "sys-withdraw");
+        assert_eq!(withdraw_fee, 0.01);
+        assert_eq!(server.unexpected_requests(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn error_bodies_give_the_native_code() {
+        let err = api_error(
+            Exchange::Binance,
+            "/api/v3/order",
+            400,
+            r#"{"code":-2010,"msg":"Account has insufficient balance for requested action."}"#,
+        );
+        assert_eq!(
+            err.to_string(),
+            "Binance /api/v3/order: -2010: Account has insufficient balance for requested \
+             action. (HTTP 400)"
+        );
+
+        let err = api_error(Exchange::BinanceUs, "/api/v3/account", 502, "Bad Gateway");
+        assert_eq!(
+            err.to_string(),
+            "BinanceUs /api/v3/account: unknown: Bad Gateway (HTTP 502)"
+        );
+    }
+
+    // USDT trading 2% below the dollar, at 0.9790 bid and 0.9810 offered
+    const USDT_DEPEG: &str = r#"
+==> 2024-03-21T15:04:10Z GET /sapi/v1/spot/delist-schedule?timestamp=1
+X-MBX-APIKEY: <redacted>
+<== 200 in 115ms
+[]
+
+==> 2024-03-21T15:04:11Z GET /api/v3/exchangeInfo
+<== 200 in 122ms
+{"timezone":"UTC","serverTime":1711033445000,"rateLimits":[],"exchangeFilters":[],"symbols":[{
+"symbol":"USDTUSD","status":"TRADING","baseAsset":"USDT","baseAssetPrecision":8,
+"quoteAsset":"USD","quotePrecision":4,"quoteAssetPrecision":4,"baseCommissionPrecision":8,
+"quoteCommissionPrecision":2,"orderTypes":["LIMIT","LIMIT_MAKER","MARKET"],
+"icebergAllowed":true,"ocoAllowed":true,"quoteOrderQtyMarketAllowed":true,
+"allowTrailingStop":false,"cancelReplaceAllowed":false,"isSpotTradingAllowed":true,
+"isMarginTradingAllowed":false,"filters":[],"permissions":["SPOT"]}]}
+
+==> 2024-03-21T15:04:12Z GET /api/v3/ticker/24hr?symbol=USDTUSD
+<== 200 in 129ms
+{"symbol":"USDTUSD","priceChange":"-0.02000000","priceChangePercent":"-1.996",
+"weightedAvgPrice":"0.98650000","prevClosePrice":"1.00020000","lastPrice":"0.98000000",
+"lastQty":"500.00000000","bidPrice":"0.97900000","bidQty":"25000.00000000",
+"askPrice":"0.98100000","askQty":"18000.00000000","openPrice":"1.00000000",
+"highPrice":"1.00030000","lowPrice":"0.97500000","volume":"8120934.00000000",
+"quoteVolume":"8011300.20000000","openTime":1710947045000,"closeTime":1711033445000,
+"firstId":1203,"lastId":9981,"count":8779}
+"#;
+
+    #[tokio::test]
+    async fn a_usdt_depeg_is_valued_at_the_market_rate() {
+        let server = crate::conformance::ReplayServer::start(USDT_DEPEG)
+            .await
+            .unwrap();
+        let client = crate::conformance::replay_client(Exchange::BinanceUs, &server).unwrap();
+
+        let rate = quote_usd_rate(client.as_ref(), "USDT").await.unwrap();
+        assert!((rate.rate - 0.98).abs() < 1e-9, "{rate:?}");
+        assert!(!rate.assumed);
+        assert_eq!(rate.source, QuoteUsdRateSource::Market);
+        // 1,000 USDT is reported as $980, not the $1,000 parity would give
+        assert!((1_000. * rate.rate - 980.).abs() < 1e-6);
+        assert_eq!(quote_label("USDT", Some(rate)), "USDT, ≈$0.9800");
+        assert_eq!(server.unexpected_requests(), Vec::<String>::new());
+    }
+
+    // A post-only sell of 2 SOL that fills 0.5 at 500 and 0.5 at 502 before it's cancelled
+    const PARTIALLY_FILLED_THEN_CANCELLED: &str = r#"
+==> 2024-03-21T15:04:18Z GET /api/v3/order?symbol=SOLBUSD&origClientOrderId=sys-order&timestamp=1
+X-MBX-APIKEY: <redacted>
+<== 200 in 81ms
+{"symbol":"SOLBUSD","orderId":1001,"orderListId":-1,"clientOrderId":"sys-order",
+"price":"500.00000000","origQty":"2.00000000","executedQty":"1.00000000",
+"cummulativeQuoteQty":"501.00000000","status":"CANCELED","timeInForce":"GTC",
+"type":"LIMIT_MAKER","side":"SELL","stopPrice":"0.00000000","icebergQty":"0.00000000",
+"time":1711033446000,"updateTime":1711033452000,"isWorking":true,
+"origQuoteOrderQty":"0.00000000"}
+
+==> 2024-03-21T15:04:19Z GET /api/v3/myTrades?symbol=SOLBUSD&orderId=1001&timestamp=1
+X-MBX-APIKEY: <redacted>
+<== 200 in 88ms
+[{"symbol":"SOLBUSD","id":5001,"orderId":1001,"orderListId":-1,"price":"500.00000000",
+"qty":"0.50000000","quoteQty":"250.00000000","commission":"0.25000000","commissionAsset":"BUSD",
+"time":1711033450000,"isBuyer":false,"isMaker":true,"isBestMatch":true},
+{"symbol":"SOLBUSD","id":5002,"orderId":1001,"orderListId":-1,"price":"502.00000000",
+"qty":"0.50000000","quoteQty":"251.00000000","commission":"0.25100000","commissionAsset":"BUSD",
+"time":1711033451000,"isBuyer":false,"isMaker":true,"isBestMatch":true}]
+"#;
+
+    #[tokio::test]
+    async fn a_partially_filled_then_cancelled_order_averages_its_fills() {
+        let server = crate::conformance::ReplayServer::start(PARTIALLY_FILLED_THEN_CANCELLED)
+            .await
+            .unwrap();
+        let client = crate::conformance::replay_client(Exchange::Binance, &server).unwrap();
+
+        let order_status = client
+            .order_status(&TradingPair::new("SOL", "BUSD"), &"sys-order".to_string())
+            .await
+            .unwrap();
+        assert!(!order_status.open);
+        assert_eq!(order_status.cancellation, Some(OrderCancellation::User));
+        assert_eq!(order_status.price, 500.);
+        assert_eq!(order_status.filled_amount, 1.);
+        assert_eq!(order_status.average_fill_price, Some(501.));
+        assert_eq!(order_status.cumulative_quote_amount, Some(501.));
+        let (fee, fee_coin) = order_status.fee.unwrap();
+        assert!((fee - 0.501).abs() < 1e-9);
+        assert_eq!(fee_coin, "USD");
+        assert_eq!(server.unexpected_requests(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn flexible_products_are_reported_as_lending() {
+        let products = serde_json::from_str::<FlexibleProducts>(
+            r#"{"rows":[
+            {"asset":"USDC","latestAnnualPercentageRate":"0.0525","canPurchase":true},
+            {"asset":"SOL","latestAnnualPercentageRate":"0.021","canPurchase":true},
+            {"asset":"BTC","latestAnnualPercentageRate":"0.003","canPurchase":true}
+            ],"total":3}"#,
+        )
+        .unwrap()
+        .rows;
+        let balance = |available, total| ExchangeBalance { available, total };
+        let spot = HashMap::from([("SOL".to_string(), balance(4., 5.))]);
+        let flexible = HashMap::from([("USDC".to_string(), balance(100., 100.))]);
+        let locked = HashMap::from([("SOL".to_string(), balance(0., 10.))]);
+
+        let all_lending_info = flexible_lending_info(products, &spot, &flexible, &locked).unwrap();
+        // Nothing is held of BTC
+        assert_eq!(all_lending_info.len(), 2);
+        let sol = &all_lending_info["SOL"];
+        assert_eq!((sol.lendable, sol.offered, sol.locked), (4., 0., 10.));
+        let usdc = &all_lending_info["USDC"];
+        assert_eq!((usdc.lendable, usdc.offered, usdc.locked), (0., 100., 0.));
+        assert!((usdc.estimate_rate - 5.25).abs() < 1e-9);
+    }
 }
@@ -0,0 +1,82 @@
+use {
+    std::time::{Duration, Instant},
+    thiserror::Error,
+    tokio::sync::watch,
+};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Interrupted {
+    #[error("Interrupted")]
+    Cancelled,
+
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+}
+
+/// Lets long-running loops stop between steps when the user presses Ctrl-C or an overall deadline
+/// passes, instead of being killed mid-operation
+#[derive(Clone)]
+pub struct Cancellation {
+    cancelled: watch::Receiver<bool>,
+    deadline: Option<Instant>,
+}
+
+impl Cancellation {
+    /// Cancelled by the first Ctrl-C.  Only call this for operations that check for cancellation,
+    /// as the first Ctrl-C no longer kills the process
+    pub fn on_ctrl_c() -> Self {
+        let (sender, cancelled) = watch::channel(false);
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Interrupted, stopping at the next safe point...");
+                let _ = sender.send(true);
+                // A second Ctrl-C exits immediately
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    std::process::exit(130);
+                }
+            }
+        });
+        Self {
+            cancelled,
+            deadline: None,
+        }
+    }
+
+    /// Also expires `timeout` from now, or at the existing deadline if that's sooner
+    pub fn with_timeout(&self, timeout: Duration) -> Self {
+        let deadline = Instant::now() + timeout;
+        Self {
+            cancelled: self.cancelled.clone(),
+            deadline: Some(match self.deadline {
+                Some(existing_deadline) => existing_deadline.min(deadline),
+                None => deadline,
+            }),
+        }
+    }
+
+    pub fn check(&self) -> Result<(), Interrupted> {
+        if *self.cancelled.borrow() {
+            return Err(Interrupted::Cancelled);
+        }
+        if matches!(self.deadline, Some(deadline) if Instant::now() >= deadline) {
+            return Err(Interrupted::DeadlineExceeded);
+        }
+        Ok(())
+    }
+
+    /// Sleeps for `duration`, waking early if cancelled or the deadline passes
+    pub async fn sleep(&self, duration: Duration) -> Result<(), Interrupted> {
+        self.check()?;
+        let duration = match self.deadline {
+            Some(deadline) => duration.min(deadline.saturating_duration_since(Instant::now())),
+            None => duration,
+        };
+
+        let mut cancelled = self.cancelled.clone();
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            Ok(()) = cancelled.changed() => {}
+        }
+        self.check()
+    }
+}
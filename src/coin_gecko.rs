@@ -10,6 +10,9 @@ use {
 #[derive(Debug, Serialize, Deserialize)]
 struct CurrencyList {
     usd: f64,
+    // Unix time, only requested of current prices
+    #[serde(default)]
+    last_updated_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,7 +63,16 @@ fn get_cg_pro_api_key() -> (&'static str, String) {
 }
 
 pub async fn get_current_price(token: &MaybeToken) -> Result<Decimal, Box<dyn std::error::Error>> {
-    type CurrentPriceCache = HashMap<MaybeToken, Decimal>;
+    get_current_price_and_time(token)
+        .await
+        .map(|(price, _)| price)
+}
+
+/// The current price along with when Coin Gecko last updated it, if reported
+pub async fn get_current_price_and_time(
+    token: &MaybeToken,
+) -> Result<(Decimal, Option<DateTime<Utc>>), Box<dyn std::error::Error>> {
+    type CurrentPriceCache = HashMap<MaybeToken, (Decimal, Option<DateTime<Utc>>)>;
     lazy_static::lazy_static! {
         static ref CURRENT_PRICE_CACHE: Arc<RwLock<CurrentPriceCache>> = Arc::new(RwLock::new(HashMap::new()));
     }
@@ -73,7 +85,7 @@ pub async fn get_current_price(token: &MaybeToken) -> Result<Decimal, Box<dyn st
 
             let (maybe_pro, x_cg_pro_api_key) = get_cg_pro_api_key();
             let url = format!(
-                "https://{maybe_pro}api.coingecko.com/api/v3/simple/price?ids={coin}&vs_currencies=usd{x_cg_pro_api_key}"
+                "https://{maybe_pro}api.coingecko.com/api/v3/simple/price?ids={coin}&vs_currencies=usd&include_last_updated_at=true{x_cg_pro_api_key}"
             );
 
             #[derive(Debug, Serialize, Deserialize)]
@@ -98,7 +110,12 @@ pub async fn get_current_price(token: &MaybeToken) -> Result<Decimal, Box<dyn st
                 .or(coins.bsol)
                 .ok_or_else(|| format!("Simple price data not available for {coin}").into())
                 .map(|price| {
-                    let price = Decimal::from_f64(price.usd).unwrap();
+                    let price = (
+                        Decimal::from_f64(price.usd).unwrap(),
+                        price
+                            .last_updated_at
+                            .map(|last_updated_at| Utc.timestamp(last_updated_at, 0)),
+                    );
                     current_price_cache.insert(*token, price);
                     price
                 })
@@ -146,3 +163,83 @@ pub async fn get_historical_price(
         }
     }
 }
+
+fn stablecoin_to_coin(symbol: &str) -> Option<&'static str> {
+    Some(match symbol {
+        "USDC" => "usd-coin",
+        "USDT" => "tether",
+        "BUSD" => "binance-usd",
+        "FDUSD" => "first-digital-usd",
+        "PYUSD" => "paypal-usd",
+        "DAI" => "dai",
+        "UXD" => "uxd-stablecoin",
+        _ => return None,
+    })
+}
+
+/// Current USD price of a dollar-denominated coin such as USDT, identified by its exchange symbol
+pub async fn get_current_stablecoin_price(symbol: &str) -> Result<f64, Box<dyn std::error::Error>> {
+    type StablecoinPriceCache = HashMap<String, f64>;
+    lazy_static::lazy_static! {
+        static ref STABLECOIN_PRICE_CACHE: Arc<RwLock<StablecoinPriceCache>> = Arc::new(RwLock::new(HashMap::new()));
+    }
+    let mut stablecoin_price_cache = STABLECOIN_PRICE_CACHE.write().await;
+
+    match stablecoin_price_cache.get(symbol) {
+        Some(price) => Ok(*price),
+        None => {
+            let coin = stablecoin_to_coin(symbol)
+                .ok_or_else(|| format!("Coin Gecko price data not available for {symbol}"))?;
+
+            let (maybe_pro, x_cg_pro_api_key) = get_cg_pro_api_key();
+            let url = format!(
+                "https://{maybe_pro}api.coingecko.com/api/v3/simple/price?ids={coin}&vs_currencies=usd{x_cg_pro_api_key}"
+            );
+
+            reqwest::get(url)
+                .await?
+                .json::<HashMap<String, CurrencyList>>()
+                .await?
+                .remove(coin)
+                .ok_or_else(|| format!("Simple price data not available for {coin}").into())
+                .map(|price| {
+                    stablecoin_price_cache.insert(symbol.to_string(), price.usd);
+                    price.usd
+                })
+        }
+    }
+}
+
+/// USD value of one unit of a fiat currency such as EUR, crossed through the Bitcoin price
+pub async fn get_current_fiat_usd_rate(fiat: &str) -> Result<f64, Box<dyn std::error::Error>> {
+    type FiatRateCache = HashMap<String, f64>;
+    lazy_static::lazy_static! {
+        static ref FIAT_RATE_CACHE: Arc<RwLock<FiatRateCache>> = Arc::new(RwLock::new(HashMap::new()));
+    }
+    let mut fiat_rate_cache = FIAT_RATE_CACHE.write().await;
+
+    if let Some(rate) = fiat_rate_cache.get(fiat) {
+        return Ok(*rate);
+    }
+
+    let vs_currency = fiat.to_ascii_lowercase();
+    let (maybe_pro, x_cg_pro_api_key) = get_cg_pro_api_key();
+    let url = format!(
+        "https://{maybe_pro}api.coingecko.com/api/v3/simple/price?ids=bitcoin&vs_currencies=usd,{vs_currency}{x_cg_pro_api_key}"
+    );
+
+    let prices = reqwest::get(url)
+        .await?
+        .json::<HashMap<String, HashMap<String, f64>>>()
+        .await?
+        .remove("bitcoin")
+        .ok_or("Simple price data not available for bitcoin")?;
+    match (prices.get("usd"), prices.get(&vs_currency)) {
+        (Some(usd), Some(fiat_price)) if *fiat_price > 0. => {
+            let rate = usd / fiat_price;
+            fiat_rate_cache.insert(fiat.to_string(), rate);
+            Ok(rate)
+        }
+        _ => Err(format!("Coin Gecko exchange rate not available for {fiat}").into()),
+    }
+}
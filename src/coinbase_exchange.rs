@@ -1,69 +1,831 @@
 use {
-    crate::{exchange::*, token::MaybeToken},
+    crate::{
+        debug_capture::{CapturedRequest, CapturedResponse, DebugCapture},
+        exchange::*,
+        rate_limit::{RateLimit, RateLimiter},
+        request_metrics,
+        token::{MaybeToken, Token},
+        two_factor::TwoFactorCodeProvider,
+    },
     async_trait::async_trait,
-    futures::{pin_mut, stream::StreamExt},
+    chrono::{DateTime, Utc},
+    hmac::{Hmac, Mac},
     rust_decimal::prelude::*,
+    serde::{de::DeserializeOwned, Deserialize},
+    sha2::Sha256,
     solana_sdk::pubkey::Pubkey,
-    std::collections::HashMap,
+    std::{
+        collections::{BTreeMap, HashMap},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    },
+    tokio::sync::OnceCell,
 };
 
+const API_URL: &str = "https://api.coinbase.com";
+const API_VERSION: &str = "2023-01-01";
+
+// Public market data is served by the Exchange API rather than the retail API
+const EXCHANGE_API_URL: &str = "https://api.exchange.coinbase.com";
+
+// Summary of the status page, which covers outages and scheduled maintenance
+const STATUS_URL: &str = "https://status.coinbase.com/api/v2/status.json";
+
+// Coinbase's network identifier for Solana and SPL tokens
+const SOLANA_NETWORK: &str = "solana";
+
+// Advanced Trade allows 30 private requests a second, separately from the retail API's budget
+const ADVANCED_TRADE_RATE_LIMIT: RateLimit = RateLimit {
+    weight: 25,
+    window: Duration::from_secs(1),
+};
+
+// Subaccount name for the retail wallets of the consumer product, as opposed to an Advanced Trade
+// portfolio
+const RETAIL_WALLETS: &str = "retail";
+
+fn is_retail_wallets(subaccount: &str) -> bool {
+    subaccount.eq_ignore_ascii_case(RETAIL_WALLETS)
+}
+
 pub struct CoinbaseExchangeClient {
-    client: coinbase_rs::Private,
+    api_url: String,
+    exchange_api_url: String,
+    http_client: reqwest::Client,
+    api_key: String,
+    secret: String,
+    portfolio: Option<String>,
+    portfolio_uuid: OnceCell<String>,
+    pair_infos: OnceCell<Vec<PairInfo>>,
+    debug_capture: Option<DebugCapture>,
+    // Paces the retail API, which deposits and withdrawals go through
+    rate_limiter: Arc<RateLimiter>,
+    // Paces the Advanced Trade API, which portfolios go through
+    trading_rate_limiter: Arc<RateLimiter>,
+    wallet_warning_shown: AtomicBool,
+}
+
+#[derive(Deserialize)]
+struct Portfolios {
+    portfolios: Vec<Portfolio>,
+}
+
+#[derive(Deserialize)]
+struct Portfolio {
+    name: String,
+    uuid: String,
+    #[serde(rename = "type")]
+    portfolio_type: String,
+    #[serde(default)]
+    deleted: bool,
+}
+
+#[derive(Deserialize)]
+struct PortfolioBreakdownResponse {
+    breakdown: PortfolioBreakdown,
+}
+
+#[derive(Deserialize)]
+struct PortfolioBreakdown {
+    spot_positions: Vec<SpotPosition>,
+}
+
+#[derive(Deserialize)]
+struct SpotPosition {
+    asset: String,
+    total_balance_crypto: f64,
+    available_to_trade_crypto: f64,
+}
+
+#[derive(Deserialize)]
+struct BrokerageAccounts {
+    accounts: Vec<BrokerageAccount>,
+    #[serde(default)]
+    has_next: bool,
+    cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BrokerageAccount {
+    uuid: String,
+    currency: String,
+}
+
+#[derive(Deserialize)]
+struct KeyPermissions {
+    can_trade: bool,
+    can_transfer: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct CoinbaseProduct {
+    base_currency: String,
+    quote_currency: String,
+    quote_increment: String,
+    base_increment: String,
+    min_market_funds: Option<String>,
+    status: String,
+    #[serde(default)]
+    trading_disabled: bool,
+    #[serde(default)]
+    cancel_only: bool,
+    #[serde(default)]
+    post_only: bool,
+}
+
+impl CoinbaseProduct {
+    fn system_status(&self) -> SystemStatus {
+        if self.status != "online" || self.trading_disabled {
+            SystemStatus::Maintenance
+        } else if self.cancel_only {
+            SystemStatus::CancelOnly
+        } else if self.post_only {
+            SystemStatus::PostOnly
+        } else {
+            SystemStatus::Online
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CoinbaseResponse<T> {
+    data: T,
+}
+
+// v2 endpoints report `{"errors":[{"id":"...","message":"..."}]}`, while v3 endpoints report
+// `{"error":"...","message":"..."}`
+#[derive(Deserialize)]
+struct CoinbaseErrorBody {
+    #[serde(default)]
+    errors: Vec<CoinbaseErrorDetail>,
+    error: Option<String>,
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseErrorDetail {
+    id: String,
+    message: String,
+}
+
+fn api_error(path: &str, http_status: u16, body: &str) -> ExchangeError {
+    let (code, message) = match serde_json::from_str::<CoinbaseErrorBody>(body) {
+        Ok(CoinbaseErrorBody { errors, .. }) if !errors.is_empty() => (
+            errors[0].id.clone(),
+            errors
+                .into_iter()
+                .map(|error| error.message)
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        Ok(CoinbaseErrorBody {
+            error: Some(error),
+            message,
+            ..
+        }) => (error, message.unwrap_or_default()),
+        _ => ("unknown".into(), body.to_string()),
+    };
+    ExchangeError::Api {
+        exchange: Exchange::Coinbase,
+        endpoint: path.into(),
+        http_status: Some(http_status),
+        code,
+        message,
+    }
+}
+
+#[derive(Deserialize)]
+struct CoinbasePage<T> {
+    data: Vec<T>,
+    pagination: CoinbasePagination,
+}
+
+#[derive(Deserialize)]
+struct CoinbasePagination {
+    next_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseTransaction {
+    id: String,
+    #[serde(rename = "type")]
+    transaction_type: String,
+    status: String,
+    created_at: DateTime<Utc>,
+    idem: Option<String>,
+    amount: CoinbaseAmount,
+    network: Option<CoinbaseNetwork>,
+    to: Option<CoinbaseDestination>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseAmount {
+    amount: String,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseRetailAccount {
+    id: String,
+    primary: bool,
+    // "wallet", "fiat" or "vault"
+    #[serde(rename = "type")]
+    account_type: String,
+    currency: CoinbaseCurrency,
+    balance: CoinbaseAmount,
+    #[serde(default)]
+    allow_deposits: bool,
+    #[serde(default)]
+    allow_withdrawals: bool,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseAddress {
+    address: String,
+    #[serde(default)]
+    network: String,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseCurrency {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseNetwork {
+    hash: Option<String>,
+    transaction_fee: Option<CoinbaseAmount>,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseDestination {
+    address: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CoinbasePaymentMethod {
+    id: String,
+    name: String,
+    currency: String,
+    #[serde(default)]
+    allow_withdraw: bool,
+}
+
+#[derive(Deserialize)]
+struct CoinbaseWithdrawal {
+    id: String,
+    status: String,
+    amount: CoinbaseAmount,
+    fee: Option<CoinbaseAmount>,
+}
+
+impl CoinbaseExchangeClient {
+    // Public endpoints are idempotent, so a request that times out is sent again
+    async fn public_request<T: DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        request_metrics::instrument(Exchange::Coinbase, path, self.send_public_request(path)).await
+    }
+
+    async fn send_public_request<T: DeserializeOwned>(
+        &self,
+        path: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let url = format!("{}{path}", self.exchange_api_url);
+        let (status, body) = retry_idempotent(|| async {
+            let response = self
+                .http_client
+                .get(&url)
+                .header("User-Agent", "sys")
+                .send()
+                .await
+                .map_err(|err| request_error(Exchange::Coinbase, path, err))?;
+            let status = response.status();
+            let body = response
+                .text()
+                .await
+                .map_err(|err| request_error(Exchange::Coinbase, path, err))?;
+            Ok((status, body))
+        })
+        .await?;
+
+        if !status.is_success() {
+            return Err(api_error(path, status.as_u16(), &body).into());
+        }
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    async fn api_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+        two_factor_code: Option<&str>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        self.api_request_with_timeout(method, path, body, two_factor_code, None)
+            .await
+    }
+
+    // Like `api_request`, but allowing `timeout` instead of the client's request timeout.  GET
+    // requests that time out are signed and sent again
+    async fn api_request_with_timeout<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+        two_factor_code: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let request = || {
+            self.signed_api_request(method.clone(), path, body.clone(), two_factor_code, timeout)
+        };
+        if method == reqwest::Method::GET {
+            retry_idempotent(request).await
+        } else {
+            request().await
+        }
+    }
+
+    async fn signed_api_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+        two_factor_code: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        if path.starts_with("/api/v3/brokerage") {
+            self.trading_rate_limiter.acquire(1).await;
+        } else {
+            self.rate_limiter.acquire(1).await;
+        }
+        request_metrics::instrument(
+            Exchange::Coinbase,
+            path,
+            self.send_signed_api_request(method, path, body, two_factor_code, timeout),
+        )
+        .await
+    }
+
+    async fn send_signed_api_request<T: DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+        two_factor_code: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)?
+            .as_secs()
+            .to_string();
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes())
+            .map_err(|err| format!("Invalid Coinbase secret: {err}"))?;
+        mac.update(format!("{timestamp}{method}{path}{body}").as_bytes());
+        let signature = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        let url = format!("{}{path}", self.api_url);
+        let mut headers = vec![
+            ("CB-ACCESS-KEY", self.api_key.as_str()),
+            ("CB-ACCESS-SIGN", signature.as_str()),
+            ("CB-ACCESS-TIMESTAMP", timestamp.as_str()),
+            ("CB-VERSION", API_VERSION),
+            ("Content-Type", "application/json"),
+        ];
+        if let Some(two_factor_code) = two_factor_code {
+            headers.push(("CB-2FA-TOKEN", two_factor_code));
+        }
+
+        let mut request = self.http_client.request(method.clone(), &url);
+        for (name, value) in &headers {
+            request = request.header(*name, *value);
+        }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let started = Instant::now();
+        let response = request
+            .body(body.clone())
+            .send()
+            .await
+            .map_err(|err| request_error(Exchange::Coinbase, path, err))?;
+        let http_status = response.status();
+        let response_body = response
+            .text()
+            .await
+            .map_err(|err| request_error(Exchange::Coinbase, path, err))?;
+
+        if let Some(debug_capture) = &self.debug_capture {
+            debug_capture.record(
+                &CapturedRequest {
+                    method: method.as_str(),
+                    url: &url,
+                    headers: &headers,
+                    body: &body,
+                },
+                &CapturedResponse {
+                    status: http_status.as_u16(),
+                    body: &response_body,
+                    latency: started.elapsed(),
+                },
+            );
+        }
+
+        if !http_status.is_success() {
+            return Err(api_error(path, http_status.as_u16(), &response_body).into());
+        }
+        Ok(serde_json::from_str::<T>(&response_body)?)
+    }
+
+    async fn portfolios(&self) -> Result<Vec<Portfolio>, Box<dyn std::error::Error>> {
+        Ok(self
+            .api_request::<Portfolios>(
+                reqwest::Method::GET,
+                "/api/v3/brokerage/portfolios",
+                None,
+                None,
+            )
+            .await?
+            .portfolios
+            .into_iter()
+            .filter(|portfolio| !portfolio.deleted)
+            .collect())
+    }
+
+    // Finds a portfolio by name (case-insensitive) or uuid
+    fn find_portfolio<'a>(
+        portfolios: &'a [Portfolio],
+        portfolio: &str,
+    ) -> Result<&'a Portfolio, Box<dyn std::error::Error>> {
+        portfolios
+            .iter()
+            .find(|p| p.uuid == portfolio || p.name.eq_ignore_ascii_case(portfolio))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown Coinbase portfolio: {portfolio}. Available portfolios: {}",
+                    portfolios
+                        .iter()
+                        .map(|p| p.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+                .into()
+            })
+    }
+
+    // The portfolio selected by the credentials' subaccount, or the default portfolio
+    async fn portfolio_uuid(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self
+            .portfolio_uuid
+            .get_or_try_init(|| async {
+                let portfolios = self.portfolios().await?;
+                let portfolio = match &self.portfolio {
+                    Some(portfolio) => Self::find_portfolio(&portfolios, portfolio)?,
+                    None => {
+                        let portfolio = portfolios
+                            .iter()
+                            .find(|p| p.portfolio_type == "DEFAULT")
+                            .ok_or("No default Coinbase portfolio found")?;
+                        log::info!(
+                            "No Coinbase portfolio specified, using the default portfolio: {}",
+                            portfolio.name
+                        );
+                        portfolio
+                    }
+                };
+                Ok::<_, Box<dyn std::error::Error>>(portfolio.uuid.clone())
+            })
+            .await?
+            .clone())
+    }
+
+    async fn portfolio_balances(
+        &self,
+        portfolio_uuid: &str,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        let breakdown = self
+            .api_request::<PortfolioBreakdownResponse>(
+                reqwest::Method::GET,
+                &format!("/api/v3/brokerage/portfolios/{portfolio_uuid}"),
+                None,
+                None,
+            )
+            .await?
+            .breakdown;
+
+        Ok(breakdown
+            .spot_positions
+            .into_iter()
+            .filter(|position| {
+                position.asset == "SOL"
+                    || is_usd_equivalent(&position.asset)
+                    || is_fiat(&position.asset)
+            })
+            .map(|position| {
+                (
+                    position.asset,
+                    ExchangeBalance {
+                        available: position.available_to_trade_crypto,
+                        total: position.total_balance_crypto,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    // Every retail wallet, including vaults and non-primary wallets
+    async fn retail_accounts(
+        &self,
+    ) -> Result<Vec<CoinbaseRetailAccount>, Box<dyn std::error::Error>> {
+        let mut accounts = vec![];
+        let mut next_uri = Some("/v2/accounts?limit=100".to_string());
+        while let Some(uri) = next_uri.take() {
+            let page = self
+                .api_request::<CoinbasePage<CoinbaseRetailAccount>>(
+                    reqwest::Method::GET,
+                    &uri,
+                    None,
+                    None,
+                )
+                .await?;
+            accounts.extend(page.data);
+            next_uri = page.pagination.next_uri;
+        }
+        Ok(accounts)
+    }
+
+    async fn retail_balances(
+        &self,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        let mut balances = HashMap::<String, ExchangeBalance>::new();
+        for account in self.retail_accounts().await? {
+            let coin = account.currency.code;
+            if !(coin == "SOL" || is_usd_equivalent(&coin) || is_fiat(&coin)) {
+                continue;
+            }
+            let total = account.balance.amount.parse::<f64>()?;
+            if total == 0. {
+                continue;
+            }
+
+            let balance = balances.entry(coin).or_default();
+            balance.total += total;
+            // Withdrawals from a vault are held for days, so vault funds are never available
+            if account.account_type != "vault" {
+                balance.available += total;
+            }
+        }
+        Ok(balances)
+    }
+
+    // Primary retail wallet holding `coin`
+    async fn retail_account_id(&self, coin: &str) -> Result<String, Box<dyn std::error::Error>> {
+        self.retail_accounts()
+            .await?
+            .into_iter()
+            .find(|account| account.primary && account.currency.code == coin)
+            .map(|account| account.id)
+            .ok_or_else(|| format!("No Coinbase retail {coin} wallet found").into())
+    }
+
+    // Primary retail wallets holding SOL or a supported SPL token
+    async fn token_accounts(
+        &self,
+    ) -> Result<Vec<(String, MaybeToken)>, Box<dyn std::error::Error>> {
+        Ok(self
+            .retail_accounts()
+            .await?
+            .into_iter()
+            .filter(|account| account.primary)
+            .filter_map(|account| {
+                let token = if account.currency.code == "SOL" {
+                    MaybeToken::SOL()
+                } else {
+                    Token::from_str(&account.currency.code).ok()?.into()
+                };
+                Some((account.id, token))
+            })
+            .collect())
+    }
+
+    // Deposit addresses of the retail wallet `account_id`, on every network
+    async fn retail_addresses(
+        &self,
+        account_id: &str,
+    ) -> Result<Vec<CoinbaseAddress>, Box<dyn std::error::Error>> {
+        let mut addresses = vec![];
+        let mut next_uri = Some(format!("/v2/accounts/{account_id}/addresses"));
+        while let Some(uri) = next_uri.take() {
+            let page = self
+                .api_request::<CoinbasePage<CoinbaseAddress>>(
+                    reqwest::Method::GET,
+                    &uri,
+                    None,
+                    None,
+                )
+                .await?;
+            addresses.extend(page.data);
+            next_uri = page.pagination.next_uri;
+        }
+        Ok(addresses)
+    }
+
+    async fn has_solana_address(
+        &self,
+        account_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self
+            .retail_addresses(account_id)
+            .await?
+            .iter()
+            .any(|address| address.network == SOLANA_NETWORK))
+    }
+
+    // Account holding `coin` in the portfolio `portfolio_uuid`
+    async fn portfolio_account_id(
+        &self,
+        portfolio_uuid: &str,
+        coin: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut cursor = None;
+        loop {
+            let mut path = format!(
+                "/api/v3/brokerage/accounts?limit=250&retail_portfolio_id={portfolio_uuid}"
+            );
+            if let Some(cursor) = &cursor {
+                path += &format!("&cursor={cursor}");
+            }
+            let page = self
+                .api_request::<BrokerageAccounts>(reqwest::Method::GET, &path, None, None)
+                .await?;
+            if let Some(account) = page
+                .accounts
+                .into_iter()
+                .find(|account| account.currency == coin)
+            {
+                return Ok(account.uuid);
+            }
+            if !page.has_next || page.cursor.is_none() {
+                return Err(format!("No {coin} account found in the Coinbase portfolio").into());
+            }
+            cursor = page.cursor;
+        }
+    }
 }
 
 #[async_trait]
 impl ExchangeClient for CoinbaseExchangeClient {
+    async fn supported_tokens(&self) -> Result<Vec<MaybeToken>, Box<dyn std::error::Error>> {
+        let mut supported_tokens = vec![];
+        for (_, token) in self.token_accounts().await? {
+            let token_support = self.supports_token(token).await?;
+            if token_support.deposits_enabled || token_support.withdrawals_enabled {
+                supported_tokens.push(token);
+            }
+        }
+        Ok(supported_tokens)
+    }
+
+    async fn supports_token(
+        &self,
+        token: MaybeToken,
+    ) -> Result<TokenSupport, Box<dyn std::error::Error>> {
+        let account = self
+            .retail_accounts()
+            .await?
+            .into_iter()
+            .find(|account| account.primary && account.currency.code == token.name());
+        let account = match account {
+            Some(account) => account,
+            None => return Ok(TokenSupport::default()),
+        };
+        let has_solana_address = self.has_solana_address(&account.id).await?;
+        Ok(TokenSupport {
+            listed: true,
+            // SPL-USDC addresses are currently reported incorrectly, see `deposit_address()`
+            deposits_enabled: account.allow_deposits
+                && (has_solana_address || token.name() == "USDC"),
+            withdrawals_enabled: account.allow_withdrawals && has_solana_address,
+        })
+    }
+
+    async fn deposit_requirements(
+        &self,
+        token: MaybeToken,
+    ) -> Result<DepositRequirements, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct CoinbaseCurrency {
+            #[serde(default)]
+            supported_networks: Vec<CoinbaseCurrencyNetwork>,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct CoinbaseCurrencyNetwork {
+            id: String,
+            status: String,
+            network_confirmations: Option<u32>,
+            processing_time_seconds: Option<u64>,
+        }
+
+        let network = self
+            .public_request::<CoinbaseCurrency>(&format!("/currencies/{}", token.name()))
+            .await?
+            .supported_networks
+            .into_iter()
+            .find(|network| network.id == "solana")
+            .ok_or_else(|| format!("Coinbase has no Solana network route for {token}"))?;
+        Ok(DepositRequirements {
+            finality: network
+                .network_confirmations
+                .map_or(DepositFinality::Unpublished, DepositFinality::Confirmations),
+            minimum: None,
+            deposits_enabled: network.status == "online"
+                && self.supports_token(token).await?.deposits_enabled,
+            processing_time_secs: network.processing_time_seconds,
+        })
+    }
+
     async fn deposit_address(
         &self,
         token: MaybeToken,
     ) -> Result<Pubkey, Box<dyn std::error::Error>> {
-        let accounts = self.client.accounts();
-        pin_mut!(accounts);
-
-        while let Some(account_result) = accounts.next().await {
-            for account in account_result.unwrap() {
-                if let Ok(id) = coinbase_rs::Uuid::from_str(&account.id) {
-                    if token.name() == account.currency.code
-                        && account.primary
-                        && account.allow_deposits
-                    {
-                        let addresses = self.client.list_addresses(&id);
-                        pin_mut!(addresses);
-
-                        let mut pubkeys = vec![];
-                        while let Some(addresses_result) = addresses.next().await {
-                            for address in addresses_result.unwrap() {
-                                let push = match address.network.as_str() {
-                                    "solana" => true,
-
-                                    // SPL-USDC addresses are currently reported incorrectly
-                                    "ethereum" if token.name() == "USDC" => true,
-                                    _ => false,
-                                };
-
-                                if push {
-                                    if let Ok(pubkey) = address.address.parse::<Pubkey>() {
-                                        pubkeys.push(pubkey);
-                                    }
-                                }
-                            }
-                        }
-                        assert!(pubkeys.len() <= 1);
-                        if let Some(pubkey) = pubkeys.pop() {
-                            return Ok(pubkey);
-                        }
-                        break;
-                    }
-                }
-            }
-        }
-        Err(format!("Unsupported deposit token: {}", token.name()).into())
+        let account = self
+            .retail_accounts()
+            .await?
+            .into_iter()
+            .find(|account| {
+                token.name() == account.currency.code && account.primary && account.allow_deposits
+            })
+            .ok_or_else(|| format!("Unsupported deposit token: {}", token.name()))?;
+
+        let mut pubkeys = self
+            .retail_addresses(&account.id)
+            .await?
+            .into_iter()
+            .filter(|address| match address.network.as_str() {
+                SOLANA_NETWORK => true,
+
+                // SPL-USDC addresses are currently reported incorrectly
+                "ethereum" if token.name() == "USDC" => true,
+                _ => false,
+            })
+            .filter_map(|address| address.address.parse::<Pubkey>().ok())
+            .collect::<Vec<_>>();
+        assert!(pubkeys.len() <= 1);
+        pubkeys
+            .pop()
+            .ok_or_else(|| format!("Unsupported deposit token: {}", token.name()).into())
     }
 
     async fn balances(
         &self,
     ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
-        Err("Balances not supported".into())
+        // Retail wallet funds can be withdrawn directly, or moved into the portfolio instantly
+        let mut balances = HashMap::<String, ExchangeBalance>::new();
+        for wallet_balances in self.wallet_balances().await?.into_values() {
+            for (coin, wallet_balance) in wallet_balances {
+                let balance = balances.entry(coin).or_default();
+                balance.total += wallet_balance.total;
+                balance.available += wallet_balance.available;
+            }
+        }
+        Ok(balances)
+    }
+
+    async fn wallet_balances(
+        &self,
+    ) -> Result<BTreeMap<String, HashMap<String, ExchangeBalance>>, Box<dyn std::error::Error>>
+    {
+        let portfolio_uuid = self.portfolio_uuid().await?;
+        let mut wallets = BTreeMap::from([(
+            "portfolio".to_string(),
+            self.portfolio_balances(&portfolio_uuid).await?,
+        )]);
+
+        // The retail wallets need the API key's wallet scopes, so are skipped when unavailable
+        match self.retail_balances().await {
+            Ok(balances) => {
+                if !balances.is_empty() {
+                    wallets.insert(RETAIL_WALLETS.to_string(), balances);
+                }
+            }
+            Err(err) => {
+                if !self.wallet_warning_shown.swap(true, Ordering::Relaxed) {
+                    eprintln!("Unable to fetch Coinbase retail wallet balances: {err}");
+                }
+            }
+        }
+        Ok(wallets)
     }
 
     async fn recent_deposits(
@@ -73,47 +835,411 @@ impl ExchangeClient for CoinbaseExchangeClient {
                  // once the transaction is confirmed (see `db::drop_deposit()`).
     }
 
-    async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
-        Ok(vec![])
+    async fn withdrawals(
+        &self,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
+        let mut withdrawals = vec![];
+
+        for (account_id, token) in self.token_accounts().await? {
+            // Transactions are listed newest first, with no server-side date filter
+            let mut next_uri = Some(format!(
+                "/v2/accounts/{account_id}/transactions?limit=100&order=desc"
+            ));
+            while let Some(uri) = next_uri.take() {
+                let page = self
+                    .api_request_with_timeout::<CoinbasePage<CoinbaseTransaction>>(
+                        reqwest::Method::GET,
+                        &uri,
+                        None,
+                        None,
+                        Some(HISTORY_REQUEST_TIMEOUT),
+                    )
+                    .await?;
+
+                let reached_start = page
+                    .data
+                    .last()
+                    .map(|transaction| transaction.created_at < start_time)
+                    .unwrap_or(true);
+
+                for transaction in page.data.into_iter().filter(|transaction| {
+                    transaction.transaction_type == "send"
+                        && transaction.created_at >= start_time
+                        && transaction.created_at < end_time
+                }) {
+                    let (hash, network_fee) = match transaction.network {
+                        Some(network) => (
+                            network.hash,
+                            network
+                                .transaction_fee
+                                .and_then(|fee| fee.amount.parse::<f64>().ok()),
+                        ),
+                        None => (None, None),
+                    };
+                    let (completed, tx_id) = match transaction.status.as_str() {
+                        "completed" => (true, hash),
+                        "failed" | "canceled" | "expired" => (true, None),
+                        _ => (false, None),
+                    };
+
+                    withdrawals.push(WithdrawalInfo {
+                        address: transaction
+                            .to
+                            .and_then(|to| to.address)
+                            .and_then(|address| address.parse::<Pubkey>().ok())
+                            .unwrap_or_default(),
+                        token,
+                        amount: transaction.amount.amount.parse::<f64>()?.abs(),
+                        tag: transaction.id,
+                        completed,
+                        tx_id,
+                        time: Some(transaction.created_at),
+                        fee: network_fee,
+                        client_reference: transaction.idem,
+                    });
+                }
+
+                if !reached_start {
+                    next_uri = page.pagination.next_uri;
+                }
+            }
+        }
+
+        sort_and_dedup_withdrawals(&mut withdrawals);
+        Ok(withdrawals)
     }
 
     async fn request_withdraw(
         &self,
-        _address: Pubkey,
-        _token: MaybeToken,
-        _amount: f64,
+        address: Pubkey,
+        token: MaybeToken,
+        amount: u64,
         _password: Option<String>,
-        _code: Option<String>,
+        code: Option<&dyn TwoFactorCodeProvider>,
+        client_reference: &str,
     ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>
     {
-        Err("Withdrawals not supported".into())
+        let (account_id, _) = self
+            .token_accounts()
+            .await?
+            .into_iter()
+            .find(|(_, account_token)| *account_token == token)
+            .ok_or_else(|| format!("No Coinbase {token} account found"))?;
+
+        if !self.has_solana_address(&account_id).await? {
+            return Err(format!("Coinbase has no Solana network route for {token}").into());
+        }
+
+        let code = code.map(|code| code.code()).transpose()?;
+        let transaction = self
+            .api_request::<CoinbaseResponse<CoinbaseTransaction>>(
+                reqwest::Method::POST,
+                &format!("/v2/accounts/{account_id}/transactions"),
+                Some(serde_json::json!({
+                    "type": "send",
+                    "to": address.to_string(),
+                    "amount": format_native_amount(token, amount, self.withdrawal_decimals(token)),
+                    "currency": token.name(),
+                    "network": SOLANA_NETWORK,
+                    "idem": client_reference,
+                })),
+                code.as_deref(),
+            )
+            .await?
+            .data;
+
+        let withdraw_fee = transaction
+            .network
+            .and_then(|network| network.transaction_fee)
+            .map(|fee| fee.amount.parse::<f64>())
+            .transpose()?
+            .unwrap_or_default();
+        Ok((transaction.id, withdraw_fee))
+    }
+
+    async fn fiat_withdrawal_methods(
+        &self,
+        currency: &str,
+    ) -> Result<Vec<FiatWithdrawalMethod>, Box<dyn std::error::Error>> {
+        check_fiat_currency(currency)?;
+
+        let mut methods = vec![];
+        let mut next_uri = Some("/v2/payment-methods?limit=100".to_string());
+        while let Some(uri) = next_uri.take() {
+            let page = self
+                .api_request::<CoinbasePage<CoinbasePaymentMethod>>(
+                    reqwest::Method::GET,
+                    &uri,
+                    None,
+                    None,
+                )
+                .await?;
+            methods.extend(
+                page.data
+                    .into_iter()
+                    .filter(|payment_method| {
+                        payment_method.allow_withdraw && payment_method.currency == currency
+                    })
+                    .map(|payment_method| FiatWithdrawalMethod {
+                        id: payment_method.id,
+                        name: payment_method.name,
+                        currency: payment_method.currency,
+                    }),
+            );
+            next_uri = page.pagination.next_uri;
+        }
+        Ok(methods)
+    }
+
+    async fn request_fiat_withdraw(
+        &self,
+        currency: &str,
+        amount: f64,
+        method_id: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        check_fiat_currency(currency)?;
+
+        let account_id = self.retail_account_id(currency).await?;
+        let withdrawal = self
+            .api_request::<CoinbaseResponse<CoinbaseWithdrawal>>(
+                reqwest::Method::POST,
+                &format!("/v2/accounts/{account_id}/withdrawals"),
+                Some(serde_json::json!({
+                    "amount": amount.to_string(),
+                    "currency": currency,
+                    "payment_method": method_id,
+                    "commit": true,
+                })),
+                None,
+            )
+            .await?
+            .data;
+        Ok(withdrawal.id)
+    }
+
+    async fn fiat_withdrawal_status(
+        &self,
+        currency: &str,
+        withdrawal_id: &str,
+    ) -> Result<FiatWithdrawalStatus, Box<dyn std::error::Error>> {
+        check_fiat_currency(currency)?;
+
+        let account_id = self.retail_account_id(currency).await?;
+        let withdrawal = self
+            .api_request::<CoinbaseResponse<CoinbaseWithdrawal>>(
+                reqwest::Method::GET,
+                &format!("/v2/accounts/{account_id}/withdrawals/{withdrawal_id}"),
+                None,
+                None,
+            )
+            .await?
+            .data;
+
+        let state = match withdrawal.status.as_str() {
+            "created" => FiatWithdrawalState::Pending,
+            "completed" => FiatWithdrawalState::Completed,
+            "canceled" => FiatWithdrawalState::Cancelled,
+            "failed" | "expired" => FiatWithdrawalState::Failed,
+            _ => FiatWithdrawalState::Processing,
+        };
+        Ok(FiatWithdrawalStatus {
+            state,
+            amount: withdrawal.amount.amount.parse::<f64>()?.abs(),
+            fee: withdrawal
+                .fee
+                .and_then(|fee| fee.amount.parse::<f64>().ok()),
+        })
     }
 
     async fn print_market_info(
         &self,
-        _pair: &str,
-        _format: MarketInfoFormat,
+        pair: &TradingPair,
+        format: MarketInfoFormat,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        Err("Quotes not supported".into())
+        if let MarketInfoFormat::Candles(range) = format {
+            return print_candles(range, &self.candles(pair, range).await?);
+        }
+
+        let ticker_stats = self.ticker_stats(pair).await?;
+        let pair_info = self.pair_info(pair).await.ok();
+        let usd_rate = if format == MarketInfoFormat::All {
+            quote_usd_rate(self, &pair.quote).await.ok()
+        } else {
+            None
+        };
+        print_ticker_stats(&ticker_stats, pair_info.as_ref(), usd_rate, format)
+    }
+
+    async fn server_time(&self) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct CoinbaseTime {
+            iso: DateTime<Utc>,
+        }
+
+        Ok(self.public_request::<CoinbaseTime>("/time").await?.iso)
     }
 
-    async fn bid_ask(&self, _pair: &str) -> Result<BidAsk, Box<dyn std::error::Error>> {
+    async fn system_status(
+        &self,
+        pair: Option<&TradingPair>,
+    ) -> Result<SystemStatus, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct StatusPage {
+            status: StatusPageIndicator,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct StatusPageIndicator {
+            indicator: String, // none, minor, major, critical or maintenance
+        }
+
+        let status_page = self
+            .http_client
+            .get(STATUS_URL)
+            .send()
+            .await
+            .map_err(|err| request_error(Exchange::Coinbase, STATUS_URL, err))?
+            .json::<StatusPage>()
+            .await?;
+        // Lesser incidents are usually confined to a single product or feature
+        let system_status = match status_page.status.indicator.as_str() {
+            "critical" | "maintenance" => SystemStatus::Maintenance,
+            _ => SystemStatus::Online,
+        };
+        let pair = match pair {
+            Some(pair) => pair,
+            None => return Ok(system_status),
+        };
+
+        let product = self
+            .public_request::<CoinbaseProduct>(&format!("/products/{}-{}", pair.base, pair.quote))
+            .await?;
+        Ok(system_status.max(product.system_status()))
+    }
+
+    async fn api_permissions(&self) -> Result<ApiPermissions, Box<dyn std::error::Error>> {
+        let key_permissions = self
+            .api_request::<KeyPermissions>(
+                reqwest::Method::GET,
+                "/api/v3/brokerage/key_permissions",
+                None,
+                None,
+            )
+            .await?;
+        Ok(ApiPermissions {
+            trade: key_permissions.can_trade,
+            withdraw: key_permissions.can_transfer,
+            ip_restricted: None,
+        })
+    }
+
+    async fn order_book(
+        &self,
+        pair: &TradingPair,
+        depth: usize,
+    ) -> Result<OrderBook, Box<dyn std::error::Error>> {
+        // Levels are `[price, size, num-orders]`
+        #[derive(Debug, Deserialize)]
+        struct CoinbaseProductBook {
+            bids: Vec<(String, String, serde_json::Value)>,
+            asks: Vec<(String, String, serde_json::Value)>,
+            time: Option<DateTime<Utc>>,
+        }
+
+        let request_time = Utc::now();
+        let book = self
+            .public_request::<CoinbaseProductBook>(&format!(
+                "/products/{}-{}/book?level=2",
+                pair.base, pair.quote
+            ))
+            .await?;
+
+        let levels = |levels: &[(String, String, serde_json::Value)]| {
+            parse_order_book_levels(
+                levels
+                    .iter()
+                    .map(|(price, size, _)| (price.as_str(), size.as_str())),
+                depth,
+            )
+        };
+        Ok(OrderBook {
+            bids: levels(&book.bids)?,
+            asks: levels(&book.asks)?,
+            time: book.time.unwrap_or(request_time),
+        })
+    }
+
+    async fn ticker_stats(
+        &self,
+        pair: &TradingPair,
+    ) -> Result<TickerStats, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct CoinbaseProductStats {
+            open: String,
+            high: String,
+            low: String,
+            last: String,
+            volume: String,
+        }
+
+        #[derive(Debug, Deserialize)]
+        struct CoinbaseProductTicker {
+            bid: String,
+            ask: String,
+        }
+
+        let product_id = format!("{}-{}", pair.base, pair.quote);
+        let stats = self
+            .public_request::<CoinbaseProductStats>(&format!("/products/{product_id}/stats"))
+            .await?;
+        let ticker = self
+            .public_request::<CoinbaseProductTicker>(&format!("/products/{product_id}/ticker"))
+            .await?;
+
+        let last_price = stats.last.parse::<f64>()?;
+        let open_24h = stats.open.parse::<f64>()?;
+        let base_volume_24h = stats.volume.parse::<f64>()?;
+
+        Ok(TickerStats {
+            pair: pair.to_string(),
+            last_price,
+            bid_price: ticker.bid.parse::<f64>()?,
+            ask_price: ticker.ask.parse::<f64>()?,
+            open_24h,
+            high_24h: stats.high.parse::<f64>()?,
+            low_24h: stats.low.parse::<f64>()?,
+            base_volume_24h,
+            // Coinbase does not report quote volume, approximate with the last price
+            quote_volume_24h: base_volume_24h * last_price,
+            price_change_percent_24h: if open_24h > 0. {
+                (last_price - open_24h) / open_24h * 100.
+            } else {
+                0.
+            },
+            weighted_average_price_24h: None,
+        })
+    }
+
+    async fn bid_ask(&self, _pair: &TradingPair) -> Result<BidAsk, Box<dyn std::error::Error>> {
         Err("Trading not supported".into())
     }
 
     async fn place_order(
         &self,
-        _pair: &str,
+        _pair: &TradingPair,
         _side: OrderSide,
         _price: f64,
         _amount: f64,
+        _client_order_id: &str,
     ) -> Result<OrderId, Box<dyn std::error::Error>> {
         Err("Trading not supported".into())
     }
 
     async fn cancel_order(
         &self,
-        _pair: &str,
+        _pair: &TradingPair,
         _order_id: &OrderId,
     ) -> Result<(), Box<dyn std::error::Error>> {
         Err("Trading not supported".into())
@@ -121,7 +1247,7 @@ impl ExchangeClient for CoinbaseExchangeClient {
 
     async fn order_status(
         &self,
-        _pair: &str,
+        _pair: &TradingPair,
         _order_id: &OrderId,
     ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
         Err("Trading not supported".into())
@@ -134,6 +1260,12 @@ impl ExchangeClient for CoinbaseExchangeClient {
         Err("Lending not supported".into())
     }
 
+    async fn all_lending_info(
+        &self,
+    ) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>> {
+        Err("Lending not supported".into())
+    }
+
     async fn get_lending_history(
         &self,
         _lending_history: LendingHistory,
@@ -149,8 +1281,154 @@ impl ExchangeClient for CoinbaseExchangeClient {
         Err("Lending not supported".into())
     }
 
-    fn preferred_solusd_pair(&self) -> &'static str {
-        "SOLUSD"
+    // Portfolios, followed by the retail wallets
+    async fn list_subaccounts(&self) -> Result<Vec<Subaccount>, Box<dyn std::error::Error>> {
+        let portfolio_uuid = self.portfolio_uuid().await?;
+        let mut subaccounts = self
+            .portfolios()
+            .await?
+            .into_iter()
+            .map(|portfolio| Subaccount {
+                selected: portfolio.uuid == portfolio_uuid,
+                default: portfolio.portfolio_type == "DEFAULT",
+                name: portfolio.name,
+                id: portfolio.uuid,
+            })
+            .collect::<Vec<_>>();
+        subaccounts.push(Subaccount {
+            name: RETAIL_WALLETS.to_string(),
+            id: RETAIL_WALLETS.to_string(),
+            default: false,
+            selected: false,
+        });
+        Ok(subaccounts)
+    }
+
+    // Portfolios other than the one selected by the credentials.  The retail wallets are left out
+    // as they're already included in `balances()`
+    async fn subaccounts(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .list_subaccounts()
+            .await?
+            .into_iter()
+            .filter(|subaccount| !subaccount.selected && !is_retail_wallets(&subaccount.name))
+            .map(|subaccount| subaccount.name)
+            .collect())
+    }
+
+    async fn subaccount_balances(
+        &self,
+        name: &str,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        if is_retail_wallets(name) {
+            return self.retail_balances().await;
+        }
+        let portfolios = self.portfolios().await?;
+        let portfolio = Self::find_portfolio(&portfolios, name)?;
+        self.portfolio_balances(&portfolio.uuid).await
+    }
+
+    async fn transfer_between_subaccounts(
+        &self,
+        coin: &str,
+        amount: f64,
+        from: &str,
+        to: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let portfolios = self.portfolios().await?;
+        if is_retail_wallets(from) || is_retail_wallets(to) {
+            let (source, target) = match (is_retail_wallets(from), is_retail_wallets(to)) {
+                (true, true) => return Err("Source and destination are the same".into()),
+                (true, false) => {
+                    let portfolio = Self::find_portfolio(&portfolios, to)?;
+                    (
+                        self.retail_account_id(coin).await?,
+                        self.portfolio_account_id(&portfolio.uuid, coin).await?,
+                    )
+                }
+                (false, _) => {
+                    let portfolio = Self::find_portfolio(&portfolios, from)?;
+                    (
+                        self.portfolio_account_id(&portfolio.uuid, coin).await?,
+                        self.retail_account_id(coin).await?,
+                    )
+                }
+            };
+
+            // An instant transfer between two of the user's own accounts, with no on-chain hop
+            self.api_request::<serde_json::Value>(
+                reqwest::Method::POST,
+                &format!("/v2/accounts/{source}/transactions"),
+                Some(serde_json::json!({
+                    "type": "transfer",
+                    "to": target,
+                    "amount": amount.to_string(),
+                    "currency": coin,
+                })),
+                None,
+            )
+            .await?;
+            return Ok(());
+        }
+
+        let source = Self::find_portfolio(&portfolios, from)?;
+        let target = Self::find_portfolio(&portfolios, to)?;
+
+        self.api_request::<serde_json::Value>(
+            reqwest::Method::POST,
+            "/api/v3/brokerage/portfolios/move_funds",
+            Some(serde_json::json!({
+                "funds": {
+                    "value": amount.to_string(),
+                    "currency": coin,
+                },
+                "source_portfolio_uuid": source.uuid,
+                "target_portfolio_uuid": target.uuid,
+            })),
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    fn preferred_solusd_pair(&self) -> TradingPair {
+        TradingPair::new("SOL", "USD")
+    }
+
+    fn maker_fee_rate(&self) -> f64 {
+        0.004
+    }
+
+    fn taker_fee_rate(&self) -> f64 {
+        0.006
+    }
+
+    // Listed for market info and market status, although orders aren't supported
+    async fn pair_infos(&self) -> Result<Vec<PairInfo>, Box<dyn std::error::Error>> {
+        Ok(self
+            .pair_infos
+            .get_or_try_init(|| async {
+                Ok::<_, Box<dyn std::error::Error>>(
+                    self.public_request::<Vec<CoinbaseProduct>>("/products")
+                        .await?
+                        .into_iter()
+                        .map(|product| PairInfo {
+                            pair: TradingPair::new(&product.base_currency, &product.quote_currency),
+                            tick_size: product.quote_increment.parse().ok(),
+                            step_size: product.base_increment.parse().ok(),
+                            min_notional: product
+                                .min_market_funds
+                                .as_ref()
+                                .and_then(|min_market_funds| min_market_funds.parse().ok()),
+                            status: product.system_status(),
+                            // Coinbase announces delistings, but doesn't publish a schedule
+                            delist_time: None,
+                        })
+                        .collect(),
+                )
+            })
+            .await?
+            .clone())
     }
 }
 
@@ -160,9 +1438,164 @@ pub fn new(
         secret,
         subaccount,
     }: ExchangeCredentials,
+    options: ExchangeClientOptions,
 ) -> Result<CoinbaseExchangeClient, Box<dyn std::error::Error>> {
-    assert!(subaccount.is_none());
+    options.check_supported(
+        Exchange::Coinbase,
+        &[
+            "base_url",
+            "timeout",
+            "connect_timeout",
+            "proxy",
+            "debug_capture",
+        ],
+    )?;
+
+    let debug_capture = options
+        .debug_capture_dir
+        .map(|dir| {
+            DebugCapture::create(
+                Exchange::Coinbase,
+                &dir,
+                &[api_key.as_str(), secret.as_str()],
+            )
+        })
+        .transpose()?;
+    if let Some(debug_capture) = &debug_capture {
+        eprintln!(
+            "Capturing Coinbase requests to {}",
+            debug_capture.path().display()
+        );
+    }
+
+    // A base URL replaces both the retail and Exchange API hosts, whose paths don't overlap
+    let (api_url, exchange_api_url) = match &options.base_url {
+        Some(base_url) => (base_url.clone(), base_url.clone()),
+        None => (API_URL.to_string(), EXCHANGE_API_URL.to_string()),
+    };
+
     Ok(CoinbaseExchangeClient {
-        client: coinbase_rs::Private::new(coinbase_rs::MAIN_URL, &api_key, &secret),
+        api_url,
+        exchange_api_url,
+        http_client: options.http_client()?,
+        rate_limiter: RateLimiter::shared(
+            Exchange::Coinbase,
+            &api_key,
+            options.rate_limit_dir.as_deref(),
+        ),
+        trading_rate_limiter: RateLimiter::shared_budget(
+            Exchange::Coinbase,
+            Some("advanced-trade"),
+            ADVANCED_TRADE_RATE_LIMIT,
+            &api_key,
+            options.rate_limit_dir.as_deref(),
+        ),
+        wallet_warning_shown: AtomicBool::new(false),
+        api_key,
+        secret,
+        // The subaccount selects a portfolio, by name or uuid
+        portfolio: subaccount,
+        portfolio_uuid: OnceCell::new(),
+        pair_infos: OnceCell::new(),
+        debug_capture,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::str::FromStr};
+
+    // The SOL wallet has a Solana address, and the send matches only if it names the network
+    const SOL_WITHDRAWAL: &str = r##"
+==> 2024-03-21T15:04:28Z GET /v2/accounts
+CB-ACCESS-KEY: <redacted>
+<== 200 in 151ms
+{"pagination":{"ending_before":null,"starting_after":null,"previous_ending_before":null,
+"next_starting_after":null,"limit":100,"order":"desc","previous_uri":null,"next_uri":null},
+"data":[{"id":"4e9c1a7b-2d3f-4b8e-a6c5-9f0d1e2b3c4a","name":"SOL Wallet","primary":true,
+"type":"wallet","currency":{"code":"SOL","name":"Solana","color":"#000000","sort_index":100,
+"exponent":8,"type":"crypto","address_regex":"^[1-9A-HJ-NP-Za-km-z]{32,44}$",
+"asset_id":"4f039497-3af8-5bb3-951c-6df9afa9be1c","slug":"solana"},
+"balance":{"amount":"5.00000000","currency":"SOL"},"created_at":"2021-06-02T18:11:25Z",
+"updated_at":"2024-03-20T08:45:00Z","resource":"account",
+"resource_path":"/v2/accounts/4e9c1a7b-2d3f-4b8e-a6c5-9f0d1e2b3c4a","allow_deposits":true,
+"allow_withdrawals":true}]}
+
+==> 2024-03-21T15:04:29Z GET /v2/accounts/4e9c1a7b-2d3f-4b8e-a6c5-9f0d1e2b3c4a/addresses
+CB-ACCESS-KEY: <redacted>
+<== 200 in 140ms
+{"pagination":{"ending_before":null,"starting_after":null,"previous_ending_before":null,
+"next_starting_after":null,"limit":25,"order":"desc","previous_uri":null,"next_uri":null},
+"data":[{"id":"dd3183eb-af1d-5f5d-a90d-cbff946435ff",
+"address":"7xKXtg2CW87d97TXJSDpbD5jBkheTqA83TZRuJosgAsU","name":"SOL address",
+"created_at":"2021-06-02T18:11:25Z","updated_at":"2021-06-02T18:11:25Z","network":"solana",
+"uri_scheme":"solana","resource":"address",
+"resource_path":
+"/v2/accounts/4e9c1a7b-2d3f-4b8e-a6c5-9f0d1e2b3c4a/addresses/dd3183eb-af1d-5f5d-a90d-cbff946435ff",
+"warnings":[],"deposit_uri":"solana:7xKXtg2CW87d97TXJSDpbD5jBkheTqA83TZRuJosgAsU"}]}
+
+==> 2024-03-21T15:04:30Z POST /v2/accounts/4e9c1a7b-2d3f-4b8e-a6c5-9f0d1e2b3c4a/transactions
+CB-ACCESS-KEY: <redacted>
+
+{"type":"send","currency":"SOL","network":"solana","idem":"sys-withdraw"}
+<== 201 in 310ms
+{"data":{"id":"e3c1d5a7-9b2f-4d6e-8a0c-1f3e5b7d9a2c","type":"send","status":"pending",
+"amount":{"amount":"-1.00000000","currency":"SOL"},"created_at":"2024-03-21T15:04:30Z",
+"idem":"sys-withdraw","network":{"status":"pending","hash":null,
+"transaction_fee":{"amount":"0.00100000","currency":"SOL"}},
+"to":{"resource":"address","address":"CiwjEStzDKR8j1uQgVvU6hFtt8thAK3aZMDN3X6yzW5q"}}}
+"##;
+
+    #[tokio::test]
+    async fn withdrawals_name_the_solana_network() {
+        let server = crate::conformance::ReplayServer::start(SOL_WITHDRAWAL)
+            .await
+            .unwrap();
+        let client = crate::conformance::replay_client(Exchange::Coinbase, &server).unwrap();
+        let address = Pubkey::from_str("CiwjEStzDKR8j1uQgVvU6hFtt8thAK3aZMDN3X6yzW5q").unwrap();
+
+        let (withdraw_id, withdraw_fee) = client
+            .request_withdraw(
+                address,
+                MaybeToken::SOL(),
+                1_000_000_000,
+                None,
+                None,
+                "sys-withdraw",
+            )
+            .await
+            .unwrap();
+        assert_eq!(withdraw_id, "e3c1d5a7-9b2f-4d6e-8a0c-1f3e5b7d9a2c");
+        assert_eq!(withdraw_fee, 0.001);
+        assert_eq!(server.unexpected_requests(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn error_bodies_give_the_native_code() {
+        let err = api_error(
+            "/v2/accounts",
+            401,
+            r#"{"errors":[{"id":"invalid_token","message":"The access token is invalid"}]}"#,
+        );
+        assert_eq!(
+            err.to_string(),
+            "Coinbase /v2/accounts: invalid_token: The access token is invalid (HTTP 401)"
+        );
+
+        let err = api_error(
+            "/api/v3/brokerage/orders",
+            400,
+            r#"{"error":"INVALID_ARGUMENT","message":"invalid product_id"}"#,
+        );
+        assert_eq!(
+            err.to_string(),
+            "Coinbase /api/v3/brokerage/orders: INVALID_ARGUMENT: invalid product_id (HTTP 400)"
+        );
+
+        let err = api_error("/v2/accounts", 503, "Service Unavailable");
+        assert_eq!(
+            err.to_string(),
+            "Coinbase /v2/accounts: unknown: Service Unavailable (HTTP 503)"
+        );
+    }
+}
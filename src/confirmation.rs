@@ -0,0 +1,119 @@
+//! Confirmation of operations that can't be undone, such as withdrawals and order cancellations.
+//! The operation is summarized with the exact values it will be carried out with, and confirmed
+//! by typing a short digest of those values, so that what was confirmed is what gets executed
+use {
+    sha1::{Digest, Sha1},
+    std::{
+        fmt,
+        io::{self, BufRead, IsTerminal, Write},
+        sync::atomic::{AtomicBool, Ordering},
+    },
+    thiserror::Error,
+};
+
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Treat every irreversible operation as confirmed, for scripts
+pub fn set_assume_yes(assume_yes: bool) {
+    ASSUME_YES.store(assume_yes, Ordering::Relaxed);
+}
+
+/// Whether irreversible operations are confirmed on the terminal before they're carried out
+pub fn required() -> bool {
+    !ASSUME_YES.load(Ordering::Relaxed)
+}
+
+#[derive(Error, Debug)]
+pub enum ConfirmationError {
+    #[error(
+        "{0} must be confirmed, but there is no terminal to confirm it on. Pass --yes to \
+         proceed without confirmation"
+    )]
+    NotInteractive(String),
+
+    #[error("{0} was not confirmed")]
+    Declined(String),
+
+    #[error("Unable to read confirmation: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// An operation that can't be undone, described by the values it will be carried out with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrreversibleOperation {
+    action: String,
+    details: Vec<(String, String)>,
+}
+
+impl IrreversibleOperation {
+    pub fn new(action: impl Into<String>) -> Self {
+        Self {
+            action: action.into(),
+            details: vec![],
+        }
+    }
+
+    pub fn detail(mut self, name: impl Into<String>, value: impl fmt::Display) -> Self {
+        self.details.push((name.into(), value.to_string()));
+        self
+    }
+
+    /// The first 8 hex digits of the SHA-1 of the action and its details, which any change to
+    /// them changes
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha1::new();
+        hasher.update(self.action.as_bytes());
+        for (name, value) in &self.details {
+            // Separated, so that moving text from one field to the next changes the digest
+            hasher.update([0]);
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            hasher.update(value.as_bytes());
+        }
+        hasher
+            .finalize()
+            .iter()
+            .take(4)
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+impl fmt::Display for IrreversibleOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.action)?;
+        let width = self
+            .details
+            .iter()
+            .map(|(name, _)| name.len() + 1)
+            .max()
+            .unwrap_or_default();
+        for (name, value) in &self.details {
+            write!(f, "\n  {:<width$} {value}", format!("{name}:"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Asks for `operation` to be confirmed by typing its digest, unless `set_assume_yes()` was
+/// called.  Without a terminal to ask on, the operation is refused rather than assumed confirmed
+pub fn confirm(operation: &IrreversibleOperation) -> Result<(), ConfirmationError> {
+    if !required() {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        return Err(ConfirmationError::NotInteractive(operation.action.clone()));
+    }
+
+    let digest = operation.digest();
+    eprintln!("{operation}");
+    eprint!("This can't be undone. Type {digest} to confirm: ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    if answer.trim() != digest {
+        return Err(ConfirmationError::Declined(operation.action.clone()));
+    }
+    Ok(())
+}
@@ -0,0 +1,784 @@
+//! Conformance checks for `ExchangeClient` implementations.  Each check drives a client against a
+//! local server that replays recorded exchange responses, so an implementation can be verified
+//! without credentials or network access.
+//!
+//! Recordings use the `debug_capture` file format, one file per check named after it, such as
+//! `conformance/kraken/withdrawals.log`.  A check the exchange is required to pass fails without
+//! a recording.  Every recording follows the same scenario, described in `conformance/README.md`
+use {
+    crate::{
+        exchange::{
+            Exchange, ExchangeClient, ExchangeClientBuilder, ExchangeCredentials,
+            OrderCancellation, OrderSide, OrderStatus, ReportingTimeZone,
+        },
+        request_metrics::ErrorCategory,
+        token::MaybeToken,
+    },
+    chrono::prelude::*,
+    std::{
+        fs, io,
+        path::Path,
+        sync::{Arc, Mutex},
+    },
+    strum::{EnumString, IntoStaticStr},
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+};
+
+// Replayed requests are signed with these.  The secret is valid base64, as Kraken requires
+const API_KEY: &str = "conformance";
+const SECRET: &str = "Y29uZm9ybWFuY2U=";
+
+// The scenario every recording follows
+const SOL_TOTAL: f64 = 10.5;
+const SOL_AVAILABLE: f64 = 9.5;
+const ORDER_PRICE: f64 = 500.;
+const ORDER_AMOUNT: f64 = 2.;
+const ORDER_PARTIAL_FILL: f64 = 0.5;
+const CLIENT_ORDER_ID: &str = "sys-conformance";
+const DEPOSIT_AMOUNT: f64 = 12.25;
+// Completed, cancelled and pending SOL withdrawals in March 2024, oldest first
+const WITHDRAWAL_AMOUNTS: &[f64] = &[8., 4., 2.5];
+
+const TOLERANCE: f64 = 1e-9;
+
+// Parameters that differ on every request, so are ignored when matching a request to a recording
+const VOLATILE_PARAMS: &[&str] = &[
+    "timestamp",
+    "nonce",
+    "signature",
+    "recvWindow",
+    "startTime",
+    "endTime",
+    "start",
+    "end",
+];
+
+/// A part of the `ExchangeClient` contract, exercised against its own recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, IntoStaticStr)]
+pub enum Check {
+    /// Balances are parsed, with no more available than held
+    #[strum(serialize = "balances")]
+    Balances,
+    /// The bid is positive and the ask no lower
+    #[strum(serialize = "bid-ask")]
+    BidAsk,
+    /// An order is placed, partially filled, cancelled and reported at each step
+    #[strum(serialize = "order-lifecycle")]
+    OrderLifecycle,
+    /// Only credited deposits are listed
+    #[strum(serialize = "deposits")]
+    Deposits,
+    /// Completed, cancelled and pending withdrawals are told apart and listed oldest first
+    #[strum(serialize = "withdrawals")]
+    Withdrawals,
+    /// A rejected API key is reported as an authentication failure
+    #[strum(serialize = "authentication-error")]
+    AuthenticationError,
+    /// Exceeding the exchange's rate limit is reported as such
+    #[strum(serialize = "rate-limit-error")]
+    RateLimitError,
+    /// Any other error the exchange answers with is reported as a rejection
+    #[strum(serialize = "rejected-request")]
+    RejectedRequest,
+}
+
+pub const CHECKS: &[Check] = &[
+    Check::Balances,
+    Check::BidAsk,
+    Check::OrderLifecycle,
+    Check::Deposits,
+    Check::Withdrawals,
+    Check::AuthenticationError,
+    Check::RateLimitError,
+    Check::RejectedRequest,
+];
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", <&str>::from(self))
+    }
+}
+
+impl Check {
+    async fn run(
+        &self,
+        client: &dyn ExchangeClient,
+        server: &ReplayServer,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Check::Balances => check_balances(client).await,
+            Check::BidAsk => check_bid_ask(client).await,
+            Check::OrderLifecycle => check_order_lifecycle(client).await,
+            Check::Deposits => check_deposits(client).await,
+            Check::Withdrawals => check_withdrawals(client).await,
+            Check::AuthenticationError => {
+                check_error(client, server, ErrorCategory::Authentication).await
+            }
+            Check::RateLimitError => check_error(client, server, ErrorCategory::RateLimited).await,
+            Check::RejectedRequest => check_error(client, server, ErrorCategory::Rejected).await,
+        }
+    }
+
+    /// Whether an integration for `exchange` must pass the check.  Exchanges without the feature
+    /// are exempt
+    pub fn required(&self, exchange: Exchange) -> bool {
+        match exchange {
+            Exchange::Binance | Exchange::BinanceUs | Exchange::Kraken => true,
+            Exchange::Coinbase => !matches!(
+                self,
+                Check::BidAsk | Check::OrderLifecycle | Check::Deposits
+            ),
+            Exchange::Ftx | Exchange::FtxUs => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckOutcome {
+    Passed,
+    Failed(String),
+    /// There is no recording for a check the exchange isn't required to pass
+    Skipped,
+}
+
+/// Runs every check against a client for `exchange`, replaying the recordings in `dir`.  A
+/// required check without a recording fails
+pub async fn run(exchange: Exchange, dir: &Path) -> Vec<(Check, CheckOutcome)> {
+    let mut outcomes = vec![];
+    for check in CHECKS {
+        let path = dir.join(format!("{check}.log"));
+        let outcome = match fs::read_to_string(&path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                if check.required(exchange) {
+                    CheckOutcome::Failed(format!("No recording at {}", path.display()))
+                } else {
+                    CheckOutcome::Skipped
+                }
+            }
+            Err(err) => CheckOutcome::Failed(format!("{}: {err}", path.display())),
+            Ok(capture) => match run_check(exchange, *check, &capture).await {
+                Ok(()) => CheckOutcome::Passed,
+                Err(err) => CheckOutcome::Failed(err.to_string()),
+            },
+        };
+        outcomes.push((*check, outcome));
+    }
+    outcomes
+}
+
+// Each check gets a client of its own, so nothing cached from one recording leaks into the next
+async fn run_check(
+    exchange: Exchange,
+    check: Check,
+    capture: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server = ReplayServer::start(capture).await?;
+    let client = replay_client(exchange, &server)?;
+
+    let result = check.run(client.as_ref(), &server).await;
+    let unexpected_requests = server.unexpected_requests();
+    if unexpected_requests.is_empty() {
+        return result;
+    }
+    let unexpected_requests = format!("unrecorded requests: {}", unexpected_requests.join(", "));
+    Err(match result {
+        Ok(()) => unexpected_requests,
+        Err(err) => format!("{err} ({unexpected_requests})"),
+    }
+    .into())
+}
+
+/// A client for `exchange` whose requests are answered by `server`, signed with placeholder
+/// credentials
+pub fn replay_client(
+    exchange: Exchange,
+    server: &ReplayServer,
+) -> Result<Box<dyn ExchangeClient>, Box<dyn std::error::Error>> {
+    ExchangeClientBuilder::new(exchange)
+        .credentials(ExchangeCredentials {
+            api_key: API_KEY.into(),
+            secret: SECRET.into(),
+            subaccount: None,
+        })
+        .base_url(server.url())
+        .allow_http_localhost(true)
+        .build()
+}
+
+fn expect(what: &str, actual: f64, expected: f64) -> Result<(), Box<dyn std::error::Error>> {
+    if (actual - expected).abs() > TOLERANCE {
+        return Err(format!("{what} is {actual}, expected {expected}").into());
+    }
+    Ok(())
+}
+
+async fn check_balances(client: &dyn ExchangeClient) -> Result<(), Box<dyn std::error::Error>> {
+    let balances = client.balances().await?;
+    for (coin, balance) in &balances {
+        if !balance.available.is_finite()
+            || !balance.total.is_finite()
+            || balance.available < 0.
+            || balance.available > balance.total + TOLERANCE
+        {
+            return Err(format!(
+                "{coin} balance has {} available of {} total",
+                balance.available, balance.total
+            )
+            .into());
+        }
+    }
+
+    let sol = balances.get("SOL").ok_or("No SOL balance")?;
+    expect("SOL total", sol.total, SOL_TOTAL)?;
+    expect("SOL available", sol.available, SOL_AVAILABLE)
+}
+
+async fn check_bid_ask(client: &dyn ExchangeClient) -> Result<(), Box<dyn std::error::Error>> {
+    let pair = client.preferred_solusd_pair();
+    let bid_ask = client.bid_ask(&pair).await?;
+    if bid_ask.bid_price.is_nan() || bid_ask.bid_price <= 0. {
+        return Err(format!("{pair} bid of {} is not positive", bid_ask.bid_price).into());
+    }
+    if bid_ask.ask_price.is_nan() || bid_ask.ask_price < bid_ask.bid_price {
+        return Err(format!(
+            "{pair} ask of {} is below the bid of {}",
+            bid_ask.ask_price, bid_ask.bid_price
+        )
+        .into());
+    }
+    for amount in [bid_ask.bid_amount, bid_ask.ask_amount]
+        .into_iter()
+        .flatten()
+    {
+        if amount.is_nan() || amount < 0. {
+            return Err(format!("{pair} top of book amount of {amount} is negative").into());
+        }
+    }
+    Ok(())
+}
+
+fn expect_order(
+    order_status: &OrderStatus,
+    stage: &str,
+    open: bool,
+    filled_amount: f64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let open_or_closed = |open| if open { "open" } else { "closed" };
+    if order_status.open != open {
+        return Err(format!(
+            "{stage} order is {}, expected {}",
+            open_or_closed(order_status.open),
+            open_or_closed(open)
+        )
+        .into());
+    }
+    expect(
+        &format!("{stage} order filled amount"),
+        order_status.filled_amount,
+        filled_amount,
+    )
+}
+
+async fn check_order_lifecycle(
+    client: &dyn ExchangeClient,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pair = client.preferred_solusd_pair();
+    let order_id = client
+        .place_order(
+            &pair,
+            OrderSide::Sell,
+            ORDER_PRICE,
+            ORDER_AMOUNT,
+            CLIENT_ORDER_ID,
+        )
+        .await?;
+    if order_id.is_empty() {
+        return Err("Placed order has an empty order id".into());
+    }
+
+    let order_status = client.order_status(&pair, &order_id).await?;
+    expect_order(&order_status, "Placed", true, 0.)?;
+    if order_status.side != OrderSide::Sell {
+        return Err(format!("Placed order is a {:?}, expected a sell", order_status.side).into());
+    }
+    expect("Placed order price", order_status.price, ORDER_PRICE)?;
+    expect("Placed order amount", order_status.amount, ORDER_AMOUNT)?;
+
+    let order_status = client.order_status(&pair, &order_id).await?;
+    expect_order(&order_status, "Partially filled", true, ORDER_PARTIAL_FILL)?;
+    if let Some(average_fill_price) = order_status.average_fill_price {
+        expect("Average fill price", average_fill_price, ORDER_PRICE)?;
+    }
+
+    client.cancel_order(&pair, &order_id).await?;
+    let order_status = client.order_status(&pair, &order_id).await?;
+    expect_order(&order_status, "Cancelled", false, ORDER_PARTIAL_FILL)?;
+    if order_status.cancellation != Some(OrderCancellation::User) {
+        return Err(format!(
+            "Cancelled order reports {:?}, expected a cancellation by the user",
+            order_status.cancellation
+        )
+        .into());
+    }
+    Ok(())
+}
+
+async fn check_deposits(client: &dyn ExchangeClient) -> Result<(), Box<dyn std::error::Error>> {
+    let deposits = client
+        .recent_deposits()
+        .await?
+        .ok_or("Recent deposits are not reported")?;
+    let deposit = match deposits.as_slice() {
+        [deposit] => deposit,
+        _ => {
+            return Err(format!(
+                "{} deposits listed, expected only the credited one",
+                deposits.len()
+            )
+            .into())
+        }
+    };
+
+    if deposit.tx_id.is_empty() {
+        return Err("Deposit has no transaction id".into());
+    }
+    if deposit.token != Some(MaybeToken::SOL()) {
+        return Err(format!("Deposit is of {:?}, expected SOL", deposit.token).into());
+    }
+    expect("Deposit amount", deposit.amount, DEPOSIT_AMOUNT)
+}
+
+async fn check_withdrawals(client: &dyn ExchangeClient) -> Result<(), Box<dyn std::error::Error>> {
+    let (start, end) = ReportingTimeZone::Utc.days(
+        NaiveDate::from_ymd(2024, 3, 1),
+        NaiveDate::from_ymd(2024, 3, 31),
+    );
+    let withdrawals = client.withdrawals(start, end).await?;
+
+    let states = withdrawals
+        .iter()
+        .map(
+            |withdrawal| match (withdrawal.completed, &withdrawal.tx_id) {
+                (true, Some(_)) => "completed",
+                (true, None) => "cancelled",
+                (false, _) => "pending",
+            },
+        )
+        .collect::<Vec<_>>();
+    if states != ["completed", "cancelled", "pending"] {
+        return Err(format!(
+            "Withdrawals are {}, expected completed, cancelled and pending, oldest first",
+            states.join(", ")
+        )
+        .into());
+    }
+    if !withdrawals
+        .windows(2)
+        .all(|pair| pair[0].time <= pair[1].time)
+    {
+        return Err("Withdrawals are not listed oldest first".into());
+    }
+
+    for (withdrawal, amount) in withdrawals.iter().zip(WITHDRAWAL_AMOUNTS) {
+        if withdrawal.tag.is_empty() {
+            return Err("Withdrawal has no tag".into());
+        }
+        if withdrawal.token != MaybeToken::SOL() {
+            return Err(format!(
+                "Withdrawal {} is of {}, expected SOL",
+                withdrawal.tag, withdrawal.token
+            )
+            .into());
+        }
+        expect(
+            &format!("Withdrawal {} amount", withdrawal.tag),
+            withdrawal.amount,
+            *amount,
+        )?;
+    }
+    Ok(())
+}
+
+// An authenticated request every implementation makes: the API key's permissions, or the day's
+// withdrawals where the exchange doesn't report permissions
+async fn authenticated_request(
+    client: &dyn ExchangeClient,
+    server: &ReplayServer,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let requests = server.request_count();
+    let result = client.api_permissions().await.map(|_| ());
+    if server.request_count() > requests {
+        return result;
+    }
+    let now = Utc::now();
+    client
+        .withdrawals(now - chrono::Duration::days(1), now)
+        .await
+        .map(|_| ())
+}
+
+async fn check_error(
+    client: &dyn ExchangeClient,
+    server: &ReplayServer,
+    expected: ErrorCategory,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match authenticated_request(client, server).await {
+        Ok(()) => Err(format!("Request succeeded, expected a {} error", expected.name()).into()),
+        Err(err) => {
+            let category = ErrorCategory::of(err.as_ref());
+            if category != expected {
+                return Err(format!(
+                    "Request failed with a {} error, expected a {} error: {err}",
+                    category.name(),
+                    expected.name()
+                )
+                .into());
+            }
+            Ok(())
+        }
+    }
+}
+
+// A recorded request, and the responses recorded for it in order
+#[derive(Debug)]
+struct Recording {
+    method: String,
+    path: String,
+    params: Vec<(String, String)>,
+    responses: Vec<(u16, String)>,
+    replayed: usize,
+}
+
+// Decodes a percent-encoded query string or form value
+fn decode_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// The parameters of an "a=1&b=2" query string or form body that identify a request
+fn request_params(form: &str) -> Vec<(String, String)> {
+    form.split('&')
+        .filter_map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let (name, value) = (decode_component(name), decode_component(value));
+            // Scrubbed credentials can't be matched
+            if name.is_empty() || VOLATILE_PARAMS.contains(&name.as_str()) || value == "<redacted>"
+            {
+                None
+            } else {
+                Some((name, value))
+            }
+        })
+        .collect()
+}
+
+// The parameters of a JSON object body that identify a request: its top-level strings, numbers
+// and booleans
+fn json_params(body: &str) -> Vec<(String, String)> {
+    let fields = match serde_json::from_str::<serde_json::Value>(body) {
+        Ok(serde_json::Value::Object(fields)) => fields,
+        _ => return vec![],
+    };
+    fields
+        .into_iter()
+        .filter_map(|(name, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                serde_json::Value::Number(value) => value.to_string(),
+                serde_json::Value::Bool(value) => value.to_string(),
+                _ => return None,
+            };
+            (!VOLATILE_PARAMS.contains(&name.as_str()) && value != "<redacted>")
+                .then_some((name, value))
+        })
+        .collect()
+}
+
+fn request_key(method: &str, target: &str, body: &str) -> (String, String, Vec<(String, String)>) {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut params = request_params(query);
+    if body.starts_with('{') || body.starts_with('[') {
+        params.extend(json_params(body));
+    } else {
+        params.extend(request_params(body));
+    }
+    (method.to_ascii_uppercase(), path.to_string(), params)
+}
+
+// Parses a `debug_capture` file.  Anything before the first request, such as a description of
+// the recording, is ignored
+fn parse_recordings(capture: &str) -> Result<Vec<Recording>, String> {
+    let mut recordings = Vec::<Recording>::new();
+    for entry in format!("\n{capture}").split("\n==> ").skip(1) {
+        let (request, response) = entry
+            .split_once("\n<== ")
+            .ok_or_else(|| format!("Recorded request has no response: {entry}"))?;
+
+        let request_line = request.lines().next().unwrap_or_default();
+        let (method, url) = match request_line.split_whitespace().collect::<Vec<_>>()[..] {
+            [_time, method, url] => (method, url),
+            _ => return Err(format!("Malformed recorded request: {request_line}")),
+        };
+        // Only the path and query of the URL are matched, whichever host it was recorded from
+        let target = match url.split_once("://") {
+            Some((_, url)) => url.find('/').map_or("/", |i| &url[i..]),
+            None => url,
+        };
+        // The request body follows the headers after a blank line
+        let body = request
+            .split_once("\n\n")
+            .map_or("", |(_, body)| body.trim());
+
+        let (status_line, response_body) = response.split_once('\n').unwrap_or((response, ""));
+        let status = status_line
+            .split_whitespace()
+            .next()
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| format!("Malformed recorded response: {status_line}"))?;
+        let response = (status, response_body.trim().to_string());
+
+        let (method, path, params) = request_key(method, target, body);
+        match recordings.iter_mut().find(|recording| {
+            recording.method == method && recording.path == path && recording.params == params
+        }) {
+            Some(recording) => recording.responses.push(response),
+            None => recordings.push(Recording {
+                method,
+                path,
+                params,
+                responses: vec![response],
+                replayed: 0,
+            }),
+        }
+    }
+    Ok(recordings)
+}
+
+#[derive(Default)]
+struct ReplayState {
+    recordings: Vec<Recording>,
+    requests: usize,
+    unexpected_requests: Vec<String>,
+}
+
+impl ReplayState {
+    // The most specific recording a request matches answers it, with its recorded responses in
+    // turn and the last one repeated
+    fn respond(&mut self, method: &str, target: &str, body: &str) -> (u16, String) {
+        self.requests += 1;
+        let (method, path, params) = request_key(method, target, body);
+        match self
+            .recordings
+            .iter_mut()
+            .filter(|recording| {
+                recording.method == method
+                    && recording.path == path
+                    && recording.params.iter().all(|param| params.contains(param))
+            })
+            .max_by_key(|recording| recording.params.len())
+        {
+            Some(recording) => {
+                let response = recording.responses
+                    [recording.replayed.min(recording.responses.len() - 1)]
+                .clone();
+                recording.replayed += 1;
+                response
+            }
+            None => {
+                self.unexpected_requests.push(format!("{method} {target}"));
+                (404, r#"{"error":"no recorded response"}"#.into())
+            }
+        }
+    }
+}
+
+/// An HTTP server on localhost answering requests with the responses recorded for them
+pub struct ReplayServer {
+    url: String,
+    state: Arc<Mutex<ReplayState>>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl ReplayServer {
+    /// Serves the requests and responses of a `debug_capture` file
+    pub async fn start(capture: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let state = Arc::new(Mutex::new(ReplayState {
+            recordings: parse_recordings(capture)?,
+            ..ReplayState::default()
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+
+        let server = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = serve_connection(stream, &state).await {
+                            log::debug!("Replay connection failed: {err}");
+                        }
+                    });
+                }
+            }
+        });
+        Ok(Self { url, state, server })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Number of requests received so far
+    pub fn request_count(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Requests that matched no recording, as "METHOD /path?query"
+    pub fn unexpected_requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().unexpected_requests.clone()
+    }
+}
+
+impl Drop for ReplayServer {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+// Answers a single request, then closes the connection
+async fn serve_connection(mut stream: TcpStream, state: &Mutex<ReplayState>) -> io::Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 4096];
+    let header_end = loop {
+        if let Some(i) = request.windows(4).position(|window| window == b"\r\n\r\n") {
+            break i + 4;
+        }
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..len]);
+    };
+
+    let head = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let content_length = head
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while request.len() < header_end + content_length {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+    let body = String::from_utf8_lossy(&request[header_end..]).into_owned();
+
+    let mut request_line = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (status, response_body) = state.lock().unwrap().respond(method, target, &body);
+
+    let reason = reqwest::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|status| status.canonical_reason())
+        .unwrap_or_default();
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{response_body}",
+                response_body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the checks against the recordings kept in the repository, failing on any check that
+    // fails or that's required but not recorded
+    async fn assert_conformance(exchange: Exchange) {
+        let recordings = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("conformance")
+            .join(exchange.name());
+        let failures = run(exchange, &recordings)
+            .await
+            .into_iter()
+            .filter_map(|(check, outcome)| match outcome {
+                CheckOutcome::Failed(err) => Some(format!("{check}: {err}")),
+                CheckOutcome::Passed | CheckOutcome::Skipped => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(
+            failures.is_empty(),
+            "{exchange} conformance failed:\n{}",
+            failures.join("\n")
+        );
+    }
+
+    #[cfg(feature = "binance")]
+    #[tokio::test]
+    async fn binance_conforms() {
+        assert_conformance(Exchange::Binance).await;
+    }
+
+    #[cfg(feature = "coinbase")]
+    #[tokio::test]
+    async fn coinbase_conforms() {
+        assert_conformance(Exchange::Coinbase).await;
+    }
+
+    #[cfg(feature = "kraken")]
+    #[tokio::test]
+    async fn kraken_conforms() {
+        assert_conformance(Exchange::Kraken).await;
+    }
+
+    #[tokio::test]
+    async fn a_missing_required_recording_fails() {
+        let outcomes = run(Exchange::Coinbase, Path::new("no-such-recordings")).await;
+        for (check, outcome) in outcomes {
+            match outcome {
+                CheckOutcome::Failed(err) => {
+                    assert!(check.required(Exchange::Coinbase), "{check}");
+                    assert!(err.starts_with("No recording at"), "{check}: {err}");
+                }
+                CheckOutcome::Skipped => assert!(!check.required(Exchange::Coinbase), "{check}"),
+                CheckOutcome::Passed => panic!("{check} passed without a recording"),
+            }
+        }
+    }
+}
@@ -5,19 +5,20 @@ use {
     rust_decimal::prelude::*,
     separator::FixedPlaceSeparatable,
     serde::{Deserialize, Serialize},
+    sha1::{Digest, Sha1},
     solana_sdk::{
         clock::{Epoch, Slot},
         pubkey::Pubkey,
         signature::Signature,
     },
     std::{
-        collections::{HashMap, HashSet},
+        collections::{BTreeMap, HashMap, HashSet},
         fmt, fs, io,
         path::{Path, PathBuf},
         time::{SystemTime, UNIX_EPOCH},
     },
     strum::{EnumString, IntoStaticStr},
-    sys::{exchange::*, token::*},
+    sys::{exchange::*, settings::Setting, token::*},
     thiserror::Error,
 };
 
@@ -58,6 +59,9 @@ pub enum DbError {
 
     #[error("Import failed: {0}")]
     ImportFailed(String),
+
+    #[error("Withdrawal intent does not exist: {0}")]
+    WithdrawalIntentDoesNotExist(String),
 }
 
 pub type DbResult<T> = std::result::Result<T, DbError>;
@@ -107,6 +111,155 @@ pub struct PendingDeposit {
     pub exchange: Exchange,
     pub amount: u64, // lamports/tokens
     pub transfer: PendingTransfer,
+    #[serde(default)]
+    pub sent_at: Option<DateTime<Utc>>, // `None` for deposits recorded before this was tracked
+    #[serde(default)]
+    pub overdue_warned: bool,
+}
+
+/// A deposit sent to an exchange outside of sys, tracked by hand until the exchange credits it
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ExternalDeposit {
+    pub exchange: Exchange,
+    #[serde(with = "field_as_string")]
+    pub signature: Signature,
+    pub token: MaybeToken,
+    pub amount: u64,
+    pub sent_at: DateTime<Utc>,
+    #[serde(default)]
+    pub overdue_warned: bool,
+}
+
+/// A deposit sent on-chain that the exchange has not credited yet, whether sent by sys or not
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct InTransitDeposit {
+    pub exchange: Exchange,
+    #[serde(with = "field_as_string")]
+    pub signature: Signature,
+    pub token: MaybeToken,
+    pub amount: u64,
+    pub sent_at: Option<DateTime<Utc>>,
+    pub external: bool,
+    pub overdue_warned: bool,
+}
+
+/// Which side of an exchange deposit a reconciliation entry comes from
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum DepositSide {
+    /// A send to the exchange deposit address
+    OnChain,
+    /// An exchange credit with no known send
+    Exchange,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum DepositReconciliationStatus {
+    /// Credited by the exchange under the send's transaction signature
+    Matched,
+    /// Credited by the exchange under a different transaction id, matched by token, amount and
+    /// time instead
+    MatchedByAmount,
+    /// Sent but not credited yet
+    Pending,
+    /// Not credited within the allowed age, or credited with no known send
+    Unmatched,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ReconciledDeposit {
+    pub exchange: Exchange,
+    pub side: DepositSide,
+    pub tx_id: String,
+    pub token: MaybeToken,
+    pub amount: u64, // lamports/tokens
+    pub time: Option<DateTime<Utc>>,
+    pub status: DepositReconciliationStatus,
+    /// The exchange's transaction id for a send credited under a different one
+    pub exchange_tx_id: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SnapshotBalance {
+    pub amount: f64,
+    pub usd_price: Option<f64>,
+    /// The exchange market, or the external source, `usd_price` came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub price_source: Option<String>,
+}
+
+impl SnapshotBalance {
+    pub fn usd_value(&self) -> Option<f64> {
+        self.usd_price.map(|usd_price| usd_price * self.amount)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ExchangeBalanceSnapshot {
+    pub exchange: Exchange,
+    pub account: String,
+    /// Why the balances couldn't be fetched, in which case `balances` is empty
+    pub error: Option<String>,
+    pub balances: BTreeMap<String, SnapshotBalance>, // keyed by coin
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BalanceSnapshot {
+    pub time: DateTime<Utc>,
+    pub exchanges: Vec<ExchangeBalanceSnapshot>,
+}
+
+impl BalanceSnapshot {
+    /// True if any exchange was unreachable when the snapshot was taken
+    pub fn is_partial(&self) -> bool {
+        self.exchanges
+            .iter()
+            .any(|exchange_snapshot| exchange_snapshot.error.is_some())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct QuotingFill {
+    pub time: DateTime<Utc>,
+    pub order_id: String,
+    pub side: OrderSide,
+    pub amount: f64,
+    pub price: f64,
+    pub fee: Option<(f64, String)>,
+}
+
+/// Position and fills of a two-sided quoting session on one market, kept across restarts
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct QuotingSession {
+    pub exchange: Exchange,
+    pub pair: String,
+    pub start_time: DateTime<Utc>,
+    pub fills: Vec<QuotingFill>,
+    /// Set once the loss limit is reached, after which the session places no more orders
+    pub halted: Option<String>,
+}
+
+impl QuotingSession {
+    /// Base currency bought less base currency sold
+    pub fn inventory(&self) -> f64 {
+        self.fills
+            .iter()
+            .map(|fill| match fill.side {
+                OrderSide::Buy => fill.amount,
+                OrderSide::Sell => -fill.amount,
+            })
+            .sum()
+    }
+
+    /// Quote currency received less quote currency paid, before fees
+    pub fn cash_flow(&self) -> f64 {
+        self.fills
+            .iter()
+            .map(|fill| match fill.side {
+                OrderSide::Buy => -fill.amount * fill.price,
+                OrderSide::Sell => fill.amount * fill.price,
+            })
+            .sum()
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -126,6 +279,556 @@ pub struct PendingWithdrawal {
     pub lots: Vec<Lot>,
 }
 
+// The deposit address an exchange first reported for an account and token.  Funds are only sent to
+// the exchange while it keeps reporting this address, and a new one must be accepted explicitly
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct PinnedDepositAddress {
+    pub exchange: Exchange,
+    pub account: String,
+    pub token: MaybeToken,
+    #[serde(with = "field_as_string")]
+    pub address: Pubkey,
+    pub pinned_at: DateTime<Utc>,
+    // Addresses this one replaced, oldest first
+    #[serde(default)]
+    pub replaced: Vec<ReplacedDepositAddress>,
+}
+
+// A deposit address as the exchange last reported it, served in place of asking again until it's
+// older than `deposit_address_cache_ttl()`.  `credentials` identifies the API key it was fetched
+// with, as another key may belong to another account with other addresses
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CachedDepositAddress {
+    pub exchange: Exchange,
+    pub account: String,
+    pub token: MaybeToken,
+    #[serde(with = "field_as_string")]
+    pub address: Pubkey,
+    pub verified_at: DateTime<Utc>,
+    pub credentials: String,
+}
+
+// Identifies an API key without keeping a copy of it outside of the credentials database
+fn credentials_fingerprint(exchange_credentials: &ExchangeCredentials) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(exchange_credentials.api_key.as_bytes());
+    hasher.update([0]);
+    hasher.update(
+        exchange_credentials
+            .subaccount
+            .as_deref()
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+    hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ReplacedDepositAddress {
+    #[serde(with = "field_as_string")]
+    pub address: Pubkey,
+    pub pinned_at: DateTime<Utc>,
+    pub replaced_at: DateTime<Utc>,
+}
+
+// Fiat has no lots, so a fiat withdrawal is only recorded here, and kept once it settles
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct FiatWithdrawal {
+    pub exchange: Exchange,
+    pub withdrawal_id: String,
+    pub currency: String,
+    pub amount: f64,
+    pub method_id: String,
+    pub request_time: DateTime<Utc>,
+    pub state: FiatWithdrawalState,
+    pub fee: Option<f64>,
+    pub last_update: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum StakeFundingStepKind {
+    Deactivate,
+    Withdraw,
+    Deposit,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct StakeFundingStep {
+    pub kind: StakeFundingStepKind,
+    #[serde(with = "field_as_string")]
+    pub signature: Signature,
+    pub amount: Option<u64>,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum StakeFundingState {
+    /// Waiting for the stake to become fully inactive
+    Deactivating,
+    /// Moving the lamports from the stake account to the wallet
+    Withdrawing,
+    /// Sending the withdrawn SOL to the exchange and waiting for it to be credited
+    Depositing,
+    Completed {
+        exchange_tx_id: String,
+    },
+}
+
+// Funding an exchange from a stake account spans at least an epoch boundary, so each step is
+// recorded as it's taken and the next run picks up from there
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StakeFunding {
+    #[serde(with = "field_as_string")]
+    pub stake_address: Pubkey,
+    #[serde(with = "field_as_string")]
+    pub wallet_address: Pubkey,
+    pub exchange: Exchange,
+    pub exchange_account: String,
+    pub sol_reserve: u64,
+    pub lot_selection_method: LotSelectionMethod,
+    pub started_at: DateTime<Utc>,
+    pub state: StakeFundingState,
+    pub steps: Vec<StakeFundingStep>,
+}
+
+impl StakeFunding {
+    pub fn step(&self, kind: StakeFundingStepKind) -> Option<&StakeFundingStep> {
+        self.steps.iter().find(|step| step.kind == kind)
+    }
+
+    pub fn is_completed(&self) -> bool {
+        matches!(self.state, StakeFundingState::Completed { .. })
+    }
+}
+
+// Withdrawals worth at least `threshold_usd` wait for a second invocation to approve them
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct WithdrawalApprovalPolicy {
+    pub threshold_usd: f64,
+    pub expiry_hours: u32,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub enum WithdrawalIntentState {
+    Pending,
+    Executed { tag: String },
+    Failed { error: String },
+    Cancelled,
+    Expired,
+}
+
+impl WithdrawalIntentState {
+    pub fn is_pending(&self) -> bool {
+        matches!(self, Self::Pending)
+    }
+}
+
+impl fmt::Display for WithdrawalIntentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pending => write!(f, "pending"),
+            Self::Executed { tag } => write!(f, "executed as {tag}"),
+            Self::Failed { error } => write!(f, "failed: {error}"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Expired => write!(f, "expired"),
+        }
+    }
+}
+
+// A withdrawal held back for approval, executed as recorded once its confirmation code is given
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct WithdrawalIntent {
+    pub code: String,
+    pub exchange: Exchange,
+    pub exchange_account: String,
+    pub token: MaybeToken,
+    pub amount: u64,
+    #[serde(with = "field_as_string")]
+    pub to_address: Pubkey,
+    pub lot_selection_method: LotSelectionMethod,
+    pub lot_numbers: Option<HashSet<usize>>,
+    pub usd_value: Option<f64>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub state: WithdrawalIntentState,
+    // Set for a fiat withdrawal, whose `token`, `amount`, `to_address` and lots are meaningless
+    #[serde(default)]
+    pub fiat: Option<FiatWithdrawalIntent>,
+}
+
+impl WithdrawalIntent {
+    /// The amount withdrawn, with its currency
+    pub fn amount_description(&self) -> String {
+        match &self.fiat {
+            Some(fiat) => format_quote_amount(&fiat.amount.to_string(), &fiat.currency),
+            None => format!(
+                "{}{}",
+                self.token.symbol(),
+                self.token.ui_amount(self.amount)
+            ),
+        }
+    }
+
+    /// Where the withdrawal goes, an address or a fiat withdrawal method
+    pub fn destination(&self) -> String {
+        match &self.fiat {
+            Some(fiat) => fiat.method_id.clone(),
+            None => self.to_address.to_string(),
+        }
+    }
+}
+
+// A fiat currency withdrawal to a registered withdrawal method, held back for approval
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct FiatWithdrawalIntent {
+    pub currency: String,
+    pub amount: f64,
+    pub method_id: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum WithdrawalAuditEvent {
+    Created,
+    Approved,
+    Executed,
+    Failed,
+    Cancelled,
+    Expired,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct WithdrawalAuditEntry {
+    pub time: DateTime<Utc>,
+    pub code: String,
+    pub event: WithdrawalAuditEvent,
+    // User and host that ran the command
+    pub actor: String,
+    pub detail: String,
+}
+
+// Where an evacuation may withdraw `token` to, allow-listed ahead of time so that funds are never
+// sent under pressure to an address entered in the moment
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct EvacuationAddress {
+    pub token: MaybeToken,
+    #[serde(with = "field_as_string")]
+    pub address: Pubkey,
+    pub added: DateTime<Utc>,
+    // User and host that added the address
+    pub added_by: String,
+}
+
+// A declared amount of `token` to sell on `exchange` through any number of orders.  Orders placed
+// within it are counted against it, so that together they never sell more than `ui_amount`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UnwindTarget {
+    pub name: String,
+    pub exchange: Exchange,
+    pub token: MaybeToken,
+    pub ui_amount: f64,
+    pub created: DateTime<Utc>,
+    // Orders placed within the target, oldest first
+    pub order_ids: Vec<String>,
+}
+
+// How far an unwind target has got, from the fills recorded for its orders.  The unfilled part of
+// an open order may fill at any moment, so it's committed until the order closes
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UnwindProgress {
+    pub ui_amount: f64,
+    // Sold by closed orders, and by open orders where their partial fills are known
+    pub filled: f64,
+    // Still unfilled in open orders
+    pub open: f64,
+}
+
+impl UnwindProgress {
+    /// Amount of the target not sold yet
+    pub fn remaining(&self) -> f64 {
+        (self.ui_amount - self.filled).max(0.)
+    }
+
+    /// Amount a new order may still sell without the target being exceeded, were every open order
+    /// to fill
+    pub fn uncommitted(&self) -> f64 {
+        (self.ui_amount - self.filled - self.open).max(0.)
+    }
+}
+
+// A lending offer as submitted, with the rate the exchange estimated when it was made.  The offer
+// stands until the next one for the same coin
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct LendingOfferRecord {
+    pub exchange: Exchange,
+    pub coin: String,
+    pub amount: f64,
+    // Annual percentage, as reported in `LendingInfo::estimate_rate`
+    pub estimate_rate: f64,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum ManagedOrderOutcome {
+    Filled,
+    Cancelled,
+    // Never reached the exchange, or refused by it
+    Rejected,
+    // Cancelled at its expiry, by `sys` or by the exchange
+    Expired,
+}
+
+// Where a managed order is in its lifecycle.  `Intent` is recorded with the client order id before
+// the order is sent, so that an interrupted placement is looked up on the exchange rather than
+// placed again
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum ManagedOrderState {
+    Intent,
+    Submitted,
+    Open,
+    PartiallyFilled,
+    Closed(ManagedOrderOutcome),
+}
+
+impl ManagedOrderState {
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, Self::Closed(_))
+    }
+}
+
+impl fmt::Display for ManagedOrderState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Closed(outcome) => write!(f, "{outcome:?}"),
+            state => write!(f, "{state:?}"),
+        }
+    }
+}
+
+// An order placed by a `sys` strategy, tracked from before it's sent until it closes.  Identified
+// by its client order id, as the exchange's order id is only known once it's been placed
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ManagedOrder {
+    pub client_order_id: String,
+    pub exchange: Exchange,
+    pub pair: String,
+    pub side: OrderSide,
+    pub price: f64,
+    pub amount: f64,
+    pub taker: bool,
+    pub strategy: String,
+    pub expiry: Option<DateTime<Utc>>,
+    pub order_id: Option<String>,
+    pub filled_amount: f64,
+    pub state: ManagedOrderState,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    // Why the order was rejected, or cancelled by the exchange
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, EnumString, IntoStaticStr)]
+pub enum LimitedOperation {
+    // Buys and sells alike
+    #[strum(serialize = "order")]
+    Order,
+    #[strum(serialize = "buy")]
+    Buy,
+    #[strum(serialize = "sell")]
+    Sell,
+    #[strum(serialize = "withdrawal")]
+    Withdrawal,
+}
+
+pub const POSSIBLE_LIMITED_OPERATION_VALUES: &[&str] = &["order", "buy", "sell", "withdrawal"];
+
+impl LimitedOperation {
+    pub fn covers(self, operation: Self) -> bool {
+        self == operation || (self == Self::Order && matches!(operation, Self::Buy | Self::Sell))
+    }
+}
+
+impl fmt::Display for LimitedOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", <&str>::from(self))
+    }
+}
+
+// A cap on the amount of `token`, or on the USD value when `token` is `None`, that one operation
+// may move, or that all operations may move within the trailing `window_hours`
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SpendingLimit {
+    pub operation: LimitedOperation,
+    pub token: Option<MaybeToken>,
+    pub max: f64,
+    pub window_hours: Option<u32>,
+}
+
+impl SpendingLimit {
+    pub fn format_value(&self, value: f64) -> String {
+        match self.token {
+            Some(token) => format!("{}{value}", token.symbol()),
+            None => format!("${}", value.separated_string_with_fixed_place(2)),
+        }
+    }
+
+    // Whether `other` caps the same thing, so that setting it replaces this limit
+    pub fn same_scope(&self, other: &Self) -> bool {
+        self.operation == other.operation
+            && self.token == other.token
+            && self.window_hours == other.window_hours
+    }
+}
+
+impl fmt::Display for SpendingLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} limit of {} per {}",
+            self.operation,
+            self.format_value(self.max),
+            match self.window_hours {
+                Some(window_hours) => format!("{window_hours} hours"),
+                None => "operation".into(),
+            }
+        )
+    }
+}
+
+// An order placed or withdrawal requested, counted against the rolling-window spending limits.
+// Orders count in full when placed, whether or not they go on to fill
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SpendingRecord {
+    pub time: DateTime<Utc>,
+    pub operation: LimitedOperation,
+    pub exchange: Exchange,
+    pub token: MaybeToken,
+    pub ui_amount: f64,
+    pub usd_value: Option<f64>,
+    // Order id or withdrawal tag
+    pub reference: String,
+    // The currency of a fiat withdrawal, which only counts against USD limits and whose `token`
+    // is meaningless
+    #[serde(default)]
+    pub fiat_currency: Option<String>,
+}
+
+// What's been moved within the rolling window of a spending limit
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct SpendingLimitUsage {
+    pub used: f64,
+    // Operations that weren't valued in USD when they were recorded, which a USD limit must value
+    // before it can be checked
+    pub unvalued: Vec<SpendingRecord>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, IntoStaticStr)]
+pub enum ExchangeJournalKind {
+    #[strum(serialize = "fill")]
+    Fill,
+    #[strum(serialize = "fee")]
+    Fee,
+    #[strum(serialize = "deposit")]
+    Deposit,
+    #[strum(serialize = "withdrawal")]
+    Withdrawal,
+    #[strum(serialize = "conversion")]
+    Conversion,
+    #[strum(serialize = "interest")]
+    Interest,
+}
+
+impl fmt::Display for ExchangeJournalKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", <&str>::from(self))
+    }
+}
+
+// A change `sys` made to, or observed in, an exchange balance.  Fills are timed when the order
+// is synced and deposits when the exchange credits them, so that applying the entries after a
+// balance snapshot reproduces the balance the exchange should report
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ExchangeJournalEntry {
+    pub time: DateTime<Utc>,
+    pub exchange: Exchange,
+    pub kind: ExchangeJournalKind,
+    pub coin: String,
+    // Positive when the balance grows
+    pub amount: f64,
+    // Order id, withdrawal tag or deposit signature
+    pub reference: String,
+    // Recovered from the exchange's account history rather than recorded as it happened
+    #[serde(default)]
+    pub backfilled: bool,
+}
+
+// A range of an exchange's account history that could not be backfilled, and why
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct UnavailableHistory {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub reason: String,
+}
+
+// Progress backfilling one category of an exchange's account history into the exchange journal,
+// walking from `start` to `end` oldest first.  `end` is when the journal began recording the
+// exchange, so that backfilled entries never overlap entries recorded as they happened
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct HistoryBackfill {
+    pub exchange: Exchange,
+    pub category: HistoryCategory,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub completed_through: DateTime<Utc>,
+    // Journal entries backfilled so far
+    pub records: usize,
+    pub unavailable: Vec<UnavailableHistory>,
+}
+
+impl HistoryBackfill {
+    pub fn completed(&self) -> bool {
+        self.completed_through >= self.end
+    }
+
+    /// Moves past history up to `end` that can't be retrieved, recording the range and why
+    pub fn skip_unavailable(&mut self, end: DateTime<Utc>, reason: String) {
+        match self.unavailable.last_mut() {
+            Some(last) if last.end == self.completed_through && last.reason == reason => {
+                last.end = end;
+            }
+            _ => self.unavailable.push(UnavailableHistory {
+                start: self.completed_through,
+                end,
+                reason,
+            }),
+        }
+        self.completed_through = end;
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum SpendingAuditEvent {
+    LimitSet,
+    LimitRemoved,
+    LimitOverridden,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SpendingAuditEntry {
+    pub time: DateTime<Utc>,
+    pub event: SpendingAuditEvent,
+    // User and host that ran the command
+    pub actor: String,
+    pub detail: String,
+}
+
+// Spending records are kept this long, which bounds the longest rolling window
+pub const MAX_SPENDING_WINDOW_HOURS: u32 = 31 * 24;
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct PendingTransfer {
     #[serde(with = "field_as_string")]
@@ -176,6 +879,29 @@ pub struct OpenOrder {
 
     #[serde(default = "MaybeToken::SOL")]
     pub token: MaybeToken,
+
+    #[serde(default)]
+    pub client_order_id: Option<String>,
+
+    // Cancelled by `sys` on the first sync after this time, unless `native_expiry` is set and the
+    // exchange cancels it
+    #[serde(default)]
+    pub expiry: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub native_expiry: bool,
+
+    // Kept at a fixed distance from the top of book by `sys exchange peg`
+    #[serde(default)]
+    pub pegged: bool,
+}
+
+/// An exchange order as the journal recorded it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct JournaledOrder {
+    pub side: OrderSide,
+    pub token: MaybeToken,
+    pub filled_amount: u64, // lamports/tokens
+    pub open: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
@@ -193,6 +919,9 @@ pub enum LotAcquistionKind {
         exchange: Exchange,
         pair: String,
         order_id: String,
+        /// Placed outside of `sys` and imported from the exchange's order history
+        #[serde(default)]
+        external: bool,
     },
     NotAvailable, // Generic acquisition subject to income tax
     Fiat,         // Generic acquisition with post-tax fiat
@@ -215,7 +944,12 @@ impl fmt::Display for LotAcquistionKind {
                 exchange,
                 pair,
                 order_id,
-            } => write!(f, "{exchange:?} {pair}, order {order_id}"),
+                external,
+            } => write!(
+                f,
+                "{exchange:?} {pair}, {}order {order_id}",
+                if *external { "external " } else { "" }
+            ),
             LotAcquistionKind::Fiat => {
                 write!(f, "post tax")
             }
@@ -335,6 +1069,12 @@ pub enum LotDisposalKind {
         pair: String,
         order_id: String,
         fee: Option<(f64, String)>,
+        /// `fee` valued in USD at the time of the trade
+        #[serde(default)]
+        usd_fee: Option<UsdFee>,
+        /// Placed outside of `sys` and imported from the exchange's order history
+        #[serde(default)]
+        external: bool,
     },
     Other {
         description: String,
@@ -362,7 +1102,17 @@ impl LotDisposalKind {
             | LotDisposalKind::Fiat { .. } => None,
         }
     }
-}
+
+    pub fn usd_fee(&self) -> Option<&UsdFee> {
+        match self {
+            LotDisposalKind::Usd { usd_fee, .. } => usd_fee.as_ref(),
+            LotDisposalKind::Other { .. }
+            | LotDisposalKind::Swap { .. }
+            | LotDisposalKind::WithdrawalFee { .. }
+            | LotDisposalKind::Fiat { .. } => None,
+        }
+    }
+}
 
 impl fmt::Display for LotDisposalKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -372,14 +1122,22 @@ impl fmt::Display for LotDisposalKind {
                 pair,
                 order_id,
                 fee,
+                usd_fee,
+                external,
             } => write!(
                 f,
-                "{:?} {}, order {}{})",
+                "{:?} {}, {}order {}{})",
                 exchange,
                 pair,
+                if *external { "external " } else { "" },
                 order_id,
-                match fee {
-                    Some((amount, coin)) if *amount > 0. => format!(" (fee: {amount} {coin})"),
+                match (fee, usd_fee) {
+                    (Some((amount, coin)), Some(usd_fee)) if *amount > 0. => {
+                        format!(" (fee: {amount} {coin}, {usd_fee})")
+                    }
+                    (Some((amount, coin)), None) if *amount > 0. => {
+                        format!(" (fee: {amount} {coin})")
+                    }
                     _ => "".into(),
                 }
             ),
@@ -624,6 +1382,29 @@ pub struct DbData {
     transitory_sweep_stake_accounts: Vec<TransitorySweepStake>,
     tax_rate: Option<TaxRate>,
     validator_credit_scores: Option<HashMap<Epoch, Vec<ValidatorCreditScore>>>,
+    historical_prices: Option<HashMap<String, Decimal>>, // keyed by "source/pair/date"
+    reconciled_deposits: Option<Vec<ReconciledDeposit>>,
+    balance_snapshots: Option<Vec<BalanceSnapshot>>,
+    quoting_sessions: Option<Vec<QuotingSession>>,
+    fiat_withdrawals: Option<Vec<FiatWithdrawal>>,
+    pinned_deposit_addresses: Option<Vec<PinnedDepositAddress>>,
+    cached_deposit_addresses: Option<Vec<CachedDepositAddress>>,
+    withdrawal_approval_policy: Option<WithdrawalApprovalPolicy>,
+    withdrawal_intents: Option<Vec<WithdrawalIntent>>,
+    withdrawal_audit_log: Option<Vec<WithdrawalAuditEntry>>,
+    stake_fundings: Option<Vec<StakeFunding>>,
+    spending_limits: Option<Vec<SpendingLimit>>,
+    spending_records: Option<Vec<SpendingRecord>>,
+    spending_audit_log: Option<Vec<SpendingAuditEntry>>,
+    external_deposits: Option<Vec<ExternalDeposit>>,
+    deposit_credit_windows: Option<HashMap<Exchange, u32>>, // hours
+    exchange_journal: Option<Vec<ExchangeJournalEntry>>,
+    history_backfills: Option<Vec<HistoryBackfill>>,
+    evacuation_addresses: Option<Vec<EvacuationAddress>>,
+    unwind_targets: Option<Vec<UnwindTarget>>,
+    lending_offers: Option<Vec<LendingOfferRecord>>,
+    managed_orders: Option<Vec<ManagedOrder>>,
+    settings: Option<BTreeMap<Setting, String>>,
 }
 
 impl DbData {
@@ -674,6 +1455,29 @@ impl DbData {
                 .unwrap_or_default(),
             tax_rate: None,
             validator_credit_scores: None,
+            historical_prices: None,
+            reconciled_deposits: None,
+            balance_snapshots: None,
+            quoting_sessions: None,
+            fiat_withdrawals: None,
+            pinned_deposit_addresses: None,
+            cached_deposit_addresses: None,
+            withdrawal_approval_policy: None,
+            withdrawal_intents: None,
+            withdrawal_audit_log: None,
+            stake_fundings: None,
+            spending_limits: None,
+            spending_records: None,
+            spending_audit_log: None,
+            external_deposits: None,
+            deposit_credit_windows: None,
+            exchange_journal: None,
+            history_backfills: None,
+            evacuation_addresses: None,
+            unwind_targets: None,
+            lending_offers: None,
+            managed_orders: None,
+            settings: None,
         }
     }
 
@@ -745,6 +1549,7 @@ impl Db {
                 .rem(&format!("{exchange:?}{exchange_account}"))
                 .ok();
             self.credentials_db.dump()?;
+            self.uncache_deposit_addresses(exchange, exchange_account)?;
         }
         Ok(())
     }
@@ -752,18 +1557,37 @@ impl Db {
     pub fn get_default_accounts_from_configured_exchanges(
         &self,
     ) -> Vec<(Exchange, ExchangeCredentials, String)> {
-        self.credentials_db
+        Exchange::all()
+            .iter()
+            .filter_map(|exchange| {
+                self.get_exchange_credentials(*exchange, "")
+                    .map(|exchange_credentials| (*exchange, exchange_credentials, "".into()))
+            })
+            .collect()
+    }
+
+    /// Every exchange account with stored credentials, ordered by exchange and account name
+    pub fn get_configured_exchange_accounts(&self) -> Vec<(Exchange, ExchangeCredentials, String)> {
+        let mut accounts = self
+            .credentials_db
             .get_all()
             .into_iter()
             .filter_map(|key| {
-                if let Ok(exchange) = key.parse() {
-                    self.get_exchange_credentials(exchange, "")
-                        .map(|exchange_credentials| (exchange, exchange_credentials, "".into()))
-                } else {
-                    None
-                }
+                // The longest matching name, so a BinanceUs key isn't read as a Binance account
+                let (exchange, exchange_name) = Exchange::all()
+                    .iter()
+                    .map(|exchange| (*exchange, format!("{exchange:?}")))
+                    .filter(|(_, exchange_name)| key.starts_with(exchange_name.as_str()))
+                    .max_by_key(|(_, exchange_name)| exchange_name.len())?;
+                let exchange_account = key[exchange_name.len()..].to_string();
+                self.get_exchange_credentials(exchange, &exchange_account)
+                    .map(|exchange_credentials| (exchange, exchange_credentials, exchange_account))
             })
-            .collect()
+            .collect::<Vec<_>>();
+        accounts.sort_by_key(|(exchange, _, exchange_account)| {
+            (format!("{exchange:?}"), exchange_account.clone())
+        });
+        accounts
     }
 
     pub fn set_metrics_config(&mut self, metrics_config: MetricsConfig) -> DbResult<()> {
@@ -829,8 +1653,23 @@ impl Db {
                 to_token: token,
                 lots: from_account.extract_lots(self, amount, lot_selection_method, lot_numbers)?,
             },
+            sent_at: Some(Utc::now()),
+            overdue_warned: false,
         };
         self.data.pending_deposits.push(deposit);
+        self.data
+            .reconciled_deposits
+            .get_or_insert_with(Vec::default)
+            .push(ReconciledDeposit {
+                exchange,
+                side: DepositSide::OnChain,
+                tx_id: signature.to_string(),
+                token,
+                amount,
+                time: Some(Utc::now()),
+                status: DepositReconciliationStatus::Pending,
+                exchange_tx_id: None,
+            });
         self.update_account(from_account) // `update_account` calls `save`...
     }
 
@@ -890,6 +1729,237 @@ impl Db {
             .collect()
     }
 
+    /// Tracks a deposit sent outside of sys, replacing any already tracked with its signature
+    pub fn add_external_deposit(&mut self, deposit: ExternalDeposit) -> DbResult<()> {
+        let external_deposits = self.data.external_deposits.get_or_insert_with(Vec::default);
+        external_deposits.retain(|existing| existing.signature != deposit.signature);
+        external_deposits.push(deposit);
+        self.save()
+    }
+
+    /// Stops tracking a deposit sent outside of sys, returning whether it was tracked
+    pub fn remove_external_deposit(&mut self, signature: Signature) -> DbResult<bool> {
+        let external_deposits = self.data.external_deposits.get_or_insert_with(Vec::default);
+        let len = external_deposits.len();
+        external_deposits.retain(|existing| existing.signature != signature);
+        if external_deposits.len() == len {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Deposits sent by sys and those tracked by hand that the exchange has not credited yet,
+    /// oldest first
+    pub fn in_transit_deposits(&self, exchange: Option<Exchange>) -> Vec<InTransitDeposit> {
+        let mut deposits = self
+            .pending_deposits(exchange)
+            .into_iter()
+            .map(|pending_deposit| InTransitDeposit {
+                exchange: pending_deposit.exchange,
+                signature: pending_deposit.transfer.signature,
+                token: pending_deposit.transfer.to_token,
+                amount: pending_deposit.amount,
+                sent_at: pending_deposit.sent_at,
+                external: false,
+                overdue_warned: pending_deposit.overdue_warned,
+            })
+            .chain(
+                self.data
+                    .external_deposits
+                    .iter()
+                    .flatten()
+                    .filter(|deposit| {
+                        exchange.map_or(true, |exchange| deposit.exchange == exchange)
+                    })
+                    .map(|deposit| InTransitDeposit {
+                        exchange: deposit.exchange,
+                        signature: deposit.signature,
+                        token: deposit.token,
+                        amount: deposit.amount,
+                        sent_at: Some(deposit.sent_at),
+                        external: true,
+                        overdue_warned: deposit.overdue_warned,
+                    }),
+            )
+            .collect::<Vec<_>>();
+        deposits.sort_by_key(|deposit| deposit.sent_at);
+        deposits
+    }
+
+    /// Notes that the deposit with `signature` was reported as overdue, so it's only reported once
+    pub fn set_deposit_overdue_warned(&mut self, signature: Signature) -> DbResult<()> {
+        for pending_deposit in self.data.pending_deposits.iter_mut() {
+            if pending_deposit.transfer.signature == signature {
+                pending_deposit.overdue_warned = true;
+            }
+        }
+        for deposit in self.data.external_deposits.iter_mut().flatten() {
+            if deposit.signature == signature {
+                deposit.overdue_warned = true;
+            }
+        }
+        self.save()
+    }
+
+    /// Hours `exchange` may take to credit a deposit before it is reported as overdue, when set
+    pub fn deposit_credit_window(&self, exchange: Exchange) -> Option<u32> {
+        self.data
+            .deposit_credit_windows
+            .as_ref()
+            .and_then(|deposit_credit_windows| deposit_credit_windows.get(&exchange))
+            .copied()
+    }
+
+    pub fn set_deposit_credit_window(
+        &mut self,
+        exchange: Exchange,
+        hours: Option<u32>,
+    ) -> DbResult<()> {
+        let deposit_credit_windows = self
+            .data
+            .deposit_credit_windows
+            .get_or_insert_with(HashMap::default);
+        match hours {
+            Some(hours) => deposit_credit_windows.insert(exchange, hours),
+            None => deposit_credit_windows.remove(&exchange),
+        };
+        self.save()
+    }
+
+    pub fn reconciled_deposits(&self, exchange: Exchange) -> Vec<ReconciledDeposit> {
+        self.data
+            .reconciled_deposits
+            .iter()
+            .flatten()
+            .filter(|reconciled_deposit| reconciled_deposit.exchange == exchange)
+            .cloned()
+            .collect()
+    }
+
+    /// Replaces the reconciliation state of every deposit to `exchange`
+    pub fn set_reconciled_deposits(
+        &mut self,
+        exchange: Exchange,
+        reconciled_deposits: Vec<ReconciledDeposit>,
+    ) -> DbResult<()> {
+        let all_reconciled_deposits = self
+            .data
+            .reconciled_deposits
+            .get_or_insert_with(Vec::default);
+        all_reconciled_deposits
+            .retain(|reconciled_deposit| reconciled_deposit.exchange != exchange);
+        all_reconciled_deposits.extend(reconciled_deposits);
+        self.save()
+    }
+
+    pub fn add_balance_snapshot(&mut self, balance_snapshot: BalanceSnapshot) -> DbResult<()> {
+        let balance_snapshots = self.data.balance_snapshots.get_or_insert_with(Vec::default);
+        balance_snapshots.push(balance_snapshot);
+        balance_snapshots.sort_by_key(|balance_snapshot| balance_snapshot.time);
+        self.save()
+    }
+
+    /// All balance snapshots, oldest first
+    pub fn balance_snapshots(&self) -> Vec<BalanceSnapshot> {
+        self.data.balance_snapshots.clone().unwrap_or_default()
+    }
+
+    pub fn record_exchange_journal(
+        &mut self,
+        entries: impl IntoIterator<Item = ExchangeJournalEntry>,
+    ) -> DbResult<()> {
+        self.data
+            .exchange_journal
+            .get_or_insert_with(Vec::default)
+            .extend(entries);
+        self.save()
+    }
+
+    /// Journal entries of `exchange` recorded after `since`, oldest first
+    pub fn exchange_journal(
+        &self,
+        exchange: Exchange,
+        since: DateTime<Utc>,
+    ) -> Vec<ExchangeJournalEntry> {
+        let mut entries = self
+            .data
+            .exchange_journal
+            .iter()
+            .flatten()
+            .filter(|entry| entry.exchange == exchange && entry.time > since)
+            .cloned()
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.time);
+        entries
+    }
+
+    pub fn history_backfill(
+        &self,
+        exchange: Exchange,
+        category: HistoryCategory,
+    ) -> Option<HistoryBackfill> {
+        self.data
+            .history_backfills
+            .iter()
+            .flatten()
+            .find(|backfill| backfill.exchange == exchange && backfill.category == category)
+            .cloned()
+    }
+
+    /// Journals `entries` and saves `backfill` progress together, so an interrupted backfill
+    /// resumes after the last window recorded.  Entries already in the journal are skipped
+    pub fn record_history_backfill(
+        &mut self,
+        backfill: &mut HistoryBackfill,
+        entries: Vec<ExchangeJournalEntry>,
+    ) -> DbResult<()> {
+        let journal = self.data.exchange_journal.get_or_insert_with(Vec::default);
+        for entry in entries {
+            let recorded = journal.iter().any(|recorded| {
+                (
+                    recorded.exchange,
+                    recorded.kind,
+                    &recorded.coin,
+                    &recorded.reference,
+                    recorded.time,
+                ) == (
+                    entry.exchange,
+                    entry.kind,
+                    &entry.coin,
+                    &entry.reference,
+                    entry.time,
+                )
+            });
+            if !recorded {
+                journal.push(entry);
+                backfill.records += 1;
+            }
+        }
+
+        let backfills = self.data.history_backfills.get_or_insert_with(Vec::default);
+        backfills.retain(|b| b.exchange != backfill.exchange || b.category != backfill.category);
+        backfills.push(backfill.clone());
+        self.save()
+    }
+
+    pub fn quoting_session(&self, exchange: Exchange, pair: &str) -> Option<QuotingSession> {
+        self.data
+            .quoting_sessions
+            .iter()
+            .flatten()
+            .find(|session| session.exchange == exchange && session.pair == pair)
+            .cloned()
+    }
+
+    /// Replaces the session for the same exchange and pair
+    pub fn set_quoting_session(&mut self, session: QuotingSession) -> DbResult<()> {
+        let sessions = self.data.quoting_sessions.get_or_insert_with(Vec::default);
+        sessions.retain(|s| s.exchange != session.exchange || s.pair != session.pair);
+        sessions.push(session);
+        self.save()
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn record_swap(
         &mut self,
@@ -1058,86 +2128,778 @@ impl Db {
             from_account.extract_lots(self, amount, lot_selection_method, lot_numbers)?
         };
 
-        let withdrawal = PendingWithdrawal {
-            exchange,
-            tag,
-            token,
-            amount: amount - fee,
-            fee,
-            from_address,
-            to_address,
-            lots,
-        };
+        let withdrawal = PendingWithdrawal {
+            exchange,
+            tag,
+            token,
+            amount: amount - fee,
+            fee,
+            from_address,
+            to_address,
+            lots,
+        };
+
+        self.data.pending_withdrawals.push(withdrawal);
+        self.update_account(from_account) // `update_account` calls `save`.../
+    }
+
+    // The caller must call `save()`...
+    fn remove_pending_withdrawal(&mut self, tag: &str) {
+        self.data.pending_withdrawals.retain(|pw| pw.tag != tag);
+    }
+
+    pub fn cancel_withdrawal(
+        &mut self,
+        PendingWithdrawal {
+            tag,
+            from_address,
+            token,
+            lots,
+            ..
+        }: PendingWithdrawal,
+    ) -> DbResult<()> {
+        self.remove_pending_withdrawal(&tag);
+
+        let mut from_account = self
+            .get_account(from_address, token)
+            .ok_or(DbError::AccountDoesNotExist(from_address, token))?;
+        if !token.fiat_fungible() {
+            from_account.merge_lots(lots);
+        }
+        self.update_account(from_account) // `update_account` calls `save`...
+    }
+
+    pub fn confirm_withdrawal(
+        &mut self,
+        PendingWithdrawal {
+            exchange,
+            tag,
+            to_address,
+            token,
+            mut lots,
+            fee,
+            ..
+        }: PendingWithdrawal,
+        when: NaiveDate,
+    ) -> DbResult<()> {
+        self.remove_pending_withdrawal(&tag);
+
+        if fee > 0 {
+            assert!(lots[0].amount > fee); // TODO: handle a fee that's split across multiple lots
+            let fee_price = lots[0].acquisition.price(); // Assume no gain/lost on the fee disposal for simplicity
+            lots[0].amount -= fee;
+            let fee_lot = Lot {
+                lot_number: self.next_lot_number(),
+                acquisition: lots[0].acquisition.clone(),
+                amount: fee,
+            };
+            let _ = self.record_lots_disposal(
+                token,
+                vec![fee_lot],
+                LotDisposalKind::WithdrawalFee { exchange, tag },
+                when,
+                fee_price,
+            );
+        }
+
+        let mut to_account = self
+            .get_account(to_address, token)
+            .ok_or(DbError::AccountDoesNotExist(to_address, token))?;
+
+        to_account.merge_lots(lots);
+        self.update_account(to_account) // `update_account` calls `save`...
+    }
+
+    pub fn pinned_deposit_address(
+        &self,
+        exchange: Exchange,
+        account: &str,
+        token: MaybeToken,
+    ) -> Option<PinnedDepositAddress> {
+        self.data
+            .pinned_deposit_addresses
+            .iter()
+            .flatten()
+            .find(|pinned| {
+                pinned.exchange == exchange && pinned.account == account && pinned.token == token
+            })
+            .cloned()
+    }
+
+    pub fn pinned_deposit_addresses(&self) -> Vec<PinnedDepositAddress> {
+        self.data
+            .pinned_deposit_addresses
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// Pins `address`, recording the address it replaces if one was already pinned
+    pub fn pin_deposit_address(
+        &mut self,
+        exchange: Exchange,
+        account: &str,
+        token: MaybeToken,
+        address: Pubkey,
+    ) -> DbResult<()> {
+        let now = Utc::now();
+        let pinned_deposit_addresses = self
+            .data
+            .pinned_deposit_addresses
+            .get_or_insert_with(Vec::default);
+        match pinned_deposit_addresses.iter_mut().find(|pinned| {
+            pinned.exchange == exchange && pinned.account == account && pinned.token == token
+        }) {
+            Some(pinned) if pinned.address == address => return Ok(()),
+            Some(pinned) => {
+                pinned.replaced.push(ReplacedDepositAddress {
+                    address: pinned.address,
+                    pinned_at: pinned.pinned_at,
+                    replaced_at: now,
+                });
+                pinned.address = address;
+                pinned.pinned_at = now;
+            }
+            None => pinned_deposit_addresses.push(PinnedDepositAddress {
+                exchange,
+                account: account.to_string(),
+                token,
+                address,
+                pinned_at: now,
+                replaced: vec![],
+            }),
+        }
+        self.save()
+    }
+
+    /// The cached deposit address, unless it was fetched with other credentials than the account's
+    /// current ones
+    pub fn cached_deposit_address(
+        &self,
+        exchange: Exchange,
+        account: &str,
+        token: MaybeToken,
+    ) -> Option<CachedDepositAddress> {
+        let credentials =
+            credentials_fingerprint(&self.get_exchange_credentials(exchange, account)?);
+        self.data
+            .cached_deposit_addresses
+            .iter()
+            .flatten()
+            .find(|cached| {
+                cached.exchange == exchange
+                    && cached.account == account
+                    && cached.token == token
+                    && cached.credentials == credentials
+            })
+            .cloned()
+    }
+
+    pub fn cached_deposit_addresses(&self) -> Vec<CachedDepositAddress> {
+        self.data
+            .cached_deposit_addresses
+            .clone()
+            .unwrap_or_default()
+    }
+
+    /// Caches `address` as just fetched with the account's current credentials
+    pub fn cache_deposit_address(
+        &mut self,
+        exchange: Exchange,
+        account: &str,
+        token: MaybeToken,
+        address: Pubkey,
+    ) -> DbResult<()> {
+        let credentials = match self.get_exchange_credentials(exchange, account) {
+            Some(exchange_credentials) => credentials_fingerprint(&exchange_credentials),
+            None => return Ok(()),
+        };
+        let cached_deposit_addresses = self
+            .data
+            .cached_deposit_addresses
+            .get_or_insert_with(Vec::default);
+        cached_deposit_addresses.retain(|cached| {
+            !(cached.exchange == exchange && cached.account == account && cached.token == token)
+        });
+        cached_deposit_addresses.push(CachedDepositAddress {
+            exchange,
+            account: account.to_string(),
+            token,
+            address,
+            verified_at: Utc::now(),
+            credentials,
+        });
+        self.save()
+    }
+
+    /// Drops every cached deposit address of the account, such as when its credentials change
+    pub fn uncache_deposit_addresses(&mut self, exchange: Exchange, account: &str) -> DbResult<()> {
+        if let Some(cached_deposit_addresses) = self.data.cached_deposit_addresses.as_mut() {
+            let len = cached_deposit_addresses.len();
+            cached_deposit_addresses
+                .retain(|cached| !(cached.exchange == exchange && cached.account == account));
+            if cached_deposit_addresses.len() != len {
+                return self.save();
+            }
+        }
+        Ok(())
+    }
+
+    pub fn record_fiat_withdrawal(&mut self, fiat_withdrawal: FiatWithdrawal) -> DbResult<()> {
+        self.data
+            .fiat_withdrawals
+            .get_or_insert_with(Vec::default)
+            .push(fiat_withdrawal);
+        self.save()
+    }
+
+    pub fn update_fiat_withdrawal(
+        &mut self,
+        exchange: Exchange,
+        withdrawal_id: &str,
+        state: FiatWithdrawalState,
+        fee: Option<f64>,
+    ) -> DbResult<()> {
+        if let Some(fiat_withdrawal) = self
+            .data
+            .fiat_withdrawals
+            .iter_mut()
+            .flatten()
+            .find(|fw| fw.exchange == exchange && fw.withdrawal_id == withdrawal_id)
+        {
+            fiat_withdrawal.state = state;
+            fiat_withdrawal.fee = fee.or(fiat_withdrawal.fee);
+            fiat_withdrawal.last_update = Utc::now();
+        }
+        self.save()
+    }
+
+    /// Every fiat withdrawal requested, oldest first
+    pub fn fiat_withdrawals(&self, exchange: Option<Exchange>) -> Vec<FiatWithdrawal> {
+        self.data
+            .fiat_withdrawals
+            .iter()
+            .flatten()
+            .filter(|fiat_withdrawal| {
+                exchange.map_or(true, |exchange| fiat_withdrawal.exchange == exchange)
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_withdrawal_approval_policy(&self) -> Option<&WithdrawalApprovalPolicy> {
+        self.data.withdrawal_approval_policy.as_ref()
+    }
+
+    pub fn set_withdrawal_approval_policy(
+        &mut self,
+        policy: Option<WithdrawalApprovalPolicy>,
+    ) -> DbResult<()> {
+        self.data.withdrawal_approval_policy = policy;
+        self.save()
+    }
+
+    // User and host running this command, for the audit logs
+    fn audit_actor() -> String {
+        let user = std::env::var("USER").unwrap_or_else(|_| "unknown".into());
+        let host = fs::read_to_string("/etc/hostname")
+            .ok()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .map(|host| host.trim().to_string())
+            .unwrap_or_else(|| "unknown".into());
+        format!("{user}@{host}")
+    }
+
+    fn append_withdrawal_audit_entry(
+        &mut self,
+        code: &str,
+        event: WithdrawalAuditEvent,
+        detail: String,
+    ) {
+        self.data
+            .withdrawal_audit_log
+            .get_or_insert_with(Vec::default)
+            .push(WithdrawalAuditEntry {
+                time: Utc::now(),
+                code: code.to_string(),
+                event,
+                actor: Self::audit_actor(),
+                detail,
+            });
+    }
+
+    pub fn add_withdrawal_intent(&mut self, intent: WithdrawalIntent) -> DbResult<()> {
+        let detail = format!(
+            "{:?} {} {} to {}, expires {}",
+            intent.exchange,
+            intent.exchange_account,
+            intent.amount_description(),
+            intent.destination(),
+            intent.expires_at
+        );
+        self.append_withdrawal_audit_entry(&intent.code, WithdrawalAuditEvent::Created, detail);
+        self.data
+            .withdrawal_intents
+            .get_or_insert_with(Vec::default)
+            .push(intent);
+        self.save()
+    }
+
+    /// Every withdrawal intent created, oldest first
+    pub fn withdrawal_intents(&self) -> Vec<WithdrawalIntent> {
+        self.data
+            .withdrawal_intents
+            .iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    pub fn get_withdrawal_intent(&self, code: &str) -> Option<WithdrawalIntent> {
+        self.data
+            .withdrawal_intents
+            .iter()
+            .flatten()
+            .find(|intent| intent.code.eq_ignore_ascii_case(code))
+            .cloned()
+    }
+
+    /// Records `event` against the intent without changing its state
+    pub fn audit_withdrawal_intent(
+        &mut self,
+        code: &str,
+        event: WithdrawalAuditEvent,
+        detail: String,
+    ) -> DbResult<()> {
+        self.append_withdrawal_audit_entry(code, event, detail);
+        self.save()
+    }
+
+    pub fn update_withdrawal_intent(
+        &mut self,
+        code: &str,
+        state: WithdrawalIntentState,
+        event: WithdrawalAuditEvent,
+        detail: String,
+    ) -> DbResult<()> {
+        let intent = self
+            .data
+            .withdrawal_intents
+            .iter_mut()
+            .flatten()
+            .find(|intent| intent.code.eq_ignore_ascii_case(code))
+            .ok_or_else(|| DbError::WithdrawalIntentDoesNotExist(code.to_string()))?;
+        intent.state = state;
+        let code = intent.code.clone();
+        self.append_withdrawal_audit_entry(&code, event, detail);
+        self.save()
+    }
+
+    /// Expires every pending intent past its expiry time, returning the intents expired
+    pub fn expire_withdrawal_intents(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> DbResult<Vec<WithdrawalIntent>> {
+        let mut expired = vec![];
+        for intent in self.data.withdrawal_intents.iter_mut().flatten() {
+            if intent.state.is_pending() && intent.expires_at <= now {
+                intent.state = WithdrawalIntentState::Expired;
+                expired.push(intent.clone());
+            }
+        }
+        if expired.is_empty() {
+            return Ok(expired);
+        }
+        for intent in &expired {
+            self.append_withdrawal_audit_entry(
+                &intent.code,
+                WithdrawalAuditEvent::Expired,
+                format!("expired at {}", intent.expires_at),
+            );
+        }
+        self.save()?;
+        Ok(expired)
+    }
+
+    /// The withdrawal approval audit log, oldest first
+    pub fn withdrawal_audit_log(&self) -> Vec<WithdrawalAuditEntry> {
+        self.data
+            .withdrawal_audit_log
+            .iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Allow-listed evacuation addresses, one per token
+    pub fn evacuation_addresses(&self) -> Vec<EvacuationAddress> {
+        self.data
+            .evacuation_addresses
+            .iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Allow-lists `address` as the evacuation address for `token`, replacing any other.  The
+    /// address must be a tracked account, as withdrawals only go to tracked accounts
+    pub fn set_evacuation_address(&mut self, token: MaybeToken, address: Pubkey) -> DbResult<()> {
+        if self.get_account(address, token).is_none() {
+            return Err(DbError::AccountDoesNotExist(address, token));
+        }
+        let evacuation_addresses = self
+            .data
+            .evacuation_addresses
+            .get_or_insert_with(Vec::default);
+        evacuation_addresses.retain(|existing| existing.token != token);
+        evacuation_addresses.push(EvacuationAddress {
+            token,
+            address,
+            added: Utc::now(),
+            added_by: Self::audit_actor(),
+        });
+        self.save()
+    }
+
+    pub fn remove_evacuation_address(
+        &mut self,
+        token: MaybeToken,
+    ) -> DbResult<Option<EvacuationAddress>> {
+        let evacuation_addresses = self
+            .data
+            .evacuation_addresses
+            .get_or_insert_with(Vec::default);
+        let removed = evacuation_addresses
+            .iter()
+            .position(|existing| existing.token == token)
+            .map(|index| evacuation_addresses.remove(index));
+        self.save()?;
+        Ok(removed)
+    }
+
+    pub fn unwind_targets(&self) -> Vec<UnwindTarget> {
+        self.data.unwind_targets.iter().flatten().cloned().collect()
+    }
+
+    pub fn unwind_target(&self, name: &str) -> Option<UnwindTarget> {
+        self.data
+            .unwind_targets
+            .iter()
+            .flatten()
+            .find(|target| target.name == name)
+            .cloned()
+    }
+
+    /// Declares `target`, unless there's already a target with its name
+    pub fn add_unwind_target(&mut self, target: UnwindTarget) -> DbResult<bool> {
+        let unwind_targets = self.data.unwind_targets.get_or_insert_with(Vec::default);
+        if unwind_targets
+            .iter()
+            .any(|existing| existing.name == target.name)
+        {
+            return Ok(false);
+        }
+        unwind_targets.push(target);
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn remove_unwind_target(&mut self, name: &str) -> DbResult<Option<UnwindTarget>> {
+        let unwind_targets = self.data.unwind_targets.get_or_insert_with(Vec::default);
+        let removed = unwind_targets
+            .iter()
+            .position(|existing| existing.name == name)
+            .map(|index| unwind_targets.remove(index));
+        self.save()?;
+        Ok(removed)
+    }
+
+    /// Counts `order_id` against the unwind target `name`
+    pub fn record_unwind_order(&mut self, name: &str, order_id: &str) -> DbResult<()> {
+        if let Some(target) = self
+            .data
+            .unwind_targets
+            .iter_mut()
+            .flatten()
+            .find(|target| target.name == name)
+        {
+            target.order_ids.push(order_id.to_string());
+        }
+        self.save()
+    }
+
+    /// Progress of `target` from the journal.  `open_fills` gives what's known to have filled of
+    /// an open order, which the journal only records once the order closes
+    pub fn unwind_progress(
+        &self,
+        target: &UnwindTarget,
+        open_fills: &HashMap<String, f64>,
+    ) -> UnwindProgress {
+        let journaled_orders = self.journaled_orders(target.exchange);
+        let open_orders = self.open_orders(Some(target.exchange), Some(OrderSide::Sell));
+
+        let mut progress = UnwindProgress {
+            ui_amount: target.ui_amount,
+            filled: 0.,
+            open: 0.,
+        };
+        for order_id in &target.order_ids {
+            match open_orders.iter().find(|order| order.order_id == *order_id) {
+                Some(order) => {
+                    let ui_amount = target
+                        .token
+                        .ui_amount(order.lots.iter().map(|lot| lot.amount).sum());
+                    let filled = open_fills
+                        .get(order_id)
+                        .copied()
+                        .unwrap_or_default()
+                        .min(ui_amount);
+                    progress.filled += filled;
+                    progress.open += ui_amount - filled;
+                }
+                None => {
+                    if let Some(journaled_order) = journaled_orders.get(order_id) {
+                        progress.filled += target.token.ui_amount(journaled_order.filled_amount);
+                    }
+                }
+            }
+        }
+        progress
+    }
+
+    pub fn record_lending_offer(&mut self, lending_offer: LendingOfferRecord) -> DbResult<()> {
+        self.data
+            .lending_offers
+            .get_or_insert_with(Vec::default)
+            .push(lending_offer);
+        self.save()
+    }
+
+    /// Lending offers submitted on `exchange`, oldest first
+    pub fn lending_offers(&self, exchange: Exchange) -> Vec<LendingOfferRecord> {
+        self.data
+            .lending_offers
+            .iter()
+            .flatten()
+            .filter(|lending_offer| lending_offer.exchange == exchange)
+            .cloned()
+            .collect()
+    }
+
+    /// Records a managed order, replacing any with the same client order id
+    pub fn set_managed_order(&mut self, managed_order: ManagedOrder) -> DbResult<()> {
+        let managed_orders = self.data.managed_orders.get_or_insert_with(Vec::default);
+        match managed_orders
+            .iter_mut()
+            .find(|existing| existing.client_order_id == managed_order.client_order_id)
+        {
+            Some(existing) => *existing = managed_order,
+            None => managed_orders.push(managed_order),
+        }
+        self.save()
+    }
+
+    pub fn managed_order(&self, client_order_id: &str) -> Option<ManagedOrder> {
+        self.data
+            .managed_orders
+            .iter()
+            .flatten()
+            .find(|managed_order| managed_order.client_order_id == client_order_id)
+            .cloned()
+    }
+
+    pub fn managed_order_by_order_id(
+        &self,
+        exchange: Exchange,
+        order_id: &str,
+    ) -> Option<ManagedOrder> {
+        self.data
+            .managed_orders
+            .iter()
+            .flatten()
+            .find(|managed_order| {
+                managed_order.exchange == exchange
+                    && managed_order.order_id.as_deref() == Some(order_id)
+            })
+            .cloned()
+    }
+
+    /// Managed orders on `exchange`, oldest first.  `unsettled_only` leaves out the closed ones
+    pub fn managed_orders(&self, exchange: Exchange, unsettled_only: bool) -> Vec<ManagedOrder> {
+        self.data
+            .managed_orders
+            .iter()
+            .flatten()
+            .filter(|managed_order| {
+                managed_order.exchange == exchange
+                    && !(unsettled_only && managed_order.state.is_terminal())
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// The funding of an exchange from `stake_address` still in progress, if any
+    pub fn get_stake_funding(&self, stake_address: Pubkey) -> Option<StakeFunding> {
+        self.data
+            .stake_fundings
+            .iter()
+            .flatten()
+            .find(|funding| funding.stake_address == stake_address && !funding.is_completed())
+            .cloned()
+    }
+
+    /// Every exchange funding from a stake account, oldest first
+    pub fn stake_fundings(&self) -> Vec<StakeFunding> {
+        self.data.stake_fundings.iter().flatten().cloned().collect()
+    }
+
+    /// Adds `funding`, or replaces the one in progress for the same stake account
+    pub fn update_stake_funding(&mut self, funding: StakeFunding) -> DbResult<()> {
+        let stake_fundings = self.data.stake_fundings.get_or_insert_with(Vec::default);
+        match stake_fundings.iter_mut().find(|existing| {
+            existing.stake_address == funding.stake_address
+                && existing.started_at == funding.started_at
+        }) {
+            Some(existing) => *existing = funding,
+            None => stake_fundings.push(funding),
+        }
+        self.save()
+    }
+
+    /// The settings that are set, see `sys::settings`
+    pub fn settings(&self) -> BTreeMap<Setting, String> {
+        self.data.settings.clone().unwrap_or_default()
+    }
+
+    pub fn set_setting(&mut self, setting: Setting, value: String) -> DbResult<()> {
+        self.data
+            .settings
+            .get_or_insert_with(BTreeMap::default)
+            .insert(setting, value);
+        self.save()
+    }
 
-        self.data.pending_withdrawals.push(withdrawal);
-        self.update_account(from_account) // `update_account` calls `save`.../
+    /// Returns the setting to its default, returning the value it had
+    pub fn unset_setting(&mut self, setting: Setting) -> DbResult<Option<String>> {
+        let value = self
+            .data
+            .settings
+            .get_or_insert_with(BTreeMap::default)
+            .remove(&setting);
+        self.save()?;
+        Ok(value)
     }
 
-    // The caller must call `save()`...
-    fn remove_pending_withdrawal(&mut self, tag: &str) {
-        self.data.pending_withdrawals.retain(|pw| pw.tag != tag);
+    pub fn spending_limits(&self) -> Vec<SpendingLimit> {
+        self.data
+            .spending_limits
+            .iter()
+            .flatten()
+            .cloned()
+            .collect()
     }
 
-    pub fn cancel_withdrawal(
-        &mut self,
-        PendingWithdrawal {
-            tag,
-            from_address,
-            token,
-            lots,
-            ..
-        }: PendingWithdrawal,
-    ) -> DbResult<()> {
-        self.remove_pending_withdrawal(&tag);
+    fn append_spending_audit_entry(&mut self, event: SpendingAuditEvent, detail: String) {
+        self.data
+            .spending_audit_log
+            .get_or_insert_with(Vec::default)
+            .push(SpendingAuditEntry {
+                time: Utc::now(),
+                event,
+                actor: Self::audit_actor(),
+                detail,
+            });
+    }
 
-        let mut from_account = self
-            .get_account(from_address, token)
-            .ok_or(DbError::AccountDoesNotExist(from_address, token))?;
-        if !token.fiat_fungible() {
-            from_account.merge_lots(lots);
+    /// Adds `limit`, replacing any existing limit on the same operation, token and window
+    pub fn set_spending_limit(&mut self, limit: SpendingLimit) -> DbResult<()> {
+        let spending_limits = self.data.spending_limits.get_or_insert_with(Vec::default);
+        spending_limits.retain(|existing| !existing.same_scope(&limit));
+        spending_limits.push(limit.clone());
+        self.append_spending_audit_entry(SpendingAuditEvent::LimitSet, limit.to_string());
+        self.save()
+    }
+
+    /// Removes the limit at `index` in `spending_limits()`, returning it
+    pub fn remove_spending_limit(&mut self, index: usize) -> DbResult<Option<SpendingLimit>> {
+        let spending_limits = self.data.spending_limits.get_or_insert_with(Vec::default);
+        if index >= spending_limits.len() {
+            return Ok(None);
         }
-        self.update_account(from_account) // `update_account` calls `save`...
+        let limit = spending_limits.remove(index);
+        self.append_spending_audit_entry(SpendingAuditEvent::LimitRemoved, limit.to_string());
+        self.save()?;
+        Ok(Some(limit))
     }
 
-    pub fn confirm_withdrawal(
+    /// Records that a per-operation limit was bypassed for the operation `detail` describes
+    pub fn audit_spending_limit_override(
         &mut self,
-        PendingWithdrawal {
-            exchange,
-            tag,
-            to_address,
-            token,
-            mut lots,
-            fee,
-            ..
-        }: PendingWithdrawal,
-        when: NaiveDate,
+        limit: &SpendingLimit,
+        detail: String,
     ) -> DbResult<()> {
-        self.remove_pending_withdrawal(&tag);
+        self.append_spending_audit_entry(
+            SpendingAuditEvent::LimitOverridden,
+            format!("{limit}: {detail}"),
+        );
+        self.save()
+    }
 
-        if fee > 0 {
-            assert!(lots[0].amount > fee); // TODO: handle a fee that's split across multiple lots
-            let fee_price = lots[0].acquisition.price(); // Assume no gain/lost on the fee disposal for simplicity
-            lots[0].amount -= fee;
-            let fee_lot = Lot {
-                lot_number: self.next_lot_number(),
-                acquisition: lots[0].acquisition.clone(),
-                amount: fee,
-            };
-            let _ = self.record_lots_disposal(
-                token,
-                vec![fee_lot],
-                LotDisposalKind::WithdrawalFee { exchange, tag },
-                when,
-                fee_price,
-            );
-        }
+    /// The spending limit audit log, oldest first
+    pub fn spending_audit_log(&self) -> Vec<SpendingAuditEntry> {
+        self.data
+            .spending_audit_log
+            .iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
 
-        let mut to_account = self
-            .get_account(to_address, token)
-            .ok_or(DbError::AccountDoesNotExist(to_address, token))?;
+    /// Operations since `since`, oldest first
+    pub fn spending_records(&self, since: DateTime<Utc>) -> Vec<SpendingRecord> {
+        self.data
+            .spending_records
+            .iter()
+            .flatten()
+            .filter(|record| record.time >= since)
+            .cloned()
+            .collect()
+    }
 
-        to_account.merge_lots(lots);
-        self.update_account(to_account) // `update_account` calls `save`...
+    /// What's been moved within the rolling window of `limit` ending at `now`, or `None` for a
+    /// per-operation limit
+    pub fn spending_limit_used(
+        &self,
+        limit: &SpendingLimit,
+        now: DateTime<Utc>,
+    ) -> Option<SpendingLimitUsage> {
+        let window_hours = limit.window_hours?;
+        let mut usage = SpendingLimitUsage::default();
+        for record in self
+            .spending_records(now - chrono::Duration::hours(window_hours.into()))
+            .into_iter()
+            .filter(|record| {
+                limit.operation.covers(record.operation)
+                    && limit.token.map_or(true, |token| {
+                        record.fiat_currency.is_none() && token == record.token
+                    })
+            })
+        {
+            match (limit.token, record.usd_value) {
+                (Some(_), _) => usage.used += record.ui_amount,
+                (None, Some(usd_value)) => usage.used += usd_value,
+                (None, None) => usage.unvalued.push(record),
+            }
+        }
+        Some(usage)
+    }
+
+    /// Records an operation against the spending limits, dropping records too old for any window
+    pub fn record_spending(&mut self, record: SpendingRecord) -> DbResult<()> {
+        let cutoff = record.time - chrono::Duration::hours(MAX_SPENDING_WINDOW_HOURS.into());
+        let spending_records = self.data.spending_records.get_or_insert_with(Vec::default);
+        spending_records.retain(|existing| existing.time >= cutoff);
+        spending_records.push(record);
+        self.save()
     }
 
     pub fn pending_withdrawals(&self, exchange: Option<Exchange>) -> Vec<PendingWithdrawal> {
@@ -1164,6 +2926,7 @@ impl Db {
         pair: String,
         price: f64,
         order_id: String,
+        client_order_id: Option<String>,
         lots: Vec<Lot>,
         ui_amount: Option<f64>,
     ) -> DbResult<()> {
@@ -1189,11 +2952,42 @@ impl Db {
             deposit_address: deposit_account.address,
             token: deposit_account.token,
             ui_amount,
+            client_order_id,
+            expiry: None,
+            native_expiry: false,
+            pegged: false,
         });
         self.update_account(deposit_account) // `update_account` calls `save`...
     }
 
-    #[allow(dead_code)]
+    pub fn set_order_expiry(
+        &mut self,
+        order_id: &str,
+        expiry: DateTime<Utc>,
+        native_expiry: bool,
+    ) -> DbResult<()> {
+        let order = self
+            .data
+            .open_orders
+            .iter_mut()
+            .find(|order| order.order_id == order_id)
+            .ok_or_else(|| DbError::OpenOrderDoesNotExist(order_id.to_string()))?;
+        order.expiry = Some(expiry);
+        order.native_expiry = native_expiry;
+        self.save()
+    }
+
+    pub fn set_order_pegged(&mut self, order_id: &str) -> DbResult<()> {
+        let order = self
+            .data
+            .open_orders
+            .iter_mut()
+            .find(|order| order.order_id == order_id)
+            .ok_or_else(|| DbError::OpenOrderDoesNotExist(order_id.to_string()))?;
+        order.pegged = true;
+        self.save()
+    }
+
     pub fn update_order_price(&mut self, order_id: &str, price: f64) -> DbResult<()> {
         self.data.open_orders = self
             .data
@@ -1218,6 +3012,7 @@ impl Db {
         price: f64,
         when: NaiveDate,
         fee: Option<(f64, String)>,
+        usd_fee: Option<UsdFee>,
     ) -> DbResult<()> {
         self.auto_save(false)?;
 
@@ -1258,6 +3053,7 @@ impl Db {
                                 exchange,
                                 pair,
                                 order_id,
+                                external: false,
                             },
                         },
                         amount: filled_amount,
@@ -1278,30 +3074,20 @@ impl Db {
                     None,
                 );
 
-                if !filled_lots.is_empty() {
-                    for lot in filled_lots {
-                        // Split fee proportionally across all disposed lots
-                        let fee = fee.clone().map(|(fee_amount, fee_coin)| {
-                            (
-                                lot.amount as f64 / filled_amount as f64 * fee_amount,
-                                fee_coin,
-                            )
-                        });
-                        self.data.disposed_lots.push(DisposedLot {
-                            lot,
-                            when,
-                            price: Some(price),
-                            decimal_price: None,
-                            kind: LotDisposalKind::Usd {
-                                exchange,
-                                pair: pair.clone(),
-                                order_id: order_id.clone(),
-                                fee,
-                            },
-                            token,
-                        });
-                    }
-                }
+                self.record_order_disposal(
+                    token,
+                    filled_lots,
+                    when,
+                    price,
+                    LotDisposalKind::Usd {
+                        exchange,
+                        pair,
+                        order_id,
+                        fee,
+                        usd_fee,
+                        external: false,
+                    },
+                );
 
                 if !cancelled_lots.is_empty() {
                     let mut deposit_account = self
@@ -1316,6 +3102,201 @@ impl Db {
         self.auto_save(true)
     }
 
+    // Disposes of the lots filled by an order, splitting the order's fee proportionally across
+    // them.  The caller must call `save()`...
+    fn record_order_disposal(
+        &mut self,
+        token: MaybeToken,
+        lots: Vec<Lot>,
+        when: NaiveDate,
+        price: f64,
+        kind: LotDisposalKind,
+    ) {
+        let filled_amount: u64 = lots.iter().map(|lot| lot.amount).sum();
+        for lot in lots {
+            let lot_share = lot.amount as f64 / filled_amount as f64;
+            let mut kind = kind.clone();
+            if let LotDisposalKind::Usd { fee, usd_fee, .. } = &mut kind {
+                *fee = fee
+                    .take()
+                    .map(|(fee_amount, fee_coin)| (lot_share * fee_amount, fee_coin));
+                *usd_fee = usd_fee.take().map(|usd_fee| UsdFee {
+                    amount: lot_share * usd_fee.amount,
+                    ..usd_fee
+                });
+            }
+            self.data.disposed_lots.push(DisposedLot {
+                lot,
+                when,
+                price: Some(price),
+                decimal_price: None,
+                kind,
+                token,
+            });
+        }
+    }
+
+    /// What the journal recorded of each order placed on `exchange`, keyed by order id.  Orders
+    /// that closed without filling leave no record
+    pub fn journaled_orders(&self, exchange: Exchange) -> HashMap<String, JournaledOrder> {
+        let mut journaled_orders = HashMap::<String, JournaledOrder>::new();
+
+        for order in &self.data.open_orders {
+            if order.exchange == exchange {
+                journaled_orders.insert(
+                    order.order_id.clone(),
+                    JournaledOrder {
+                        side: order.side,
+                        token: order.token,
+                        filled_amount: 0,
+                        open: true,
+                    },
+                );
+            }
+        }
+
+        let mut record_fill = |order_id: &str, side, token, amount| {
+            journaled_orders
+                .entry(order_id.to_string())
+                .or_insert(JournaledOrder {
+                    side,
+                    token,
+                    filled_amount: 0,
+                    open: false,
+                })
+                .filled_amount += amount;
+        };
+
+        // Lots bought on the exchange, wherever they are now
+        let held_lots = self
+            .data
+            .accounts
+            .iter()
+            .flat_map(|account| account.lots.iter().map(move |lot| (lot, account.token)))
+            .chain(
+                self.data
+                    .open_orders
+                    .iter()
+                    .flat_map(|order| order.lots.iter().map(move |lot| (lot, order.token))),
+            )
+            .chain(self.data.pending_withdrawals.iter().flat_map(|withdrawal| {
+                withdrawal
+                    .lots
+                    .iter()
+                    .map(move |lot| (lot, withdrawal.token))
+            }))
+            .chain(self.data.pending_transfers.iter().flat_map(|transfer| {
+                transfer
+                    .lots
+                    .iter()
+                    .map(move |lot| (lot, transfer.from_token))
+            }))
+            .chain(
+                self.data
+                    .disposed_lots
+                    .iter()
+                    .map(|disposed_lot| (&disposed_lot.lot, disposed_lot.token)),
+            );
+        for (lot, token) in held_lots {
+            if let LotAcquistionKind::Exchange {
+                exchange: lot_exchange,
+                order_id,
+                ..
+            } = &lot.acquisition.kind
+            {
+                if *lot_exchange == exchange {
+                    record_fill(order_id, OrderSide::Buy, token, lot.amount);
+                }
+            }
+        }
+
+        // Lots sold on the exchange
+        for disposed_lot in &self.data.disposed_lots {
+            if let LotDisposalKind::Usd {
+                exchange: lot_exchange,
+                order_id,
+                ..
+            } = &disposed_lot.kind
+            {
+                if *lot_exchange == exchange {
+                    record_fill(
+                        order_id,
+                        OrderSide::Sell,
+                        disposed_lot.token,
+                        disposed_lot.lot.amount,
+                    );
+                }
+            }
+        }
+
+        journaled_orders
+    }
+
+    /// Records the fills of an order placed outside of `sys`, at `usd_price` per token.  Bought
+    /// lots are added to the exchange deposit account, and sold lots are selected from it
+    #[allow(clippy::too_many_arguments)]
+    pub fn import_external_order(
+        &mut self,
+        exchange: Exchange,
+        pair: String,
+        order: &HistoricalOrder,
+        usd_price: f64,
+        deposit_address: Pubkey,
+        token: MaybeToken,
+        usd_fee: Option<UsdFee>,
+        lot_selection_method: LotSelectionMethod,
+    ) -> DbResult<()> {
+        let filled_amount = token.amount(order.status.filled_amount);
+        let when = order.status.last_update;
+
+        let mut deposit_account = self
+            .get_account(deposit_address, token)
+            .ok_or(DbError::AccountDoesNotExist(deposit_address, token))?;
+
+        match order.status.side {
+            OrderSide::Buy => {
+                deposit_account.merge_lots(vec![Lot {
+                    lot_number: self.next_lot_number(),
+                    acquisition: LotAcquistion {
+                        when,
+                        price: Some(usd_price),
+                        decimal_price: None,
+                        kind: LotAcquistionKind::Exchange {
+                            exchange,
+                            pair,
+                            order_id: order.order_id.clone(),
+                            external: true,
+                        },
+                    },
+                    amount: filled_amount,
+                }]);
+            }
+            OrderSide::Sell => {
+                let lots = deposit_account.extract_lots(
+                    self,
+                    filled_amount,
+                    lot_selection_method,
+                    None,
+                )?;
+                self.record_order_disposal(
+                    token,
+                    lots,
+                    when,
+                    usd_price,
+                    LotDisposalKind::Usd {
+                        exchange,
+                        pair,
+                        order_id: order.order_id.clone(),
+                        fee: order.status.fee.clone(),
+                        usd_fee,
+                        external: true,
+                    },
+                );
+            }
+        }
+        self.update_account(deposit_account) // `update_account` calls `save`...
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn record_disposal(
         &mut self,
@@ -1600,6 +3581,33 @@ impl Db {
         self.save()
     }
 
+    pub fn get_historical_price(
+        &self,
+        source: &str,
+        pair: &str,
+        when: NaiveDate,
+    ) -> Option<Decimal> {
+        self.data
+            .historical_prices
+            .as_ref()
+            .and_then(|historical_prices| historical_prices.get(&format!("{source}/{pair}/{when}")))
+            .cloned()
+    }
+
+    pub fn set_historical_price(
+        &mut self,
+        source: &str,
+        pair: &str,
+        when: NaiveDate,
+        price: Decimal,
+    ) -> DbResult<()> {
+        self.data
+            .historical_prices
+            .get_or_insert_with(HashMap::default)
+            .insert(format!("{source}/{pair}/{when}"), price);
+        self.save()
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub fn record_transfer(
         &mut self,
@@ -1988,3 +3996,155 @@ impl Db {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spending_record(
+        time: DateTime<Utc>,
+        operation: LimitedOperation,
+        ui_amount: f64,
+        usd_value: Option<f64>,
+    ) -> SpendingRecord {
+        SpendingRecord {
+            time,
+            operation,
+            exchange: Exchange::Kraken,
+            token: MaybeToken::SOL(),
+            ui_amount,
+            usd_value,
+            reference: format!("{operation}-{}", time.timestamp()),
+            fiat_currency: None,
+        }
+    }
+
+    #[test]
+    fn spending_window_is_evaluated_after_reload() {
+        let db_path = std::env::temp_dir().join(format!("sys-db-test-{}", std::process::id()));
+        let now = Utc::now();
+        let hours_ago = |hours| now - chrono::Duration::hours(hours);
+        let _ = fs::remove_dir_all(&db_path);
+        {
+            let mut db = new(&db_path).unwrap();
+            let max_window = i64::from(MAX_SPENDING_WINDOW_HOURS);
+            db.record_spending(spending_record(
+                hours_ago(max_window + 48),
+                LimitedOperation::Sell,
+                100.,
+                Some(10_000.),
+            ))
+            .unwrap();
+            for (hours, operation, ui_amount, usd_value) in [
+                (30, LimitedOperation::Sell, 10., Some(1_000.)),
+                (2, LimitedOperation::Buy, 1., Some(100.)),
+                (1, LimitedOperation::Sell, 2., None),
+                (0, LimitedOperation::Withdrawal, 4., Some(400.)),
+            ] {
+                db.record_spending(spending_record(
+                    hours_ago(hours),
+                    operation,
+                    ui_amount,
+                    usd_value,
+                ))
+                .unwrap();
+            }
+        }
+
+        let db = new(&db_path).unwrap();
+        fs::remove_dir_all(&db_path).unwrap();
+        // The record older than any window was dropped when the next one was recorded
+        assert_eq!(db.spending_records(hours_ago(24 * 365)).len(), 4);
+
+        let limit = |operation, token, window_hours| SpendingLimit {
+            operation,
+            token,
+            max: 1_000_000.,
+            window_hours,
+        };
+        let sol = Some(MaybeToken::SOL());
+        let used = |limit: SpendingLimit| {
+            db.spending_limit_used(&limit, now)
+                .map(|usage| (usage.used, usage.unvalued.len()))
+        };
+        assert_eq!(
+            used(limit(LimitedOperation::Order, sol, Some(24))),
+            Some((3., 0))
+        );
+        assert_eq!(
+            used(limit(LimitedOperation::Order, sol, Some(48))),
+            Some((13., 0))
+        );
+        assert_eq!(
+            used(limit(LimitedOperation::Sell, sol, Some(24))),
+            Some((2., 0))
+        );
+        // The sale that couldn't be valued is left for the check to value
+        assert_eq!(
+            used(limit(LimitedOperation::Order, None, Some(24))),
+            Some((100., 1))
+        );
+        assert_eq!(
+            used(limit(LimitedOperation::Withdrawal, None, Some(1))),
+            Some((400., 0))
+        );
+        assert_eq!(used(limit(LimitedOperation::Order, sol, None)), None);
+    }
+
+    #[test]
+    fn fiat_withdrawals_only_count_against_usd_limits() {
+        let db_path = std::env::temp_dir().join(format!("sys-db-fiat-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&db_path);
+        let mut db = new(&db_path).unwrap();
+        let now = Utc::now();
+        db.record_spending(SpendingRecord {
+            fiat_currency: Some("USD".into()),
+            ..spending_record(now, LimitedOperation::Withdrawal, 500., Some(500.))
+        })
+        .unwrap();
+        fs::remove_dir_all(&db_path).unwrap();
+
+        let limit = |token| SpendingLimit {
+            operation: LimitedOperation::Withdrawal,
+            token,
+            max: 1_000.,
+            window_hours: Some(24),
+        };
+        let used = |token| db.spending_limit_used(&limit(token), now).unwrap().used;
+        assert_eq!(used(None), 500.);
+        assert_eq!(used(Some(MaybeToken::SOL())), 0.);
+    }
+
+    #[test]
+    fn fiat_withdrawal_intents_describe_the_fiat_withdrawal() {
+        let now = Utc::now();
+        let intent = WithdrawalIntent {
+            code: "0A1B2C3D".into(),
+            exchange: Exchange::Kraken,
+            exchange_account: String::new(),
+            token: MaybeToken::SOL(),
+            amount: 0,
+            to_address: Pubkey::default(),
+            lot_selection_method: LotSelectionMethod::default(),
+            lot_numbers: None,
+            usd_value: Some(2_500.),
+            created_at: now,
+            expires_at: now,
+            state: WithdrawalIntentState::Pending,
+            fiat: Some(FiatWithdrawalIntent {
+                currency: "EUR".into(),
+                amount: 2_300.,
+                method_id: "sepa-1".into(),
+            }),
+        };
+        assert_eq!(intent.amount_description(), "€2300");
+        assert_eq!(intent.destination(), "sepa-1");
+
+        // Intents recorded before fiat withdrawals could be held are token withdrawals
+        let mut json = serde_json::to_value(&intent).unwrap();
+        json.as_object_mut().unwrap().remove("fiat");
+        let intent: WithdrawalIntent = serde_json::from_value(json).unwrap();
+        assert_eq!(intent.fiat, None);
+        assert_eq!(intent.amount_description(), "◎0");
+    }
+}
@@ -0,0 +1,341 @@
+//! Writes every request an exchange client makes, and the response to it, to a file that can be
+//! attached to a bug report.  Credentials, signatures and anything else that could authenticate
+//! as the user are scrubbed before they reach the file
+use {
+    crate::exchange::Exchange,
+    chrono::prelude::*,
+    std::{
+        fs::File,
+        io::{self, Write},
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::Duration,
+    },
+};
+
+const REDACTED: &str = "<redacted>";
+
+lazy_static::lazy_static! {
+    static ref CAPTURED_EXCHANGES: Mutex<Option<(Vec<Exchange>, PathBuf)>> = Mutex::default();
+}
+
+/// Exchanges whose clients send every request through the capture. Binance makes some of its
+/// requests with the SDK, out of reach of it
+pub const SUPPORTED_EXCHANGES: &[Exchange] = &[Exchange::Coinbase, Exchange::Kraken];
+
+/// Capture the requests of every client created from now on for one of `exchanges`, in `dir`
+pub fn enable(exchanges: Vec<Exchange>, dir: &Path) {
+    *CAPTURED_EXCHANGES.lock().unwrap() = Some((exchanges, dir.to_path_buf()));
+}
+
+/// The directory to capture `exchange`'s requests in, if `enable()` included it
+pub fn dir_for(exchange: Exchange) -> Option<PathBuf> {
+    CAPTURED_EXCHANGES
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|(exchanges, _)| exchanges.contains(&exchange))
+        .map(|(_, dir)| dir.clone())
+}
+
+// Headers, query parameters and body fields whose names contain any of these are redacted
+const SENSITIVE_NAMES: &[&str] = &[
+    "key",
+    "secret",
+    "sign",
+    "passphrase",
+    "password",
+    "auth",
+    "token",
+    "cookie",
+    "otp",
+    "2fa",
+];
+
+// Stripped entirely, as a signature is useless once the rest of the request is redacted
+const SIGNATURE_NAMES: &[&str] = &["signature", "sign"];
+
+fn is_sensitive(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SENSITIVE_NAMES
+        .iter()
+        .any(|sensitive| name.contains(sensitive))
+}
+
+fn is_signature(name: &str) -> bool {
+    SIGNATURE_NAMES
+        .iter()
+        .any(|signature| name.eq_ignore_ascii_case(signature))
+}
+
+/// Scrubs credentials out of requests and responses
+#[derive(Debug, Default, Clone)]
+pub struct Scrubber {
+    secrets: Vec<String>,
+}
+
+impl Scrubber {
+    /// `secrets` are replaced wherever they appear, in addition to the redaction of sensitive
+    /// headers and fields by name
+    pub fn new(secrets: &[&str]) -> Self {
+        Self {
+            secrets: secrets
+                .iter()
+                .filter(|secret| !secret.is_empty())
+                .map(|secret| secret.to_string())
+                .collect(),
+        }
+    }
+
+    /// Replaces every known secret in `text`
+    pub fn scrub_text(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_string(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+    }
+
+    pub fn scrub_header(&self, name: &str, value: &str) -> String {
+        if is_sensitive(name) {
+            REDACTED.to_string()
+        } else {
+            self.scrub_text(value)
+        }
+    }
+
+    /// Scrubs an "a=1&b=2" query string or form body, dropping signatures and redacting sensitive
+    /// values
+    pub fn scrub_form(&self, form: &str) -> String {
+        let scrubbed = form
+            .split('&')
+            .filter_map(|param| {
+                let name = param.split_once('=').map_or(param, |(name, _)| name);
+                if is_signature(name) {
+                    None
+                } else if is_sensitive(name) {
+                    Some(format!("{name}={REDACTED}"))
+                } else {
+                    Some(param.to_string())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        self.scrub_text(&scrubbed)
+    }
+
+    pub fn scrub_url(&self, url: &str) -> String {
+        match url.split_once('?') {
+            Some((path, query)) => format!("{}?{}", self.scrub_text(path), self.scrub_form(query)),
+            None => self.scrub_text(url),
+        }
+    }
+
+    fn scrub_json(&self, value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                map.retain(|name, _| !is_signature(name));
+                for (name, value) in map.iter_mut() {
+                    if is_sensitive(name) && !value.is_object() && !value.is_array() {
+                        *value = serde_json::Value::String(REDACTED.into());
+                    } else {
+                        self.scrub_json(value);
+                    }
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    self.scrub_json(value);
+                }
+            }
+            serde_json::Value::String(string) => {
+                *string = self.scrub_text(string);
+            }
+            _ => {}
+        }
+    }
+
+    /// Scrubs a JSON or form-encoded body, falling back to plain text for anything else
+    pub fn scrub_body(&self, body: &str) -> String {
+        if let Ok(mut value) = serde_json::from_str::<serde_json::Value>(body) {
+            self.scrub_json(&mut value);
+            return value.to_string();
+        }
+        if body.contains('=') && !body.contains(char::is_whitespace) {
+            return self.scrub_form(body);
+        }
+        self.scrub_text(body)
+    }
+}
+
+pub struct CapturedRequest<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    pub headers: &'a [(&'a str, &'a str)],
+    pub body: &'a str,
+}
+
+pub struct CapturedResponse<'a> {
+    pub status: u16,
+    pub body: &'a str,
+    pub latency: Duration,
+}
+
+/// One exchange client's capture file
+pub struct DebugCapture {
+    path: PathBuf,
+    file: Mutex<File>,
+    scrubber: Scrubber,
+}
+
+impl DebugCapture {
+    /// Creates a timestamped capture file for `exchange` in `dir`
+    pub fn create(exchange: Exchange, dir: &Path, secrets: &[&str]) -> io::Result<Self> {
+        let path = dir.join(format!(
+            "{}-capture-{}.log",
+            exchange.name(),
+            Utc::now().format("%Y%m%dT%H%M%S")
+        ));
+        let file = File::create(&path)?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            scrubber: Scrubber::new(secrets),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends a request and its response.  Capture is best effort, a failure to write never fails
+    /// the request itself
+    pub fn record(&self, request: &CapturedRequest, response: &CapturedResponse) {
+        let scrubber = &self.scrubber;
+        let mut entry = format!(
+            "==> {} {} {}\n",
+            Utc::now().to_rfc3339(),
+            request.method,
+            scrubber.scrub_url(request.url)
+        );
+        for (name, value) in request.headers {
+            entry += &format!("{name}: {}\n", scrubber.scrub_header(name, value));
+        }
+        if !request.body.is_empty() {
+            entry += &format!("\n{}\n", scrubber.scrub_body(request.body));
+        }
+        entry += &format!(
+            "<== {} in {}ms\n{}\n\n",
+            response.status,
+            response.latency.as_millis(),
+            scrubber.scrub_body(response.body)
+        );
+
+        if let Ok(mut file) = self.file.lock() {
+            if let Err(err) = file.write_all(entry.as_bytes()) {
+                log::warn!("Unable to write to {}: {err}", self.path.display());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const API_KEY: &str = "Zm9vYmFyYmF6cXV4QVBJS0VZ";
+    const SECRET: &str = "c2VjcmV0c2VjcmV0c2VjcmV0";
+    const SIGNATURE: &str = "4f1c2e9a7b3d5f6e8a0b1c2d3e4f5a6b";
+
+    fn capture(exchange: Exchange, request: CapturedRequest, response_body: &str) -> String {
+        let dir = std::env::temp_dir().join(format!(
+            "sys-debug-capture-test-{}-{}",
+            exchange.name(),
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let capture = DebugCapture::create(exchange, &dir, &[API_KEY, SECRET]).unwrap();
+        capture.record(
+            &request,
+            &CapturedResponse {
+                status: 200,
+                body: response_body,
+                latency: Duration::from_millis(42),
+            },
+        );
+        let contents = std::fs::read_to_string(capture.path()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        contents
+    }
+
+    fn assert_scrubbed(contents: &str) {
+        for credential in [API_KEY, SECRET, SIGNATURE] {
+            assert!(
+                !contents.contains(credential),
+                "{credential} in:\n{contents}"
+            );
+        }
+    }
+
+    #[test]
+    fn kraken_api_sign_and_nonce_form_are_scrubbed() {
+        let contents = capture(
+            Exchange::Kraken,
+            CapturedRequest {
+                method: "POST",
+                url: "https://api.kraken.com/0/private/Balance",
+                headers: &[
+                    ("API-Key", API_KEY),
+                    ("API-Sign", SIGNATURE),
+                    ("Content-Type", "application/x-www-form-urlencoded"),
+                ],
+                body: &format!("nonce=1700000000000&otp=123456&asset=SOL&key={API_KEY}"),
+            },
+            r#"{"error":[],"result":{"SOL":"1.5"}}"#,
+        );
+        assert_scrubbed(&contents);
+        assert!(contents.contains("nonce=1700000000000"));
+        assert!(contents.contains("asset=SOL"));
+        assert!(!contents.contains("123456"));
+    }
+
+    #[test]
+    fn binance_signature_query_is_scrubbed() {
+        let contents = capture(
+            Exchange::Binance,
+            CapturedRequest {
+                method: "GET",
+                url: &format!(
+                    "https://api.binance.us/api/v3/account?recvWindow=5000\
+                     &timestamp=1700000000000&signature={SIGNATURE}"
+                ),
+                headers: &[("X-MBX-APIKEY", API_KEY)],
+                body: "",
+            },
+            &format!(r#"{{"code":-2015,"msg":"Invalid API-key {API_KEY}, IP, or permissions"}}"#),
+        );
+        assert_scrubbed(&contents);
+        assert!(contents.contains("timestamp=1700000000000"));
+        assert!(!contents.contains("signature="));
+    }
+
+    #[test]
+    fn coinbase_access_headers_are_scrubbed() {
+        let contents = capture(
+            Exchange::Coinbase,
+            CapturedRequest {
+                method: "POST",
+                url: "https://api.exchange.coinbase.com/orders",
+                headers: &[
+                    ("CB-ACCESS-KEY", API_KEY),
+                    ("CB-ACCESS-SIGN", SIGNATURE),
+                    ("CB-ACCESS-TIMESTAMP", "1700000000"),
+                    ("CB-ACCESS-PASSPHRASE", SECRET),
+                ],
+                body: r#"{"product_id":"SOL-USD","side":"buy","size":"1"}"#,
+            },
+            r#"{"id":"d0c5340b-6d6c-49d9-b567-48c4bfca13d2","status":"pending"}"#,
+        );
+        assert_scrubbed(&contents);
+        assert!(contents.contains("CB-ACCESS-TIMESTAMP: 1700000000"));
+        assert!(contents.contains("SOL-USD"));
+    }
+}
@@ -1,12 +1,27 @@
 use {
     crate::{
-        binance_exchange, coinbase_exchange, ftx_exchange, kraken_exchange, token::MaybeToken,
+        coin_gecko,
+        confirmation::{self, IrreversibleOperation},
+        field_as_string,
+        pre_trade::{self, OrderIntent, PreTradeCheck, PreWithdrawalCheck, WithdrawalRequest},
+        settings::{self, Setting},
+        token::{MaybeToken, Token},
+        two_factor::TwoFactorCodeProvider,
     },
     async_trait::async_trait,
-    chrono::NaiveDate,
+    chrono::{DateTime, FixedOffset, Local, NaiveDate, TimeZone, Utc},
+    rust_decimal::prelude::*,
+    separator::FixedPlaceSeparatable,
     serde::{Deserialize, Serialize},
     solana_sdk::pubkey::Pubkey,
-    std::{collections::HashMap, str::FromStr},
+    std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        path::{Path, PathBuf},
+        str::FromStr,
+        sync::atomic::{AtomicU64, Ordering},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    strum::{EnumString, IntoStaticStr},
     thiserror::Error,
 };
 
@@ -26,28 +41,862 @@ impl std::fmt::Display for Exchange {
     }
 }
 
-pub const USD_COINS: &[&str] = &["USD", "USDC", "USDT", "BUSD", "ZUSD"];
+impl Exchange {
+    pub fn all() -> &'static [Exchange] {
+        &[
+            Exchange::Binance,
+            Exchange::BinanceUs,
+            Exchange::Coinbase,
+            Exchange::Ftx,
+            Exchange::FtxUs,
+            Exchange::Kraken,
+        ]
+    }
+
+    /// Lowercase name of the exchange, as used on the command line
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exchange::Binance => "binance",
+            Exchange::BinanceUs => "binanceus",
+            Exchange::Coinbase => "coinbase",
+            Exchange::Ftx => "ftx",
+            Exchange::FtxUs => "ftxus",
+            Exchange::Kraken => "kraken",
+        }
+    }
+
+    /// Cargo feature that compiles in support for this exchange
+    pub fn feature_name(&self) -> &'static str {
+        match self {
+            Exchange::Binance | Exchange::BinanceUs => "binance",
+            Exchange::Coinbase => "coinbase",
+            Exchange::Ftx | Exchange::FtxUs => "ftx",
+            Exchange::Kraken => "kraken",
+        }
+    }
+
+    /// Defunct exchanges are retained so historical records remain readable, but can no longer
+    /// be queried
+    pub fn is_defunct(&self) -> bool {
+        matches!(self, Exchange::Ftx | Exchange::FtxUs)
+    }
+
+    pub fn is_compiled_in(&self) -> bool {
+        match self {
+            Exchange::Binance | Exchange::BinanceUs => cfg!(feature = "binance"),
+            Exchange::Coinbase => cfg!(feature = "coinbase"),
+            Exchange::Ftx | Exchange::FtxUs => cfg!(feature = "ftx"),
+            Exchange::Kraken => cfg!(feature = "kraken"),
+        }
+    }
+
+    /// What `sys` is able to do with this exchange
+    pub fn capabilities(&self) -> ExchangeCapabilities {
+        match self {
+            Exchange::Binance | Exchange::BinanceUs => ExchangeCapabilities {
+                supports_balances: true,
+                supports_trading: true,
+                supports_taker_orders: true,
+                supports_sol_network_withdrawals: true,
+                supports_withdrawal_references: true,
+                supports_order_history: true,
+                supports_volume_tiers: true,
+                supports_stream_tokens: true,
+                // Binance US has no conversions or Simple Earn
+                supports_earn: *self == Exchange::Binance,
+                history_categories: if *self == Exchange::Binance {
+                    HISTORY_CATEGORIES
+                } else {
+                    &[
+                        HistoryCategory::Deposits,
+                        HistoryCategory::Withdrawals,
+                        HistoryCategory::Trades,
+                    ]
+                },
+                ..ExchangeCapabilities::default()
+            },
+            Exchange::Coinbase => ExchangeCapabilities {
+                supports_balances: true,
+                supports_subaccounts: true,
+                supports_sol_network_withdrawals: true,
+                supports_spl_deposits: true,
+                supports_withdrawal_references: true,
+                supports_fiat_withdrawals: true,
+                withdrawal_fee_on_top: true,
+                ..ExchangeCapabilities::default()
+            },
+            Exchange::Ftx | Exchange::FtxUs => ExchangeCapabilities::default(),
+            Exchange::Kraken => ExchangeCapabilities {
+                supports_balances: true,
+                supports_trading: true,
+                supports_taker_orders: true,
+                supports_staking: true,
+                supports_earn: true,
+                supports_sol_network_withdrawals: true,
+                supports_spl_deposits: true,
+                supports_ledger_export: true,
+                supports_native_order_expiry: true,
+                supports_order_amendment: true,
+                supports_fiat_withdrawals: true,
+                supports_order_history: true,
+                supports_volume_tiers: true,
+                supports_stream_tokens: true,
+                history_categories: HISTORY_CATEGORIES,
+                ..ExchangeCapabilities::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ExchangeCapabilities {
+    pub supports_balances: bool,
+    pub supports_trading: bool,
+    pub supports_taker_orders: bool,
+    pub supports_market_orders: bool,
+    pub supports_lending: bool,
+    pub supports_staking: bool,
+    /// Earn or lending positions are listed by `all_lending_info()` and can be redeemed with
+    /// `redeem_lending()`, whether or not new lending offers can be made
+    pub supports_earn: bool,
+    pub supports_subaccounts: bool,
+    pub supports_sol_network_withdrawals: bool,
+    pub supports_spl_deposits: bool,
+    pub supports_ledger_export: bool,
+    pub supports_withdrawal_references: bool,
+    /// Limit orders can be given an expiry that the exchange enforces itself.  Elsewhere `sys`
+    /// cancels expired orders when it syncs the exchange
+    pub supports_native_order_expiry: bool,
+    /// Resting orders can be repriced in place, rather than cancelled and replaced
+    pub supports_order_amendment: bool,
+    /// Fiat currency can be withdrawn to a bank account registered with the exchange
+    pub supports_fiat_withdrawals: bool,
+    /// Past orders can be listed, including those placed outside of `sys`
+    pub supports_order_history: bool,
+    /// 30-day volume and the next fee tier are reported
+    pub supports_volume_tiers: bool,
+    /// Private streaming API tokens are issued, and reused across invocations while valid
+    pub supports_stream_tokens: bool,
+    /// The withdrawal fee is charged on top of the amount withdrawn, rather than out of it
+    pub withdrawal_fee_on_top: bool,
+    /// Account history that can be backfilled into the exchange journal
+    pub history_categories: &'static [HistoryCategory],
+}
+
+impl ExchangeCapabilities {
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, bool)> {
+        [
+            ("balances", self.supports_balances),
+            ("trading", self.supports_trading),
+            ("taker orders", self.supports_taker_orders),
+            ("market orders", self.supports_market_orders),
+            ("lending", self.supports_lending),
+            ("staking", self.supports_staking),
+            ("earn", self.supports_earn),
+            ("subaccounts", self.supports_subaccounts),
+            ("SOL withdrawals", self.supports_sol_network_withdrawals),
+            ("SPL token deposits", self.supports_spl_deposits),
+            ("ledger export", self.supports_ledger_export),
+            ("withdrawal references", self.supports_withdrawal_references),
+            ("native order expiry", self.supports_native_order_expiry),
+            ("order amendment", self.supports_order_amendment),
+            ("fiat withdrawals", self.supports_fiat_withdrawals),
+            ("order history", self.supports_order_history),
+            ("volume tiers", self.supports_volume_tiers),
+            ("stream tokens", self.supports_stream_tokens),
+        ]
+        .into_iter()
+    }
+}
+
+pub(crate) const DEFAULT_USD_COINS: &[&str] = &["USD", "USDC", "USDT", "BUSD", "FDUSD", "PYUSD"];
+
+/// Dollar-denominated coins in order of preference, used when selecting a quote currency and when
+/// totaling balances.
+///
+/// Override with the `usd-coins` setting, to include or exclude a given stablecoin
+pub fn usd_preference_order() -> &'static [String] {
+    lazy_static::lazy_static! {
+        static ref USD_COINS: Vec<String> = match settings::get(Setting::UsdCoins) {
+            Some(coins) => parse_coin_list(&coins),
+            None => DEFAULT_USD_COINS.iter().map(|coin| coin.to_string()).collect(),
+        };
+    }
+    &USD_COINS
+}
+
+/// Quote currencies in order of preference when selecting a market for a token, from the
+/// comma-separated `quote-preference` setting such as "USDC,USD" or "EUR".
+///
+/// When unset, each exchange's preferred SOL market is used followed by `usd_preference_order()`
+pub fn quote_preference_order() -> Option<&'static [String]> {
+    lazy_static::lazy_static! {
+        static ref QUOTE_PREFERENCE: Option<Vec<String>> = settings::get(Setting::QuotePreference)
+            .map(|coins| parse_coin_list(&coins))
+            .filter(|coins| !coins.is_empty());
+    }
+    QUOTE_PREFERENCE.as_deref()
+}
+
+pub(crate) fn parse_coin_list(coins: &str) -> Vec<String> {
+    coins
+        .split(',')
+        .map(|coin| coin.trim().to_ascii_uppercase())
+        .filter(|coin| !coin.is_empty())
+        .collect()
+}
+
+// National currencies that exchanges quote markets in
+const FIAT_CURRENCIES: &[&str] = &["USD", "EUR", "GBP", "AUD", "CAD", "CHF", "JPY"];
+
+/// Case-insensitive, with Kraken's legacy codes such as "ZEUR" accepted
+pub fn is_fiat(coin: &str) -> bool {
+    let coin = coin.to_ascii_uppercase();
+    FIAT_CURRENCIES.contains(&normalize_kraken_asset(&coin))
+}
+
+pub fn check_fiat_currency(currency: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if is_fiat(currency) {
+        Ok(())
+    } else {
+        Err(format!("{currency} is not a fiat currency").into())
+    }
+}
+
+/// Parses comma-separated USD exchange rates such as "EUR=1.08,GBP=1.27"
+pub(crate) fn parse_fx_rates(rates: &str) -> Result<HashMap<String, f64>, String> {
+    rates
+        .split(',')
+        .map(str::trim)
+        .filter(|rate| !rate.is_empty())
+        .map(|entry| {
+            let (currency, rate) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid rate: {entry} (expected CURRENCY=RATE)"))?;
+            let rate = rate
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| rate.is_finite() && *rate > 0.)
+                .ok_or_else(|| format!("invalid rate: {entry} (expected a positive rate)"))?;
+            Ok((currency.trim().to_ascii_uppercase(), rate))
+        })
+        .collect()
+}
+
+/// USD exchange rates from the `fx-rates` setting.  These take precedence over market rates when
+/// valuing a currency
+pub fn fx_rate_override(currency: &str) -> Option<f64> {
+    let rates = parse_fx_rates(&settings::get(Setting::FxRates)?).ok()?;
+    rates.get(&currency.to_ascii_uppercase()).copied()
+}
+
+/// Where a USD price for an asset is taken from when valuing holdings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriceSourceKind {
+    /// The exchange's `pair` market, otherwise its market for the asset quoted in the first coin
+    /// of `usd_preference_order()` it lists, converted to USD
+    Exchange {
+        exchange: Exchange,
+        pair: Option<TradingPair>,
+    },
+    CoinGecko,
+    /// Tulip's price of a lending liquidity token
+    Tulip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriceSource {
+    pub kind: PriceSourceKind,
+    /// A price older than this is passed over for the next source
+    pub max_age: Option<chrono::Duration>,
+}
+
+impl FromStr for PriceSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (source, max_age) = match s.split_once('@') {
+            Some((source, max_age)) => {
+                let max_age = max_age.trim().parse::<u32>().map_err(|_| {
+                    format!("invalid price source age: {s} (expected SOURCE@SECONDS)")
+                })?;
+                (
+                    source.trim(),
+                    Some(chrono::Duration::seconds(max_age.into())),
+                )
+            }
+            None => (s.trim(), None),
+        };
+
+        let kind = match source.to_ascii_lowercase().as_str() {
+            "coingecko" => PriceSourceKind::CoinGecko,
+            "tulip" => PriceSourceKind::Tulip,
+            _ => {
+                let (exchange, pair) = match source.split_once(':') {
+                    Some((exchange, pair)) => (
+                        exchange,
+                        Some(
+                            pair.to_ascii_uppercase()
+                                .parse::<TradingPair>()
+                                .map_err(|err| err.to_string())?,
+                        ),
+                    ),
+                    None => (source, None),
+                };
+                PriceSourceKind::Exchange {
+                    exchange: exchange
+                        .parse()
+                        .map_err(|err: ParseExchangeError| err.to_string())?,
+                    pair,
+                }
+            }
+        };
+        Ok(Self { kind, max_age })
+    }
+}
+
+pub(crate) fn parse_price_sources(
+    price_sources: &str,
+) -> Result<HashMap<String, Vec<PriceSource>>, String> {
+    price_sources
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (asset, sources) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid entry: {entry} (expected ASSET=SOURCE,...)"))?;
+            let sources = sources
+                .split(',')
+                .map(str::trim)
+                .filter(|source| !source.is_empty())
+                .map(PriceSource::from_str)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((asset.trim().to_ascii_uppercase(), sources))
+        })
+        .collect()
+}
+
+fn price_sources() -> Result<HashMap<String, Vec<PriceSource>>, String> {
+    settings::get(Setting::PriceSources).map_or_else(
+        || Ok(HashMap::default()),
+        |price_sources| {
+            parse_price_sources(&price_sources).map_err(|err| format!("price-sources: {err}"))
+        },
+    )
+}
+
+/// Fails if the `price-sources` setting can't be parsed
+pub fn check_price_sources() -> Result<(), String> {
+    price_sources().map(|_| ())
+}
+
+/// Price sources to value `asset` from, in order of priority, from the `price-sources` setting of
+/// semicolon-separated `ASSET=SOURCE,...` lists, `*` being the list for assets without one of
+/// their own.  A source is `coingecko`, `tulip`, an exchange such as
+/// `kraken` or one of its markets such as `binance:SOL/USDC`, optionally followed by `@SECONDS`
+/// to pass over prices older than that.  For example
+/// "SOL=kraken:SOL/USD,coinbase,binance:SOL/USDC,coingecko@600;*=kraken,coingecko".
+///
+/// `None` when `asset` has no list, in which case each exchange is tried in turn before the
+/// token's own price source
+pub fn price_source_priority(asset: &str) -> Option<Vec<PriceSource>> {
+    let mut price_sources = price_sources().ok()?;
+    price_sources
+        .remove(&asset.to_ascii_uppercase())
+        .or_else(|| price_sources.remove("*"))
+}
+
+pub(crate) const DEFAULT_PRICE_MISMATCH_PERCENT: f64 = 2.;
+
+/// Difference between two price sources, as a fraction of the price used, beyond which valuation
+/// warns that one of them may be wrong.
+///
+/// Override with the `price-mismatch-percent` setting
+pub fn price_mismatch_threshold() -> f64 {
+    settings::get(Setting::PriceMismatchPercent)
+        .and_then(|percent| settings::parse_percent(&percent).ok())
+        .unwrap_or(DEFAULT_PRICE_MISMATCH_PERCENT)
+        / 100.
+}
+
+pub(crate) const DEFAULT_DEPOSIT_ADDRESS_CACHE_HOURS: i64 = 24;
+
+/// How long a deposit address fetched from an exchange is used before it's fetched again, from the
+/// `deposit-address-cache-hours` setting.  Zero fetches it every time
+pub fn deposit_address_cache_ttl() -> chrono::Duration {
+    chrono::Duration::hours(
+        settings::get(Setting::DepositAddressCacheHours)
+            .and_then(|hours| hours.trim().parse::<i64>().ok())
+            .unwrap_or(DEFAULT_DEPOSIT_ADDRESS_CACHE_HOURS),
+    )
+}
+
+/// Currency that portfolio values are presented in, from the `reporting-currency` setting.  USD
+/// when unset
+pub fn reporting_currency() -> String {
+    settings::get(Setting::ReportingCurrency)
+        .map(|currency| currency.trim().to_ascii_uppercase())
+        .filter(|currency| !currency.is_empty())
+        .unwrap_or_else(|| "USD".into())
+}
+
+/// Time zone whose days reports are divided into, regardless of the day boundaries each exchange
+/// uses itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportingTimeZone {
+    Utc,
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl FromStr for ReportingTimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "utc" | "z" => return Ok(Self::Utc),
+            "local" => return Ok(Self::Local),
+            _ => {}
+        }
+
+        let invalid =
+            || format!("invalid time zone: {s} (expected UTC, local or an offset such as -05:00)");
+        let (sign, offset) = match s.strip_prefix('+') {
+            Some(offset) => (1, offset),
+            None => (-1, s.strip_prefix('-').ok_or_else(invalid)?),
+        };
+        let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+        let hours = hours.parse::<i32>().map_err(|_| invalid())?;
+        let minutes = minutes.parse::<i32>().map_err(|_| invalid())?;
+        if minutes >= 60 {
+            return Err(invalid());
+        }
+        FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+            .map(Self::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+impl std::fmt::Display for ReportingTimeZone {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Utc => write!(f, "UTC"),
+            Self::Local => write!(f, "local time"),
+            Self::Fixed(offset) => write!(f, "UTC{offset}"),
+        }
+    }
+}
+
+impl ReportingTimeZone {
+    /// When `date` begins in this time zone.  A day that begins in a daylight saving gap begins
+    /// when the gap ends
+    pub fn day_start(&self, date: NaiveDate) -> DateTime<Utc> {
+        let midnight = date.and_hms(0, 0, 0);
+        match self {
+            Self::Utc => DateTime::from_utc(midnight, Utc),
+            Self::Local => (0..24)
+                .find_map(|hour| {
+                    Local
+                        .from_local_datetime(&(midnight + chrono::Duration::hours(hour)))
+                        .earliest()
+                })
+                .expect("day start")
+                .with_timezone(&Utc),
+            Self::Fixed(offset) => offset
+                .from_local_datetime(&midnight)
+                .unwrap()
+                .with_timezone(&Utc),
+        }
+    }
+
+    /// The day `time` falls on in this time zone
+    pub fn date_of(&self, time: DateTime<Utc>) -> NaiveDate {
+        match self {
+            Self::Utc => time.naive_utc().date(),
+            Self::Local => time.with_timezone(&Local).naive_local().date(),
+            Self::Fixed(offset) => time.with_timezone(offset).naive_local().date(),
+        }
+    }
+
+    /// The days from `start` to `end` inclusive in this time zone, from the start of `start` up to
+    /// but excluding the start of the day after `end`
+    pub fn days(&self, start: NaiveDate, end: NaiveDate) -> (DateTime<Utc>, DateTime<Utc>) {
+        (
+            self.day_start(start),
+            self.day_start(end + chrono::Duration::days(1)),
+        )
+    }
+}
+
+/// Time zone that reports divide into days, from the `reporting-timezone` setting: `UTC`,
+/// `local` or an offset from UTC such as `-05:00`.  UTC when unset
+pub fn reporting_time_zone() -> Result<ReportingTimeZone, String> {
+    match settings::get(Setting::ReportingTimezone) {
+        Some(time_zone) if !time_zone.trim().is_empty() => time_zone
+            .parse()
+            .map_err(|err| format!("reporting-timezone: {err}")),
+        _ => Ok(ReportingTimeZone::Utc),
+    }
+}
+
+// A preferred market with less than this fraction of an alternative's 24h quote volume is thin
+const THIN_MARKET_VOLUME_RATIO: f64 = 0.1;
+
+/// Case-insensitive, with Kraken's legacy "ZUSD" asset code treated as "USD"
+pub fn is_usd_equivalent(coin: &str) -> bool {
+    let coin = coin.to_ascii_uppercase();
+    let coin = normalize_kraken_asset(&coin);
+    coin == "USD"
+        || usd_preference_order()
+            .iter()
+            .any(|usd_coin| usd_coin == coin)
+}
 
 impl FromStr for Exchange {
     type Err = ParseExchangeError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "Binance" | "binance" => Ok(Exchange::Binance),
-            "BinanceUs" | "binanceus" => Ok(Exchange::BinanceUs),
-            "Coinbase" | "coinbase" => Ok(Exchange::Coinbase),
-            "Ftx" | "ftx" => Ok(Exchange::Ftx),
-            "FtxUs" | "ftxus" => Ok(Exchange::FtxUs),
-            "Kraken" | "kraken" => Ok(Exchange::Kraken),
-            _ => Err(ParseExchangeError::InvalidExchange),
+        let name = s
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '-' | '_' | '.'))
+            .collect::<String>()
+            .to_lowercase();
+
+        match name.as_str() {
+            "binance" | "binancecom" => Ok(Exchange::Binance),
+            "binanceus" | "binanceusa" => Ok(Exchange::BinanceUs),
+            "coinbase" | "coinbasepro" | "coinbaseexchange" | "cbpro" | "gdax" => {
+                Ok(Exchange::Coinbase)
+            }
+            "ftx" | "ftxcom" => Ok(Exchange::Ftx),
+            "ftxus" => Ok(Exchange::FtxUs),
+            "kraken" => Ok(Exchange::Kraken),
+            _ => Err(ParseExchangeError::InvalidExchange {
+                input: s.into(),
+                suggestion: Exchange::all()
+                    .iter()
+                    .map(|exchange| (edit_distance(&name, exchange.name()), *exchange))
+                    .filter(|(distance, _)| *distance <= 2)
+                    .min_by_key(|(distance, _)| *distance)
+                    .map(|(_, exchange)| exchange),
+            }),
         }
     }
 }
 
 #[derive(Error, Debug)]
 pub enum ParseExchangeError {
-    #[error("invalid exchange")]
-    InvalidExchange,
+    #[error(
+        "invalid exchange: {input:?}{}; valid exchanges are {}",
+        format_exchange_suggestion(.suggestion),
+        valid_exchange_names()
+    )]
+    InvalidExchange {
+        input: String,
+        suggestion: Option<Exchange>,
+    },
+}
+
+fn format_exchange_suggestion(suggestion: &Option<Exchange>) -> String {
+    suggestion
+        .map(|exchange| format!(", did you mean {}?", exchange.name()))
+        .unwrap_or_default()
+}
+
+fn valid_exchange_names() -> String {
+    Exchange::all()
+        .iter()
+        .map(|exchange| exchange.name())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TradingPair {
+    pub base: String,
+    pub quote: String,
+}
+
+impl TradingPair {
+    pub fn new(base: &str, quote: &str) -> Self {
+        Self {
+            base: base.to_uppercase(),
+            quote: quote.to_uppercase(),
+        }
+    }
+
+    pub fn from_token(token: MaybeToken, quote: &str) -> Self {
+        Self::new(token.name(), quote)
+    }
+
+    pub fn base_token(&self) -> Option<MaybeToken> {
+        if self.base == "SOL" {
+            Some(MaybeToken::SOL())
+        } else {
+            Token::from_str(&self.base).ok().map(|token| token.into())
+        }
+    }
+
+    pub fn to_exchange_symbol(&self, exchange: Exchange) -> String {
+        match exchange {
+            Exchange::Binance | Exchange::BinanceUs => format!("{}{}", self.base, self.quote),
+            Exchange::Coinbase => format!("{}-{}", self.base, self.quote),
+            Exchange::Ftx | Exchange::FtxUs => format!("{}/{}", self.base, self.quote),
+            Exchange::Kraken => {
+                match (
+                    kraken_legacy_asset_code(&self.base),
+                    kraken_legacy_asset_code(&self.quote),
+                ) {
+                    (Some(base), Some(quote)) => format!("{base}{quote}"),
+                    _ => format!(
+                        "{}{}",
+                        kraken_asset_altname(&self.base),
+                        kraken_asset_altname(&self.quote)
+                    ),
+                }
+            }
+        }
+    }
+
+    pub fn from_exchange_symbol(
+        exchange: Exchange,
+        symbol: &str,
+    ) -> Result<Self, ParseTradingPairError> {
+        if symbol.contains(TRADING_PAIR_SEPARATORS) {
+            return symbol.parse();
+        }
+        let symbol = symbol.to_uppercase();
+
+        let split_by_quote = |quotes: &[&str]| {
+            quotes
+                .iter()
+                .filter(|quote| symbol.len() > quote.len() && symbol.ends_with(*quote))
+                .max_by_key(|quote| quote.len())
+                .map(|quote| symbol.split_at(symbol.len() - quote.len()))
+        };
+
+        let (base, quote): (String, String) = match exchange {
+            Exchange::Binance | Exchange::BinanceUs => split_by_quote(BINANCE_QUOTE_ASSETS)
+                .map(|(base, quote)| (base.into(), quote.into())),
+            Exchange::Coinbase | Exchange::Ftx | Exchange::FtxUs => None,
+            Exchange::Kraken => {
+                let legacy = (symbol.len() == 8).then(|| symbol.split_at(4)).and_then(
+                    |(base, quote)| match (
+                        kraken_asset_from_legacy_code(base),
+                        kraken_asset_from_legacy_code(quote),
+                    ) {
+                        (Some(base), Some(quote)) => Some((base.into(), quote.into())),
+                        _ => None,
+                    },
+                );
+
+                legacy.or_else(|| {
+                    split_by_quote(KRAKEN_QUOTE_ASSETS).map(|(base, quote)| {
+                        (
+                            normalize_kraken_asset(base).to_string(),
+                            normalize_kraken_asset(quote).to_string(),
+                        )
+                    })
+                })
+            }
+        }
+        .ok_or_else(|| ParseTradingPairError::InvalidExchangeSymbol(exchange, symbol.clone()))?;
+
+        Ok(Self::new(&base, &quote))
+    }
+}
+
+impl std::fmt::Display for TradingPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.base, self.quote)
+    }
+}
+
+const TRADING_PAIR_SEPARATORS: &[char] = &['/', '-', '_'];
+
+impl FromStr for TradingPair {
+    type Err = ParseTradingPairError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(TRADING_PAIR_SEPARATORS).collect::<Vec<_>>()[..] {
+            [base, quote] if !base.is_empty() && !quote.is_empty() => Ok(Self::new(base, quote)),
+            _ => Err(ParseTradingPairError::InvalidTradingPair(s.into())),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ParseTradingPairError {
+    #[error("invalid trading pair: {0} (expected BASE/QUOTE)")]
+    InvalidTradingPair(String),
+
+    #[error("unable to parse {0:?} trading pair symbol: {1}")]
+    InvalidExchangeSymbol(Exchange, String),
+}
+
+// Ordered so that quote assets sharing a suffix (USDT/USD) are tried longest first
+const BINANCE_QUOTE_ASSETS: &[&str] = &[
+    "USDT", "BUSD", "USDC", "TUSD", "USD", "BTC", "ETH", "BNB", "EUR", "GBP", "DAI",
+];
+
+const KRAKEN_QUOTE_ASSETS: &[&str] = &[
+    "ZUSD", "ZEUR", "ZGBP", "USDT", "USDC", "USD", "EUR", "GBP", "XBT", "ETH", "DAI",
+];
+
+// Kraken's legacy assets use a four character code with an "X" (crypto) or "Z" (fiat) prefix, and
+// Bitcoin/Dogecoin are known as XBT/XDG
+const KRAKEN_LEGACY_ASSETS: &[(&str, &str)] = &[
+    ("BTC", "XXBT"),
+    ("DOGE", "XXDG"),
+    ("ETC", "XETC"),
+    ("ETH", "XETH"),
+    ("LTC", "XLTC"),
+    ("MLN", "XMLN"),
+    ("REP", "XREP"),
+    ("XLM", "XXLM"),
+    ("XMR", "XXMR"),
+    ("XRP", "XXRP"),
+    ("ZEC", "XZEC"),
+    ("AUD", "ZAUD"),
+    ("CAD", "ZCAD"),
+    ("EUR", "ZEUR"),
+    ("GBP", "ZGBP"),
+    ("JPY", "ZJPY"),
+    ("USD", "ZUSD"),
+];
+
+fn kraken_legacy_asset_code(asset: &str) -> Option<&'static str> {
+    KRAKEN_LEGACY_ASSETS
+        .iter()
+        .find(|(symbol, _)| *symbol == asset)
+        .map(|(_, code)| *code)
+}
+
+fn kraken_asset_from_legacy_code(code: &str) -> Option<&'static str> {
+    KRAKEN_LEGACY_ASSETS
+        .iter()
+        .find(|(_, legacy_code)| *legacy_code == code)
+        .map(|(symbol, _)| *symbol)
+}
+
+fn kraken_asset_altname(asset: &str) -> &str {
+    match asset {
+        "BTC" => "XBT",
+        "DOGE" => "XDG",
+        asset => asset,
+    }
+}
+
+/// Maps a Kraken asset code, legacy ("XXBT", "ZUSD") or otherwise ("XBT", "SOL"), to its
+/// common symbol
+pub fn normalize_kraken_asset(code: &str) -> &str {
+    match kraken_asset_from_legacy_code(code) {
+        Some(symbol) => symbol,
+        None => match code {
+            "XBT" => "BTC",
+            "XDG" => "DOGE",
+            code => code,
+        },
+    }
+}
+
+// Staked balances are reported under suffixed asset codes, such as "SOL.S", "SOL.F" or "DOT28.S"
+pub(crate) fn kraken_staked_asset(code: &str) -> Option<&str> {
+    code.split_once('.')
+        .filter(|(_, suffix)| matches!(*suffix, "S" | "B" | "F" | "M" | "P"))
+        .map(|(asset, _)| asset.trim_end_matches(|c: char| c.is_ascii_digit()))
+}
+
+// Coin symbols of the tokens listed by each exchange. `None` is SOL
+const BINANCE_TOKEN_SYMBOLS: &[(&str, Option<Token>)] =
+    &[("SOL", None), ("USDC", Some(Token::USDC))];
+const COINBASE_TOKEN_SYMBOLS: &[(&str, Option<Token>)] = &[
+    ("SOL", None),
+    ("USDC", Some(Token::USDC)),
+    ("MSOL", Some(Token::mSOL)),
+];
+const FTX_TOKEN_SYMBOLS: &[(&str, Option<Token>)] = &[("SOL", None), ("USDC", Some(Token::USDC))];
+const KRAKEN_TOKEN_SYMBOLS: &[(&str, Option<Token>)] = &[
+    ("SOL", None),
+    ("USDC", Some(Token::USDC)),
+    ("MSOL", Some(Token::mSOL)),
+];
+
+/// The token an exchange coin symbol denotes, if any.  Kraken legacy asset codes and staked
+/// variants such as "SOL.S" map to the underlying token
+pub fn exchange_symbol_token(exchange: Exchange, symbol: &str) -> Option<MaybeToken> {
+    let symbol = symbol.to_ascii_uppercase();
+    let (symbol, token_symbols) = match exchange {
+        Exchange::Binance | Exchange::BinanceUs => (symbol.as_str(), BINANCE_TOKEN_SYMBOLS),
+        Exchange::Coinbase => (symbol.as_str(), COINBASE_TOKEN_SYMBOLS),
+        Exchange::Ftx | Exchange::FtxUs => (symbol.as_str(), FTX_TOKEN_SYMBOLS),
+        Exchange::Kraken => (
+            normalize_kraken_asset(kraken_staked_asset(&symbol).unwrap_or(symbol.as_str())),
+            KRAKEN_TOKEN_SYMBOLS,
+        ),
+    };
+    token_symbols
+        .iter()
+        .find(|(token_symbol, _)| *token_symbol == symbol)
+        .map(|(_, token)| MaybeToken::from(*token))
+}
+
+/// The exchange coin symbol for `token`, the reverse of `exchange_symbol_token`
+pub fn token_exchange_symbol(exchange: Exchange, token: MaybeToken) -> Option<&'static str> {
+    let token_symbols = match exchange {
+        Exchange::Binance | Exchange::BinanceUs => BINANCE_TOKEN_SYMBOLS,
+        Exchange::Coinbase => COINBASE_TOKEN_SYMBOLS,
+        Exchange::Ftx | Exchange::FtxUs => FTX_TOKEN_SYMBOLS,
+        Exchange::Kraken => KRAKEN_TOKEN_SYMBOLS,
+    };
+    token_symbols
+        .iter()
+        .find(|(_, symbol_token)| MaybeToken::from(*symbol_token) == token)
+        .map(|(symbol, _)| *symbol)
+}
+
+/// Exchange balances keyed by token, with balances of coins that aren't tokens `sys` knows left
+/// under their exchange symbol
+#[derive(Debug, Default)]
+pub struct TokenBalances {
+    pub tokens: HashMap<MaybeToken, ExchangeBalance>,
+    pub unmapped: HashMap<String, ExchangeBalance>,
+}
+
+impl TokenBalances {
+    pub fn new(exchange: Exchange, balances: HashMap<String, ExchangeBalance>) -> Self {
+        let mut token_balances = Self::default();
+        for (symbol, balance) in balances {
+            match exchange_symbol_token(exchange, &symbol) {
+                Some(token) => {
+                    let token_balance = token_balances.tokens.entry(token).or_default();
+                    token_balance.available += balance.available;
+                    token_balance.total += balance.total;
+                }
+                None => {
+                    token_balances.unmapped.insert(symbol, balance);
+                }
+            }
+        }
+        token_balances
+    }
+
+    pub fn get(&self, token: MaybeToken) -> ExchangeBalance {
+        self.tokens.get(&token).cloned().unwrap_or_default()
+    }
+}
+
+pub async fn token_balances(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+) -> Result<TokenBalances, Box<dyn std::error::Error>> {
+    Ok(TokenBalances::new(
+        exchange,
+        exchange_client.balances().await?,
+    ))
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -57,20 +906,24 @@ pub struct ExchangeCredentials {
     pub subaccount: Option<String>,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ExchangeBalance {
     pub available: f64,
     pub total: f64,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct DepositInfo {
     pub tx_id: String,
     pub amount: f64, // TODO: rename to `ui_amount`
+    /// `None` for coins other than SOL and the known SPL tokens
+    pub token: Option<MaybeToken>,
+    pub time: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct WithdrawalInfo {
+    #[serde(with = "field_as_string")]
     pub address: Pubkey,
     pub token: MaybeToken,
     pub amount: f64, // TODO: rename to `ui_amount`
@@ -78,137 +931,3339 @@ pub struct WithdrawalInfo {
 
     pub completed: bool, // when `completed`, a `tx_id` of `None` indicates a cancelled withdrawal
     pub tx_id: Option<String>,
+
+    // When the withdrawal was requested, if reported by the exchange
+    #[serde(default)]
+    pub time: Option<DateTime<Utc>>,
+
+    #[serde(default)]
+    pub fee: Option<f64>, // TODO: rename to `ui_fee`
+
+    // The client-supplied reference the withdrawal was requested with, where supported
+    #[serde(default)]
+    pub client_reference: Option<String>,
 }
 
-#[derive(Debug)]
-pub struct BidAsk {
-    pub bid_price: f64,
-    pub ask_price: f64,
+/// Number of days covered by `ExchangeClient::recent_withdrawals()`
+pub const RECENT_WITHDRAWAL_DAYS: i64 = 90;
+
+/// Splits the time range from `start` up to `end` into consecutive windows of at most `days` days,
+/// each ending where the next begins
+pub(crate) fn date_range_windows(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    days: i64,
+) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let mut windows = vec![];
+    let mut window_start = start;
+    while window_start < end {
+        let window_end = (window_start + chrono::Duration::days(days)).min(end);
+        windows.push((window_start, window_end));
+        window_start = window_end;
+    }
+    windows
 }
 
-pub type OrderId = String;
+/// Orders withdrawals by request time and drops any seen twice across query windows
+pub(crate) fn sort_and_dedup_withdrawals(withdrawals: &mut Vec<WithdrawalInfo>) {
+    withdrawals.sort_by(|a, b| a.time.cmp(&b.time).then_with(|| a.tag.cmp(&b.tag)));
+    withdrawals.dedup_by(|a, b| a.tag == b.tag);
+}
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
-pub enum OrderSide {
-    Buy,
-    Sell,
+/// Whether an exchange lists a token, and currently moves it over the Solana network
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TokenSupport {
+    pub listed: bool,
+    pub deposits_enabled: bool,
+    pub withdrawals_enabled: bool,
 }
 
-impl std::fmt::Display for OrderSide {
+impl TokenSupport {
+    pub fn check_deposits(
+        &self,
+        exchange: Exchange,
+        token: MaybeToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.listed {
+            return Err(format!("{exchange:?} does not list {token}").into());
+        }
+        if !self.deposits_enabled {
+            return Err(format!(
+                "{exchange:?} does not currently support Solana network deposits of {token}"
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn check_withdrawals(
+        &self,
+        exchange: Exchange,
+        token: MaybeToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.listed {
+            return Err(format!("{exchange:?} does not list {token}").into());
+        }
+        if !self.withdrawals_enabled {
+            return Err(format!(
+                "{exchange:?} does not currently support Solana network withdrawals of {token}"
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// A bank account, or other fiat destination, registered with the exchange for withdrawals
+#[derive(Debug, Clone, Serialize)]
+pub struct FiatWithdrawalMethod {
+    /// Passed to `ExchangeClient::request_fiat_withdraw()`.  The withdrawal key name on Kraken,
+    /// and the payment method id on Coinbase
+    pub id: String,
+    pub name: String,
+    pub currency: String,
+}
+
+/// Fiat withdrawals settle over days rather than minutes, and can be returned by the bank well
+/// after the exchange has sent them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FiatWithdrawalState {
+    /// Accepted by the exchange but not yet sent
+    Pending,
+    /// Sent to the bank, awaiting settlement
+    Processing,
+    Completed,
+    Cancelled,
+    /// Failed or returned by the bank
+    Failed,
+}
+
+impl FiatWithdrawalState {
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self,
+            FiatWithdrawalState::Completed
+                | FiatWithdrawalState::Cancelled
+                | FiatWithdrawalState::Failed
+        )
+    }
+}
+
+impl std::fmt::Display for FiatWithdrawalState {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        let state = match self {
+            FiatWithdrawalState::Pending => "pending",
+            FiatWithdrawalState::Processing => "processing",
+            FiatWithdrawalState::Completed => "completed",
+            FiatWithdrawalState::Cancelled => "cancelled",
+            FiatWithdrawalState::Failed => "failed",
+        };
+        write!(f, "{state}")
     }
 }
 
-#[derive(Debug)]
-pub struct OrderStatus {
-    pub open: bool,
-    pub side: OrderSide,
-    pub price: f64,
+#[derive(Debug, Clone, Serialize)]
+pub struct FiatWithdrawalStatus {
+    pub state: FiatWithdrawalState,
     pub amount: f64,
-    pub filled_amount: f64,
-    pub last_update: NaiveDate,
-    pub fee: Option<(f64, String)>,
+    pub fee: Option<f64>,
 }
 
-#[derive(PartialEq, Eq)]
-pub enum MarketInfoFormat {
-    All,
-    Ask,
-    Weighted24hAveragePrice,
-    Hourly,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WithdrawalLimits {
+    pub minimum: f64, // ui amount
+    pub fee: f64,     // ui amount
 }
 
-pub struct LendingInfo {
-    pub lendable: f64,
-    pub offered: f64,
-    pub locked: f64,
-    pub estimate_rate: f64, // estimated lending rate for the next spot margin cycle
-    pub previous_rate: f64, // lending rate in the previous spot margin cycle
+// Approximate time to produce a Solana slot, and the slots until a block is finalized
+const SOLANA_SLOT_MS: i64 = 400;
+const SOLANA_FINALITY_SLOTS: i64 = 32;
+
+/// Time allowed for an exchange to credit a deposit once it has enough confirmations, when the
+/// exchange doesn't publish its own processing time
+const DEFAULT_DEPOSIT_PROCESSING_MINUTES: i64 = 5;
+
+/// When an exchange considers a deposit final enough to credit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum DepositFinality {
+    Confirmations(u32),
+    Finalized,
+    /// The exchange doesn't publish its requirement
+    Unpublished,
 }
 
-pub enum LendingHistory {
-    Range {
-        start_date: NaiveDate,
-        end_date: NaiveDate,
-    },
-    Previous {
-        days: usize,
-    },
+impl std::fmt::Display for DepositFinality {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DepositFinality::Confirmations(confirmations) => {
+                write!(f, "{confirmations} confirmations")
+            }
+            DepositFinality::Finalized => write!(f, "finalized"),
+            DepositFinality::Unpublished => write!(f, "unpublished"),
+        }
+    }
 }
 
-#[async_trait]
-pub trait ExchangeClient {
-    async fn deposit_address(
-        &self,
-        token: MaybeToken,
-    ) -> Result<Pubkey, Box<dyn std::error::Error>>;
-    async fn recent_deposits(&self)
-        -> Result<Option<Vec<DepositInfo>>, Box<dyn std::error::Error>>;
-    async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>>;
-    async fn request_withdraw(
-        &self,
-        address: Pubkey,
-        token: MaybeToken,
-        amount: f64,
+/// What an exchange requires before it credits a deposit of a token
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DepositRequirements {
+    pub finality: DepositFinality,
+    /// Smallest deposit credited, as a ui amount, when the exchange publishes one
+    pub minimum: Option<f64>,
+    pub deposits_enabled: bool,
+    /// How long the exchange takes to credit a deposit once it's final, when it publishes one
+    pub processing_time_secs: Option<u64>,
+}
+
+impl DepositRequirements {
+    /// How long after a deposit is sent the exchange should have credited it, or `None` if the
+    /// exchange publishes neither its finality requirement nor its processing time
+    pub fn expected_credit_time(&self) -> Option<chrono::Duration> {
+        let finality_time = match self.finality {
+            DepositFinality::Confirmations(confirmations) => {
+                chrono::Duration::milliseconds(i64::from(confirmations) * SOLANA_SLOT_MS)
+            }
+            DepositFinality::Finalized => {
+                chrono::Duration::milliseconds(SOLANA_FINALITY_SLOTS * SOLANA_SLOT_MS)
+            }
+            DepositFinality::Unpublished => return self.processing_time(),
+        };
+        let processing_time = self
+            .processing_time()
+            .unwrap_or_else(|| chrono::Duration::minutes(DEFAULT_DEPOSIT_PROCESSING_MINUTES));
+        Some(finality_time + processing_time)
+    }
+
+    fn processing_time(&self) -> Option<chrono::Duration> {
+        self.processing_time_secs
+            .map(|secs| chrono::Duration::seconds(secs as i64))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BidAsk {
+    pub bid_price: f64,
+    pub ask_price: f64,
+    // Top of book depth, when reported by the exchange
+    pub bid_amount: Option<f64>,
+    pub ask_amount: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct OrderBookLevel {
+    pub price: f64,
+    pub amount: f64,
+}
+
+/// Order book snapshot with the best price first on each side
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderBook {
+    pub bids: Vec<OrderBookLevel>,
+    pub asks: Vec<OrderBookLevel>,
+    // Snapshot time reported by the exchange, otherwise when the request was sent
+    pub time: DateTime<Utc>,
+}
+
+/// Parses the `(price, amount)` strings of an exchange order book response
+pub(crate) fn parse_order_book_levels<'a>(
+    levels: impl IntoIterator<Item = (&'a str, &'a str)>,
+    depth: usize,
+) -> Result<Vec<OrderBookLevel>, Box<dyn std::error::Error>> {
+    levels
+        .into_iter()
+        .take(depth)
+        .map(|(price, amount)| {
+            Ok(OrderBookLevel {
+                price: price.parse()?,
+                amount: amount.parse()?,
+            })
+        })
+        .collect()
+}
+
+/// A firm price for `amount` of `pair`, valid until `expires_at` by the exchange's clock
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quote {
+    pub id: String,
+    pub pair: TradingPair,
+    pub side: OrderSide,
+    pub amount: f64,
+    pub price: f64,
+    pub expires_at: DateTime<Utc>,
+}
+
+// Quotes this close to expiry are not submitted, as the request could arrive too late
+const QUOTE_EXPIRY_MARGIN_MS: i64 = 1_000;
+
+/// Executes `quote` unless it will have expired, by the exchange's clock, by the time the
+/// execution request arrives
+pub async fn execute_unexpired_quote(
+    exchange_client: &dyn ExchangeClient,
+    quote: &Quote,
+) -> Result<OrderId, Box<dyn std::error::Error>> {
+    let request_time = Utc::now();
+    let (clock_offset, one_way_latency) = match exchange_client.server_time().await {
+        Ok(server_time) => {
+            // Assume the server time was read half way through the round trip
+            let one_way_latency = (Utc::now() - request_time) / 2;
+            (
+                server_time - (request_time + one_way_latency),
+                one_way_latency,
+            )
+        }
+        Err(err) => {
+            eprintln!("Warning: checking quote expiry with the local clock: {err}");
+            (chrono::Duration::zero(), chrono::Duration::zero())
+        }
+    };
+
+    let arrival_time = Utc::now()
+        + clock_offset
+        + one_way_latency
+        + chrono::Duration::milliseconds(QUOTE_EXPIRY_MARGIN_MS);
+    if arrival_time >= quote.expires_at {
+        return Err(format!("Quote {} expired at {}", quote.id, quote.expires_at).into());
+    }
+    exchange_client.execute_quote(&quote.id).await
+}
+
+pub(crate) const DEFAULT_MAX_SPREAD_PERCENT: f64 = 0.5;
+
+/// Widest bid/ask spread, as a fraction of the mid price, that orders are placed into.
+///
+/// Override with the `max-spread-percent` setting
+pub fn max_spread() -> f64 {
+    settings::get(Setting::MaxSpreadPercent)
+        .and_then(|percent| settings::parse_percent(&percent).ok())
+        .unwrap_or(DEFAULT_MAX_SPREAD_PERCENT)
+        / 100.
+}
+
+impl BidAsk {
+    /// `(ask - bid) / mid`
+    pub fn spread(&self) -> f64 {
+        let mid_price = (self.ask_price + self.bid_price) / 2.;
+        (self.ask_price - self.bid_price) / mid_price
+    }
+
+    /// Guards against placing orders into a momentarily thin or dislocated book
+    pub fn check_spread(&self, pair: &TradingPair) -> Result<(), ExchangeError> {
+        let spread = self.spread();
+        let max_spread = max_spread();
+        if spread > max_spread {
+            return Err(ExchangeError::SpreadTooWide {
+                pair: pair.clone(),
+                spread,
+                max_spread,
+            });
+        }
+        Ok(())
+    }
+}
+
+pub type OrderId = String;
+
+/// A fresh id to tag an order placement or withdrawal request with.  Kept to 18 characters, the
+/// longest free-form client id accepted by all supported exchanges
+pub fn new_client_id() -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    format!(
+        "sys{millis:011x}{:04x}",
+        SEQUENCE.fetch_add(1, Ordering::Relaxed) & 0xffff
+    )
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderSide {
+    #[serde(alias = "Buy")]
+    Buy,
+    #[serde(alias = "Sell")]
+    Sell,
+}
+
+impl std::fmt::Display for OrderSide {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+/// Why an order closed before it was fully filled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OrderCancellation {
+    /// Cancelled through the API or the exchange's website
+    User,
+    /// Cancelled by the exchange itself, for example by self-trade prevention, a delisting or
+    /// cancel-only mode
+    Exchange { reason: String },
+}
+
+impl std::fmt::Display for OrderCancellation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::User => write!(f, "cancelled"),
+            Self::Exchange { reason } => write!(f, "cancelled by the exchange: {reason}"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OrderStatus {
+    pub open: bool,
+    pub side: OrderSide,
+    pub price: f64,
+    pub amount: f64,
+    pub filled_amount: f64,
+    /// Volume-weighted average price of the fills, `None` until something fills
+    #[serde(default)]
+    pub average_fill_price: Option<f64>,
+    /// Quote currency paid or received for the fills, before fees
+    #[serde(default)]
+    pub cumulative_quote_amount: Option<f64>,
+    pub last_update: NaiveDate,
+    pub fee: Option<(f64, String)>,
+    /// How a closed order was cancelled, when the exchange reports it
+    #[serde(default)]
+    pub cancellation: Option<OrderCancellation>,
+}
+
+impl OrderStatus {
+    /// The price actually paid or received, or the limit price if nothing has filled
+    pub fn fill_price(&self) -> f64 {
+        self.average_fill_price.unwrap_or(self.price)
+    }
+
+    /// The exchange's reason for cancelling the order, if the exchange rather than the user did
+    pub fn exchange_cancel_reason(&self) -> Option<&str> {
+        match &self.cancellation {
+            Some(OrderCancellation::Exchange { reason }) => Some(reason),
+            _ => None,
+        }
+    }
+}
+
+/// An order as reported by the exchange's order history, whether or not `sys` placed it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistoricalOrder {
+    pub order_id: OrderId,
+    pub creation_time: DateTime<Utc>,
+    pub client_order_id: Option<String>,
+    pub status: OrderStatus,
+}
+
+/// An order resting on the exchange, whether or not `sys` placed it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExchangeOpenOrder {
+    pub pair: TradingPair,
+    pub order_id: OrderId,
+    pub side: OrderSide,
+    pub price: f64,
+    pub amount: f64,
+}
+
+/// Average fill price and cumulative quote amount of `filled_amount` filled for
+/// `cumulative_quote_amount`, both `None` if nothing filled
+pub fn fill_totals(filled_amount: f64, cumulative_quote_amount: f64) -> (Option<f64>, Option<f64>) {
+    if filled_amount > 0. {
+        (
+            Some(cumulative_quote_amount / filled_amount),
+            Some(cumulative_quote_amount),
+        )
+    } else {
+        (None, None)
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub enum MarketInfoFormat {
+    All,
+    Ask,
+    Weighted24hAveragePrice,
+    Candles(CandleRange),
+    Json,
+}
+
+/// The most recent `count` candles of `interval_minutes` each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandleRange {
+    pub interval_minutes: u32,
+    pub count: usize,
+}
+
+impl Default for CandleRange {
+    fn default() -> Self {
+        Self {
+            interval_minutes: 60,
+            count: 24,
+        }
+    }
+}
+
+impl CandleRange {
+    pub fn validate(
+        &self,
+        exchange: Exchange,
+        supported_intervals: &[u32],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !supported_intervals.contains(&self.interval_minutes) {
+            return Err(format!(
+                "{exchange:?} does not support {} minute candles. Supported intervals: {}",
+                self.interval_minutes,
+                supported_intervals
+                    .iter()
+                    .map(|minutes| minutes.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .into());
+        }
+        if self.count == 0 {
+            return Err("Candle count must be at least 1".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Candle {
+    pub time: DateTime<Utc>,
+    pub high: f64,
+    pub low: f64,
+    pub volume: f64,
+}
+
+/// Prints `candles`, which are in chronological order, newest first
+pub fn print_candles(
+    range: CandleRange,
+    candles: &[Candle],
+) -> Result<(), Box<dyn std::error::Error>> {
+    if candles.len() != range.count {
+        return Err(format!(
+            "Failed to fetch {} candles (fetched {})",
+            range.count,
+            candles.len()
+        )
+        .into());
+    }
+
+    println!(
+        "{} minute candles, times in {}",
+        range.interval_minutes,
+        chrono::Local::now().offset()
+    );
+    println!("time,low,high,average,volume");
+    for candle in candles.iter().rev() {
+        println!(
+            "{},{},{},{},{}",
+            DateTime::<chrono::Local>::from(candle.time),
+            candle.low,
+            candle.high,
+            (candle.low + candle.high) / 2.,
+            candle.volume
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TickerStats {
+    pub pair: String,
+    pub last_price: f64,
+    pub bid_price: f64,
+    pub ask_price: f64,
+    pub open_24h: f64,
+    pub high_24h: f64,
+    pub low_24h: f64,
+    pub base_volume_24h: f64,
+    pub quote_volume_24h: f64,
+    pub price_change_percent_24h: f64,
+    pub weighted_average_price_24h: Option<f64>,
+}
+
+/// Prints `ticker_stats` in any format other than `MarketInfoFormat::Candles`.  `pair_info`
+/// sets the precision of the human-readable format, which also values the quote currency with
+/// `quote_usd_rate` when it isn't USD
+pub fn print_ticker_stats(
+    ticker_stats: &TickerStats,
+    pair_info: Option<&PairInfo>,
+    quote_usd_rate: Option<QuoteUsdRate>,
+    format: MarketInfoFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match format {
+        MarketInfoFormat::All => {
+            let tick_size = pair_info.and_then(|pair_info| pair_info.tick_size);
+            let step_size = pair_info.and_then(|pair_info| pair_info.step_size);
+            let mut symbols = ticker_stats.pair.split('/');
+            let base = symbols.next().unwrap_or_default();
+            let quote = symbols.next().unwrap_or("USD");
+            let price =
+                |price| format_quote_amount(&format_increment(price, tick_size, true), quote);
+
+            println!("Pair: {}", ticker_stats.pair);
+            if quote != "USD" {
+                println!("Quote: {}", quote_label(quote, quote_usd_rate));
+            }
+            println!(
+                "Last: {}, Ask: {}, Bid: {}",
+                price(ticker_stats.last_price),
+                price(ticker_stats.ask_price),
+                price(ticker_stats.bid_price)
+            );
+            println!(
+                "Last 24h: open {}, high {}, low {}, change {:.2}%",
+                price(ticker_stats.open_24h),
+                price(ticker_stats.high_24h),
+                price(ticker_stats.low_24h),
+                ticker_stats.price_change_percent_24h
+            );
+            println!(
+                "Last 24h volume: {} {} ({}{})",
+                format_increment(ticker_stats.base_volume_24h, step_size, true),
+                base,
+                format_quote_amount(
+                    &format_decimals(ticker_stats.quote_volume_24h, 2, true),
+                    quote
+                ),
+                match quote_usd_rate {
+                    Some(QuoteUsdRate { rate, .. }) if quote != "USD" => format!(
+                        ", ≈${}",
+                        format_decimals(ticker_stats.quote_volume_24h * rate, 2, true)
+                    ),
+                    _ => String::new(),
+                }
+            );
+            if let Some(weighted_average_price) = ticker_stats.weighted_average_price_24h {
+                println!(
+                    "Weighted 24h average price: {}",
+                    price(weighted_average_price)
+                );
+            }
+        }
+        MarketInfoFormat::Ask => {
+            println!("{}", ticker_stats.ask_price);
+        }
+        MarketInfoFormat::Weighted24hAveragePrice => {
+            let weighted_average_price = ticker_stats
+                .weighted_average_price_24h
+                .ok_or("Weighted 24h average price not available")?;
+            println!("{weighted_average_price}");
+        }
+        MarketInfoFormat::Candles(_) => {
+            return Err("Candles are not part of the ticker stats".into());
+        }
+        MarketInfoFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(ticker_stats)?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LendingInfo {
+    pub lendable: f64,
+    pub offered: f64,
+    pub locked: f64,
+    pub estimate_rate: f64, // estimated lending rate for the next spot margin cycle
+    pub previous_rate: f64, // lending rate in the previous spot margin cycle
+}
+
+/// Lending redeemed to free up funds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LendingRedemption {
+    pub redeemed: f64,
+    /// Interest given up by ending the loan early, when the exchange reports it
+    pub forfeited_interest: Option<f64>,
+}
+
+pub enum LendingHistory {
+    /// From `start` up to but excluding `end`
+    Range {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+    /// The `days` days up to now
+    Previous { days: usize },
+}
+
+impl LendingHistory {
+    /// The days from `start_date` to `end_date` inclusive, in `time_zone`
+    pub fn days(start_date: NaiveDate, end_date: NaiveDate, time_zone: ReportingTimeZone) -> Self {
+        let (start, end) = time_zone.days(start_date, end_date);
+        Self::Range { start, end }
+    }
+
+    /// Start and end of the history, the end excluded
+    pub fn bounds(&self) -> (DateTime<Utc>, DateTime<Utc>) {
+        match self {
+            Self::Range { start, end } => (*start, *end),
+            Self::Previous { days } => {
+                let end = Utc::now();
+                (end - chrono::Duration::days(*days as i64), end)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StakingOperation {
+    Allocate,
+    Deallocate,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StakingInfo {
+    pub coin: String,
+    pub strategy_id: String,
+    pub lock_type: String,
+    pub apr_low: Option<f64>,
+    pub apr_high: Option<f64>,
+    pub allocated: f64,
+    pub rewarded: f64,
+    pub pending_allocation: bool,
+    pub pending_deallocation: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StakingReward {
+    pub time: DateTime<Utc>,
+    pub coin: String,
+    pub amount: f64,
+}
+
+/// A subaccount, or its exchange-specific equivalent such as a Coinbase portfolio
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subaccount {
+    pub name: String,
+    pub id: String,
+    pub default: bool,
+    /// Whether the exchange credentials are scoped to this subaccount
+    pub selected: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeeSchedule {
+    pub maker_rate: f64,
+    pub taker_rate: f64,
+    /// Discount reflected in the rates, if any
+    pub discount: Option<String>,
+}
+
+/// A 30-day trading volume fee tier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeTier {
+    /// 30-day volume, in USD, at which the tier starts
+    pub volume_threshold: f64,
+    pub maker_rate: f64,
+    pub taker_rate: f64,
+}
+
+/// Progress through an exchange's 30-day volume fee tiers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VolumeTierInfo {
+    /// Rolling 30-day trading volume, in USD
+    pub volume_30d: f64,
+    pub maker_rate: f64,
+    pub taker_rate: f64,
+    /// `None` at the top tier, or if the exchange doesn't publish its tiers
+    pub next_tier: Option<VolumeTier>,
+}
+
+impl VolumeTierInfo {
+    /// Volume, in USD, still to trade to reach the next tier
+    pub fn volume_to_next_tier(&self) -> Option<f64> {
+        self.next_tier
+            .as_ref()
+            .map(|next_tier| (next_tier.volume_threshold - self.volume_30d).max(0.))
+    }
+}
+
+/// What an API key is allowed to do, as reported by the exchange
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApiPermissions {
+    pub trade: bool,
+    pub withdraw: bool,
+    /// Whether the key only works from whitelisted IP addresses, if reported
+    pub ip_restricted: Option<bool>,
+}
+
+/// Whether an exchange, or one of its markets, is accepting requests.  Ordered from least to
+/// most restrictive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum SystemStatus {
+    Online,
+    /// Only orders that rest on the book are accepted
+    PostOnly,
+    /// New orders are rejected, but existing orders can be cancelled
+    CancelOnly,
+    Maintenance,
+}
+
+impl std::fmt::Display for SystemStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SystemStatus::Online => write!(f, "online"),
+            SystemStatus::PostOnly => write!(f, "in post-only mode"),
+            SystemStatus::CancelOnly => write!(f, "in cancel-only mode"),
+            SystemStatus::Maintenance => write!(f, "down for maintenance"),
+        }
+    }
+}
+
+/// Requests that change account state, and so must wait for the exchange to allow them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemOperation {
+    PlaceOrder { taker: bool },
+    Withdraw,
+}
+
+impl SystemStatus {
+    pub fn allows(&self, operation: SystemOperation) -> bool {
+        match (self, operation) {
+            (SystemStatus::Online, _) => true,
+            (SystemStatus::PostOnly, SystemOperation::PlaceOrder { taker }) => !taker,
+            (SystemStatus::PostOnly | SystemStatus::CancelOnly, SystemOperation::Withdraw) => true,
+            _ => false,
+        }
+    }
+}
+
+/// USD value of a fee at the time of the trade it was charged on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UsdFee {
+    pub amount: f64,
+    /// The value comes from a daily close rather than a minute-level price
+    pub daily_close: bool,
+}
+
+impl std::fmt::Display for UsdFee {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "${}", format_decimals(self.amount, 2, true))?;
+        if self.daily_close {
+            write!(f, " (daily close)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a `QuoteUsdRate` came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuoteUsdRateSource {
+    Usd,
+    /// `fx_rate_override()`
+    FixedRate,
+    /// The exchange's QUOTE/USD or USD/QUOTE market, or for a crypto quote such as BTC its market
+    /// against a dollar-denominated coin
+    Market,
+    CoinGecko,
+    /// Parity, for a dollar-denominated coin with no other price
+    Assumed,
+}
+
+/// USD value of one unit of a quote currency
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct QuoteUsdRate {
+    pub rate: f64,
+    /// No market or external price was available, so a dollar-denominated coin is assumed to be
+    /// at parity
+    pub assumed: bool,
+    pub source: QuoteUsdRateSource,
+}
+
+impl QuoteUsdRate {
+    pub const USD: Self = Self {
+        rate: 1.,
+        assumed: false,
+        source: QuoteUsdRateSource::Usd,
+    };
+
+    pub fn fixed(rate: f64) -> Self {
+        Self {
+            rate,
+            assumed: false,
+            source: QuoteUsdRateSource::FixedRate,
+        }
+    }
+}
+
+/// The best bid and ask of every market on one exchange, as of `time`
+#[derive(Debug)]
+pub struct TickerSnapshot {
+    pub time: DateTime<Utc>,
+    pub bid_asks: HashMap<TradingPair, BidAsk>,
+}
+
+impl TickerSnapshot {
+    pub async fn fetch(
+        exchange_client: &dyn ExchangeClient,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let time = Utc::now();
+        Ok(Self {
+            time,
+            bid_asks: exchange_client.bid_asks().await?,
+        })
+    }
+}
+
+/// `pair`'s best bid and ask from `snapshot`, or from the exchange when the snapshot doesn't have
+/// the market
+pub async fn snapshot_bid_ask(
+    exchange_client: &dyn ExchangeClient,
+    snapshot: Option<&TickerSnapshot>,
+    pair: &TradingPair,
+) -> Result<BidAsk, Box<dyn std::error::Error>> {
+    match snapshot.and_then(|snapshot| snapshot.bid_asks.get(pair)) {
+        Some(bid_ask) => Ok(bid_ask.clone()),
+        None => {
+            if snapshot.is_some() {
+                log::debug!("{pair} missing from the ticker snapshot, requesting it alone");
+            }
+            exchange_client.bid_ask(pair).await
+        }
+    }
+}
+
+/// Values `quote` in USD from `fx_rate_override()` or the exchange's QUOTE/USD or USD/QUOTE
+/// market, or for a crypto quote its market against the first coin in `usd_preference_order()`
+/// the exchange lists.  Falls back to Coin Gecko and finally to parity for dollar-denominated coins
+pub async fn quote_usd_rate(
+    exchange_client: &dyn ExchangeClient,
+    quote: &str,
+) -> Result<QuoteUsdRate, Box<dyn std::error::Error>> {
+    snapshot_quote_usd_rate(exchange_client, None, quote).await
+}
+
+/// Like `quote_usd_rate()`, taking the exchange's markets from `snapshot` where it has them
+pub async fn snapshot_quote_usd_rate(
+    exchange_client: &dyn ExchangeClient,
+    snapshot: Option<&TickerSnapshot>,
+    quote: &str,
+) -> Result<QuoteUsdRate, Box<dyn std::error::Error>> {
+    if quote == "USD" {
+        return Ok(QuoteUsdRate::USD);
+    }
+    if let Some(rate) = fx_rate_override(quote) {
+        return Ok(QuoteUsdRate::fixed(rate));
+    }
+
+    let trading_pairs = exchange_client.trading_pairs().await?;
+    let quote_usd_pair = TradingPair::new(quote, "USD");
+    if trading_pairs.contains(&quote_usd_pair) {
+        let bid_ask = snapshot_bid_ask(exchange_client, snapshot, &quote_usd_pair).await?;
+        return Ok(QuoteUsdRate {
+            rate: (bid_ask.bid_price + bid_ask.ask_price) / 2.,
+            assumed: false,
+            source: QuoteUsdRateSource::Market,
+        });
+    }
+    let usd_quote_pair = TradingPair::new("USD", quote);
+    if trading_pairs.contains(&usd_quote_pair) {
+        let bid_ask = snapshot_bid_ask(exchange_client, snapshot, &usd_quote_pair).await?;
+        return Ok(QuoteUsdRate {
+            rate: 2. / (bid_ask.bid_price + bid_ask.ask_price),
+            assumed: false,
+            source: QuoteUsdRateSource::Market,
+        });
+    }
+    if !is_fiat(quote) && !is_usd_equivalent(quote) {
+        if let Some(usd_coin_pair) = usd_preference_order()
+            .iter()
+            .map(|usd_coin| TradingPair::new(quote, usd_coin))
+            .find(|pair| trading_pairs.contains(pair))
+        {
+            let bid_ask = snapshot_bid_ask(exchange_client, snapshot, &usd_coin_pair).await?;
+            // The dollar-denominated coin itself is valued without another market lookup
+            let usd_coin_rate = if usd_coin_pair.quote == "USD" {
+                Some(1.)
+            } else {
+                coin_gecko::get_current_stablecoin_price(&usd_coin_pair.quote)
+                    .await
+                    .ok()
+            };
+            return Ok(QuoteUsdRate {
+                rate: (bid_ask.bid_price + bid_ask.ask_price) / 2. * usd_coin_rate.unwrap_or(1.),
+                assumed: usd_coin_rate.is_none(),
+                source: QuoteUsdRateSource::Market,
+            });
+        }
+    }
+
+    let rate = if is_fiat(quote) {
+        coin_gecko::get_current_fiat_usd_rate(quote).await
+    } else {
+        coin_gecko::get_current_stablecoin_price(quote).await
+    };
+    if let Ok(rate) = rate {
+        return Ok(QuoteUsdRate {
+            rate,
+            assumed: false,
+            source: QuoteUsdRateSource::CoinGecko,
+        });
+    }
+
+    if is_usd_equivalent(quote) {
+        Ok(QuoteUsdRate {
+            rate: 1.,
+            assumed: true,
+            source: QuoteUsdRateSource::Assumed,
+        })
+    } else {
+        Err(format!("No market available to value {quote} in USD").into())
+    }
+}
+
+/// Formats an amount of `quote`, as "$142.35" for USD, "€142.35" for EUR and "142.35 USDT"
+/// otherwise
+pub fn format_quote_amount(amount: &str, quote: &str) -> String {
+    match quote {
+        "USD" => format!("${amount}"),
+        "EUR" => format!("€{amount}"),
+        "GBP" => format!("£{amount}"),
+        _ => format!("{amount} {quote}"),
+    }
+}
+
+/// Names `quote` along with its USD value, such as "USDT, ≈$0.9992"
+pub fn quote_label(quote: &str, quote_usd_rate: Option<QuoteUsdRate>) -> String {
+    if quote == "USD" {
+        return quote.to_string();
+    }
+    match quote_usd_rate {
+        Some(QuoteUsdRate { assumed: true, .. }) => format!("{quote}, assumed ≈$1"),
+        Some(QuoteUsdRate { rate, .. }) => format!("{quote}, ≈${rate:.4}"),
+        None => format!("{quote}, no USD rate available"),
+    }
+}
+
+// Minute candles are only requested for trades this recent
+const MINUTE_PRICE_WINDOW_MINUTES: usize = 60;
+
+/// USD price of `coin` at `when`, and whether it fell back to the daily close.  A minute-level
+/// price is only available for the last `MINUTE_PRICE_WINDOW_MINUTES` minutes
+pub async fn usd_price_at(
+    exchange_client: &dyn ExchangeClient,
+    coin: &str,
+    when: DateTime<Utc>,
+) -> Result<(f64, bool), Box<dyn std::error::Error>> {
+    if is_usd_equivalent(coin) {
+        return Ok((1., false));
+    }
+
+    let trading_pairs = exchange_client.trading_pairs().await?;
+    let pair = usd_preference_order()
+        .iter()
+        .map(|quote| TradingPair::new(coin, quote))
+        .find(|pair| trading_pairs.contains(pair))
+        .ok_or_else(|| format!("No USD-quoted market available for {coin}"))?;
+
+    if Utc::now().signed_duration_since(when)
+        <= chrono::Duration::minutes(MINUTE_PRICE_WINDOW_MINUTES as i64)
+    {
+        let range = CandleRange {
+            interval_minutes: 1,
+            count: MINUTE_PRICE_WINDOW_MINUTES,
+        };
+        if let Ok(candles) = exchange_client.candles(&pair, range).await {
+            if let Some(candle) = candles.iter().rev().find(|candle| candle.time <= when) {
+                return Ok(((candle.high + candle.low) / 2., false));
+            }
+        }
+    }
+
+    let price = exchange_client
+        .price_on(&pair, when.date().naive_local())
+        .await?;
+    Ok((price, true))
+}
+
+/// Converts `fee`, charged on a fill of `pair` at `price`, to USD.  A fee in the base or quote
+/// currency is valued from the fill price itself, anything else from the market at `when`
+pub async fn fee_in_usd(
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    price: f64,
+    fee: &(f64, String),
+    when: DateTime<Utc>,
+) -> Result<UsdFee, Box<dyn std::error::Error>> {
+    let (amount, coin) = fee;
+    let coin = coin.to_uppercase();
+
+    let quote_amount = if coin == pair.quote {
+        Some(*amount)
+    } else if coin == pair.base {
+        Some(amount * price)
+    } else {
+        None
+    };
+
+    let (amount, (usd_price, daily_close)) = match quote_amount {
+        Some(quote_amount) => (
+            quote_amount,
+            usd_price_at(exchange_client, &pair.quote, when).await?,
+        ),
+        None => (*amount, usd_price_at(exchange_client, &coin, when).await?),
+    };
+    Ok(UsdFee {
+        amount: amount * usd_price,
+        daily_close,
+    })
+}
+
+/// An entry in an exchange's account ledger
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub id: String,
+    pub reference: String,
+    pub time: DateTime<Utc>,
+    pub kind: String,
+    pub asset: String,
+    pub staked: bool,
+    /// Balance change before `fee` is deducted
+    pub amount: f64,
+    pub fee: f64,
+}
+
+/// A kind of account history, walked a date range at a time to backfill the exchange journal
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, EnumString, IntoStaticStr,
+)]
+pub enum HistoryCategory {
+    #[strum(serialize = "deposits")]
+    Deposits,
+    #[strum(serialize = "withdrawals")]
+    Withdrawals,
+    #[strum(serialize = "trades")]
+    Trades,
+    /// Instant conversions between assets outside of the order book
+    #[strum(serialize = "conversions")]
+    Conversions,
+    /// Lending interest and staking rewards
+    #[strum(serialize = "interest")]
+    Interest,
+}
+
+pub const HISTORY_CATEGORIES: &[HistoryCategory] = &[
+    HistoryCategory::Deposits,
+    HistoryCategory::Withdrawals,
+    HistoryCategory::Trades,
+    HistoryCategory::Conversions,
+    HistoryCategory::Interest,
+];
+
+impl std::fmt::Display for HistoryCategory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", <&str>::from(self))
+    }
+}
+
+/// A balance change in an exchange's account history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountHistoryEntry {
+    pub time: DateTime<Utc>,
+    pub coin: String,
+    /// Balance change before `fee` is deducted, positive when the balance grows
+    pub amount: f64,
+    pub fee: f64,
+    /// Deposit transaction, withdrawal, order or conversion the change belongs to
+    pub reference: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct PairInfo {
+    pub pair: TradingPair,
+    pub tick_size: Option<f64>,
+    pub step_size: Option<f64>,
+    pub min_notional: Option<f64>,
+    /// Whether the market was accepting orders when the pair metadata was fetched
+    pub status: SystemStatus,
+    /// When the exchange has announced the market will be delisted, where it publishes a schedule
+    pub delist_time: Option<DateTime<Utc>>,
+}
+
+fn round_to_increment(value: f64, increment: Option<f64>, round_up: bool) -> f64 {
+    let (value_decimal, increment) = match (
+        Decimal::from_f64(value),
+        increment.and_then(Decimal::from_f64),
+    ) {
+        (Some(value), Some(increment)) if !increment.is_zero() => (value, increment),
+        _ => return value,
+    };
+
+    let increments = value_decimal / increment;
+    let increments = if round_up {
+        increments.ceil()
+    } else {
+        increments.floor()
+    };
+    (increments * increment).to_f64().unwrap_or(value)
+}
+
+/// How an order amount is rounded to the step size of the pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, IntoStaticStr)]
+pub enum AmountRounding {
+    /// Never more than asked for, so a sell can't exceed the balance
+    #[strum(serialize = "down")]
+    Down,
+    /// Never less than asked for, so a buy acquires at least the amount
+    #[strum(serialize = "up")]
+    Up,
+    /// The amount must already be a multiple of the step size
+    #[strum(serialize = "exact")]
+    Exact,
+}
+
+pub const POSSIBLE_AMOUNT_ROUNDING_VALUES: &[&str] = &["down", "up", "exact"];
+
+impl Default for AmountRounding {
+    fn default() -> Self {
+        Self::Down
+    }
+}
+
+/// How an order price is rounded to the tick size of the pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, IntoStaticStr)]
+pub enum PriceRounding {
+    /// Away from the other side of the book, down for a buy and up for a sell, so the order can't
+    /// cross the book it would otherwise rest on
+    #[strum(serialize = "passive")]
+    Passive,
+    /// Towards the other side of the book, up for a buy and down for a sell
+    #[strum(serialize = "aggressive")]
+    Aggressive,
+}
+
+pub const POSSIBLE_PRICE_ROUNDING_VALUES: &[&str] = &["passive", "aggressive"];
+
+impl Default for PriceRounding {
+    fn default() -> Self {
+        Self::Passive
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct OrderRounding {
+    pub price: PriceRounding,
+    pub amount: AmountRounding,
+}
+
+/// Decimal places shown when neither pair metadata nor a token's decimals are known
+const DEFAULT_DISPLAY_DECIMALS: usize = 8;
+
+/// Number of decimal places in `increment`, such as 2 for a 0.01 tick size
+pub fn increment_decimals(increment: f64) -> usize {
+    Decimal::from_f64(increment)
+        .map(|increment| increment.normalize().scale() as usize)
+        .unwrap_or(DEFAULT_DISPLAY_DECIMALS)
+}
+
+/// Formats `value` with exactly `decimals` decimal places
+pub fn format_decimals(value: f64, decimals: usize, separators: bool) -> String {
+    if separators {
+        value.separated_string_with_fixed_place(decimals)
+    } else {
+        format!("{value:.decimals$}")
+    }
+}
+
+/// Formats `value` with the decimal places of `increment`.  Without a known increment up to
+/// `DEFAULT_DISPLAY_DECIMALS` places are shown, less any trailing zeros
+pub fn format_increment(value: f64, increment: Option<f64>, separators: bool) -> String {
+    match increment.filter(|increment| *increment > 0.) {
+        Some(increment) => format_decimals(value, increment_decimals(increment), separators),
+        None => {
+            let formatted = format_decimals(value, DEFAULT_DISPLAY_DECIMALS, separators);
+            formatted
+                .trim_end_matches('0')
+                .trim_end_matches('.')
+                .to_string()
+        }
+    }
+}
+
+/// Formats a `ui_amount` of `token` with the token's decimals
+pub fn format_token_amount(token: MaybeToken, ui_amount: f64, separators: bool) -> String {
+    format_decimals(ui_amount, token.decimals().into(), separators)
+}
+
+/// Converts an exchange-reported `ui_amount` of `token` to native units, rounding down.  The
+/// conversion is done in decimal, where multiplying the `f64` by a power of ten would not be exact
+pub fn native_amount_floor(token: MaybeToken, ui_amount: f64) -> u64 {
+    let scale = Decimal::from(10u64.pow(token.decimals().into()));
+    Decimal::from_f64(ui_amount)
+        .filter(|ui_amount| ui_amount.is_sign_positive())
+        .map_or(0, |ui_amount| {
+            ui_amount
+                .checked_mul(scale)
+                .and_then(|amount| amount.floor().to_u64())
+                .unwrap_or(u64::MAX)
+        })
+}
+
+/// Rounds a native `amount` of `token` down to what `decimals` decimal places can express
+pub fn truncate_native_amount(token: MaybeToken, amount: u64, decimals: u8) -> u64 {
+    let unit = 10u64.pow(u32::from(token.decimals().saturating_sub(decimals)));
+    amount - amount % unit
+}
+
+/// Formats a native `amount` of `token` with exactly `decimals` decimal places, dropping any finer
+/// digits.  The integer is never converted to an `f64`, so the result can't round past `amount`
+pub fn format_native_amount(token: MaybeToken, amount: u64, decimals: u8) -> String {
+    let token_decimals = u32::from(token.decimals());
+    let scale = 10u64.pow(token_decimals);
+    let whole = amount / scale;
+    if decimals == 0 {
+        return whole.to_string();
+    }
+    let fraction = format!(
+        "{:0width$}",
+        amount % scale,
+        width = token_decimals as usize
+    );
+    let fraction = format!("{fraction:0<width$}", width = usize::from(decimals));
+    format!("{whole}.{}", &fraction[..usize::from(decimals)])
+}
+
+/// Formats an `amount` of an exchange coin with the decimals of the token it denotes
+pub fn format_coin_amount(exchange: Exchange, coin: &str, amount: f64, separators: bool) -> String {
+    match exchange_symbol_token(exchange, coin) {
+        Some(token) => format_token_amount(token, amount, separators),
+        None => format_increment(amount, None, separators),
+    }
+}
+
+impl PairInfo {
+    /// Rounds `price` to the tick size and `amount` to the step size, in the directions given by
+    /// `rounding`.  Values already on an increment are left as they are
+    pub fn round_order(
+        &self,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        rounding: OrderRounding,
+    ) -> Result<(f64, f64), Box<dyn std::error::Error>> {
+        let round_price_up = match rounding.price {
+            PriceRounding::Passive => side == OrderSide::Sell,
+            PriceRounding::Aggressive => side == OrderSide::Buy,
+        };
+        let price = round_to_increment(price, self.tick_size, round_price_up);
+        let amount = match rounding.amount {
+            AmountRounding::Down => round_to_increment(amount, self.step_size, false),
+            AmountRounding::Up => round_to_increment(amount, self.step_size, true),
+            AmountRounding::Exact => {
+                if round_to_increment(amount, self.step_size, false) != amount {
+                    return Err(format!(
+                        "Amount {amount} is not a multiple of the {} step size for {}",
+                        self.format_amount(self.step_size.unwrap_or_default(), false),
+                        self.pair
+                    )
+                    .into());
+                }
+                amount
+            }
+        };
+
+        if let Some(min_notional) = self.min_notional {
+            if price * amount < min_notional {
+                let quote_amount = |amount| {
+                    format_quote_amount(&self.format_price(amount, false), &self.pair.quote)
+                };
+                return Err(format!(
+                    "Order notional {} is below the {} minimum for {}",
+                    quote_amount(price * amount),
+                    quote_amount(min_notional),
+                    self.pair
+                )
+                .into());
+            }
+        }
+        Ok((price, amount))
+    }
+
+    /// Formats `price` with the precision of the tick size
+    pub fn format_price(&self, price: f64, separators: bool) -> String {
+        format_increment(price, self.tick_size, separators)
+    }
+
+    /// Formats `amount` with the precision of the step size
+    pub fn format_amount(&self, amount: f64, separators: bool) -> String {
+        format_increment(amount, self.step_size, separators)
+    }
+
+    /// Fails with `ExchangeError::Unavailable` if the market didn't allow `operation` when the
+    /// pair metadata was fetched.  Use `check_system_status` for the market's current status
+    pub fn check_status(
+        &self,
+        exchange: Exchange,
+        operation: SystemOperation,
+    ) -> Result<(), ExchangeError> {
+        if self.status.allows(operation) {
+            Ok(())
+        } else {
+            Err(ExchangeError::Unavailable {
+                exchange,
+                pair: Some(self.pair.clone()),
+                status: self.status,
+            })
+        }
+    }
+
+    /// The announced delisting of the market, unless it's already past
+    pub fn upcoming_delisting(&self) -> Option<DateTime<Utc>> {
+        self.delist_time
+            .filter(|delist_time| *delist_time > Utc::now())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExchangeError {
+    #[error("invalid trading pair: {pair}{}", format_suggestions(.suggestions))]
+    InvalidPair {
+        pair: String,
+        suggestions: Vec<TradingPair>,
+    },
+
+    #[error(
+        "{pair} spread of {:.3}% exceeds the {:.3}% limit (max-spread-percent setting)",
+        .spread * 100.,
+        .max_spread * 100.
+    )]
+    SpreadTooWide {
+        pair: TradingPair,
+        spread: f64,
+        max_spread: f64,
+    },
+
+    #[error("{exchange:?} {endpoint}: {code}: {message}{}", format_http_status(.http_status))]
+    Api {
+        exchange: Exchange,
+        endpoint: String,
+        http_status: Option<u16>,
+        code: String, // exchange-native error code
+        message: String,
+    },
+
+    #[error("{exchange:?} {endpoint}: request timed out")]
+    Timeout {
+        exchange: Exchange,
+        endpoint: String,
+    },
+
+    #[error("{exchange:?} {}is {status}", format_market(.pair))]
+    Unavailable {
+        exchange: Exchange,
+        pair: Option<TradingPair>,
+        status: SystemStatus,
+    },
+}
+
+impl ExchangeError {
+    /// Whether an idempotent request that failed with this error can safely be sent again
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ExchangeError::Timeout { .. })
+    }
+
+    /// Whether the exchange rejected the request's two-factor code, or required one
+    pub fn is_two_factor_error(&self) -> bool {
+        matches!(
+            self,
+            ExchangeError::Api { exchange: Exchange::Coinbase, code, .. }
+                if code == "two_factor_required"
+        )
+    }
+
+    /// Likely cause of an authentication failure, when the exchange's error identifies one
+    pub fn credentials_failure(&self) -> Option<&'static str> {
+        let (exchange, code, message) = match self {
+            ExchangeError::Api {
+                exchange,
+                code,
+                message,
+                ..
+            } => (exchange, code, message),
+            _ => return None,
+        };
+        let message = message.to_ascii_lowercase();
+        match exchange {
+            Exchange::Binance | Exchange::BinanceUs => match code.as_str() {
+                "-1021" => Some("clock skew"),
+                "-1022" => Some("invalid signature, usually a wrong secret"),
+                "-2014" => Some("invalid API key"),
+                "-2015" => Some("invalid API key, IP not whitelisted, or missing permission"),
+                _ => None,
+            },
+            Exchange::Kraken => match message.as_str() {
+                "invalid key" => Some("invalid API key"),
+                "invalid signature" => Some("invalid signature, usually a wrong secret"),
+                "invalid nonce" => Some("invalid nonce, from clock skew or a reused key"),
+                "permission denied" => Some("missing permission or IP not whitelisted"),
+                _ => None,
+            },
+            Exchange::Coinbase => match code.as_str() {
+                "invalid_signature" => Some("invalid signature, usually a wrong secret"),
+                "invalid_token" | "revoked_token" => Some("invalid API key"),
+                "invalid_scope" => Some("missing permission"),
+                "authentication_error" if message.contains("timestamp") => Some("clock skew"),
+                "authentication_error" if message.contains("ip address") => {
+                    Some("IP not whitelisted")
+                }
+                "authentication_error" => Some("invalid API key"),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Reports a timed out HTTP request as `ExchangeError::Timeout`, and other failures as they are
+pub fn request_error(
+    exchange: Exchange,
+    endpoint: &str,
+    err: reqwest::Error,
+) -> Box<dyn std::error::Error> {
+    if err.is_timeout() {
+        ExchangeError::Timeout {
+            exchange,
+            endpoint: endpoint.into(),
+        }
+        .into()
+    } else {
+        err.into()
+    }
+}
+
+/// Bounds a request made through an exchange SDK, whose HTTP client can't be given a timeout
+pub async fn with_timeout<T>(
+    exchange: Exchange,
+    endpoint: &str,
+    timeout: std::time::Duration,
+    request: impl std::future::Future<Output = T>,
+) -> Result<T, ExchangeError> {
+    tokio::time::timeout(timeout, request)
+        .await
+        .map_err(|_| ExchangeError::Timeout {
+            exchange,
+            endpoint: endpoint.into(),
+        })
+}
+
+// Times an idempotent request is sent again after a retryable failure
+const IDEMPOTENT_RETRIES: usize = 2;
+
+/// Sends an idempotent request, sending it again if it fails in a way that
+/// `ExchangeError::is_retryable` allows.  Never use this for requests that change account state
+pub async fn retry_idempotent<T, F, Fut>(mut request: F) -> Result<T, Box<dyn std::error::Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let mut retries = 0;
+    loop {
+        let err = match request().await {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        let retryable = err
+            .downcast_ref::<ExchangeError>()
+            .map_or(false, |err| err.is_retryable());
+        if !retryable || retries == IDEMPOTENT_RETRIES {
+            return Err(err);
+        }
+        log::warn!("{err}, retrying");
+        if let Some(
+            ExchangeError::Timeout { exchange, endpoint }
+            | ExchangeError::Api {
+                exchange, endpoint, ..
+            },
+        ) = err.downcast_ref::<ExchangeError>()
+        {
+            crate::request_metrics::record_retry(*exchange, endpoint);
+        }
+        retries += 1;
+    }
+}
+
+fn format_market(pair: &Option<TradingPair>) -> String {
+    match pair {
+        Some(pair) => format!("{pair} "),
+        None => String::new(),
+    }
+}
+
+fn format_http_status(http_status: &Option<u16>) -> String {
+    match http_status {
+        Some(http_status) => format!(" (HTTP {http_status})"),
+        None => String::new(),
+    }
+}
+
+fn format_suggestions(suggestions: &[TradingPair]) -> String {
+    if suggestions.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "; did you mean {}?",
+            suggestions
+                .iter()
+                .map(|pair| pair.to_string())
+                .collect::<Vec<_>>()
+                .join(" or ")
+        )
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut distances = (0..=b.len()).collect::<Vec<_>>();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut previous_diagonal = distances[0];
+        distances[0] = i + 1;
+        for (j, b_char) in b.iter().enumerate() {
+            let previous_above = distances[j + 1];
+            distances[j + 1] = if a_char == *b_char {
+                previous_diagonal
+            } else {
+                1 + previous_diagonal.min(previous_above).min(distances[j])
+            };
+            previous_diagonal = previous_above;
+        }
+    }
+    distances[b.len()]
+}
+
+/// Returns the listed trading pairs that are a close match for `symbol`, closest first
+pub fn suggest_trading_pairs(symbol: &str, trading_pairs: &[TradingPair]) -> Vec<TradingPair> {
+    const MAX_EDIT_DISTANCE: usize = 2;
+    const MAX_SUGGESTIONS: usize = 3;
+
+    let symbol = symbol
+        .replace(TRADING_PAIR_SEPARATORS, "")
+        .to_ascii_uppercase();
+    let mut suggestions = trading_pairs
+        .iter()
+        .map(|pair| {
+            (
+                edit_distance(&symbol, &format!("{}{}", pair.base, pair.quote)),
+                pair,
+            )
+        })
+        .filter(|(distance, _)| *distance <= MAX_EDIT_DISTANCE)
+        .collect::<Vec<_>>();
+    suggestions.sort_by_key(|(distance, _)| *distance);
+    suggestions
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, pair)| pair.clone())
+        .collect()
+}
+
+#[async_trait]
+pub trait ExchangeClient: Send + Sync {
+    async fn deposit_address(
+        &self,
+        token: MaybeToken,
+    ) -> Result<Pubkey, Box<dyn std::error::Error>>;
+    /// SOL and the SPL tokens that the exchange currently moves over the Solana network
+    async fn supported_tokens(&self) -> Result<Vec<MaybeToken>, Box<dyn std::error::Error>> {
+        Err("Token support queries not supported".into())
+    }
+    async fn supports_token(
+        &self,
+        _token: MaybeToken,
+    ) -> Result<TokenSupport, Box<dyn std::error::Error>> {
+        Err("Token support queries not supported".into())
+    }
+    /// Smallest deposit of `token` the exchange credits, as a ui amount, when it publishes one
+    async fn deposit_minimum(
+        &self,
+        _token: MaybeToken,
+    ) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+        Ok(None)
+    }
+    /// Confirmations, minimum and availability of deposits of `token`, from the exchange's asset
+    /// metadata
+    async fn deposit_requirements(
+        &self,
+        token: MaybeToken,
+    ) -> Result<DepositRequirements, Box<dyn std::error::Error>> {
+        Ok(DepositRequirements {
+            finality: DepositFinality::Unpublished,
+            minimum: self.deposit_minimum(token).await?,
+            deposits_enabled: self.supports_token(token).await?.deposits_enabled,
+            processing_time_secs: None,
+        })
+    }
+    async fn recent_deposits(&self)
+        -> Result<Option<Vec<DepositInfo>>, Box<dyn std::error::Error>>;
+    async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
+        let end = Utc::now();
+        self.withdrawals(end - chrono::Duration::days(RECENT_WITHDRAWAL_DAYS), end)
+            .await
+    }
+    /// Withdrawals requested from `start` up to but excluding `end`, oldest first.  Use
+    /// `ReportingTimeZone::days()` for the withdrawals of whole days
+    async fn withdrawals(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>>;
+    /// Requests a withdrawal of `amount`, in native units of `token`.  The amount is sent to the
+    /// exchange as formatted by `format_native_amount` with `withdrawal_decimals` places
+    async fn request_withdraw(
+        &self,
+        address: Pubkey,
+        token: MaybeToken,
+        amount: u64,
         withdrawal_password: Option<String>,
-        withdrawal_code: Option<String>,
+        withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+        client_reference: &str,
     ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>;
+    /// Decimal places the exchange accepts in a withdrawal amount of `token`
+    fn withdrawal_decimals(&self, token: MaybeToken) -> u8 {
+        token.decimals()
+    }
+    /// Bank accounts registered with the exchange for withdrawals of `currency`
+    async fn fiat_withdrawal_methods(
+        &self,
+        _currency: &str,
+    ) -> Result<Vec<FiatWithdrawalMethod>, Box<dyn std::error::Error>> {
+        Err("Fiat withdrawals not supported".into())
+    }
+    /// Requests a withdrawal of `amount` of `currency` to a registered `method_id`, returning the
+    /// exchange's withdrawal id
+    async fn request_fiat_withdraw(
+        &self,
+        _currency: &str,
+        _amount: f64,
+        _method_id: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        Err("Fiat withdrawals not supported".into())
+    }
+    async fn fiat_withdrawal_status(
+        &self,
+        _currency: &str,
+        _withdrawal_id: &str,
+    ) -> Result<FiatWithdrawalStatus, Box<dyn std::error::Error>> {
+        Err("Fiat withdrawals not supported".into())
+    }
+    /// Withdrawal minimum and fee for `token`, when the exchange publishes them up front
+    async fn withdrawal_limits(
+        &self,
+        _token: MaybeToken,
+    ) -> Result<Option<WithdrawalLimits>, Box<dyn std::error::Error>> {
+        Ok(None)
+    }
     async fn balances(
         &self,
     ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>>;
+    /// Balances broken down by the wallet holding them, such as "spot" or "earn-flexible"
+    async fn wallet_balances(
+        &self,
+    ) -> Result<BTreeMap<String, HashMap<String, ExchangeBalance>>, Box<dyn std::error::Error>>
+    {
+        Ok(BTreeMap::from([(
+            "spot".to_string(),
+            self.balances().await?,
+        )]))
+    }
     async fn print_market_info(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         format: MarketInfoFormat,
     ) -> Result<(), Box<dyn std::error::Error>>;
-    async fn bid_ask(&self, pair: &str) -> Result<BidAsk, Box<dyn std::error::Error>>;
+    async fn bid_ask(&self, pair: &TradingPair) -> Result<BidAsk, Box<dyn std::error::Error>>;
+    /// Best bid and ask of every market the exchange lists, in a single request
+    async fn bid_asks(&self) -> Result<HashMap<TradingPair, BidAsk>, Box<dyn std::error::Error>> {
+        Err("Bulk tickers not supported".into())
+    }
+    /// Up to `depth` levels on each side of the book
+    async fn order_book(
+        &self,
+        pair: &TradingPair,
+        _depth: usize,
+    ) -> Result<OrderBook, Box<dyn std::error::Error>> {
+        Err(format!("Order book not supported for {pair}").into())
+    }
+    async fn ticker_stats(
+        &self,
+        pair: &TradingPair,
+    ) -> Result<TickerStats, Box<dyn std::error::Error>> {
+        Err(format!("Ticker stats not supported for {pair}").into())
+    }
+    /// The most recent candles, oldest first
+    async fn candles(
+        &self,
+        pair: &TradingPair,
+        _range: CandleRange,
+    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
+        Err(format!("Candles not supported for {pair}").into())
+    }
+    /// Daily close price for `pair` on `date` (UTC)
+    async fn price_on(
+        &self,
+        pair: &TradingPair,
+        _date: NaiveDate,
+    ) -> Result<f64, Box<dyn std::error::Error>> {
+        Err(format!("Historical prices not supported for {pair}").into())
+    }
     async fn place_order(
         &self,
-        pair: &str,
-        side: OrderSide,
-        price: f64,
-        amount: f64,
-    ) -> Result<OrderId, Box<dyn std::error::Error>>;
-    #[allow(clippy::ptr_arg)]
-    async fn cancel_order(
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+    ) -> Result<OrderId, Box<dyn std::error::Error>>;
+    /// Places a limit order that is allowed to take liquidity.  Any portion that does not fill
+    /// immediately is cancelled by exchanges that support immediate-or-cancel orders
+    async fn place_taker_order(
+        &self,
+        _pair: &TradingPair,
+        _side: OrderSide,
+        _price: f64,
+        _amount: f64,
+        _client_order_id: &str,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        Err("Taker orders not supported".into())
+    }
+    /// Places a limit order like `place_order()` that the exchange cancels at `expiry`
+    async fn place_expiring_order(
+        &self,
+        _pair: &TradingPair,
+        _side: OrderSide,
+        _price: f64,
+        _amount: f64,
+        _client_order_id: &str,
+        _expiry: DateTime<Utc>,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        Err("Order expiry not supported".into())
+    }
+    /// Moves a resting limit order to `price`, keeping its order id
+    #[allow(clippy::ptr_arg)]
+    async fn amend_order(
+        &self,
+        _pair: &TradingPair,
+        _order_id: &OrderId,
+        _price: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("Order amendment not supported".into())
+    }
+    /// Requests a firm price for a large order, to be executed with `execute_unexpired_quote()`
+    async fn request_quote(
+        &self,
+        _pair: &TradingPair,
+        _side: OrderSide,
+        _amount: f64,
+    ) -> Result<Quote, Box<dyn std::error::Error>> {
+        Err("Quote requests not supported".into())
+    }
+    async fn execute_quote(&self, _quote_id: &str) -> Result<OrderId, Box<dyn std::error::Error>> {
+        Err("Quote requests not supported".into())
+    }
+    /// The exchange's clock
+    async fn server_time(&self) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        Err("Server time not supported".into())
+    }
+    /// Whether the exchange is accepting requests, or with `pair`, whether that market is.  A
+    /// market is never more available than its exchange
+    async fn system_status(
+        &self,
+        _pair: Option<&TradingPair>,
+    ) -> Result<SystemStatus, Box<dyn std::error::Error>> {
+        Err("System status not reported".into())
+    }
+    /// Permissions of the API key in use
+    async fn api_permissions(&self) -> Result<ApiPermissions, Box<dyn std::error::Error>> {
+        Err("API key permissions not reported".into())
+    }
+    /// The exchange order id of the order placed with `client_order_id`, or `None` if the
+    /// exchange never received it
+    async fn find_order_by_client_id(
+        &self,
+        _pair: &TradingPair,
+        _client_order_id: &str,
+    ) -> Result<Option<OrderId>, Box<dyn std::error::Error>> {
+        Err("Client order id lookup not supported".into())
+    }
+    /// Status of the order placed with `client_order_id`.  `None` means the exchange never saw
+    /// the order and it is safe to place again; lookup failures are returned as errors
+    async fn order_status_by_client_id(
+        &self,
+        pair: &TradingPair,
+        client_order_id: &str,
+    ) -> Result<Option<(OrderId, OrderStatus)>, Box<dyn std::error::Error>> {
+        match self.find_order_by_client_id(pair, client_order_id).await? {
+            Some(order_id) => {
+                let order_status = self.order_status(pair, &order_id).await?;
+                Ok(Some((order_id, order_status)))
+            }
+            None => Ok(None),
+        }
+    }
+    #[allow(clippy::ptr_arg)]
+    async fn cancel_order(
+        &self,
+        pair: &TradingPair,
+        order_id: &OrderId,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    /// Cancels the order and returns its terminal status, including anything that filled before
+    /// the cancel landed.  An order that had already filled or closed is not an error
+    #[allow(clippy::ptr_arg)]
+    async fn cancel_order_final(
+        &self,
+        pair: &TradingPair,
+        order_id: &OrderId,
+    ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+        if let Err(err) = self.cancel_order(pair, order_id).await {
+            let err = err.to_string();
+            let order_status = self.order_status(pair, order_id).await?;
+            return if order_status.open {
+                Err(err.into())
+            } else {
+                Ok(order_status)
+            };
+        }
+
+        // Some exchanges acknowledge a cancel before the order is closed
+        for _ in 0..CANCEL_STATUS_ATTEMPTS {
+            let order_status = self.order_status(pair, order_id).await?;
+            if !order_status.open {
+                return Ok(order_status);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+        Err(format!("Order {order_id} is still open after being cancelled").into())
+    }
+    #[allow(clippy::ptr_arg)]
+    async fn order_status(
+        &self,
+        pair: &TradingPair,
+        order_id: &OrderId,
+    ) -> Result<OrderStatus, Box<dyn std::error::Error>>;
+    /// Every order open on the exchange, on any pair, including any placed outside of `sys`
+    async fn open_orders(&self) -> Result<Vec<ExchangeOpenOrder>, Box<dyn std::error::Error>> {
+        Err("Listing open orders not supported".into())
+    }
+    /// Orders on `pair` created between `start` and `end`, including any placed outside of `sys`
+    async fn order_history(
+        &self,
+        _pair: &TradingPair,
+        _start: DateTime<Utc>,
+        _end: DateTime<Utc>,
+    ) -> Result<Vec<HistoricalOrder>, Box<dyn std::error::Error>> {
+        Err("Order history not supported".into())
+    }
+    async fn get_lending_info(
+        &self,
+        coin: &str,
+    ) -> Result<Option<LendingInfo>, Box<dyn std::error::Error>>;
+    /// Lending info for every coin with a lendable balance or an active offer, keyed by coin
+    async fn all_lending_info(
+        &self,
+    ) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>>;
+    async fn get_lending_history(
+        &self,
+        lending_history: LendingHistory,
+    ) -> Result<HashMap<String, f64>, Box<dyn std::error::Error>>;
+    /// Lending proceeds for each day from `start_date` to `end_date` inclusive, keyed by day in
+    /// `time_zone` and then coin
+    async fn get_daily_lending_history(
+        &self,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+        time_zone: ReportingTimeZone,
+    ) -> Result<BTreeMap<NaiveDate, HashMap<String, f64>>, Box<dyn std::error::Error>> {
+        let mut daily_lending_history = BTreeMap::default();
+        let mut date = start_date;
+        while date <= end_date {
+            let lending_history = self
+                .get_lending_history(LendingHistory::days(date, date, time_zone))
+                .await?;
+            daily_lending_history.insert(date, lending_history);
+            date += chrono::Duration::days(1);
+        }
+        Ok(daily_lending_history)
+    }
+    async fn submit_lending_offer(
+        &self,
+        coin: &str,
+        size: f64,
+    ) -> Result<(), Box<dyn std::error::Error>>;
+    /// Reduces the lending offer for `coin` by up to `amount`.  The funds become available once
+    /// the exchange releases them, which may not be immediate
+    async fn redeem_lending(
+        &self,
+        coin: &str,
+        amount: f64,
+    ) -> Result<LendingRedemption, Box<dyn std::error::Error>> {
+        let lending_info = self
+            .get_lending_info(coin)
+            .await?
+            .ok_or_else(|| format!("Lending not available for {coin}"))?;
+        let redeemed = amount.min(lending_info.offered);
+        self.submit_lending_offer(coin, lending_info.offered - redeemed)
+            .await?;
+        Ok(LendingRedemption {
+            redeemed,
+            forfeited_interest: None,
+        })
+    }
+    async fn get_staking_info(
+        &self,
+        _coin: &str,
+    ) -> Result<Vec<StakingInfo>, Box<dyn std::error::Error>> {
+        Err("Staking not supported".into())
+    }
+    /// Staking requests complete asynchronously. Poll `get_staking_info()` until the operation is
+    /// no longer pending
+    async fn submit_staking_request(
+        &self,
+        _coin: &str,
+        _operation: StakingOperation,
+        _amount: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("Staking not supported".into())
+    }
+    async fn get_staking_rewards(
+        &self,
+        _coin: &str,
+        _staking_history: LendingHistory,
+    ) -> Result<Vec<StakingReward>, Box<dyn std::error::Error>> {
+        Err("Staking not supported".into())
+    }
+    async fn list_subaccounts(&self) -> Result<Vec<Subaccount>, Box<dyn std::error::Error>> {
+        Err("Subaccounts not supported".into())
+    }
+    /// Names of the subaccounts reachable with these credentials, other than the account they're
+    /// scoped to.  Empty when the exchange has no subaccounts
+    async fn subaccounts(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(vec![])
+    }
+    async fn subaccount_balances(
+        &self,
+        _name: &str,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        Err("Subaccounts not supported".into())
+    }
+    /// Moves funds between subaccounts, identified by name or id
+    async fn transfer_between_subaccounts(
+        &self,
+        _coin: &str,
+        _amount: f64,
+        _from: &str,
+        _to: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("Subaccounts not supported".into())
+    }
+    /// Ledger entries within the time range, oldest first
+    async fn get_ledger(
         &self,
-        pair: &str,
-        order_id: &OrderId,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    #[allow(clippy::ptr_arg)]
-    async fn order_status(
+        _start_time: DateTime<Utc>,
+        _end_time: DateTime<Utc>,
+    ) -> Result<Vec<LedgerEntry>, Box<dyn std::error::Error>> {
+        Err("Ledger export not supported".into())
+    }
+    /// How far back `category` of account history is served, `None` when all of it is
+    fn history_lookback(&self, _category: HistoryCategory) -> Option<chrono::Duration> {
+        None
+    }
+    /// Account history of `category` between `start` and `end`, oldest first
+    async fn account_history(
         &self,
-        pair: &str,
-        order_id: &OrderId,
-    ) -> Result<OrderStatus, Box<dyn std::error::Error>>;
-    async fn get_lending_info(
+        category: HistoryCategory,
+        _start: DateTime<Utc>,
+        _end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        Err(format!("Backfilling {category} not supported").into())
+    }
+    fn preferred_solusd_pair(&self) -> TradingPair;
+    /// Standard tier fee rates, used to estimate the balance required by an order
+    fn maker_fee_rate(&self) -> f64;
+    fn taker_fee_rate(&self) -> f64;
+    /// Fee rates for this account, including any discounts
+    async fn fee_schedule(&self) -> Result<FeeSchedule, Box<dyn std::error::Error>> {
+        Ok(FeeSchedule {
+            maker_rate: self.maker_fee_rate(),
+            taker_rate: self.taker_fee_rate(),
+            discount: None,
+        })
+    }
+    /// Rolling 30-day volume and how far it is from the next fee tier
+    async fn volume_tier_info(&self) -> Result<VolumeTierInfo, Box<dyn std::error::Error>> {
+        Err("Volume tiers not supported".into())
+    }
+    /// Token authenticating the private streaming API, such as a Kraken WebSocket token or a
+    /// Binance listen key.  Reused across invocations while valid and refreshed ahead of expiry
+    async fn stream_token(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Err("Stream tokens not supported".into())
+    }
+    /// Discards the stream token after the exchange rejects it, so the next `stream_token`
+    /// re-authenticates and the stream can resubscribe with the new token
+    fn invalidate_stream_token(&self) {}
+    async fn pair_infos(&self) -> Result<Vec<PairInfo>, Box<dyn std::error::Error>>;
+
+    async fn trading_pairs(&self) -> Result<Vec<TradingPair>, Box<dyn std::error::Error>> {
+        Ok(self
+            .pair_infos()
+            .await?
+            .into_iter()
+            .map(|pair_info| pair_info.pair)
+            .collect())
+    }
+
+    async fn pair_info(&self, pair: &TradingPair) -> Result<PairInfo, Box<dyn std::error::Error>> {
+        let pair_infos = self.pair_infos().await?;
+        if let Some(pair_info) = pair_infos.iter().find(|pair_info| pair_info.pair == *pair) {
+            return Ok(pair_info.clone());
+        }
+
+        let trading_pairs = pair_infos
+            .into_iter()
+            .map(|pair_info| pair_info.pair)
+            .collect::<Vec<_>>();
+        Err(ExchangeError::InvalidPair {
+            pair: pair.to_string(),
+            suggestions: suggest_trading_pairs(&pair.to_string(), &trading_pairs),
+        }
+        .into())
+    }
+
+    /// Market for `token` quoted in `quote`, such as SOL/BTC.  "USD" selects the same market as
+    /// `preferred_usd_pair`
+    async fn preferred_pair(
         &self,
-        coin: &str,
-    ) -> Result<Option<LendingInfo>, Box<dyn std::error::Error>>;
-    async fn get_lending_history(
+        token: MaybeToken,
+        quote: &str,
+    ) -> Result<TradingPair, Box<dyn std::error::Error>> {
+        if quote.eq_ignore_ascii_case("USD") {
+            return self.preferred_usd_pair(token).await;
+        }
+
+        let pair = TradingPair::from_token(token, normalize_kraken_asset(&quote.to_uppercase()));
+        let trading_pairs = self.trading_pairs().await?;
+        if trading_pairs.contains(&pair) {
+            return Ok(pair);
+        }
+        Err(ExchangeError::InvalidPair {
+            pair: pair.to_string(),
+            suggestions: suggest_trading_pairs(&pair.to_string(), &trading_pairs),
+        }
+        .into())
+    }
+
+    async fn preferred_usd_pair(
         &self,
-        lending_history: LendingHistory,
-    ) -> Result<HashMap<String, f64>, Box<dyn std::error::Error>>;
-    async fn submit_lending_offer(
+        token: MaybeToken,
+    ) -> Result<TradingPair, Box<dyn std::error::Error>> {
+        let trading_pairs = self.trading_pairs().await?;
+
+        if let Some(quote_preference) = quote_preference_order() {
+            let pair = quote_preference
+                .iter()
+                .map(|quote| TradingPair::from_token(token, quote))
+                .find(|pair| trading_pairs.contains(pair))
+                .ok_or_else(|| {
+                    format!(
+                        "No {} market available for {token}",
+                        quote_preference.join("/")
+                    )
+                })?;
+
+            let alternatives = quote_preference
+                .iter()
+                .chain(usd_preference_order())
+                .map(|quote| TradingPair::from_token(token, quote))
+                .filter(|alternative| *alternative != pair && trading_pairs.contains(alternative))
+                .collect::<Vec<_>>();
+            self.warn_if_thin_market(&pair, &alternatives).await;
+            return Ok(pair);
+        }
+
+        let preferred_solusd_pair = self.preferred_solusd_pair();
+        if token.is_sol() && trading_pairs.contains(&preferred_solusd_pair) {
+            return Ok(preferred_solusd_pair);
+        }
+
+        usd_preference_order()
+            .iter()
+            .map(|quote| TradingPair::from_token(token, quote))
+            .find(|pair| trading_pairs.contains(pair))
+            .ok_or_else(|| format!("No USD-quoted market available for {token}").into())
+    }
+
+    /// Best effort, exchanges without `ticker_stats` are silently skipped
+    async fn warn_if_thin_market(&self, pair: &TradingPair, alternatives: &[TradingPair]) {
+        let quote_volume = match self.ticker_stats(pair).await {
+            Ok(ticker_stats) => ticker_stats.quote_volume_24h,
+            Err(_) => return,
+        };
+
+        let mut seen = HashSet::new();
+        for alternative in alternatives {
+            if !seen.insert(alternative) {
+                continue;
+            }
+            if let Ok(ticker_stats) = self.ticker_stats(alternative).await {
+                if quote_volume < ticker_stats.quote_volume_24h * THIN_MARKET_VOLUME_RATIO {
+                    eprintln!(
+                        "Warning: {pair} 24h volume of ${quote_volume:.0} is thin compared to \
+                         {alternative} (${:.0})",
+                        ticker_stats.quote_volume_24h
+                    );
+                }
+            }
+        }
+    }
+
+    /// Fails if the exchange doesn't list `pair`.  A listed market may still be halted or
+    /// scheduled for delisting, see `PairInfo::status` and `PairInfo::delist_time`
+    async fn validate_pair(&self, pair: &TradingPair) -> Result<(), Box<dyn std::error::Error>> {
+        self.pair_info(pair).await.map(|_| ())
+    }
+}
+
+/// Fails with `ExchangeError::Unavailable` if the exchange, or the market for `pair`, does not
+/// currently allow `operation`.  An exchange that doesn't report its status is assumed available
+pub async fn check_system_status(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    pair: Option<&TradingPair>,
+    operation: SystemOperation,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match exchange_client.system_status(pair).await {
+        Ok(status) if !status.allows(operation) => Err(ExchangeError::Unavailable {
+            exchange,
+            pair: pair.cloned(),
+            status,
+        }
+        .into()),
+        Ok(_) => Ok(()),
+        Err(err) => {
+            log::debug!("{exchange:?} system status unavailable: {err}");
+            Ok(())
+        }
+    }
+}
+
+/// Fails if an order is below the market's minimum notional, or the available balance can't cover
+/// it and the fees it would pay.  A `taker` order may cross the book, so pays the taker rate,
+/// while any other order is post-only
+pub async fn preflight_exchange_order(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    side: OrderSide,
+    price: f64,
+    amount: f64,
+    taker: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let notional = price * amount;
+    let pair_info = exchange_client.pair_info(pair).await?;
+    if let Some(min_notional) = pair_info.min_notional {
+        if notional < min_notional {
+            return Err(format!(
+                "Order notional {} is below the {} minimum for {} on {:?}",
+                format_quote_amount(&pair_info.format_price(notional, false), &pair.quote),
+                format_quote_amount(&pair_info.format_price(min_notional, false), &pair.quote),
+                pair,
+                exchange
+            )
+            .into());
+        }
+    }
+
+    let fee_rate = if taker {
+        exchange_client.taker_fee_rate()
+    } else {
+        exchange_client.maker_fee_rate()
+    };
+    let (asset, required) = match side {
+        OrderSide::Buy => (&pair.quote, notional * (1. + fee_rate)),
+        OrderSide::Sell => (&pair.base, amount),
+    };
+    let available = exchange_client
+        .balances()
+        .await?
+        .get(asset)
+        .cloned()
+        .unwrap_or_default()
+        .available;
+    if required > available {
+        return Err(format!(
+            "Order requires {required} {asset}, fees included, but only {available} {asset} is \
+             available on {exchange:?}"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Rounds an order to the price and amount increments of `pair`, reporting any change.  `None`
+/// leaves the order exactly as given
+pub async fn round_exchange_order(
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    side: OrderSide,
+    price: f64,
+    amount: f64,
+    rounding: Option<OrderRounding>,
+) -> Result<(f64, f64), Box<dyn std::error::Error>> {
+    let rounding = match rounding {
+        Some(rounding) => rounding,
+        None => return Ok((price, amount)),
+    };
+
+    let (rounded_price, rounded_amount) = exchange_client
+        .pair_info(pair)
+        .await?
+        .round_order(side, price, amount, rounding)?;
+    if rounded_price != price {
+        println!(
+            "Rounded price from {} to {}",
+            format_quote_amount(&price.to_string(), &pair.quote),
+            format_quote_amount(&rounded_price.to_string(), &pair.quote)
+        );
+    }
+    if rounded_amount != amount {
+        println!("Rounded amount from {amount} to {rounded_amount}");
+    }
+    Ok((rounded_price, rounded_amount))
+}
+
+/// What `place_order_with_client_id` does to an order before it's sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrderChecks {
+    /// Rounds the price and amount to the market's increments, `None` sends them exactly as given
+    pub rounding: Option<OrderRounding>,
+    /// Runs `preflight_exchange_order()`
+    pub preflight: bool,
+}
+
+impl Default for OrderChecks {
+    fn default() -> Self {
+        Self {
+            rounding: Some(OrderRounding::default()),
+            preflight: true,
+        }
+    }
+}
+
+/// Places an order tagged with a new client order id.  If placement fails, the exchange is asked
+/// whether the order landed anyway before it is retried once, so a dropped response never
+/// results in a duplicate order.  A maker order with an `expiry` is left to the exchange to
+/// cancel where it supports that, otherwise the caller is responsible for cancelling it
+#[allow(clippy::too_many_arguments)]
+pub async fn place_tagged_order(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    side: OrderSide,
+    price: f64,
+    amount: f64,
+    taker: bool,
+    expiry: Option<DateTime<Utc>>,
+    checks: OrderChecks,
+    strategy: &str,
+    pre_trade_checks: &[&dyn PreTradeCheck],
+) -> Result<(OrderId, String), Box<dyn std::error::Error>> {
+    let client_order_id = new_client_id();
+    let order_id = place_order_with_client_id(
+        exchange,
+        exchange_client,
+        pair,
+        side,
+        price,
+        amount,
+        taker,
+        expiry,
+        &client_order_id,
+        checks,
+        strategy,
+        pre_trade_checks,
+    )
+    .await?;
+    Ok((order_id, client_order_id))
+}
+
+/// `place_tagged_order`, with a client order id the caller has already recorded, so that an
+/// interrupted placement can later be looked up with `order_status_by_client_id()`.  The order is
+/// rounded and checked as `checks` asks, then the rounded order is run past `pre_trade_checks` on
+/// behalf of `strategy`, before anything is sent.  A rejection is returned as a
+/// `pre_trade::Rejection`.  A caller that records the price and amount first rounds them itself,
+/// rounding again leaves them unchanged
+#[allow(clippy::too_many_arguments)]
+pub async fn place_order_with_client_id(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    side: OrderSide,
+    price: f64,
+    amount: f64,
+    taker: bool,
+    expiry: Option<DateTime<Utc>>,
+    client_order_id: &str,
+    checks: OrderChecks,
+    strategy: &str,
+    pre_trade_checks: &[&dyn PreTradeCheck],
+) -> Result<OrderId, Box<dyn std::error::Error>> {
+    check_system_status(
+        exchange,
+        exchange_client,
+        Some(pair),
+        SystemOperation::PlaceOrder { taker },
+    )
+    .await?;
+    let (price, amount) =
+        round_exchange_order(exchange_client, pair, side, price, amount, checks.rounding).await?;
+    if checks.preflight {
+        preflight_exchange_order(exchange, exchange_client, pair, side, price, amount, taker)
+            .await?;
+    }
+    pre_trade::check_order(
+        pre_trade_checks,
+        &OrderIntent {
+            exchange,
+            pair: pair.clone(),
+            side,
+            price,
+            amount,
+            strategy: strategy.into(),
+        },
+    )
+    .await?;
+    let native_expiry = expiry.filter(|_| exchange.capabilities().supports_native_order_expiry);
+
+    let mut retried = false;
+    loop {
+        let result = if taker {
+            exchange_client
+                .place_taker_order(pair, side, price, amount, client_order_id)
+                .await
+        } else if let Some(expiry) = native_expiry {
+            exchange_client
+                .place_expiring_order(pair, side, price, amount, client_order_id, expiry)
+                .await
+        } else {
+            exchange_client
+                .place_order(pair, side, price, amount, client_order_id)
+                .await
+        };
+
+        let err = match result {
+            Ok(order_id) => return Ok(order_id),
+            Err(err) => err,
+        };
+
+        match exchange_client
+            .find_order_by_client_id(pair, client_order_id)
+            .await
+        {
+            Ok(Some(order_id)) => return Ok(order_id),
+            Ok(None) if !retried => {
+                log::warn!("Order {client_order_id} was not placed ({err}), retrying");
+                retried = true;
+            }
+            Ok(None) => return Err(err),
+            Err(lookup_err) => {
+                return Err(format!(
+                    "{err}. Unable to determine whether order {client_order_id} was placed: {lookup_err}"
+                )
+                .into())
+            }
+        }
+    }
+}
+
+// Codes entered by hand or computed from a clock may be rejected, but not indefinitely
+const MAX_TWO_FACTOR_ATTEMPTS: usize = 3;
+
+// Times `cancel_order_final` checks for a cancelled order to close, a second apart
+const CANCEL_STATUS_ATTEMPTS: usize = 5;
+
+/// Requests a withdrawal tagged with a new client reference, once `checks` pass it on behalf of
+/// `strategy` and it's confirmed.  A rejection is returned as a `pre_trade::Rejection`.  If the
+/// request fails, recent withdrawals are checked for it before it is retried once.  Exchanges
+/// without client references are never retried automatically, as a lookalike withdrawal is the
+/// only evidence available
+#[allow(clippy::too_many_arguments)]
+pub async fn request_tagged_withdraw(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    address: Pubkey,
+    token: MaybeToken,
+    amount: u64,
+    withdrawal_password: Option<String>,
+    withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+    strategy: &str,
+    checks: &[&dyn PreWithdrawalCheck],
+) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>> {
+    check_system_status(exchange, exchange_client, None, SystemOperation::Withdraw).await?;
+    pre_trade::check_withdrawal(
+        checks,
+        &WithdrawalRequest {
+            exchange,
+            token,
+            amount,
+            to_address: address,
+            strategy: strategy.into(),
+        },
+    )
+    .await?;
+    if confirmation::required() {
+        let fee = match exchange_client.withdrawal_limits(token).await {
+            Ok(Some(limits)) => withdrawal_fee_detail(exchange, token, amount, limits.fee),
+            Ok(None) => "as charged by the exchange".into(),
+            Err(err) => format!("unknown, {err}"),
+        };
+        confirmation::confirm(
+            &IrreversibleOperation::new(format!("Withdrawal from {exchange:?}"))
+                .detail(
+                    "Amount",
+                    format!("{}{}", token.symbol(), token.ui_amount(amount)),
+                )
+                .detail("Token", token)
+                .detail("Destination", address)
+                .detail("Fee", fee),
+        )?;
+    }
+    let client_reference = new_client_id();
+    let request_time = Utc::now() - chrono::Duration::minutes(1);
+    let supports_withdrawal_references = exchange.capabilities().supports_withdrawal_references;
+
+    let mut retried = false;
+    let mut two_factor_attempts = 1;
+    loop {
+        let err = match exchange_client
+            .request_withdraw(
+                address,
+                token,
+                amount,
+                withdrawal_password.clone(),
+                withdrawal_code,
+                &client_reference,
+            )
+            .await
+        {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+
+        // A rejected code means no withdrawal was created, so ask for a fresh one
+        if let Some(withdrawal_code) = withdrawal_code {
+            if withdrawal_code.can_refresh()
+                && two_factor_attempts < MAX_TWO_FACTOR_ATTEMPTS
+                && err
+                    .downcast_ref::<ExchangeError>()
+                    .map_or(false, |err| err.is_two_factor_error())
+            {
+                eprintln!("{err}, retrying with a new code");
+                two_factor_attempts += 1;
+                continue;
+            }
+        }
+
+        let recent_withdrawals = exchange_client.recent_withdrawals().await.map_err(|lookup_err| {
+            format!(
+                "{err}. Unable to determine whether withdrawal {client_reference} was created: {lookup_err}"
+            )
+        })?;
+
+        let existing_withdrawal = recent_withdrawals.into_iter().find(|wi| {
+            if supports_withdrawal_references {
+                wi.client_reference.as_deref() == Some(client_reference.as_str())
+            } else {
+                wi.token == token
+                    && wi.address == address
+                    && (wi.amount - token.ui_amount(amount)).abs()
+                        <= wi.fee.unwrap_or_default() + f64::EPSILON
+                    && wi.time.map(|time| time >= request_time).unwrap_or(false)
+            }
+        });
+        if let Some(wi) = existing_withdrawal {
+            log::warn!("Withdrawal {} was created despite: {err}", wi.tag);
+            return Ok((wi.tag, wi.fee.unwrap_or_default()));
+        }
+
+        if !supports_withdrawal_references {
+            return Err(format!(
+                "{err}. No matching {exchange:?} withdrawal was found, confirm it was not created \
+                 before requesting it again"
+            )
+            .into());
+        }
+        if retried {
+            return Err(err);
+        }
+        log::warn!("Withdrawal {client_reference} was not created ({err}), retrying");
+        retried = true;
+    }
+}
+
+/// Describes the `fee` charged to withdraw `amount` of `token`, and the total debited where
+/// `exchange` charges it on top of the amount
+fn withdrawal_fee_detail(exchange: Exchange, token: MaybeToken, amount: u64, fee: f64) -> String {
+    if exchange.capabilities().withdrawal_fee_on_top {
+        format!(
+            "{}{fee} on top of the amount, {}{} debited in total",
+            token.symbol(),
+            token.symbol(),
+            token.ui_amount(amount) + fee
+        )
+    } else {
+        format!("{}{fee}, included in the amount", token.symbol())
+    }
+}
+
+/// The native amount of `token` to request to withdraw the entire `available` balance, as much of
+/// it as `withdrawal_decimals` can express.  Where `exchange` charges the `fee` on top of the
+/// amount withdrawn it's set aside, elsewhere the fee comes out of the amount withdrawn, so the
+/// balance must exceed it
+pub fn withdraw_all_amount(
+    exchange: Exchange,
+    token: MaybeToken,
+    available: u64,
+    fee: Option<u64>,
+    withdrawal_decimals: u8,
+) -> Result<u64, String> {
+    let fee_on_top = exchange.capabilities().withdrawal_fee_on_top;
+    let amount = match fee {
+        Some(fee) if fee_on_top => available.saturating_sub(fee),
+        _ => available,
+    };
+    let amount = truncate_native_amount(token, amount, withdrawal_decimals);
+
+    let covered = match fee {
+        Some(fee) if !fee_on_top => amount > fee,
+        _ => amount > 0,
+    };
+    match fee {
+        _ if covered => Ok(amount),
+        Some(fee) => Err(format!(
+            "{} {token} available on {exchange:?} does not cover the withdrawal fee of {}",
+            token.ui_amount(available),
+            token.ui_amount(fee)
+        )),
+        None => Err(format!("No {token} available on {exchange:?}")),
+    }
+}
+
+/// Withdraws the entire available balance of `token`, as `withdraw_all_amount()` works it out,
+/// once `checks` pass it.  Returns the withdrawal id, then the amount withdrawn and the fee in
+/// native units
+#[allow(clippy::too_many_arguments)]
+pub async fn withdraw_all(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    address: Pubkey,
+    token: MaybeToken,
+    withdrawal_password: Option<String>,
+    withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+    strategy: &str,
+    checks: &[&dyn PreWithdrawalCheck],
+) -> Result<(String, u64, u64), Box<dyn std::error::Error>> {
+    let available = native_amount_floor(
+        token,
+        token_balances(exchange, exchange_client)
+            .await?
+            .get(token)
+            .available,
+    );
+    let fee = exchange_client
+        .withdrawal_limits(token)
+        .await?
+        .map(|limits| native_amount_floor(token, limits.fee));
+    let amount = withdraw_all_amount(
+        exchange,
+        token,
+        available,
+        fee,
+        exchange_client.withdrawal_decimals(token),
+    )?;
+
+    let (withdraw_id, fee) = request_tagged_withdraw(
+        exchange,
+        exchange_client,
+        address,
+        token,
+        amount,
+        withdrawal_password,
+        withdrawal_code,
+        strategy,
+        checks,
+    )
+    .await?;
+    Ok((withdraw_id, amount, native_amount_floor(token, fee)))
+}
+
+/// Time allowed to establish a connection to an exchange, unless the builder overrides it
+pub const DEFAULT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Time allowed for a whole exchange request, unless the builder overrides it
+pub const DEFAULT_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Time allowed for a page of account history, which can legitimately take much longer than
+/// other requests
+pub const HISTORY_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// How an exchange client reaches the exchange
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExchangeProxy {
+    /// Connect directly, even if `HTTPS_PROXY` or `ALL_PROXY` is set in the environment
+    Direct,
+    /// An `http://` proxy, used with CONNECT, or a `socks5://` proxy.  Credentials may be included
+    /// as `user:password@`
+    Url(String),
+}
+
+impl FromStr for ExchangeProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") || s.eq_ignore_ascii_case("direct") {
+            return Ok(ExchangeProxy::Direct);
+        }
+        let scheme = s.split_once("://").map(|(scheme, _)| scheme);
+        match scheme {
+            Some("http" | "https" | "socks5" | "socks5h") => Ok(ExchangeProxy::Url(s.into())),
+            _ => Err(format!(
+                "Invalid proxy: {s}. Expected an http:// or socks5:// URL, or \"none\""
+            )),
+        }
+    }
+}
+
+/// Connection options for an exchange client, beyond its credentials
+#[derive(Debug, Default, Clone)]
+pub struct ExchangeClientOptions {
+    /// Use the exchange's test environment instead of production
+    pub sandbox: bool,
+    /// Send requests to this host instead of the exchange's, such as a regional mirror or a mock
+    /// server.  Must be https unless `allow_http_localhost` is set
+    pub base_url: Option<String>,
+    /// Permit an http `base_url` on the local machine, for testing against a mock server
+    pub allow_http_localhost: bool,
+    /// Time allowed for each request, `DEFAULT_REQUEST_TIMEOUT` by default
+    pub timeout: Option<std::time::Duration>,
+    /// Time allowed to connect, `DEFAULT_CONNECT_TIMEOUT` by default
+    pub connect_timeout: Option<std::time::Duration>,
+    /// Applies to the exchange client only, leaving other traffic such as Solana RPC requests
+    /// alone.  `None` takes the `exchange-proxy` setting, and otherwise follows the proxy
+    /// environment variables
+    pub proxy: Option<ExchangeProxy>,
+    /// Identifies the account in construction errors
+    pub account_label: Option<String>,
+    /// Write scrubbed copies of the client's API requests and responses to a timestamped file in
+    /// this directory
+    pub debug_capture_dir: Option<PathBuf>,
+    /// Share the client's request budget with other processes through state files in this
+    /// directory, by default the one given to `rate_limit::set_default_state_dir`.  Clients in the
+    /// same process always share a budget per API key
+    pub rate_limit_dir: Option<PathBuf>,
+    /// Persist streaming API tokens in this directory, by default the one given to
+    /// `stream_session::set_default_dir`
+    pub stream_session_dir: Option<PathBuf>,
+}
+
+impl ExchangeClientOptions {
+    /// Fails if any option outside of `supported` is set, rather than silently ignoring it
+    pub fn check_supported(
         &self,
-        coin: &str,
-        size: f64,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn preferred_solusd_pair(&self) -> &'static str;
+        exchange: Exchange,
+        supported: &[&str],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let unsupported = [
+            ("sandbox", self.sandbox),
+            ("base_url", self.base_url.is_some()),
+            ("timeout", self.timeout.is_some()),
+            ("connect_timeout", self.connect_timeout.is_some()),
+            ("proxy", self.proxy.is_some()),
+            ("debug_capture", self.debug_capture_dir.is_some()),
+        ]
+        .into_iter()
+        .filter(|(option, set)| *set && !supported.contains(option))
+        .map(|(option, _)| option)
+        .collect::<Vec<_>>();
+
+        if unsupported.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{exchange:?} does not support the {} option{}",
+                unsupported.join(", "),
+                if unsupported.len() == 1 { "" } else { "s" }
+            )
+            .into())
+        }
+    }
+
+    pub fn request_timeout(&self) -> std::time::Duration {
+        self.timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    /// HTTP client for requests the client makes itself, with the connect and request timeouts
+    /// and the proxy applied.  Individual requests may set a longer timeout of their own
+    pub fn http_client(&self) -> Result<reqwest::Client, Box<dyn std::error::Error>> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT))
+            .timeout(self.request_timeout());
+        match &self.proxy {
+            Some(ExchangeProxy::Direct) => builder = builder.no_proxy(),
+            Some(ExchangeProxy::Url(url)) => builder = builder.proxy(reqwest::Proxy::all(url)?),
+            None => {}
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Parses comma-separated proxies for individual exchanges such as
+/// "kraken=socks5://localhost:1080,coinbase=none"
+pub(crate) fn parse_exchange_proxies(
+    proxies: &str,
+) -> Result<HashMap<Exchange, ExchangeProxy>, String> {
+    proxies
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (exchange, proxy) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid proxy: {entry} (expected EXCHANGE=PROXY)"))?;
+            let exchange = exchange
+                .trim()
+                .parse::<Exchange>()
+                .map_err(|err| err.to_string())?;
+            Ok((exchange, proxy.trim().parse()?))
+        })
+        .collect()
+}
+
+/// The proxy for `exchange` from the `exchange-proxy` setting
+fn configured_proxy(exchange: Exchange) -> Result<Option<ExchangeProxy>, String> {
+    match settings::get(Setting::ExchangeProxy) {
+        Some(proxies) => Ok(parse_exchange_proxies(&proxies)?.remove(&exchange)),
+        None => Ok(None),
+    }
+}
+
+fn validate_base_url(base_url: &str, allow_http_localhost: bool) -> Result<(), String> {
+    let url = reqwest::Url::parse(base_url).map_err(|err| format!("Invalid base URL: {err}"))?;
+    let localhost = matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]"));
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if localhost && allow_http_localhost => Ok(()),
+        "http" if localhost => Err(format!(
+            "Base URL {base_url} is not https. Allow http to localhost for testing explicitly"
+        )),
+        _ => Err(format!("Base URL {base_url} is not https")),
+    }
+}
+
+pub struct ExchangeClientBuilder {
+    exchange: Exchange,
+    exchange_credentials: Option<ExchangeCredentials>,
+    options: ExchangeClientOptions,
+}
+
+impl ExchangeClientBuilder {
+    pub fn new(exchange: Exchange) -> Self {
+        Self {
+            exchange,
+            exchange_credentials: None,
+            options: ExchangeClientOptions::default(),
+        }
+    }
+
+    pub fn credentials(mut self, exchange_credentials: ExchangeCredentials) -> Self {
+        self.exchange_credentials = Some(exchange_credentials);
+        self
+    }
+
+    pub fn sandbox(mut self, sandbox: bool) -> Self {
+        self.options.sandbox = sandbox;
+        self
+    }
+
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.options.base_url = Some(base_url.trim_end_matches('/').to_string());
+        self
+    }
+
+    pub fn allow_http_localhost(mut self, allow_http_localhost: bool) -> Self {
+        self.options.allow_http_localhost = allow_http_localhost;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: std::time::Duration) -> Self {
+        self.options.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    pub fn proxy(mut self, proxy: ExchangeProxy) -> Self {
+        self.options.proxy = Some(proxy);
+        self
+    }
+
+    pub fn account_label(mut self, account_label: &str) -> Self {
+        self.options.account_label = Some(account_label.to_string());
+        self
+    }
+
+    pub fn debug_capture(mut self, dir: &Path) -> Self {
+        self.options.debug_capture_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    pub fn rate_limit_dir(mut self, dir: &Path) -> Self {
+        self.options.rate_limit_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    pub fn stream_session_dir(mut self, dir: &Path) -> Self {
+        self.options.stream_session_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    pub fn build(self) -> Result<Box<dyn ExchangeClient>, Box<dyn std::error::Error>> {
+        let Self {
+            exchange,
+            exchange_credentials,
+            mut options,
+        } = self;
+        if options.debug_capture_dir.is_none() {
+            options.debug_capture_dir = crate::debug_capture::dir_for(exchange);
+        }
+        if options.rate_limit_dir.is_none() {
+            options.rate_limit_dir = crate::rate_limit::default_state_dir();
+        }
+        let account_label = options
+            .account_label
+            .clone()
+            .unwrap_or_else(|| format!("{exchange:?}"));
+        if let Some(base_url) = &options.base_url {
+            validate_base_url(base_url, options.allow_http_localhost)
+                .map_err(|err| format!("{account_label}: {err}"))?;
+        }
+        if options.proxy.is_none() {
+            options.proxy =
+                configured_proxy(exchange).map_err(|err| format!("{account_label}: {err}"))?;
+        }
+        let exchange_credentials = exchange_credentials
+            .ok_or_else(|| format!("{account_label}: no API key configured"))?;
+
+        let exchange_client: Box<dyn ExchangeClient> = match exchange {
+            #[cfg(feature = "binance")]
+            Exchange::Binance => Box::new(
+                crate::binance_exchange::new(exchange_credentials, options)
+                    .map_err(|err| format!("{account_label}: {err}"))?,
+            ),
+            #[cfg(feature = "binance")]
+            Exchange::BinanceUs => Box::new(
+                crate::binance_exchange::new_us(exchange_credentials, options)
+                    .map_err(|err| format!("{account_label}: {err}"))?,
+            ),
+            #[cfg(feature = "coinbase")]
+            Exchange::Coinbase => Box::new(
+                crate::coinbase_exchange::new(exchange_credentials, options)
+                    .map_err(|err| format!("{account_label}: {err}"))?,
+            ),
+            Exchange::Ftx | Exchange::FtxUs => {
+                return Err(format!(
+                    "FTX is no longer operational. Remove the stored API key with `sys {} api \
+                     clear`",
+                    exchange.name()
+                )
+                .into())
+            }
+            #[cfg(feature = "kraken")]
+            Exchange::Kraken => Box::new(
+                crate::kraken_exchange::new(exchange_credentials, options)
+                    .map_err(|err| format!("{account_label}: {err}"))?,
+            ),
+            #[allow(unreachable_patterns)]
+            _ => {
+                let _ = (exchange_credentials, options);
+                return Err(format!(
+                    "Support for {exchange:?} was not compiled in. Rebuild with `--features {}`",
+                    exchange.feature_name()
+                )
+                .into());
+            }
+        };
+        Ok(exchange_client)
+    }
 }
 
 pub fn exchange_client_new(
     exchange: Exchange,
     exchange_credentials: ExchangeCredentials,
 ) -> Result<Box<dyn ExchangeClient>, Box<dyn std::error::Error>> {
-    let exchange_client: Box<dyn ExchangeClient> = match exchange {
-        Exchange::Binance => Box::new(binance_exchange::new(exchange_credentials)?),
-        Exchange::BinanceUs => Box::new(binance_exchange::new_us(exchange_credentials)?),
-        Exchange::Coinbase => Box::new(coinbase_exchange::new(exchange_credentials)?),
-        Exchange::Ftx => Box::new(ftx_exchange::new(exchange_credentials)?),
-        Exchange::FtxUs => Box::new(ftx_exchange::new_us(exchange_credentials)?),
-        Exchange::Kraken => Box::new(kraken_exchange::new(exchange_credentials)?),
-    };
-    Ok(exchange_client)
+    ExchangeClientBuilder::new(exchange)
+        .credentials(exchange_credentials)
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2^53 + 1 lamports, which an `f64` can't hold
+    const AVAILABLE: u64 = 9_007_199_254_740_993;
+    const FEE: u64 = 10_000_000;
+
+    #[test]
+    fn withdraw_all_deducts_a_fee_on_top_in_native_units() {
+        let sol = MaybeToken::SOL();
+        assert!(Exchange::Coinbase.capabilities().withdrawal_fee_on_top);
+        assert_eq!(
+            withdraw_all_amount(Exchange::Coinbase, sol, AVAILABLE, Some(FEE), 9),
+            Ok(9_007_199_244_740_993)
+        );
+        assert_eq!(
+            withdraw_all_amount(Exchange::Coinbase, sol, AVAILABLE, Some(FEE), 8),
+            Ok(9_007_199_244_740_990)
+        );
+        assert!(withdraw_all_amount(Exchange::Coinbase, sol, FEE, Some(FEE), 9).is_err());
+    }
+
+    #[test]
+    fn withdraw_all_leaves_a_deducted_fee_in_the_amount() {
+        let sol = MaybeToken::SOL();
+        assert!(!Exchange::Kraken.capabilities().withdrawal_fee_on_top);
+        assert_eq!(
+            withdraw_all_amount(Exchange::Kraken, sol, AVAILABLE, Some(FEE), 9),
+            Ok(AVAILABLE)
+        );
+        assert_eq!(
+            withdraw_all_amount(Exchange::Kraken, sol, AVAILABLE, Some(FEE), 8),
+            Ok(9_007_199_254_740_990)
+        );
+        assert!(withdraw_all_amount(Exchange::Kraken, sol, FEE, Some(FEE), 9).is_err());
+        assert!(withdraw_all_amount(Exchange::Kraken, sol, 0, None, 9).is_err());
+    }
+
+    #[test]
+    fn native_amount_floor_is_exact_in_decimal() {
+        let sol = MaybeToken::SOL();
+        assert_eq!(native_amount_floor(sol, 0.3), 300_000_000);
+        assert_eq!(native_amount_floor(sol, 1.000000001), 1_000_000_001);
+        assert_eq!(native_amount_floor(sol, -1.), 0);
+        assert_eq!(native_amount_floor(sol, f64::NAN), 0);
+    }
+
+    #[test]
+    fn order_status_round_trips_with_lowercase_sides() {
+        let order_status = OrderStatus {
+            open: false,
+            side: OrderSide::Sell,
+            price: 101.5,
+            amount: 2.,
+            filled_amount: 0.5,
+            average_fill_price: Some(101.25),
+            cumulative_quote_amount: Some(50.625),
+            last_update: NaiveDate::from_ymd(2024, 3, 21),
+            fee: Some((0.05, "USD".into())),
+            cancellation: Some(OrderCancellation::User),
+        };
+        let json = serde_json::to_value(&order_status).unwrap();
+        assert_eq!(json["side"], "sell");
+        assert_eq!(json["last_update"], "2024-03-21");
+        let round_trip = serde_json::from_value::<OrderStatus>(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&round_trip).unwrap(), json);
+    }
+
+    #[test]
+    fn order_sides_also_deserialize_from_debug_casing() {
+        for (json, side) in [
+            (r#""buy""#, OrderSide::Buy),
+            (r#""Buy""#, OrderSide::Buy),
+            (r#""sell""#, OrderSide::Sell),
+            (r#""Sell""#, OrderSide::Sell),
+        ] {
+            assert_eq!(serde_json::from_str::<OrderSide>(json).unwrap(), side);
+        }
+        assert_eq!(serde_json::to_string(&OrderSide::Buy).unwrap(), r#""buy""#);
+    }
+
+    #[test]
+    fn withdrawal_info_round_trips_with_base58_address_and_rfc3339_time() {
+        let address = Pubkey::new_unique();
+        let time = Utc.ymd(2024, 3, 21).and_hms(15, 4, 33);
+        let withdrawal = WithdrawalInfo {
+            address,
+            token: MaybeToken::SOL(),
+            amount: 8.,
+            tag: "FTQ2A8K-VaKkCq7UwxBNxgwBqpP5Kz".into(),
+            completed: true,
+            tx_id: Some("3hizm34taS8t9UvpJg9oRCJ7EWYkuUHN".into()),
+            time: Some(time),
+            fee: Some(0.01),
+            client_reference: None,
+        };
+        let json = serde_json::to_value(&withdrawal).unwrap();
+        assert_eq!(json["address"], address.to_string());
+        assert_eq!(
+            DateTime::parse_from_rfc3339(json["time"].as_str().unwrap()).unwrap(),
+            time
+        );
+        let round_trip = serde_json::from_value::<WithdrawalInfo>(json.clone()).unwrap();
+        assert_eq!(round_trip.address, address);
+        assert_eq!(round_trip.time, Some(time));
+        assert_eq!(serde_json::to_value(&round_trip).unwrap(), json);
+    }
+
+    #[test]
+    fn balances_bid_asks_deposits_and_lending_round_trip() {
+        fn round_trip<T: Serialize + serde::de::DeserializeOwned>(value: &T) {
+            let json = serde_json::to_value(value).unwrap();
+            let round_trip = serde_json::from_value::<T>(json.clone()).unwrap();
+            assert_eq!(serde_json::to_value(&round_trip).unwrap(), json);
+        }
+        round_trip(&ExchangeBalance {
+            available: 9.5,
+            total: 10.5,
+        });
+        round_trip(&BidAsk {
+            bid_price: 99.5,
+            ask_price: 100.5,
+            bid_amount: Some(12.),
+            ask_amount: None,
+        });
+        round_trip(&DepositInfo {
+            tx_id: "5VERv8NMvzbJMEkV8xnrLkEaWRtSz9CosKDYjCJjBRnb".into(),
+            amount: 12.25,
+            token: Some(MaybeToken::SOL()),
+            time: Some(Utc.ymd(2024, 3, 5).and_hms(12, 0, 0)),
+        });
+        round_trip(&LendingInfo {
+            lendable: 100.,
+            offered: 50.,
+            locked: 25.,
+            estimate_rate: 0.05,
+            previous_rate: 0.04,
+        });
+    }
+
+    #[test]
+    fn kraken_asset_codes_are_normalized() {
+        for (code, symbol) in [
+            ("ZUSD", "USD"),
+            ("ZEUR", "EUR"),
+            ("XXBT", "BTC"),
+            ("XBT", "BTC"),
+            ("XXDG", "DOGE"),
+            ("XDG", "DOGE"),
+            ("USDC", "USDC"),
+            ("SOL", "SOL"),
+        ] {
+            assert_eq!(normalize_kraken_asset(code), symbol, "{code}");
+        }
+    }
+
+    #[test]
+    fn usd_equivalence_is_case_insensitive_and_knows_kraken_codes() {
+        for coin in [
+            "USD", "usd", "ZUSD", "zusd", "USDC", "usdt", "PYUSD", "FDUSD",
+        ] {
+            assert!(is_usd_equivalent(coin), "{coin}");
+        }
+        for coin in ["ZEUR", "EUR", "XXBT", "SOL", "USD.HOLD", ""] {
+            assert!(!is_usd_equivalent(coin), "{coin}");
+        }
+    }
+
+    #[test]
+    fn coin_lists_are_trimmed_and_uppercased() {
+        assert_eq!(
+            parse_coin_list(" usdc, USD ,,pyusd"),
+            vec!["USDC".to_string(), "USD".into(), "PYUSD".into()]
+        );
+    }
+
+    #[test]
+    fn binance_symbols_map_to_tokens() {
+        for exchange in [Exchange::Binance, Exchange::BinanceUs] {
+            let token = |symbol| exchange_symbol_token(exchange, symbol);
+            assert_eq!(token("SOL"), Some(MaybeToken::SOL()));
+            assert_eq!(token("sol"), Some(MaybeToken::SOL()));
+            assert_eq!(token("USDC"), Some(Token::USDC.into()));
+            for symbol in ["MSOL", "BNB", "USDT", "LDSOL"] {
+                assert_eq!(token(symbol), None, "{exchange:?} {symbol}");
+            }
+        }
+    }
+
+    #[test]
+    fn coinbase_symbols_map_to_tokens() {
+        let token = |symbol| exchange_symbol_token(Exchange::Coinbase, symbol);
+        assert_eq!(token("SOL"), Some(MaybeToken::SOL()));
+        assert_eq!(token("USDC"), Some(Token::USDC.into()));
+        assert_eq!(token("MSOL"), Some(Token::mSOL.into()));
+        assert_eq!(token("mSOL"), Some(Token::mSOL.into()));
+        for symbol in ["USD", "ETH", "SOL.S"] {
+            assert_eq!(token(symbol), None, "{symbol}");
+        }
+    }
+
+    #[test]
+    fn kraken_symbols_map_to_tokens() {
+        let token = |symbol| exchange_symbol_token(Exchange::Kraken, symbol);
+        for symbol in ["SOL", "SOL.S", "SOL.F", "SOL03.S"] {
+            assert_eq!(token(symbol), Some(MaybeToken::SOL()), "{symbol}");
+        }
+        assert_eq!(token("USDC"), Some(Token::USDC.into()));
+        assert_eq!(token("MSOL"), Some(Token::mSOL.into()));
+        for symbol in ["ZUSD", "XXBT", "SOL.X", "DOT.S"] {
+            assert_eq!(token(symbol), None, "{symbol}");
+        }
+    }
+
+    #[test]
+    fn token_balances_combine_staked_balances_and_keep_unmapped_coins() {
+        let balance = |available, total| ExchangeBalance { available, total };
+        let token_balances = TokenBalances::new(
+            Exchange::Kraken,
+            HashMap::from([
+                ("SOL".to_string(), balance(1., 2.)),
+                ("SOL.S".to_string(), balance(0., 3.)),
+                ("ZUSD".to_string(), balance(100., 100.)),
+            ]),
+        );
+        let sol = token_balances.get(MaybeToken::SOL());
+        assert_eq!((sol.available, sol.total), (1., 5.));
+        assert_eq!(token_balances.get(Token::USDC.into()).total, 0.);
+        assert_eq!(
+            token_balances.unmapped.keys().collect::<Vec<_>>(),
+            vec!["ZUSD"]
+        );
+    }
+
+    fn sol_usdc_pair_info() -> PairInfo {
+        PairInfo {
+            pair: TradingPair::new("SOL", "USDC"),
+            tick_size: Some(0.01),
+            step_size: Some(0.001),
+            min_notional: Some(1.),
+            status: SystemStatus::Online,
+            delist_time: None,
+        }
+    }
+
+    #[test]
+    fn exact_multiples_are_left_as_they_are_whatever_the_rounding() {
+        let pair_info = sol_usdc_pair_info();
+        for price in [PriceRounding::Passive, PriceRounding::Aggressive] {
+            for amount in [
+                AmountRounding::Down,
+                AmountRounding::Up,
+                AmountRounding::Exact,
+            ] {
+                for side in [OrderSide::Buy, OrderSide::Sell] {
+                    let rounding = OrderRounding { price, amount };
+                    for (order_price, order_amount) in [(142.35, 1.001), (0.07, 20.), (100., 0.3)] {
+                        assert_eq!(
+                            pair_info
+                                .round_order(side, order_price, order_amount, rounding)
+                                .unwrap(),
+                            (order_price, order_amount),
+                            "{side:?} {rounding:?}"
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inexact_values_round_in_the_direction_asked_for() {
+        use {AmountRounding::*, OrderSide::*, PriceRounding::*};
+
+        let pair_info = sol_usdc_pair_info();
+        for (side, price, amount, rounded) in [
+            (Buy, Passive, Down, (142.35, 1.001)),
+            (Sell, Passive, Down, (142.36, 1.001)),
+            (Buy, Aggressive, Up, (142.36, 1.002)),
+            (Sell, Aggressive, Up, (142.35, 1.002)),
+        ] {
+            let rounding = OrderRounding { price, amount };
+            assert_eq!(
+                pair_info
+                    .round_order(side, 142.355, 1.0015, rounding)
+                    .unwrap(),
+                rounded,
+                "{side:?} {rounding:?}"
+            );
+        }
+        let rounding = OrderRounding {
+            price: Passive,
+            amount: Exact,
+        };
+        assert!(pair_info
+            .round_order(Buy, 142.355, 1.0015, rounding)
+            .is_err());
+    }
+
+    #[test]
+    fn a_trade_at_2330_local_lands_on_the_day_of_each_time_zone() {
+        // 23:30 on March 4th at UTC-05:00 is 04:30 on March 5th in UTC
+        let local = "-05:00".parse::<ReportingTimeZone>().unwrap();
+        assert_eq!(local, ReportingTimeZone::Fixed(FixedOffset::west(5 * 3600)));
+        let trade_time = Utc.ymd(2024, 3, 5).and_hms(4, 30, 0);
+        let march_4 = NaiveDate::from_ymd(2024, 3, 4);
+        let march_5 = NaiveDate::from_ymd(2024, 3, 5);
+        let contains = |history: LendingHistory| {
+            let (start, end) = history.bounds();
+            start <= trade_time && trade_time < end
+        };
+
+        assert_eq!(local.date_of(trade_time), march_4);
+        assert!(contains(LendingHistory::days(march_4, march_4, local)));
+        assert!(!contains(LendingHistory::days(march_5, march_5, local)));
+
+        let utc = ReportingTimeZone::Utc;
+        assert_eq!(utc.date_of(trade_time), march_5);
+        assert!(!contains(LendingHistory::days(march_4, march_4, utc)));
+        assert!(contains(LendingHistory::days(march_5, march_5, utc)));
+    }
+
+    #[test]
+    fn exchange_proxies_are_parsed_per_exchange() {
+        let proxies =
+            parse_exchange_proxies("Kraken=socks5://localhost:1080, coinbase=none").unwrap();
+        assert_eq!(
+            proxies.get(&Exchange::Kraken),
+            Some(&ExchangeProxy::Url("socks5://localhost:1080".into()))
+        );
+        assert_eq!(
+            proxies.get(&Exchange::Coinbase),
+            Some(&ExchangeProxy::Direct)
+        );
+        assert_eq!(proxies.get(&Exchange::Binance), None);
+
+        assert!(parse_exchange_proxies("socks5://localhost:1080").is_err());
+        assert!(parse_exchange_proxies("kraken=localhost:1080").is_err());
+        assert!(parse_exchange_proxies("mtgox=none").is_err());
+    }
+
+    #[test]
+    fn withdrawal_fee_detail_totals_a_fee_on_top() {
+        let sol = MaybeToken::SOL();
+        assert_eq!(
+            withdrawal_fee_detail(Exchange::Coinbase, sol, 1_500_000_000, 0.25),
+            "◎0.25 on top of the amount, ◎1.75 debited in total"
+        );
+        assert_eq!(
+            withdrawal_fee_detail(Exchange::Kraken, sol, 1_500_000_000, 0.25),
+            "◎0.25, included in the amount"
+        );
+    }
 }
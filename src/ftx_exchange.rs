@@ -2,14 +2,16 @@ use {
     crate::{
         exchange::*,
         token::{MaybeToken, Token},
+        two_factor::TwoFactorCodeProvider,
     },
     async_trait::async_trait,
     chrono::{prelude::*, Duration},
     ftx::rest::{
         CancelOrder, GetFills, GetHistoricalPrices, GetLendingInfo, GetLendingRates, GetMarket,
-        GetMyLendingHistory, GetOrder, GetWalletBalances, GetWalletDeposits, GetWalletWithdrawals,
-        MyLendingHistory, OrderStatus as FtxOrderStatus, OrderType, PlaceOrder, RequestWithdrawal,
-        Rest, Side as FtxOrderSide, SubmitLendingOffer, WithdrawStatus,
+        GetMarkets, GetMyLendingHistory, GetOrder, GetWalletBalances, GetWalletDeposits,
+        GetWalletWithdrawals, MyLendingHistory, OrderStatus as FtxOrderStatus, OrderType,
+        PlaceOrder, RequestWithdrawal, Rest, Side as FtxOrderSide, SubmitLendingOffer,
+        WithdrawStatus,
     },
     rust_decimal::prelude::*,
     solana_sdk::pubkey::Pubkey,
@@ -19,24 +21,17 @@ use {
     },
 };
 
-pub struct FtxExchangeClient {
-    rest: Rest,
-}
+// Largest page of historical prices returned per request
+const HISTORICAL_PRICES_LIMIT: usize = 1500;
 
-fn binance_to_ftx_pair(binance_pair: &str) -> Result<&'static str, Box<dyn std::error::Error>> {
-    match binance_pair {
-        "SOLUSDT" => Ok("SOL/USDT"),
-        "SOLUSD" => Ok("SOL/USD"),
-        _ => Err(format!("Unknown pair: {binance_pair}").into()),
-    }
+/// Converts an hourly spot margin lending rate into an annual percentage
+fn hourly_to_annual_rate(rate: Decimal) -> f64 {
+    const HOURS_PER_YEAR: f64 = 24. * 356.;
+    rate.to_f64().unwrap() * HOURS_PER_YEAR * 100.
 }
 
-fn ftx_to_binance_pair(ftx_pair: &str) -> Result<&'static str, Box<dyn std::error::Error>> {
-    match ftx_pair {
-        "SOL/USDT" => Ok("SOLUSDT"),
-        "SOL/USD" => Ok("SOLUSD"),
-        _ => Err(format!("Unknown pair: {ftx_pair}").into()),
-    }
+pub struct FtxExchangeClient {
+    rest: Rest,
 }
 
 #[async_trait]
@@ -70,16 +65,17 @@ impl ExchangeClient for FtxExchangeClient {
             .map_err(|err| format!("{err:?}"))?;
 
         let mut balances = HashMap::new();
-        for coin in ["SOL"].iter().chain(USD_COINS) {
-            if let Some(balance) = wallet_balances.iter().find(|b| b.coin == *coin) {
-                balances.insert(
-                    coin.to_string(),
-                    ExchangeBalance {
-                        available: balance.available_without_borrow.to_f64().unwrap(),
-                        total: balance.total.to_f64().unwrap(),
-                    },
-                );
-            }
+        for balance in wallet_balances
+            .iter()
+            .filter(|b| b.coin == "SOL" || is_usd_equivalent(&b.coin))
+        {
+            balances.insert(
+                balance.coin.clone(),
+                ExchangeBalance {
+                    available: balance.available_without_borrow.to_f64().unwrap(),
+                    total: balance.total.to_f64().unwrap(),
+                },
+            );
         }
         Ok(balances)
     }
@@ -103,6 +99,8 @@ impl ExchangeClient for FtxExchangeClient {
                             return Some(DepositInfo {
                                 tx_id,
                                 amount: wd.size.unwrap().to_f64().unwrap(),
+                                token: None,
+                                time: None,
                             });
                         }
                     }
@@ -112,8 +110,12 @@ impl ExchangeClient for FtxExchangeClient {
         ))
     }
 
-    async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
-        Ok(self
+    async fn withdrawals(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
+        let mut withdrawals = self
             .rest
             .request(GetWalletWithdrawals {
                 limit: None,
@@ -138,6 +140,9 @@ impl ExchangeClient for FtxExchangeClient {
                             _ => (false, None),
                         };
 
+                        let time = DateTime::parse_from_rfc3339(&tag)
+                            .ok()
+                            .map(|time| time.with_timezone(&Utc));
                         return Some(WithdrawalInfo {
                             address,
                             token: token.into(),
@@ -145,25 +150,37 @@ impl ExchangeClient for FtxExchangeClient {
                             tag,
                             completed,
                             tx_id,
+                            time,
+                            fee: None,
+                            client_reference: None,
                         });
                     }
                 }
                 None
             })
-            .collect())
+            .filter(|wi| {
+                wi.time
+                    .map(|time| (start..end).contains(&time))
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<_>>();
+
+        sort_and_dedup_withdrawals(&mut withdrawals);
+        Ok(withdrawals)
     }
 
     async fn request_withdraw(
         &self,
         address: Pubkey,
         token: MaybeToken,
-        amount: f64,
+        amount: u64,
         password: Option<String>,
-        code: Option<String>,
+        code: Option<&dyn TwoFactorCodeProvider>,
+        _client_reference: &str,
     ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>
     {
         let coin = token.to_string();
-        let size = FromPrimitive::from_f64(amount).unwrap();
+        let size = format_native_amount(token, amount, self.withdrawal_decimals(token)).parse()?;
 
         let wd = self
             .rest
@@ -174,7 +191,7 @@ impl ExchangeClient for FtxExchangeClient {
                 tag: None,
                 method: Some("sol".into()),
                 password,
-                code,
+                code: code.map(|code| code.code()).transpose()?,
             })
             .await?;
 
@@ -186,10 +203,14 @@ impl ExchangeClient for FtxExchangeClient {
 
     async fn print_market_info(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         format: MarketInfoFormat,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let ftx_pair = binance_to_ftx_pair(pair)?;
+        if let MarketInfoFormat::Candles(range) = format {
+            return print_candles(range, &self.candles(pair, range).await?);
+        }
+
+        let ftx_pair = &pair.to_exchange_symbol(Exchange::Ftx);
 
         let hourly_prices = self
             .rest
@@ -233,61 +254,110 @@ impl ExchangeClient for FtxExchangeClient {
 
         match format {
             MarketInfoFormat::All => {
+                let tick_size = self
+                    .pair_info(pair)
+                    .await
+                    .ok()
+                    .and_then(|pair_info| pair_info.tick_size);
+                let price = |price: Decimal| {
+                    format_increment(price.to_f64().unwrap_or_default(), tick_size, true)
+                };
                 println!(
-                    "{} | Ask: ${:.2}, Bid: ${:.2}, Last: ${:.2}, 24hr Average: ${:.2}",
+                    "{} | Ask: ${}, Bid: ${}, Last: ${}, 24hr Average: ${}",
                     pair,
-                    market.ask.unwrap(),
-                    market.bid.unwrap(),
-                    market.last.unwrap_or_default(),
-                    weighted_24h_avg_price
+                    price(market.ask.unwrap()),
+                    price(market.bid.unwrap()),
+                    price(market.last.unwrap_or_default()),
+                    format_increment(weighted_24h_avg_price, tick_size, true)
                 );
             }
             MarketInfoFormat::Ask => {
                 println!("{}", market.ask.unwrap());
             }
-            MarketInfoFormat::Hourly => {
-                println!("hour,low,high,average,volume");
-                for p in &hourly_prices {
-                    println!(
-                        "{},{},{},{},{}",
-                        DateTime::<Local>::from(p.start_time),
-                        p.low,
-                        p.high,
-                        (p.low + p.high).to_f64().unwrap() / 2.,
-                        p.volume
-                    );
-                }
-            }
+            MarketInfoFormat::Candles(_) => unreachable!(),
             MarketInfoFormat::Weighted24hAveragePrice => {
                 println!("{weighted_24h_avg_price:.4}");
             }
+            MarketInfoFormat::Json => {
+                return Err("JSON market info not supported for FTX".into());
+            }
         }
 
         Ok(())
     }
 
-    async fn bid_ask(&self, pair: &str) -> Result<BidAsk, Box<dyn std::error::Error>> {
-        let pair = binance_to_ftx_pair(pair)?;
+    async fn candles(
+        &self,
+        pair: &TradingPair,
+        range: CandleRange,
+    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
+        range.validate(Exchange::Ftx, &[1, 5, 15, 60, 240, 1440])?;
+        let ftx_pair = &pair.to_exchange_symbol(Exchange::Ftx);
+
+        // Pages are fetched backwards from the most recent candle
+        let mut candles = vec![];
+        let mut end_time = None;
+        while candles.len() < range.count {
+            let limit = (range.count - candles.len()).min(HISTORICAL_PRICES_LIMIT);
+            let prices = self
+                .rest
+                .request(GetHistoricalPrices {
+                    market_name: ftx_pair,
+                    resolution: range.interval_minutes * 60,
+                    limit: Some(limit as u32),
+                    start_time: None,
+                    end_time,
+                })
+                .await
+                .map_err(|err| format!("{err:?}"))?;
+
+            let mut page = prices
+                .into_iter()
+                .map(|price| Candle {
+                    time: price.start_time,
+                    high: price.high.to_f64().unwrap(),
+                    low: price.low.to_f64().unwrap(),
+                    volume: price.volume.to_f64().unwrap(),
+                })
+                .collect::<Vec<_>>();
+            match page.first() {
+                Some(oldest) => end_time = Some(oldest.time - Duration::seconds(1)),
+                None => break,
+            }
+            let exhausted = page.len() < limit;
+            page.append(&mut candles);
+            candles = page;
+            if exhausted {
+                break;
+            }
+        }
+        Ok(candles)
+    }
+
+    async fn bid_ask(&self, pair: &TradingPair) -> Result<BidAsk, Box<dyn std::error::Error>> {
         let market = self
             .rest
-            .request(GetMarket::new(pair))
+            .request(GetMarket::new(&pair.to_exchange_symbol(Exchange::Ftx)))
             .await
             .map_err(|err| format!("{err:?}"))?;
 
         Ok(BidAsk {
             bid_price: market.bid.unwrap().to_f64().unwrap(),
             ask_price: market.ask.unwrap().to_f64().unwrap(),
+            bid_amount: None,
+            ask_amount: None,
         })
     }
 
     async fn place_order(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         side: OrderSide,
         price: f64,
         amount: f64,
+        client_order_id: &str,
     ) -> Result<OrderId, Box<dyn std::error::Error>> {
-        let pair = binance_to_ftx_pair(pair)?;
+        let pair = &pair.to_exchange_symbol(Exchange::Ftx);
         let side = match side {
             OrderSide::Buy => FtxOrderSide::Buy,
             OrderSide::Sell => FtxOrderSide::Sell,
@@ -303,7 +373,7 @@ impl ExchangeClient for FtxExchangeClient {
                 reduce_only: false,
                 ioc: false,
                 post_only: true,
-                client_id: None,
+                client_id: Some(client_order_id),
                 reject_on_price_band: false,
             })
             .await
@@ -314,7 +384,7 @@ impl ExchangeClient for FtxExchangeClient {
 
     async fn cancel_order(
         &self,
-        _pair: &str,
+        _pair: &TradingPair,
         order_id: &OrderId,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let order_id = order_id.parse()?;
@@ -331,7 +401,7 @@ impl ExchangeClient for FtxExchangeClient {
 
     async fn order_status(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         order_id: &OrderId,
     ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
         let order_id = order_id.parse()?;
@@ -347,7 +417,10 @@ impl ExchangeClient for FtxExchangeClient {
             FtxOrderSide::Buy => OrderSide::Buy,
         };
         assert_eq!(order_info.r#type, OrderType::Limit);
-        assert_eq!(pair, ftx_to_binance_pair(&order_info.market)?);
+        assert_eq!(
+            *pair,
+            TradingPair::from_exchange_symbol(Exchange::Ftx, &order_info.market)?
+        );
 
         // TODO: use `order_info.created_at` instead?
         let last_update = {
@@ -367,8 +440,10 @@ impl ExchangeClient for FtxExchangeClient {
 
         let mut fee = 0.;
         let mut fee_currency = None;
+        let mut quote_amount = 0.;
         for fill in fills {
             fee += fill.fee.to_f64().unwrap();
+            quote_amount += (fill.price * fill.size).to_f64().unwrap();
             if fee_currency.is_none() {
                 fee_currency = Some(fill.fee_currency);
             } else {
@@ -376,14 +451,21 @@ impl ExchangeClient for FtxExchangeClient {
             }
         }
 
+        let filled_amount = order_info.filled_size.unwrap_or_default().to_f64().unwrap();
+        let (average_fill_price, cumulative_quote_amount) =
+            fill_totals(filled_amount, quote_amount);
+
         Ok(OrderStatus {
             open: order_info.status != FtxOrderStatus::Closed,
             side,
             price: order_info.price.unwrap_or_default().to_f64().unwrap(),
             amount: order_info.size.to_f64().unwrap(),
-            filled_amount: order_info.filled_size.unwrap_or_default().to_f64().unwrap(),
+            filled_amount,
+            average_fill_price,
+            cumulative_quote_amount,
             last_update,
             fee: fee_currency.map(|fee_currency| (fee, fee_currency)),
+            cancellation: None,
         })
     }
 
@@ -401,7 +483,6 @@ impl ExchangeClient for FtxExchangeClient {
             .find(|rate| rate.coin == coin)
             .ok_or_else(|| format!("No lending rate available for {coin}"))?;
 
-        const HOURS_PER_YEAR: f64 = 24. * 356.;
         Ok(lending_info
             .iter()
             .find(|lending_info| lending_info.coin == *coin)
@@ -409,38 +490,59 @@ impl ExchangeClient for FtxExchangeClient {
                 lendable: lending_info.lendable.to_f64().unwrap(),
                 locked: lending_info.locked.to_f64().unwrap(),
                 offered: lending_info.offered.to_f64().unwrap(),
-                estimate_rate: lending_rate.estimate.to_f64().unwrap() * HOURS_PER_YEAR * 100.,
-                previous_rate: lending_rate.previous.unwrap_or_default().to_f64().unwrap()
-                    * HOURS_PER_YEAR
-                    * 100.,
+                estimate_rate: hourly_to_annual_rate(lending_rate.estimate),
+                previous_rate: hourly_to_annual_rate(lending_rate.previous.unwrap_or_default()),
             }))
     }
 
+    async fn all_lending_info(
+        &self,
+    ) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>> {
+        let lending_info = self
+            .rest
+            .request(GetLendingInfo {})
+            .await
+            .map_err(|err| format!("{err:?}"))?;
+        let lending_rates = self
+            .rest
+            .request(GetLendingRates {})
+            .await
+            .map_err(|err| format!("{err:?}"))?;
+
+        Ok(lending_info
+            .into_iter()
+            .filter(|lending_info| {
+                !(lending_info.lendable.is_zero()
+                    && lending_info.offered.is_zero()
+                    && lending_info.locked.is_zero())
+            })
+            .filter_map(|lending_info| {
+                let lending_rate = lending_rates
+                    .iter()
+                    .find(|rate| rate.coin == lending_info.coin)?;
+                Some((
+                    lending_info.coin,
+                    LendingInfo {
+                        lendable: lending_info.lendable.to_f64().unwrap(),
+                        locked: lending_info.locked.to_f64().unwrap(),
+                        offered: lending_info.offered.to_f64().unwrap(),
+                        estimate_rate: hourly_to_annual_rate(lending_rate.estimate),
+                        previous_rate: hourly_to_annual_rate(
+                            lending_rate.previous.unwrap_or_default(),
+                        ),
+                    },
+                ))
+            })
+            .collect())
+    }
+
     async fn get_lending_history(
         &self,
         lending_history: LendingHistory,
     ) -> Result<HashMap<String, f64>, Box<dyn std::error::Error>> {
         let one_day = Duration::days(1);
 
-        let (mut start_time, end_time) = match lending_history {
-            LendingHistory::Range {
-                start_date,
-                end_date,
-            } => {
-                let start_time = Local.from_local_date(&start_date).unwrap().and_hms(0, 0, 0);
-                let end_time = Local
-                    .from_local_date(&end_date)
-                    .unwrap()
-                    .and_hms(23, 59, 59);
-                (start_time, end_time)
-            }
-            LendingHistory::Previous { days } => {
-                let end_time = Local::now().date().and_hms(0, 0, 0) + one_day;
-                let start_time = end_time - one_day * days as i32;
-
-                (start_time, end_time - Duration::seconds(1))
-            }
-        };
+        let (mut start_time, end_time) = lending_history.bounds();
 
         println!("Start date: {start_time}");
         println!("End date:   {end_time}");
@@ -485,8 +587,36 @@ impl ExchangeClient for FtxExchangeClient {
         Ok(())
     }
 
-    fn preferred_solusd_pair(&self) -> &'static str {
-        "SOLUSD"
+    fn preferred_solusd_pair(&self) -> TradingPair {
+        TradingPair::new("SOL", "USD")
+    }
+
+    fn maker_fee_rate(&self) -> f64 {
+        0.0002
+    }
+
+    fn taker_fee_rate(&self) -> f64 {
+        0.0007
+    }
+
+    async fn pair_infos(&self) -> Result<Vec<PairInfo>, Box<dyn std::error::Error>> {
+        Ok(self
+            .rest
+            .request(GetMarkets {})
+            .await
+            .map_err(|err| format!("{err:?}"))?
+            .into_iter()
+            .filter_map(|market| {
+                Some(PairInfo {
+                    pair: TradingPair::from_exchange_symbol(Exchange::Ftx, &market.name).ok()?,
+                    tick_size: market.price_increment.to_f64(),
+                    step_size: market.size_increment.to_f64(),
+                    min_notional: None,
+                    status: SystemStatus::Online,
+                    delist_time: None,
+                })
+            })
+            .collect())
     }
 }
 
@@ -496,7 +626,9 @@ pub fn new(
         secret,
         subaccount,
     }: ExchangeCredentials,
+    options: ExchangeClientOptions,
 ) -> Result<FtxExchangeClient, Box<dyn std::error::Error>> {
+    options.check_supported(Exchange::Ftx, &[])?;
     Ok(FtxExchangeClient {
         rest: Rest::new(ftx::options::Options {
             endpoint: ftx::options::Endpoint::Com,
@@ -513,7 +645,9 @@ pub fn new_us(
         secret,
         subaccount,
     }: ExchangeCredentials,
+    options: ExchangeClientOptions,
 ) -> Result<FtxExchangeClient, Box<dyn std::error::Error>> {
+    options.check_supported(Exchange::FtxUs, &[])?;
     Ok(FtxExchangeClient {
         rest: Rest::new(ftx::options::Options {
             endpoint: ftx::options::Endpoint::Us,
@@ -1,25 +1,447 @@
 use {
-    crate::{exchange::*, token::MaybeToken},
+    crate::{
+        debug_capture::{CapturedRequest, CapturedResponse, DebugCapture},
+        exchange::*,
+        rate_limit::RateLimiter,
+        request_metrics,
+        stream_session::{StreamSession, StreamSessionStore},
+        token::{MaybeToken, Token},
+        two_factor::TwoFactorCodeProvider,
+    },
     async_trait::async_trait,
     chrono::prelude::*,
-    kraken_sdk_rest::Client,
-    rust_decimal::prelude::*,
+    hmac::{Hmac, Mac},
+    serde::{de::DeserializeOwned, Deserialize},
+    sha2::{Digest, Sha256, Sha512},
     solana_sdk::pubkey::Pubkey,
-    std::collections::HashMap,
+    std::{
+        collections::HashMap,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::Instant,
+    },
+    tokio::sync::{Mutex, OnceCell},
 };
 
+const API_URL: &str = "https://api.kraken.com";
+
+// Kraken rejects a signed request whose nonce isn't greater than the last one it saw for the API
+// key, so every signed request takes its nonce from this one source and is sent while holding
+// `SIGNED_REQUEST_LOCK`, so that nonces also arrive in the order they were issued
+static LAST_NONCE: AtomicU64 = AtomicU64::new(0);
+
+lazy_static::lazy_static! {
+    static ref SIGNED_REQUEST_LOCK: Mutex<()> = Mutex::default();
+}
+
+fn clock_nonce() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
+
+// The clock in milliseconds, or one past the last nonce if the clock hasn't moved past it
+fn next_nonce() -> u64 {
+    let now = clock_nonce();
+    let mut nonce = now;
+    LAST_NONCE
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            nonce = now.max(last + 1);
+            Some(nonce)
+        })
+        .unwrap();
+    nonce
+}
+
 pub struct KrakenExchangeClient {
-    client: Client,
+    base_url: String,
+    http_client: reqwest::Client,
+    api_key: String,
+    secret: String,
+    pair_infos: OnceCell<Vec<PairInfo>>,
+    debug_capture: Option<DebugCapture>,
+    // Paces private API calls, which share one call counter per API key
+    rate_limiter: Arc<RateLimiter>,
+    stream_session: Arc<StreamSessionStore>,
+}
+
+#[derive(Deserialize)]
+struct KrakenResponse<T> {
+    error: Vec<String>,
+    result: Option<T>,
+}
+
+#[derive(Deserialize)]
+struct EarnStrategies {
+    items: Vec<EarnStrategy>,
+}
+
+#[derive(Deserialize)]
+struct EarnStrategy {
+    id: String,
+    asset: String,
+    lock_type: EarnLockType,
+    apr_estimate: Option<EarnAprEstimate>,
+    #[serde(default)]
+    can_allocate: bool,
+    #[serde(default)]
+    can_deallocate: bool,
+}
+
+#[derive(Deserialize)]
+struct EarnLockType {
+    #[serde(rename = "type")]
+    lock_type: String,
+}
+
+#[derive(Deserialize)]
+struct EarnAprEstimate {
+    low: String,
+    high: String,
+}
+
+#[derive(Deserialize)]
+struct EarnAllocations {
+    items: Vec<EarnAllocation>,
+}
+
+#[derive(Deserialize)]
+struct EarnAllocation {
+    strategy_id: String,
+    amount_allocated: EarnAllocatedAmount,
+    total_rewarded: EarnAmount,
+}
+
+#[derive(Deserialize)]
+struct EarnAllocatedAmount {
+    total: EarnAmount,
+}
+
+#[derive(Deserialize)]
+struct EarnAmount {
+    native: String,
+}
+
+// Earn strategies whose allocations can be deallocated at any time
+const LIQUID_EARN_LOCK_TYPES: &[&str] = &["flex", "instant"];
+
+// Earn strategies stand in for lending: what's allocated is offered, and locked too when the
+// strategy is bonded or timed.  An asset's rate is the best of its strategies.  Kraken publishes no
+// previous rate, so the estimate stands in for it
+fn earn_lending_info(
+    strategies: Vec<EarnStrategy>,
+    allocations: &[EarnAllocation],
+    balances: &HashMap<String, ExchangeBalance>,
+) -> HashMap<String, LendingInfo> {
+    let mut all_lending_info = HashMap::<String, LendingInfo>::new();
+    for strategy in strategies {
+        let asset = normalize_kraken_asset(&strategy.asset).to_string();
+        let allocated = allocations
+            .iter()
+            .find(|allocation| allocation.strategy_id == strategy.id)
+            .and_then(|allocation| allocation.amount_allocated.total.native.parse::<f64>().ok())
+            .unwrap_or_default();
+        let rate = strategy
+            .apr_estimate
+            .as_ref()
+            .and_then(|apr_estimate| apr_estimate.high.parse::<f64>().ok())
+            .unwrap_or_default();
+
+        let lending_info = all_lending_info
+            .entry(asset.clone())
+            .or_insert_with(|| LendingInfo {
+                lendable: balances
+                    .get(&asset)
+                    .map(|balance| balance.available)
+                    .unwrap_or_default(),
+                offered: 0.,
+                locked: 0.,
+                estimate_rate: 0.,
+                previous_rate: 0.,
+            });
+        lending_info.offered += allocated;
+        if !LIQUID_EARN_LOCK_TYPES.contains(&strategy.lock_type.lock_type.as_str()) {
+            lending_info.locked += allocated;
+        }
+        lending_info.estimate_rate = lending_info.estimate_rate.max(rate);
+        lending_info.previous_rate = lending_info.estimate_rate;
+    }
+    all_lending_info
+        .retain(|_, lending_info| lending_info.lendable > 0. || lending_info.offered > 0.);
+    all_lending_info
+}
+
+#[derive(Deserialize)]
+struct EarnOperationStatus {
+    pending: bool,
+}
+
+#[derive(Deserialize)]
+struct Ledgers {
+    ledger: HashMap<String, KrakenLedgerEntry>,
+}
+
+#[derive(Deserialize)]
+struct KrakenLedgerEntry {
+    refid: String,
+    time: f64,
+    #[serde(rename = "type")]
+    entry_type: String,
+    #[serde(default)]
+    subtype: String,
+    asset: String,
+    amount: String,
+    fee: String,
 }
 
-fn normalize_coin_name(kraken_coin: &str) -> &str {
-    if kraken_coin == "ZUSD" {
-        "USD"
+#[derive(Deserialize)]
+struct KrakenAddOrder {
+    txid: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct KrakenOpenOrders {
+    open: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct KrakenClosedOrders {
+    closed: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct KrakenOrderHistory {
+    closed: HashMap<String, KrakenOrderInfo>,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct KrakenOpenOrderHistory {
+    open: HashMap<String, KrakenOrderInfo>,
+}
+
+#[derive(Deserialize)]
+struct KrakenOrderInfo {
+    status: String,
+    opentm: f64,
+    closetm: Option<f64>,
+    cl_ord_id: Option<String>,
+    descr: KrakenOrderDescription,
+    vol: String,
+    vol_exec: String,
+    cost: String,
+    fee: String,
+    price: String,
+    // Why a closed order was cancelled or expired
+    reason: Option<String>,
+}
+
+impl KrakenOrderInfo {
+    // Cancels through the API or the website are reported as "User requested", anything else is
+    // Kraken's own doing
+    fn cancellation(&self) -> Option<OrderCancellation> {
+        if !["canceled", "expired"].contains(&self.status.as_str()) {
+            return None;
+        }
+        Some(match self.reason.as_deref() {
+            None | Some("User requested") => OrderCancellation::User,
+            Some(reason) => OrderCancellation::Exchange {
+                reason: reason.to_string(),
+            },
+        })
+    }
+}
+
+// How an order placed by `add_limit_order` meets the book
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LimitOrderKind {
+    // Rejected rather than taking liquidity
+    PostOnly,
+    // Takes what liquidity it can, and the unfilled remainder is cancelled
+    ImmediateOrCancel,
+}
+
+#[derive(Deserialize)]
+struct KrakenOrderDescription {
+    pair: String,
+    #[serde(rename = "type")]
+    side: String,
+    ordertype: String,
+    price: String,
+}
+
+#[derive(Deserialize)]
+struct KrakenTradeVolume {
+    volume: String,
+    #[serde(default)]
+    fees: HashMap<String, KrakenTierFee>,
+    #[serde(default)]
+    fees_maker: HashMap<String, KrakenTierFee>,
+}
+
+// Rates are percentages
+#[derive(Deserialize)]
+struct KrakenTierFee {
+    fee: String,
+    nextfee: Option<String>,
+    nextvolume: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KrakenWebSocketsToken {
+    token: String,
+    // Seconds the token has to be used to open a connection
+    expires: i64,
+}
+
+#[derive(Deserialize)]
+struct KrakenDepositAddress {
+    address: String,
+}
+
+#[derive(Deserialize)]
+struct KrakenDepositStatus {
+    status: String,
+    txid: String,
+    amount: String,
+}
+
+#[derive(Deserialize)]
+struct KrakenFundingMethod {
+    method: String,
+    minimum: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct KrakenWithdrawAddress {
+    address: String,
+    key: String,
+    verified: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct KrakenWithdrawInfo {
+    fee: String,
+}
+
+#[derive(Deserialize)]
+struct KrakenWithdrawRefId {
+    refid: String,
+}
+
+#[derive(Deserialize)]
+struct KrakenWithdrawStatus {
+    refid: String,
+    txid: Option<String>,
+    info: String,
+    amount: String,
+    fee: String,
+    time: i64,
+    status: String,
+    #[serde(rename = "status-prop")]
+    status_prop: Option<String>,
+}
+
+// Maps a Kraken ledger asset code to its common symbol, and whether it is a staked variant
+fn normalize_ledger_asset(code: &str) -> (&str, bool) {
+    match kraken_staked_asset(code) {
+        Some(asset) => (normalize_kraken_asset(asset), true),
+        None => (normalize_kraken_asset(code), false),
+    }
+}
+
+// The account history a ledger entry kind belongs to, `None` for moves within the account such
+// as allocations to and from staking
+fn ledger_history_category(kind: &str) -> Option<HistoryCategory> {
+    let (entry_type, subtype) = kind.split_once('/').unwrap_or((kind, ""));
+    match (entry_type, subtype) {
+        ("deposit", _) => Some(HistoryCategory::Deposits),
+        ("withdrawal", _) => Some(HistoryCategory::Withdrawals),
+        ("trade", _) => Some(HistoryCategory::Trades),
+        // Buy Crypto and convert, which debit one asset and credit another outside of the book
+        ("spend" | "receive" | "conversion", _) => Some(HistoryCategory::Conversions),
+        ("staking" | "reward" | "dividend", _) | ("earn", "reward") => {
+            Some(HistoryCategory::Interest)
+        }
+        _ => None,
+    }
+}
+
+// Errors are reported as "<severity><category>:<message>" strings, such as
+// "EGeneral:Invalid arguments". The first error's code is used for the whole response
+// Only SOL quoted in a fiat currency can be traded, as `KrakenExchangeClient::balances()` only
+// accounts for open orders in those markets
+fn is_tradable_pair(pair: &TradingPair) -> bool {
+    pair.base == "SOL" && is_fiat(&pair.quote)
+}
+
+fn check_tradable_pair(pair: &TradingPair) -> Result<(), Box<dyn std::error::Error>> {
+    if is_tradable_pair(pair) {
+        Ok(())
     } else {
-        kraken_coin
+        Err(format!("Unsupported trading pair: {pair}").into())
     }
 }
 
+fn parse_errors(errors: &[String]) -> (String, String) {
+    let code = errors
+        .first()
+        .and_then(|error| error.split_once(':'))
+        .map(|(code, _)| code.to_string())
+        .unwrap_or_else(|| "unknown".into());
+    let message = errors
+        .iter()
+        .map(|error| {
+            error
+                .split_once(':')
+                .map(|(_, message)| message)
+                .unwrap_or(error)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    (code, message)
+}
+
+#[derive(Debug, Deserialize)]
+struct KrakenStatus {
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct KrakenAssetPair {
+    base: String,
+    quote: String,
+    pair_decimals: i32,
+    lot_decimals: i32,
+    status: Option<String>,
+}
+
+// Each side's levels are [price, volume, timestamp]
+#[derive(Deserialize)]
+struct KrakenOrderBook {
+    asks: Vec<(String, String, i64)>,
+    bids: Vec<(String, String, i64)>,
+}
+
+// The exchange and each pair report one of the same set of statuses
+fn parse_system_status(status: &str) -> SystemStatus {
+    match status {
+        "post_only" => SystemStatus::PostOnly,
+        "cancel_only" => SystemStatus::CancelOnly,
+        "maintenance" => SystemStatus::Maintenance,
+        // "limit_only" and "reduce_only" don't restrict the limit orders placed here
+        _ => SystemStatus::Online,
+    }
+}
+
+// Supported OHLC intervals, in minutes
+const OHLC_INTERVALS: &[u32] = &[1, 5, 15, 30, 60, 240, 1440, 10080, 21600];
+
+// Only this many of the most recent candles are served
+const OHLC_LIMIT: usize = 720;
+
+// Solana network funding methods, used for both deposits and withdrawals
 fn deposit_methods() -> HashMap</*coin: */ &'static str, /* method: */ &'static str> {
     HashMap::from([
         ("SOL", "Solana"),
@@ -28,21 +450,382 @@ fn deposit_methods() -> HashMap</*coin: */ &'static str, /* method: */ &'static
     ])
 }
 
+impl KrakenExchangeClient {
+    // Public endpoints are idempotent, so a request that times out is sent again
+    async fn public_request<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        query: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        request_metrics::instrument(
+            Exchange::Kraken,
+            endpoint,
+            self.send_public_request(endpoint, query),
+        )
+        .await
+    }
+
+    async fn send_public_request<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        query: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let url = format!("{}/0/public/{endpoint}?{query}", self.base_url);
+        let body = retry_idempotent(|| async {
+            let response = self
+                .http_client
+                .get(&url)
+                .send()
+                .await
+                .map_err(|err| request_error(Exchange::Kraken, endpoint, err))?;
+            response
+                .text()
+                .await
+                .map_err(|err| request_error(Exchange::Kraken, endpoint, err))
+        })
+        .await?;
+
+        let response = serde_json::from_str::<KrakenResponse<T>>(&body)?;
+        if !response.error.is_empty() {
+            let (code, message) = parse_errors(&response.error);
+            return Err(ExchangeError::Api {
+                exchange: Exchange::Kraken,
+                endpoint: endpoint.into(),
+                http_status: None,
+                code,
+                message,
+            }
+            .into());
+        }
+        Ok(response
+            .result
+            .ok_or_else(|| format!("{endpoint}: empty response"))?)
+    }
+
+    async fn private_request<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        params: &[(&str, String)],
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        // History queries count double against the call counter, and can be slow to answer
+        let history = ["Ledgers", "QueryLedgers", "TradesHistory"].contains(&endpoint);
+        self.rate_limiter.acquire(if history { 2 } else { 1 }).await;
+        request_metrics::instrument(
+            Exchange::Kraken,
+            endpoint,
+            self.send_private_request(endpoint, params, history),
+        )
+        .await
+    }
+
+    async fn send_private_request<T: DeserializeOwned>(
+        &self,
+        endpoint: &str,
+        params: &[(&str, String)],
+        history: bool,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let path = format!("/0/private/{endpoint}");
+        let _signed_request = SIGNED_REQUEST_LOCK.lock().await;
+        let nonce = next_nonce().to_string();
+
+        let mut form = vec![("nonce", nonce.clone())];
+        form.extend(params.iter().cloned());
+        let postdata = serde_urlencoded::to_string(&form)?;
+
+        let mut mac = Hmac::<Sha512>::new_from_slice(&base64::decode(&self.secret)?)
+            .map_err(|err| format!("Invalid Kraken secret: {err}"))?;
+        mac.update(path.as_bytes());
+        mac.update(&Sha256::digest(format!("{nonce}{postdata}").as_bytes()));
+        let signature = base64::encode(mac.finalize().into_bytes());
+
+        let url = format!("{}{path}", self.base_url);
+        let started = Instant::now();
+        let mut request = self
+            .http_client
+            .post(&url)
+            .header("API-Key", &self.api_key)
+            .header("API-Sign", &signature)
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(postdata.clone());
+        if history {
+            request = request.timeout(HISTORY_REQUEST_TIMEOUT);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|err| request_error(Exchange::Kraken, endpoint, err))?;
+        let http_status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|err| request_error(Exchange::Kraken, endpoint, err))?;
+
+        if let Some(debug_capture) = &self.debug_capture {
+            debug_capture.record(
+                &CapturedRequest {
+                    method: "POST",
+                    url: &url,
+                    headers: &[
+                        ("API-Key", self.api_key.as_str()),
+                        ("API-Sign", signature.as_str()),
+                        ("Content-Type", "application/x-www-form-urlencoded"),
+                    ],
+                    body: &postdata,
+                },
+                &CapturedResponse {
+                    status: http_status.as_u16(),
+                    body: &body,
+                    latency: started.elapsed(),
+                },
+            );
+        }
+
+        let response = match serde_json::from_str::<KrakenResponse<T>>(&body) {
+            Ok(response) => response,
+            Err(_) if !http_status.is_success() => {
+                return Err(ExchangeError::Api {
+                    exchange: Exchange::Kraken,
+                    endpoint: endpoint.into(),
+                    http_status: Some(http_status.as_u16()),
+                    code: "unknown".into(),
+                    message: body,
+                }
+                .into())
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if !response.error.is_empty() {
+            let (code, message) = parse_errors(&response.error);
+            return Err(ExchangeError::Api {
+                exchange: Exchange::Kraken,
+                endpoint: endpoint.into(),
+                http_status: (!http_status.is_success()).then(|| http_status.as_u16()),
+                code,
+                message,
+            }
+            .into());
+        }
+        response
+            .result
+            .ok_or_else(|| format!("{endpoint}: empty response").into())
+    }
+
+    // Ledger entries within the time range, oldest first.
+    //
+    // The `ofs` offset shifts as new entries arrive, so instead page backwards by passing the oldest
+    // ledger id seen as the next (inclusive) `end`
+    async fn ledger_entries(
+        &self,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<(String, KrakenLedgerEntry)>, Box<dyn std::error::Error>> {
+        let mut entries = HashMap::<String, KrakenLedgerEntry>::new();
+        let mut page_end = end_time.timestamp().to_string();
+
+        loop {
+            let ledgers = self
+                .private_request::<Ledgers>(
+                    "Ledgers",
+                    &[
+                        ("start", start_time.timestamp().to_string()),
+                        ("end", page_end.clone()),
+                    ],
+                )
+                .await?;
+
+            let mut oldest = None::<(f64, String)>;
+            let mut new_entries = 0;
+            for (id, entry) in ledgers.ledger {
+                if oldest.as_ref().map_or(true, |(time, _)| entry.time < *time) {
+                    oldest = Some((entry.time, id.clone()));
+                }
+                if entries.insert(id, entry).is_none() {
+                    new_entries += 1;
+                }
+            }
+
+            match oldest {
+                Some((_, id)) if new_entries > 0 => page_end = id,
+                _ => break,
+            }
+        }
+
+        let mut entries = entries.into_iter().collect::<Vec<_>>();
+        entries.sort_by(|(_, a), (_, b)| a.time.total_cmp(&b.time));
+        Ok(entries)
+    }
+
+    // Earn strategies for `coin`, or for every asset when `None`
+    async fn earn_strategies(
+        &self,
+        coin: Option<&str>,
+    ) -> Result<Vec<EarnStrategy>, Box<dyn std::error::Error>> {
+        let params = coin
+            .map(|coin| vec![("asset", coin.to_string())])
+            .unwrap_or_default();
+        Ok(self
+            .private_request::<EarnStrategies>("Earn/Strategies", &params)
+            .await?
+            .items
+            .into_iter()
+            .filter(|strategy| coin.map_or(true, |coin| strategy.asset == coin))
+            .collect())
+    }
+
+    async fn earn_allocations(&self) -> Result<Vec<EarnAllocation>, Box<dyn std::error::Error>> {
+        Ok(self
+            .private_request::<EarnAllocations>(
+                "Earn/Allocations",
+                &[("hide_zero_allocations", "true".into())],
+            )
+            .await?
+            .items)
+    }
+
+    async fn earn_operation_pending(
+        &self,
+        operation: StakingOperation,
+        strategy_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let endpoint = match operation {
+            StakingOperation::Allocate => "Earn/AllocateStatus",
+            StakingOperation::Deallocate => "Earn/DeallocateStatus",
+        };
+        Ok(self
+            .private_request::<EarnOperationStatus>(
+                endpoint,
+                &[("strategy_id", strategy_id.into())],
+            )
+            .await?
+            .pending)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn add_limit_order(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        kind: LimitOrderKind,
+        client_order_id: &str,
+        expiry: Option<DateTime<Utc>>,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        let mut params = vec![
+            ("ordertype", "limit".to_string()),
+            (
+                "type",
+                match side {
+                    OrderSide::Buy => "buy",
+                    OrderSide::Sell => "sell",
+                }
+                .to_string(),
+            ),
+            ("volume", amount.to_string()),
+            ("price", price.to_string()),
+            ("pair", pair.to_exchange_symbol(Exchange::Kraken)),
+            ("cl_ord_id", client_order_id.to_string()),
+        ];
+        match kind {
+            LimitOrderKind::PostOnly => params.push(("oflags", "post".to_string())),
+            LimitOrderKind::ImmediateOrCancel => params.push(("timeinforce", "IOC".to_string())),
+        }
+        if let Some(expiry) = expiry {
+            params.push(("expiretm", expiry.timestamp().to_string()));
+        }
+        let response = self
+            .private_request::<KrakenAddOrder>("AddOrder", &params)
+            .await?;
+
+        let txid = response.txid;
+        assert_eq!(txid.len(), 1);
+        Ok(txid[0].to_owned())
+    }
+}
+
 #[async_trait]
 impl ExchangeClient for KrakenExchangeClient {
+    async fn supported_tokens(&self) -> Result<Vec<MaybeToken>, Box<dyn std::error::Error>> {
+        let mut supported_tokens = vec![];
+        for coin in deposit_methods().keys() {
+            let token = if *coin == "SOL" {
+                MaybeToken::SOL()
+            } else {
+                Token::from_str(coin)?.into()
+            };
+            let token_support = self.supports_token(token).await?;
+            if token_support.deposits_enabled || token_support.withdrawals_enabled {
+                supported_tokens.push(token);
+            }
+        }
+        Ok(supported_tokens)
+    }
+
+    async fn supports_token(
+        &self,
+        token: MaybeToken,
+    ) -> Result<TokenSupport, Box<dyn std::error::Error>> {
+        let asset = token.name();
+        let method = match deposit_methods().get(asset) {
+            Some(method) => *method,
+            None => return Ok(TokenSupport::default()),
+        };
+
+        let has_method = |methods: Vec<KrakenFundingMethod>| {
+            methods
+                .iter()
+                .any(|funding_method| funding_method.method == method)
+        };
+        Ok(TokenSupport {
+            listed: true,
+            deposits_enabled: has_method(
+                self.private_request("DepositMethods", &[("asset", asset.into())])
+                    .await?,
+            ),
+            withdrawals_enabled: has_method(
+                self.private_request("WithdrawMethods", &[("asset", asset.into())])
+                    .await?,
+            ),
+        })
+    }
+
+    async fn deposit_minimum(
+        &self,
+        token: MaybeToken,
+    ) -> Result<Option<f64>, Box<dyn std::error::Error>> {
+        let asset = token.name();
+        let method = match deposit_methods().get(asset) {
+            Some(method) => *method,
+            None => return Ok(None),
+        };
+
+        let methods: Vec<KrakenFundingMethod> = self
+            .private_request("DepositMethods", &[("asset", asset.into())])
+            .await?;
+        Ok(methods
+            .into_iter()
+            .find(|funding_method| funding_method.method == method)
+            .and_then(|funding_method| funding_method.minimum)
+            .and_then(|minimum| minimum.parse::<f64>().ok()))
+    }
+
     async fn deposit_address(
         &self,
         token: MaybeToken,
     ) -> Result<Pubkey, Box<dyn std::error::Error>> {
-        let deposit_method = *deposit_methods().get(token.name()).ok_or_else(|| {
-            //dbg!(self.client.get_deposit_methods(token.to_string()).send().await?);
-            format!("Unsupported deposit token: {}", token.name())
-        })?;
+        let deposit_method = *deposit_methods()
+            .get(token.name())
+            .ok_or_else(|| format!("Unsupported deposit token: {}", token.name()))?;
 
         let deposit_addresses = self
-            .client
-            .get_deposit_addresses(token.to_string(), deposit_method)
-            .send()
+            .private_request::<Vec<KrakenDepositAddress>>(
+                "DepositAddresses",
+                &[
+                    ("asset", token.to_string()),
+                    ("method", deposit_method.into()),
+                ],
+            )
             .await?;
 
         assert_eq!(deposit_addresses.len(), 1); // TODO: Consider what to do with multiple deposit addresses
@@ -53,68 +836,71 @@ impl ExchangeClient for KrakenExchangeClient {
     async fn balances(
         &self,
     ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
-        //dbg!(self.client.get_open_orders().send().await?);
-        let open_orders = self.client.get_open_orders().send().await?;
+        let open_orders = self
+            .private_request::<KrakenOpenOrderHistory>("OpenOrders", &[])
+            .await?;
 
-        // TODO: Generalize the `in_order_sol`/`in_order_usd` handling to all coins held by the
-        // account
-        let mut in_order_sol = 0.;
-        let mut in_order_usd = 0.;
+        // Amount of each asset held by open orders, keyed by its common symbol
+        let mut in_order = HashMap::<String, f64>::new();
 
         for open_order in open_orders.open.values() {
             assert_eq!(open_order.status, "open"); // TODO: What other statuses are valid, if any?
-            if open_order.descr.pair == self.preferred_solusd_pair() {
-                let vol = open_order
-                    .vol
-                    .parse::<f64>()
-                    .map_err(|err| format!("Invalid open order `vol` field: {err}"))?;
-                let price = open_order
-                    .descr
-                    .price
-                    .parse::<f64>()
-                    .map_err(|err| format!("Invalid open order `descr.price` field: {err}"))?;
-                if open_order.descr.orderside == "sell" {
-                    in_order_sol += vol
-                } else {
-                    in_order_usd += vol * price;
-                }
+            let order_pair =
+                match TradingPair::from_exchange_symbol(Exchange::Kraken, &open_order.descr.pair) {
+                    Ok(order_pair) if is_tradable_pair(&order_pair) => order_pair,
+                    _ => continue,
+                };
+            let vol = open_order
+                .vol
+                .parse::<f64>()
+                .map_err(|err| format!("Invalid open order `vol` field: {err}"))?;
+            let price = open_order
+                .descr
+                .price
+                .parse::<f64>()
+                .map_err(|err| format!("Invalid open order `descr.price` field: {err}"))?;
+            if open_order.descr.side == "sell" {
+                *in_order.entry(order_pair.base).or_default() += vol;
+            } else {
+                *in_order.entry(order_pair.quote).or_default() += vol * price;
             }
         }
 
-        Ok(self
-            .client
-            .get_account_balance()
-            .send()
-            .await?
+        let account_balance = self
+            .private_request::<HashMap<String, String>>("Balance", &[])
+            .await?;
+        let staked_sol = account_balance
+            .iter()
+            .filter(|(coin, _)| kraken_staked_asset(coin) == Some("SOL"))
+            .filter_map(|(_, balance)| balance.parse::<f64>().ok())
+            .sum::<f64>();
+
+        let mut balances = account_balance
             .into_iter()
             .filter_map(|(coin, balance)| {
-                balance
-                    .parse::<f64>()
-                    .ok()
-                    .and_then(|balance| match coin.as_str() {
-                        "SOL" => {
-                            assert!(balance >= in_order_sol);
-                            Some(ExchangeBalance {
-                                total: balance,
-                                available: balance - in_order_sol,
-                            })
-                        }
-                        "USDC" => Some(ExchangeBalance {
+                balance.parse::<f64>().ok().and_then(|balance| {
+                    let asset = normalize_kraken_asset(&coin);
+                    if asset != "SOL" && asset != "USDC" && !is_fiat(asset) {
+                        return None;
+                    }
+                    let in_order = in_order.get(asset).copied().unwrap_or_default();
+                    assert!(balance >= in_order);
+                    Some((
+                        asset.to_string(),
+                        ExchangeBalance {
                             total: balance,
-                            available: balance,
-                        }),
-                        "ZUSD" => {
-                            assert!(balance >= in_order_usd);
-                            Some(ExchangeBalance {
-                                total: balance,
-                                available: balance - in_order_usd,
-                            })
-                        }
-                        _ => None,
-                    })
-                    .map(|exchange_balance| (normalize_coin_name(&coin).into(), exchange_balance))
+                            available: balance - in_order,
+                        },
+                    ))
+                })
             })
-            .collect())
+            .collect::<HashMap<String, ExchangeBalance>>();
+
+        // Staked SOL is not available for trading or withdrawal
+        if staked_sol > 0. {
+            balances.entry("SOL".into()).or_default().total += staked_sol;
+        }
+        Ok(balances)
     }
 
     async fn recent_deposits(
@@ -123,12 +909,25 @@ impl ExchangeClient for KrakenExchangeClient {
         let mut successful_deposits = vec![];
 
         for coin in deposit_methods().keys() {
-            for deposit_status in self.client.get_deposit_status(*coin).send().await? {
+            let token = if *coin == "SOL" {
+                MaybeToken::SOL()
+            } else {
+                Token::from_str(coin)?.into()
+            };
+            let deposit_statuses = self
+                .private_request::<Vec<KrakenDepositStatus>>(
+                    "DepositStatus",
+                    &[("asset", coin.to_string())],
+                )
+                .await?;
+            for deposit_status in deposit_statuses {
                 //dbg!(&deposit_status);
                 if deposit_status.status == "Success" {
                     successful_deposits.push(DepositInfo {
                         tx_id: deposit_status.txid,
                         amount: deposit_status.amount.parse::<f64>().unwrap(),
+                        token: Some(token),
+                        time: None,
                     });
                 }
             }
@@ -136,212 +935,704 @@ impl ExchangeClient for KrakenExchangeClient {
         Ok(Some(successful_deposits))
     }
 
-    async fn recent_withdrawals(&self) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
-        // Withdrawals not currently supported for Kraken
-        Ok(vec![])
+    async fn withdrawals(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
+        let mut withdrawals = vec![];
+
+        // Results per request are capped, so query long ranges one window at a time
+        let windows = date_range_windows(start, end, RECENT_WITHDRAWAL_DAYS);
+        for (coin, method) in deposit_methods() {
+            for (window_start, window_end) in &windows {
+                for withdraw_status in self
+                    .private_request::<Vec<KrakenWithdrawStatus>>(
+                        "WithdrawStatus",
+                        &[
+                            ("asset", coin.into()),
+                            ("method", method.into()),
+                            ("start", window_start.timestamp().to_string()),
+                            // Inclusive, where the window's end is not
+                            ("end", (window_end.timestamp() - 1).to_string()),
+                        ],
+                    )
+                    .await?
+                {
+                    let (completed, tx_id) = match withdraw_status.status.as_str() {
+                        "Success" => (true, withdraw_status.txid),
+                        "Failure" => (true, None),
+                        _ if withdraw_status.status_prop.as_deref() == Some("canceled") => {
+                            (true, None)
+                        }
+                        _ => (false, None),
+                    };
+
+                    let token = if coin == "SOL" {
+                        None
+                    } else {
+                        Token::from_str(coin).ok()
+                    };
+                    withdrawals.push(WithdrawalInfo {
+                        address: withdraw_status.info.parse::<Pubkey>().unwrap_or_default(),
+                        token: token.into(),
+                        amount: withdraw_status.amount.parse::<f64>()?,
+                        tag: withdraw_status.refid,
+                        completed,
+                        tx_id,
+                        time: Some(Utc.timestamp(withdraw_status.time, 0)),
+                        fee: withdraw_status.fee.parse::<f64>().ok(),
+                        client_reference: None,
+                    });
+                }
+            }
+        }
+
+        sort_and_dedup_withdrawals(&mut withdrawals);
+        Ok(withdrawals)
+    }
+
+    async fn request_withdraw(
+        &self,
+        address: Pubkey,
+        token: MaybeToken,
+        amount: u64,
+        _password: Option<String>,
+        _code: Option<&dyn TwoFactorCodeProvider>,
+        _client_reference: &str,
+    ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>
+    {
+        let asset = token.name();
+        let amount = format_native_amount(token, amount, self.withdrawal_decimals(token));
+        let method = *deposit_methods()
+            .get(asset)
+            .ok_or_else(|| format!("Kraken has no Solana network route for {token}"))?;
+
+        if !self
+            .private_request::<Vec<KrakenFundingMethod>>(
+                "WithdrawMethods",
+                &[("asset", asset.into())],
+            )
+            .await?
+            .iter()
+            .any(|withdraw_method| withdraw_method.method == method)
+        {
+            return Err(format!("Kraken {token} withdrawals via {method} are not enabled").into());
+        }
+
+        // Kraken only withdraws to addresses previously added to the account's address book
+        let address = address.to_string();
+        let withdraw_address = self
+            .private_request::<Vec<KrakenWithdrawAddress>>(
+                "WithdrawAddresses",
+                &[("asset", asset.into()), ("method", method.into())],
+            )
+            .await?
+            .into_iter()
+            .find(|withdraw_address| withdraw_address.address == address)
+            .ok_or_else(|| {
+                format!("{address} is not in the Kraken {method} withdrawal address book")
+            })?;
+        if withdraw_address.verified == Some(false) {
+            return Err(format!("Kraken withdrawal address {address} is not yet verified").into());
+        }
+
+        let withdraw_fee = self
+            .private_request::<KrakenWithdrawInfo>(
+                "WithdrawInfo",
+                &[
+                    ("asset", asset.into()),
+                    ("key", withdraw_address.key.clone()),
+                    ("amount", amount.clone()),
+                ],
+            )
+            .await?
+            .fee
+            .parse::<f64>()?;
+
+        let withdraw_id = self
+            .private_request::<KrakenWithdrawRefId>(
+                "Withdraw",
+                &[
+                    ("asset", asset.into()),
+                    ("key", withdraw_address.key),
+                    ("amount", amount),
+                    ("address", address),
+                ],
+            )
+            .await?
+            .refid;
+
+        Ok((withdraw_id, withdraw_fee))
+    }
+
+    async fn fiat_withdrawal_methods(
+        &self,
+        currency: &str,
+    ) -> Result<Vec<FiatWithdrawalMethod>, Box<dyn std::error::Error>> {
+        check_fiat_currency(currency)?;
+
+        let mut methods = vec![];
+        for funding_method in self
+            .private_request::<Vec<KrakenFundingMethod>>(
+                "WithdrawMethods",
+                &[("asset", currency.into())],
+            )
+            .await?
+        {
+            for withdraw_address in self
+                .private_request::<Vec<KrakenWithdrawAddress>>(
+                    "WithdrawAddresses",
+                    &[
+                        ("asset", currency.into()),
+                        ("method", funding_method.method.clone()),
+                    ],
+                )
+                .await?
+            {
+                if withdraw_address.verified == Some(false) {
+                    continue;
+                }
+                methods.push(FiatWithdrawalMethod {
+                    name: format!(
+                        "{} via {} ({})",
+                        withdraw_address.key, funding_method.method, withdraw_address.address
+                    ),
+                    id: withdraw_address.key,
+                    currency: currency.to_string(),
+                });
+            }
+        }
+        Ok(methods)
+    }
+
+    async fn request_fiat_withdraw(
+        &self,
+        currency: &str,
+        amount: f64,
+        method_id: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        check_fiat_currency(currency)?;
+
+        // Kraken only withdraws to bank accounts previously added to the account's address book,
+        // which is what `method_id` names
+        Ok(self
+            .private_request::<KrakenWithdrawRefId>(
+                "Withdraw",
+                &[
+                    ("asset", currency.into()),
+                    ("key", method_id.into()),
+                    ("amount", amount.to_string()),
+                ],
+            )
+            .await?
+            .refid)
+    }
+
+    async fn fiat_withdrawal_status(
+        &self,
+        currency: &str,
+        withdrawal_id: &str,
+    ) -> Result<FiatWithdrawalStatus, Box<dyn std::error::Error>> {
+        check_fiat_currency(currency)?;
+
+        let withdraw_status = self
+            .private_request::<Vec<KrakenWithdrawStatus>>(
+                "WithdrawStatus",
+                &[("asset", currency.into())],
+            )
+            .await?
+            .into_iter()
+            .find(|withdraw_status| withdraw_status.refid == withdrawal_id)
+            .ok_or_else(|| format!("Unknown Kraken {currency} withdrawal: {withdrawal_id}"))?;
+
+        // "Settled" means the funds have left Kraken, but the bank may still return them
+        let state = match (
+            withdraw_status.status.as_str(),
+            withdraw_status.status_prop.as_deref(),
+        ) {
+            (_, Some("canceled")) => FiatWithdrawalState::Cancelled,
+            (_, Some("return")) | ("Failure", _) => FiatWithdrawalState::Failed,
+            ("Success", _) => FiatWithdrawalState::Completed,
+            ("Settled", _) => FiatWithdrawalState::Processing,
+            _ => FiatWithdrawalState::Pending,
+        };
+        Ok(FiatWithdrawalStatus {
+            state,
+            amount: withdraw_status.amount.parse::<f64>()?,
+            fee: withdraw_status.fee.parse::<f64>().ok(),
+        })
+    }
+
+    async fn print_market_info(
+        &self,
+        pair: &TradingPair,
+        format: MarketInfoFormat,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let MarketInfoFormat::Candles(range) = format {
+            return print_candles(range, &self.candles(pair, range).await?);
+        }
+
+        let ticker_stats = self.ticker_stats(pair).await?;
+        let pair_info = self.pair_info(pair).await.ok();
+        let usd_rate = if format == MarketInfoFormat::All {
+            quote_usd_rate(self, &pair.quote).await.ok()
+        } else {
+            None
+        };
+        print_ticker_stats(&ticker_stats, pair_info.as_ref(), usd_rate, format)
+    }
+
+    async fn candles(
+        &self,
+        pair: &TradingPair,
+        range: CandleRange,
+    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        range.validate(Exchange::Kraken, OHLC_INTERVALS)?;
+        // Older candles can't be paged through
+        if range.count > OHLC_LIMIT {
+            return Err(format!("Kraken serves at most {OHLC_LIMIT} candles").into());
+        }
+
+        let ohlc = self
+            .public_request::<HashMap<String, serde_json::Value>>(
+                "OHLC",
+                &format!(
+                    "pair={}&interval={}",
+                    pair.to_exchange_symbol(Exchange::Kraken),
+                    range.interval_minutes,
+                ),
+            )
+            .await?;
+
+        // [time, open, high, low, close, vwap, volume, count]
+        let mut candles = ohlc
+            .into_iter()
+            .filter(|(key, _)| key != "last")
+            .filter_map(|(_, candles)| candles.as_array().cloned())
+            .flatten()
+            .filter_map(|candle| {
+                let field = |index: usize| {
+                    candle
+                        .get(index)
+                        .and_then(|value| value.as_str())
+                        .and_then(|value| value.parse::<f64>().ok())
+                };
+                Some(Candle {
+                    time: Utc.timestamp(candle.get(0)?.as_i64()?, 0),
+                    high: field(2)?,
+                    low: field(3)?,
+                    volume: field(6)?,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        candles.drain(..candles.len().saturating_sub(range.count));
+        Ok(candles)
+    }
+
+    async fn server_time(&self) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct KrakenTime {
+            unixtime: i64,
+        }
+
+        let unixtime = self
+            .public_request::<KrakenTime>("Time", "")
+            .await?
+            .unixtime;
+        Ok(Utc.timestamp(unixtime, 0))
     }
 
-    async fn request_withdraw(
+    async fn system_status(
         &self,
-        _address: Pubkey,
-        _token: MaybeToken,
-        _amount: f64,
-        _password: Option<String>,
-        _code: Option<String>,
-    ) -> Result<(/* withdraw_id: */ String, /*withdraw_fee: */ f64), Box<dyn std::error::Error>>
-    {
-        Err("Withdrawals not currently supported for Kraken".into())
+        pair: Option<&TradingPair>,
+    ) -> Result<SystemStatus, Box<dyn std::error::Error>> {
+        let system_status = parse_system_status(
+            &self
+                .public_request::<KrakenStatus>("SystemStatus", "")
+                .await?
+                .status,
+        );
+        let pair = match pair {
+            Some(pair) => pair,
+            None => return Ok(system_status),
+        };
+
+        let pair_status = self
+            .public_request::<HashMap<String, KrakenStatus>>(
+                "AssetPairs",
+                &format!("pair={}", pair.to_exchange_symbol(Exchange::Kraken)),
+            )
+            .await?
+            .into_values()
+            .next()
+            .map(|pair_status| parse_system_status(&pair_status.status))
+            .ok_or_else(|| format!("AssetPairs: {pair} not found"))?;
+        Ok(system_status.max(pair_status))
     }
 
-    async fn print_market_info(
+    async fn order_book(
         &self,
-        pair: &str,
-        format: MarketInfoFormat,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        #[derive(Debug)]
-        struct Hlv {
-            time: DateTime<Utc>,
-            high: f64,
-            low: f64,
-            volume: f64,
-        }
-
-        let hourly_prices = self
-            .client
-            .get_ohlc_data(pair)
-            .interval(kraken_sdk_rest::Interval::Hour1)
-            .send()
-            .await?
-            .into_iter()
-            .rev()
-            .take(24)
-            .filter_map(|ohlc| {
-                if let (Some(high), Some(low), Some(volume)) = (
-                    ohlc.high().parse::<f64>().ok(),
-                    ohlc.low().parse::<f64>().ok(),
-                    ohlc.volume().parse::<f64>().ok(),
-                ) {
-                    let naive = NaiveDateTime::from_timestamp(ohlc.time(), 0);
-                    let time: DateTime<Utc> = DateTime::from_utc(naive, Utc);
-
-                    Some(Hlv {
-                        time,
-                        high,
-                        low,
-                        volume,
-                    })
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+        pair: &TradingPair,
+        depth: usize,
+    ) -> Result<OrderBook, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        let symbol = pair.to_exchange_symbol(Exchange::Kraken);
 
-        if hourly_prices.len() != 24 {
-            return Err(format!(
-                "Failed to fetch price data for last 24 hours (fetched {} hours)",
-                hourly_prices.len()
+        let time = Utc::now();
+        let response = self
+            .public_request::<HashMap<String, KrakenOrderBook>>(
+                "Depth",
+                &format!("pair={symbol}&count={depth}"),
             )
-            .into());
-        }
+            .await?;
+        let order_book = response.get(&symbol).ok_or("Invalid API response")?;
 
-        let weighted_24h_avg_price = {
-            let mut total_volume = 0.;
-            let mut avg_price_weighted_sum = 0.;
-            for hourly_price in &hourly_prices {
-                let avg_price = (hourly_price.low + hourly_price.high).to_f64().unwrap() / 2.;
-                let volume = hourly_price.volume.to_f64().unwrap();
+        Ok(OrderBook {
+            bids: parse_order_book_levels(
+                order_book.bids.iter().map(|order_book_tier| {
+                    (order_book_tier.0.as_str(), order_book_tier.1.as_str())
+                }),
+                depth,
+            )?,
+            asks: parse_order_book_levels(
+                order_book.asks.iter().map(|order_book_tier| {
+                    (order_book_tier.0.as_str(), order_book_tier.1.as_str())
+                }),
+                depth,
+            )?,
+            time,
+        })
+    }
 
-                total_volume += volume;
-                avg_price_weighted_sum += avg_price * volume;
-            }
+    async fn ticker_stats(
+        &self,
+        pair: &TradingPair,
+    ) -> Result<TickerStats, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
 
-            avg_price_weighted_sum / total_volume
-        };
+        #[derive(Debug, Deserialize)]
+        struct KrakenTicker {
+            a: Vec<String>, // ask [price, whole lot volume, lot volume]
+            b: Vec<String>, // bid [price, whole lot volume, lot volume]
+            c: Vec<String>, // last trade closed [price, lot volume]
+            v: Vec<String>, // volume [today, last 24 hours]
+            p: Vec<String>, // volume weighted average price [today, last 24 hours]
+            l: Vec<String>, // low [today, last 24 hours]
+            h: Vec<String>, // high [today, last 24 hours]
+            o: String,      // today's opening price
+        }
+
+        let ticker = self
+            .public_request::<HashMap<String, KrakenTicker>>(
+                "Ticker",
+                &format!("pair={}", pair.to_exchange_symbol(Exchange::Kraken)),
+            )
+            .await?
+            .into_values()
+            .next()
+            .ok_or_else(|| format!("No ticker available for {pair}"))?;
 
-        let bid_ask = self.bid_ask(pair).await?;
+        let field = |values: &[String], index: usize| -> Result<f64, Box<dyn std::error::Error>> {
+            Ok(values
+                .get(index)
+                .ok_or_else(|| format!("Malformed {pair} ticker"))?
+                .parse::<f64>()?)
+        };
 
-        match format {
-            MarketInfoFormat::All => {
-                println!(
-                    "{} | Ask: ${:.2}, Bid: ${:.2}, 24hr Average: ${:.2}",
-                    pair, bid_ask.ask_price, bid_ask.bid_price, weighted_24h_avg_price
-                );
-            }
-            MarketInfoFormat::Ask => {
-                println!("{}", bid_ask.ask_price);
-            }
-            MarketInfoFormat::Hourly => {
-                println!("hour,low,high,average,volume");
-                for p in &hourly_prices {
-                    println!(
-                        "{},{},{},{},{}",
-                        DateTime::<Local>::from(p.time),
-                        p.low,
-                        p.high,
-                        (p.low + p.high).to_f64().unwrap() / 2.,
-                        p.volume
-                    );
-                }
-            }
-            MarketInfoFormat::Weighted24hAveragePrice => {
-                println!("{weighted_24h_avg_price:.4}");
-            }
-        }
+        let last_price = field(&ticker.c, 0)?;
+        let open_24h = ticker.o.parse::<f64>()?;
+        let base_volume_24h = field(&ticker.v, 1)?;
+        let weighted_average_price_24h = field(&ticker.p, 1)?;
 
-        Ok(())
+        Ok(TickerStats {
+            pair: pair.to_string(),
+            last_price,
+            bid_price: field(&ticker.b, 0)?,
+            ask_price: field(&ticker.a, 0)?,
+            open_24h,
+            high_24h: field(&ticker.h, 1)?,
+            low_24h: field(&ticker.l, 1)?,
+            base_volume_24h,
+            quote_volume_24h: base_volume_24h * weighted_average_price_24h,
+            price_change_percent_24h: if open_24h > 0. {
+                (last_price - open_24h) / open_24h * 100.
+            } else {
+                0.
+            },
+            weighted_average_price_24h: Some(weighted_average_price_24h),
+        })
     }
 
-    async fn bid_ask(&self, pair: &str) -> Result<BidAsk, Box<dyn std::error::Error>> {
-        let response = self.client.get_order_book(pair).count(1).send().await?;
+    async fn bid_ask(&self, pair: &TradingPair) -> Result<BidAsk, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        let symbol = pair.to_exchange_symbol(Exchange::Kraken);
+        let response = self
+            .public_request::<HashMap<String, KrakenOrderBook>>(
+                "Depth",
+                &format!("pair={symbol}&count=1"),
+            )
+            .await?;
 
-        if let Some(order_book) = response.get(pair) {
+        if let Some(order_book) = response.get(&symbol) {
+            let ask = order_book.asks.first();
+            let bid = order_book.bids.first();
             if let (Some(ask_price), Some(bid_price)) = (
-                order_book
-                    .asks
-                    .first()
-                    .and_then(|order_book_tier| order_book_tier.0.parse::<f64>().ok()),
-                order_book
-                    .bids
-                    .first()
-                    .and_then(|order_book_tier| order_book_tier.0.parse::<f64>().ok()),
+                ask.and_then(|order_book_tier| order_book_tier.0.parse::<f64>().ok()),
+                bid.and_then(|order_book_tier| order_book_tier.0.parse::<f64>().ok()),
             ) {
                 return Ok(BidAsk {
                     bid_price,
                     ask_price,
+                    bid_amount: bid
+                        .and_then(|order_book_tier| order_book_tier.1.parse::<f64>().ok()),
+                    ask_amount: ask
+                        .and_then(|order_book_tier| order_book_tier.1.parse::<f64>().ok()),
                 });
             }
         }
         Err("Invalid API response".into())
     }
 
+    async fn bid_asks(&self) -> Result<HashMap<TradingPair, BidAsk>, Box<dyn std::error::Error>> {
+        #[derive(Debug, Deserialize)]
+        struct KrakenTicker {
+            a: Vec<String>, // ask [price, whole lot volume, lot volume]
+            b: Vec<String>, // bid [price, whole lot volume, lot volume]
+        }
+
+        let pairs = self
+            .pair_infos()
+            .await?
+            .into_iter()
+            .map(|pair_info| {
+                (
+                    pair_info.pair.to_exchange_symbol(Exchange::Kraken),
+                    pair_info.pair,
+                )
+            })
+            .collect::<HashMap<_, _>>();
+
+        // Without a pair, Kraken answers with the ticker of every market
+        let tickers = self
+            .public_request::<HashMap<String, KrakenTicker>>("Ticker", "")
+            .await?;
+
+        let level = |values: &[String]| {
+            let price = values.first()?.parse::<f64>().ok()?;
+            let amount = values.get(2).and_then(|amount| amount.parse::<f64>().ok());
+            Some((price, amount))
+        };
+        Ok(tickers
+            .into_iter()
+            .filter_map(|(symbol, ticker)| {
+                let pair = pairs.get(&symbol)?.clone();
+                let (bid_price, bid_amount) = level(&ticker.b)?;
+                let (ask_price, ask_amount) = level(&ticker.a)?;
+                Some((
+                    pair,
+                    BidAsk {
+                        bid_price,
+                        ask_price,
+                        bid_amount,
+                        ask_amount,
+                    },
+                ))
+            })
+            .collect())
+    }
+
+    async fn price_on(
+        &self,
+        pair: &TradingPair,
+        date: NaiveDate,
+    ) -> Result<f64, Box<dyn std::error::Error>> {
+        let open_time = date.and_hms(0, 0, 0).timestamp();
+
+        // Only the most recent 720 candles are served, regardless of `since`
+        let ohlc = self
+            .public_request::<HashMap<String, serde_json::Value>>(
+                "OHLC",
+                &format!(
+                    "pair={}&interval=1440&since={}",
+                    pair.to_exchange_symbol(Exchange::Kraken),
+                    open_time - 1,
+                ),
+            )
+            .await?;
+
+        // [time, open, high, low, close, vwap, volume, count]
+        ohlc.into_iter()
+            .filter(|(key, _)| key != "last")
+            .filter_map(|(_, candles)| candles.as_array().cloned())
+            .flatten()
+            .find(|candle| candle.get(0).and_then(|time| time.as_i64()) == Some(open_time))
+            .and_then(|candle| {
+                candle
+                    .get(4)
+                    .and_then(|close| close.as_str())
+                    .map(str::to_string)
+            })
+            .and_then(|close| close.parse::<f64>().ok())
+            .ok_or_else(|| format!("No {pair} price available on {date}").into())
+    }
+
     async fn place_order(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         side: OrderSide,
         price: f64,
         amount: f64,
+        client_order_id: &str,
     ) -> Result<OrderId, Box<dyn std::error::Error>> {
-        if pair != self.preferred_solusd_pair() {
-            // Currently only the `preferred_solusd_pair` is supported due to limitations in how
-            // the `available` token balances are computed in `Self::balances()`
-            return Err(format!("Unsupported trading pair: {pair}").into());
-        }
+        self.validate_pair(pair).await?;
+        check_tradable_pair(pair)?;
 
-        let side = match side {
-            OrderSide::Buy => kraken_sdk_rest::OrderSide::Buy,
-            OrderSide::Sell => kraken_sdk_rest::OrderSide::Sell,
-        };
+        self.add_limit_order(
+            pair,
+            side,
+            price,
+            amount,
+            LimitOrderKind::PostOnly,
+            client_order_id,
+            None,
+        )
+        .await
+    }
 
-        let response = self
-            .client
-            .add_limit_order(pair, side, &amount.to_string(), &price.to_string())
-            .post_only()
-            .send()
+    async fn place_expiring_order(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+        expiry: DateTime<Utc>,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        check_tradable_pair(pair)?;
+
+        self.add_limit_order(
+            pair,
+            side,
+            price,
+            amount,
+            LimitOrderKind::PostOnly,
+            client_order_id,
+            Some(expiry),
+        )
+        .await
+    }
+
+    async fn place_taker_order(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        self.validate_pair(pair).await?;
+        check_tradable_pair(pair)?;
+
+        self.add_limit_order(
+            pair,
+            side,
+            price,
+            amount,
+            LimitOrderKind::ImmediateOrCancel,
+            client_order_id,
+            None,
+        )
+        .await
+    }
+
+    async fn amend_order(
+        &self,
+        _pair: &TradingPair,
+        order_id: &OrderId,
+        price: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.private_request::<serde_json::Value>(
+            "AmendOrder",
+            &[
+                ("txid", order_id.to_string()),
+                ("limit_price", price.to_string()),
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn find_order_by_client_id(
+        &self,
+        _pair: &TradingPair,
+        client_order_id: &str,
+    ) -> Result<Option<OrderId>, Box<dyn std::error::Error>> {
+        let params = [("cl_ord_id", client_order_id.to_string())];
+
+        let open_orders = self
+            .private_request::<KrakenOpenOrders>("OpenOrders", &params)
             .await?;
-        //dbg!(&response);
+        if let Some(order_id) = open_orders.open.into_keys().next() {
+            return Ok(Some(order_id));
+        }
 
-        let txid = response.txid.unwrap_or_default();
-        assert_eq!(txid.len(), 1);
-        Ok(txid[0].to_owned())
+        let closed_orders = self
+            .private_request::<KrakenClosedOrders>("ClosedOrders", &params)
+            .await?;
+        Ok(closed_orders.closed.into_keys().next())
     }
 
     async fn cancel_order(
         &self,
-        _pair: &str,
+        _pair: &TradingPair,
         order_id: &OrderId,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let _ = self.client.cancel_order(order_id).send().await?;
+        let _ = self
+            .private_request::<serde_json::Value>("CancelOrder", &[("txid", order_id.clone())])
+            .await?;
         Ok(())
     }
 
     async fn order_status(
         &self,
-        pair: &str,
+        pair: &TradingPair,
         order_id: &OrderId,
     ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
-        let orders = self.client.query_orders_info(order_id).send().await?;
+        let orders = self
+            .private_request::<HashMap<String, KrakenOrderInfo>>(
+                "QueryOrders",
+                &[("txid", order_id.clone())],
+            )
+            .await?;
 
         let order = orders
             .get(order_id)
             .ok_or_else(|| format!("Unknown order id: {order_id}"))?;
-        //dbg!(&order);
 
         assert_eq!(order.descr.ordertype, "limit");
 
-        // Currently only the `preferred_solusd_pair` is supported due to limitations in how
-        // the `available` token balances are computed in `Self::balances()`
-        assert_eq!(order.descr.pair, self.preferred_solusd_pair());
-        assert_eq!(order.descr.pair, pair);
+        let order_pair = TradingPair::from_exchange_symbol(Exchange::Kraken, &order.descr.pair)?;
+        assert!(is_tradable_pair(&order_pair));
+        assert_eq!(order_pair, *pair);
 
+        // Fees are charged in the quote currency
         let fee = {
-            let fee = order.fee.parse::<f64>().unwrap();
+            let fee = order.fee.parse::<f64>()?;
             if fee > f64::EPSILON {
-                Some((fee, "USD".to_string()))
+                Some((fee, order_pair.quote.clone()))
             } else {
                 None
             }
         };
 
+        let filled_amount = order.vol_exec.parse::<f64>()?;
+        let (average_fill_price, cumulative_quote_amount) =
+            fill_totals(filled_amount, order.cost.parse::<f64>()?);
+
         // TODO: use `order.opentm` instead?
         let last_update = {
             let today = Local::now().date();
@@ -350,24 +1641,145 @@ impl ExchangeClient for KrakenExchangeClient {
 
         Ok(OrderStatus {
             open: ["open"].contains(&order.status.as_str()),
-            side: match order.descr.orderside.as_str() {
+            side: match order.descr.side.as_str() {
                 "sell" => OrderSide::Sell,
                 "buy" => OrderSide::Buy,
                 side => panic!("Invalid order side: {side}"),
             },
-            price: order.descr.price.parse::<f64>().unwrap(),
-            amount: order.vol.parse::<f64>().unwrap(),
-            filled_amount: order.vol_exec.parse::<f64>().unwrap(),
+            price: order.descr.price.parse::<f64>()?,
+            amount: order.vol.parse::<f64>()?,
+            filled_amount,
+            average_fill_price,
+            cumulative_quote_amount,
             last_update,
             fee,
+            cancellation: order.cancellation(),
         })
     }
 
+    async fn open_orders(&self) -> Result<Vec<ExchangeOpenOrder>, Box<dyn std::error::Error>> {
+        let mut open_orders = vec![];
+        for (order_id, order) in self
+            .private_request::<KrakenOpenOrderHistory>("OpenOrders", &[])
+            .await?
+            .open
+        {
+            open_orders.push(ExchangeOpenOrder {
+                pair: TradingPair::from_exchange_symbol(Exchange::Kraken, &order.descr.pair)?,
+                order_id,
+                side: match order.descr.side.as_str() {
+                    "sell" => OrderSide::Sell,
+                    "buy" => OrderSide::Buy,
+                    side => return Err(format!("Invalid order side: {side}").into()),
+                },
+                price: order.descr.price.parse::<f64>()?,
+                amount: order.vol.parse::<f64>()?,
+            });
+        }
+        Ok(open_orders)
+    }
+
+    async fn order_history(
+        &self,
+        pair: &TradingPair,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<HistoricalOrder>, Box<dyn std::error::Error>> {
+        let mut orders = self
+            .private_request::<KrakenOpenOrderHistory>("OpenOrders", &[])
+            .await?
+            .open
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        // Closed orders are returned 50 at a time, newest first
+        let mut closed_orders = 0;
+        loop {
+            let page = self
+                .private_request::<KrakenOrderHistory>(
+                    "ClosedOrders",
+                    &[
+                        ("start", start.timestamp().to_string()),
+                        ("end", end.timestamp().to_string()),
+                        ("closetime", "open".into()),
+                        ("ofs", closed_orders.to_string()),
+                    ],
+                )
+                .await?;
+            let page_len = page.closed.len();
+            closed_orders += page_len;
+            orders.extend(page.closed);
+            if page_len == 0 || closed_orders >= page.count {
+                break;
+            }
+        }
+
+        let mut history = vec![];
+        for (order_id, order) in orders {
+            let opened = Utc.timestamp(order.opentm as i64, 0);
+            if opened < start || opened > end {
+                continue;
+            }
+            let order_pair =
+                match TradingPair::from_exchange_symbol(Exchange::Kraken, &order.descr.pair) {
+                    Ok(order_pair) if order_pair == *pair => order_pair,
+                    _ => continue,
+                };
+
+            let fee = order.fee.parse::<f64>()?;
+            let filled_amount = order.vol_exec.parse::<f64>()?;
+            let (average_fill_price, cumulative_quote_amount) =
+                fill_totals(filled_amount, order.cost.parse::<f64>()?);
+            // Market orders have no limit price
+            let price = match order.descr.price.parse::<f64>()? {
+                price if price > 0. => price,
+                _ => order.price.parse::<f64>()?,
+            };
+
+            history.push(HistoricalOrder {
+                order_id,
+                creation_time: opened,
+                client_order_id: order.cl_ord_id,
+                status: OrderStatus {
+                    open: ["pending", "open"].contains(&order.status.as_str()),
+                    side: match order.descr.side.as_str() {
+                        "sell" => OrderSide::Sell,
+                        "buy" => OrderSide::Buy,
+                        side => return Err(format!("Invalid order side: {side}").into()),
+                    },
+                    price,
+                    amount: order.vol.parse::<f64>()?,
+                    filled_amount,
+                    average_fill_price,
+                    cumulative_quote_amount,
+                    last_update: Utc
+                        .timestamp(order.closetm.unwrap_or(order.opentm) as i64, 0)
+                        .date()
+                        .naive_local(),
+                    fee: (fee > f64::EPSILON).then(|| (fee, order_pair.quote.clone())),
+                    cancellation: order.cancellation(),
+                },
+            });
+        }
+        history.sort_by_key(|order| order.creation_time);
+        Ok(history)
+    }
+
     async fn get_lending_info(
         &self,
-        _coin: &str,
+        coin: &str,
     ) -> Result<Option<LendingInfo>, Box<dyn std::error::Error>> {
-        Err("Lending not currently supported for Kraken".into())
+        Ok(self.all_lending_info().await?.remove(coin))
+    }
+
+    async fn all_lending_info(
+        &self,
+    ) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>> {
+        Ok(earn_lending_info(
+            self.earn_strategies(None).await?,
+            &self.earn_allocations().await?,
+            &self.balances().await?,
+        ))
     }
 
     async fn get_lending_history(
@@ -385,8 +1797,322 @@ impl ExchangeClient for KrakenExchangeClient {
         Err("Lending not currently supported for Kraken".into())
     }
 
-    fn preferred_solusd_pair(&self) -> &'static str {
-        "SOLUSD"
+    // Deallocates from Earn, liquid strategies first as the others take a while to release funds
+    async fn redeem_lending(
+        &self,
+        coin: &str,
+        amount: f64,
+    ) -> Result<LendingRedemption, Box<dyn std::error::Error>> {
+        let allocations = self.earn_allocations().await?;
+        let mut strategies = self
+            .earn_strategies(Some(coin))
+            .await?
+            .into_iter()
+            .filter_map(|strategy| {
+                let allocated = allocations
+                    .iter()
+                    .find(|allocation| allocation.strategy_id == strategy.id)
+                    .and_then(|allocation| {
+                        allocation.amount_allocated.total.native.parse::<f64>().ok()
+                    })
+                    .unwrap_or_default();
+                (strategy.can_deallocate && allocated > 0.).then(|| (strategy, allocated))
+            })
+            .collect::<Vec<_>>();
+        strategies.sort_by_key(|(strategy, _)| {
+            !LIQUID_EARN_LOCK_TYPES.contains(&strategy.lock_type.lock_type.as_str())
+        });
+
+        let mut redeemed = 0.;
+        for (strategy, allocated) in strategies {
+            if redeemed >= amount {
+                break;
+            }
+            let deallocation = allocated.min(amount - redeemed);
+            self.private_request::<serde_json::Value>(
+                "Earn/Deallocate",
+                &[
+                    ("amount", deallocation.to_string()),
+                    ("strategy_id", strategy.id),
+                ],
+            )
+            .await?;
+            redeemed += deallocation;
+        }
+        Ok(LendingRedemption {
+            redeemed,
+            forfeited_interest: None,
+        })
+    }
+
+    async fn get_staking_info(
+        &self,
+        coin: &str,
+    ) -> Result<Vec<StakingInfo>, Box<dyn std::error::Error>> {
+        let allocations = self.earn_allocations().await?;
+
+        let mut staking_info = vec![];
+        for strategy in self.earn_strategies(Some(coin)).await? {
+            let allocation = allocations
+                .iter()
+                .find(|allocation| allocation.strategy_id == strategy.id);
+
+            staking_info.push(StakingInfo {
+                coin: strategy.asset.clone(),
+                strategy_id: strategy.id.clone(),
+                lock_type: strategy.lock_type.lock_type.clone(),
+                apr_low: strategy
+                    .apr_estimate
+                    .as_ref()
+                    .and_then(|apr_estimate| apr_estimate.low.parse::<f64>().ok()),
+                apr_high: strategy
+                    .apr_estimate
+                    .as_ref()
+                    .and_then(|apr_estimate| apr_estimate.high.parse::<f64>().ok()),
+                allocated: allocation
+                    .and_then(|allocation| allocation.amount_allocated.total.native.parse().ok())
+                    .unwrap_or_default(),
+                rewarded: allocation
+                    .and_then(|allocation| allocation.total_rewarded.native.parse().ok())
+                    .unwrap_or_default(),
+                pending_allocation: self
+                    .earn_operation_pending(StakingOperation::Allocate, &strategy.id)
+                    .await?,
+                pending_deallocation: self
+                    .earn_operation_pending(StakingOperation::Deallocate, &strategy.id)
+                    .await?,
+            });
+        }
+        Ok(staking_info)
+    }
+
+    async fn submit_staking_request(
+        &self,
+        coin: &str,
+        operation: StakingOperation,
+        amount: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let allocations = self.earn_allocations().await?;
+        let allocated = |strategy: &EarnStrategy| {
+            allocations
+                .iter()
+                .find(|allocation| allocation.strategy_id == strategy.id)
+                .and_then(|allocation| allocation.amount_allocated.total.native.parse::<f64>().ok())
+                .unwrap_or_default()
+        };
+
+        // Prefer the strategy already holding the largest allocation
+        let strategy = self
+            .earn_strategies(Some(coin))
+            .await?
+            .into_iter()
+            .filter(|strategy| match operation {
+                StakingOperation::Allocate => strategy.can_allocate,
+                StakingOperation::Deallocate => strategy.can_deallocate && allocated(strategy) > 0.,
+            })
+            .max_by(|a, b| allocated(a).total_cmp(&allocated(b)))
+            .ok_or_else(|| {
+                format!("No Kraken staking strategy available to {operation:?} {coin}")
+            })?;
+
+        let endpoint = match operation {
+            StakingOperation::Allocate => "Earn/Allocate",
+            StakingOperation::Deallocate => "Earn/Deallocate",
+        };
+        self.private_request::<serde_json::Value>(
+            endpoint,
+            &[("amount", amount.to_string()), ("strategy_id", strategy.id)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_staking_rewards(
+        &self,
+        coin: &str,
+        staking_history: LendingHistory,
+    ) -> Result<Vec<StakingReward>, Box<dyn std::error::Error>> {
+        let (start_time, end_time) = staking_history.bounds();
+
+        let mut rewards = vec![];
+        for (_, entry) in self
+            .ledger_entries(start_time, end_time - chrono::Duration::seconds(1))
+            .await?
+        {
+            let is_reward = entry.entry_type == "staking"
+                || (entry.entry_type == "earn" && entry.subtype == "reward");
+            if !is_reward || normalize_ledger_asset(&entry.asset).0 != coin {
+                continue;
+            }
+
+            let amount = entry.amount.parse::<f64>()?;
+            if amount > 0. {
+                rewards.push(StakingReward {
+                    time: Utc.timestamp(entry.time as i64, 0),
+                    coin: coin.to_string(),
+                    amount,
+                });
+            }
+        }
+        Ok(rewards)
+    }
+
+    async fn get_ledger(
+        &self,
+        start_time: DateTime<Utc>,
+        end_time: DateTime<Utc>,
+    ) -> Result<Vec<LedgerEntry>, Box<dyn std::error::Error>> {
+        self.ledger_entries(start_time, end_time)
+            .await?
+            .into_iter()
+            .map(|(id, entry)| {
+                let (asset, staked) = normalize_ledger_asset(&entry.asset);
+                Ok(LedgerEntry {
+                    id,
+                    reference: entry.refid,
+                    time: Utc
+                        .timestamp(entry.time.trunc() as i64, (entry.time.fract() * 1e9) as u32),
+                    kind: if entry.subtype.is_empty() {
+                        entry.entry_type
+                    } else {
+                        format!("{}/{}", entry.entry_type, entry.subtype)
+                    },
+                    asset: asset.to_string(),
+                    staked,
+                    amount: entry
+                        .amount
+                        .parse::<f64>()
+                        .map_err(|err| format!("Invalid ledger `amount` field: {err}"))?,
+                    fee: entry
+                        .fee
+                        .parse::<f64>()
+                        .map_err(|err| format!("Invalid ledger `fee` field: {err}"))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn account_history(
+        &self,
+        category: HistoryCategory,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<AccountHistoryEntry>, Box<dyn std::error::Error>> {
+        Ok(self
+            .get_ledger(start, end)
+            .await?
+            .into_iter()
+            .filter(|entry| ledger_history_category(&entry.kind) == Some(category))
+            .map(|entry| AccountHistoryEntry {
+                time: entry.time,
+                coin: entry.asset,
+                amount: entry.amount,
+                fee: entry.fee,
+                reference: entry.reference,
+            })
+            .collect())
+    }
+
+    fn preferred_solusd_pair(&self) -> TradingPair {
+        TradingPair::new("SOL", "USD")
+    }
+
+    fn maker_fee_rate(&self) -> f64 {
+        0.0025
+    }
+
+    fn taker_fee_rate(&self) -> f64 {
+        0.004
+    }
+
+    async fn volume_tier_info(&self) -> Result<VolumeTierInfo, Box<dyn std::error::Error>> {
+        // Tiers are the same for every pair, but the rates are only reported for the pairs given
+        let trade_volume = self
+            .private_request::<KrakenTradeVolume>(
+                "TradeVolume",
+                &[(
+                    "pair",
+                    self.preferred_solusd_pair()
+                        .to_exchange_symbol(Exchange::Kraken),
+                )],
+            )
+            .await?;
+        let taker = trade_volume
+            .fees
+            .into_values()
+            .next()
+            .ok_or("Kraken reported no taker fee")?;
+        let maker = trade_volume
+            .fees_maker
+            .into_values()
+            .next()
+            .ok_or("Kraken reported no maker fee")?;
+
+        let rate = |percent: &str| percent.parse::<f64>().map(|percent| percent / 100.);
+        let next_tier = match (&taker.nextvolume, &taker.nextfee, &maker.nextfee) {
+            (Some(next_volume), Some(next_taker_fee), Some(next_maker_fee)) => Some(VolumeTier {
+                volume_threshold: next_volume.parse::<f64>()?,
+                maker_rate: rate(next_maker_fee)?,
+                taker_rate: rate(next_taker_fee)?,
+            }),
+            _ => None,
+        };
+
+        Ok(VolumeTierInfo {
+            volume_30d: trade_volume.volume.parse::<f64>()?,
+            maker_rate: rate(&maker.fee)?,
+            taker_rate: rate(&taker.fee)?,
+            next_tier,
+        })
+    }
+
+    async fn stream_token(&self) -> Result<String, Box<dyn std::error::Error>> {
+        // WebSocket tokens can't be extended, so an expiring one is replaced
+        self.stream_session
+            .token(|_expiring_session| async move {
+                let ws_token = self
+                    .private_request::<KrakenWebSocketsToken>("GetWebSocketsToken", &[])
+                    .await?;
+                Ok(StreamSession {
+                    token: ws_token.token,
+                    expires_at: Utc::now() + chrono::Duration::seconds(ws_token.expires),
+                })
+            })
+            .await
+    }
+
+    fn invalidate_stream_token(&self) {
+        self.stream_session.invalidate();
+    }
+
+    async fn pair_infos(&self) -> Result<Vec<PairInfo>, Box<dyn std::error::Error>> {
+        Ok(self
+            .pair_infos
+            .get_or_try_init(|| async {
+                Ok::<_, Box<dyn std::error::Error>>(
+                    self.public_request::<HashMap<String, KrakenAssetPair>>("AssetPairs", "")
+                        .await?
+                        .into_values()
+                        .map(|asset_pair| PairInfo {
+                            pair: TradingPair::new(
+                                normalize_kraken_asset(&asset_pair.base),
+                                normalize_kraken_asset(&asset_pair.quote),
+                            ),
+                            tick_size: Some(10_f64.powi(-asset_pair.pair_decimals)),
+                            step_size: Some(10_f64.powi(-asset_pair.lot_decimals)),
+                            min_notional: None,
+                            status: asset_pair
+                                .status
+                                .as_deref()
+                                .map_or(SystemStatus::Online, parse_system_status),
+                            // Kraken announces delistings, but doesn't publish a schedule
+                            delist_time: None,
+                        })
+                        .collect(),
+                )
+            })
+            .await?
+            .clone())
     }
 }
 
@@ -396,12 +2122,295 @@ pub fn new(
         secret,
         subaccount,
     }: ExchangeCredentials,
+    options: ExchangeClientOptions,
 ) -> Result<KrakenExchangeClient, Box<dyn std::error::Error>> {
+    options.check_supported(
+        Exchange::Kraken,
+        &[
+            "base_url",
+            "timeout",
+            "connect_timeout",
+            "proxy",
+            "debug_capture",
+        ],
+    )?;
     if subaccount.is_some() {
         return Err("subaccounts not supported".into());
     }
 
+    let debug_capture = options
+        .debug_capture_dir
+        .map(|dir| {
+            DebugCapture::create(Exchange::Kraken, &dir, &[api_key.as_str(), secret.as_str()])
+        })
+        .transpose()?;
+    if let Some(debug_capture) = &debug_capture {
+        eprintln!(
+            "Capturing Kraken requests to {}",
+            debug_capture.path().display()
+        );
+    }
+
     Ok(KrakenExchangeClient {
-        client: Client::new(&api_key, &secret),
+        base_url: options
+            .base_url
+            .clone()
+            .unwrap_or_else(|| API_URL.to_string()),
+        http_client: options.http_client()?,
+        rate_limiter: RateLimiter::shared(
+            Exchange::Kraken,
+            &api_key,
+            options.rate_limit_dir.as_deref(),
+        ),
+        stream_session: StreamSessionStore::shared(
+            Exchange::Kraken,
+            &api_key,
+            options.stream_session_dir.as_deref(),
+        ),
+        api_key,
+        secret,
+        pair_infos: OnceCell::new(),
+        debug_capture,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nonces_strictly_increase_within_a_millisecond() {
+        let nonces = (0..1_000).map(|_| next_nonce()).collect::<Vec<_>>();
+        assert!(nonces.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(LAST_NONCE.load(Ordering::SeqCst) >= *nonces.last().unwrap());
+    }
+
+    // Each request matches only if it selects the Solana withdrawal method
+    const SOL_WITHDRAWAL: &str = r#"
+==> 2024-03-21T15:04:33Z POST https://api.kraken.com/0/private/WithdrawMethods
+API-Key: <redacted>
+
+nonce=1711033473456&asset=SOL
+<== 200 in 90ms
+{"error":[],"result":[{"asset":"SOL","method":"Solana","network":"Solana","minimum":"0.02"}]}
+
+==> 2024-03-21T15:04:34Z POST https://api.kraken.com/0/private/WithdrawAddresses
+API-Key: <redacted>
+
+nonce=1711033474456&asset=SOL&method=Solana
+<== 200 in 90ms
+{"error":[],"result":[{"address":"CiwjEStzDKR8j1uQgVvU6hFtt8thAK3aZMDN3X6yzW5q","asset":"SOL",
+"method":"Solana","key":"sol-wallet","verified":true}]}
+
+==> 2024-03-21T15:04:35Z POST https://api.kraken.com/0/private/WithdrawInfo
+API-Key: <redacted>
+
+nonce=1711033475456&asset=SOL&key=sol-wallet
+<== 200 in 90ms
+{"error":[],"result":{"method":"Solana","limit":"100","amount":"1","fee":"0.01"}}
+
+==> 2024-03-21T15:04:36Z POST https://api.kraken.com/0/private/Withdraw
+API-Key: <redacted>
+
+nonce=1711033476456&asset=SOL&key=sol-wallet
+<== 200 in 90ms
+{"error":[],"result":{"refid":"FTQcuak-V6Za8qrWnhzTx67yYHz8Tg"}}
+"#;
+
+    #[tokio::test]
+    async fn withdrawals_select_the_solana_method() {
+        let server = crate::conformance::ReplayServer::start(SOL_WITHDRAWAL)
+            .await
+            .unwrap();
+        let client = crate::conformance::replay_client(Exchange::Kraken, &server).unwrap();
+        let address = Pubkey::from_str("CiwjEStzDKR8j1uQgVvU6hFtt8thAK3aZMDN3X6yzW5q").unwrap();
+
+        let (withdraw_id, withdraw_fee) = client
+            .request_withdraw(
+                address,
+                MaybeToken::SOL(),
+                1_000_000_000,
+                None,
+                None,
+                "sys-withdraw",
+            )
+            .await
+            .unwrap();
+        assert_eq!(withdraw_id, "FTQcuak-V6Za8qrWnhzTx67yYHz8Tg");
+        assert_eq!(withdraw_fee, 0.01);
+        assert_eq!(server.unexpected_requests(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn error_arrays_give_the_native_code() {
+        assert_eq!(
+            parse_errors(&[
+                "EOrder:Insufficient funds".into(),
+                "EGeneral:Invalid arguments:volume".into(),
+            ]),
+            (
+                "EOrder".to_string(),
+                "Insufficient funds, Invalid arguments:volume".to_string()
+            )
+        );
+        assert_eq!(
+            parse_errors(&["Unexpected failure".into()]),
+            ("unknown".to_string(), "Unexpected failure".to_string())
+        );
+    }
+
+    // A sell of 2 SOL that fills 0.5 at 500 and 0.5 at 502 before it's cancelled
+    const PARTIALLY_FILLED_THEN_CANCELLED: &str = r#"
+==> 2024-03-21T15:04:40Z POST https://api.kraken.com/0/private/QueryOrders
+API-Key: <redacted>
+
+nonce=1711033480456&txid=OQCLML-BW3P3-BUCMWZ
+<== 200 in 90ms
+{"error":[],"result":{"OQCLML-BW3P3-BUCMWZ":{"refid":null,"userref":0,"cl_ord_id":null,
+"status":"canceled","reason":"User requested","opentm":1711033446.1,"closetm":1711033452.3,
+"starttm":0,"expiretm":0,"descr":{"pair":"SOLUSD","type":"sell","ordertype":"limit",
+"price":"500.00","price2":"0","leverage":"none","order":"sell 2.00000000 SOLUSD @ limit 500.00",
+"close":""},"vol":"2.00000000","vol_exec":"1.00000000","cost":"501.00000","fee":"0.80160",
+"price":"501.00000","stopprice":"0.00000","limitprice":"0.00000","misc":"","oflags":"fciq,post"}}}
+"#;
+
+    #[tokio::test]
+    async fn a_partially_filled_then_cancelled_order_averages_its_fills() {
+        let server = crate::conformance::ReplayServer::start(PARTIALLY_FILLED_THEN_CANCELLED)
+            .await
+            .unwrap();
+        let client = crate::conformance::replay_client(Exchange::Kraken, &server).unwrap();
+
+        let order_status = client
+            .order_status(
+                &TradingPair::new("SOL", "USD"),
+                &"OQCLML-BW3P3-BUCMWZ".to_string(),
+            )
+            .await
+            .unwrap();
+        assert!(!order_status.open);
+        assert_eq!(order_status.cancellation, Some(OrderCancellation::User));
+        assert_eq!(order_status.price, 500.);
+        assert_eq!(order_status.filled_amount, 1.);
+        assert_eq!(order_status.average_fill_price, Some(501.));
+        assert_eq!(order_status.cumulative_quote_amount, Some(501.));
+        assert_eq!(order_status.fee, Some((0.8016, "USD".to_string())));
+        assert_eq!(server.unexpected_requests(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn requests_to_a_slow_server_time_out_and_are_retried() {
+        // Accepts connections but never answers on them
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicU64::new(0));
+        tokio::spawn({
+            let connections = connections.clone();
+            async move {
+                let mut sockets = vec![];
+                while let Ok((socket, _)) = listener.accept().await {
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    sockets.push(socket);
+                }
+            }
+        });
+        let client = ExchangeClientBuilder::new(Exchange::Kraken)
+            .credentials(ExchangeCredentials {
+                api_key: "slow".into(),
+                secret: "c2xvdw==".into(),
+                subaccount: None,
+            })
+            .base_url(&url)
+            .allow_http_localhost(true)
+            .timeout(std::time::Duration::from_millis(200))
+            .build()
+            .unwrap();
+
+        let start = Instant::now();
+        let err = client.server_time().await.unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref::<ExchangeError>(),
+                Some(ExchangeError::Timeout { endpoint, .. }) if endpoint == "Time"
+            ),
+            "{err}"
+        );
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        // The first attempt and both retries
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn earn_strategies_are_reported_as_lending() {
+        let strategies = serde_json::from_str::<EarnStrategies>(
+            r#"{"items":[
+            {"id":"ESFLEX","asset":"SOL","lock_type":{"type":"flex"},
+             "apr_estimate":{"low":"1.5","high":"2.5"},"can_allocate":true,"can_deallocate":true},
+            {"id":"ESBOND","asset":"SOL","lock_type":{"type":"bonded"},
+             "apr_estimate":{"low":"6","high":"8"},"can_allocate":true,"can_deallocate":true},
+            {"id":"ESDOT","asset":"DOT","lock_type":{"type":"bonded"},
+             "apr_estimate":{"low":"10","high":"12"},"can_allocate":true,"can_deallocate":true}
+            ]}"#,
+        )
+        .unwrap();
+        let allocations = serde_json::from_str::<EarnAllocations>(
+            r#"{"items":[
+            {"strategy_id":"ESFLEX","amount_allocated":{"total":{"native":"3"}},
+             "total_rewarded":{"native":"0.1"}},
+            {"strategy_id":"ESBOND","amount_allocated":{"total":{"native":"2"}},
+             "total_rewarded":{"native":"0.2"}}
+            ]}"#,
+        )
+        .unwrap();
+        let balances = HashMap::from([(
+            "SOL".to_string(),
+            ExchangeBalance {
+                available: 1.,
+                total: 6.,
+            },
+        )]);
+
+        let all_lending_info = earn_lending_info(strategies.items, &allocations.items, &balances);
+        // Nothing is held of DOT
+        assert_eq!(all_lending_info.keys().collect::<Vec<_>>(), vec!["SOL"]);
+        let lending_info = &all_lending_info["SOL"];
+        assert_eq!(lending_info.lendable, 1.);
+        assert_eq!(lending_info.offered, 5.);
+        assert_eq!(lending_info.locked, 2.);
+        assert_eq!(lending_info.estimate_rate, 8.);
+    }
+
+    const OPEN_ORDERS: &str = r#"
+==> 2024-03-21T15:04:50Z POST https://api.kraken.com/0/private/OpenOrders
+API-Key: <redacted>
+
+nonce=1711033490456
+<== 200 in 90ms
+{"error":[],"result":{"open":{"OQCLML-BW3P3-BUCMWZ":{"refid":null,"userref":0,"cl_ord_id":null,
+"status":"open","opentm":1711033446.1,"starttm":0,"expiretm":0,"descr":{"pair":"SOLUSD",
+"type":"sell","ordertype":"limit","price":"500.00","price2":"0","leverage":"none",
+"order":"sell 2.00000000 SOLUSD @ limit 500.00","close":""},"vol":"2.00000000",
+"vol_exec":"0.00000000","cost":"0.00000","fee":"0.00000","price":"0.00000",
+"stopprice":"0.00000","limitprice":"0.00000","misc":"","oflags":"fciq,post"}}}}
+"#;
+
+    #[tokio::test]
+    async fn open_orders_include_those_placed_elsewhere() {
+        let server = crate::conformance::ReplayServer::start(OPEN_ORDERS)
+            .await
+            .unwrap();
+        let client = crate::conformance::replay_client(Exchange::Kraken, &server).unwrap();
+
+        assert_eq!(
+            client.open_orders().await.unwrap(),
+            vec![ExchangeOpenOrder {
+                pair: TradingPair::new("SOL", "USD"),
+                order_id: "OQCLML-BW3P3-BUCMWZ".into(),
+                side: OrderSide::Sell,
+                price: 500.,
+                amount: 2.,
+            }]
+        );
+        assert_eq!(server.unexpected_requests(), Vec::<String>::new());
+    }
+}
@@ -1,14 +1,29 @@
 use solana_client::rpc_client::{RpcClient, SerializableTransaction};
 
+#[cfg(feature = "binance")]
 pub mod binance_exchange;
 pub mod coin_gecko;
+#[cfg(feature = "coinbase")]
 pub mod coinbase_exchange;
+pub mod confirmation;
+pub mod conformance;
+pub mod debug_capture;
 pub mod exchange;
+pub mod field_as_string;
+#[cfg(feature = "ftx")]
 pub mod ftx_exchange;
+#[cfg(feature = "kraken")]
 pub mod kraken_exchange;
 pub mod metrics;
+pub mod paper_exchange;
+pub mod pre_trade;
+pub mod rate_limit;
+pub mod request_metrics;
+pub mod settings;
+pub mod stream_session;
 pub mod token;
 pub mod tulip;
+pub mod two_factor;
 
 pub fn app_version() -> String {
     let tag = option_env!("GITHUB_REF")
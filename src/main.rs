@@ -1,13 +1,17 @@
 mod amount;
+mod cancellation;
 mod db;
-mod field_as_string;
 mod get_transaction_balance_change;
+mod managed_order;
 mod notifier;
+mod outcome;
 mod rpc_client_utils;
 mod stake_spreader;
 
 use {
     crate::{amount::Amount, get_transaction_balance_change::*},
+    async_trait::async_trait,
+    cancellation::Cancellation,
     chrono::prelude::*,
     chrono_humanize::HumanTime,
     clap::{
@@ -18,9 +22,12 @@ use {
     db::*,
     itertools::Itertools,
     notifier::*,
+    outcome::{ConfigurationError, OperationKind},
     rpc_client_utils::get_signature_date,
     rust_decimal::prelude::*,
     separator::FixedPlaceSeparatable,
+    serde::{Deserialize, Serialize},
+    sha1::{Digest, Sha1},
     solana_clap_utils::{self, input_parsers::*, input_validators::*},
     solana_client::{
         rpc_client::RpcClient, rpc_config::RpcTransactionConfig, rpc_response::StakeActivationState,
@@ -30,6 +37,7 @@ use {
         commitment_config::CommitmentConfig,
         message::Message,
         native_token::lamports_to_sol,
+        program_pack::Pack,
         pubkey::Pubkey,
         signature::{read_keypair_file, Keypair, Signature, Signer},
         signers::Signers,
@@ -37,21 +45,34 @@ use {
         transaction::Transaction,
     },
     std::{
-        collections::{BTreeMap, HashSet},
+        collections::{BTreeMap, BTreeSet, HashMap, HashSet},
         fs,
-        path::PathBuf,
+        path::{Path, PathBuf},
         process::exit,
         str::FromStr,
+        sync::Arc,
         thread::sleep,
         time::Duration,
     },
     sys::{
-        app_version,
+        app_version, coin_gecko,
+        confirmation::{self, IrreversibleOperation},
+        conformance::{self, CheckOutcome},
+        debug_capture,
         exchange::{self, *},
+        field_as_string,
         metrics::{self, dp, MetricsConfig},
-        send_transaction_until_expired,
+        paper_exchange::PaperExchangeClient,
+        pre_trade::{
+            self, FiatWithdrawalRequest, OrderIntent, PreTradeCheck, PreWithdrawalCheck, Rejection,
+            SpreadGuard, WithdrawalRequest,
+        },
+        rate_limit, request_metrics, send_transaction_until_expired,
+        settings::{self, Setting, POSSIBLE_SETTING_VALUES},
+        stream_session,
         token::*,
         tulip,
+        two_factor::{PromptCode, StaticCode, TotpCode, TwoFactorCodeProvider},
     },
 };
 
@@ -99,22 +120,66 @@ fn format_order_side(order_side: OrderSide) -> String {
     .to_string()
 }
 
-fn format_filled_amount(filled_amount: f64) -> String {
+// Formats an order price when pair metadata isn't at hand: with `decimals` places in a dollar or
+// fiat quote, and in full otherwise as crypto-quoted prices such as SOL/BTC are fractions of a unit
+fn format_order_price(price: f64, quote: &str, decimals: usize) -> String {
+    let price = if is_fiat(quote) || is_usd_equivalent(quote) {
+        format_decimals(price, decimals, false)
+    } else {
+        format_increment(price, None, false)
+    };
+    format_quote_amount(&price, quote)
+}
+
+fn format_filled_amount(
+    filled_amount: f64,
+    average_fill_price: Option<f64>,
+    pair: &TradingPair,
+) -> String {
     if filled_amount == 0. {
         Style::new()
     } else {
         Style::new().bold()
     }
-    .apply_to(format!(" [◎{filled_amount} filled]"))
+    .apply_to(match average_fill_price {
+        Some(average_fill_price) => format!(
+            " [◎{filled_amount} filled at {} average]",
+            format_order_price(average_fill_price, &pair.quote, 4)
+        ),
+        None => format!(" [◎{filled_amount} filled]"),
+    })
     .to_string()
 }
 
+// Lots and disposals are recorded in USD, so the fill price of a crypto-quoted market such as
+// SOL/BTC is converted at the quote's USD price when the order filled.  Dollar and fiat quotes are
+// recorded as quoted
+async fn fill_price_in_usd(
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    price: f64,
+    when: DateTime<Utc>,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    if is_fiat(&pair.quote) || is_usd_equivalent(&pair.quote) {
+        return Ok(price);
+    }
+    let (quote_usd_price, _daily_close) = usd_price_at(exchange_client, &pair.quote, when).await?;
+    Ok(price * quote_usd_price)
+}
+
 fn naivedate_of(string: &str) -> Result<NaiveDate, String> {
     NaiveDate::parse_from_str(string, "%y/%m/%d")
         .or_else(|_| NaiveDate::parse_from_str(string, "%Y/%m/%d"))
         .map_err(|err| format!("error parsing '{string}': {err}"))
 }
 
+fn is_balance_snapshot_selector(string: String) -> Result<(), String> {
+    if string.parse::<usize>().is_ok() {
+        return Ok(());
+    }
+    naivedate_of(&string).map(|_| ())
+}
+
 async fn get_block_date_and_price(
     rpc_client: &RpcClient,
     slot: Slot,
@@ -149,6 +214,49 @@ async fn retry_get_historical_price(
     token.get_historical_price(rpc_client, block_date).await
 }
 
+// Daily close of `token` on `when` from the first configured exchange that listed it by then,
+// falling back to CoinGecko.  Historical prices never change, so they are cached in the database
+async fn get_historical_price_from_exchanges(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    token: MaybeToken,
+    when: NaiveDate,
+) -> Result<(Decimal, String), Box<dyn std::error::Error>> {
+    for (exchange, exchange_client) in
+        configured_exchange_clients(db, |capabilities| capabilities.supports_trading)?
+    {
+        let pair = match exchange_client.preferred_usd_pair(token).await {
+            Ok(pair) => pair,
+            Err(_) => continue,
+        };
+        let source = format!("{exchange:?}");
+        let pair_name = pair.to_string();
+
+        if let Some(price) = db.get_historical_price(&source, &pair_name, when) {
+            return Ok((price, format!("{source} {pair_name}")));
+        }
+        match exchange_client.price_on(&pair, when).await {
+            Ok(price) => {
+                let price = Decimal::from_f64(price).unwrap();
+                db.set_historical_price(&source, &pair_name, when, price)?;
+                return Ok((price, format!("{source} {pair_name}")));
+            }
+            Err(err) => eprintln!("{exchange:?}: {err}"),
+        }
+    }
+
+    let source = "CoinGecko";
+    let pair_name = format!("{token}/USD");
+    if let Some(price) = db.get_historical_price(source, &pair_name, when) {
+        return Ok((price, source.into()));
+    }
+    let price = retry_get_historical_price(rpc_client, when, token)
+        .await
+        .map_err(|err| format!("No {token} price available on {when}: {err}"))?;
+    db.set_historical_price(source, &pair_name, when, price)?;
+    Ok((price, source.into()))
+}
+
 fn add_exchange_deposit_address_to_db(
     db: &mut Db,
     exchange: Exchange,
@@ -172,6 +280,168 @@ fn add_exchange_deposit_address_to_db(
     Ok(())
 }
 
+// The exchange's current deposit address for `token`, checked against the address pinned for the
+// account.  The first address seen is pinned, after which a different one is refused until it is
+// accepted with `address --accept`
+// The exchange's deposit address for `token`, from the cache while it was fetched within
+// `deposit_address_cache_ttl()`, so an address is never used without the exchange having reported
+// it within that window
+async fn exchange_deposit_address(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+) -> Result<Pubkey, Box<dyn std::error::Error>> {
+    if let Some(cached) = db.cached_deposit_address(exchange, exchange_account, token) {
+        if Utc::now() - cached.verified_at < exchange::deposit_address_cache_ttl() {
+            return Ok(cached.address);
+        }
+    }
+    let deposit_address = exchange_client.deposit_address(token).await?;
+    db.cache_deposit_address(exchange, exchange_account, token, deposit_address)?;
+    Ok(deposit_address)
+}
+
+// The exchange's deposit address for `token`, once `check_deposit_address()` has passed it
+async fn verified_deposit_address(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+) -> Result<Pubkey, Box<dyn std::error::Error>> {
+    let deposit_address =
+        exchange_deposit_address(db, exchange, exchange_account, exchange_client, token).await?;
+    check_deposit_address(db, exchange, exchange_account, token, deposit_address)
+}
+
+// Checks `deposit_address`, as reported by the exchange, against the address pinned for the
+// account.  The first address seen is pinned, after which a different one is refused until it is
+// accepted with `address --accept`
+fn check_deposit_address(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    token: MaybeToken,
+    deposit_address: Pubkey,
+) -> Result<Pubkey, Box<dyn std::error::Error>> {
+    match db.pinned_deposit_address(exchange, exchange_account, token) {
+        None => {
+            db.pin_deposit_address(exchange, exchange_account, token, deposit_address)?;
+            println!("Pinned {exchange:?} {token} deposit address: {deposit_address}");
+            Ok(deposit_address)
+        }
+        Some(pinned) if pinned.address == deposit_address => Ok(deposit_address),
+        Some(pinned) => Err(format!(
+            "{exchange:?} now reports {deposit_address} as the {token} deposit address for \
+             account '{exchange_account}', but {} was pinned {}. Confirm the new address with \
+             the exchange, then accept it with `sys {} address {token} --accept {deposit_address}`",
+            pinned.address,
+            HumanTime::from(pinned.pinned_at),
+            exchange.name(),
+        )
+        .into()),
+    }
+}
+
+/// When an in-transit deposit was sent, and whether it's overdue
+fn describe_deposit_transit(deposit: &InTransitDeposit, credit_window: chrono::Duration) -> String {
+    match deposit.sent_at {
+        Some(sent_at) if Utc::now() - sent_at > credit_window => {
+            format!(", sent {}, OVERDUE", HumanTime::from(sent_at))
+        }
+        Some(sent_at) => format!(", sent {}", HumanTime::from(sent_at)),
+        None => String::new(),
+    }
+}
+
+// Slack given to an exchange beyond the crediting time its deposit requirements suggest
+const DEPOSIT_CREDIT_MARGIN_MINUTES: i64 = 30;
+
+/// How long `exchange` may take to credit a deposit of `token` before it's reported as overdue.
+/// Exchanges wait for different numbers of confirmations, so this is the crediting time expected
+/// from the exchange's deposit requirements plus a margin, unless a window was configured.
+/// Exchanges that don't publish their requirements get a fixed window
+async fn deposit_credit_window(
+    db: &Db,
+    exchange: Exchange,
+    exchange_client: Option<&dyn ExchangeClient>,
+    token: MaybeToken,
+) -> chrono::Duration {
+    if let Some(hours) = db.deposit_credit_window(exchange) {
+        return chrono::Duration::hours(hours.into());
+    }
+    if let Some(exchange_client) = exchange_client {
+        match exchange_client.deposit_requirements(token).await {
+            Ok(deposit_requirements) => {
+                if let Some(expected_credit_time) = deposit_requirements.expected_credit_time() {
+                    return expected_credit_time
+                        + chrono::Duration::minutes(DEPOSIT_CREDIT_MARGIN_MINUTES);
+                }
+            }
+            Err(err) => eprintln!("Unable to get {exchange:?} {token} deposit requirements: {err}"),
+        }
+    }
+    chrono::Duration::hours(match exchange {
+        Exchange::Binance | Exchange::BinanceUs => 2,
+        Exchange::Coinbase => 3,
+        Exchange::Ftx | Exchange::FtxUs | Exchange::Kraken => 1,
+    })
+}
+
+fn format_credit_window(credit_window: chrono::Duration) -> String {
+    if credit_window.num_minutes() < 120 {
+        format!("{} minutes", credit_window.num_minutes())
+    } else {
+        format!("{} hours", credit_window.num_hours())
+    }
+}
+
+// Fill and fee entries for the exchange journal of an order, timed at `time`
+fn order_journal_entries(
+    exchange: Exchange,
+    pair: &TradingPair,
+    order_id: &str,
+    order_status: &OrderStatus,
+    time: DateTime<Utc>,
+) -> Vec<ExchangeJournalEntry> {
+    if order_status.filled_amount < f64::EPSILON {
+        return vec![];
+    }
+    let sign = match order_status.side {
+        OrderSide::Buy => 1.,
+        OrderSide::Sell => -1.,
+    };
+    let quote_amount = order_status
+        .cumulative_quote_amount
+        .unwrap_or(order_status.filled_amount * order_status.fill_price());
+    let entry = |kind, coin: &str, amount| ExchangeJournalEntry {
+        time,
+        exchange,
+        kind,
+        coin: coin.to_string(),
+        amount,
+        reference: order_id.to_string(),
+        backfilled: false,
+    };
+
+    let mut entries = vec![
+        entry(
+            ExchangeJournalKind::Fill,
+            &pair.base,
+            sign * order_status.filled_amount,
+        ),
+        entry(ExchangeJournalKind::Fill, &pair.quote, -sign * quote_amount),
+    ];
+    if let Some((fee, coin)) = &order_status.fee {
+        if *fee > 0. {
+            entries.push(entry(ExchangeJournalKind::Fee, coin, -fee));
+        }
+    }
+    entries
+}
+
 async fn process_sync_exchange(
     db: &mut Db,
     exchange: Exchange,
@@ -207,9 +477,7 @@ async fn process_sync_exchange(
                     "{} {}{} withdrawal to {} successful ({})",
                     token,
                     token.symbol(),
-                    token
-                        .ui_amount(pending_withdrawal.amount)
-                        .separated_string_with_fixed_place(2),
+                    format_token_amount(token, token.ui_amount(pending_withdrawal.amount), true),
                     wi.address,
                     tx_id,
                 );
@@ -219,6 +487,15 @@ async fn process_sync_exchange(
                 notifier.send(&format!("{exchange:?}: {msg}")).await;
             } else {
                 println!("Pending {} withdrawal to {} cancelled", token, wi.address);
+                db.record_exchange_journal([ExchangeJournalEntry {
+                    time: Utc::now(),
+                    exchange,
+                    kind: ExchangeJournalKind::Withdrawal,
+                    coin: token.name().to_string(),
+                    amount: token.ui_amount(pending_withdrawal.amount + pending_withdrawal.fee),
+                    reference: pending_withdrawal.tag.clone(),
+                    backfilled: false,
+                }])?;
                 db.cancel_withdrawal(pending_withdrawal)?;
             }
         } else {
@@ -226,11 +503,12 @@ async fn process_sync_exchange(
                 "{} {}{} withdrawal to {} pending",
                 token,
                 token.symbol(),
-                token.ui_amount(pending_withdrawal.amount),
+                format_token_amount(token, token.ui_amount(pending_withdrawal.amount), true),
                 wi.address,
             );
         }
     }
+    sync_fiat_withdrawals(db, exchange, exchange_client, notifier).await?;
 
     for pending_deposit in db.pending_deposits(Some(exchange)) {
         let response = rpc_client
@@ -271,6 +549,15 @@ async fn process_sync_exchange(
                 None => {
                     if token.fiat_fungible() {
                         db.drop_deposit(pending_deposit.transfer.signature)?;
+                        db.record_exchange_journal([ExchangeJournalEntry {
+                            time: Utc::now(),
+                            exchange,
+                            kind: ExchangeJournalKind::Deposit,
+                            coin: token.name().to_string(),
+                            amount: token.ui_amount(pending_deposit.amount),
+                            reference: pending_deposit.transfer.signature.to_string(),
+                            backfilled: false,
+                        }])?;
 
                         let msg = format!(
                             "{} {}{} BLIND deposit successful ({})",
@@ -318,6 +605,25 @@ async fn process_sync_exchange(
                                 get_signature_date(rpc_client, pending_deposit.transfer.signature)
                                     .await?;
                             db.confirm_deposit(pending_deposit.transfer.signature, when)?;
+                            db.record_exchange_journal([ExchangeJournalEntry {
+                                time: Utc::now(),
+                                exchange,
+                                kind: ExchangeJournalKind::Deposit,
+                                coin: token.name().to_string(),
+                                amount: deposit_info.amount,
+                                reference: pending_deposit.transfer.signature.to_string(),
+                                backfilled: false,
+                            }])?;
+                            let _ = outcome::record(
+                                OperationKind::DepositMatched,
+                                format!(
+                                    "{exchange:?}: {token} {}{}",
+                                    token.symbol(),
+                                    token.ui_amount(pending_deposit.amount)
+                                ),
+                                Ok::<_, String>(()),
+                                |()| pending_deposit.transfer.signature.to_string(),
+                            );
 
                             let msg = format!(
                                 "{} {}{} deposit successful ({})",
@@ -354,23 +660,106 @@ async fn process_sync_exchange(
         }
     }
 
+    if let Some(recent_deposits) = recent_deposits.as_ref() {
+        for deposit in db.in_transit_deposits(Some(exchange)) {
+            if !deposit.external
+                || !recent_deposits
+                    .iter()
+                    .any(|deposit_info| deposit_info.tx_id == deposit.signature.to_string())
+            {
+                continue;
+            }
+            db.remove_external_deposit(deposit.signature)?;
+            let msg = format!(
+                "{} {}{} deposit credited ({})",
+                deposit.token,
+                deposit.token.symbol(),
+                deposit.token.ui_amount(deposit.amount),
+                deposit.signature
+            );
+            println!("{msg}");
+            notifier.send(&format!("{exchange:?}: {msg}")).await;
+        }
+    }
+
+    for deposit in db.in_transit_deposits(Some(exchange)) {
+        if deposit.overdue_warned {
+            continue;
+        }
+        let credit_window =
+            deposit_credit_window(db, exchange, Some(exchange_client), deposit.token).await;
+        let overdue = deposit
+            .sent_at
+            .map_or(false, |sent_at| Utc::now() - sent_at > credit_window);
+        if overdue {
+            let msg = format!(
+                "WARNING: {} {}{} deposit not credited within {} of being sent ({})",
+                deposit.token,
+                deposit.token.symbol(),
+                deposit.token.ui_amount(deposit.amount),
+                format_credit_window(credit_window),
+                deposit.signature
+            );
+            println!("{}", style(&msg).red().bold());
+            notifier.send(&format!("{exchange:?}: {msg}")).await;
+            db.set_deposit_overdue_warned(deposit.signature)?;
+        }
+    }
+
+    for managed_order in managed_order::recover(db, exchange_client, exchange).await? {
+        println!(
+            "Reconciled {} order {}: {}{}",
+            managed_order.strategy,
+            managed_order
+                .order_id
+                .as_ref()
+                .unwrap_or(&managed_order.client_order_id),
+            managed_order.state,
+            managed_order
+                .detail
+                .map(|detail| format!(" ({detail})"))
+                .unwrap_or_default()
+        );
+    }
+
     for order_info in db.open_orders(Some(exchange), None) {
         let token = order_info.token;
-        let order_status = exchange_client
-            .order_status(&order_info.pair, &order_info.order_id)
-            .await?;
+        let pair = TradingPair::from_exchange_symbol(exchange, &order_info.pair)?;
+        let expired = order_info
+            .expiry
+            .map_or(false, |expiry| expiry <= Utc::now());
+        let order_status = if expired && !order_info.native_expiry {
+            // Cancelling returns the final state, so a fill that lands during the cancel is
+            // still recorded below
+            match cancel_open_order(db, exchange_client, &pair, &order_info).await {
+                Ok(order_status) => order_status,
+                Err(err) => {
+                    eprintln!(
+                        "Unable to cancel expired order {}: {err}",
+                        order_info.order_id
+                    );
+                    continue;
+                }
+            }
+        } else {
+            open_order_status(db, exchange_client, &pair, &order_info).await?
+        };
         let order_summary = format!(
-            "{}: {} {} {}{:<5} at ${:<.2}{} | id {} created {}",
+            "{}: {} {} {}{:<5} at {}{} | id {} created {}",
             order_info.pair,
             token,
             format_order_side(order_info.side),
             token.symbol(),
             order_status.amount,
-            order_status.price,
+            format_order_price(order_status.price, &pair.quote, 2),
             if order_status.filled_amount == 0. {
                 String::default()
             } else {
-                format_filled_amount(order_status.filled_amount)
+                format_filled_amount(
+                    order_status.filled_amount,
+                    order_status.average_fill_price,
+                    &pair,
+                )
             },
             order_info.order_id,
             HumanTime::from(order_info.creation_time),
@@ -385,18 +774,74 @@ async fn process_sync_exchange(
                 println!("   Open {order_summary}");
             }
         } else {
-            let fee_summary = match &order_status.fee {
-                Some((amount, coin)) if *amount > 0. => format!(" (fee: {amount} {coin})"),
+            // Orders are synced shortly after they fill, so a fill reported today is valued at
+            // the current minute
+            let when = if order_status.last_update == Utc::now().date().naive_local() {
+                Utc::now()
+            } else {
+                Utc.from_utc_date(&order_status.last_update)
+                    .and_hms(0, 0, 0)
+            };
+            let usd_price = if order_status.filled_amount > 0. {
+                match fill_price_in_usd(exchange_client, &pair, order_status.fill_price(), when)
+                    .await
+                {
+                    Ok(usd_price) => usd_price,
+                    Err(err) => {
+                        eprintln!(
+                            "Unable to value {} fill in USD, retrying next sync: {err}",
+                            order_info.order_id
+                        );
+                        continue;
+                    }
+                }
+            } else {
+                order_status.fill_price()
+            };
+            let usd_fee = match &order_status.fee {
+                Some(fee) if fee.0 > 0. => {
+                    match fee_in_usd(exchange_client, &pair, order_status.fill_price(), fee, when)
+                        .await
+                    {
+                        Ok(usd_fee) => Some(usd_fee),
+                        Err(err) => {
+                            eprintln!("Unable to value {} fee in USD: {err}", order_info.order_id);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let fee_summary = match (&order_status.fee, &usd_fee) {
+                (Some((amount, coin)), Some(usd_fee)) if *amount > 0. => {
+                    format!(" (fee: {amount} {coin}, {usd_fee})")
+                }
+                (Some((amount, coin)), None) if *amount > 0. => format!(" (fee: {amount} {coin})"),
                 _ => "".into(),
             };
+            // A cancel `sys` didn't ask for, such as self-trade prevention or a delisting, is
+            // called out so it isn't mistaken for a quiet market
+            let exchange_cancel = match order_status.exchange_cancel_reason() {
+                Some(reason) if !expired => format!(" | cancelled by the exchange: {reason}"),
+                _ => String::new(),
+            };
             db.close_order(
                 &order_info.order_id,
                 token.amount(order_status.amount),
                 token.amount(order_status.filled_amount),
-                order_status.price,
+                usd_price,
                 order_status.last_update,
-                order_status.fee,
+                order_status.fee.clone(),
+                usd_fee,
             )?;
+            // Timed when synced, as the exchange only reports the day an order last changed
+            db.record_exchange_journal(order_journal_entries(
+                exchange,
+                &pair,
+                &order_info.order_id,
+                &order_status,
+                Utc::now(),
+            ))?;
 
             if order_status.filled_amount > f64::EPSILON {
                 metrics::push(dp::exchange_fill(
@@ -405,17 +850,19 @@ async fn process_sync_exchange(
                     order_info.side,
                     token,
                     order_status.filled_amount,
-                    order_status.price,
+                    order_status.fill_price(),
                 ))
                 .await;
             }
 
             let msg = if (order_status.amount - order_status.filled_amount).abs() < f64::EPSILON {
                 format!(" Filled {order_summary}{fee_summary}")
+            } else if expired {
+                format!("Expired {order_summary}{fee_summary}")
             } else if order_status.filled_amount < f64::EPSILON {
-                format!(" Cancel {order_summary}{fee_summary}")
+                format!(" Cancel {order_summary}{fee_summary}{exchange_cancel}")
             } else {
-                format!("Partial {order_summary}{fee_summary}")
+                format!("Partial {order_summary}{fee_summary}{exchange_cancel}")
             };
             println!("{msg}");
             notifier.send(&format!("{exchange:?}: {msg}")).await;
@@ -434,6 +881,7 @@ async fn process_exchange_deposit<T: Signers>(
     token: MaybeToken,
     deposit_address: Pubkey,
     amount: Amount,
+    sol_reserve: Option<u64>,
     from_address: Pubkey,
     if_source_balance_exceeds: Option<u64>,
     if_exchange_balance_less_than: Option<u64>,
@@ -441,18 +889,18 @@ async fn process_exchange_deposit<T: Signers>(
     signers: T,
     lot_selection_method: LotSelectionMethod,
     lot_numbers: Option<HashSet<usize>>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<Option<Signature>, Box<dyn std::error::Error>> {
     if let Some(if_exchange_balance_less_than) = if_exchange_balance_less_than {
-        let exchange_balance = exchange_client
-            .balances()
-            .await?
-            .get(&token.to_string())
-            .map(|b| token.amount(b.total))
-            .unwrap_or(0)
-            + db.pending_deposits(Some(exchange))
-                .into_iter()
-                .map(|pd| pd.amount)
-                .sum::<u64>();
+        let exchange_balance = token.amount(
+            token_balances(exchange, exchange_client)
+                .await?
+                .get(token)
+                .total,
+        ) + db
+            .pending_deposits(Some(exchange))
+            .into_iter()
+            .map(|pd| pd.amount)
+            .sum::<u64>();
 
         if exchange_balance < if_exchange_balance_less_than {
             println!(
@@ -463,7 +911,7 @@ async fn process_exchange_deposit<T: Signers>(
                 token.ui_amount(if_exchange_balance_less_than),
                 token.symbol(),
             );
-            return Ok(());
+            return Ok(None);
         }
     }
 
@@ -481,7 +929,7 @@ async fn process_exchange_deposit<T: Signers>(
                 token.symbol(),
                 token.ui_amount(if_source_balance_exceeds)
             );
-            return Ok(());
+            return Ok(None);
         }
     }
 
@@ -512,19 +960,44 @@ async fn process_exchange_deposit<T: Signers>(
         .into());
     }
 
+    // What `sol_reserve` kept back and why, for the summary
+    let mut reserve_summary = None;
+    let mut creates_deposit_token_account = false;
+
     let (instructions, amount) = match token.token() {
         /*SOL*/
         None => {
             assert_eq!(from_account.lamports, from_account_balance);
 
             if from_account.owner == system_program::id() {
-                let amount = amount.unwrap_or_else(|| {
-                    if from_address == authority_address {
-                        from_account_balance.saturating_sub(fee_calculator.lamports_per_signature)
-                    } else {
-                        from_account_balance
+                let fee = if from_address == authority_address {
+                    fee_calculator.lamports_per_signature
+                } else {
+                    0
+                };
+                let amount = match sol_reserve {
+                    Some(sol_reserve) => {
+                        let kept = sol_reserve + fee;
+                        if from_account_balance <= kept {
+                            return Err(format!(
+                                "{from_address} balance of ◎{} does not exceed the ◎{} reserve \
+                                 and transaction fee",
+                                lamports_to_sol(from_account_balance),
+                                lamports_to_sol(kept),
+                            )
+                            .into());
+                        }
+                        reserve_summary = Some(format!(
+                            "◎{} left in {from_address}: ◎{} reserve for rent and future \
+                             transaction fees, ◎{} fee for this transaction",
+                            lamports_to_sol(kept),
+                            lamports_to_sol(sol_reserve),
+                            lamports_to_sol(fee),
+                        ));
+                        from_account_balance - kept
                     }
-                });
+                    None => amount.unwrap_or_else(|| from_account_balance.saturating_sub(fee)),
+                };
 
                 (
                     vec![system_instruction::transfer(
@@ -534,6 +1007,10 @@ async fn process_exchange_deposit<T: Signers>(
                     )],
                     amount,
                 )
+            } else if sol_reserve.is_some() {
+                return Err(
+                    "A SOL reserve can only be kept when depositing from a system account".into(),
+                );
             } else if from_account.owner == solana_vote_program::id() {
                 let minimum_balance = rpc_client.get_minimum_balance_for_rent_exemption(
                     solana_vote_program::vote_state::VoteState::size_of(),
@@ -580,6 +1057,7 @@ async fn process_exchange_deposit<T: Signers>(
                 .value
                 .is_none()
             {
+                creates_deposit_token_account = true;
                 instructions.push(
                     spl_associated_token_account::instruction::create_associated_token_account_idempotent(
                         &authority_address,
@@ -614,6 +1092,18 @@ async fn process_exchange_deposit<T: Signers>(
     if from_account_balance < amount {
         return Err("From account has insufficient funds".into());
     }
+    if let Some(deposit_minimum) = exchange_client.deposit_minimum(token).await? {
+        if token.ui_amount(amount) < deposit_minimum {
+            return Err(format!(
+                "Deposit of {}{} is below the {exchange:?} minimum deposit of {}{}",
+                token.symbol(),
+                token.ui_amount(amount),
+                token.symbol(),
+                deposit_minimum,
+            )
+            .into());
+        }
+    }
 
     println!("From address: {from_address} ({token})");
     if from_address != authority_address {
@@ -624,10 +1114,44 @@ async fn process_exchange_deposit<T: Signers>(
 
     let mut message = Message::new(&instructions, Some(&authority_address));
     message.recent_blockhash = recent_blockhash;
-    if rpc_client.get_fee_for_message(&message)? > authority_account.lamports {
+    let fee = rpc_client.get_fee_for_message(&message)?;
+    if fee > authority_account.lamports {
         return Err("Insufficient funds for transaction fee".into());
     }
 
+    // SPL tokens are swept entirely, the reserve is the SOL the authority keeps for fees
+    if let (Some(sol_reserve), Some(_)) = (sol_reserve, token.token()) {
+        let rent = if creates_deposit_token_account {
+            rpc_client.get_minimum_balance_for_rent_exemption(spl_token::state::Account::LEN)?
+        } else {
+            0
+        };
+        let remaining = authority_account.lamports.saturating_sub(fee + rent);
+        if remaining < sol_reserve {
+            return Err(format!(
+                "{authority_address} would be left with ◎{}, less than the ◎{} reserve",
+                lamports_to_sol(remaining),
+                lamports_to_sol(sol_reserve),
+            )
+            .into());
+        }
+        reserve_summary = Some(format!(
+            "◎{} left in {authority_address}, at least the ◎{} reserve for future transaction \
+             fees, after ◎{} for this transaction{}",
+            lamports_to_sol(remaining),
+            lamports_to_sol(sol_reserve),
+            lamports_to_sol(fee + rent),
+            if rent > 0 {
+                " including rent for the deposit token account"
+            } else {
+                ""
+            },
+        ));
+    }
+    if let Some(reserve_summary) = reserve_summary {
+        println!("Kept: {reserve_summary}");
+    }
+
     let mut transaction = Transaction::new_unsigned(message);
     let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
     if simulation_result.err.is_some() {
@@ -649,3763 +1173,14258 @@ async fn process_exchange_deposit<T: Signers>(
         lot_selection_method,
         lot_numbers,
     )?;
-    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-        return Err("Deposit failed".into());
-    }
-    Ok(())
+    let sent = send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height);
+    outcome::record(
+        OperationKind::DepositSent,
+        format!(
+            "{exchange:?}: {token} {}{} from {from_address}",
+            token.symbol(),
+            token.ui_amount(amount)
+        ),
+        if sent { Ok(()) } else { Err("Deposit failed") },
+        |()| signature.to_string(),
+    )?;
+    Ok(Some(signature))
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_exchange_withdraw(
-    db: &mut Db,
-    exchange: Exchange,
-    exchange_client: &dyn ExchangeClient,
-    token: MaybeToken,
-    deposit_address: Pubkey,
-    amount: Option<u64>,
-    to_address: Pubkey,
-    lot_selection_method: LotSelectionMethod,
-    lot_numbers: Option<HashSet<usize>>,
-    withdrawal_password: Option<String>,
-    withdrawal_code: Option<String>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let deposit_account = db
-        .get_account(deposit_address, token)
-        .expect("unknown deposit address");
-    let _to_account = db
-        .get_account(to_address, token)
-        .expect("unknown to address");
-
-    let amount = amount.unwrap_or(deposit_account.last_update_balance);
-
-    let (tag, fee_as_ui_amount) = exchange_client
-        .request_withdraw(
-            to_address,
-            token,
-            token.ui_amount(amount),
-            withdrawal_password,
-            withdrawal_code,
-        )
-        .await?;
+// Signs, simulates and sends a transaction of `instructions` paid for by `authority_address`,
+// returning its signature and last valid block height before it's sent so the caller can record it
+fn sign_stake_funding_transaction(
+    rpc_client: &RpcClient,
+    instructions: &[solana_sdk::instruction::Instruction],
+    authority_address: Pubkey,
+    signers: &[&dyn Signer],
+) -> Result<(Transaction, u64), Box<dyn std::error::Error>> {
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+    let mut message = Message::new(instructions, Some(&authority_address));
+    message.recent_blockhash = recent_blockhash;
+    if rpc_client.get_fee_for_message(&message)? > rpc_client.get_balance(&authority_address)? {
+        return Err("Insufficient funds for transaction fee".into());
+    }
 
-    let fee = token.amount(fee_as_ui_amount);
-    db.record_withdrawal(
-        exchange,
-        tag,
-        token,
-        amount,
-        fee,
-        deposit_address,
-        to_address,
-        lot_selection_method,
-        lot_numbers,
-    )?;
-    Ok(())
+    let mut transaction = Transaction::new_unsigned(message);
+    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+    if simulation_result.err.is_some() {
+        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    }
+    transaction.try_sign(signers, recent_blockhash)?;
+    Ok((transaction, last_valid_block_height))
 }
 
-enum LimitOrderPrice {
-    At(f64),
-    AmountOverAsk(f64),
-    AmountUnderBid(f64),
+fn print_stake_funding_summary(funding: &StakeFunding) {
+    println!(
+        "{:?} funding from stake account {} via {}",
+        funding.exchange, funding.stake_address, funding.wallet_address
+    );
+    for step in &funding.steps {
+        println!(
+            "  {:?}: {}{} ({})",
+            step.kind,
+            step.signature,
+            step.amount
+                .map(|amount| format!(", ◎{}", lamports_to_sol(amount)))
+                .unwrap_or_default(),
+            step.time.to_rfc3339(),
+        );
+    }
+    match &funding.state {
+        StakeFundingState::Completed { exchange_tx_id } => {
+            println!("  Credited by {:?} as {exchange_tx_id}", funding.exchange)
+        }
+        state => println!("  {state:?}"),
+    }
 }
 
+/// Moves the SOL in a stake account onto an exchange: deactivate the stake, withdraw it to the
+/// wallet once inactive, then deposit it to the exchange keeping `sol_reserve` in the wallet.
+/// Each step is recorded before the next is taken, so running again resumes where the last run
+/// stopped.  Returns once the deposit is credited, or as soon as a step has to wait unless `wait`
 #[allow(clippy::too_many_arguments)]
-async fn process_exchange_cancel(
+async fn process_exchange_fund_from_stake(
     db: &mut Db,
+    rpc_client: &RpcClient,
     exchange: Exchange,
+    exchange_account: &str,
     exchange_client: &dyn ExchangeClient,
-    order_ids: HashSet<String>,
-    max_create_time: Option<DateTime<Utc>>,
-    side: Option<OrderSide>,
+    stake_address: Pubkey,
+    sol_reserve: u64,
+    authority_address: Pubkey,
+    signers: &[&dyn Signer],
+    lot_selection_method: LotSelectionMethod,
+    wait: Option<&Cancellation>,
+    notifier: &Notifier,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut cancelled_count = 0;
-    for order_info in db.open_orders(Some(exchange), side) {
-        let mut cancel = false;
-        if order_ids.contains(&order_info.order_id) {
-            cancel = true;
-        }
+    let token = MaybeToken::SOL();
+    let wallet_address = authority_address;
+    if db.get_account(stake_address, token).is_none() {
+        return Err(format!("Stake account, {stake_address}, is not tracked").into());
+    }
+    if db.get_account(wallet_address, token).is_none() {
+        return Err(format!("Wallet, {wallet_address}, is not tracked").into());
+    }
 
-        if let Some(ref max_create_time) = max_create_time {
-            if order_info.creation_time < *max_create_time {
-                cancel = true;
+    let mut funding = match db.get_stake_funding(stake_address) {
+        Some(funding) => {
+            if funding.exchange != exchange || funding.exchange_account != exchange_account {
+                return Err(format!(
+                    "{stake_address} is already funding {:?} {}",
+                    funding.exchange, funding.exchange_account
+                )
+                .into());
             }
+            println!("Resuming {exchange:?} funding from {stake_address}");
+            funding
         }
+        None => StakeFunding {
+            stake_address,
+            wallet_address,
+            exchange,
+            exchange_account: exchange_account.to_string(),
+            sol_reserve,
+            lot_selection_method,
+            started_at: Utc::now(),
+            state: StakeFundingState::Deactivating,
+            steps: vec![],
+        },
+    };
 
-        if cancel {
-            println!("Cancelling order {}", order_info.order_id);
-            cancelled_count += 1;
-            exchange_client
-                .cancel_order(&order_info.pair, &order_info.order_id)
-                .await
-                .unwrap_or_else(|err| eprintln!("{err:?}"));
-        }
-    }
+    loop {
+        match funding.state.clone() {
+            StakeFundingState::Deactivating => {
+                let activation = rpc_client.get_stake_activation(stake_address, None)?;
+                if matches!(
+                    activation.state,
+                    StakeActivationState::Active | StakeActivationState::Activating
+                ) {
+                    let (transaction, last_valid_block_height) = sign_stake_funding_transaction(
+                        rpc_client,
+                        &[solana_sdk::stake::instruction::deactivate_stake(
+                            &stake_address,
+                            &authority_address,
+                        )],
+                        authority_address,
+                        signers,
+                    )?;
+                    let signature = transaction.signatures[0];
+                    println!("Deactivating {stake_address}: {signature}");
+                    if !send_transaction_until_expired(
+                        rpc_client,
+                        &transaction,
+                        last_valid_block_height,
+                    ) {
+                        return Err("Deactivation failed".into());
+                    }
+                    funding.steps.push(StakeFundingStep {
+                        kind: StakeFundingStepKind::Deactivate,
+                        signature,
+                        amount: None,
+                        time: Utc::now(),
+                    });
+                    db.update_stake_funding(funding.clone())?;
+                    continue;
+                }
 
-    println!("{cancelled_count} orders cancelled");
-    Ok(())
-}
+                if activation.state == StakeActivationState::Inactive {
+                    println!("{stake_address} is inactive");
+                    funding.state = StakeFundingState::Withdrawing;
+                    db.update_stake_funding(funding.clone())?;
+                    continue;
+                }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_exchange_buy(
-    db: &mut Db,
-    exchange: Exchange,
-    exchange_client: &dyn ExchangeClient,
-    token: MaybeToken,
-    pair: String,
-    amount: Option<f64>,
-    price: LimitOrderPrice,
-    if_balance_exceeds: Option<f64>,
-    notifier: &Notifier,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let bid_ask = exchange_client.bid_ask(&pair).await?;
-    println!(
-        "{} | Ask: ${}, Bid: ${}",
-        pair, bid_ask.ask_price, bid_ask.bid_price
-    );
+                db.update_stake_funding(funding.clone())?;
+                let epoch_info = rpc_client.get_epoch_info()?;
+                println!(
+                    "{stake_address} is deactivating, ◎{} still active. Epoch {} ends in about \
+                     {} slots",
+                    lamports_to_sol(activation.active),
+                    epoch_info.epoch,
+                    epoch_info.slots_in_epoch - epoch_info.slot_index
+                );
+                match wait {
+                    Some(cancellation) => cancellation.sleep(Duration::from_secs(5 * 60)).await?,
+                    None => {
+                        println!("Run again after the epoch boundary to continue");
+                        return Ok(());
+                    }
+                }
+            }
+            StakeFundingState::Withdrawing => {
+                if let Some(step) = funding.step(StakeFundingStepKind::Withdraw).cloned() {
+                    process_account_sync_pending_transfers(db, rpc_client).await?;
+                    if db
+                        .pending_transfers()
+                        .iter()
+                        .any(|pending_transfer| pending_transfer.signature == step.signature)
+                    {
+                        println!(
+                            "Withdrawal from {stake_address} pending: {}",
+                            step.signature
+                        );
+                        match wait {
+                            Some(cancellation) => {
+                                cancellation.sleep(Duration::from_secs(30)).await?
+                            }
+                            None => return Ok(()),
+                        }
+                        continue;
+                    }
+                    if matches!(
+                        rpc_client.get_signature_status(&step.signature)?,
+                        Some(Ok(()))
+                    ) {
+                        if db
+                            .get_account(stake_address, token)
+                            .map_or(false, |account| account.last_update_balance == 0)
+                        {
+                            db.remove_account(stake_address, token)?;
+                        }
+                        funding.state = StakeFundingState::Depositing;
+                    } else {
+                        println!("Withdrawal {} did not land, retrying", step.signature);
+                        funding
+                            .steps
+                            .retain(|step| step.kind != StakeFundingStepKind::Withdraw);
+                    }
+                    db.update_stake_funding(funding.clone())?;
+                    continue;
+                }
 
-    let deposit_address = exchange_client.deposit_address(token).await?;
-    let deposit_account = db.get_account(deposit_address, token).ok_or_else(|| {
-        format!(
-            "Exchange deposit account does not exist, run `sync` first: {deposit_address} ({token})",
-        )
-    })?;
+                process_account_sync(db, rpc_client, Some(stake_address), None, false, notifier)
+                    .await?;
+                let amount = rpc_client.get_balance(&stake_address)?;
+                let tracked_balance = db
+                    .get_account(stake_address, token)
+                    .expect("stake account")
+                    .last_update_balance;
+                if amount != tracked_balance {
+                    return Err(format!(
+                        "{stake_address} balance of ◎{} does not match the tracked balance of ◎{}, \
+                         run `sys sync` first",
+                        lamports_to_sol(amount),
+                        lamports_to_sol(tracked_balance)
+                    )
+                    .into());
+                }
 
-    let balances = exchange_client.balances().await?;
-    let usd_balance = balances.get("USD").cloned().unwrap_or_default().available;
+                let (transaction, last_valid_block_height) = sign_stake_funding_transaction(
+                    rpc_client,
+                    &[solana_sdk::stake::instruction::withdraw(
+                        &stake_address,
+                        &authority_address,
+                        &wallet_address,
+                        amount,
+                        None,
+                    )],
+                    authority_address,
+                    signers,
+                )?;
+                let signature = transaction.signatures[0];
+                println!(
+                    "Withdrawing ◎{} from {stake_address} to {wallet_address}: {signature}",
+                    lamports_to_sol(amount)
+                );
+                db.record_transfer(
+                    signature,
+                    last_valid_block_height,
+                    Some(amount),
+                    stake_address,
+                    token,
+                    wallet_address,
+                    token,
+                    funding.lot_selection_method.clone(),
+                    None,
+                )?;
+                funding.steps.push(StakeFundingStep {
+                    kind: StakeFundingStepKind::Withdraw,
+                    signature,
+                    amount: Some(amount),
+                    time: Utc::now(),
+                });
+                db.update_stake_funding(funding.clone())?;
 
-    if let Some(if_balance_exceeds) = if_balance_exceeds {
-        if usd_balance < if_balance_exceeds {
-            println!(
-                "Order declined because {exchange:?} available balance is less than ${if_balance_exceeds}"
-            );
-            return Ok(());
-        }
-    }
+                if send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height)
+                {
+                    let when = get_signature_date(rpc_client, signature).await?;
+                    db.confirm_transfer(signature, when)?;
+                }
+            }
+            StakeFundingState::Depositing => {
+                if let Some(step) = funding.step(StakeFundingStepKind::Deposit).cloned() {
+                    process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier)
+                        .await?;
+                    if db
+                        .pending_deposits(Some(exchange))
+                        .iter()
+                        .any(|pending_deposit| pending_deposit.transfer.signature == step.signature)
+                    {
+                        println!("Waiting for {exchange:?} to credit {}", step.signature);
+                        match wait {
+                            Some(cancellation) => {
+                                cancellation.sleep(Duration::from_secs(30)).await?
+                            }
+                            None => {
+                                println!("Run again to check for the deposit");
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+                    if matches!(
+                        rpc_client.get_signature_status(&step.signature)?,
+                        Some(Ok(()))
+                    ) {
+                        let exchange_tx_id = db
+                            .reconciled_deposits(exchange)
+                            .into_iter()
+                            .find(|deposit| deposit.tx_id == step.signature.to_string())
+                            .and_then(|deposit| deposit.exchange_tx_id)
+                            .unwrap_or_else(|| step.signature.to_string());
+                        funding.state = StakeFundingState::Completed { exchange_tx_id };
+                        db.update_stake_funding(funding.clone())?;
+                        print_stake_funding_summary(&funding);
+                        return Ok(());
+                    }
+                    println!("Deposit {} did not land, retrying", step.signature);
+                    funding
+                        .steps
+                        .retain(|step| step.kind != StakeFundingStepKind::Deposit);
+                    db.update_stake_funding(funding.clone())?;
+                    continue;
+                }
 
-    let price = match price {
-        LimitOrderPrice::At(price) => price,
-        LimitOrderPrice::AmountOverAsk(_) => panic!("Bug: AmountOverAsk invalid for a buy order"),
-        LimitOrderPrice::AmountUnderBid(extra) => bid_ask.bid_price - extra,
-    };
-    let price = (price * 10_000.).round() / 10_000.; // Round to four decimal places
+                let deposit_address = verified_deposit_address(
+                    db,
+                    exchange,
+                    exchange_account,
+                    exchange_client,
+                    token,
+                )
+                .await?;
+                add_exchange_deposit_address_to_db(
+                    db,
+                    exchange,
+                    exchange_account,
+                    token,
+                    deposit_address,
+                    rpc_client,
+                )?;
 
-    if price > bid_ask.bid_price {
-        return Err(format!("Order price, {price}, is greater than bid price").into());
+                // A deposit sent by an interrupted run is adopted rather than sent again
+                let pending_deposit =
+                    db.pending_deposits(Some(exchange))
+                        .into_iter()
+                        .find(|pending_deposit| {
+                            pending_deposit.transfer.from_address == wallet_address
+                                && pending_deposit.transfer.to_address == deposit_address
+                        });
+                let (signature, amount) = match pending_deposit {
+                    Some(pending_deposit) => {
+                        (pending_deposit.transfer.signature, pending_deposit.amount)
+                    }
+                    None => {
+                        process_account_sync(
+                            db,
+                            rpc_client,
+                            Some(wallet_address),
+                            None,
+                            false,
+                            notifier,
+                        )
+                        .await?;
+                        let withdrawn = funding
+                            .step(StakeFundingStepKind::Withdraw)
+                            .and_then(|step| step.amount)
+                            .unwrap_or_default();
+                        let wallet_balance = db
+                            .get_account(wallet_address, token)
+                            .expect("wallet")
+                            .last_update_balance;
+                        let fee = get_deprecated_fee_calculator(rpc_client)?.lamports_per_signature;
+                        let amount =
+                            withdrawn.min(wallet_balance.saturating_sub(funding.sol_reserve + fee));
+                        println!(
+                            "Keeping ◎{} in {wallet_address} as the SOL reserve",
+                            lamports_to_sol(funding.sol_reserve)
+                        );
+
+                        let signature = process_exchange_deposit(
+                            db,
+                            rpc_client,
+                            exchange,
+                            exchange_client,
+                            token,
+                            deposit_address,
+                            Amount::Exact(amount),
+                            None,
+                            wallet_address,
+                            None,
+                            None,
+                            authority_address,
+                            signers.to_vec(),
+                            funding.lot_selection_method.clone(),
+                            None,
+                        )
+                        .await?
+                        .expect("deposit signature");
+                        (signature, amount)
+                    }
+                };
+                funding.steps.push(StakeFundingStep {
+                    kind: StakeFundingStepKind::Deposit,
+                    signature,
+                    amount: Some(amount),
+                    time: Utc::now(),
+                });
+                db.update_stake_funding(funding.clone())?;
+            }
+            StakeFundingState::Completed { .. } => {
+                print_stake_funding_summary(&funding);
+                return Ok(());
+            }
+        }
     }
+}
 
-    let amount = match amount {
-        None => (usd_balance / price).floor(),
-        Some(amount) => amount,
-    };
+// A send is matched to an exchange credit by token and amount when the exchange reports a different
+// transaction id, provided the two are at most this far apart
+const DEPOSIT_MATCH_WINDOW_HOURS: i64 = 24;
 
-    println!("Placing buy order for ◎{amount} at ${price}");
+// Exchanges sometimes report the transaction signature with extra decoration around it
+fn deposit_tx_id_matches(signature: &str, tx_id: &str) -> bool {
+    !tx_id.is_empty() && tx_id.contains(signature)
+}
 
-    let order_id = exchange_client
-        .place_order(&pair, OrderSide::Buy, price, amount)
-        .await?;
-    let msg = format!(
-        "Order created: {}: {:?} ◎{} at ${}, id {}",
-        pair,
-        OrderSide::Buy,
-        amount,
-        price,
-        order_id,
+fn describe_reconciled_deposit(exchange: Exchange, deposit: &ReconciledDeposit) -> String {
+    let token = deposit.token;
+    let amount = format!(
+        "{} {}{}",
+        token,
+        token.symbol(),
+        format_token_amount(token, token.ui_amount(deposit.amount), true)
     );
-    db.open_order(
-        OrderSide::Buy,
-        deposit_account,
-        exchange,
-        pair,
-        price,
-        order_id,
-        vec![],
-        Some(amount),
-    )?;
-    println!("{msg}");
-    notifier.send(&format!("{exchange:?}: {msg}")).await;
-    Ok(())
+    let sent = deposit
+        .time
+        .map(|time| format!(" {}", HumanTime::from(time)))
+        .unwrap_or_default();
+
+    match (deposit.side, deposit.status) {
+        (_, DepositReconciliationStatus::Matched) => {
+            format!("{amount} deposit credited ({})", deposit.tx_id)
+        }
+        (_, DepositReconciliationStatus::MatchedByAmount) => format!(
+            "{amount} deposit credited as {}, matched by amount and time ({})",
+            deposit.exchange_tx_id.as_deref().unwrap_or_default(),
+            deposit.tx_id
+        ),
+        (_, DepositReconciliationStatus::Pending) => {
+            format!(
+                "{amount} deposit sent{sent}, not credited yet ({})",
+                deposit.tx_id
+            )
+        }
+        (DepositSide::OnChain, DepositReconciliationStatus::Unmatched) => format!(
+            "{amount} deposit sent{sent} has not been credited by {exchange:?}. \
+             Check that it was sent to the right address! ({})",
+            deposit.tx_id
+        ),
+        (DepositSide::Exchange, DepositReconciliationStatus::Unmatched) => format!(
+            "{amount} credited by {exchange:?} with no known send (tx id: {})",
+            deposit.tx_id
+        ),
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn process_exchange_sell(
+async fn process_exchange_reconcile_deposits(
     db: &mut Db,
+    rpc_client: &RpcClient,
     exchange: Exchange,
     exchange_client: &dyn ExchangeClient,
     token: MaybeToken,
-    pair: String,
-    amount: f64,
-    price: LimitOrderPrice,
-    if_balance_exceeds: Option<u64>,
-    if_price_over: Option<f64>,
-    if_price_over_basis: bool,
-    price_floor: Option<f64>,
-    lot_selection_method: LotSelectionMethod,
-    lot_numbers: Option<HashSet<usize>>,
+    signatures: Vec<Signature>,
+    max_age: chrono::Duration,
+    all: bool,
+    json: bool,
     notifier: &Notifier,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let bid_ask = exchange_client.bid_ask(&pair).await?;
-    println!(
-        "{} | Ask: ${}, Bid: ${}",
-        pair, bid_ask.ask_price, bid_ask.bid_price
-    );
+    let exchange_deposits = exchange_client
+        .recent_deposits()
+        .await?
+        .ok_or_else(|| format!("Recent deposits not available for {exchange:?}"))?;
 
-    let deposit_address = exchange_client.deposit_address(token).await?;
-    let mut deposit_account = db.get_account(deposit_address, token).ok_or_else(|| {
-        format!(
-            "Exchange deposit account does not exist, run `sync` first: {deposit_address} ({token})",
-        )
-    })?;
+    let previous_deposits = db.reconciled_deposits(exchange);
 
-    if let Some(if_balance_exceeds) = if_balance_exceeds {
-        if deposit_account.last_update_balance < if_balance_exceeds {
-            println!(
-                "Order declined because {:?} available balance is less than {}",
+    // Exchange credits with no known send are worked out from scratch each time
+    let mut deposits = previous_deposits
+        .iter()
+        .filter(|deposit| deposit.side == DepositSide::OnChain)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    // Deposits recorded before reconciliation was tracked
+    for pending_deposit in db.pending_deposits(Some(exchange)) {
+        let tx_id = pending_deposit.transfer.signature.to_string();
+        if !deposits.iter().any(|deposit| deposit.tx_id == tx_id) {
+            deposits.push(ReconciledDeposit {
                 exchange,
-                token.ui_amount(if_balance_exceeds)
-            );
-            return Ok(());
+                side: DepositSide::OnChain,
+                tx_id,
+                token: pending_deposit.transfer.to_token,
+                amount: pending_deposit.amount,
+                time: None,
+                status: DepositReconciliationStatus::Pending,
+                exchange_tx_id: None,
+            });
         }
     }
 
-    let price = match price {
-        LimitOrderPrice::At(price) => price,
-        LimitOrderPrice::AmountOverAsk(extra) => bid_ask.ask_price + extra,
-        LimitOrderPrice::AmountUnderBid(_) => {
-            panic!("Bug: AmountUnderBid invalid for a sell order")
-        }
-    };
-    let mut price = (price * 100.).round() / 100.; // Round to two decimal places
-
-    if let Some(if_price_over) = if_price_over {
-        if price <= if_price_over {
-            let msg = format!(
-                "Order declined because price, ${price}, is not greater than ${if_price_over}",
-            );
-            println!("{msg}");
-            notifier.send(&format!("{exchange:?}: {msg}")).await;
-            return Ok(());
+    if !signatures.is_empty() {
+        let deposit_address = exchange_client.deposit_address(token).await?;
+        let (address, address_is_token) = match token.token() {
+            None => (deposit_address, false),
+            Some(token) => (token.ata(&deposit_address), true),
+        };
+        for signature in signatures {
+            let tx_id = signature.to_string();
+            if deposits.iter().any(|deposit| deposit.tx_id == tx_id) {
+                continue;
+            }
+            let balance_change =
+                get_transaction_balance_change(rpc_client, &signature, &address, address_is_token)?;
+            deposits.push(ReconciledDeposit {
+                exchange,
+                side: DepositSide::OnChain,
+                tx_id,
+                token,
+                amount: balance_change
+                    .post_amount
+                    .saturating_sub(balance_change.pre_amount),
+                time: balance_change
+                    .when
+                    .map(|when| DateTime::<Utc>::from_utc(when, Utc)),
+                status: DepositReconciliationStatus::Pending,
+                exchange_tx_id: None,
+            });
         }
     }
 
-    if let Some(price_floor) = price_floor {
-        if price < price_floor {
-            let msg =
-                format!("Proposed price, ${price}, is beneath price floor. Adjusting upwards");
-            price = price_floor;
-            println!("{msg}");
-            notifier.send(&format!("{exchange:?}: {msg}")).await;
+    // Exchange credits already accounted for by an earlier match
+    let mut claimed_tx_ids = HashSet::new();
+    for deposit in &deposits {
+        match deposit.status {
+            DepositReconciliationStatus::Matched => {
+                claimed_tx_ids.extend(
+                    exchange_deposits
+                        .iter()
+                        .filter(|exchange_deposit| {
+                            deposit_tx_id_matches(&deposit.tx_id, &exchange_deposit.tx_id)
+                        })
+                        .map(|exchange_deposit| exchange_deposit.tx_id.clone()),
+                );
+            }
+            DepositReconciliationStatus::MatchedByAmount => {
+                claimed_tx_ids.extend(deposit.exchange_tx_id.clone());
+            }
+            DepositReconciliationStatus::Pending | DepositReconciliationStatus::Unmatched => {}
         }
     }
 
-    let order_lots = deposit_account.extract_lots(
-        db,
-        token.amount(amount),
-        lot_selection_method,
-        lot_numbers,
-    )?;
-    if if_price_over_basis {
-        if let Some(basis) = order_lots.iter().find_map(|lot| {
-            let basis = lot.acquisition.price();
-            if Decimal::from_f64(price).unwrap() < basis {
-                Some(basis)
-            } else {
-                None
-            }
-        }) {
-            let msg =
-                format!("Order declined because price, ${price}, is less than basis ${basis}",);
-            println!("{msg}");
-            notifier.send(&format!("{exchange:?}: {msg}")).await;
-            return Ok(());
+    let now = Utc::now();
+    for deposit in deposits.iter_mut().filter(|deposit| {
+        matches!(
+            deposit.status,
+            DepositReconciliationStatus::Pending | DepositReconciliationStatus::Unmatched
+        )
+    }) {
+        let unclaimed_deposits = exchange_deposits
+            .iter()
+            .filter(|exchange_deposit| !claimed_tx_ids.contains(&exchange_deposit.tx_id))
+            .collect::<Vec<_>>();
+        let tx_id_match = unclaimed_deposits.iter().find(|exchange_deposit| {
+            deposit_tx_id_matches(&deposit.tx_id, &exchange_deposit.tx_id)
+        });
+        let amount_match = unclaimed_deposits.iter().find(|exchange_deposit| {
+            // Same tolerance `process_sync_exchange` allows for exchanges skimming a few tokens
+            let amount_matches = (deposit.token.amount(exchange_deposit.amount) as i64
+                - deposit.amount as i64)
+                .abs()
+                < 10;
+            let time_matches = match (deposit.time, exchange_deposit.time) {
+                (Some(sent), Some(credited)) => {
+                    (credited - sent).num_hours().abs() <= DEPOSIT_MATCH_WINDOW_HOURS
+                }
+                _ => false,
+            };
+            exchange_deposit.token == Some(deposit.token) && amount_matches && time_matches
+        });
+
+        if let Some(exchange_deposit) = tx_id_match {
+            deposit.status = DepositReconciliationStatus::Matched;
+            claimed_tx_ids.insert(exchange_deposit.tx_id.clone());
+        } else if let Some(exchange_deposit) = amount_match {
+            deposit.status = DepositReconciliationStatus::MatchedByAmount;
+            deposit.exchange_tx_id = Some(exchange_deposit.tx_id.clone());
+            claimed_tx_ids.insert(exchange_deposit.tx_id.clone());
+        } else {
+            deposit.status = match deposit.time {
+                Some(sent) if now - sent > max_age => DepositReconciliationStatus::Unmatched,
+                _ => DepositReconciliationStatus::Pending,
+            };
         }
     }
 
-    if price < bid_ask.ask_price {
-        return Err("Order price is less than ask price".into());
-    }
-
-    println!("Placing sell order for ◎{amount} at ${price}");
-    println!("Lots");
-    for lot in &order_lots {
-        println_lot(
-            deposit_account.token,
-            lot,
-            Decimal::from_f64(price),
-            None,
-            &mut 0.,
-            &mut 0.,
-            &mut false,
-            &mut 0.,
-            None,
-            true,
-        )
-        .await;
+    for exchange_deposit in &exchange_deposits {
+        if let Some(token) = exchange_deposit.token {
+            if !claimed_tx_ids.contains(&exchange_deposit.tx_id) {
+                deposits.push(ReconciledDeposit {
+                    exchange,
+                    side: DepositSide::Exchange,
+                    tx_id: exchange_deposit.tx_id.clone(),
+                    token,
+                    amount: token.amount(exchange_deposit.amount),
+                    time: exchange_deposit.time,
+                    status: DepositReconciliationStatus::Unmatched,
+                    exchange_tx_id: None,
+                });
+            }
+        }
     }
 
-    let order_id = exchange_client
-        .place_order(&pair, OrderSide::Sell, price, amount)
-        .await?;
-    let msg = format!(
-        "Order created: {}: {:?} ◎{} at ${}, id {}",
-        pair,
-        OrderSide::Sell,
-        amount,
-        price,
-        order_id,
-    );
-    db.open_order(
-        OrderSide::Sell,
-        deposit_account,
-        exchange,
-        pair,
-        price,
-        order_id,
-        order_lots,
-        None,
-    )?;
-    println!("{msg}");
-    notifier.send(&format!("{exchange:?}: {msg}")).await;
-    Ok(())
-}
-
-fn println_jup_quote(from_token: Token, to_token: Token, quote: &jup_ag::Quote) {
-    let route = quote
-        .route_plan
+    // Only report what changed since the last run, unless everything was asked for
+    let reported_deposits = deposits
         .iter()
-        .map(|route_plan| route_plan.swap_info.label.clone().unwrap_or_default())
-        .join(", ");
-    println!(
-        "Swap {}{} for {}{} (min: {}{}) via {}",
-        from_token.symbol(),
-        from_token.ui_amount(quote.in_amount),
-        to_token.symbol(),
-        to_token.ui_amount(quote.out_amount),
-        to_token.symbol(),
-        to_token.ui_amount(quote.other_amount_threshold),
-        route,
-    );
-}
+        .filter(|deposit| {
+            all || !previous_deposits.iter().any(|previous_deposit| {
+                previous_deposit.side == deposit.side
+                    && previous_deposit.tx_id == deposit.tx_id
+                    && previous_deposit.status == deposit.status
+            })
+        })
+        .collect::<Vec<_>>();
 
-async fn process_jup_quote(
-    from_token: Token,
-    to_token: Token,
-    ui_amount: f64,
-    slippage_bps: u64,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let quote = jup_ag::quote(
-        from_token.mint(),
-        to_token.mint(),
-        from_token.amount(ui_amount),
-        jup_ag::QuoteConfig {
-            slippage_bps: Some(slippage_bps),
-            ..jup_ag::QuoteConfig::default()
-        },
-    )
-    .await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reported_deposits)?);
+    } else {
+        for deposit in &reported_deposits {
+            let msg = describe_reconciled_deposit(exchange, deposit);
+            if deposit.side == DepositSide::OnChain
+                && deposit.status == DepositReconciliationStatus::Unmatched
+            {
+                println!("{}", style(format!("WARNING: {msg}")).red().bold());
+                notifier.send(&format!("{exchange:?}: {msg}")).await;
+            } else {
+                println!("{msg}");
+            }
+        }
+        if reported_deposits.is_empty() {
+            println!("No changes since the last reconciliation");
+        }
 
-    println_jup_quote(from_token, to_token, &quote);
+        let count = |side, statuses: &[DepositReconciliationStatus]| {
+            deposits
+                .iter()
+                .filter(|deposit| deposit.side == side && statuses.contains(&deposit.status))
+                .count()
+        };
+        println!(
+            "Sends: {} matched, {} pending, {} unmatched. Credits with no known send: {}",
+            count(
+                DepositSide::OnChain,
+                &[
+                    DepositReconciliationStatus::Matched,
+                    DepositReconciliationStatus::MatchedByAmount
+                ]
+            ),
+            count(
+                DepositSide::OnChain,
+                &[DepositReconciliationStatus::Pending]
+            ),
+            count(
+                DepositSide::OnChain,
+                &[DepositReconciliationStatus::Unmatched]
+            ),
+            count(
+                DepositSide::Exchange,
+                &[DepositReconciliationStatus::Unmatched]
+            ),
+        );
+    }
+
+    db.set_reconciled_deposits(exchange, deposits)?;
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_jup_swap<T: Signers>(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    address: Pubkey,
-    from_token: Token,
-    to_token: Token,
-    ui_amount: Option<f64>,
-    slippage_bps: u64,
-    lot_selection_method: LotSelectionMethod,
-    signers: T,
-    existing_signature: Option<Signature>,
-    if_from_balance_exceeds: Option<u64>,
-    for_no_less_than: Option<f64>,
-    max_coingecko_value_percentage_loss: f64,
-    compute_unit_price_micro_lamports: Option<u64>,
-    notifier: &Notifier,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let from_account = db
-        .get_account(address, from_token.into())
-        .ok_or_else(|| format!("{from_token} account does not exist for {address}"))?;
+// Redeemed from lending beyond a withdrawal's shortfall, to cover rounding and fees
+const LENDING_REDEMPTION_BUFFER: f64 = 0.01;
 
-    let from_token_price = from_token.get_current_price(rpc_client).await?;
-    let to_token_price = to_token.get_current_price(rpc_client).await?;
+/// Redeems enough lending to cover a `shortfall` in the available `token` balance, then waits
+/// until `amount` is available to withdraw or `cancellation` stops the wait
+async fn redeem_lending_for_withdrawal(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    amount: u64,
+    shortfall: f64,
+    cancellation: &Cancellation,
+) -> Result<LendingRedemption, Box<dyn std::error::Error>> {
+    if !exchange.capabilities().supports_earn {
+        return Err(format!("Lending can't be redeemed on {exchange:?}").into());
+    }
+    let coin = token_exchange_symbol(exchange, token)
+        .ok_or_else(|| format!("{token} can't be redeemed from lending on {exchange:?}"))?;
+    let lending_info = exchange_client
+        .get_lending_info(coin)
+        .await?
+        .ok_or_else(|| format!("Lending not available for {coin}"))?;
+    if lending_info.offered < shortfall {
+        return Err(format!(
+            "Withdrawal is {shortfall} {coin} short, but only {} {coin} is offered for lending",
+            lending_info.offered
+        )
+        .into());
+    }
 
-    if let Some(existing_signature) = existing_signature {
-        db.record_swap(
-            existing_signature,
-            0, /*last_valid_block_height*/
-            address,
-            from_token.into(),
-            from_token_price,
-            to_token.into(),
-            to_token_price,
-            lot_selection_method,
-        )?;
-    } else {
-        let amount = match ui_amount {
-            Some(ui_amount) => from_token.amount(ui_amount),
-            None => from_account.last_update_balance,
-        };
+    let redemption_amount = (shortfall * (1. + LENDING_REDEMPTION_BUFFER))
+        .ceil()
+        .min(lending_info.offered);
+    let result = exchange_client
+        .redeem_lending(coin, redemption_amount)
+        .await;
+    let detail = match &result {
+        Ok(redemption) => format!(
+            "Redeemed {} {coin} from lending{}",
+            redemption.redeemed,
+            redemption
+                .forfeited_interest
+                .map(|forfeited_interest| format!(
+                    ", forfeiting {forfeited_interest} {coin} of interest"
+                ))
+                .unwrap_or_default()
+        ),
+        Err(_) => format!("Redeem {redemption_amount} {coin} from lending"),
+    };
+    let redemption = outcome::record(
+        OperationKind::LendingRedeemed,
+        format!("{exchange:?}: {detail}"),
+        result,
+        |_| coin.to_string(),
+    )?;
+    println!("{detail}");
+    db.record_lending_offer(LendingOfferRecord {
+        exchange,
+        coin: coin.to_string(),
+        amount: (lending_info.offered - redemption.redeemed).max(0.),
+        estimate_rate: lending_info.estimate_rate,
+        time: Utc::now(),
+    })?;
 
-        if from_account.last_update_balance < amount {
+    loop {
+        let available = token_balances(exchange, exchange_client)
+            .await?
+            .get(token)
+            .available;
+        if amount <= native_amount_floor(token, available) {
+            return Ok(redemption);
+        }
+        println!("Waiting for {exchange:?} to release the redeemed {coin}...");
+        if let Err(err) = cancellation.sleep(Duration::from_secs(30)).await {
             return Err(format!(
-                "Insufficient {} balance in {}. Tracked balance is {}",
-                from_token,
-                address,
-                from_token.ui_amount(from_account.last_update_balance)
+                "{err} waiting for {exchange:?} to release {} {coin} redeemed from lending, \
+                 {available} {coin} is available",
+                redemption.redeemed
             )
             .into());
         }
+    }
+}
 
-        let swap_prefix = format!("Swap {}->{}", from_token, to_token);
-
-        if let Some(if_from_balance_exceeds) = if_from_balance_exceeds {
-            if from_account.last_update_balance < if_from_balance_exceeds {
-                println!(
-                    "{swap_prefix} declined because {} ({}) balance is less than {}{}",
-                    address,
-                    from_token.name(),
-                    from_token.symbol(),
-                    from_token.ui_amount(if_from_balance_exceeds)
-                );
-                return Ok(());
-            }
-        }
-
-        let _ = to_token.balance(rpc_client, &address).map_err(|err| {
-            format!(
-                "{} account does not exist for {}. \
-                To create it, run `spl-token create-account {} --owner {}: {}",
-                to_token,
-                address,
-                to_token.mint(),
-                address,
-                err
-            )
-        })?;
-
-        println!("Fetching best {from_token}->{to_token} quote...");
-        let quote = jup_ag::quote(
-            from_token.mint(),
-            to_token.mint(),
-            amount,
-            jup_ag::QuoteConfig {
-                slippage_bps: Some(slippage_bps),
-                ..jup_ag::QuoteConfig::default()
-            },
-        )
-        .await?;
-
-        println_jup_quote(from_token, to_token, &quote);
+/// Built-in pre-trade check of orders and withdrawals of `token`, worth `usd_value`, against the
+/// spending limits.  `override_limits` bypasses per-operation limits, but never a rolling-window
+/// limit.  The limits bypassed are recorded in the audit log by `audit_spending_limit_overrides()`
+/// once the operation has passed every check.  The limits and their usage are read when the check
+/// is created, so it can be handed to code that updates the database while it runs
+struct SpendingLimitCheck {
+    limits: Vec<(SpendingLimit, Option<SpendingLimitUsage>)>,
+    // `None` for a fiat withdrawal, which only USD limits apply to
+    token: Option<MaybeToken>,
+    usd_value: Option<f64>,
+    override_limits: bool,
+    overridden: std::sync::Mutex<Vec<(SpendingLimit, String)>>,
+}
 
-        let from_value =
-            from_token_price * Decimal::from_f64(from_token.ui_amount(quote.in_amount)).unwrap();
-        let min_to_value = to_token_price
-            * Decimal::from_f64(to_token.ui_amount(quote.other_amount_threshold)).unwrap();
+impl SpendingLimitCheck {
+    fn new(db: &Db, token: MaybeToken, usd_value: Option<f64>, override_limits: bool) -> Self {
+        Self::with_token(db, Some(token), usd_value, override_limits)
+    }
 
-        let swap_value_percentage_loss = Decimal::from_usize(100).unwrap()
-            - min_to_value / from_value * Decimal::from_usize(100).unwrap();
+    fn fiat(db: &Db, usd_value: Option<f64>, override_limits: bool) -> Self {
+        Self::with_token(db, None, usd_value, override_limits)
+    }
 
-        println!("Coingecko value loss: {swap_value_percentage_loss:.2}%");
-        if swap_value_percentage_loss
-            > Decimal::from_f64(max_coingecko_value_percentage_loss).unwrap()
-        {
-            return Err(format!(
-                "{swap_prefix} exceeds the max value loss ({max_coingecko_value_percentage_loss:2}%) relative to CoinGecko token price"
-            )
-            .into());
+    fn with_token(
+        db: &Db,
+        token: Option<MaybeToken>,
+        usd_value: Option<f64>,
+        override_limits: bool,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            limits: db
+                .spending_limits()
+                .into_iter()
+                .map(|limit| {
+                    let usage = db.spending_limit_used(&limit, now);
+                    (limit, usage)
+                })
+                .collect(),
+            token,
+            usd_value,
+            override_limits,
+            overridden: Default::default(),
         }
+    }
 
-        if let Some(for_no_less_than) = for_no_less_than {
-            let to_token_amount = to_token.ui_amount(quote.other_amount_threshold);
+    async fn check_operation(
+        &self,
+        operation: LimitedOperation,
+        ui_amount: f64,
+        description: &str,
+    ) -> Result<(), Rejection> {
+        let rejection = |reason: String| Rejection::new("spending limits", reason);
+        let mut overridden = vec![];
+        for (limit, usage) in &self.limits {
+            if !limit.operation.covers(operation)
+                || matches!(limit.token, Some(limit_token) if Some(limit_token) != self.token)
+            {
+                continue;
+            }
+            let requested = match limit.token {
+                Some(_) => ui_amount,
+                None => self.usd_value.ok_or_else(|| {
+                    rejection(format!(
+                        "Unable to value {description} in USD to check the {limit}"
+                    ))
+                })?,
+            };
 
-            if to_token_amount < for_no_less_than {
-                let to_token_symbol = to_token.symbol();
-                let msg = format!("{swap_prefix} would not result in at least {to_token_symbol}{for_no_less_than} tokens, only would have received {to_token_symbol}{to_token_amount}");
-                println!("{msg}");
-                notifier.send(&msg).await;
-                return Ok(());
+            match usage {
+                None => {
+                    if requested > limit.max {
+                        if !self.override_limits {
+                            return Err(rejection(format!(
+                                "{limit} exceeded by {description}: {} requested \
+                                 (pass --override-limits to bypass it)",
+                                limit.format_value(requested)
+                            )));
+                        }
+                        overridden.push((limit.clone(), description.to_string()));
+                    }
+                }
+                Some(usage) => {
+                    let used = usage.used
+                        + value_spending_records(&usage.unvalued)
+                            .await
+                            .map_err(|err| {
+                                rejection(format!(
+                                    "Unable to value an earlier operation in USD to check the \
+                                     {limit}: {err}"
+                                ))
+                            })?;
+                    if used + requested > limit.max {
+                        return Err(rejection(format!(
+                            "{limit} exceeded by {description}: {} requested with {} already used",
+                            limit.format_value(requested),
+                            limit.format_value(used),
+                        )));
+                    }
+                }
             }
         }
+        // Replaced rather than extended, as the same operation may be checked more than once
+        *self.overridden.lock().unwrap() = overridden;
+        Ok(())
+    }
 
-        println!("Generating {swap_prefix} Transaction...");
-        let mut swap_request = jup_ag::SwapRequest::new(address, quote.clone());
-        swap_request.wrap_and_unwrap_sol = Some(false);
-        swap_request.compute_unit_price_micro_lamports = compute_unit_price_micro_lamports;
+    fn into_overridden(self) -> Vec<(SpendingLimit, String)> {
+        self.overridden.into_inner().unwrap()
+    }
+}
 
-        let mut transaction = jup_ag::swap(swap_request).await?.swap_transaction;
+/// USD value at current prices of operations that couldn't be valued when they were recorded
+async fn value_spending_records(records: &[SpendingRecord]) -> Result<f64, String> {
+    let mut usd_value = 0.;
+    for record in records {
+        let price = match &record.fiat_currency {
+            Some(currency) if currency == "USD" => 1.,
+            Some(currency) => fx_rate_override(currency)
+                .ok_or_else(|| format!("no USD exchange rate for {currency}"))?,
+            None if record.token.fiat_fungible() => 1.,
+            None => coin_gecko::get_current_price(&record.token)
+                .await
+                .map_err(|err| err.to_string())?
+                .to_f64()
+                .unwrap_or_default(),
+        };
+        usd_value += record.ui_amount * price;
+    }
+    Ok(usd_value)
+}
 
-        let (recent_blockhash, last_valid_block_height) =
-            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
-        transaction.message.set_recent_blockhash(recent_blockhash);
-
-        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-        if simulation_result.err.is_some() {
-            return Err(
-                format!("Swap transaction simulation failure: {simulation_result:?}").into(),
-            );
-        }
-
-        assert_eq!(transaction.signatures[0], Signature::default());
-        let signatures = signers.try_sign_message(&transaction.message.serialize())?;
-        assert_eq!(signatures.len(), 1);
-        let signature = signatures[0];
-        transaction.signatures[0] = signature;
-
-        if db.get_account(address, to_token.into()).is_none() {
-            let epoch = rpc_client.get_epoch_info()?.epoch;
-            db.add_account(TrackedAccount {
-                address,
-                token: to_token.into(),
-                description: from_account.description,
-                last_update_epoch: epoch,
-                last_update_balance: 0,
-                lots: vec![],
-                no_sync: None,
-            })?;
-        }
-        db.record_swap(
-            signature,
-            last_valid_block_height,
-            address,
-            from_token.into(),
-            from_token_price,
-            to_token.into(),
-            to_token_price,
-            lot_selection_method,
-        )?;
-
-        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-            db.cancel_swap(signature)?;
-            return Err("Swap failed".into());
-        }
+fn audit_spending_limit_overrides(
+    db: &mut Db,
+    overridden: Vec<(SpendingLimit, String)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (limit, description) in overridden {
+        eprintln!(
+            "{}",
+            style(format!("Overriding the {limit} for {description}")).yellow()
+        );
+        db.audit_spending_limit_override(&limit, description)?;
     }
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_tulip_deposit<T: Signers>(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    liquidity_token: MaybeToken,
-    collateral_token: Token,
-    liquidity_amount: Option<u64>,
-    address: Pubkey,
-    lot_selection_method: LotSelectionMethod,
-    signers: T,
-    existing_signature: Option<Signature>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let sol = MaybeToken::SOL();
-    let minimum_lamport_balance = sol.amount(0.01);
-    let from_account_lamports = sol.balance(rpc_client, &address)?;
-    if from_account_lamports < minimum_lamport_balance {
-        return Err(format!(
-            "From account (SOL), {}, has insufficient funds ({}{} required)",
-            address,
-            sol.symbol(),
-            sol.ui_amount(minimum_lamport_balance)
+#[async_trait]
+impl PreTradeCheck for SpendingLimitCheck {
+    async fn check(&self, intent: &OrderIntent) -> Result<(), Rejection> {
+        self.check_operation(
+            limited_order_operation(intent.side),
+            intent.amount,
+            &describe_order(
+                intent.exchange,
+                &intent.pair,
+                intent.side,
+                intent.amount,
+                intent.price,
+            ),
         )
-        .into());
+        .await
     }
+}
 
-    let liquidity_tracked_account = db
-        .get_account(address, liquidity_token)
-        .ok_or_else(|| format!("Unknown account {address} ({liquidity_token})"))?;
-    let liquidity_account_balance = liquidity_tracked_account.last_update_balance;
-
-    let max_liquidity_amount = if liquidity_token.is_sol() {
-        liquidity_account_balance.saturating_sub(minimum_lamport_balance * 2)
-    } else {
-        liquidity_account_balance
-    };
-    let liquidity_amount = liquidity_amount.unwrap_or(max_liquidity_amount);
+#[async_trait]
+impl PreWithdrawalCheck for SpendingLimitCheck {
+    async fn check(&self, request: &WithdrawalRequest) -> Result<(), Rejection> {
+        self.check_operation(
+            LimitedOperation::Withdrawal,
+            request.token.ui_amount(request.amount),
+            &describe_withdrawal(request),
+        )
+        .await
+    }
 
-    if liquidity_amount > max_liquidity_amount {
-        return Err(format!(
-            "Deposit amount is too large: {0}{1} (max: {0}{2})",
-            liquidity_token.symbol(),
-            liquidity_token.ui_amount(liquidity_amount),
-            liquidity_token.ui_amount(max_liquidity_amount)
+    async fn check_fiat(&self, request: &FiatWithdrawalRequest) -> Result<(), Rejection> {
+        self.check_operation(
+            LimitedOperation::Withdrawal,
+            request.amount,
+            &describe_fiat_withdrawal(request),
         )
-        .into());
+        .await
     }
-    if liquidity_amount == 0 {
-        return Err("Nothing to deposit".into());
+}
+
+const EVACUATION_STRATEGY: &str = "evacuate";
+
+/// Built-in pre-trade check that withdrawals only go to tracked accounts, and an evacuation only
+/// to the token's allow-listed evacuation address
+struct WithdrawalAllowList<'a> {
+    db: &'a Db,
+}
+
+#[async_trait]
+impl PreWithdrawalCheck for WithdrawalAllowList<'_> {
+    async fn check(&self, request: &WithdrawalRequest) -> Result<(), Rejection> {
+        let rejection = |reason: String| Rejection::new("allow-list", reason);
+        let WithdrawalRequest {
+            token, to_address, ..
+        } = request;
+        if self.db.get_account(*to_address, *token).is_none() {
+            return Err(rejection(format!(
+                "{to_address} is not a tracked {token} account"
+            )));
+        }
+        if request.strategy == EVACUATION_STRATEGY
+            && !self
+                .db
+                .evacuation_addresses()
+                .iter()
+                .any(|evacuation_address| {
+                    evacuation_address.token == *token && evacuation_address.address == *to_address
+                })
+        {
+            return Err(rejection(format!(
+                "{to_address} is not the allow-listed {token} evacuation address"
+            )));
+        }
+        Ok(())
     }
+}
 
-    let liquidity_token_price = liquidity_token.get_current_price(rpc_client).await?;
-    let collateral_token_price = collateral_token.get_current_price(rpc_client).await?;
-    let liquidity_token_ui_amount = liquidity_token.ui_amount(liquidity_amount);
+fn describe_withdrawal(request: &WithdrawalRequest) -> String {
+    format!(
+        "{:?}: {} {}{} to {}",
+        request.exchange,
+        request.token,
+        request.token.symbol(),
+        request.token.ui_amount(request.amount),
+        request.to_address
+    )
+}
 
-    println!("{address}: {liquidity_token} -> {collateral_token}");
-    println!(
-        "Estimated deposit amount: {}{} (${})",
-        liquidity_token.symbol(),
-        liquidity_token.ui_amount(liquidity_amount),
-        liquidity_token_price * Decimal::from_f64(liquidity_token_ui_amount).unwrap()
-    );
+fn describe_fiat_withdrawal(request: &FiatWithdrawalRequest) -> String {
+    format!(
+        "{:?}: {} to {}",
+        request.exchange,
+        format_quote_amount(&request.amount.to_string(), &request.currency),
+        request.method
+    )
+}
 
-    if db.get_account(address, collateral_token.into()).is_none() {
-        let epoch = rpc_client.get_epoch_info()?.epoch;
-        db.add_account(TrackedAccount {
-            address,
-            token: collateral_token.into(),
-            description: liquidity_tracked_account.description,
-            last_update_epoch: epoch,
-            last_update_balance: 0,
-            lots: vec![],
-            no_sync: Some(true),
-        })?;
+/// Runs `checks` on an order ahead of placing it, notifying a rejection
+async fn check_order_intent(
+    checks: &[&dyn PreTradeCheck],
+    intent: &OrderIntent,
+    notifier: &Notifier,
+) -> Result<(), Rejection> {
+    let result = pre_trade::check_order(checks, intent).await;
+    if let Err(rejection) = &result {
+        notifier.send(&format!("{intent}: {rejection}")).await;
     }
+    result
+}
 
-    if let Some(existing_signature) = existing_signature {
-        db.record_swap(
-            existing_signature,
-            0, /*last_valid_block_height*/
-            address,
-            liquidity_token,
-            liquidity_token_price,
-            collateral_token.into(),
-            collateral_token_price,
-            lot_selection_method,
-        )?;
-    } else {
-        let instructions = tulip::deposit(
-            rpc_client,
-            address,
-            liquidity_token,
-            collateral_token,
-            liquidity_amount,
-        )?;
+/// Runs `checks` on a withdrawal ahead of requesting it, notifying a rejection
+async fn check_withdrawal_request(
+    checks: &[&dyn PreWithdrawalCheck],
+    request: &WithdrawalRequest,
+    notifier: &Notifier,
+) -> Result<(), Rejection> {
+    let result = pre_trade::check_withdrawal(checks, request).await;
+    if let Err(rejection) = &result {
+        notifier.send(&format!("{request}: {rejection}")).await;
+    }
+    result
+}
 
-        let (recent_blockhash, last_valid_block_height) =
-            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+/// Runs `checks` on a fiat withdrawal, notifying a rejection
+async fn check_fiat_withdrawal_request(
+    checks: &[&dyn PreWithdrawalCheck],
+    request: &FiatWithdrawalRequest,
+    notifier: &Notifier,
+) -> Result<(), Rejection> {
+    let result = pre_trade::check_fiat_withdrawal(checks, request).await;
+    if let Err(rejection) = &result {
+        notifier.send(&format!("{request}: {rejection}")).await;
+    }
+    result
+}
 
-        let mut message = Message::new(&instructions, Some(&address));
-        message.recent_blockhash = recent_blockhash;
+/// Notifies the rejection of `operation` by the checks an exchange helper ran, when `result` is
+/// one
+async fn notify_rejection<T>(
+    operation: &impl std::fmt::Display,
+    result: &Result<T, Box<dyn std::error::Error>>,
+    notifier: &Notifier,
+) {
+    if let Some(rejection) = result
+        .as_ref()
+        .err()
+        .and_then(|err| err.downcast_ref::<Rejection>())
+    {
+        notifier.send(&format!("{operation}: {rejection}")).await;
+    }
+}
 
-        let mut transaction = Transaction::new_unsigned(message);
-        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-        if simulation_result.err.is_some() {
-            return Err(format!("Simulation failure: {simulation_result:?}").into());
+fn limited_order_operation(side: OrderSide) -> LimitedOperation {
+    match side {
+        OrderSide::Buy => LimitedOperation::Buy,
+        OrderSide::Sell => LimitedOperation::Sell,
+    }
+}
+
+/// Submits a managed order once it passes the pre-trade checks, recording it against the spending
+/// limits.  `strategy` names what placed the order
+#[allow(clippy::too_many_arguments)]
+async fn place_limited_order(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: &TradingPair,
+    side: OrderSide,
+    price: f64,
+    amount: f64,
+    expiry: Option<DateTime<Utc>>,
+    checks: OrderChecks,
+    override_limits: bool,
+    strategy: &str,
+    notifier: &Notifier,
+) -> Result<(OrderId, String), Box<dyn std::error::Error>> {
+    let operation = limited_order_operation(side);
+    // Only valued when a USD limit could apply, to spare the exchange a quote lookup otherwise
+    let usd_value = if db
+        .spending_limits()
+        .iter()
+        .any(|limit| limit.token.is_none() && limit.operation.covers(operation))
+    {
+        match quote_usd_rate(exchange_client, &pair.quote).await {
+            Ok(quote_usd_rate) => Some(amount * price * quote_usd_rate.rate),
+            Err(err) => {
+                eprintln!("Unable to value {pair} order: {err}");
+                None
+            }
         }
+    } else {
+        None
+    };
+    let intent = OrderIntent {
+        exchange,
+        pair: pair.clone(),
+        side,
+        price,
+        amount,
+        strategy: strategy.to_string(),
+    };
+    let spending_limit_check = SpendingLimitCheck::new(db, token, usd_value, override_limits);
+    let result = managed_order::submit(
+        db,
+        exchange_client,
+        &intent,
+        false,
+        expiry,
+        checks,
+        &[&SpreadGuard::Live(exchange_client), &spending_limit_check],
+    )
+    .await;
+    notify_rejection(&intent, &result, notifier).await;
+    let managed_order = result?;
+    audit_spending_limit_overrides(db, spending_limit_check.into_overridden())?;
+    let order_id = managed_order.order_id.expect("placed");
+    let client_order_id = managed_order.client_order_id;
+    db.record_spending(SpendingRecord {
+        time: Utc::now(),
+        operation,
+        exchange,
+        token,
+        ui_amount: amount,
+        usd_value,
+        reference: order_id.clone(),
+        fiat_currency: None,
+    })?;
+    Ok((order_id, client_order_id))
+}
 
-        transaction.try_sign(&signers, recent_blockhash)?;
-        let signature = transaction.signatures[0];
-        println!("Transaction signature: {signature}");
+// The managed order `order` was placed as, if it was placed as one
+fn managed_order_id(db: &Db, order: &OpenOrder) -> Option<String> {
+    order
+        .client_order_id
+        .clone()
+        .filter(|client_order_id| db.managed_order(client_order_id).is_some())
+}
 
-        db.record_swap(
-            signature,
-            last_valid_block_height,
-            address,
-            liquidity_token,
-            liquidity_token_price,
-            collateral_token.into(),
-            collateral_token_price,
-            lot_selection_method,
-        )?;
+/// Status of an open order, recorded against its managed order
+async fn open_order_status(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    order: &OpenOrder,
+) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+    match managed_order_id(db, order) {
+        Some(id) => Ok(managed_order::poll(db, exchange_client, &id).await?.1),
+        None => exchange_client.order_status(pair, &order.order_id).await,
+    }
+}
 
-        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-            db.cancel_swap(signature).expect("cancel_swap");
-            return Err("Swap failed".into());
+/// Cancels an open order, returning its final status and recording it against its managed order
+async fn cancel_open_order(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    order: &OpenOrder,
+) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+    match managed_order_id(db, order) {
+        Some(id) => Ok(managed_order::cancel(db, exchange_client, &id).await?.1),
+        None => {
+            exchange_client
+                .cancel_order_final(pair, &order.order_id)
+                .await
         }
     }
-    Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn process_tulip_withdraw<T: Signers>(
+async fn process_exchange_withdraw(
     db: &mut Db,
-    rpc_client: &RpcClient,
-    liquidity_token: MaybeToken,
-    collateral_token: Token,
-    liquidity_amount: Option<u64>,
-    address: Pubkey,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    deposit_address: Pubkey,
+    amount: Option<u64>,
+    to_address: Pubkey,
     lot_selection_method: LotSelectionMethod,
-    signers: T,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let collateral_tracked_account = db
-        .get_account(address, collateral_token.into())
-        .ok_or_else(|| format!("Unknown account {address} ({collateral_token})"))?;
-    let collateral_account_balance = collateral_tracked_account.last_update_balance;
+    lot_numbers: Option<HashSet<usize>>,
+    withdrawal_password: Option<String>,
+    withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+    rpc_client: &RpcClient,
+    override_limits: bool,
+    lending_redemption: Option<&Cancellation>,
+    strategy: &str,
+    notifier: &Notifier,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let deposit_account = db
+        .get_account(deposit_address, token)
+        .expect("unknown deposit address");
 
-    let collateral_amount = match liquidity_amount {
-        None => collateral_account_balance,
-        Some(liquidity_amount) => collateral_token.amount(
-            f64::try_from(
-                Decimal::from_f64(liquidity_token.ui_amount(liquidity_amount)).unwrap()
-                    / tulip::get_current_liquidity_token_rate(rpc_client, &collateral_token)
-                        .await?,
-            )
-            .unwrap(),
-        ),
+    let amount = amount.unwrap_or(deposit_account.last_update_balance);
+    let request = WithdrawalRequest {
+        exchange,
+        token,
+        amount,
+        to_address,
+        strategy: strategy.to_string(),
     };
+    let description = describe_withdrawal(&request);
+    // Only valued when a USD limit could apply
+    let usd_value =
+        if db.spending_limits().iter().any(|limit| {
+            limit.token.is_none() && limit.operation.covers(LimitedOperation::Withdrawal)
+        }) {
+            withdrawal_usd_value(token, amount, rpc_client).await
+        } else {
+            None
+        };
+    // Checked before any lending is redeemed for the withdrawal, and again as it's requested
+    let spending_limit_check = SpendingLimitCheck::new(db, token, usd_value, override_limits);
+    check_withdrawal_request(
+        &[&WithdrawalAllowList { db }, &spending_limit_check],
+        &request,
+        notifier,
+    )
+    .await?;
 
-    if collateral_amount > collateral_account_balance {
-        return Err(format!(
-            "Withdraw amount is too large: {0}{1} (max: {0}{2})",
-            collateral_token.symbol(),
-            collateral_token.ui_amount(collateral_amount),
-            collateral_token.ui_amount(collateral_account_balance)
-        )
-        .into());
-    }
-    if collateral_amount == 0 {
-        return Err("Nothing to withdraw".into());
+    // Only checked for tokens whose balance the exchange reports
+    if let Some(balance) = token_balances(exchange, exchange_client)
+        .await?
+        .tokens
+        .get(&token)
+    {
+        if amount > native_amount_floor(token, balance.available) {
+            let shortfall = token.ui_amount(amount) - balance.available;
+            match lending_redemption {
+                Some(cancellation) if token.ui_amount(amount) <= balance.total => {
+                    redeem_lending_for_withdrawal(
+                        db,
+                        exchange,
+                        exchange_client,
+                        token,
+                        amount,
+                        shortfall,
+                        cancellation,
+                    )
+                    .await?;
+                }
+                _ => {
+                    return Err(format!(
+                        "Withdrawal of {} exceeds the {} {token} available on {exchange:?}",
+                        token.ui_amount(amount),
+                        balance.available
+                    )
+                    .into());
+                }
+            }
+        }
     }
 
-    let liquidity_token_price = liquidity_token.get_current_price(rpc_client).await?;
-    let collateral_token_price = collateral_token.get_current_price(rpc_client).await?;
-    let collateral_token_ui_amount = collateral_token.ui_amount(collateral_amount);
-
-    println!("{address}: {collateral_token} -> {liquidity_token}");
-    println!(
-        "Estimated withdraw amount: {}{} (${})",
-        collateral_token.symbol(),
-        collateral_token.ui_amount(collateral_amount),
-        collateral_token_price * Decimal::from_f64(collateral_token_ui_amount).unwrap()
-    );
+    let result = request_tagged_withdraw(
+        exchange,
+        exchange_client,
+        to_address,
+        token,
+        amount,
+        withdrawal_password,
+        withdrawal_code,
+        strategy,
+        &[&WithdrawalAllowList { db }, &spending_limit_check],
+    )
+    .await;
+    notify_rejection(&request, &result, notifier).await;
+    let (tag, fee_as_ui_amount) = outcome::record(
+        OperationKind::WithdrawalRequested,
+        description,
+        result,
+        |(tag, _)| tag.clone(),
+    )?;
+    audit_spending_limit_overrides(db, spending_limit_check.into_overridden())?;
 
-    let instructions = tulip::withdraw(
-        rpc_client,
-        address,
-        liquidity_token,
-        collateral_token,
-        collateral_amount,
+    let fee = token.amount(fee_as_ui_amount);
+    db.record_withdrawal(
+        exchange,
+        tag.clone(),
+        token,
+        amount,
+        fee,
+        deposit_address,
+        to_address,
+        lot_selection_method,
+        lot_numbers,
     )?;
+    // `amount` includes the fee, all of which leaves the exchange balance
+    db.record_exchange_journal([ExchangeJournalEntry {
+        time: Utc::now(),
+        exchange,
+        kind: ExchangeJournalKind::Withdrawal,
+        coin: token.name().to_string(),
+        amount: -token.ui_amount(amount),
+        reference: tag.clone(),
+        backfilled: false,
+    }])?;
+    db.record_spending(SpendingRecord {
+        time: Utc::now(),
+        operation: LimitedOperation::Withdrawal,
+        exchange,
+        token,
+        ui_amount: token.ui_amount(amount),
+        usd_value,
+        reference: tag.clone(),
+        fiat_currency: None,
+    })?;
+    Ok(tag)
+}
 
-    let (recent_blockhash, last_valid_block_height) =
-        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+async fn withdrawal_usd_value(
+    token: MaybeToken,
+    amount: u64,
+    rpc_client: &RpcClient,
+) -> Option<f64> {
+    match token.get_current_price(rpc_client).await {
+        Ok(price) => price.to_f64().map(|price| price * token.ui_amount(amount)),
+        Err(err) => {
+            eprintln!("Unable to value {token} withdrawal: {err}");
+            None
+        }
+    }
+}
 
-    let mut message = Message::new(&instructions, Some(&address));
-    message.recent_blockhash = recent_blockhash;
+/// The approval policy and USD value of a withdrawal of `amount` that must be approved before it's
+/// requested, or `None` when it can be requested immediately.  A withdrawal that can't be valued
+/// is held for approval
+async fn withdrawal_needing_approval(
+    db: &Db,
+    token: MaybeToken,
+    amount: u64,
+    rpc_client: &RpcClient,
+) -> Option<(WithdrawalApprovalPolicy, Option<f64>)> {
+    let policy = db.get_withdrawal_approval_policy()?.clone();
+    match withdrawal_usd_value(token, amount, rpc_client).await {
+        Some(usd_value) if usd_value < policy.threshold_usd => None,
+        _ => Some((policy, usd_value)),
+    }
+}
 
-    let mut transaction = Transaction::new_unsigned(message);
-    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-    if simulation_result.err.is_some() {
-        return Err(format!("Simulation failure: {simulation_result:?}").into());
+/// The approval policy of a fiat withdrawal worth `usd_value` that must be approved before it's
+/// requested, or `None` when it can be requested immediately.  A withdrawal that can't be valued
+/// is held for approval
+fn fiat_withdrawal_needing_approval(
+    db: &Db,
+    usd_value: Option<f64>,
+) -> Option<WithdrawalApprovalPolicy> {
+    let policy = db.get_withdrawal_approval_policy()?.clone();
+    match usd_value {
+        Some(usd_value) if usd_value < policy.threshold_usd => None,
+        _ => Some(policy),
     }
+}
 
-    transaction.try_sign(&signers, recent_blockhash)?;
-    let signature = transaction.signatures[0];
-    println!("Transaction signature: {signature}");
+fn new_withdrawal_confirmation_code() -> String {
+    let seed = format!("{}-{}", Utc::now().timestamp_nanos(), std::process::id());
+    Sha1::digest(seed.as_bytes())
+        .iter()
+        .take(4)
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
 
-    db.record_swap(
-        signature,
-        last_valid_block_height,
-        address,
-        collateral_token.into(),
-        collateral_token_price,
-        liquidity_token,
-        liquidity_token_price,
+#[allow(clippy::too_many_arguments)]
+fn process_create_withdrawal_intent(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    token: MaybeToken,
+    amount: u64,
+    to_address: Pubkey,
+    lot_selection_method: LotSelectionMethod,
+    lot_numbers: Option<HashSet<usize>>,
+    policy: &WithdrawalApprovalPolicy,
+    usd_value: Option<f64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let created_at = Utc::now();
+    let intent = WithdrawalIntent {
+        code: new_withdrawal_confirmation_code(),
+        exchange,
+        exchange_account: exchange_account.to_string(),
+        token,
+        amount,
+        to_address,
         lot_selection_method,
-    )?;
+        lot_numbers,
+        usd_value,
+        created_at,
+        expires_at: created_at + chrono::Duration::hours(policy.expiry_hours.into()),
+        state: WithdrawalIntentState::Pending,
+        fiat: None,
+    };
+    add_withdrawal_intent(db, intent, policy)
+}
 
-    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-        db.cancel_swap(signature).expect("cancel_swap");
-        return Err("Swap failed".into());
+#[allow(clippy::too_many_arguments)]
+fn process_create_fiat_withdrawal_intent(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    currency: &str,
+    amount: f64,
+    method_id: &str,
+    policy: &WithdrawalApprovalPolicy,
+    usd_value: Option<f64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let created_at = Utc::now();
+    let intent = WithdrawalIntent {
+        code: new_withdrawal_confirmation_code(),
+        exchange,
+        exchange_account: exchange_account.to_string(),
+        token: MaybeToken::SOL(),
+        amount: 0,
+        to_address: Pubkey::default(),
+        lot_selection_method: LotSelectionMethod::default(),
+        lot_numbers: None,
+        usd_value,
+        created_at,
+        expires_at: created_at + chrono::Duration::hours(policy.expiry_hours.into()),
+        state: WithdrawalIntentState::Pending,
+        fiat: Some(FiatWithdrawalIntent {
+            currency: currency.to_string(),
+            amount,
+            method_id: method_id.to_string(),
+        }),
+    };
+    add_withdrawal_intent(db, intent, policy)
+}
+
+// Records `intent`, explaining why it awaits approval and how to approve it
+fn add_withdrawal_intent(
+    db: &mut Db,
+    intent: WithdrawalIntent,
+    policy: &WithdrawalApprovalPolicy,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match intent.usd_value {
+        Some(usd_value) => println!(
+            "Withdrawal of {} (${}) is at or above the ${} approval threshold",
+            intent.amount_description(),
+            usd_value.separated_string_with_fixed_place(2),
+            policy.threshold_usd.separated_string_with_fixed_place(2),
+        ),
+        None => println!(
+            "Withdrawal of {} could not be valued and requires approval",
+            intent.amount_description(),
+        ),
     }
+    println!(
+        "Created withdrawal intent {}, expires {}",
+        intent.code,
+        HumanTime::from(intent.expires_at)
+    );
+    println!(
+        "Approve with: sys withdrawal-approval approve {}",
+        intent.code
+    );
+    db.add_withdrawal_intent(intent)?;
+    Ok(())
+}
 
+async fn process_expire_withdrawal_intents(
+    db: &mut Db,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for intent in db.expire_withdrawal_intents(Utc::now())? {
+        let msg = format!(
+            "Withdrawal intent {} expired: {:?} {} to {}",
+            intent.code,
+            intent.exchange,
+            intent.amount_description(),
+            intent.destination()
+        );
+        println!("{msg}");
+        notifier.send(&msg).await;
+    }
     Ok(())
 }
 
-async fn process_sync_swaps(
+// The strategy an approved withdrawal intent is executed as, which skips the approval gate
+const WITHDRAWAL_APPROVAL_STRATEGY: &str = "withdrawal-approval";
+
+async fn process_approve_withdrawal_intent(
     db: &mut Db,
+    code: &str,
+    withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
     rpc_client: &RpcClient,
     notifier: &Notifier,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let block_height = rpc_client.get_epoch_info()?.block_height;
+    process_expire_withdrawal_intents(db, notifier).await?;
+    let intent = db
+        .get_withdrawal_intent(code)
+        .ok_or_else(|| format!("Unknown withdrawal intent: {code}"))?;
+    if !intent.state.is_pending() {
+        return Err(format!("Withdrawal intent {} is {}", intent.code, intent.state).into());
+    }
 
-    for PendingSwap {
-        signature,
-        last_valid_block_height,
-        address,
-        from_token,
-        to_token,
+    let WithdrawalIntent {
+        code,
+        exchange,
+        exchange_account,
+        token,
+        amount,
+        to_address,
+        lot_selection_method,
+        lot_numbers,
+        fiat,
         ..
-    } in db.pending_swaps()
-    {
-        let swap = format!("swap ({address}: {from_token} -> {to_token})");
-
-        let status = rpc_client.get_signature_status_with_commitment_and_history(
-            &signature,
-            rpc_client.commitment(),
+    } = intent;
+    db.audit_withdrawal_intent(&code, WithdrawalAuditEvent::Approved, String::new())?;
+
+    let exchange_credentials = db
+        .get_exchange_credentials(exchange, &exchange_account)
+        .ok_or_else(|| ConfigurationError(format!("No API key set for {exchange:?}")))?;
+    let exchange_client = exchange_client_new(exchange, exchange_credentials)?;
+
+    // An approved withdrawal has been through the approval workflow, which stands in for the
+    // per-operation limits.  Each one bypassed is still recorded in the audit log
+    let result = match fiat {
+        Some(FiatWithdrawalIntent {
+            currency,
+            amount,
+            method_id,
+        }) => process_exchange_fiat_withdraw(
+            db,
+            exchange,
+            exchange_client.as_ref(),
+            &exchange_account,
+            &currency,
+            Some(amount),
+            &method_id,
             true,
-        )?;
-        match status {
-            Some(result) => {
-                if result.is_ok() {
-                    println!("Pending {swap} confirmed: {signature}");
-                    let result = rpc_client.get_transaction_with_config(
-                        &signature,
-                        RpcTransactionConfig {
-                            commitment: Some(rpc_client.commitment()),
-                            max_supported_transaction_version: Some(0),
-                            ..RpcTransactionConfig::default()
-                        },
-                    )?;
-
-                    let block_time = result
-                        .block_time
-                        .ok_or("Transaction block time not available")?;
+            WITHDRAWAL_APPROVAL_STRATEGY,
+            notifier,
+        )
+        .await
+        .map(|withdrawal_id| withdrawal_id.expect("approved")),
+        None => {
+            let deposit_address = exchange_deposit_address(
+                db,
+                exchange,
+                &exchange_account,
+                exchange_client.as_ref(),
+                token,
+            )
+            .await?;
+            add_exchange_deposit_address_to_db(
+                db,
+                exchange,
+                &exchange_account,
+                token,
+                deposit_address,
+                rpc_client,
+            )?;
 
-                    let when = Local.timestamp(block_time, 0);
-                    let when = NaiveDate::from_ymd(when.year(), when.month(), when.day());
+            process_exchange_withdraw(
+                db,
+                exchange,
+                exchange_client.as_ref(),
+                token,
+                deposit_address,
+                Some(amount),
+                to_address,
+                lot_selection_method,
+                lot_numbers,
+                None,
+                withdrawal_code,
+                rpc_client,
+                true,
+                None,
+                WITHDRAWAL_APPROVAL_STRATEGY,
+                notifier,
+            )
+            .await
+        }
+    };
+    match result {
+        Ok(tag) => {
+            println!("Withdrawal intent {code} executed as {tag}");
+            db.update_withdrawal_intent(
+                &code,
+                WithdrawalIntentState::Executed { tag: tag.clone() },
+                WithdrawalAuditEvent::Executed,
+                tag,
+            )?;
+        }
+        Err(err) => {
+            db.update_withdrawal_intent(
+                &code,
+                WithdrawalIntentState::Failed {
+                    error: err.to_string(),
+                },
+                WithdrawalAuditEvent::Failed,
+                err.to_string(),
+            )?;
+            return Err(err);
+        }
+    }
 
-                    let transaction_status_meta = result.transaction.meta.unwrap();
+    process_sync_exchange(db, exchange, exchange_client.as_ref(), rpc_client, notifier).await
+}
 
-                    let pre_token_balances =
-                        Option::<Vec<_>>::from(transaction_status_meta.pre_token_balances)
-                            .unwrap_or_default();
-                    let post_token_balances =
-                        Option::<Vec<_>>::from(transaction_status_meta.post_token_balances)
-                            .unwrap_or_default();
+/// Requests a fiat withdrawal, returning its id, or `None` when it's held for approval as a
+/// withdrawal intent instead
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_fiat_withdraw(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    exchange_account: &str,
+    currency: &str,
+    amount: Option<f64>,
+    method_id: &str,
+    override_limits: bool,
+    strategy: &str,
+    notifier: &Notifier,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    exchange::check_fiat_currency(currency)?;
+    if !exchange.capabilities().supports_fiat_withdrawals {
+        return Err(format!("{exchange:?} does not support fiat withdrawals").into());
+    }
+    check_system_status(exchange, exchange_client, None, SystemOperation::Withdraw).await?;
+
+    let method = exchange_client
+        .fiat_withdrawal_methods(currency)
+        .await?
+        .into_iter()
+        .find(|method| method.id == method_id)
+        .ok_or_else(|| {
+            format!("{method_id} is not a registered {exchange:?} {currency} withdrawal method")
+        })?;
 
-                    let token_amount_diff = |owner: Pubkey, mint: Pubkey| {
-                        let owner = owner.to_string();
-                        let mint = mint.to_string();
+    let available = exchange_client
+        .balances()
+        .await?
+        .get(currency)
+        .map(|balance| balance.available)
+        .unwrap_or_default();
+    let amount = amount.unwrap_or(available);
+    if amount <= 0. || amount > available {
+        return Err(format!(
+            "Withdrawal of {} exceeds the {} available on {exchange:?}",
+            format_quote_amount(&amount.to_string(), currency),
+            format_quote_amount(&available.to_string(), currency),
+        )
+        .into());
+    }
 
-                        let num_token_balances = pre_token_balances
-                            .iter()
-                            .filter(|token_balance| token_balance.mint == mint)
-                            .count();
-                        assert_eq!(
-                            num_token_balances,
-                            post_token_balances
-                                .iter()
-                                .filter(|token_balance| token_balance.mint == mint)
-                                .count()
-                        );
+    let request = FiatWithdrawalRequest {
+        exchange,
+        currency: currency.to_string(),
+        amount,
+        method: method.name.clone(),
+        strategy: strategy.to_string(),
+    };
+    let description = describe_fiat_withdrawal(&request);
+    // Always valued, as a fiat withdrawal can't be valued later without the exchange's markets
+    let usd_value = match quote_usd_rate(exchange_client, currency).await {
+        Ok(quote_usd_rate) => Some(amount * quote_usd_rate.rate),
+        Err(err) => {
+            eprintln!("Unable to value {currency} withdrawal: {err}");
+            None
+        }
+    };
+    if strategy != WITHDRAWAL_APPROVAL_STRATEGY {
+        if let Some(policy) = fiat_withdrawal_needing_approval(db, usd_value) {
+            process_expire_withdrawal_intents(db, notifier).await?;
+            process_create_fiat_withdrawal_intent(
+                db,
+                exchange,
+                exchange_account,
+                currency,
+                amount,
+                method_id,
+                &policy,
+                usd_value,
+            )?;
+            return Ok(None);
+        }
+    }
+    let spending_limit_check = SpendingLimitCheck::fiat(db, usd_value, override_limits);
+    check_fiat_withdrawal_request(&[&spending_limit_check], &request, notifier).await?;
+    audit_spending_limit_overrides(db, spending_limit_check.into_overridden())?;
+
+    confirmation::confirm(
+        &IrreversibleOperation::new(format!("Fiat withdrawal from {exchange:?}"))
+            .detail("Amount", format_quote_amount(&amount.to_string(), currency))
+            .detail("Destination", format!("{} ({method_id})", method.name))
+            .detail("Fee", "as charged by the exchange"),
+    )?;
+    let withdrawal_id = outcome::record(
+        OperationKind::FiatWithdrawalRequested,
+        description,
+        exchange_client
+            .request_fiat_withdraw(currency, amount, method_id)
+            .await,
+        |withdrawal_id| withdrawal_id.clone(),
+    )?;
+    let now = Utc::now();
+    db.record_fiat_withdrawal(FiatWithdrawal {
+        exchange,
+        withdrawal_id: withdrawal_id.clone(),
+        currency: currency.to_string(),
+        amount,
+        method_id: method_id.to_string(),
+        request_time: now,
+        state: FiatWithdrawalState::Pending,
+        fee: None,
+        last_update: now,
+    })?;
+    db.record_exchange_journal([ExchangeJournalEntry {
+        time: now,
+        exchange,
+        kind: ExchangeJournalKind::Withdrawal,
+        coin: currency.to_string(),
+        amount: -amount,
+        reference: withdrawal_id.clone(),
+        backfilled: false,
+    }])?;
+    db.record_spending(SpendingRecord {
+        time: now,
+        operation: LimitedOperation::Withdrawal,
+        exchange,
+        token: MaybeToken::SOL(),
+        ui_amount: amount,
+        usd_value,
+        reference: withdrawal_id.clone(),
+        fiat_currency: Some(currency.to_string()),
+    })?;
 
-                        let pre = pre_token_balances
-                            .iter()
-                            .filter_map(|token_balance| {
-                                if (num_token_balances == 1
-                                    || token_balance.owner.as_ref() == Some(&owner).into())
-                                    && token_balance.mint == mint
-                                {
-                                    Some(
-                                        token_balance
-                                            .ui_token_amount
-                                            .amount
-                                            .parse::<u64>()
-                                            .expect("amount"),
-                                    )
-                                } else {
-                                    None
-                                }
-                            })
-                            .next()
-                            .unwrap_or_else(|| {
-                                panic!(
-                                    "pre_token_balance not found for owner {address}, mint {mint}"
-                                )
-                            });
-                        let post = post_token_balances
-                            .iter()
-                            .filter_map(|token_balance| {
-                                if (num_token_balances == 1
-                                    || token_balance.owner.as_ref() == Some(&owner).into())
-                                    && token_balance.mint == mint
-                                {
-                                    Some(
-                                        token_balance
-                                            .ui_token_amount
-                                            .amount
-                                            .parse::<u64>()
-                                            .expect("amount"),
-                                    )
-                                } else {
-                                    None
-                                }
-                            })
-                            .next()
-                            .unwrap_or_else(|| {
-                                panic!(
-                                    "post_token_balance not found for owner {address},  mint {mint}"
-                                )
-                            });
-                        (post as i64 - pre as i64).unsigned_abs()
-                    };
+    let msg = format!(
+        "Requested {} withdrawal to {} ({withdrawal_id})",
+        format_quote_amount(&amount.to_string(), currency),
+        method.name
+    );
+    println!("{msg}");
+    notifier.send(&format!("{exchange:?}: {msg}")).await;
+    Ok(Some(withdrawal_id))
+}
 
-                    let from_amount = token_amount_diff(address, from_token.mint());
-                    let to_amount = token_amount_diff(address, to_token.mint());
-                    let msg = format!(
-                        "Swapped {}{} into {}{} at {}{} per {}1",
-                        from_token.symbol(),
-                        from_token
-                            .ui_amount(from_amount)
-                            .separated_string_with_fixed_place(2),
-                        to_token.symbol(),
-                        to_token
-                            .ui_amount(to_amount)
-                            .separated_string_with_fixed_place(2),
-                        to_token.symbol(),
-                        (to_token.ui_amount(to_amount) / from_token.ui_amount(from_amount))
-                            .separated_string_with_fixed_place(2),
-                        from_token.symbol(),
-                    );
-                    db.confirm_swap(signature, when, from_amount, to_amount)?;
-                    notifier.send(&msg).await;
-                    println!("{msg}");
-                } else {
-                    println!("Pending {swap} failed with {result:?}: {signature}");
-                    db.cancel_swap(signature)?;
-                }
+// Refreshes the state of fiat withdrawals that haven't settled.  A withdrawal whose status can't
+// be fetched is skipped, so it doesn't hold up the rest of the sync
+async fn sync_fiat_withdrawals(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for fiat_withdrawal in db
+        .fiat_withdrawals(Some(exchange))
+        .into_iter()
+        .filter(|fiat_withdrawal| !fiat_withdrawal.state.is_final())
+    {
+        let amount = format_quote_amount(
+            &fiat_withdrawal.amount.to_string(),
+            &fiat_withdrawal.currency,
+        );
+        let status = match exchange_client
+            .fiat_withdrawal_status(&fiat_withdrawal.currency, &fiat_withdrawal.withdrawal_id)
+            .await
+        {
+            Ok(status) => status,
+            Err(err) => {
+                eprintln!(
+                    "Unable to get the status of {amount} withdrawal {}: {err}",
+                    fiat_withdrawal.withdrawal_id
+                );
+                continue;
             }
-            None => {
-                if block_height > last_valid_block_height {
-                    println!("Pending {swap} cancelled: {signature}");
-                    db.cancel_swap(signature)?;
-                } else {
-                    println!(
-                        "{} pending for at most {} blocks: {}",
-                        swap,
-                        last_valid_block_height.saturating_sub(block_height),
-                        signature
-                    );
-                }
+        };
+
+        if status.state != fiat_withdrawal.state || status.fee != fiat_withdrawal.fee {
+            db.update_fiat_withdrawal(
+                exchange,
+                &fiat_withdrawal.withdrawal_id,
+                status.state,
+                status.fee,
+            )?;
+            if status.state == FiatWithdrawalState::Cancelled {
+                db.record_exchange_journal([ExchangeJournalEntry {
+                    time: Utc::now(),
+                    exchange,
+                    kind: ExchangeJournalKind::Withdrawal,
+                    coin: fiat_withdrawal.currency.clone(),
+                    amount: fiat_withdrawal.amount,
+                    reference: fiat_withdrawal.withdrawal_id.clone(),
+                    backfilled: false,
+                }])?;
             }
         }
-    }
 
+        let msg = format!(
+            "{amount} withdrawal {} {}",
+            fiat_withdrawal.withdrawal_id, status.state
+        );
+        println!("{msg}");
+        if status.state.is_final() {
+            notifier.send(&format!("{exchange:?}: {msg}")).await;
+        }
+    }
     Ok(())
 }
 
-struct LiquidityTokenInfo {
-    liquidity_token: MaybeToken,
-    current_liquidity_token_rate: Decimal,
-    current_apr: Option<f64>,
+fn print_fiat_withdrawals(fiat_withdrawals: &[FiatWithdrawal]) {
+    for fiat_withdrawal in fiat_withdrawals {
+        println!(
+            "{} {:<10} {} to {} ({}), requested {}{}",
+            fiat_withdrawal.withdrawal_id,
+            fiat_withdrawal.state,
+            format_quote_amount(
+                &fiat_withdrawal.amount.to_string(),
+                &fiat_withdrawal.currency
+            ),
+            fiat_withdrawal.method_id,
+            fiat_withdrawal.currency,
+            HumanTime::from(fiat_withdrawal.request_time),
+            fiat_withdrawal
+                .fee
+                .map(|fee| format!(
+                    ", fee {}",
+                    format_quote_amount(&fee.to_string(), &fiat_withdrawal.currency)
+                ))
+                .unwrap_or_default(),
+        );
+    }
 }
 
-fn liquidity_token_ui_amount(
-    acquisition_liquidity_ui_amount: Option<f64>,
-    ui_amount: f64,
-    liquidity_token_info: Option<&LiquidityTokenInfo>,
-    include_apr: bool,
-) -> (String, String) {
-    liquidity_token_info
-        .map(
-            |LiquidityTokenInfo {
-                 liquidity_token,
-                 current_liquidity_token_rate,
-                 current_apr,
-             }| {
-                let liquidity_ui_amount = f64::try_from(
-                    Decimal::from_f64(ui_amount).unwrap() * current_liquidity_token_rate,
-                )
-                .unwrap();
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_transfer(
+    db: &mut Db,
+    from_exchange: Exchange,
+    from_account: &str,
+    from_client: &dyn ExchangeClient,
+    to_exchange: Exchange,
+    to_account: &str,
+    to_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    amount: Option<u64>,
+    lot_selection_method: LotSelectionMethod,
+    withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+    resume_tag: Option<String>,
+    cancellation: &Cancellation,
+    rpc_client: &RpcClient,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let start = std::time::Instant::now();
+    let poll_interval = Duration::from_secs(30);
 
-                (
-                    format!(
-                        " [{}{}]",
-                        liquidity_token.format_ui_amount(liquidity_ui_amount),
-                        match current_apr {
-                            Some(current_apr) if include_apr => format!(", {current_apr:.2}% APR"),
-                            _ => String::new(),
-                        }
-                    ),
-                    acquisition_liquidity_ui_amount
-                        .map(|acquisition_liquidity_ui_amount| {
-                            format!(
-                                "[{}{}]",
-                                liquidity_token.symbol(),
-                                (liquidity_ui_amount - acquisition_liquidity_ui_amount)
-                                    .separated_string_with_fixed_place(2)
-                            )
-                        })
-                        .unwrap_or_default(),
-                )
-            },
+    if let Ok(token_support) = from_client.supports_token(token).await {
+        token_support.check_withdrawals(from_exchange, token)?;
+    }
+    if let Ok(token_support) = to_client.supports_token(token).await {
+        token_support.check_deposits(to_exchange, token)?;
+    }
+
+    let from_deposit_address =
+        verified_deposit_address(db, from_exchange, from_account, from_client, token).await?;
+    add_exchange_deposit_address_to_db(
+        db,
+        from_exchange,
+        from_account,
+        token,
+        from_deposit_address,
+        rpc_client,
+    )?;
+
+    let to_deposit_address =
+        verified_deposit_address(db, to_exchange, to_account, to_client, token).await?;
+    if to_deposit_address == from_deposit_address || to_deposit_address == Pubkey::default() {
+        return Err(format!(
+            "{to_exchange:?} returned an invalid {token} deposit address: {to_deposit_address}"
         )
-        .unwrap_or_default()
-}
+        .into());
+    }
+    add_exchange_deposit_address_to_db(
+        db,
+        to_exchange,
+        to_account,
+        token,
+        to_deposit_address,
+        rpc_client,
+    )?;
+    println!("{to_exchange:?} {token} deposit address: {to_deposit_address}");
 
-#[allow(clippy::too_many_arguments)]
-async fn println_lot(
-    token: MaybeToken,
-    lot: &Lot,
-    current_price: Option<Decimal>,
-    liquidity_token_info: Option<&LiquidityTokenInfo>,
-    total_income: &mut f64,
-    total_cap_gain: &mut f64,
-    long_term_cap_gain: &mut bool,
-    total_current_value: &mut f64,
-    notifier: Option<&Notifier>,
-    verbose: bool,
-) {
-    let current_value = current_price.map(|current_price| {
-        f64::try_from(Decimal::from_f64(token.ui_amount(lot.amount)).unwrap() * current_price)
-            .unwrap()
+    // Deposits already credited before the withdrawal, to tell a new arrival apart when the
+    // destination reports a different transaction id
+    let prior_deposits = to_client
+        .recent_deposits()
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|deposit_info| deposit_info.tx_id)
+        .collect::<HashSet<_>>();
+
+    // An interrupted transfer leaves its withdrawal pending in the database, pick it back up
+    // instead of submitting another
+    let resume_tag = resume_tag.or_else(|| {
+        db.pending_withdrawals(Some(from_exchange))
+            .into_iter()
+            .find(|pending_withdrawal| {
+                pending_withdrawal.token == token
+                    && pending_withdrawal.from_address == from_deposit_address
+                    && pending_withdrawal.to_address == to_deposit_address
+            })
+            .map(|pending_withdrawal| pending_withdrawal.tag)
     });
-    let income = lot.income(token);
-    let cap_gain = lot.cap_gain(token, current_price.unwrap_or_default());
 
-    let mut acquisition_liquidity_ui_amount = None;
-    if let Some(LiquidityTokenInfo {
-        liquidity_token, ..
-    }) = liquidity_token_info
-    {
-        if let LotAcquistionKind::Swap { token, amount, .. } = lot.acquisition.kind {
-            if !token.fiat_fungible() && token == *liquidity_token {
-                if let Some(amount) = amount {
-                    acquisition_liquidity_ui_amount = Some(token.ui_amount(amount));
+    let tag = match resume_tag {
+        Some(tag) => {
+            println!("Resuming {from_exchange:?} withdrawal {tag}");
+            tag
+        }
+        None => {
+            let resolved_amount = amount.unwrap_or_else(|| {
+                db.get_account(from_deposit_address, token)
+                    .expect("unknown deposit address")
+                    .last_update_balance
+            });
+            if let Some((policy, _)) =
+                withdrawal_needing_approval(db, token, resolved_amount, rpc_client).await
+            {
+                return Err(format!(
+                    "Withdrawals of ${} or more require approval, withdraw to \
+                     {to_deposit_address} with `sys {} withdraw` to create a withdrawal intent",
+                    policy.threshold_usd.separated_string_with_fixed_place(2),
+                    from_exchange.name()
+                )
+                .into());
+            }
+            let ui_amount = token.ui_amount(resolved_amount);
+            if let Some(limits) = from_client.withdrawal_limits(token).await? {
+                if ui_amount < limits.minimum {
+                    return Err(format!(
+                        "{ui_amount} {token} is below the {from_exchange:?} withdrawal minimum of {}",
+                        limits.minimum
+                    )
+                    .into());
+                }
+                if ui_amount <= limits.fee {
+                    return Err(format!(
+                        "{ui_amount} {token} does not cover the {from_exchange:?} withdrawal fee of {}",
+                        limits.fee
+                    )
+                    .into());
                 }
+                println!("{from_exchange:?} withdrawal fee: {} {token}", limits.fee);
             }
-        }
-    }
 
-    *total_income += income;
-    *total_cap_gain += cap_gain;
-    *total_current_value += current_value.unwrap_or_default();
-    *long_term_cap_gain = is_long_term_cap_gain(lot.acquisition.when, None);
+            let tag = process_exchange_withdraw(
+                db,
+                from_exchange,
+                from_client,
+                token,
+                from_deposit_address,
+                amount,
+                to_deposit_address,
+                lot_selection_method,
+                None,
+                None,
+                withdrawal_code,
+                rpc_client,
+                false,
+                None,
+                "transfer",
+                notifier,
+            )
+            .await?;
+            println!("{from_exchange:?} withdrawal {tag} submitted");
+            tag
+        }
+    };
 
-    let ui_amount = token.ui_amount(lot.amount);
-    let (liquidity_ui_amount, liquidity_token_cap_gain) = liquidity_token_ui_amount(
-        acquisition_liquidity_ui_amount,
-        ui_amount,
-        liquidity_token_info,
-        false,
-    );
+    let fee = db
+        .pending_withdrawals(Some(from_exchange))
+        .into_iter()
+        .find(|pending_withdrawal| pending_withdrawal.tag == tag)
+        .map(|pending_withdrawal| token.ui_amount(pending_withdrawal.fee));
 
-    let current_value = current_value
-        .map(|current_value| {
-            format!(
-                "value: ${}{}",
-                current_value.separated_string_with_fixed_place(2),
-                liquidity_ui_amount
+    let withdrawal = loop {
+        let withdrawal = from_client
+            .recent_withdrawals()
+            .await?
+            .into_iter()
+            .find(|wi| wi.tag == tag)
+            .ok_or_else(|| format!("Unknown {from_exchange:?} withdrawal: {tag}"))?;
+        if withdrawal.completed {
+            break withdrawal;
+        }
+        println!("Waiting for {from_exchange:?} withdrawal {tag} to complete...");
+        if let Err(err) = cancellation.sleep(poll_interval).await {
+            return Err(format!(
+                "{err} waiting for {from_exchange:?} withdrawal {tag}. Rerun to resume"
             )
-        })
-        .unwrap_or_else(|| "value: ?".into());
-
-    let description = if verbose {
-        format!("| {}", lot.acquisition.kind,)
-    } else {
-        String::new()
+            .into());
+        }
     };
+    process_sync_exchange(db, from_exchange, from_client, rpc_client, notifier).await?;
 
-    let msg = format!(
-        "{:>5}. {} | {:>20} at ${:<6} | {:<35} | income: ${:<11} | {} gain: ${:<14}{} {}",
-        lot.lot_number,
-        lot.acquisition.when,
-        token.format_ui_amount(ui_amount),
-        f64::try_from(lot.acquisition.price())
-            .unwrap()
-            .separated_string_with_fixed_place(2),
-        current_value,
-        income.separated_string_with_fixed_place(2),
-        if *long_term_cap_gain {
-            " long"
-        } else {
-            "short"
-        },
-        cap_gain.separated_string_with_fixed_place(2),
-        liquidity_token_cap_gain,
-        description,
-    );
+    let tx_id = withdrawal
+        .tx_id
+        .ok_or_else(|| format!("{from_exchange:?} withdrawal {tag} was cancelled"))?;
 
-    if !token.fiat_fungible() {
-        if let Some(notifier) = notifier {
+    let deposit_amount = withdrawal.amount;
+
+    let credit_window = deposit_credit_window(db, to_exchange, Some(to_client), token).await;
+    let credit_start = Utc::now();
+    let mut overdue_warned = false;
+    loop {
+        match to_client.recent_deposits().await? {
+            None => {
+                println!("{to_exchange:?} does not report deposits, unable to confirm {tx_id}");
+                break;
+            }
+            Some(recent_deposits) => {
+                if recent_deposits.iter().any(|deposit_info| {
+                    deposit_info.tx_id == tx_id
+                        || (!prior_deposits.contains(&deposit_info.tx_id)
+                            && token.amount(deposit_info.amount) == token.amount(deposit_amount))
+                }) {
+                    let _ = outcome::record(
+                        OperationKind::DepositMatched,
+                        format!(
+                            "{to_exchange:?}: {token} {}{} from {from_exchange:?}",
+                            token.symbol(),
+                            format_token_amount(token, deposit_amount, true)
+                        ),
+                        Ok::<_, String>(()),
+                        |()| tx_id.clone(),
+                    );
+                    break;
+                }
+            }
+        }
+        if !overdue_warned && Utc::now() - credit_start > credit_window {
+            overdue_warned = true;
+            let msg = format!(
+                "WARNING: {to_exchange:?} has not credited {tx_id} within {} of the withdrawal \
+                 completing",
+                format_credit_window(credit_window)
+            );
+            println!("{}", style(&msg).red().bold());
             notifier.send(&msg).await;
         }
-
-        println!("{msg}");
+        println!("Waiting for {to_exchange:?} to credit {tx_id}...");
+        if let Err(err) = cancellation.sleep(poll_interval).await {
+            return Err(format!(
+                "{err} waiting for {to_exchange:?} to credit {tx_id}. Rerun with `--tag {tag}` to resume"
+            )
+            .into());
+        }
     }
+
+    let msg = format!(
+        "{} {}{} transferred from {from_exchange:?} to {to_exchange:?} ({tx_id}), fee: {}, elapsed: {}s",
+        token,
+        token.symbol(),
+        format_token_amount(token, deposit_amount, true),
+        fee.map(|fee| format!("{}{}", token.symbol(), format_token_amount(token, fee, true)))
+            .unwrap_or_else(|| "unknown".into()),
+        start.elapsed().as_secs(),
+    );
+    println!("{msg}");
+    notifier.send(&msg).await;
+    Ok(())
 }
 
-fn format_disposed_lot(
-    disposed_lot: &DisposedLot,
-    total_income: &mut f64,
-    total_cap_gain: &mut f64,
-    long_term_cap_gain: &mut bool,
-    total_current_value: &mut f64,
-    verbose: bool,
-) -> String {
-    #![allow(clippy::to_string_in_format_args)]
-    let cap_gain = disposed_lot
-        .lot
-        .cap_gain(disposed_lot.token, disposed_lot.price());
-    let income = disposed_lot.lot.income(disposed_lot.token);
+enum LimitOrderPrice {
+    At(f64),
+    AmountOverAsk(f64),
+    AmountUnderBid(f64),
+}
 
-    *long_term_cap_gain =
-        is_long_term_cap_gain(disposed_lot.lot.acquisition.when, Some(disposed_lot.when));
-    *total_income += income;
-    *total_current_value += income + cap_gain;
-    *total_cap_gain += cap_gain;
+// The size of an order, in the base token or as an amount of the quote currency to trade at the
+// order price
+#[derive(Debug, Clone, Copy)]
+enum OrderAmount {
+    Base(f64),
+    // `tolerance` is how far, as a fraction of `amount`, the order's value may end up from it once
+    // rounded to the lot size
+    Quote { amount: f64, tolerance: f64 },
+}
 
-    let description = if verbose {
-        format!(
-            "| {} | {}",
-            disposed_lot.lot.acquisition.kind, disposed_lot.kind
-        )
-    } else {
-        String::new()
-    };
+impl OrderAmount {
+    // The base amount to trade at `price`, before rounding
+    fn base_amount(&self, price: f64) -> f64 {
+        match self {
+            OrderAmount::Base(amount) => *amount,
+            OrderAmount::Quote { amount, .. } => *amount / price,
+        }
+    }
 
-    format!(
-        "{:>5}. {} | {:<7} | {:<17} at ${:<6} | income: ${:<11} | sold {} at ${:6} | {} gain: ${:<14} {}",
-        disposed_lot.lot.lot_number,
-        disposed_lot.lot.acquisition.when,
-        disposed_lot.token.to_string(),
-        disposed_lot.token.format_amount(disposed_lot.lot.amount),
-        f64::try_from(disposed_lot.lot.acquisition.price()).unwrap().separated_string_with_fixed_place(2),
-        income.separated_string_with_fixed_place(2),
-        disposed_lot.when,
-        f64::try_from(disposed_lot.price()).unwrap().separated_string_with_fixed_place(2),
-        if *long_term_cap_gain {
-            " long"
-        } else {
-            "short"
-        },
-        cap_gain.separated_string_with_fixed_place(2),
-        description,
-    )
+    // Refuses a quote-denominated order whose rounded value strays from the requested amount by
+    // more than its tolerance
+    fn check_rounded(
+        &self,
+        pair: &TradingPair,
+        price: f64,
+        amount: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let OrderAmount::Quote {
+            amount: quote_amount,
+            tolerance,
+        } = self
+        {
+            let value = price * amount;
+            let difference = (value - quote_amount).abs() / quote_amount;
+            if difference > *tolerance {
+                return Err(format!(
+                    "Order for {amount} at {} is worth {}, {:.2}% from the requested {} \
+                     (--quote-tolerance {})",
+                    format_quote_amount(&price.to_string(), &pair.quote),
+                    format_quote_amount(&format_decimals(value, 2, false), &pair.quote),
+                    difference * 100.,
+                    format_quote_amount(&quote_amount.to_string(), &pair.quote),
+                    tolerance * 100.,
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    // The requested quote amount alongside the rounded order's value, for quote-denominated orders
+    fn describe_rounding(&self, pair: &TradingPair, price: f64, amount: f64) -> String {
+        match self {
+            OrderAmount::Base(_) => String::new(),
+            OrderAmount::Quote {
+                amount: quote_amount,
+                ..
+            } => format!(
+                " ({} requested, {} after rounding)",
+                format_quote_amount(&quote_amount.to_string(), &pair.quote),
+                format_quote_amount(&format_decimals(price * amount, 2, false), &pair.quote),
+            ),
+        }
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
-async fn process_account_add(
+// Lists `orders` in `operation` by id, with what each would trade
+fn with_order_details(
+    operation: IrreversibleOperation,
+    orders: &[OpenOrder],
+) -> IrreversibleOperation {
+    orders.iter().fold(operation, |operation, order| {
+        let ui_amount = order.ui_amount.unwrap_or_else(|| {
+            order
+                .token
+                .ui_amount(order.lots.iter().map(|lot| lot.amount).sum::<u64>())
+        });
+        operation.detail(
+            &order.order_id,
+            format!(
+                "{} {} {} at {}",
+                order.pair,
+                order.side,
+                order.token.format_ui_amount(ui_amount),
+                order.price
+            ),
+        )
+    })
+}
+
+async fn process_exchange_cancel(
     db: &mut Db,
-    rpc_client: &RpcClient,
-    address: Pubkey,
-    token: MaybeToken,
-    description: String,
-    when: Option<NaiveDate>,
-    price: Option<f64>,
-    income: bool,
-    signature: Option<Signature>,
-    no_sync: bool,
-    ui_amount: Option<f64>,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    order_ids: HashSet<String>,
+    max_create_time: Option<DateTime<Utc>>,
+    side: Option<OrderSide>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (when, amount, last_update_epoch, kind) = match signature {
-        Some(signature) => {
-            assert!(ui_amount.is_none()); // argument parsing should have asserted this already
-            let (address, address_is_token) = match token.token() {
-                Some(token) => (token.ata(&address), true),
-                None => (address, false),
-            };
+    let orders = db
+        .open_orders(Some(exchange), side)
+        .into_iter()
+        .filter(|order_info| {
+            order_ids.contains(&order_info.order_id)
+                || max_create_time.map_or(false, |max_create_time| {
+                    order_info.creation_time < max_create_time
+                })
+        })
+        .collect::<Vec<_>>();
+    if !orders.is_empty() {
+        confirmation::confirm(&with_order_details(
+            IrreversibleOperation::new(format!(
+                "Cancellation of {} {exchange:?} orders",
+                orders.len()
+            )),
+            &orders,
+        ))?;
+    }
 
-            let GetTransactionAddrssBalanceChange {
-                post_amount,
-                slot,
-                when: block_time,
-                ..
-            } = get_transaction_balance_change(rpc_client, &signature, &address, address_is_token)?;
+    let mut cancelled_count = 0;
+    for order_info in orders {
+        println!("Cancelling order {}", order_info.order_id);
+        cancelled_count += 1;
+        match TradingPair::from_exchange_symbol(exchange, &order_info.pair) {
+            Ok(pair) => match cancel_open_order(db, exchange_client, &pair, &order_info).await {
+                Ok(order_status) if order_status.filled_amount > 0. => println!(
+                    "Order {} closed with{}",
+                    order_info.order_id,
+                    format_filled_amount(
+                        order_status.filled_amount,
+                        order_status.average_fill_price,
+                        &pair
+                    )
+                ),
+                Ok(_) => {}
+                Err(err) => eprintln!("{err:?}"),
+            },
+            Err(err) => eprintln!("{err:?}"),
+        }
+    }
 
-            let when = block_time.map(|dt| dt.date()).or_else(|| {
-                println!(
-                    "Block time not available for slot {slot}, using `--when` argument instead"
-                );
-                when
-            });
+    println!("{cancelled_count} orders cancelled");
+    Ok(())
+}
 
-            let epoch_schdule = rpc_client.get_epoch_schedule()?;
-            let last_update_epoch = epoch_schdule
-                .get_epoch_and_slot_index(slot)
-                .0
-                .saturating_sub(1);
+// Times each evacuation step is attempted before it's reported as failed
+const EVACUATION_ATTEMPTS: usize = 3;
+const EVACUATION_RETRY_DELAY: Duration = Duration::from_secs(2);
 
-            (
-                when,
-                post_amount,
-                last_update_epoch,
-                LotAcquistionKind::Transaction { slot, signature },
-            )
+// Runs `step` until it succeeds or has been attempted `EVACUATION_ATTEMPTS` times
+async fn evacuation_step<T, F, Fut>(
+    description: &str,
+    mut step: F,
+) -> Result<T, Box<dyn std::error::Error>>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+{
+    let mut attempt = 1;
+    loop {
+        match step().await {
+            Err(err) if attempt < EVACUATION_ATTEMPTS => {
+                eprintln!("{description} failed ({attempt} of {EVACUATION_ATTEMPTS}): {err}");
+                tokio::time::sleep(EVACUATION_RETRY_DELAY).await;
+                attempt += 1;
+            }
+            result => return result,
         }
-        None => {
-            let amount = match ui_amount {
-                Some(ui_amount) => token.amount(ui_amount),
-                None => token.balance(rpc_client, &address)?,
-            };
+    }
+}
 
-            let last_update_epoch = rpc_client.get_epoch_info()?.epoch.saturating_sub(1);
-            (
-                when,
-                amount,
-                last_update_epoch,
-                if income {
-                    LotAcquistionKind::NotAvailable
-                } else {
-                    LotAcquistionKind::Fiat
-                },
-            )
+// Every order open on the exchange, including any placed outside of `sys`.  When the exchange can't
+// list them, the journaled ones are cancelled instead
+async fn evacuation_open_orders(
+    db: &Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+) -> Vec<ExchangeOpenOrder> {
+    if !exchange.capabilities().supports_trading {
+        return vec![];
+    }
+    match exchange_client.open_orders().await {
+        Ok(open_orders) => open_orders,
+        Err(err) => {
+            eprintln!(
+                "Unable to list {exchange:?} open orders, cancelling the journaled ones: {err}"
+            );
+            db.open_orders(Some(exchange), None)
+                .into_iter()
+                .filter_map(|order| {
+                    let amount = order.ui_amount.unwrap_or_else(|| {
+                        order
+                            .token
+                            .ui_amount(order.lots.iter().map(|lot| lot.amount).sum::<u64>())
+                    });
+                    Some(ExchangeOpenOrder {
+                        pair: TradingPair::from_exchange_symbol(exchange, &order.pair).ok()?,
+                        order_id: order.order_id,
+                        side: order.side,
+                        price: order.price,
+                        amount,
+                    })
+                })
+                .collect()
         }
-    };
+    }
+}
 
-    println!("Adding {address} (token: {token})");
+enum TokenEvacuation {
+    Withdrawn(String),
+    // Left behind for a reason another attempt won't change, such as a balance under the minimum
+    Skipped(String),
+}
 
-    let current_price = token.get_current_price(rpc_client).await?;
-    let decimal_price = match price {
-        Some(price) => Decimal::from_f64(price).unwrap(),
-        None => match when {
-            Some(when) => token.get_historical_price(rpc_client, when).await?,
-            None => current_price,
-        },
-    };
+// Withdraws the available balance of `token` to `to_address`.  The balance is read afresh on each
+// call, so retrying after a withdrawal that did go through finds nothing left to send
+#[allow(clippy::too_many_arguments)]
+async fn evacuate_token(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    to_address: Pubkey,
+    lot_selection_method: LotSelectionMethod,
+    withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+    rpc_client: &RpcClient,
+    override_limits: bool,
+    dry_run: bool,
+    notifier: &Notifier,
+) -> Result<TokenEvacuation, Box<dyn std::error::Error>> {
+    let available = token_balances(exchange, exchange_client)
+        .await?
+        .get(token)
+        .available;
+    let mut amount = truncate_native_amount(
+        token,
+        native_amount_floor(token, available),
+        exchange_client.withdrawal_decimals(token),
+    );
+    if amount == 0 {
+        return Ok(TokenEvacuation::Skipped("nothing available".into()));
+    }
+    if let Ok(token_support) = exchange_client.supports_token(token).await {
+        if let Err(err) = token_support.check_withdrawals(exchange, token) {
+            return Ok(TokenEvacuation::Skipped(err.to_string()));
+        }
+    }
 
-    let mut lots = vec![];
-    if amount > 0 {
-        let lot = Lot {
-            lot_number: db.next_lot_number(),
-            acquisition: LotAcquistion::new(when.unwrap_or_else(today), decimal_price, kind),
-            amount,
-        };
-        println_lot(
+    let deposit_address =
+        exchange_deposit_address(db, exchange, exchange_account, exchange_client, token).await?;
+    if !dry_run {
+        add_exchange_deposit_address_to_db(
+            db,
+            exchange,
+            exchange_account,
             token,
-            &lot,
-            Some(current_price),
-            None,
-            &mut 0.,
-            &mut 0.,
-            &mut false,
-            &mut 0.,
-            None,
-            true,
-        )
-        .await;
-
-        lots.push(lot);
+            deposit_address,
+            rpc_client,
+        )?;
+    }
+    // Only the lots `sys` tracks can be withdrawn, anything else remains on the exchange
+    let mut untracked = String::new();
+    if !token.fiat_fungible() {
+        let tracked = db
+            .get_account(deposit_address, token)
+            .map(|account| account.last_update_balance)
+            .unwrap_or_default();
+        if amount > tracked {
+            untracked = format!(
+                ", leaving {}{} without lots",
+                token.symbol(),
+                token.ui_amount(amount - tracked)
+            );
+            amount = tracked;
+        }
+    }
+    if let Some(limits) = exchange_client.withdrawal_limits(token).await? {
+        if token.ui_amount(amount) < limits.minimum || token.ui_amount(amount) <= limits.fee {
+            return Ok(TokenEvacuation::Skipped(format!(
+                "{}{} is below the {}{} withdrawal minimum or {}{} fee{untracked}",
+                token.symbol(),
+                token.ui_amount(amount),
+                token.symbol(),
+                limits.minimum,
+                token.symbol(),
+                limits.fee
+            )));
+        }
     }
 
-    let account = TrackedAccount {
-        address,
+    if dry_run {
+        return Ok(TokenEvacuation::Withdrawn(format!(
+            "would withdraw {}{} to {to_address}{untracked}",
+            token.symbol(),
+            token.ui_amount(amount)
+        )));
+    }
+    let tag = process_exchange_withdraw(
+        db,
+        exchange,
+        exchange_client,
         token,
-        description,
-        last_update_epoch,
-        last_update_balance: amount,
-        lots,
-        no_sync: Some(no_sync),
-    };
-    db.add_account(account)?;
-
-    Ok(())
+        deposit_address,
+        Some(amount),
+        to_address,
+        lot_selection_method,
+        None,
+        None,
+        withdrawal_code,
+        rpc_client,
+        override_limits,
+        None,
+        EVACUATION_STRATEGY,
+        notifier,
+    )
+    .await?;
+    Ok(TokenEvacuation::Withdrawn(format!(
+        "withdrew {}{} to {to_address}, tag {tag}{untracked}",
+        token.symbol(),
+        token.ui_amount(amount)
+    )))
 }
 
+/// Empties a possibly compromised exchange account: cancels every order open on it, including any
+/// placed outside of `sys`, redeems its lending and Earn positions and withdraws each allow-listed
+/// token to its evacuation address.  Cancellations and redemptions run concurrently, withdrawals
+/// one at a time as each is journaled.  Destinations come only from the allow-list, which bypasses
+/// withdrawal approval, and the evacuation is refused outright when the allow-list is empty.
+/// `dry_run` rehearses it without changing anything
 #[allow(clippy::too_many_arguments)]
-async fn process_account_dispose(
+async fn process_exchange_evacuate(
     db: &mut Db,
-    rpc_client: &RpcClient,
-    address: Pubkey,
-    token: MaybeToken,
-    ui_amount: f64,
-    description: String,
-    when: Option<NaiveDate>,
-    price: Option<f64>,
+    exchange: Exchange,
+    exchange_account: &str,
+    exchange_client: &dyn ExchangeClient,
     lot_selection_method: LotSelectionMethod,
-    lot_numbers: Option<HashSet<usize>>,
+    withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+    rpc_client: &RpcClient,
+    override_limits: bool,
+    dry_run: bool,
+    notifier: &Notifier,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let price = match price {
-        Some(price) => Decimal::from_f64(price).unwrap(),
-        None => match when {
-            Some(when) => token.get_historical_price(rpc_client, when).await?,
-            None => token.get_current_price(rpc_client).await?,
-        },
-    };
+    let evacuation_addresses = db.evacuation_addresses();
+    if evacuation_addresses.is_empty() {
+        return Err(ConfigurationError(
+            "No evacuation addresses are allow-listed, so nothing can be evacuated. Add one for \
+             each token with `sys evacuation-address add` before it's needed"
+                .into(),
+        )
+        .into());
+    }
 
-    let disposed_lots = db.record_disposal(
-        address,
-        token,
-        token.amount(ui_amount),
-        description,
-        when.unwrap_or_else(today),
-        price,
-        lot_selection_method,
-        lot_numbers,
-    )?;
-    if !disposed_lots.is_empty() {
-        println!("Disposed Lots:");
-        for disposed_lot in disposed_lots {
-            println!(
-                "{}",
-                format_disposed_lot(&disposed_lot, &mut 0., &mut 0., &mut false, &mut 0., true)
+    // The orders cancelled are the ones confirmed.  Withdrawal amounts are only known once orders
+    // are cancelled and lending redeemed, so each withdrawal is confirmed on its own
+    let open_orders = evacuation_open_orders(db, exchange, exchange_client).await;
+    if !dry_run {
+        let mut operation = open_orders.iter().fold(
+            IrreversibleOperation::new(format!("Evacuation of {exchange:?}")),
+            |operation, order| {
+                operation.detail(
+                    &order.order_id,
+                    format!(
+                        "{} {} {} at {}",
+                        order.pair, order.side, order.amount, order.price
+                    ),
+                )
+            },
+        );
+        if exchange.capabilities().supports_earn {
+            operation = operation.detail("Lending", "redeemed in full");
+        }
+        for evacuation_address in &evacuation_addresses {
+            operation = operation.detail(
+                format!("{} to", evacuation_address.token),
+                evacuation_address.address,
             );
         }
-        println!();
+        confirmation::confirm(&operation)?;
     }
-    Ok(())
-}
 
-#[derive(Default, Debug, PartialEq)]
-struct RealizedGain {
-    income: f64,
-    short_term_cap_gain: f64,
-    long_term_cap_gain: f64,
-}
+    let verb = if dry_run { "Rehearsing" } else { "Evacuating" };
+    let msg = format!("{verb} {exchange:?}");
+    println!("{msg}");
+    if !dry_run {
+        notifier.send(&msg).await;
+    }
+    let mut moved = vec![];
+    let mut not_moved = vec![];
+
+    println!("Cancelling {} open orders", open_orders.len());
+    if !dry_run && !open_orders.is_empty() {
+        let results = futures::future::join_all(open_orders.iter().map(|order| {
+            evacuation_step(&format!("Cancelling order {}", order.order_id), || {
+                exchange_client.cancel_order_final(&order.pair, &order.order_id)
+            })
+        }))
+        .await;
+        for (order, result) in open_orders.iter().zip(results) {
+            if let Err(err) = result {
+                not_moved.push(format!(
+                    "order {} ({}): not cancelled: {err}",
+                    order.order_id, order.pair
+                ));
+            }
+        }
+        // Closes the cancelled orders, returning the lots held by sell orders for withdrawal
+        if let Err(err) =
+            process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier).await
+        {
+            eprintln!("Unable to sync {exchange:?} after cancelling orders: {err}");
+        }
+    }
 
-#[derive(Default)]
-struct AnnualRealizedGain {
-    by_quarter: [RealizedGain; 4],
-    by_payment_period: [RealizedGain; 4],
-}
+    if exchange.capabilities().supports_earn {
+        match exchange_client.all_lending_info().await {
+            Ok(lending_info) => {
+                let offered = lending_info
+                    .into_iter()
+                    .filter(|(_, lending_info)| lending_info.offered > 0.)
+                    .collect::<Vec<_>>();
+                println!("Redeeming {} lending positions", offered.len());
+                if dry_run {
+                    for (coin, lending_info) in &offered {
+                        moved.push(format!(
+                            "{coin}: would redeem {} from lending",
+                            lending_info.offered
+                        ));
+                    }
+                } else {
+                    let results =
+                        futures::future::join_all(offered.iter().map(|(coin, _)| async move {
+                            evacuation_step(&format!("Redeeming {coin} lending"), || async move {
+                                // Read afresh, so a retry doesn't redeem twice
+                                match exchange_client.get_lending_info(coin).await? {
+                                    Some(lending_info) if lending_info.offered > 0. => {
+                                        exchange_client
+                                            .redeem_lending(coin, lending_info.offered)
+                                            .await
+                                            .map(|redemption| redemption.redeemed)
+                                    }
+                                    _ => Ok(0.),
+                                }
+                            })
+                            .await
+                        }))
+                        .await;
+                    for ((coin, lending_info), result) in offered.iter().zip(results) {
+                        let result = outcome::record(
+                            OperationKind::LendingRedeemed,
+                            format!("{exchange:?}: Redeem all {coin} from lending"),
+                            result,
+                            |_| coin.to_string(),
+                        );
+                        if let Ok(redeemed) = result {
+                            db.record_lending_offer(LendingOfferRecord {
+                                exchange,
+                                coin: coin.clone(),
+                                amount: (lending_info.offered - redeemed).max(0.),
+                                estimate_rate: lending_info.estimate_rate,
+                                time: Utc::now(),
+                            })?;
+                        }
+                        match result {
+                            Ok(redeemed) => moved.push(format!(
+                                "{coin}: redeemed {redeemed} from lending, run `evacuate` again \
+                                 to withdraw it once released"
+                            )),
+                            Err(err) => {
+                                not_moved.push(format!("{coin}: lending not redeemed: {err}"))
+                            }
+                        }
+                    }
+                }
+            }
+            Err(err) => not_moved.push(format!("lending: unable to list positions: {err}")),
+        }
+    }
 
-impl AnnualRealizedGain {
-    const MONTH_TO_PAYMENT_PERIOD: [usize; 12] = [0, 0, 0, 1, 1, 2, 2, 2, 3, 3, 3, 3];
+    for evacuation_address in &evacuation_addresses {
+        let token = evacuation_address.token;
+        let mut attempt = 1;
+        let result = loop {
+            match evacuate_token(
+                db,
+                exchange,
+                exchange_account,
+                exchange_client,
+                token,
+                evacuation_address.address,
+                lot_selection_method.clone(),
+                withdrawal_code,
+                rpc_client,
+                override_limits,
+                dry_run,
+                notifier,
+            )
+            .await
+            {
+                Err(err) if attempt < EVACUATION_ATTEMPTS => {
+                    eprintln!(
+                        "Withdrawing {token} failed ({attempt} of {EVACUATION_ATTEMPTS}): {err}"
+                    );
+                    tokio::time::sleep(EVACUATION_RETRY_DELAY).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+        match result {
+            Ok(TokenEvacuation::Withdrawn(detail)) => {
+                println!("{token}: {detail}");
+                moved.push(format!("{token}: {detail}"));
+            }
+            Ok(TokenEvacuation::Skipped(reason)) => not_moved.push(format!("{token}: {reason}")),
+            Err(err) => not_moved.push(format!("{token}: not withdrawn: {err}")),
+        }
+    }
 
-    fn record_income(&mut self, month: usize, income: f64) {
-        self.by_quarter[month / 3].income += income;
-        self.by_payment_period[Self::MONTH_TO_PAYMENT_PERIOD[month]].income += income;
+    println!();
+    println!(
+        "{} report for {exchange:?}",
+        if dry_run { "Rehearsal" } else { "Evacuation" }
+    );
+    for (heading, lines) in [("Moved", &moved), ("Not moved", &not_moved)] {
+        println!("{heading}:");
+        if lines.is_empty() {
+            println!("  nothing");
+        }
+        for line in lines {
+            println!("  {line}");
+        }
+    }
+    println!("Remaining:");
+    match exchange_client.balances().await {
+        Ok(balances) => {
+            let mut remaining = balances
+                .into_iter()
+                .filter(|(_, balance)| balance.total > 0.)
+                .collect::<Vec<_>>();
+            remaining.sort_by(|a, b| a.0.cmp(&b.0));
+            if remaining.is_empty() {
+                println!("  nothing");
+            }
+            for (coin, balance) in remaining {
+                println!(
+                    "  {coin:<8} {} ({} available)",
+                    format_coin_amount(exchange, &coin, balance.total, true),
+                    format_coin_amount(exchange, &coin, balance.available, true)
+                );
+            }
+        }
+        Err(err) => println!("  unable to fetch balances: {err}"),
     }
 
-    fn record_short_term_cap_gain(&mut self, month: usize, cap_gain: f64) {
-        self.by_quarter[month / 3].short_term_cap_gain += cap_gain;
-        self.by_payment_period[Self::MONTH_TO_PAYMENT_PERIOD[month]].short_term_cap_gain +=
-            cap_gain;
+    if !dry_run {
+        notifier
+            .send(&format!(
+                "{exchange:?} evacuation finished: {} moved, {} not moved",
+                moved.len(),
+                not_moved.len()
+            ))
+            .await;
     }
+    Ok(())
+}
 
-    fn record_long_term_cap_gain(&mut self, month: usize, cap_gain: f64) {
-        self.by_quarter[month / 3].long_term_cap_gain += cap_gain;
-        self.by_payment_period[Self::MONTH_TO_PAYMENT_PERIOD[month]].long_term_cap_gain += cap_gain;
+// An order the exchange reports differently from how the journal recorded it
+fn describe_order_conflict(journaled: &JournaledOrder, order: &HistoricalOrder) -> Option<String> {
+    let token = journaled.token;
+    if journaled.side != order.status.side {
+        return Some(format!(
+            "journal records a {}, exchange reports a {}",
+            journaled.side, order.status.side
+        ));
+    }
+    if journaled.open && !order.status.open {
+        // Closed by the exchange on its own, the next sync records it and sends an alert
+        return order
+            .status
+            .exchange_cancel_reason()
+            .map(|reason| format!("journal records it open, exchange cancelled it: {reason}"));
+    }
+    if journaled.open || order.status.open {
+        return None;
+    }
+    let filled_amount = token.amount(order.status.filled_amount);
+    if journaled.filled_amount != filled_amount {
+        return Some(format!(
+            "journal records {}{} filled, exchange reports {}{}",
+            token.symbol(),
+            token.ui_amount(journaled.filled_amount),
+            token.symbol(),
+            token.ui_amount(filled_amount),
+        ));
     }
+    None
 }
 
-async fn process_account_cost_basis(
-    db: &Db,
-    when: NaiveDate,
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_import_orders(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: TradingPair,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    lot_selection_method: LotSelectionMethod,
+    dry_run: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut held_tokens =
-        BTreeMap::<MaybeToken, Vec<(/*amount: */ u64, /*price: */ Decimal)>>::default();
+    let deposit_address =
+        verified_deposit_address(db, exchange, exchange_account, exchange_client, token).await?;
+    let journaled_orders = db.journaled_orders(exchange);
+    let orders = exchange_client.order_history(&pair, start, end).await?;
+
+    let mut journaled_count = 0;
+    let mut imported_count = 0;
+    let mut conflicts = vec![];
+    for order in orders {
+        let order_summary = format!(
+            "{pair}: {} {}{} at {} | id {} created {}",
+            format_order_side(order.status.side),
+            token.symbol(),
+            order.status.filled_amount,
+            format_quote_amount(&order.status.fill_price().to_string(), &pair.quote),
+            order.order_id,
+            order.creation_time.format("%Y-%m-%d %H:%M"),
+        );
 
-    println!("Average Cost Basis on {when}");
-    for disposed_lot in db.disposed_lots() {
-        if disposed_lot.lot.acquisition.when > when || disposed_lot.when < when {
+        if let Some(journaled) = journaled_orders.get(&order.order_id) {
+            match describe_order_conflict(journaled, &order) {
+                Some(conflict) => conflicts.push(format!("{order_summary}: {conflict}")),
+                None => journaled_count += 1,
+            }
             continue;
         }
-        held_tokens
-            .entry(disposed_lot.token)
-            .or_insert_with(Vec::new)
-            .push((
-                disposed_lot.lot.amount,
-                disposed_lot.lot.acquisition.price(),
-            ));
-    }
-
-    for account in db.get_accounts() {
-        let held_token = held_tokens.entry(account.token).or_insert_with(Vec::new);
-        for lot in account.lots {
-            if lot.acquisition.when <= when {
-                held_token.push((lot.amount, lot.acquisition.price()));
-            }
+        if order.status.open {
+            println!("  Skipped {order_summary}: still open, import it once it closes");
+            continue;
+        }
+        if order.status.filled_amount < f64::EPSILON {
+            continue;
         }
-    }
-
-    // Merge wSOL lots into SOL
-    if let Some(mut lots) = held_tokens.remove(&Token::wSOL.into()) {
-        held_tokens
-            .entry(MaybeToken::SOL())
-            .or_insert_with(Vec::new)
-            .append(&mut lots);
-    }
 
-    for (token, lots) in held_tokens {
-        if lots.is_empty() || token.fiat_fungible() {
+        if dry_run {
+            println!("Would import {order_summary}");
+            imported_count += 1;
             continue;
         }
 
-        let mut total_amount = 0;
-        let mut total_price = Decimal::default();
-
-        for (amount, price) in lots {
-            total_amount += amount;
-            total_price += Decimal::from_f64(token.ui_amount(amount)).unwrap() * price;
+        let when = Utc
+            .from_utc_date(&order.status.last_update)
+            .and_hms(0, 0, 0);
+        let usd_price = match fill_price_in_usd(
+            exchange_client,
+            &pair,
+            order.status.fill_price(),
+            when,
+        )
+        .await
+        {
+            Ok(usd_price) => usd_price,
+            Err(err) => {
+                eprintln!("Unable to import {order_summary}: {err}");
+                continue;
+            }
+        };
+        let usd_fee = match &order.status.fee {
+            Some(fee) if fee.0 > 0. => {
+                match fee_in_usd(exchange_client, &pair, order.status.fill_price(), fee, when).await
+                {
+                    Ok(usd_fee) => Some(usd_fee),
+                    Err(err) => {
+                        eprintln!("Unable to value {} fee in USD: {err}", order.order_id);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        };
+        match db.import_external_order(
+            exchange,
+            pair.to_exchange_symbol(exchange),
+            &order,
+            usd_price,
+            deposit_address,
+            token,
+            usd_fee,
+            lot_selection_method.clone(),
+        ) {
+            Ok(()) => {
+                db.record_exchange_journal(order_journal_entries(
+                    exchange,
+                    &pair,
+                    &order.order_id,
+                    &order.status,
+                    order.creation_time,
+                ))?;
+                println!("Imported {order_summary}");
+                imported_count += 1;
+            }
+            Err(err) => eprintln!("Unable to import {order_summary}: {err}"),
         }
+    }
+
+    println!(
+        "{} orders {}, {journaled_count} already in the journal",
+        imported_count,
+        if dry_run { "to import" } else { "imported" }
+    );
+    if !conflicts.is_empty() {
         println!(
-            "  {:>7}: {:<20} at ${} ; ${:.2} per {}",
-            token.to_string(),
-            token.format_amount(total_amount),
-            TryInto::<f64>::try_into(total_price)
-                .unwrap()
-                .separated_string_with_fixed_place(2),
-            total_price / Decimal::from_f64(token.ui_amount(total_amount)).unwrap(),
-            token.name()
+            "{} orders disagree with the journal and were left as is:",
+            conflicts.len()
         );
+        for conflict in conflicts {
+            println!("  {conflict}");
+        }
     }
     Ok(())
 }
 
-async fn process_account_list(
+// Exchanges lacking a required capability are skipped silently
+fn configured_exchange_clients(
     db: &Db,
-    rpc_client: &RpcClient,
-    account_filter: Option<Pubkey>,
-    show_all_disposed_lots: bool,
-    summary_only: bool,
-    notifier: &Notifier,
-    verbose: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut annual_realized_gains = BTreeMap::<usize, AnnualRealizedGain>::default();
-    let mut held_tokens = BTreeMap::<
-        MaybeToken,
-        (
-            /*price*/ Option<Decimal>,
-            /*amount*/ u64,
-            RealizedGain,
-        ),
-    >::default();
+    required: impl Fn(&ExchangeCapabilities) -> bool,
+) -> Result<Vec<(Exchange, Box<dyn ExchangeClient>)>, Box<dyn std::error::Error>> {
+    db.get_default_accounts_from_configured_exchanges()
+        .into_iter()
+        .filter(|(exchange, _, _)| {
+            if exchange.is_defunct() {
+                eprintln!("Skipping {exchange:?}: defunct, historical records only");
+                return false;
+            }
+            required(&exchange.capabilities())
+        })
+        .map(|(exchange, exchange_credentials, _exchange_account)| {
+            exchange_client_new(exchange, exchange_credentials)
+                .map(|exchange_client| (exchange, exchange_client))
+        })
+        .collect()
+}
 
-    // hacky: display a unified rate if the long and short term rate is equal
-    let unified_tax_rate = db
-        .get_tax_rate()
-        .map(|tax_rate| tax_rate.short_term_gain - tax_rate.long_term_gain <= f64::EPSILON)
-        .unwrap_or(false);
+// An exchange that failed its part of a command aggregating several, reported alongside the
+// others' results rather than failing the command
+#[derive(Debug, Clone, Serialize)]
+struct ExchangeFailure {
+    exchange: Exchange,
+    account: String,
+    category: request_metrics::ErrorCategory,
+    error: String,
+    // Time of the balance snapshot substituted for the exchange's live balances
+    cached_from: Option<DateTime<Utc>>,
+}
 
-    let mut accounts = db.get_accounts();
-    accounts.sort_by(|a, b| {
-        let mut result = a.last_update_balance.cmp(&b.last_update_balance);
-        if result == std::cmp::Ordering::Equal {
-            result = a.address.cmp(&b.address);
+impl ExchangeFailure {
+    fn location(&self) -> String {
+        if self.account.is_empty() {
+            format!("{:?}", self.exchange)
+        } else {
+            format!("{:?} ({})", self.exchange, self.account)
         }
-        if result == std::cmp::Ordering::Equal {
-            result = a.description.cmp(&b.description);
+    }
+}
+
+impl std::fmt::Display for ExchangeFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: unavailable, {} error: {}",
+            self.location(),
+            self.category.name(),
+            self.error
+        )?;
+        match self.cached_from {
+            Some(cached_from) => write!(
+                f,
+                " (using the balance snapshot from {})",
+                HumanTime::from(cached_from)
+            ),
+            None => write!(f, " (left out)"),
         }
-        result
-    });
-    if accounts.is_empty() {
-        println!("No accounts");
+    }
+}
+
+// Records one exchange's part of a command aggregating several, so that the exit code tells a
+// partial failure from a total one
+fn record_exchange_query<T>(
+    exchange: Exchange,
+    account: &str,
+    result: Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, ExchangeFailure> {
+    let location = if account.is_empty() {
+        format!("{exchange:?}")
     } else {
-        let mut total_income = 0.;
-        let mut total_unrealized_short_term_gain = 0.;
-        let mut total_unrealized_long_term_gain = 0.;
-        let mut total_current_value = 0.;
+        format!("{exchange:?} ({account})")
+    };
+    outcome::record(
+        OperationKind::ExchangeQueried,
+        location.clone(),
+        result,
+        |_| location,
+    )
+    .map_err(|err| ExchangeFailure {
+        exchange,
+        account: account.to_string(),
+        category: request_metrics::ErrorCategory::of(err.as_ref()),
+        error: err.to_string(),
+        cached_from: None,
+    })
+}
 
-        let open_orders = db.open_orders(None, None);
+#[derive(Serialize)]
+struct ExchangePriceComparison {
+    exchange: Exchange,
+    pair: String,
+    bid: f64,
+    ask: f64,
+    quote_usd_rate: QuoteUsdRate,
+    execution_price: f64,
+    net_usd: f64,
+    /// Only with `--tiers`, for exchanges that report volume tiers
+    volume_tier: Option<VolumeTierInfo>,
+    /// The trade's volume alone would move the account to the next fee tier
+    reaches_next_tier: bool,
+    /// Announced delisting of the market
+    delist_time: Option<DateTime<Utc>>,
+}
 
-        for account in accounts {
-            if let Some(ref account_filter) = account_filter {
-                if account.address != *account_filter {
-                    continue;
-                }
-            }
+async fn compare_exchange_price(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    amount: f64,
+    side: OrderSide,
+    withdrawal_fee: f64,
+    tiers: bool,
+) -> Result<ExchangePriceComparison, Box<dyn std::error::Error>> {
+    let pair = exchange_client.preferred_usd_pair(token).await?;
+    let bid_ask = exchange_client.bid_ask(&pair).await?;
 
-            if let std::collections::btree_map::Entry::Vacant(e) = held_tokens.entry(account.token)
-            {
-                e.insert((
-                    account.token.get_current_price(rpc_client).await.ok(),
-                    0,
-                    RealizedGain::default(),
-                ));
+    let quote_usd_rate = quote_usd_rate(exchange_client, &pair.quote).await?;
+
+    let taker_fee_rate = exchange_client.taker_fee_rate();
+    let (execution_price, net_usd) = match side {
+        OrderSide::Sell => {
+            let execution_price = bid_ask.bid_price * quote_usd_rate.rate * (1. - taker_fee_rate);
+            (execution_price, execution_price * amount - withdrawal_fee)
+        }
+        OrderSide::Buy => {
+            let execution_price = bid_ask.ask_price * quote_usd_rate.rate * (1. + taker_fee_rate);
+            (execution_price, execution_price * amount + withdrawal_fee)
+        }
+    };
+
+    let volume_tier = if tiers && exchange.capabilities().supports_volume_tiers {
+        match exchange_client.volume_tier_info().await {
+            Ok(volume_tier) => Some(volume_tier),
+            Err(err) => {
+                eprintln!("{exchange:?}: unable to fetch volume tier: {err}");
+                None
             }
+        }
+    } else {
+        None
+    };
+    let reaches_next_tier = volume_tier
+        .as_ref()
+        .and_then(|volume_tier| volume_tier.volume_to_next_tier())
+        .map_or(false, |volume_to_next_tier| {
+            execution_price * amount >= volume_to_next_tier
+        });
+    let delist_time = exchange_client
+        .pair_info(&pair)
+        .await
+        .ok()
+        .and_then(|pair_info| pair_info.upcoming_delisting());
 
-            let held_token = held_tokens.get_mut(&account.token).unwrap();
-            let current_token_price = held_token.0;
-            held_token.1 += account.last_update_balance;
+    Ok(ExchangePriceComparison {
+        exchange,
+        pair: pair.to_exchange_symbol(exchange),
+        bid: bid_ask.bid_price,
+        ask: bid_ask.ask_price,
+        quote_usd_rate,
+        execution_price,
+        net_usd,
+        volume_tier,
+        reaches_next_tier,
+        delist_time,
+    })
+}
 
-            let ui_amount = account.token.ui_amount(account.last_update_balance);
+async fn process_exchange_compare(
+    db: &Db,
+    token: MaybeToken,
+    amount: f64,
+    side: OrderSide,
+    withdrawal_fee: f64,
+    tiers: bool,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exchange_clients =
+        configured_exchange_clients(db, |capabilities| capabilities.supports_trading)?;
+
+    let results =
+        futures::future::join_all(exchange_clients.iter().map(|(exchange, exchange_client)| {
+            compare_exchange_price(
+                *exchange,
+                exchange_client.as_ref(),
+                token,
+                amount,
+                side,
+                withdrawal_fee,
+                tiers,
+            )
+        }))
+        .await;
 
-            let liquidity_token_info =
-                if let Some(liquidity_token) = account.token.liquidity_token() {
-                    if let Ok(current_liquidity_token_rate) = account
-                        .token
-                        .get_current_liquidity_token_rate(rpc_client)
-                        .await
-                    {
-                        Some(LiquidityTokenInfo {
-                            liquidity_token,
-                            current_liquidity_token_rate,
-                            current_apr: tulip::get_current_lending_apr(rpc_client, &account.token)
-                                .await
-                                .ok(),
-                        })
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                };
+    let mut comparisons = vec![];
+    let mut failures = vec![];
+    for ((exchange, _), result) in exchange_clients.iter().zip(results) {
+        match record_exchange_query(*exchange, "", result) {
+            Ok(comparison) => comparisons.push(comparison),
+            Err(failure) => failures.push(failure),
+        }
+    }
+    comparisons.sort_by(|a, b| match side {
+        OrderSide::Sell => b.net_usd.total_cmp(&a.net_usd),
+        OrderSide::Buy => a.net_usd.total_cmp(&b.net_usd),
+    });
 
-            let (liquidity_ui_amount, _) =
-                liquidity_token_ui_amount(None, ui_amount, liquidity_token_info.as_ref(), true);
-            let msg = format!(
-                "{} ({}): {}{}{} - {}",
-                account.address,
-                account.token,
-                account.token.symbol(),
-                ui_amount.separated_string_with_fixed_place(2),
-                liquidity_ui_amount,
-                account.description
-            );
-            println!("{msg}");
-            if ui_amount > 0.01 {
-                notifier.send(&msg).await;
-            }
-            account.assert_lot_balance();
+    if json {
+        // The output stays a list of comparisons, failures are in the `--summary`
+        for failure in &failures {
+            eprintln!("{failure}");
+        }
+        println!("{}", serde_json::to_string_pretty(&comparisons)?);
+        return Ok(());
+    }
 
-            if summary_only {
-                continue;
+    println!(
+        "{:<10} {:<10} {:>12} {:>12} {:>12} {:>14}",
+        "Exchange",
+        "Pair",
+        "Bid",
+        "Ask",
+        "Execution",
+        match side {
+            OrderSide::Sell => "Net Proceeds",
+            OrderSide::Buy => "Net Cost",
+        }
+    );
+    for comparison in &comparisons {
+        println!(
+            "{:<10} {:<10} {:>12} {:>12} {:>12} {:>14}{}",
+            format!("{:?}", comparison.exchange),
+            comparison.pair,
+            comparison.bid,
+            comparison.ask,
+            format!("${:.4}", comparison.execution_price),
+            format!("${:.2}", comparison.net_usd),
+            if comparison.quote_usd_rate.assumed {
+                " (quote currency assumed to be at USD parity)"
+            } else {
+                ""
+            }
+        );
+        if let Some(next_tier) = comparison
+            .volume_tier
+            .as_ref()
+            .and_then(|volume_tier| volume_tier.next_tier.as_ref())
+        {
+            if comparison.reaches_next_tier {
+                println!(
+                    "{:<10} reaches the next fee tier: maker {:.3}%, taker {:.3}%",
+                    "",
+                    next_tier.maker_rate * 100.,
+                    next_tier.taker_rate * 100.
+                );
             }
+        }
+        if let Some(delist_time) = comparison.delist_time {
+            println!(
+                "{:<10} {}",
+                "",
+                style(format!(
+                    "Warning: {}",
+                    format_delisting(comparison.exchange, &comparison.pair, delist_time)
+                ))
+                .yellow()
+            );
+        }
+    }
+    for failure in &failures {
+        println!("{failure}");
+    }
+    Ok(())
+}
 
-            let open_orders = open_orders
-                .iter()
-                .filter(|oo| oo.deposit_address == account.address && oo.token == account.token)
-                .collect::<Vec<_>>();
+#[derive(Serialize)]
+struct ProceedsEstimate {
+    exchange: Exchange,
+    pair: String,
+    amount: f64,
+    /// Time of the order book snapshot the fill was estimated from
+    book_time: DateTime<Utc>,
+    book_age_ms: i64,
+    quote_usd_rate: QuoteUsdRate,
+    average_usd_price: f64,
+    gross_usd: f64,
+    fee_rate: f64,
+    maker: bool,
+    trading_fee_usd: f64,
+    withdrawal_token: String,
+    /// `None` when the exchange does not publish its withdrawal fee
+    withdrawal_fee_usd: Option<f64>,
+    withdrawal_minimum_usd: Option<f64>,
+    net_usd: f64,
+}
 
-            if !account.lots.is_empty() || !open_orders.is_empty() {
-                let mut lots = account.lots.iter().collect::<Vec<_>>();
-                lots.sort_by_key(|lot| lot.acquisition.when);
+impl ProceedsEstimate {
+    fn below_withdrawal_minimum(&self) -> bool {
+        self.withdrawal_minimum_usd.map_or(false, |minimum| {
+            self.gross_usd - self.trading_fee_usd < minimum
+        })
+    }
+}
 
-                let mut account_income = 0.;
-                let mut account_current_value = 0.;
-                let mut account_unrealized_short_term_gain = 0.;
-                let mut account_unrealized_long_term_gain = 0.;
+#[allow(clippy::too_many_arguments)]
+async fn estimate_exchange_proceeds(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    amount: f64,
+    withdrawal_token: MaybeToken,
+    maker: bool,
+    depth: usize,
+    max_age: chrono::Duration,
+) -> Result<ProceedsEstimate, Box<dyn std::error::Error>> {
+    // Sell straight into the withdrawal token when there's a market for it
+    let withdrawal_pair = TradingPair::from_token(token, &withdrawal_token.to_string());
+    let pair = if exchange_client
+        .trading_pairs()
+        .await?
+        .contains(&withdrawal_pair)
+    {
+        withdrawal_pair
+    } else {
+        exchange_client.preferred_usd_pair(token).await?
+    };
 
-                for lot in lots {
-                    let mut account_unrealized_gain = 0.;
-                    let mut long_term_cap_gain = false;
-                    println_lot(
-                        account.token,
-                        lot,
-                        current_token_price,
-                        liquidity_token_info.as_ref(),
-                        &mut account_income,
-                        &mut account_unrealized_gain,
-                        &mut long_term_cap_gain,
-                        &mut account_current_value,
-                        None,
-                        verbose,
-                    )
-                    .await;
+    let order_book = exchange_client.order_book(&pair, depth).await?;
+    let book_age = Utc::now().signed_duration_since(order_book.time);
+    if book_age > max_age {
+        return Err(format!(
+            "{pair} order book snapshot is {}ms old, refusing to estimate from data older than {}ms",
+            book_age.num_milliseconds(),
+            max_age.num_milliseconds()
+        )
+        .into());
+    }
 
-                    annual_realized_gains
-                        .entry(lot.acquisition.when.year() as usize)
-                        .or_default()
-                        .record_income(
-                            lot.acquisition.when.month0() as usize,
-                            lot.income(account.token),
-                        );
-
-                    if long_term_cap_gain {
-                        account_unrealized_long_term_gain += account_unrealized_gain;
-                    } else {
-                        account_unrealized_short_term_gain += account_unrealized_gain;
-                    }
-                }
-
-                for open_order in open_orders {
-                    let mut lots = open_order.lots.iter().collect::<Vec<_>>();
-                    lots.sort_by_key(|lot| lot.acquisition.when);
-                    let ui_amount = open_order.ui_amount.unwrap_or_else(|| {
-                        account
-                            .token
-                            .ui_amount(lots.iter().map(|lot| lot.amount).sum::<u64>())
-                    });
-                    println!(
-                        " [Open {}: {} {} at ${} | id {} created {}]",
-                        open_order.pair,
-                        format_order_side(open_order.side),
-                        account.token.format_ui_amount(ui_amount),
-                        open_order.price,
-                        open_order.order_id,
-                        HumanTime::from(open_order.creation_time),
-                    );
-                    for lot in lots {
-                        let mut account_unrealized_gain = 0.;
-                        let mut long_term_cap_gain = false;
-                        println_lot(
-                            account.token,
-                            lot,
-                            current_token_price,
-                            liquidity_token_info.as_ref(),
-                            &mut account_income,
-                            &mut account_unrealized_gain,
-                            &mut long_term_cap_gain,
-                            &mut account_current_value,
-                            None,
-                            verbose,
-                        )
-                        .await;
+    let quote_usd_rate = quote_usd_rate(exchange_client, &pair.quote).await?;
+    let bids = consolidated_levels(exchange, &pair, quote_usd_rate.rate, order_book.bids)
+        .collect::<Vec<_>>();
+    let execution = consolidated_execution(&bids, OrderSide::Sell, amount);
+    if execution.filled_amount < amount {
+        return Err(format!(
+            "{pair} order book only fills {} of {amount} {token} within {depth} levels",
+            execution.filled_amount
+        )
+        .into());
+    }
+    let gross_usd = execution.average_usd_price * amount;
+
+    let fee_rate = match exchange_client.fee_schedule().await {
+        Ok(fee_schedule) if maker => fee_schedule.maker_rate,
+        Ok(fee_schedule) => fee_schedule.taker_rate,
+        Err(err) => {
+            eprintln!("{exchange:?}: using the standard fee rate, fee schedule unavailable: {err}");
+            if maker {
+                exchange_client.maker_fee_rate()
+            } else {
+                exchange_client.taker_fee_rate()
+            }
+        }
+    };
+    let trading_fee_usd = gross_usd * fee_rate;
 
-                        annual_realized_gains
-                            .entry(lot.acquisition.when.year() as usize)
-                            .or_default()
-                            .record_income(
-                                lot.acquisition.when.month0() as usize,
-                                lot.income(account.token),
-                            );
+    let (withdrawal_fee_usd, withdrawal_minimum_usd) =
+        match exchange_client.withdrawal_limits(withdrawal_token).await? {
+            Some(limits) => {
+                let withdrawal_token_usd_rate =
+                    quote_usd_rate(exchange_client, &withdrawal_token.to_string())
+                        .await?
+                        .rate;
+                (
+                    Some(limits.fee * withdrawal_token_usd_rate),
+                    Some(limits.minimum * withdrawal_token_usd_rate),
+                )
+            }
+            None => (None, None),
+        };
 
-                        if long_term_cap_gain {
-                            account_unrealized_long_term_gain += account_unrealized_gain;
-                        } else {
-                            account_unrealized_short_term_gain += account_unrealized_gain;
-                        }
-                    }
-                }
+    Ok(ProceedsEstimate {
+        exchange,
+        pair: pair.to_exchange_symbol(exchange),
+        amount,
+        book_time: order_book.time,
+        book_age_ms: book_age.num_milliseconds(),
+        quote_usd_rate,
+        average_usd_price: execution.average_usd_price,
+        gross_usd,
+        fee_rate,
+        maker,
+        trading_fee_usd,
+        withdrawal_token: withdrawal_token.to_string(),
+        withdrawal_fee_usd,
+        withdrawal_minimum_usd,
+        net_usd: gross_usd - trading_fee_usd - withdrawal_fee_usd.unwrap_or_default(),
+    })
+}
 
-                println!(
-                    "    Value: ${}{}",
-                    account_current_value.separated_string_with_fixed_place(2),
-                    if account.token.fiat_fungible() {
-                        "".into()
-                    } else {
-                        format!(
-                            ", income: ${}, {}",
-                            account_income.separated_string_with_fixed_place(2),
-                            if unified_tax_rate {
-                                format!(
-                                    "unrealized cap gain: ${}",
-                                    (account_unrealized_short_term_gain
-                                        + account_unrealized_long_term_gain)
-                                        .separated_string_with_fixed_place(2)
-                                )
-                            } else {
-                                format!("unrealized short-term cap gain: ${}, unrealized long-term cap gain: ${}",
-                                    account_unrealized_short_term_gain.separated_string_with_fixed_place(2),
-                                    account_unrealized_long_term_gain.separated_string_with_fixed_place(2)
-                                )
-                            }
-                        )
-                    }
-                );
+fn print_proceeds_estimate(estimate: &ProceedsEstimate, token: MaybeToken) {
+    println!(
+        "{:?} {}, order book at {} ({}ms old)",
+        estimate.exchange,
+        estimate.pair,
+        estimate.book_time.to_rfc3339(),
+        estimate.book_age_ms
+    );
+    println!(
+        "  Gross:           {:>14}  ({} {token} at ${:.4} average{})",
+        format!(
+            "${}",
+            estimate.gross_usd.separated_string_with_fixed_place(2)
+        ),
+        estimate.amount,
+        estimate.average_usd_price,
+        if estimate.quote_usd_rate.assumed {
+            ", quote currency assumed to be at USD parity"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "  Trading fee:     {:>14}  ({} {:.3}%)",
+        format!(
+            "-${}",
+            estimate
+                .trading_fee_usd
+                .separated_string_with_fixed_place(2)
+        ),
+        if estimate.maker { "maker" } else { "taker" },
+        estimate.fee_rate * 100.
+    );
+    match estimate.withdrawal_fee_usd {
+        Some(withdrawal_fee_usd) => println!(
+            "  Withdrawal fee:  {:>14}  ({})",
+            format!(
+                "-${}",
+                withdrawal_fee_usd.separated_string_with_fixed_place(2)
+            ),
+            estimate.withdrawal_token
+        ),
+        None => println!(
+            "  Withdrawal fee:  {:>14}  ({:?} does not publish its {} withdrawal fee)",
+            "unknown", estimate.exchange, estimate.withdrawal_token
+        ),
+    }
+    println!(
+        "  Net:             {:>14}",
+        format!("${}", estimate.net_usd.separated_string_with_fixed_place(2))
+    );
+    if estimate.below_withdrawal_minimum() {
+        println!(
+            "  Proceeds are below the ${} {} withdrawal minimum",
+            estimate
+                .withdrawal_minimum_usd
+                .unwrap_or_default()
+                .separated_string_with_fixed_place(2),
+            estimate.withdrawal_token
+        );
+    }
+}
 
-                total_unrealized_short_term_gain += account_unrealized_short_term_gain;
-                total_unrealized_long_term_gain += account_unrealized_long_term_gain;
-                total_income += account_income;
-                total_current_value += account_current_value;
+/// Estimates what lands in the wallet from selling `amount` of `token` on each exchange and
+/// withdrawing the proceeds as `withdrawal_token`, best first
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_proceeds(
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    token: MaybeToken,
+    amount: f64,
+    withdrawal_token: MaybeToken,
+    maker: bool,
+    depth: usize,
+    max_age: chrono::Duration,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let results =
+        futures::future::join_all(exchange_clients.iter().map(|(exchange, exchange_client)| {
+            estimate_exchange_proceeds(
+                *exchange,
+                exchange_client.as_ref(),
+                token,
+                amount,
+                withdrawal_token,
+                maker,
+                depth,
+                max_age,
+            )
+        }))
+        .await;
 
-                held_token.2.short_term_cap_gain += account_unrealized_short_term_gain;
-                held_token.2.long_term_cap_gain += account_unrealized_long_term_gain;
-            } else {
-                println!("  No lots");
-            }
-            println!();
+    let mut estimates = vec![];
+    let mut errors = vec![];
+    for ((exchange, _), result) in exchange_clients.iter().zip(results) {
+        match result {
+            Ok(estimate) => estimates.push(estimate),
+            Err(err) => errors.push(format!("{exchange:?}: {err}")),
         }
+    }
+    estimates.sort_by(|a, b| b.net_usd.total_cmp(&a.net_usd));
 
-        if account_filter.is_some() || summary_only {
-            return Ok(());
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "estimates": estimates,
+                "errors": errors,
+            }))?
+        );
+    } else {
+        for estimate in &estimates {
+            print_proceeds_estimate(estimate, token);
+        }
+        for error in &errors {
+            eprintln!("{error}");
         }
+    }
 
-        let mut disposed_lots = db.disposed_lots();
-        disposed_lots.sort_by_key(|lot| lot.when);
-        if !disposed_lots.is_empty() {
-            println!("Disposed ({} lots):", disposed_lots.len());
+    if estimates.is_empty() {
+        return Err("No proceeds estimate available".into());
+    }
+    Ok(())
+}
 
-            let mut disposed_income = 0.;
-            let mut disposed_short_term_cap_gain = 0.;
-            let mut disposed_long_term_cap_gain = 0.;
-            let mut disposed_value = 0.;
+async fn process_proceeds_matches(
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    arg_matches: &ArgMatches<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+    let withdrawal_token = MaybeToken::from(value_t!(arg_matches, "withdrawal_token", Token).ok());
+    let amount = value_t_or_exit!(arg_matches, "amount", f64);
+    let depth = value_t_or_exit!(arg_matches, "depth", usize);
+    let max_age = chrono::Duration::milliseconds(
+        (value_t_or_exit!(arg_matches, "max_age", f64) * 1000.) as i64,
+    );
 
-            for (i, disposed_lot) in disposed_lots.iter().enumerate() {
-                let mut long_term_cap_gain = false;
-                let mut disposed_cap_gain = 0.;
-                let msg = format_disposed_lot(
-                    disposed_lot,
-                    &mut disposed_income,
-                    &mut disposed_cap_gain,
-                    &mut long_term_cap_gain,
-                    &mut disposed_value,
-                    verbose,
-                );
+    process_exchange_proceeds(
+        exchange_clients,
+        token,
+        amount,
+        withdrawal_token,
+        arg_matches.is_present("maker"),
+        depth,
+        max_age,
+        arg_matches.is_present("json"),
+    )
+    .await
+}
 
-                if show_all_disposed_lots {
-                    println!("{msg}");
-                } else {
-                    if disposed_lots.len() > 5 && i == disposed_lots.len().saturating_sub(5) {
-                        println!("...");
-                    }
-                    if i > disposed_lots.len().saturating_sub(5) {
-                        println!("{msg}");
-                    }
-                }
+#[derive(Serialize)]
+struct ConsolidatedLevel {
+    exchange: Exchange,
+    pair: String,
+    usd_price: f64,
+    amount: f64,
+}
 
-                annual_realized_gains
-                    .entry(disposed_lot.lot.acquisition.when.year() as usize)
-                    .or_default()
-                    .record_income(
-                        disposed_lot.lot.acquisition.when.month0() as usize,
-                        disposed_lot.lot.income(disposed_lot.token),
-                    );
+#[derive(Serialize)]
+struct ExecutionSlice {
+    exchange: Exchange,
+    pair: String,
+    amount: f64,
+    average_usd_price: f64,
+}
 
-                let annual_realized_gain = annual_realized_gains
-                    .entry(disposed_lot.when.year() as usize)
-                    .or_default();
+#[derive(Serialize)]
+struct ConsolidatedExecution {
+    side: OrderSide,
+    amount: f64,
+    filled_amount: f64,
+    average_usd_price: f64,
+    worst_usd_price: f64,
+    slices: Vec<ExecutionSlice>,
+}
 
-                if long_term_cap_gain {
-                    disposed_long_term_cap_gain += disposed_cap_gain;
-                    annual_realized_gain.record_long_term_cap_gain(
-                        disposed_lot.when.month0() as usize,
-                        disposed_cap_gain,
-                    );
-                } else {
-                    disposed_short_term_cap_gain += disposed_cap_gain;
-                    annual_realized_gain.record_short_term_cap_gain(
-                        disposed_lot.when.month0() as usize,
-                        disposed_cap_gain,
+// Takes liquidity from `levels`, best price first, until `amount` is filled or the levels run out
+fn consolidated_execution(
+    levels: &[ConsolidatedLevel],
+    side: OrderSide,
+    amount: f64,
+) -> ConsolidatedExecution {
+    let mut slices: Vec<ExecutionSlice> = vec![];
+    let mut filled_amount = 0.;
+    let mut usd_total = 0.;
+    let mut worst_usd_price = 0.;
+
+    for level in levels {
+        if filled_amount >= amount {
+            break;
+        }
+        let slice_amount = level.amount.min(amount - filled_amount);
+        filled_amount += slice_amount;
+        usd_total += slice_amount * level.usd_price;
+        worst_usd_price = level.usd_price;
+
+        match slices
+            .iter_mut()
+            .find(|slice| slice.exchange == level.exchange && slice.pair == level.pair)
+        {
+            Some(slice) => {
+                slice.average_usd_price = (slice.average_usd_price * slice.amount
+                    + level.usd_price * slice_amount)
+                    / (slice.amount + slice_amount);
+                slice.amount += slice_amount;
+            }
+            None => slices.push(ExecutionSlice {
+                exchange: level.exchange,
+                pair: level.pair.clone(),
+                amount: slice_amount,
+                average_usd_price: level.usd_price,
+            }),
+        }
+    }
+
+    ConsolidatedExecution {
+        side,
+        amount,
+        filled_amount,
+        average_usd_price: if filled_amount > 0. {
+            usd_total / filled_amount
+        } else {
+            0.
+        },
+        worst_usd_price,
+        slices,
+    }
+}
+
+// Order books for every USD-quoted market of `token` on the exchange, with the USD value of each
+// market's quote currency
+async fn usd_order_books(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    depth: usize,
+) -> Result<Vec<(TradingPair, f64, OrderBook)>, Box<dyn std::error::Error>> {
+    let trading_pairs = exchange_client.trading_pairs().await?;
+    let pairs = usd_preference_order()
+        .iter()
+        .map(|quote| TradingPair::from_token(token, quote))
+        .filter(|pair| trading_pairs.contains(pair))
+        .collect::<Vec<_>>();
+
+    let results = futures::future::join_all(pairs.iter().map(|pair| async move {
+        let order_book = exchange_client.order_book(pair, depth).await?;
+        let quote_usd_rate = quote_usd_rate(exchange_client, &pair.quote).await?;
+        Ok::<_, Box<dyn std::error::Error>>((order_book, quote_usd_rate))
+    }))
+    .await;
+
+    let mut order_books = vec![];
+    for (pair, result) in pairs.into_iter().zip(results) {
+        match result {
+            Ok((order_book, quote_usd_rate)) => {
+                if quote_usd_rate.assumed {
+                    eprintln!(
+                        "{exchange:?}: {} assumed to be at USD parity for {pair}",
+                        pair.quote
                     );
                 }
+                order_books.push((pair, quote_usd_rate.rate, order_book));
             }
-            println!(
-                "    Disposed value: ${} (income: ${}, {})",
-                disposed_value.separated_string_with_fixed_place(2),
-                disposed_income.separated_string_with_fixed_place(2),
-                if unified_tax_rate {
-                    format!(
-                        "cap gain: ${}",
-                        (disposed_short_term_cap_gain + disposed_long_term_cap_gain)
-                            .separated_string_with_fixed_place(2)
-                    )
-                } else {
-                    format!(
-                        "short-term cap gain: ${}, long-term cap gain: ${}",
-                        disposed_short_term_cap_gain.separated_string_with_fixed_place(2),
-                        disposed_long_term_cap_gain.separated_string_with_fixed_place(2)
-                    )
-                }
-            );
-            println!();
+            Err(err) => eprintln!("{exchange:?} {pair}: {err}"),
         }
+    }
+    Ok(order_books)
+}
 
-        if let Some(sweep_stake_account) = db.get_sweep_stake_account() {
-            println!("Sweep stake account: {}", sweep_stake_account.address);
-            println!(
-                "Stake authority: {}",
-                sweep_stake_account.stake_authority.display()
-            );
-            println!();
-        }
+fn consolidated_levels(
+    exchange: Exchange,
+    pair: &TradingPair,
+    quote_usd_rate: f64,
+    levels: Vec<OrderBookLevel>,
+) -> impl Iterator<Item = ConsolidatedLevel> + '_ {
+    levels.into_iter().map(move |level| ConsolidatedLevel {
+        exchange,
+        pair: pair.to_string(),
+        usd_price: level.price * quote_usd_rate,
+        amount: level.amount,
+    })
+}
 
-        let tax_rate = db.get_tax_rate();
-        println!("Realized Gains");
-        if unified_tax_rate {
-            println!("  Year    | Income          |       Cap gain | Estimated Tax ");
-        } else {
-            println!(
-                "  Year    | Income          | Short-term gain | Long-term gain | Estimated Tax "
-            );
-        }
-        for (year, annual_realized_gain) in annual_realized_gains {
-            let (symbol, realized_gains) = {
-                ('P', annual_realized_gain.by_payment_period)
-                // TODO: Add user option to restore `by_quarter` display
-                //('Q', annual_realized_gains.by_quarter)
-            };
-            for (q, realized_gain) in realized_gains.iter().enumerate() {
-                if *realized_gain != RealizedGain::default() {
-                    let tax = if let Some(tax_rate) = tax_rate {
-                        let tax = [
-                            realized_gain.income * tax_rate.income,
-                            realized_gain.short_term_cap_gain * tax_rate.short_term_gain
-                                + realized_gain.long_term_cap_gain * tax_rate.long_term_gain,
-                        ]
-                        .into_iter()
-                        .map(|x| x.max(0.))
-                        .sum::<f64>();
+async fn process_exchange_book(
+    db: &Db,
+    token: MaybeToken,
+    amount: Option<f64>,
+    side: OrderSide,
+    depth: usize,
+    max_age: chrono::Duration,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exchange_clients = configured_exchange_clients(db, |_| true)?;
 
-                        if tax > 0. {
-                            format!("${}", tax.separated_string_with_fixed_place(2))
-                        } else {
-                            String::new()
-                        }
-                    } else {
-                        "-".into()
-                    };
+    let results =
+        futures::future::join_all(exchange_clients.iter().map(|(exchange, exchange_client)| {
+            usd_order_books(*exchange, exchange_client.as_ref(), token, depth)
+        }))
+        .await;
 
-                    println!(
-                        "  {} {}{} | ${:14} | {}| {}",
-                        year,
-                        symbol,
-                        q + 1,
-                        realized_gain.income.separated_string_with_fixed_place(2),
-                        if unified_tax_rate {
-                            format!(
-                                "${:14}",
-                                (realized_gain.short_term_cap_gain
-                                    + realized_gain.long_term_cap_gain)
-                                    .separated_string_with_fixed_place(2)
-                            )
-                        } else {
-                            format!(
-                                "${:14} | ${:14}",
-                                realized_gain
-                                    .short_term_cap_gain
-                                    .separated_string_with_fixed_place(2),
-                                realized_gain
-                                    .long_term_cap_gain
-                                    .separated_string_with_fixed_place(2)
-                            )
-                        },
-                        tax
-                    );
+    let now = Utc::now();
+    let mut bids = vec![];
+    let mut asks = vec![];
+    for ((exchange, _), result) in exchange_clients.iter().zip(results) {
+        match result {
+            Ok(order_books) => {
+                for (pair, quote_usd_rate, order_book) in order_books {
+                    let age = now.signed_duration_since(order_book.time);
+                    if age > max_age {
+                        eprintln!(
+                            "Dropping {exchange:?} {pair} order book, snapshot is {}ms old",
+                            age.num_milliseconds()
+                        );
+                        continue;
+                    }
+                    bids.extend(consolidated_levels(
+                        *exchange,
+                        &pair,
+                        quote_usd_rate,
+                        order_book.bids,
+                    ));
+                    asks.extend(consolidated_levels(
+                        *exchange,
+                        &pair,
+                        quote_usd_rate,
+                        order_book.asks,
+                    ));
                 }
             }
+            Err(err) => eprintln!("{exchange:?}: {err}"),
         }
-        println!();
-
-        println!("Current Holdings");
-        for (held_token, (current_token_price, total_held_amount, unrealized_gain)) in held_tokens {
-            if total_held_amount == 0 {
-                continue;
-            }
-
-            let estimated_tax = tax_rate
-                .and_then(|tax_rate| {
-                    let tax = unrealized_gain.short_term_cap_gain * tax_rate.short_term_gain
-                        + unrealized_gain.long_term_cap_gain * tax_rate.long_term_gain;
+    }
+    bids.sort_by(|a, b| b.usd_price.total_cmp(&a.usd_price));
+    asks.sort_by(|a, b| a.usd_price.total_cmp(&b.usd_price));
 
-                    if tax > 0. {
-                        Some(format!(
-                            "; ${} estimated tax",
-                            tax.separated_string_with_fixed_place(2)
-                        ))
-                    } else {
-                        None
-                    }
-                })
-                .unwrap_or_default();
+    let execution = amount.map(|amount| match side {
+        OrderSide::Sell => consolidated_execution(&bids, side, amount),
+        OrderSide::Buy => consolidated_execution(&asks, side, amount),
+    });
 
-            println!(
-                "  {:<7}       {:<20} (${:14} ; ${} per {}{})",
-                held_token.to_string(),
-                held_token.format_amount(total_held_amount),
-                current_token_price
-                    .map(|current_token_price| f64::try_from(
-                        Decimal::from_f64(held_token.ui_amount(total_held_amount)).unwrap()
-                            * current_token_price
-                    )
-                    .unwrap()
-                    .separated_string_with_fixed_place(2))
-                    .unwrap_or_else(|| "?".into()),
-                current_token_price
-                    .map(|current_token_price| f64::try_from(current_token_price)
-                        .unwrap()
-                        .separated_string_with_fixed_place(3))
-                    .unwrap_or_else(|| "?".into()),
-                held_token,
-                estimated_tax,
-            );
-        }
-        println!();
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "token": token.to_string(),
+                "bids": bids,
+                "asks": asks,
+                "execution": execution,
+            }))?
+        );
+        return Ok(());
+    }
 
-        println!("Summary");
+    let print_level = |level: &ConsolidatedLevel| {
         println!(
-            "  Current Value:       ${}",
-            total_current_value.separated_string_with_fixed_place(2)
+            "{:>14} {:>16} {:<10} {}",
+            format!("${:.4}", level.usd_price),
+            level.amount.separated_string_with_fixed_place(4),
+            format!("{:?}", level.exchange),
+            level.pair,
         );
+    };
+    println!(
+        "{:>14} {:>16} {:<10} Pair",
+        "Price (USD)", "Amount", "Exchange"
+    );
+    for level in asks.iter().take(depth).rev() {
+        print_level(level);
+    }
+    if let (Some(bid), Some(ask)) = (bids.first(), asks.first()) {
         println!(
-            "  Income:              ${} (realized)",
-            total_income.separated_string_with_fixed_place(2)
+            "{:>14} spread, {:.3}%",
+            format!("${:.4}", ask.usd_price - bid.usd_price),
+            (ask.usd_price - bid.usd_price) / ((ask.usd_price + bid.usd_price) / 2.) * 100.
         );
-        if unified_tax_rate {
-            println!(
-                "  Cap gain:            ${} (unrealized)",
-                (total_unrealized_short_term_gain + total_unrealized_long_term_gain)
-                    .separated_string_with_fixed_place(2)
-            );
-        } else {
+    } else {
+        println!("{:>14}", "-");
+    }
+    for level in bids.iter().take(depth) {
+        print_level(level);
+    }
+
+    if let Some(execution) = execution {
+        println!();
+        println!(
+            "{} {} {token}: average ${:.4}, worst ${:.4}",
+            match side {
+                OrderSide::Sell => "Selling",
+                OrderSide::Buy => "Buying",
+            },
+            execution.filled_amount,
+            execution.average_usd_price,
+            execution.worst_usd_price,
+        );
+        for slice in &execution.slices {
             println!(
-                "  Short-term cap gain: ${} (unrealized)",
-                total_unrealized_short_term_gain.separated_string_with_fixed_place(2)
+                "  {:<10} {:<10} {:>16} at ${:.4}",
+                format!("{:?}", slice.exchange),
+                slice.pair,
+                slice.amount.separated_string_with_fixed_place(4),
+                slice.average_usd_price,
             );
+        }
+        if execution.filled_amount < execution.amount {
             println!(
-                "  Long-term cap gain:  ${} (unrealized)",
-                total_unrealized_long_term_gain.separated_string_with_fixed_place(2)
+                "Only {} of {} {token} is available within the fetched depth",
+                execution.filled_amount, execution.amount
             );
         }
-
-        let pending_deposits = db.pending_deposits(None).len();
-        let pending_withdrawals = db.pending_withdrawals(None).len();
-        let pending_transfers = db.pending_transfers().len();
-        let pending_swaps = db.pending_swaps().len();
-
-        if pending_deposits + pending_withdrawals + pending_transfers + pending_swaps > 0 {
-            println!();
-        }
-        if pending_deposits > 0 {
-            println!("  !! Pending deposits: {pending_deposits}");
-        }
-        if pending_withdrawals > 0 {
-            println!("  !! Pending withdrawals: {pending_withdrawals}");
-        }
-        if pending_transfers > 0 {
-            println!("  !! Pending transfers: {pending_transfers}");
-        }
-        if pending_swaps > 0 {
-            println!("  !! Pending swaps: {pending_swaps}");
-        }
     }
-
     Ok(())
 }
 
-async fn process_account_xls(
-    db: &Db,
-    outfile: &str,
-    filter_by_year: Option<i32>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use simple_excel_writer::*;
+// Levels fetched to estimate the book execution price of a quoted order
+const QUOTE_COMPARISON_DEPTH: usize = 500;
 
-    let mut workbook = Workbook::create(outfile);
+async fn process_exchange_quote(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    pair: TradingPair,
+    side: OrderSide,
+    amount: f64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let order_book = exchange_client
+        .order_book(&pair, QUOTE_COMPARISON_DEPTH)
+        .await?;
+    let levels = consolidated_levels(
+        exchange,
+        &pair,
+        1.,
+        match side {
+            OrderSide::Sell => order_book.bids,
+            OrderSide::Buy => order_book.asks,
+        },
+    )
+    .collect::<Vec<_>>();
+    let execution = consolidated_execution(&levels, side, amount);
 
-    let mut sheet = workbook.create_sheet(&match filter_by_year {
-        Some(year) => format!("Disposed in {year}"),
-        None => "Disposed".into(),
-    });
-    sheet.add_column(Column { width: 12. });
-    sheet.add_column(Column { width: 15. });
-    sheet.add_column(Column { width: 12. });
-    sheet.add_column(Column { width: 12. });
-    sheet.add_column(Column { width: 10. });
-    sheet.add_column(Column { width: 40. });
-    sheet.add_column(Column { width: 12. });
-    sheet.add_column(Column { width: 10. });
-    sheet.add_column(Column { width: 10. });
-    sheet.add_column(Column { width: 10. });
-    sheet.add_column(Column { width: 10. });
-    sheet.add_column(Column { width: 40. });
+    let taker_fee_rate = exchange_client.taker_fee_rate();
+    let book_price = match side {
+        OrderSide::Sell => execution.average_usd_price * (1. - taker_fee_rate),
+        OrderSide::Buy => execution.average_usd_price * (1. + taker_fee_rate),
+    };
+    if execution.filled_amount < amount {
+        println!(
+            "Book: only {} of {amount} available within {QUOTE_COMPARISON_DEPTH} levels",
+            execution.filled_amount
+        );
+    } else {
+        println!(
+            "Book: {book_price:.4} {} average after fees, worst level {:.4}",
+            pair.quote, execution.worst_usd_price
+        );
+    }
 
-    let mut disposed_lots = db.disposed_lots();
-    disposed_lots.sort_by_key(|lot| lot.when);
+    let quote = exchange_client.request_quote(&pair, side, amount).await?;
+    println!(
+        "Quote {}: {:.4} {}, expires {}",
+        quote.id,
+        quote.price,
+        pair.quote,
+        quote.expires_at.with_timezone(&Local),
+    );
 
-    if let Some(year) = filter_by_year {
-        // Exclude disposed lots that were neither acquired nor disposed of in the filter year
-        disposed_lots.retain(|disposed_lot| {
-            (disposed_lot.lot.acquisition.when.year() == year
-                && disposed_lot.lot.income(disposed_lot.token) > 0.)
-                || disposed_lot.when.year() == year
-        })
+    if execution.filled_amount < amount {
+        println!("Quote is better: the book is too shallow for this size");
+        return Ok(());
+    }
+    let quote_advantage = match side {
+        OrderSide::Sell => quote.price - book_price,
+        OrderSide::Buy => book_price - quote.price,
+    } * amount;
+    if quote_advantage > 0. {
+        println!("Quote is better by {quote_advantage:.2} {}", pair.quote);
+    } else {
+        println!("Book is better by {:.2} {}", -quote_advantage, pair.quote);
     }
+    Ok(())
+}
 
-    workbook.write_sheet(&mut sheet, |sheet_writer| {
-        sheet_writer.append_row(row![
-            "Token",
-            "Amount",
-            "Income (USD)",
-            "Acq. Date",
-            "Acq. Price (USD)",
-            "Acquisition Description",
-            "Cap Gain (USD)",
-            "Cap Gain Type",
-            "Sale Date",
-            "Sale Price (USD)",
-            "Fee (USD)",
-            "Sale Description"
-        ])?;
+struct ArbitrageQuote<'a> {
+    exchange: Exchange,
+    exchange_client: &'a dyn ExchangeClient,
+    pair: TradingPair,
+    bid_ask: BidAsk,
+    quote_usd_rate: f64,
+    base_available: f64,
+    quote_available: f64,
+}
 
-        for disposed_lot in disposed_lots {
-            let long_term_cap_gain =
-                is_long_term_cap_gain(disposed_lot.lot.acquisition.when, Some(disposed_lot.when));
+impl ArbitrageQuote<'_> {
+    fn usd_buy_price(&self) -> f64 {
+        self.bid_ask.ask_price * self.quote_usd_rate * (1. + self.exchange_client.taker_fee_rate())
+    }
 
-            let mut income = disposed_lot.lot.income(disposed_lot.token);
-            if let Some(year) = filter_by_year {
-                if disposed_lot.lot.acquisition.when.year() != year {
-                    income = 0. // Exclude income from other years
-                }
-            }
+    fn usd_sell_price(&self) -> f64 {
+        self.bid_ask.bid_price * self.quote_usd_rate * (1. - self.exchange_client.taker_fee_rate())
+    }
+}
 
-            sheet_writer.append_row(row![
-                disposed_lot.token.to_string(),
-                disposed_lot.token.ui_amount(disposed_lot.lot.amount),
-                income,
-                disposed_lot.lot.acquisition.when.to_string(),
-                disposed_lot.lot.acquisition.price().to_string(),
-                disposed_lot.lot.acquisition.kind.to_string(),
-                disposed_lot
-                    .lot
-                    .cap_gain(disposed_lot.token, disposed_lot.price()),
-                if long_term_cap_gain { "Long" } else { "Short" },
-                disposed_lot.when.to_string(),
-                disposed_lot.price().to_string(),
-                disposed_lot
-                    .kind
-                    .fee()
-                    .map(|(amount, currency)| {
-                        assert_eq!(currency, "USD");
-                        *amount
-                    })
-                    .unwrap_or_default(),
-                disposed_lot.kind.to_string()
-            ])?;
-        }
-        Ok(())
-    })?;
+async fn get_arbitrage_quote(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+) -> Result<ArbitrageQuote<'_>, Box<dyn std::error::Error>> {
+    let pair = exchange_client.preferred_usd_pair(token).await?;
+    let bid_ask = exchange_client.bid_ask(&pair).await?;
+    let quote_usd_rate = quote_usd_rate(exchange_client, &pair.quote).await?;
+    if quote_usd_rate.assumed {
+        return Err(format!("No market available to value {} in USD", pair.quote).into());
+    }
 
-    let mut current_holdings_rows = vec![];
-    let mut current_holdings_by_year_rows = vec![];
+    let balances = exchange_client.balances().await?;
+    let available = |asset: &str| {
+        balances
+            .get(asset)
+            .map(|balance| balance.available)
+            .unwrap_or_default()
+    };
 
-    #[derive(Clone)]
-    enum R {
-        Number(f64),
-        Text(String),
+    Ok(ArbitrageQuote {
+        exchange,
+        exchange_client,
+        base_available: available(&pair.base),
+        quote_available: available(&pair.quote),
+        pair,
+        bid_ask,
+        quote_usd_rate: quote_usd_rate.rate,
+    })
+}
+
+// Returns the amount filled by an arbitrage leg, cancelling whatever did not fill immediately
+async fn settle_arbitrage_leg(
+    db: &mut Db,
+    quote: &ArbitrageQuote<'_>,
+    order: Result<ManagedOrder, Box<dyn std::error::Error>>,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    let order = order?;
+    // A taker leg that hasn't closed by the first poll is cancelled straight away
+    let (_, order_status) = managed_order::await_terminal(
+        db,
+        quote.exchange_client,
+        &order.client_order_id,
+        Duration::ZERO,
+    )
+    .await?;
+    Ok(order_status.filled_amount)
+}
+
+// Arbitrage legs take liquidity, so their prices round towards the book they cross
+const CROSSING_ROUNDING: OrderRounding = OrderRounding {
+    price: PriceRounding::Aggressive,
+    amount: AmountRounding::Down,
+};
+
+// The exchange deposit account an arbitrage leg trades against
+async fn arbitrage_deposit_account(
+    db: &Db,
+    quote: &ArbitrageQuote<'_>,
+    token: MaybeToken,
+) -> Result<TrackedAccount, Box<dyn std::error::Error>> {
+    let deposit_address = quote.exchange_client.deposit_address(token).await?;
+    Ok(db.get_account(deposit_address, token).ok_or_else(|| {
+        format!(
+            "{:?} deposit account does not exist, run `sync` first: {deposit_address} ({token})",
+            quote.exchange
+        )
+    })?)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn execute_arbitrage(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    token: MaybeToken,
+    buy: &ArbitrageQuote<'_>,
+    sell: &ArbitrageQuote<'_>,
+    amount: f64,
+    lot_selection_method: LotSelectionMethod,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (buy_price, buy_amount) = buy
+        .exchange_client
+        .pair_info(&buy.pair)
+        .await?
+        .round_order(
+            OrderSide::Buy,
+            buy.bid_ask.ask_price,
+            amount,
+            CROSSING_ROUNDING,
+        )?;
+    let (sell_price, sell_amount) = sell
+        .exchange_client
+        .pair_info(&sell.pair)
+        .await?
+        .round_order(
+            OrderSide::Sell,
+            sell.bid_ask.bid_price,
+            amount,
+            CROSSING_ROUNDING,
+        )?;
+    let amount = buy_amount.min(sell_amount);
+
+    // Both legs are checked before either is placed, so a rejection never leaves one leg exposed,
+    // and each is checked again as it's sent
+    let legs = [
+        (buy, OrderSide::Buy, buy_price),
+        (sell, OrderSide::Sell, sell_price),
+    ];
+    let intents = legs.map(|(leg, side, price)| OrderIntent {
+        exchange: leg.exchange,
+        pair: leg.pair.clone(),
+        side,
+        price,
+        amount,
+        strategy: "arbitrage".into(),
+    });
+    let spread_guards = legs.map(|(leg, _, _)| SpreadGuard::Quoted(&leg.bid_ask));
+    let spending_limit_checks = legs.map(|(leg, _, price)| {
+        SpendingLimitCheck::new(db, token, Some(amount * price * leg.quote_usd_rate), false)
+    });
+    let leg_checks: [[&dyn PreTradeCheck; 2]; 2] = [
+        [&spread_guards[0], &spending_limit_checks[0]],
+        [&spread_guards[1], &spending_limit_checks[1]],
+    ];
+    for (intent, checks) in intents.iter().zip(&leg_checks) {
+        check_order_intent(checks, intent, notifier).await?;
     }
 
-    impl ToCellValue for R {
-        fn to_cell_value(&self) -> CellValue {
-            match self {
-                R::Number(x) => x.to_cell_value(),
-                R::Text(x) => x.to_cell_value(),
+    // Each leg is journaled as an open order, so the sync that follows records its fill against
+    // the lots of the exchange deposit account like any other order
+    let buy_deposit_account = arbitrage_deposit_account(db, buy, token).await?;
+    let mut sell_deposit_account = arbitrage_deposit_account(db, sell, token).await?;
+    let sell_lots =
+        sell_deposit_account.extract_lots(db, token.amount(amount), lot_selection_method, None)?;
+    let journal = [
+        (buy_deposit_account, vec![], Some(amount)),
+        (sell_deposit_account, sell_lots, None),
+    ];
+
+    // Both legs are recorded before either is sent, then sent at once.  A preflight check of one
+    // leg could fail after the other has been sent, so the legs rely on the amount having been
+    // sized to the balances available when they were quoted
+    let checks = OrderChecks {
+        rounding: Some(CROSSING_ROUNDING),
+        preflight: false,
+    };
+    let [buy_order, sell_order] = [
+        managed_order::prepare(db, &intents[0], true, None)?,
+        managed_order::prepare(db, &intents[1], true, None)?,
+    ];
+    let (buy_result, sell_result) = futures::join!(
+        managed_order::send(buy.exchange_client, &buy_order, checks, &leg_checks[0]),
+        managed_order::send(sell.exchange_client, &sell_order, checks, &leg_checks[1]),
+    );
+    let buy_order =
+        managed_order::record_sent(db, buy.exchange_client, buy_order, buy_result).await;
+    notify_rejection(&intents[0], &buy_order, notifier).await;
+    let sell_order =
+        managed_order::record_sent(db, sell.exchange_client, sell_order, sell_result).await;
+    notify_rejection(&intents[1], &sell_order, notifier).await;
+    let mut orders = vec![];
+    for (((leg, side, price), order), (deposit_account, lots, ui_amount)) in
+        legs.into_iter().zip([buy_order, sell_order]).zip(journal)
+    {
+        let order = outcome::record(
+            OperationKind::OrderPlaced,
+            describe_order(leg.exchange, &leg.pair, side, amount, price),
+            order,
+            |order| order.order_id.clone().unwrap_or_default(),
+        );
+        if let Ok(order) = &order {
+            let order_id = order.order_id.as_deref().unwrap_or_default();
+            // Settling the legs comes first, so a journal failure is only reported
+            if let Err(err) = db.open_order(
+                side,
+                deposit_account,
+                leg.exchange,
+                order.pair.clone(),
+                price,
+                order_id.to_string(),
+                Some(order.client_order_id.clone()),
+                lots,
+                ui_amount,
+            ) {
+                eprintln!("Unable to journal arbitrage order {order_id}: {err}");
+            }
+            if let Err(err) = db.record_spending(SpendingRecord {
+                time: Utc::now(),
+                operation: limited_order_operation(side),
+                exchange: leg.exchange,
+                token,
+                ui_amount: amount,
+                usd_value: Some(amount * price * leg.quote_usd_rate),
+                reference: order_id.to_string(),
+                fiat_currency: None,
+            }) {
+                eprintln!("Unable to record {order_id} against the spending limits: {err}");
             }
         }
+        orders.push(order);
     }
-
-    for account in db.get_accounts() {
-        for lot in account.lots.iter() {
-            let row = (
-                lot.acquisition.when,
-                vec![
-                    R::Text(account.token.to_string()),
-                    R::Number(account.token.ui_amount(lot.amount)),
-                    R::Number(lot.income(account.token)),
-                    R::Text(lot.acquisition.when.to_string()),
-                    R::Text(lot.acquisition.price().to_string()),
-                    R::Text(lot.acquisition.kind.to_string()),
-                    R::Text(account.description.clone()),
-                    R::Text(account.address.to_string()),
-                ],
+    let sell_order = orders.pop().expect("sell leg");
+    let buy_order = orders.pop().expect("buy leg");
+    let bought = settle_arbitrage_leg(db, buy, buy_order).await;
+    let sold = settle_arbitrage_leg(db, sell, sell_order).await;
+    for leg in [buy, sell] {
+        if let Err(err) =
+            process_sync_exchange(db, leg.exchange, leg.exchange_client, rpc_client, notifier).await
+        {
+            eprintln!(
+                "Unable to journal the {:?} arbitrage leg, run `sync`: {err}",
+                leg.exchange
             );
-            current_holdings_rows.push(row.clone());
-            if let Some(year) = filter_by_year {
-                if lot.acquisition.when.year() == year {
-                    current_holdings_by_year_rows.push(row);
-                    continue;
-                }
-            }
         }
     }
 
-    for open_order in db.open_orders(None, Some(OrderSide::Sell)) {
-        for lot in open_order.lots.iter() {
-            let row = (
-                lot.acquisition.when,
-                vec![
-                    R::Text(open_order.token.to_string()),
-                    R::Number(open_order.token.ui_amount(lot.amount)),
-                    R::Number(lot.income(open_order.token)),
-                    R::Text(lot.acquisition.when.to_string()),
-                    R::Text(lot.acquisition.price().to_string()),
-                    R::Text(lot.acquisition.kind.to_string()),
-                    R::Text(format!(
-                        "Open Order: {:?} {}",
-                        open_order.exchange, open_order.pair
-                    )),
-                    R::Text(open_order.deposit_address.to_string()),
-                ],
+    match (&bought, &sold) {
+        (Ok(bought), Ok(sold)) if bought == sold => {
+            let msg = format!(
+                "Arbitrage executed: bought {}{} on {:?} at ${}, sold on {:?} at ${}",
+                token.symbol(),
+                bought,
+                buy.exchange,
+                buy_price,
+                sell.exchange,
+                sell_price
             );
-            current_holdings_rows.push(row.clone());
-            if let Some(year) = filter_by_year {
-                if lot.acquisition.when.year() == year {
-                    current_holdings_by_year_rows.push(row);
-                    continue;
+            println!("{msg}");
+            notifier.send(&msg).await;
+            Ok(())
+        }
+        _ => {
+            let describe_leg = |result: &Result<f64, Box<dyn std::error::Error>>| match result {
+                Ok(filled_amount) => format!("{}{}", token.symbol(), filled_amount),
+                Err(err) => format!("unknown amount ({err})"),
+            };
+            let msg = format!(
+                "ARBITRAGE EXPOSURE: bought {} on {:?}, sold {} on {:?}{}",
+                describe_leg(&bought),
+                buy.exchange,
+                describe_leg(&sold),
+                sell.exchange,
+                match (&bought, &sold) {
+                    (Ok(bought), Ok(sold)) =>
+                        format!(", net {} position change of {}", token, bought - sold),
+                    _ => String::new(),
                 }
-            }
+            );
+            eprintln!("{msg}");
+            notifier.send(&msg).await;
+            Err(msg.into())
         }
     }
-    current_holdings_rows.sort_by_key(|row| row.0);
-    current_holdings_by_year_rows.sort_by_key(|row| row.0);
+}
 
-    let mut write_holdings = |name: String, rows: Vec<(_, Vec<R>)>| {
-        let mut sheet = workbook.create_sheet(&name);
+// How long arbitrage leaves an exchange alone after it stops accepting orders
+const ARBITRAGE_UNAVAILABLE_BACKOFF: Duration = Duration::from_secs(300);
 
-        sheet.add_column(Column { width: 12. });
-        sheet.add_column(Column { width: 15. });
-        sheet.add_column(Column { width: 12. });
-        sheet.add_column(Column { width: 12. });
-        sheet.add_column(Column { width: 10. });
-        sheet.add_column(Column { width: 40. });
-        sheet.add_column(Column { width: 40. });
-        sheet.add_column(Column { width: 50. });
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_arbitrage(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    tokens: Vec<MaybeToken>,
+    min_spread: f64,
+    max_amount: Option<f64>,
+    interval: Option<Duration>,
+    execute: bool,
+    lot_selection_method: LotSelectionMethod,
+    cancellation: &Cancellation,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exchange_clients =
+        configured_exchange_clients(db, |capabilities| capabilities.supports_trading)?;
+
+    let mut executed = 0;
+    let mut paused_until = HashMap::<Exchange, std::time::Instant>::new();
+    let is_paused = |paused_until: &HashMap<Exchange, std::time::Instant>, exchange| {
+        paused_until
+            .get(&exchange)
+            .map_or(false, |until| std::time::Instant::now() < *until)
+    };
+    'monitor: loop {
+        for token in &tokens {
+            // Both legs of an execution settle before the next check, so no orders are left open
+            if let Err(err) = cancellation.check() {
+                println!("Arbitrage stopped: {err}");
+                break 'monitor;
+            }
 
-        workbook.write_sheet(&mut sheet, |sheet_writer| {
-            sheet_writer.append_row(row![
-                "Token",
-                "Amount",
-                "Income (USD)",
-                "Acq. Date",
-                "Acq. Price (USD)",
-                "Acquisition Description",
-                "Account Description",
-                "Account Address"
-            ])?;
+            let available_clients = exchange_clients
+                .iter()
+                .filter(|(exchange, _)| !is_paused(&paused_until, *exchange))
+                .collect::<Vec<_>>();
+            let results = futures::future::join_all(available_clients.iter().map(
+                |(exchange, exchange_client)| {
+                    get_arbitrage_quote(*exchange, exchange_client.as_ref(), *token)
+                },
+            ))
+            .await;
 
-            for (_, row) in rows {
-                sheet_writer.append_row(Row::from_iter(row.into_iter()))?;
+            let mut quotes = vec![];
+            for ((exchange, _), result) in available_clients.iter().zip(results) {
+                match result {
+                    Ok(quote) => quotes.push(quote),
+                    Err(err) => eprintln!("{exchange:?}: {err}"),
+                }
             }
 
-            Ok(())
-        })
-    };
-    if let Some(year) = filter_by_year {
-        write_holdings(
-            format!("Holdings acquired in {year}"),
-            current_holdings_by_year_rows,
-        )?;
-    }
-    write_holdings("All Holdings".to_string(), current_holdings_rows)?;
+            for buy in &quotes {
+                for sell in &quotes {
+                    if buy.exchange == sell.exchange
+                        || is_paused(&paused_until, buy.exchange)
+                        || is_paused(&paused_until, sell.exchange)
+                    {
+                        continue;
+                    }
 
-    workbook.close()?;
-    println!("Wrote {outfile}");
+                    let spread =
+                        (sell.usd_sell_price() - buy.usd_buy_price()) / buy.usd_buy_price() * 100.;
+                    if spread < min_spread {
+                        continue;
+                    }
+
+                    let amount = [
+                        Some(
+                            buy.quote_available
+                                / (buy.bid_ask.ask_price
+                                    * (1. + buy.exchange_client.taker_fee_rate())),
+                        ),
+                        Some(sell.base_available),
+                        buy.bid_ask.ask_amount,
+                        sell.bid_ask.bid_amount,
+                        max_amount,
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .fold(f64::INFINITY, f64::min);
+
+                    let msg = format!(
+                        "{} arbitrage: buy {} on {:?} at ${}, sell {} on {:?} at ${}, \
+                         {:.2}% spread after fees, executable amount {}{}",
+                        token,
+                        buy.pair,
+                        buy.exchange,
+                        buy.bid_ask.ask_price,
+                        sell.pair,
+                        sell.exchange,
+                        sell.bid_ask.bid_price,
+                        spread,
+                        token.symbol(),
+                        amount,
+                    );
+                    println!("{msg}");
+
+                    if execute && amount > 0. {
+                        // Placing one leg on an exchange that won't accept the other would leave
+                        // the position exposed, so pause that exchange and retry it later
+                        let mut unavailable = None;
+                        for leg in [buy, sell] {
+                            if let Err(err) = check_system_status(
+                                leg.exchange,
+                                leg.exchange_client,
+                                Some(&leg.pair),
+                                SystemOperation::PlaceOrder { taker: true },
+                            )
+                            .await
+                            {
+                                unavailable = Some((leg.exchange, err.to_string()));
+                                break;
+                            }
+                        }
+                        if let Some((exchange, err)) = unavailable {
+                            println!(
+                                "Pausing {exchange:?} arbitrage for {}s: {err}",
+                                ARBITRAGE_UNAVAILABLE_BACKOFF.as_secs()
+                            );
+                            paused_until.insert(
+                                exchange,
+                                std::time::Instant::now() + ARBITRAGE_UNAVAILABLE_BACKOFF,
+                            );
+                            continue;
+                        }
+                        notifier.send(&msg).await;
+                        // A leg rejected by a pre-trade check, such as a dislocated book, skips
+                        // this round rather than ending the run
+                        match execute_arbitrage(
+                            db,
+                            rpc_client,
+                            *token,
+                            buy,
+                            sell,
+                            amount,
+                            lot_selection_method.clone(),
+                            notifier,
+                        )
+                        .await
+                        {
+                            Err(err) if err.is::<Rejection>() => {
+                                println!("Skipping arbitrage: {err}");
+                                continue;
+                            }
+                            result => result?,
+                        }
+                        executed += 1;
+                    }
+                }
+            }
+        }
 
+        match interval {
+            Some(interval) => {
+                if let Err(err) = cancellation.sleep(interval).await {
+                    println!("Arbitrage stopped: {err}");
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    if execute {
+        println!("{executed} arbitrage opportunities executed");
+    }
     Ok(())
 }
 
-async fn process_account_merge<T: Signers>(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    from_address: Pubkey,
-    into_address: Pubkey,
-    authority_address: Pubkey,
-    signers: T,
-    existing_signature: Option<Signature>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let token = MaybeToken::SOL(); // TODO: Support merging tokens one day
+#[derive(Default, Serialize)]
+struct PortfolioHolding {
+    amount: f64,
+    price: Option<f64>,
+    value: Option<f64>,
+    locations: BTreeMap<String, f64>,
+    // The dollar-denominated coins making up the USD holding, each valued at its own USD rate so
+    // a depeg shows up in the holding's price
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    usd_coins: BTreeMap<String, UsdCoinHolding>,
+    // The price in USD, before conversion to the reporting currency.  Unset for the USD holding,
+    // whose coins each have their own
+    #[serde(skip)]
+    usd_price: Option<UsdPrice>,
+    // The exchange market, or the external source, the price came from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    price_source: Option<String>,
+    #[serde(skip)]
+    exchange_balances: Vec<ExchangeAssetBalance>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    delistings: Vec<AssetDelisting>,
+}
 
-    if let Some(existing_signature) = existing_signature {
-        db.record_transfer(
-            existing_signature,
-            0, /*last_valid_block_height*/
-            None,
-            from_address,
-            token,
-            into_address,
-            token,
-            LotSelectionMethod::default(),
-            None,
-        )?;
-    } else {
-        let (recent_blockhash, last_valid_block_height) =
-            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+// An exchange's announced delisting of every market for an asset held there
+#[derive(Serialize)]
+struct AssetDelisting {
+    exchange: Exchange,
+    asset: String,
+    delist_time: DateTime<Utc>,
+}
 
-        let from_account = rpc_client
-            .get_account_with_commitment(&from_address, rpc_client.commitment())?
-            .value
-            .ok_or_else(|| format!("From account, {from_address}, does not exist"))?;
+#[derive(Default, Serialize)]
+struct UsdCoinHolding {
+    amount: f64,
+    usd_rate: Option<QuoteUsdRate>,
+    #[serde(skip)]
+    usd_price: Option<UsdPrice>,
+}
 
-        let from_tracked_account = db
-            .get_account(from_address, token)
-            .ok_or_else(|| format!("Account, {from_address}, is not tracked"))?;
+// The balance of one asset in one exchange account, `account` being empty for the account the
+// credentials belong to
+struct ExchangeAssetBalance {
+    exchange: Exchange,
+    account: String,
+    asset: String,
+    amount: f64,
+}
 
-        let into_account = rpc_client
-            .get_account_with_commitment(&into_address, rpc_client.commitment())?
-            .value
-            .ok_or_else(|| format!("From account, {into_address}, does not exist"))?;
+#[derive(Clone)]
+struct UsdPrice {
+    price: f64,
+    // The exchange market, or the external source, the price came from
+    source: String,
+    // Not taken from a market, such as a Coin Gecko price or a dollar coin assumed at parity
+    estimated: bool,
+}
 
-        let authority_account = if from_address == authority_address {
-            from_account.clone()
-        } else {
-            rpc_client
-                .get_account_with_commitment(&authority_address, rpc_client.commitment())?
-                .value
-                .ok_or_else(|| format!("Authority account, {authority_address}, does not exist"))?
+impl UsdPrice {
+    fn of_usd_coin(coin: &str, quote_usd_rate: QuoteUsdRate, exchange: Option<Exchange>) -> Self {
+        let source = match (quote_usd_rate.source, exchange) {
+            (QuoteUsdRateSource::Usd, _) => "USD".into(),
+            (QuoteUsdRateSource::FixedRate, _) => "fx-rates".into(),
+            (QuoteUsdRateSource::Market, Some(exchange)) => format!("{exchange:?} {coin}/USD"),
+            (QuoteUsdRateSource::Market, None) => format!("{coin}/USD"),
+            (QuoteUsdRateSource::CoinGecko, _) => "CoinGecko".into(),
+            (QuoteUsdRateSource::Assumed, _) => "parity".into(),
         };
+        Self {
+            price: quote_usd_rate.rate,
+            source,
+            estimated: !matches!(
+                quote_usd_rate.source,
+                QuoteUsdRateSource::Usd | QuoteUsdRateSource::Market
+            ),
+        }
+    }
+}
 
-        let amount = from_tracked_account.last_update_balance;
-
-        let instructions = if from_account.owner == solana_sdk::stake::program::id()
-            && into_account.owner == solana_sdk::stake::program::id()
-        {
-            solana_sdk::stake::instruction::merge(&into_address, &from_address, &authority_address)
-        } else if from_account.owner == solana_sdk::stake::program::id()
-            && into_account.owner == system_program::id()
-        {
-            vec![solana_sdk::stake::instruction::withdraw(
-                &from_address,
-                &authority_address,
-                &into_address,
-                amount,
-                None,
-            )]
-        } else {
-            return Err(format!(
-                "Unsupported merge from {} account to {} account",
-                from_account.owner, into_account.owner
-            )
-            .into());
-        };
+lazy_static::lazy_static! {
+    // Each exchange's tickers, fetched in one request per exchange when a portfolio is valued so
+    // that its assets aren't priced one market request at a time
+    static ref TICKER_SNAPSHOTS: std::sync::Mutex<HashMap<Exchange, Arc<TickerSnapshot>>> =
+        std::sync::Mutex::new(HashMap::default());
+}
 
-        println!("Merging {from_address} into {into_address}");
-        if from_address != authority_address {
-            println!("Authority address: {authority_address}");
-        }
+fn ticker_snapshot(exchange: Exchange) -> Option<Arc<TickerSnapshot>> {
+    TICKER_SNAPSHOTS.lock().unwrap().get(&exchange).cloned()
+}
 
-        let mut message = Message::new(&instructions, Some(&authority_address));
-        message.recent_blockhash = recent_blockhash;
-        if rpc_client.get_fee_for_message(&message)? > authority_account.lamports {
-            return Err("Insufficient funds for transaction fee".into());
+// Values `asset` from the first exchange that lists it against a USD coin, converting that coin
+// to USD
+async fn exchange_usd_price(
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    asset: &str,
+) -> Option<UsdPrice> {
+    for (exchange, exchange_client) in exchange_clients {
+        let trading_pairs = match exchange_client.trading_pairs().await {
+            Ok(trading_pairs) => trading_pairs,
+            Err(_) => continue,
+        };
+        let snapshot = ticker_snapshot(*exchange);
+        for quote in exchange::usd_preference_order() {
+            let pair = TradingPair::new(asset, quote);
+            if trading_pairs.contains(&pair) {
+                if let (Ok(bid_ask), Ok(quote_usd_rate)) = (
+                    exchange::snapshot_bid_ask(
+                        exchange_client.as_ref(),
+                        snapshot.as_deref(),
+                        &pair,
+                    )
+                    .await,
+                    exchange::snapshot_quote_usd_rate(
+                        exchange_client.as_ref(),
+                        snapshot.as_deref(),
+                        quote,
+                    )
+                    .await,
+                ) {
+                    return Some(UsdPrice {
+                        price: (bid_ask.bid_price + bid_ask.ask_price) / 2. * quote_usd_rate.rate,
+                        source: format!("{exchange:?} {pair}"),
+                        estimated: quote_usd_rate.assumed,
+                    });
+                }
+            }
         }
+    }
+    None
+}
 
-        let mut transaction = Transaction::new_unsigned(message);
-        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-        if simulation_result.err.is_some() {
-            return Err(format!("Simulation failure: {simulation_result:?}").into());
-        }
+fn asset_token(asset: &str) -> Option<MaybeToken> {
+    if asset == "SOL" {
+        Some(MaybeToken::SOL())
+    } else {
+        Token::from_str(asset).ok().map(MaybeToken::from)
+    }
+}
 
-        transaction.try_sign(&signers, recent_blockhash)?;
-        let signature = transaction.signatures[0];
-        println!("Transaction signature: {signature}");
+// Values `asset` from one configured price source, or explains why the source was passed over
+async fn source_usd_price(
+    rpc_client: &RpcClient,
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    asset: &str,
+    price_source: &PriceSource,
+) -> Result<UsdPrice, Box<dyn std::error::Error>> {
+    let (usd_price, time) = match &price_source.kind {
+        PriceSourceKind::Exchange { exchange, pair } => {
+            let exchange_client = exchange_clients
+                .iter()
+                .find(|(configured_exchange, _)| configured_exchange == exchange)
+                .map(|(_, exchange_client)| exchange_client.as_ref())
+                .ok_or_else(|| format!("{exchange:?} is not configured"))?;
+            let trading_pairs = exchange_client.trading_pairs().await?;
+            let pair = match pair {
+                Some(pair) if pair.base != asset => {
+                    return Err(format!("{pair} is not a {asset} market").into())
+                }
+                Some(pair) => pair.clone(),
+                None => exchange::usd_preference_order()
+                    .iter()
+                    .map(|quote| TradingPair::new(asset, quote))
+                    .find(|pair| trading_pairs.contains(pair))
+                    .ok_or_else(|| format!("{exchange:?} has no USD market for {asset}"))?,
+            };
 
-        db.record_transfer(
-            signature,
-            last_valid_block_height,
-            Some(amount),
-            from_address,
-            token,
-            into_address,
-            token,
-            LotSelectionMethod::default(),
-            None,
-        )?;
+            let snapshot = ticker_snapshot(*exchange);
+            let snapshot_bid_ask = snapshot.as_ref().and_then(|snapshot| {
+                snapshot
+                    .bid_asks
+                    .get(&pair)
+                    .map(|bid_ask| (bid_ask.bid_price, bid_ask.ask_price, snapshot.time))
+            });
+            let (bid, ask, time) = match snapshot_bid_ask {
+                Some(snapshot_bid_ask) => snapshot_bid_ask,
+                None => {
+                    let order_book = exchange_client.order_book(&pair, 1).await?;
+                    match (order_book.bids.first(), order_book.asks.first()) {
+                        (Some(bid), Some(ask)) => (bid.price, ask.price, order_book.time),
+                        _ => return Err(format!("{exchange:?} {pair} order book is empty").into()),
+                    }
+                }
+            };
+            let quote_usd_rate = exchange::snapshot_quote_usd_rate(
+                exchange_client,
+                snapshot.as_deref(),
+                &pair.quote,
+            )
+            .await?;
+            (
+                UsdPrice {
+                    price: (bid + ask) / 2. * quote_usd_rate.rate,
+                    source: format!("{exchange:?} {pair}"),
+                    estimated: quote_usd_rate.assumed,
+                },
+                Some(time),
+            )
+        }
+        PriceSourceKind::CoinGecko => {
+            let token = asset_token(asset)
+                .filter(|token| token.liquidity_token().is_none())
+                .ok_or_else(|| format!("Coin Gecko does not price {asset}"))?;
+            let (price, time) = coin_gecko::get_current_price_and_time(&token).await?;
+            (
+                UsdPrice {
+                    price: price.to_f64().unwrap_or_default(),
+                    source: "CoinGecko".into(),
+                    estimated: true,
+                },
+                time,
+            )
+        }
+        PriceSourceKind::Tulip => {
+            let token = asset_token(asset)
+                .filter(|token| token.liquidity_token().is_some())
+                .ok_or_else(|| format!("Tulip does not price {asset}"))?;
+            let price = token.get_current_price(rpc_client).await?;
+            (
+                UsdPrice {
+                    price: price.to_f64().unwrap_or_default(),
+                    source: "Tulip".into(),
+                    estimated: true,
+                },
+                // Read from the chain as it is now
+                None,
+            )
+        }
+    };
 
-        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-            db.cancel_transfer(signature)?;
-            return Err("Merge failed".into());
+    if let (Some(max_age), Some(time)) = (price_source.max_age, time) {
+        let age = Utc::now().signed_duration_since(time);
+        if age > max_age {
+            return Err(format!(
+                "{} price is {}s old, older than the {}s limit",
+                usd_price.source,
+                age.num_seconds(),
+                max_age.num_seconds()
+            )
+            .into());
         }
-        let when = get_signature_date(rpc_client, signature).await?;
-        db.confirm_transfer(signature, when)?;
-        db.remove_account(from_address, token)?;
     }
-    Ok(())
+    Ok(usd_price)
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_account_sweep<T: Signers>(
-    db: &mut Db,
+// Values `asset` from the first of `price_sources` with a fresh price, warning when the next
+// source with one disagrees by more than `price_mismatch_threshold()`
+async fn prioritized_usd_price(
     rpc_client: &RpcClient,
-    from_address: Pubkey,
-    token: MaybeToken,
-    retain_amount: u64,
-    no_sweep_ok: bool,
-    from_authority_address: Pubkey,
-    signers: T,
-    to_address: Option<Pubkey>,
-    notifier: &Notifier,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (recent_blockhash, last_valid_block_height) =
-        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
-    let fee_calculator = get_deprecated_fee_calculator(rpc_client)?;
-
-    let from_account = rpc_client
-        .get_account_with_commitment(&from_address, rpc_client.commitment())?
-        .value
-        .ok_or_else(|| format!("Account, {from_address}, does not exist"))?;
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    asset: &str,
+    price_sources: &[PriceSource],
+) -> Option<UsdPrice> {
+    let mut usd_price = None::<UsdPrice>;
+    for price_source in price_sources {
+        let source_price =
+            match source_usd_price(rpc_client, exchange_clients, asset, price_source).await {
+                Ok(source_price) => source_price,
+                Err(err) => {
+                    if usd_price.is_none() {
+                        eprintln!("{asset} price source passed over: {err}");
+                    }
+                    continue;
+                }
+            };
+        match &usd_price {
+            None => usd_price = Some(source_price),
+            Some(usd_price) => {
+                let difference = (source_price.price - usd_price.price).abs() / usd_price.price;
+                if difference > exchange::price_mismatch_threshold() {
+                    eprintln!(
+                        "{}",
+                        style(format!(
+                            "Warning: {asset} price of ${:.4} from {} differs from {} (${:.4}) \
+                             by {:.2}% (price-mismatch-percent setting)",
+                            usd_price.price,
+                            usd_price.source,
+                            source_price.source,
+                            source_price.price,
+                            difference * 100.
+                        ))
+                        .yellow()
+                    );
+                }
+                break;
+            }
+        }
+    }
+    usd_price
+}
 
-    let from_tracked_account = db
-        .get_account(from_address, token)
-        .ok_or_else(|| format!("Account, {from_address}, is not tracked"))?;
+// Values a non-dollar asset from its configured price sources, otherwise from the exchanges
+// falling back to the token's own price source.  Each asset is priced once per run, so every
+// report of the run uses the same price
+async fn asset_usd_price(
+    rpc_client: &RpcClient,
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    asset: &str,
+) -> Option<UsdPrice> {
+    lazy_static::lazy_static! {
+        static ref USD_PRICES: std::sync::Mutex<HashMap<String, Option<UsdPrice>>> =
+            std::sync::Mutex::new(HashMap::default());
+    }
+    if let Some(usd_price) = USD_PRICES.lock().unwrap().get(asset) {
+        return usd_price.clone();
+    }
 
-    let authority_account = if from_address == from_authority_address {
-        from_account.clone()
+    let usd_price = if exchange::is_fiat(asset) {
+        usd_coin_rate_and_exchange(exchange_clients, asset)
+            .await
+            .map(|(usd_rate, exchange)| UsdPrice::of_usd_coin(asset, usd_rate, exchange))
+    } else if let Some(price_sources) = exchange::price_source_priority(asset) {
+        prioritized_usd_price(rpc_client, exchange_clients, asset, &price_sources).await
+    } else if let Some(usd_price) = exchange_usd_price(exchange_clients, asset).await {
+        Some(usd_price)
     } else {
-        rpc_client
-            .get_account_with_commitment(&from_authority_address, rpc_client.commitment())?
-            .value
-            .ok_or_else(|| format!("Authority account, {from_authority_address}, does not exist"))?
+        async {
+            let token = asset_token(asset)?;
+            let price = token.get_current_price(rpc_client).await.ok()?.to_f64()?;
+            Some(UsdPrice {
+                price,
+                source: if token.liquidity_token().is_some() {
+                    "Tulip".into()
+                } else {
+                    "CoinGecko".into()
+                },
+                estimated: true,
+            })
+        }
+        .await
     };
+    USD_PRICES
+        .lock()
+        .unwrap()
+        .insert(asset.to_string(), usd_price.clone());
+    usd_price
+}
 
-    let mut num_transaction_signatures = 1; // from_address_authority
+// Values a dollar-denominated coin or a fiat currency from the first exchange with a market or
+// external price for it, only assuming parity for dollar coins when none has one
+async fn usd_coin_rate(
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    coin: &str,
+) -> Option<QuoteUsdRate> {
+    usd_coin_rate_and_exchange(exchange_clients, coin)
+        .await
+        .map(|(usd_rate, _)| usd_rate)
+}
 
-    let (to_address, via_transitory_stake) = if let Some(to_address) = to_address {
-        let _ = db
-            .get_account(to_address, token)
-            .ok_or_else(|| format!("Account {to_address} ({token}) does not exist"))?;
-        (to_address, None)
-    } else {
-        if !token.is_sol() {
-            return Err("--to <ADDRESS> must be provided for token sweeps".into());
+// Like `usd_coin_rate`, along with the exchange whose market or rate source was used
+async fn usd_coin_rate_and_exchange(
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    coin: &str,
+) -> Option<(QuoteUsdRate, Option<Exchange>)> {
+    if coin == "USD" {
+        return Some((QuoteUsdRate::USD, None));
+    }
+    if let Some(rate) = exchange::fx_rate_override(coin) {
+        return Some((QuoteUsdRate::fixed(rate), None));
+    }
+
+    let mut assumed_rate = None;
+    for (exchange, exchange_client) in exchange_clients {
+        let snapshot = ticker_snapshot(*exchange);
+        match exchange::snapshot_quote_usd_rate(exchange_client.as_ref(), snapshot.as_deref(), coin)
+            .await
+        {
+            Ok(quote_usd_rate) if !quote_usd_rate.assumed => {
+                return Some((quote_usd_rate, Some(*exchange)))
+            }
+            Ok(quote_usd_rate) => assumed_rate = Some((quote_usd_rate, Some(*exchange))),
+            Err(_) => {}
         }
+    }
+    assumed_rate
+}
 
-        let transitory_stake_account = Keypair::new();
+// `future`'s output, along with how long it took
+async fn timed<T>(future: impl std::future::Future<Output = T>) -> (T, Duration) {
+    let start = std::time::Instant::now();
+    let output = future.await;
+    (output, start.elapsed())
+}
 
-        let sweep_stake_account = db
-            .get_sweep_stake_account()
-            .ok_or("Sweep stake account not configured")?;
-        let sweep_stake_authority_keypair = read_keypair_file(&sweep_stake_account.stake_authority)
-            .map_err(|err| {
-                format!(
-                    "Failed to read {}: {}",
-                    sweep_stake_account.stake_authority.display(),
-                    err
-                )
-            })?;
+// Where valuing a portfolio spent its time on one exchange, shown with `--verbose`
+struct ExchangeValuationTiming {
+    exchange: Exchange,
+    balances: Duration,
+    tickers: Duration,
+    // Markets in the exchange's ticker snapshot, or why it couldn't be fetched
+    ticker_count: Result<usize, String>,
+    // Until every asset first found on the exchange was priced
+    pricing: Duration,
+}
 
-        num_transaction_signatures += 1; // transitory_stake_account
-        if from_authority_address != sweep_stake_authority_keypair.pubkey() {
-            num_transaction_signatures += 1;
+// Exchanges that fail are returned rather than failing the portfolio. With `cached_fallback`, the
+// balances of the latest snapshot are substituted for those that fail
+async fn get_portfolio(
+    db: &Db,
+    rpc_client: &RpcClient,
+    exchange_clients: &[(Exchange, Box<dyn ExchangeClient>)],
+    all_subaccounts: bool,
+    cached_fallback: bool,
+) -> (
+    BTreeMap<String, PortfolioHolding>,
+    Vec<ExchangeFailure>,
+    Vec<ExchangeValuationTiming>,
+) {
+    // Described as `add_exchange_deposit_address_to_db` describes them
+    let exchange_account_descriptions = db
+        .get_configured_exchange_accounts()
+        .into_iter()
+        .filter(|(exchange, _, _)| {
+            exchange_clients
+                .iter()
+                .any(|(client_exchange, _)| client_exchange == exchange)
+        })
+        .map(|(exchange, _, exchange_account)| format!("{exchange:?} {exchange_account}"))
+        .collect::<HashSet<_>>();
+
+    let collapse_usd_coins = |asset: &str| {
+        if exchange::is_usd_equivalent(asset) {
+            "USD".to_string()
+        } else {
+            asset.to_string()
         }
+    };
 
-        (
-            transitory_stake_account.pubkey(),
-            Some((
-                transitory_stake_account,
-                sweep_stake_authority_keypair,
-                sweep_stake_account.address,
-            )),
-        )
+    let mut holdings = BTreeMap::<String, PortfolioHolding>::default();
+    let mut add_balances = |exchange: Exchange,
+                            account: Option<&str>,
+                            balances: TokenBalances,
+                            cached_from: Option<DateTime<Utc>>| {
+        let location = match (account, cached_from) {
+            (_, Some(cached_from)) => {
+                format!("{exchange:?} (as of {})", HumanTime::from(cached_from))
+            }
+            (Some(account), None) => format!("{exchange:?} ({account})"),
+            (None, None) => format!("{exchange:?}"),
+        };
+        let balances = balances
+            .tokens
+            .into_iter()
+            .map(|(token, balance)| (token.name().to_string(), balance))
+            .chain(balances.unmapped);
+        for (asset, balance) in balances {
+            if balance.total > 0. {
+                let holding = holdings.entry(collapse_usd_coins(&asset)).or_default();
+                holding.amount += balance.total;
+                *holding.locations.entry(location.clone()).or_default() += balance.total;
+                // Cached balances are left out of the export, which records what was held that day
+                if cached_from.is_none() {
+                    holding.exchange_balances.push(ExchangeAssetBalance {
+                        exchange,
+                        account: account.unwrap_or_default().to_string(),
+                        asset: asset.clone(),
+                        amount: balance.total,
+                    });
+                }
+                if exchange::is_usd_equivalent(&asset) {
+                    holding.usd_coins.entry(asset).or_default().amount += balance.total;
+                }
+            }
+        }
     };
 
-    if authority_account.lamports
-        < num_transaction_signatures * fee_calculator.lamports_per_signature
+    // Each exchange's balances and tickers are fetched alongside every other exchange's, so the
+    // portfolio takes about as long as the slowest exchange rather than all of them in turn
+    let results = futures::future::join_all(exchange_clients.iter().map(
+        |(_, exchange_client)| async move {
+            futures::join!(
+                timed(exchange_client.balances()),
+                timed(TickerSnapshot::fetch(exchange_client.as_ref())),
+            )
+        },
+    ))
+    .await;
+    let balance_snapshots = if cached_fallback {
+        db.balance_snapshots()
+    } else {
+        vec![]
+    };
+    let mut failures = vec![];
+    let mut timings = vec![];
+    for ((exchange, _), ((result, balances_elapsed), (snapshot, tickers_elapsed))) in
+        exchange_clients.iter().zip(results)
     {
-        return Err(format!(
-            "Authority has insufficient funds for the transaction fee of {}",
-            token.ui_amount(num_transaction_signatures * fee_calculator.lamports_per_signature)
-        )
-        .into());
-    }
+        timings.push(ExchangeValuationTiming {
+            exchange: *exchange,
+            balances: balances_elapsed,
+            tickers: tickers_elapsed,
+            ticker_count: snapshot
+                .as_ref()
+                .map(|snapshot| snapshot.bid_asks.len())
+                .map_err(|err| err.to_string()),
+            pricing: Duration::default(),
+        });
+        // Without a snapshot, the exchange's markets are requested one at a time
+        if let Ok(snapshot) = snapshot {
+            TICKER_SNAPSHOTS
+                .lock()
+                .unwrap()
+                .insert(*exchange, Arc::new(snapshot));
+        }
 
-    let (mut instructions, sweep_amount) = if token.is_sol() {
-        if from_account.lamports < from_tracked_account.last_update_balance {
-            return Err(format!(
-                "{}: On-chain account balance ({}) less than tracked balance ({})",
-                from_address,
-                token.ui_amount(from_account.lamports),
-                token.ui_amount(from_tracked_account.last_update_balance)
-            )
-            .into());
+        match record_exchange_query(*exchange, "", result) {
+            Ok(balances) => add_balances(
+                *exchange,
+                None,
+                TokenBalances::new(*exchange, balances),
+                None,
+            ),
+            Err(mut failure) => {
+                let cached = balance_snapshots.iter().rev().find_map(|balance_snapshot| {
+                    balance_snapshot
+                        .exchanges
+                        .iter()
+                        .find(|exchange_snapshot| {
+                            exchange_snapshot.exchange == *exchange
+                                && exchange_snapshot.account.is_empty()
+                                && exchange_snapshot.error.is_none()
+                        })
+                        .map(|exchange_snapshot| (balance_snapshot.time, exchange_snapshot))
+                });
+                if let Some((time, exchange_snapshot)) = cached {
+                    let balances = exchange_snapshot
+                        .balances
+                        .iter()
+                        .map(|(coin, balance)| {
+                            (
+                                coin.clone(),
+                                ExchangeBalance {
+                                    available: balance.amount,
+                                    total: balance.amount,
+                                },
+                            )
+                        })
+                        .collect();
+                    add_balances(
+                        *exchange,
+                        None,
+                        TokenBalances::new(*exchange, balances),
+                        Some(time),
+                    );
+                    failure.cached_from = Some(time);
+                }
+                failures.push(failure);
+            }
         }
+    }
 
-        if from_account.owner == system_program::id() {
-            let lamports = if from_address == from_authority_address {
-                from_tracked_account.last_update_balance.saturating_sub(
-                    num_transaction_signatures * fee_calculator.lamports_per_signature
-                        + retain_amount,
-                )
-            } else {
-                from_tracked_account
-                    .last_update_balance
-                    .saturating_sub(retain_amount)
-            };
+    if all_subaccounts {
+        for (exchange, exchange_client) in exchange_clients {
+            let subaccounts =
+                match record_exchange_query(*exchange, "", exchange_client.subaccounts().await) {
+                    Ok(subaccounts) => subaccounts,
+                    Err(failure) => {
+                        failures.push(failure);
+                        continue;
+                    }
+                };
+            for subaccount in subaccounts {
+                match record_exchange_query(
+                    *exchange,
+                    &subaccount,
+                    exchange_client.subaccount_balances(&subaccount).await,
+                ) {
+                    Ok(balances) => add_balances(
+                        *exchange,
+                        Some(&subaccount),
+                        TokenBalances::new(*exchange, balances),
+                        None,
+                    ),
+                    Err(failure) => failures.push(failure),
+                }
+            }
+        }
+    }
 
-            (
-                vec![system_instruction::transfer(
-                    &from_address,
-                    &to_address,
-                    lamports,
-                )],
-                lamports,
-            )
-        } else if from_account.owner == solana_vote_program::id() {
-            let minimum_balance = rpc_client.get_minimum_balance_for_rent_exemption(
-                solana_vote_program::vote_state::VoteState::size_of(),
-            )?;
+    // An asset that can no longer be traded once all of its markets are delisted has to be moved
+    // off the exchange before then
+    for (exchange, exchange_client) in exchange_clients {
+        let pair_infos = match exchange_client.pair_infos().await {
+            Ok(pair_infos) => pair_infos,
+            Err(_) => continue,
+        };
+        for holding in holdings.values_mut() {
+            let assets = holding
+                .exchange_balances
+                .iter()
+                .filter(|exchange_balance| exchange_balance.exchange == *exchange)
+                .map(|exchange_balance| exchange_balance.asset.clone())
+                .unique()
+                .collect::<Vec<_>>();
+            for asset in assets {
+                let delist_time = pair_infos
+                    .iter()
+                    .filter(|pair_info| pair_info.pair.base == asset)
+                    .map(|pair_info| pair_info.upcoming_delisting())
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|delist_times| delist_times.into_iter().max());
+                if let Some(delist_time) = delist_time {
+                    holding.delistings.push(AssetDelisting {
+                        exchange: *exchange,
+                        asset,
+                        delist_time,
+                    });
+                }
+            }
+        }
+    }
 
-            let lamports = from_tracked_account
-                .last_update_balance
-                .saturating_sub(minimum_balance + retain_amount);
+    // Deposits sent but not yet credited are in neither the exchange nor the wallet balances
+    for deposit in db.in_transit_deposits(None) {
+        if !exchange_clients
+            .iter()
+            .any(|(exchange, _)| *exchange == deposit.exchange)
+        {
+            continue;
+        }
+        let amount = deposit.token.ui_amount(deposit.amount);
+        let holding = holdings
+            .entry(collapse_usd_coins(deposit.token.name()))
+            .or_default();
+        holding.amount += amount;
+        *holding
+            .locations
+            .entry(format!("{:?} (in transit)", deposit.exchange))
+            .or_default() += amount;
+        if exchange::is_usd_equivalent(deposit.token.name()) {
+            holding
+                .usd_coins
+                .entry(deposit.token.name().to_string())
+                .or_default()
+                .amount += amount;
+        }
+    }
 
-            (
-                vec![solana_vote_program::vote_instruction::withdraw(
-                    &from_address,
-                    &from_authority_address,
-                    lamports,
-                    &to_address,
-                )],
-                lamports,
-            )
-        } else if from_account.owner == solana_sdk::stake::program::id() {
-            let lamports = from_tracked_account
-                .last_update_balance
-                .saturating_sub(retain_amount);
+    // Exchange deposit accounts are already covered by the exchange balances
+    for account in db.get_accounts() {
+        if exchange_account_descriptions.contains(&account.description)
+            || account.last_update_balance == 0
+        {
+            continue;
+        }
+        let amount = account.token.ui_amount(account.last_update_balance);
+        let holding = holdings
+            .entry(collapse_usd_coins(account.token.name()))
+            .or_default();
+        holding.amount += amount;
+        *holding.locations.entry("On-chain".into()).or_default() += amount;
+        if exchange::is_usd_equivalent(account.token.name()) {
+            holding
+                .usd_coins
+                .entry(account.token.name().to_string())
+                .or_default()
+                .amount += amount;
+        }
+    }
 
-            (
-                vec![solana_sdk::stake::instruction::withdraw(
-                    &from_address,
-                    &from_authority_address,
-                    &to_address,
-                    lamports,
-                    None,
-                )],
-                lamports,
-            )
+    // Assets are priced concurrently, each timed against the first exchange it was found on
+    let pricing = holdings.iter_mut().map(|(asset, holding)| async move {
+        let start = std::time::Instant::now();
+        holding.price = if asset == "USD" {
+            let mut value = 0.;
+            for (coin, usd_coin) in holding.usd_coins.iter_mut() {
+                let usd_rate = usd_coin_rate_and_exchange(exchange_clients, coin).await;
+                usd_coin.usd_rate = usd_rate.map(|(usd_rate, _)| usd_rate);
+                usd_coin.usd_price = usd_rate
+                    .map(|(usd_rate, exchange)| UsdPrice::of_usd_coin(coin, usd_rate, exchange));
+                value += usd_coin.amount * usd_coin.usd_rate.map_or(1., |rate| rate.rate);
+            }
+            Some(value / holding.amount)
         } else {
-            return Err(format!("Unsupported `from` account owner: {}", from_account.owner).into());
+            holding.usd_price = asset_usd_price(rpc_client, exchange_clients, asset).await;
+            holding.price_source = holding
+                .usd_price
+                .as_ref()
+                .map(|usd_price| usd_price.source.clone());
+            holding.usd_price.as_ref().map(|usd_price| usd_price.price)
+        };
+        holding.value = holding.price.map(|price| price * holding.amount);
+        (
+            holding
+                .exchange_balances
+                .first()
+                .map(|exchange_balance| exchange_balance.exchange),
+            start.elapsed(),
+        )
+    });
+    for (exchange, elapsed) in futures::future::join_all(pricing).await {
+        if let Some(timing) = timings
+            .iter_mut()
+            .find(|timing| Some(timing.exchange) == exchange)
+        {
+            timing.pricing = timing.pricing.max(elapsed);
+        }
+    }
+    (holdings, failures, timings)
+}
+
+// Ledger exports fetch and write one window of entries at a time, so memory use stays flat no
+// matter how much history is exported
+const LEDGER_EXPORT_WINDOW_DAYS: i64 = 7;
+
+// Written alongside a ledger export file until the export completes
+#[derive(Serialize, Deserialize)]
+struct LedgerExportProgress {
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    json: bool,
+    completed_through: DateTime<Utc>,
+    bytes_written: u64,
+    pages_fetched: usize,
+    rows_written: usize,
+}
+
+fn write_ledger_entry(
+    writer: &mut dyn std::io::Write,
+    exchange: Exchange,
+    entry: &LedgerEntry,
+    json: bool,
+    first: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        if !first {
+            writeln!(writer, ",")?;
         }
+        write!(writer, "  {}", serde_json::to_string(entry)?)?;
     } else {
-        let token = token.token().unwrap();
+        writeln!(
+            writer,
+            "{},{exchange:?},{},{},{},{},{},{},{}",
+            entry.time.to_rfc3339(),
+            entry.id,
+            entry.reference,
+            entry.kind,
+            entry.asset,
+            entry.staked,
+            entry.amount,
+            entry.fee,
+        )?;
+    }
+    Ok(())
+}
 
-        let lamports = from_tracked_account
-            .last_update_balance
-            .saturating_sub(retain_amount);
+async fn process_exchange_ledger(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    json: bool,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Seek, Write};
+
+    let progress_path = output
+        .as_ref()
+        .map(|output| PathBuf::from(format!("{}.progress", output.display())));
+
+    let mut progress = match &progress_path {
+        Some(progress_path) if progress_path.exists() => {
+            let progress =
+                serde_json::from_str::<LedgerExportProgress>(&fs::read_to_string(progress_path)?)?;
+            if (progress.start_time, progress.end_time, progress.json)
+                != (start_time, end_time, json)
+            {
+                return Err(format!(
+                    "{} belongs to an interrupted export with different arguments. \
+                     Remove it to start over",
+                    progress_path.display()
+                )
+                .into());
+            }
+            eprintln!(
+                "Resuming export after {} ({} rows already written)",
+                progress.completed_through, progress.rows_written
+            );
+            progress
+        }
+        _ => LedgerExportProgress {
+            start_time,
+            end_time,
+            json,
+            completed_through: start_time,
+            bytes_written: 0,
+            pages_fetched: 0,
+            rows_written: 0,
+        },
+    };
 
-        (
-            vec![spl_token::instruction::transfer_checked(
-                &spl_token::id(),
-                &token.ata(&from_address),
-                &token.mint(),
-                &token.ata(&to_address),
-                &from_authority_address,
-                &[],
-                lamports,
-                token.decimals(),
-            )
-            .unwrap()],
-            lamports,
-        )
+    let writer: Box<dyn Write> = match &output {
+        Some(output) => {
+            let mut file = fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(output)?;
+            // Drop anything written after the last completed window
+            file.set_len(progress.bytes_written)?;
+            file.seek(std::io::SeekFrom::End(0))?;
+            Box::new(file)
+        }
+        None => Box::new(std::io::stdout()),
+    };
+    let mut writer = CountingWriter {
+        inner: writer,
+        count: progress.bytes_written,
     };
 
-    if sweep_amount < token.amount(1.) {
-        let msg = format!(
-            "{} has less than {}1 to sweep ({})",
-            from_address,
-            token.symbol(),
-            token.ui_amount(sweep_amount)
-        );
-        return if no_sweep_ok {
-            println!("{msg}");
-            Ok(())
+    if progress.bytes_written == 0 {
+        if json {
+            writeln!(writer, "[")?;
         } else {
-            Err(msg.into())
-        };
+            writeln!(
+                writer,
+                "time,exchange,id,reference,kind,asset,staked,amount,fee"
+            )?;
+        }
     }
 
-    println!("From address: {from_address}");
-    if from_address != from_authority_address {
-        println!("Authority address: {from_authority_address}");
-    }
-    println!("Destination address: {to_address}");
-    println!(
-        "Sweep amount: {}{}",
-        token.symbol(),
-        token.ui_amount(sweep_amount)
-    );
+    while progress.completed_through < end_time {
+        let window_start = progress.completed_through;
+        let window_end =
+            (window_start + chrono::Duration::days(LEDGER_EXPORT_WINDOW_DAYS)).min(end_time);
 
-    let msg = if let Some((
-        transitory_stake_account,
-        sweep_stake_authority_keypair,
-        sweep_stake_address,
-    )) = via_transitory_stake.as_ref()
-    {
-        assert_eq!(to_address, transitory_stake_account.pubkey());
+        for entry in exchange_client
+            .get_ledger(window_start, window_end)
+            .await?
+            .iter()
+            // Consecutive windows share a boundary, keep entries at it for the earlier window only
+            .filter(|entry| entry.time > window_start || window_start == start_time)
+        {
+            write_ledger_entry(
+                &mut writer,
+                exchange,
+                entry,
+                json,
+                progress.rows_written == 0,
+            )?;
+            progress.rows_written += 1;
+        }
+        writer.flush()?;
 
-        let (sweep_stake_authorized, sweep_stake_vote_account_address) =
-            rpc_client_utils::get_stake_authorized(rpc_client, *sweep_stake_address)?;
+        progress.completed_through = window_end;
+        progress.bytes_written = writer.count;
+        progress.pages_fetched += 1;
+        if let Some(progress_path) = &progress_path {
+            fs::write(progress_path, serde_json::to_string(&progress)?)?;
+        }
+        eprint!(
+            "\rPages fetched: {}, rows written: {}",
+            progress.pages_fetched, progress.rows_written
+        );
+    }
+    eprintln!();
 
-        if sweep_stake_authorized.staker != sweep_stake_authority_keypair.pubkey() {
-            return Err("Stake authority mismatch".into());
+    if json {
+        if progress.rows_written > 0 {
+            writeln!(writer)?;
         }
+        writeln!(writer, "]")?;
+    }
+    writer.flush()?;
 
-        instructions.append(&mut vec![
-            system_instruction::allocate(
-                &transitory_stake_account.pubkey(),
-                std::mem::size_of::<solana_sdk::stake::state::StakeState>() as u64,
-            ),
-            system_instruction::assign(
-                &transitory_stake_account.pubkey(),
-                &solana_sdk::stake::program::id(),
-            ),
-            solana_sdk::stake::instruction::initialize(
-                &transitory_stake_account.pubkey(),
-                &sweep_stake_authorized,
-                &solana_sdk::stake::state::Lockup::default(),
-            ),
-            solana_sdk::stake::instruction::delegate_stake(
-                &transitory_stake_account.pubkey(),
-                &sweep_stake_authority_keypair.pubkey(),
-                &sweep_stake_vote_account_address,
-            ),
-        ]);
-        format!(
-            "Sweeping {}{} from {} into {} (via {})",
-            token.symbol(),
-            token.ui_amount(sweep_amount),
-            from_address,
-            sweep_stake_address,
-            to_address
-        )
-    } else {
-        format!(
-            "Sweeping {} from {} into {}",
-            token.ui_amount(sweep_amount),
-            from_address,
-            to_address
-        )
-    };
+    if let Some(progress_path) = &progress_path {
+        if progress_path.exists() {
+            fs::remove_file(progress_path)?;
+        }
+    }
+    Ok(())
+}
 
-    let mut message = Message::new(&instructions, Some(&from_authority_address));
-    message.recent_blockhash = recent_blockhash;
-    assert_eq!(
-        rpc_client.get_fee_for_message(&message)?,
-        num_transaction_signatures * fee_calculator.lamports_per_signature
-    );
+// Tracks the bytes written through it, to record how much of a ledger export is complete
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
 
-    let mut transaction = Transaction::new_unsigned(message);
-    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-    if simulation_result.err.is_some() {
-        return Err(format!("Simulation failure: {simulation_result:?}").into());
+impl<W: std::io::Write> std::io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
     }
 
-    transaction.partial_sign(&signers, recent_blockhash);
-    if let Some((transitory_stake_account, sweep_stake_authority_keypair, ..)) =
-        via_transitory_stake.as_ref()
-    {
-        transaction.try_sign(
-            &[transitory_stake_account, sweep_stake_authority_keypair],
-            recent_blockhash,
-        )?;
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
+}
 
-    let signature = transaction.signatures[0];
-    println!("Transaction signature: {signature}");
-
-    let epoch = rpc_client.get_epoch_info()?.epoch;
-    if let Some((transitory_stake_account, ..)) = via_transitory_stake.as_ref() {
-        db.add_transitory_sweep_stake_address(transitory_stake_account.pubkey(), epoch)?;
+fn print_staking_info(staking_info: &[StakingInfo]) {
+    for info in staking_info {
+        let apr = match (info.apr_low, info.apr_high) {
+            (Some(low), Some(high)) if low != high => format!("{low:.2}-{high:.2}%"),
+            (Some(apr), _) | (_, Some(apr)) => format!("{apr:.2}%"),
+            (None, None) => "-".into(),
+        };
+        let pending = match (info.pending_allocation, info.pending_deallocation) {
+            (true, true) => " (allocation and deallocation pending)",
+            (true, false) => " (allocation pending)",
+            (false, true) => " (deallocation pending)",
+            (false, false) => "",
+        };
+        println!(
+            "{} {} [{}]: {} staked, {} rewarded, APR {apr}{pending}",
+            info.coin, info.lock_type, info.strategy_id, info.allocated, info.rewarded,
+        );
     }
-    db.record_transfer(
-        signature,
-        last_valid_block_height,
-        Some(sweep_amount),
-        from_address,
-        token,
-        to_address,
-        token,
-        LotSelectionMethod::default(),
-        None,
-    )?;
+}
 
-    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-        db.cancel_transfer(signature)?;
-        if let Some((transitory_stake_account, ..)) = via_transitory_stake.as_ref() {
-            db.remove_transitory_sweep_stake_address(transitory_stake_account.pubkey())?;
+async fn wait_for_staking_request(
+    exchange_client: &dyn ExchangeClient,
+    coin: &str,
+    operation: StakingOperation,
+    cancellation: &Cancellation,
+) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let staking_info = exchange_client.get_staking_info(coin).await?;
+        let pending = staking_info.iter().any(|info| match operation {
+            StakingOperation::Allocate => info.pending_allocation,
+            StakingOperation::Deallocate => info.pending_deallocation,
+        });
+        if !pending {
+            print_staking_info(&staking_info);
+            return Ok(());
+        }
+        println!("Waiting for {operation:?} to complete...");
+        if let Err(err) = cancellation.sleep(Duration::from_secs(30)).await {
+            return Err(format!(
+                "{err} waiting for {operation:?} to complete. The request remains submitted"
+            )
+            .into());
         }
-        return Err("Sweep failed".into());
     }
-    println!("Confirming sweep: {signature}");
-    let when = get_signature_date(rpc_client, signature).await?;
-    db.confirm_transfer(signature, when)?;
-
-    notifier.send(&msg).await;
-    println!("{msg}");
-    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_account_split<T: Signers>(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    from_address: Pubkey,
-    amount: Option<u64>,
-    description: Option<String>,
-    lot_selection_method: LotSelectionMethod,
-    lot_numbers: Option<HashSet<usize>>,
-    authority_address: Pubkey,
-    signers: T,
-    into_keypair: Option<Keypair>,
-    if_balance_exceeds: Option<f64>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // TODO: Support splitting two system accounts? Tokens? Otherwise at least error cleanly when it's attempted
-    let token = MaybeToken::SOL(); // TODO: Support splitting tokens one day
+fn process_exchanges(db: &Db, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let configured_exchanges = db
+        .get_default_accounts_from_configured_exchanges()
+        .into_iter()
+        .map(|(exchange, _, _)| exchange)
+        .collect::<HashSet<_>>();
 
-    let (recent_blockhash, last_valid_block_height) =
-        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+    if json {
+        let exchanges = Exchange::all()
+            .iter()
+            .map(|exchange| {
+                serde_json::json!({
+                    "exchange": exchange,
+                    "name": exchange.name(),
+                    "configured": configured_exchanges.contains(exchange),
+                    "compiled_in": exchange.is_compiled_in(),
+                    "defunct": exchange.is_defunct(),
+                    "capabilities": exchange.capabilities(),
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&exchanges)?);
+        return Ok(());
+    }
 
-    let into_keypair = into_keypair.unwrap_or_else(Keypair::new);
-    if db.get_account(into_keypair.pubkey(), token).is_some() {
-        return Err(format!(
-            "Account {} ({}) already exists",
-            into_keypair.pubkey(),
-            token
-        )
-        .into());
+    for exchange in Exchange::all() {
+        let status = if exchange.is_defunct() {
+            "defunct"
+        } else if !exchange.is_compiled_in() {
+            "not compiled in"
+        } else if configured_exchanges.contains(exchange) {
+            "configured"
+        } else {
+            "not configured"
+        };
+        let capabilities = exchange
+            .capabilities()
+            .iter()
+            .filter(|(_, supported)| *supported)
+            .map(|(capability, _)| capability)
+            .collect::<Vec<_>>();
+
+        println!(
+            "{:<10} {:<15} {}",
+            exchange.name(),
+            status,
+            if capabilities.is_empty() {
+                "-".to_string()
+            } else {
+                capabilities.join(", ")
+            }
+        );
     }
+    Ok(())
+}
 
-    let from_account = db
-        .get_account(from_address, MaybeToken::SOL())
-        .ok_or_else(|| format!("SOL account does not exist for {from_address}"))?;
+async fn process_exchanges_token_support(
+    db: &Db,
+    token: MaybeToken,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut token_supports = vec![];
+    for (exchange, exchange_client) in configured_exchange_clients(db, |_| true)? {
+        match exchange_client.supports_token(token).await {
+            Ok(token_support) => token_supports.push((exchange, token_support)),
+            Err(err) => eprintln!("Skipping {exchange:?}: {err}"),
+        }
+    }
 
-    let (split_all, amount, description) = match amount {
-        None => (
-            true,
-            from_account.last_update_balance,
-            description.unwrap_or(from_account.description),
-        ),
-        Some(amount) => (
-            false,
-            amount,
-            description.unwrap_or_else(|| format!("Split at {}", Local::now())),
-        ),
-    };
+    if json {
+        let token_supports = token_supports
+            .into_iter()
+            .map(|(exchange, token_support)| {
+                serde_json::json!({
+                    "exchange": exchange,
+                    "token": token.to_string(),
+                    "support": token_support,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&token_supports)?);
+        return Ok(());
+    }
 
-    if let Some(if_balance_exceeds) = if_balance_exceeds {
-        if token.ui_amount(amount) < if_balance_exceeds {
+    let yes_no = |enabled| if enabled { "yes" } else { "no" };
+    for (exchange, token_support) in token_supports {
+        if token_support.listed {
             println!(
-                "Split declined because {:?} balance is less than {}",
-                from_address,
-                token.format_ui_amount(if_balance_exceeds)
+                "{:<10} {token} deposits: {}, withdrawals: {}",
+                exchange.name(),
+                yes_no(token_support.deposits_enabled),
+                yes_no(token_support.withdrawals_enabled),
             );
-            return Ok(());
+        } else {
+            println!("{:<10} {token} not listed", exchange.name());
         }
     }
+    Ok(())
+}
 
-    let instructions = solana_sdk::stake::instruction::split(
-        &from_address,
-        &authority_address,
-        amount,
-        &into_keypair.pubkey(),
-    );
-
-    let message = Message::new(&instructions, Some(&authority_address));
-
-    let mut transaction = Transaction::new_unsigned(message);
-    transaction.message.recent_blockhash = recent_blockhash;
-    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-    if simulation_result.err.is_some() {
-        return Err(format!("Simulation failure: {simulation_result:?}").into());
+async fn process_conformance(
+    exchange: Exchange,
+    recordings: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !recordings.is_dir() {
+        return Err(
+            ConfigurationError(format!("No recordings found in {}", recordings.display())).into(),
+        );
     }
 
-    println!(
-        "Splitting {} from {} into {}",
-        token.ui_amount(amount),
-        from_address,
-        into_keypair.pubkey(),
-    );
-
-    transaction.partial_sign(&signers, recent_blockhash);
-    transaction.try_sign(&[&into_keypair], recent_blockhash)?;
-
-    let signature = transaction.signatures[0];
-    println!("Transaction signature: {signature}");
-
-    let epoch = rpc_client.get_epoch_info()?.epoch;
-    db.add_account(TrackedAccount {
-        address: into_keypair.pubkey(),
-        token,
-        description,
-        last_update_epoch: epoch.saturating_sub(1),
-        last_update_balance: 0,
-        lots: vec![],
-        no_sync: from_account.no_sync,
-    })?;
-    db.record_transfer(
-        signature,
-        last_valid_block_height,
-        Some(amount),
-        from_address,
-        token,
-        into_keypair.pubkey(),
-        token,
-        lot_selection_method,
-        lot_numbers,
-    )?;
+    let outcomes = conformance::run(exchange, recordings).await;
+    let mut failed = 0;
+    for (check, outcome) in &outcomes {
+        match outcome {
+            CheckOutcome::Passed => println!("{:<22} passed", check.to_string()),
+            CheckOutcome::Failed(err) => {
+                failed += 1;
+                println!("{:<22} FAILED: {err}", check.to_string());
+            }
+            CheckOutcome::Skipped => println!("{:<22} skipped, not required", check.to_string()),
+        }
+    }
 
-    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-        db.cancel_transfer(signature)?;
-        db.remove_account(into_keypair.pubkey(), MaybeToken::SOL())?;
-        return Err("Split failed".into());
+    let skipped = outcomes
+        .iter()
+        .filter(|(_, outcome)| matches!(outcome, CheckOutcome::Skipped))
+        .count();
+    if skipped == outcomes.len() {
+        return Err(format!("No checks recorded in {}", recordings.display()).into());
     }
-    println!("Split confirmed: {signature}");
-    let when = get_signature_date(rpc_client, signature).await?;
-    db.confirm_transfer(signature, when)?;
-    if split_all {
-        // TODO: This `remove_account` is racy and won't work in all cases. Consider plumbing the
-        // removal through `confirm_transfer` instead
-        let from_account = db.get_account(from_address, MaybeToken::SOL()).unwrap();
-        assert!(from_account.lots.is_empty());
-        db.remove_account(from_address, MaybeToken::SOL())?;
+    if failed > 0 {
+        return Err(format!("{failed} of {} checks failed", outcomes.len() - skipped).into());
     }
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_account_redelegate<T: Signers>(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    from_address: Pubkey,
-    vote_account_address: Pubkey,
-    lot_selection_method: LotSelectionMethod,
-    authority_address: Pubkey,
-    signers: &T,
-    into_keypair: Option<Keypair>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let (recent_blockhash, last_valid_block_height) =
-        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+// Exchanges reject signed requests whose timestamps drift further than this
+const MAX_CLOCK_SKEW_MS: i64 = 5_000;
 
-    let minimum_stake_account_balance = rpc_client
-        .get_minimum_balance_for_rent_exemption(solana_sdk::stake::state::StakeState::size_of())?;
+#[derive(Serialize)]
+struct CredentialsHealth {
+    exchange: Exchange,
+    account: String,
+    passed: bool,
+    latency_ms: Option<u128>,
+    clock_skew_ms: Option<i64>,
+    permissions: Option<ApiPermissions>,
+    system_status: Option<SystemStatus>,
+    failures: Vec<String>,
+    warnings: Vec<String>,
+}
 
-    let into_keypair = into_keypair.unwrap_or_else(Keypair::new);
-    if db
-        .get_account(into_keypair.pubkey(), MaybeToken::SOL())
-        .is_some()
-    {
-        return Err(format!(
-            "Account {} ({}) already exists",
-            into_keypair.pubkey(),
-            MaybeToken::SOL()
-        )
-        .into());
+// Names the likely cause of an authentication error, where it can be identified
+fn describe_credentials_error(err: &(dyn std::error::Error + 'static)) -> String {
+    let reason = match err.downcast_ref::<ExchangeError>() {
+        Some(exchange_error) => exchange_error.credentials_failure(),
+        None => {
+            // Errors from exchange SDKs only carry the exchange's message
+            let message = err.to_string().to_ascii_lowercase();
+            if message.contains("invalid key") || message.contains("invalid api-key") {
+                Some("invalid API key")
+            } else if message.contains("invalid signature") {
+                Some("invalid signature, usually a wrong secret")
+            } else if message.contains("invalid nonce") {
+                Some("invalid nonce, from clock skew or a reused key")
+            } else if message.contains("permission denied") {
+                Some("missing permission or IP not whitelisted")
+            } else {
+                None
+            }
+        }
+    };
+    match reason {
+        Some(reason) => format!("{reason} ({err})"),
+        None => err.to_string(),
     }
+}
 
-    let from_account = db
-        .get_account(from_address, MaybeToken::SOL())
-        .ok_or_else(|| format!("SOL account does not exist for {from_address}"))?;
+async fn check_credentials_health(
+    db: &Db,
+    exchange: Exchange,
+    exchange_credentials: ExchangeCredentials,
+    exchange_account: String,
+) -> CredentialsHealth {
+    let mut health = CredentialsHealth {
+        exchange,
+        account: exchange_account,
+        passed: false,
+        latency_ms: None,
+        clock_skew_ms: None,
+        permissions: None,
+        system_status: None,
+        failures: vec![],
+        warnings: vec![],
+    };
 
-    if from_account.last_update_balance < minimum_stake_account_balance * 2 {
-        return Err(format!(
-            "Account {} ({}) has insufficient balance",
-            into_keypair.pubkey(),
-            MaybeToken::SOL()
-        )
-        .into());
+    let exchange_client = match exchange_client_new(exchange, exchange_credentials) {
+        Ok(exchange_client) => exchange_client,
+        Err(err) => {
+            health
+                .failures
+                .push(describe_credentials_error(err.as_ref()));
+            return health;
+        }
+    };
+
+    let start = std::time::Instant::now();
+    match exchange_client.balances().await {
+        Ok(_) => health.latency_ms = Some(start.elapsed().as_millis()),
+        Err(err) => health.failures.push(format!(
+            "{} error: {}",
+            request_metrics::ErrorCategory::of(err.as_ref()).name(),
+            describe_credentials_error(err.as_ref())
+        )),
     }
-    let redelegated_amount = from_account.last_update_balance - minimum_stake_account_balance;
 
-    let instructions = solana_sdk::stake::instruction::redelegate(
-        &from_address,
-        &authority_address,
-        &vote_account_address,
-        &into_keypair.pubkey(),
-    );
+    if let Ok(server_time) = exchange_client.server_time().await {
+        let clock_skew_ms = (Utc::now() - server_time).num_milliseconds();
+        if clock_skew_ms.abs() > MAX_CLOCK_SKEW_MS {
+            health.failures.push(format!(
+                "clock skew of {:.1}s",
+                clock_skew_ms as f64 / 1000.
+            ));
+        }
+        health.clock_skew_ms = Some(clock_skew_ms);
+    }
 
-    let message = Message::new(&instructions, Some(&authority_address));
+    // The credentials are fine even when the exchange isn't, so this only warns
+    if let Ok(system_status) = exchange_client.system_status(None).await {
+        if system_status != SystemStatus::Online {
+            health.warnings.push(
+                ExchangeError::Unavailable {
+                    exchange,
+                    pair: None,
+                    status: system_status,
+                }
+                .to_string(),
+            );
+        }
+        health.system_status = Some(system_status);
+    }
 
-    let mut transaction = Transaction::new_unsigned(message);
-    transaction.message.recent_blockhash = recent_blockhash;
-    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-    if simulation_result.err.is_some() {
-        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    // Withdrawals are only needed from exchange accounts with tracked deposit addresses
+    let deposit_account_description = format!("{exchange:?} {}", health.account);
+    let withdrawals_needed = db
+        .get_accounts()
+        .iter()
+        .any(|account| account.description == deposit_account_description);
+    match exchange_client.api_permissions().await {
+        Ok(permissions) => {
+            if exchange.capabilities().supports_trading && !permissions.trade {
+                health.failures.push("missing trade permission".into());
+            }
+            if withdrawals_needed && !permissions.withdraw {
+                health
+                    .failures
+                    .push("missing withdraw permission, needed for tracked deposits".into());
+            }
+            health.permissions = Some(permissions);
+        }
+        Err(err) => health
+            .warnings
+            .push(format!("permissions not checked: {err}")),
     }
 
-    println!(
-        "Relegating {} to {} via{}",
-        from_address,
-        vote_account_address,
-        into_keypair.pubkey(),
-    );
-
-    transaction.partial_sign(signers, recent_blockhash);
-    transaction.try_sign(&[&into_keypair], recent_blockhash)?;
+    health.passed = health.failures.is_empty();
+    health
+}
 
-    let signature = transaction.signatures[0];
-    println!("Transaction signature: {signature}");
+async fn process_health_check(db: &Db, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let results = futures::future::join_all(db.get_configured_exchange_accounts().into_iter().map(
+        |(exchange, exchange_credentials, exchange_account)| {
+            check_credentials_health(db, exchange, exchange_credentials, exchange_account)
+        },
+    ))
+    .await;
 
-    let epoch = rpc_client.get_epoch_info()?.epoch;
-    db.add_account(TrackedAccount {
-        address: into_keypair.pubkey(),
-        token: MaybeToken::SOL(),
-        description: from_account.description,
-        last_update_epoch: epoch.saturating_sub(1),
-        last_update_balance: 0,
-        lots: vec![],
-        no_sync: None,
-    })?;
-    db.record_transfer(
-        signature,
-        last_valid_block_height,
-        Some(redelegated_amount),
-        from_address,
-        MaybeToken::SOL(),
-        into_keypair.pubkey(),
-        MaybeToken::SOL(),
-        lot_selection_method,
-        None,
-    )?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        let yes_no = |permission: Option<bool>| match permission {
+            Some(true) => "yes",
+            Some(false) => "no",
+            None => "?",
+        };
+        println!(
+            "{:<10} {:<12} {:<6} {:>9} {:>11} {:<11} {:<6} {:<8} Details",
+            "Exchange", "Account", "Status", "Latency", "Clock skew", "System", "Trade", "Withdraw"
+        );
+        for health in &results {
+            println!(
+                "{:<10} {:<12} {:<6} {:>9} {:>11} {:<11} {:<6} {:<8} {}",
+                health.exchange.name(),
+                if health.account.is_empty() {
+                    "(default)"
+                } else {
+                    &health.account
+                },
+                if health.passed { "pass" } else { "FAIL" },
+                health
+                    .latency_ms
+                    .map(|latency_ms| format!("{latency_ms}ms"))
+                    .unwrap_or_else(|| "-".into()),
+                health
+                    .clock_skew_ms
+                    .map(|clock_skew_ms| format!("{clock_skew_ms}ms"))
+                    .unwrap_or_else(|| "-".into()),
+                health
+                    .system_status
+                    .map(|system_status| format!("{system_status:?}"))
+                    .unwrap_or_else(|| "-".into()),
+                yes_no(
+                    health
+                        .permissions
+                        .as_ref()
+                        .map(|permissions| permissions.trade)
+                ),
+                yes_no(
+                    health
+                        .permissions
+                        .as_ref()
+                        .map(|permissions| permissions.withdraw)
+                ),
+                health
+                    .failures
+                    .iter()
+                    .chain(&health.warnings)
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join("; "),
+            );
+        }
+    }
 
-    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-        db.cancel_transfer(signature)?;
-        db.remove_account(into_keypair.pubkey(), MaybeToken::SOL())?;
-        return Err("Redelegate failed".into());
+    for health in &results {
+        let _ = record_exchange_query(
+            health.exchange,
+            &health.account,
+            if health.passed {
+                Ok(())
+            } else {
+                Err(health.failures.join("; ").into())
+            },
+        );
     }
-    println!("Redelegation confirmed: {signature}");
-    let when = get_signature_date(rpc_client, signature).await?;
-    db.confirm_transfer(signature, when)?;
 
+    let failed = results.iter().filter(|health| !health.passed).count();
+    if results.is_empty() {
+        return Err("No exchange credentials configured".into());
+    }
+    if failed > 0 {
+        return Err(format!("{failed} of {} exchange accounts failed", results.len()).into());
+    }
     Ok(())
 }
 
-async fn process_account_sync(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    address: Option<Pubkey>,
-    max_epochs_to_process: Option<u64>,
-    reconcile_no_sync_account_balances: bool,
-    notifier: &Notifier,
-) -> Result<(), Box<dyn std::error::Error>> {
-    process_account_sync_pending_transfers(db, rpc_client).await?;
-    process_account_sync_sweep(db, rpc_client, notifier).await?;
+fn print_request_metrics(hours: i64) {
+    let mut exchanges = request_metrics::recent(Utc::now() - chrono::Duration::hours(hours))
+        .into_iter()
+        .collect::<Vec<_>>();
+    exchanges.sort_by_key(|(exchange, _)| exchange.name());
 
-    let (mut accounts, mut no_sync_accounts): (_, Vec<_>) = match address {
-        Some(address) => {
-            // sync all tokens for the given address...
-            let accounts = db.get_account_tokens(address);
-            if accounts.is_empty() {
-                return Err(format!("{address} does not exist").into());
-            }
-            accounts
+    println!("Exchange requests over the last {hours}h:");
+    if exchanges.is_empty() {
+        println!("  none recorded");
+        return;
+    }
+    println!(
+        "{:<10} {:<12} {:>8} {:>7} {:>8} {:>9} {:>9}  Errors",
+        "Exchange", "Endpoints", "Requests", "Errors", "Retries", "Mean", "p95"
+    );
+    for (exchange, metrics) in exchanges {
+        for (class, stats) in &metrics.endpoints {
+            println!(
+                "{:<10} {:<12} {:>8} {:>6.1}% {:>8} {:>9} {:>9}  {}",
+                exchange.name(),
+                class.name(),
+                stats.requests,
+                stats.error_rate() * 100.,
+                stats.retries,
+                stats
+                    .mean_latency_ms()
+                    .map(|latency_ms| format!("{latency_ms}ms"))
+                    .unwrap_or_else(|| "-".into()),
+                stats
+                    .latency_quantile_ms(0.95)
+                    .map(|latency_ms| format!("<={latency_ms}ms"))
+                    .unwrap_or_else(|| "-".into()),
+                stats
+                    .errors
+                    .iter()
+                    .map(|(category, count)| format!("{} {count}", category.name()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+        if metrics.rate_limit_waits > 0 {
+            println!(
+                "{:<10} waited for rate budget {} times, {:.1}s in total",
+                exchange.name(),
+                metrics.rate_limit_waits,
+                metrics.rate_limit_wait_ms as f64 / 1000.
+            );
         }
-        None => db.get_accounts(),
     }
-    .into_iter()
-    .partition(|account| !account.no_sync.unwrap_or_default());
-
-    if reconcile_no_sync_account_balances {
-        for account in no_sync_accounts.iter_mut() {
-            if account.lots.is_empty() {
-                continue;
-            }
+}
 
-            let current_balance = account.token.balance(rpc_client, &account.address)?;
+// Columns of the exchange balance export, in this order.  Documented in the README, so only ever
+// add columns at the end
+const EXCHANGE_BALANCE_EXPORT_HEADER: &str =
+    "date,exchange,account,token,quantity,usd_price,usd_value,price_source,price_estimated";
 
-            match current_balance.cmp(&account.last_update_balance) {
-                std::cmp::Ordering::Less => {
-                    println!(
-                        "\nWarning: {} ({}) balance is less than expected. Actual: {}{}, expected: {}{}\n",
-                        account.address,
-                        account.token,
-                        account.token.symbol(),
-                        account.token.ui_amount(current_balance),
-                        account.token.symbol(),
-                        account.token.ui_amount(account.last_update_balance)
-                    );
-                }
-                std::cmp::Ordering::Greater => {
-                    // sort by lowest basis
-                    account
-                        .lots
-                        .sort_by(|a, b| a.acquisition.price().cmp(&b.acquisition.price()));
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
 
-                    let lowest_basis_lot = &mut account.lots[0];
-                    let additional_balance = current_balance - account.last_update_balance;
-                    lowest_basis_lot.amount += additional_balance;
+// Writes a row per exchange, account and token held to `path`, or with `dated` to a file for
+// `date` in the directory `path`.  Rows of other dates in the file are kept and those of `date`
+// replaced, so exporting again the same day doesn't duplicate them
+fn export_exchange_balances(
+    holdings: &BTreeMap<String, PortfolioHolding>,
+    date: NaiveDate,
+    path: &Path,
+    dated: bool,
+) -> Result<(PathBuf, usize), Box<dyn std::error::Error>> {
+    let date = date.format("%Y-%m-%d").to_string();
+    let path = if dated {
+        fs::create_dir_all(path)?;
+        path.join(format!("exchange-balances-{date}.csv"))
+    } else {
+        path.to_path_buf()
+    };
 
-                    let msg = format!(
-                        "{} ({}): Additional {}{} added",
-                        account.address,
-                        account.token,
-                        account.token.symbol(),
-                        account.token.ui_amount(additional_balance)
-                    );
-                    notifier.send(&msg).await;
-                    println!("{msg}");
+    let date_prefix = format!("{date},");
+    let mut lines = if path.exists() {
+        fs::read_to_string(&path)?
+            .lines()
+            .skip(1)
+            .filter(|line| !line.starts_with(&date_prefix))
+            .map(String::from)
+            .collect::<Vec<_>>()
+    } else {
+        vec![]
+    };
 
-                    account.last_update_balance = current_balance;
-                    db.update_account(account.clone())?;
-                }
-                _ => {}
-            }
+    let mut rows = vec![];
+    for holding in holdings.values() {
+        for balance in &holding.exchange_balances {
+            let usd_price = match holding.usd_coins.get(&balance.asset) {
+                Some(usd_coin) => usd_coin.usd_price.as_ref(),
+                None => holding.usd_price.as_ref(),
+            };
+            rows.push(
+                [
+                    date.clone(),
+                    balance.exchange.name().to_string(),
+                    balance.account.clone(),
+                    balance.asset.clone(),
+                    balance.amount.to_string(),
+                    usd_price
+                        .map(|usd_price| usd_price.price.to_string())
+                        .unwrap_or_default(),
+                    usd_price
+                        .map(|usd_price| (usd_price.price * balance.amount).to_string())
+                        .unwrap_or_default(),
+                    usd_price
+                        .map(|usd_price| usd_price.source.clone())
+                        .unwrap_or_default(),
+                    usd_price
+                        .map(|usd_price| usd_price.estimated.to_string())
+                        .unwrap_or_default(),
+                ]
+                .iter()
+                .map(|field| csv_field(field))
+                .join(","),
+            );
         }
     }
+    rows.sort();
+    let row_count = rows.len();
+    lines.extend(rows);
+
+    let mut contents = format!("{EXCHANGE_BALANCE_EXPORT_HEADER}\n");
+    for line in lines {
+        contents.push_str(&line);
+        contents.push('\n');
+    }
+    fs::write(&path, contents)?;
+    Ok((path, row_count))
+}
 
-    let current_sol_price = MaybeToken::SOL().get_current_price(rpc_client).await?;
+async fn process_portfolio(
+    db: &Db,
+    rpc_client: &RpcClient,
+    currency: &str,
+    min_value: f64,
+    all_subaccounts: bool,
+    cached_fallback: bool,
+    export: Option<(PathBuf, bool)>,
+    json: bool,
+    verbose: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    exchange::check_price_sources().map_err(ConfigurationError)?;
+    let exchange_clients =
+        configured_exchange_clients(db, |capabilities| capabilities.supports_balances)?;
+    let ((mut holdings, failures, timings), elapsed) = timed(get_portfolio(
+        db,
+        rpc_client,
+        &exchange_clients,
+        all_subaccounts,
+        cached_fallback,
+    ))
+    .await;
+
+    // On stderr, so that JSON output stays parseable
+    if verbose {
+        for timing in &timings {
+            eprintln!(
+                "{:?}: balances {}ms, tickers {}ms ({}), pricing {}ms",
+                timing.exchange,
+                timing.balances.as_millis(),
+                timing.tickers.as_millis(),
+                match &timing.ticker_count {
+                    Ok(ticker_count) => format!("{ticker_count} markets"),
+                    Err(err) => format!("unavailable, priced market by market: {err}"),
+                },
+                timing.pricing.as_millis(),
+            );
+        }
+        eprintln!("Portfolio valued in {}ms", elapsed.as_millis());
+    }
 
-    let addresses: Vec<Pubkey> = accounts
-        .iter()
-        .map(|TrackedAccount { address, .. }| *address)
-        .collect::<Vec<_>>();
+    if let Some((path, dated)) = export {
+        let today = Local::now().date().naive_local();
+        let (path, row_count) = export_exchange_balances(&holdings, today, &path, dated)?;
+        if !json {
+            println!(
+                "Exported {row_count} exchange balances to {}",
+                path.display()
+            );
+        }
+    }
 
-    let epoch_info = rpc_client.get_epoch_info()?;
-    let mut stop_epoch = epoch_info.epoch.saturating_sub(1);
+    // Holdings are valued in USD, then converted to the reporting currency
+    let currency_usd_rate = usd_coin_rate(&exchange_clients, currency)
+        .await
+        .ok_or_else(|| format!("Unable to value {currency} in USD"))?;
+    if currency_usd_rate.assumed {
+        eprintln!("Warning: no {currency} rate available, assuming parity with USD");
+    }
+    for holding in holdings.values_mut() {
+        holding.price = holding.price.map(|price| price / currency_usd_rate.rate);
+        holding.value = holding.value.map(|value| value / currency_usd_rate.rate);
+    }
 
-    let start_epoch = accounts
-        .iter()
-        .map(
-            |TrackedAccount {
-                 last_update_epoch, ..
-             }| last_update_epoch,
-        )
-        .min()
-        .unwrap_or(&stop_epoch)
-        + 1;
+    holdings.retain(|_, holding| holding.value.map_or(true, |value| value >= min_value));
+    let total_value = holdings
+        .values()
+        .filter_map(|holding| holding.value)
+        .sum::<f64>();
 
-    if start_epoch > stop_epoch {
-        println!("Processed up to epoch {stop_epoch}");
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "currency": currency,
+                "holdings": holdings,
+                "total_value": total_value,
+                "unavailable": failures,
+            }))?
+        );
         return Ok(());
     }
 
-    if let Some(max_epochs_to_process) = max_epochs_to_process {
-        if max_epochs_to_process == 0 {
-            return Ok(());
-        }
-        stop_epoch = stop_epoch.min(start_epoch.saturating_add(max_epochs_to_process - 1));
+    for failure in &failures {
+        println!("{}", style(failure).yellow());
     }
 
-    // Look for inflationary rewards
-    for epoch in start_epoch..=stop_epoch {
-        let msg = format!("Processing epoch: {epoch}");
-        notifier.send(&msg).await;
-        println!("{msg}");
-
-        let inflation_rewards = rpc_client.get_inflation_reward(&addresses, Some(epoch))?;
-
-        for (inflation_reward, address, account) in
-            itertools::izip!(inflation_rewards, addresses.iter(), accounts.iter_mut(),)
-        {
-            assert_eq!(*address, account.address);
-            if account.last_update_epoch >= epoch {
-                continue;
+    for (asset, holding) in &holdings {
+        println!(
+            "{:<8} {:>20} {:>14} {:>16} {}",
+            asset,
+            holding.amount.separated_string_with_fixed_place(8),
+            holding.price.map_or_else(
+                || "unpriced".into(),
+                |price| format_quote_amount(&price.separated_string_with_fixed_place(4), currency)
+            ),
+            holding.value.map_or_else(
+                || "unpriced".into(),
+                |value| format_quote_amount(&value.separated_string_with_fixed_place(2), currency)
+            ),
+            holding.price_source.as_deref().unwrap_or_default(),
+        );
+        for (location, amount) in &holding.locations {
+            println!(
+                "    {:<12} {}",
+                location,
+                amount.separated_string_with_fixed_place(8)
+            );
+        }
+        for delisting in &holding.delistings {
+            println!(
+                "    {}",
+                style(format!(
+                    "Warning: {}, move it off the exchange before then",
+                    format_delisting(delisting.exchange, &delisting.asset, delisting.delist_time)
+                ))
+                .yellow()
+            );
+        }
+        for (coin, usd_coin) in &holding.usd_coins {
+            if coin != "USD" {
+                println!(
+                    "    {:<12} {} ({})",
+                    coin,
+                    usd_coin.amount.separated_string_with_fixed_place(8),
+                    quote_label(coin, usd_coin.usd_rate)
+                );
             }
+        }
+    }
+    println!(
+        "Total value: {}{}",
+        format_quote_amount(&total_value.separated_string_with_fixed_place(2), currency),
+        if failures.iter().any(|failure| failure.cached_from.is_none()) {
+            " (incomplete, some exchanges are left out)"
+        } else if !failures.is_empty() {
+            " (including cached balances)"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
 
-            if let Some(inflation_reward) = inflation_reward {
-                assert!(!account.token.is_token()); // Only SOL accounts can receive inflationary rewards
-
-                account.last_update_balance += inflation_reward.amount;
+fn balance_snapshot_location(exchange_snapshot: &ExchangeBalanceSnapshot) -> String {
+    if exchange_snapshot.account.is_empty() {
+        format!("{:?}", exchange_snapshot.exchange)
+    } else {
+        format!(
+            "{:?} ({})",
+            exchange_snapshot.exchange, exchange_snapshot.account
+        )
+    }
+}
 
-                let slot = inflation_reward.effective_slot;
-                let (when, price) =
-                    get_block_date_and_price(rpc_client, slot, account.token).await?;
-                let lot = Lot {
-                    lot_number: db.next_lot_number(),
-                    acquisition: LotAcquistion::new(
-                        when,
-                        price,
-                        LotAcquistionKind::EpochReward { epoch, slot },
-                    ),
-                    amount: inflation_reward.amount,
-                };
+fn format_amount_change(change: f64) -> String {
+    format!(
+        "{}{}",
+        if change < 0. { "-" } else { "+" },
+        change.abs().separated_string_with_fixed_place(8)
+    )
+}
 
-                let msg = format!("{}: {}", account.address, account.description);
-                notifier.send(&msg).await;
-                println!("{msg}");
+fn format_usd_change(change: Option<f64>) -> String {
+    match change {
+        Some(change) => format!(
+            "{}${}",
+            if change < 0. { "-" } else { "+" },
+            change.abs().separated_string_with_fixed_place(2)
+        ),
+        None => "unpriced".into(),
+    }
+}
 
-                println_lot(
-                    account.token,
-                    &lot,
-                    Some(current_sol_price),
-                    None,
-                    &mut 0.,
-                    &mut 0.,
-                    &mut false,
-                    &mut 0.,
-                    Some(notifier),
-                    true,
-                )
-                .await;
-                account.lots.push(lot);
+async fn process_balance_snapshot_record(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    exchange::check_price_sources().map_err(ConfigurationError)?;
+    let time = Utc::now();
+    let exchange_accounts = db
+        .get_configured_exchange_accounts()
+        .into_iter()
+        .filter(|(exchange, _, _)| {
+            !exchange.is_defunct() && exchange.capabilities().supports_balances
+        })
+        .map(|(exchange, exchange_credentials, exchange_account)| {
+            (
+                exchange,
+                exchange_account,
+                exchange_client_new(exchange, exchange_credentials),
+            )
+        })
+        .collect::<Vec<_>>();
+    if exchange_accounts.is_empty() {
+        return Err("No exchange credentials configured".into());
+    }
+
+    let results = futures::future::join_all(exchange_accounts.iter().map(
+        |(_, _, exchange_client)| async move {
+            match exchange_client {
+                Ok(exchange_client) => exchange_client.balances().await,
+                Err(err) => Err(err.to_string().into()),
+            }
+        },
+    ))
+    .await;
+
+    // Unreachable exchanges are recorded with their error so the snapshot is marked partial,
+    // rather than looking like their balances went to zero
+    let mut exchanges = vec![];
+    let mut price_clients = vec![];
+    for ((exchange, exchange_account, exchange_client), result) in
+        exchange_accounts.into_iter().zip(results)
+    {
+        let mut exchange_snapshot = ExchangeBalanceSnapshot {
+            exchange,
+            account: exchange_account,
+            error: None,
+            balances: BTreeMap::default(),
+        };
+        match record_exchange_query(exchange, &exchange_snapshot.account, result) {
+            Ok(balances) => {
+                for (coin, balance) in balances {
+                    if balance.total > 0. {
+                        exchange_snapshot.balances.insert(
+                            coin,
+                            SnapshotBalance {
+                                amount: balance.total,
+                                usd_price: None,
+                                price_source: None,
+                            },
+                        );
+                    }
+                }
+            }
+            Err(failure) => {
+                eprintln!("{failure}");
+                exchange_snapshot.error = Some(format!(
+                    "{} error: {}",
+                    failure.category.name(),
+                    failure.error
+                ));
             }
         }
+        if let (None, Ok(exchange_client)) = (&exchange_snapshot.error, exchange_client) {
+            price_clients.push((exchange, exchange_client));
+        }
+        exchanges.push(exchange_snapshot);
     }
 
-    // Look for unexpected balance changes (such as transaction and rent rewards)
-    for account in accounts.iter_mut() {
-        account.last_update_epoch = stop_epoch;
-
-        let current_balance = account.token.balance(rpc_client, &account.address)?;
-        if current_balance < account.last_update_balance {
-            println!(
-                "\nWarning: {} ({}) balance is less than expected. Actual: {}{}, expected: {}{}\n",
-                account.address,
-                account.token,
-                account.token.symbol(),
-                account.token.ui_amount(current_balance),
-                account.token.symbol(),
-                account.token.ui_amount(account.last_update_balance)
-            );
-        } else if current_balance > account.last_update_balance + account.token.amount(0.005) {
-            let slot = epoch_info.absolute_slot;
-            let current_token_price = account.token.get_current_price(rpc_client).await?;
-            let (when, decimal_price) =
-                get_block_date_and_price(rpc_client, slot, account.token).await?;
-            let amount = current_balance - account.last_update_balance;
-
-            let lot = Lot {
-                lot_number: db.next_lot_number(),
-                acquisition: LotAcquistion::new(
-                    when,
-                    decimal_price,
-                    LotAcquistionKind::NotAvailable,
-                ),
-                amount,
-            };
+    let mut usd_prices = HashMap::<String, Option<UsdPrice>>::default();
+    for exchange_snapshot in exchanges.iter_mut() {
+        for (coin, balance) in exchange_snapshot.balances.iter_mut() {
+            if !usd_prices.contains_key(coin) {
+                let usd_price = if exchange::is_usd_equivalent(coin) {
+                    usd_coin_rate_and_exchange(&price_clients, coin)
+                        .await
+                        .map(|(usd_rate, exchange)| UsdPrice::of_usd_coin(coin, usd_rate, exchange))
+                } else {
+                    asset_usd_price(rpc_client, &price_clients, coin).await
+                };
+                usd_prices.insert(coin.clone(), usd_price);
+            }
+            let usd_price = usd_prices[coin].as_ref();
+            balance.usd_price = usd_price.map(|usd_price| usd_price.price);
+            balance.price_source = usd_price.map(|usd_price| usd_price.source.clone());
+        }
+    }
 
-            let msg = format!(
-                "{} ({}): {}",
-                account.address, account.token, account.description
-            );
-            notifier.send(&msg).await;
-            println!("{msg}");
+    let balance_snapshot = BalanceSnapshot { time, exchanges };
+    db.add_balance_snapshot(balance_snapshot.clone())?;
 
-            println_lot(
-                account.token,
-                &lot,
-                Some(current_token_price),
-                None,
-                &mut 0.,
-                &mut 0.,
-                &mut false,
-                &mut 0.,
-                Some(notifier),
-                true,
-            )
-            .await;
-            account.lots.push(lot);
-            account.last_update_balance = current_balance;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&balance_snapshot)?);
+        return Ok(());
+    }
+    println!(
+        "Balance snapshot taken at {}{}",
+        balance_snapshot.time.format("%Y-%m-%d %H:%M UTC"),
+        if balance_snapshot.is_partial() {
+            " (partial)"
+        } else {
+            ""
+        }
+    );
+    for exchange_snapshot in &balance_snapshot.exchanges {
+        let location = balance_snapshot_location(exchange_snapshot);
+        match &exchange_snapshot.error {
+            Some(err) => println!("{location:<20} unreachable: {err}"),
+            None => println!(
+                "{location:<20} {} coins, ${}{}",
+                exchange_snapshot.balances.len(),
+                exchange_snapshot
+                    .balances
+                    .values()
+                    .filter_map(SnapshotBalance::usd_value)
+                    .sum::<f64>()
+                    .separated_string_with_fixed_place(2),
+                if exchange_snapshot
+                    .balances
+                    .values()
+                    .any(|balance| balance.usd_price.is_none())
+                {
+                    " plus unpriced coins"
+                } else {
+                    ""
+                }
+            ),
         }
-
-        db.update_account(account.clone())?;
     }
-
     Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_account_wrap<T: Signers>(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    address: Pubkey,
-    amount: Option<u64>,
-    if_source_balance_exceeds: Option<u64>,
-    lot_selection_method: LotSelectionMethod,
-    lot_numbers: Option<HashSet<usize>>,
-    authority_address: Pubkey,
-    signers: T,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let sol = MaybeToken::SOL();
-    let wsol = Token::wSOL;
-    let wsol_address = wsol.ata(&address);
-
-    let from_account = db
-        .get_account(address, sol)
-        .ok_or_else(|| format!("SOL account does not exist for {address}"))?;
-    let amount = amount.unwrap_or(from_account.last_update_balance);
-
-    if let Some(if_source_balance_exceeds) = if_source_balance_exceeds {
-        if from_account.last_update_balance < if_source_balance_exceeds {
-            println!(
-                "wrap declined because {} balance is less than {}{}",
-                address,
-                sol.symbol(),
-                sol.ui_amount(if_source_balance_exceeds)
-            );
-            return Ok(());
-        }
+fn process_balance_snapshot_list(db: &Db, json: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let balance_snapshots = db.balance_snapshots();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&balance_snapshots)?);
+        return Ok(());
     }
-
-    if amount == 0 {
-        println!("Nothing to wrap");
+    if balance_snapshots.is_empty() {
+        println!("No balance snapshots");
         return Ok(());
     }
+    for (i, balance_snapshot) in balance_snapshots.iter().enumerate() {
+        println!(
+            "{:>4}  {}  ${:>16}  {} exchange accounts{}",
+            i + 1,
+            balance_snapshot.time.format("%Y-%m-%d %H:%M UTC"),
+            balance_snapshot
+                .exchanges
+                .iter()
+                .flat_map(|exchange_snapshot| exchange_snapshot.balances.values())
+                .filter_map(SnapshotBalance::usd_value)
+                .sum::<f64>()
+                .separated_string_with_fixed_place(2),
+            balance_snapshot.exchanges.len(),
+            if balance_snapshot.is_partial() {
+                ", partial"
+            } else {
+                ""
+            }
+        );
+    }
+    Ok(())
+}
 
-    if db.get_account(address, wsol.into()).is_none() {
-        let epoch = rpc_client.get_epoch_info()?.epoch;
-        db.add_account(TrackedAccount {
-            address,
-            token: wsol.into(),
-            description: from_account.description,
-            last_update_epoch: epoch,
-            last_update_balance: 0,
-            lots: vec![],
-            no_sync: None,
-        })?;
+// Selects a snapshot by its number in `balance-snapshot list` or by date.  A date selects the
+// first snapshot taken on or after it for the start of a report, and the last snapshot taken on
+// or before it for the end
+fn select_balance_snapshot<'a>(
+    balance_snapshots: &'a [BalanceSnapshot],
+    selector: &str,
+    is_start: bool,
+) -> Result<&'a BalanceSnapshot, Box<dyn std::error::Error>> {
+    if let Ok(number) = selector.parse::<usize>() {
+        return number
+            .checked_sub(1)
+            .and_then(|i| balance_snapshots.get(i))
+            .ok_or_else(|| format!("No balance snapshot number {number}").into());
     }
 
-    let (recent_blockhash, last_valid_block_height) =
-        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+    let date = naivedate_of(selector)?;
+    let balance_snapshot = if is_start {
+        balance_snapshots
+            .iter()
+            .find(|balance_snapshot| balance_snapshot.time.date().naive_local() >= date)
+    } else {
+        balance_snapshots
+            .iter()
+            .rev()
+            .find(|balance_snapshot| balance_snapshot.time.date().naive_local() <= date)
+    };
+    balance_snapshot.ok_or_else(|| {
+        format!(
+            "No balance snapshot taken {} {date}",
+            if is_start {
+                "on or after"
+            } else {
+                "on or before"
+            }
+        )
+        .into()
+    })
+}
 
-    let mut instructions = vec![];
+// Net ledger movement of one coin between two snapshots, by kind of entry
+#[derive(Debug, Default, Clone, Serialize)]
+struct LedgerFlows {
+    transfers: f64,
+    trades: f64,
+    other: f64,
+}
 
-    // TODO: replace the following block with
-    // `spl_associated_token_account::instruction::create_associated_token_account_idempotent()`
-    // when it ships to mainnet
-    if rpc_client
-        .get_account_with_commitment(&wsol_address, rpc_client.commitment())?
-        .value
-        .is_none()
-    {
-        instructions.push(
-            spl_associated_token_account::instruction::create_associated_token_account_idempotent(
-                &authority_address,
-                &address,
-                &wsol.mint(),
-                &spl_token::id(),
-            ),
-        );
+async fn ledger_flows(
+    db: &Db,
+    exchange: Exchange,
+    exchange_account: &str,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+) -> Result<HashMap<String, LedgerFlows>, Box<dyn std::error::Error>> {
+    let exchange_credentials = db
+        .get_exchange_credentials(exchange, exchange_account)
+        .ok_or_else(|| ConfigurationError(format!("No API key set for {exchange:?}")))?;
+    let exchange_client = exchange_client_new(exchange, exchange_credentials)?;
+
+    let mut flows = HashMap::<String, LedgerFlows>::default();
+    for entry in exchange_client.get_ledger(start_time, end_time).await? {
+        let flow = flows.entry(entry.asset).or_default();
+        let amount = entry.amount - entry.fee;
+        match entry.kind.split('/').next().unwrap_or_default() {
+            "deposit" | "withdrawal" | "transfer" => flow.transfers += amount,
+            "trade" | "spend" | "receive" | "margin" | "rollover" | "settled" => {
+                flow.trades += amount
+            }
+            _ => flow.other += amount,
+        }
     }
-    instructions.extend([
-        system_instruction::transfer(&address, &wsol_address, amount),
-        spl_token::instruction::sync_native(&spl_token::id(), &wsol_address).unwrap(),
-    ]);
+    Ok(flows)
+}
 
-    let message = Message::new(&instructions, Some(&authority_address));
+#[derive(Serialize)]
+struct BalanceChange {
+    location: String,
+    coin: String,
+    amount_before: f64,
+    amount_after: f64,
+    value_before: Option<f64>,
+    value_after: Option<f64>,
+    // Value change from the price moving, on the earlier amount
+    price_effect: Option<f64>,
+    // Value change from the amount changing, at the later price
+    quantity_effect: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ledger_flows: Option<LedgerFlows>,
+}
 
-    let mut transaction = Transaction::new_unsigned(message);
-    transaction.message.recent_blockhash = recent_blockhash;
-    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-    if simulation_result.err.is_some() {
-        return Err(format!("Simulation failure: {simulation_result:?}").into());
+impl BalanceChange {
+    fn value_change(&self) -> Option<f64> {
+        Some(self.value_after? - self.value_before?)
     }
+}
 
-    println!("Wrapping {} for {}", wsol.ui_amount(amount), address);
-
-    transaction.try_sign(&signers, recent_blockhash)?;
-
-    let signature = transaction.signatures[0];
-    println!("Transaction signature: {signature}");
-
-    db.record_transfer(
-        signature,
-        last_valid_block_height,
-        Some(amount),
-        address,
-        sol,
-        address,
-        wsol.into(),
-        lot_selection_method,
-        lot_numbers,
-    )?;
+#[derive(Default, Serialize)]
+struct BalanceChangeTotal {
+    value_change: f64,
+    price_effect: f64,
+    quantity_effect: f64,
+    unpriced: bool,
+}
 
-    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-        db.cancel_transfer(signature)?;
-        return Err("Wrap failed".into());
+impl BalanceChangeTotal {
+    fn add(&mut self, balance_change: &BalanceChange) {
+        match (
+            balance_change.value_change(),
+            balance_change.price_effect,
+            balance_change.quantity_effect,
+        ) {
+            (Some(value_change), Some(price_effect), Some(quantity_effect)) => {
+                self.value_change += value_change;
+                self.price_effect += price_effect;
+                self.quantity_effect += quantity_effect;
+            }
+            _ => self.unpriced = true,
+        }
     }
-    println!("Wrap confirmed: {signature}");
-    let when = get_signature_date(rpc_client, signature).await?;
-    db.confirm_transfer(signature, when)?;
-
-    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn process_account_unwrap<T: Signers>(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    address: Pubkey,
-    amount: Option<u64>,
-    lot_selection_method: LotSelectionMethod,
-    lot_numbers: Option<HashSet<usize>>,
-    authority_address: Pubkey,
-    signers: T,
+async fn process_balance_snapshot_report(
+    db: &Db,
+    from: Option<String>,
+    to: Option<String>,
+    json: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let sol = MaybeToken::SOL();
-    let wsol = Token::wSOL;
-
-    let from_account = db
-        .get_account(address, wsol.into())
-        .ok_or_else(|| format!("Wrapped SOL account does not exist for {address}"))?;
-    let amount = amount.unwrap_or(from_account.last_update_balance);
+    let balance_snapshots = db.balance_snapshots();
+    if balance_snapshots.len() < 2 {
+        return Err("At least two balance snapshots are needed for a report".into());
+    }
 
-    let _to_account = db
-        .get_account(address, sol)
-        .ok_or_else(|| format!("SOL account does not exist for {address}"))?;
+    let to = match to {
+        Some(to) => select_balance_snapshot(&balance_snapshots, &to, false)?,
+        None => balance_snapshots.last().unwrap(),
+    };
+    // By default, the latest snapshot at least a week before `to`
+    let from = match from {
+        Some(from) => select_balance_snapshot(&balance_snapshots, &from, true)?,
+        None => balance_snapshots
+            .iter()
+            .rev()
+            .find(|balance_snapshot| balance_snapshot.time <= to.time - chrono::Duration::days(7))
+            .unwrap_or(&balance_snapshots[0]),
+    };
+    if from.time >= to.time {
+        return Err("The starting snapshot must be earlier than the ending snapshot".into());
+    }
 
-    let (recent_blockhash, last_valid_block_height) =
-        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+    // An account unreachable in either snapshot is left out entirely, as its balances are unknown
+    let mut excluded = vec![];
+    let mut balance_changes = vec![];
+    let mut accounts = from
+        .exchanges
+        .iter()
+        .chain(&to.exchanges)
+        .map(|exchange_snapshot| {
+            (
+                exchange_snapshot.exchange,
+                exchange_snapshot.account.clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    accounts.sort_by_key(|(exchange, account)| (format!("{exchange:?}"), account.clone()));
+    accounts.dedup();
 
-    let ephemeral_token_account = Keypair::new();
+    for (exchange, account) in accounts {
+        let find = |balance_snapshot: &BalanceSnapshot| {
+            balance_snapshot
+                .exchanges
+                .iter()
+                .find(|exchange_snapshot| {
+                    exchange_snapshot.exchange == exchange && exchange_snapshot.account == account
+                })
+                .cloned()
+        };
+        let (before, after) = (find(from), find(to));
+        let location = balance_snapshot_location(before.as_ref().or(after.as_ref()).unwrap());
+        let (before, after) = match (before, after) {
+            (Some(before), Some(after)) if before.error.is_none() && after.error.is_none() => {
+                (before, after)
+            }
+            (before, after) => {
+                let reason = match (before, after) {
+                    (None, _) => "not configured at the start",
+                    (_, None) => "not configured at the end",
+                    (Some(before), _) if before.error.is_some() => "unreachable at the start",
+                    _ => "unreachable at the end",
+                };
+                excluded.push(format!("{location}: {reason}"));
+                continue;
+            }
+        };
 
-    let instructions = [
-        spl_associated_token_account::instruction::create_associated_token_account_idempotent(
-            &authority_address,
-            &ephemeral_token_account.pubkey(),
-            &wsol.mint(),
-            &spl_token::id(),
-        ),
-        spl_token::instruction::transfer_checked(
-            &spl_token::id(),
-            &wsol.ata(&address),
-            &wsol.mint(),
-            &wsol.ata(&ephemeral_token_account.pubkey()),
-            &authority_address,
-            &[],
-            amount,
-            wsol.decimals(),
-        )
-        .unwrap(),
-        spl_token::instruction::close_account(
-            &spl_token::id(),
-            &wsol.ata(&ephemeral_token_account.pubkey()),
-            &address,
-            &ephemeral_token_account.pubkey(),
-            &[],
-        )
-        .unwrap(),
-    ];
+        let flows = if exchange.capabilities().supports_ledger_export {
+            match ledger_flows(db, exchange, &account, from.time, to.time).await {
+                Ok(flows) => Some(flows),
+                Err(err) => {
+                    eprintln!("{location} ledger: {err}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
 
-    let message = Message::new(&instructions, Some(&authority_address));
+        let coins = before
+            .balances
+            .keys()
+            .chain(after.balances.keys())
+            .collect::<BTreeSet<_>>();
+        for coin in coins {
+            let no_balance = SnapshotBalance {
+                amount: 0.,
+                usd_price: None,
+                price_source: None,
+            };
+            let balance_before = before.balances.get(coin).unwrap_or(&no_balance);
+            let balance_after = after.balances.get(coin).unwrap_or(&no_balance);
+            if balance_before == balance_after {
+                continue;
+            }
 
-    let mut transaction = Transaction::new_unsigned(message);
-    transaction.message.recent_blockhash = recent_blockhash;
-    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-    if simulation_result.err.is_some() {
-        return Err(format!("Simulation failure: {simulation_result:?}").into());
+            // A coin appearing or disappearing is valued at the one price available for it
+            let value_of = |balance: &SnapshotBalance| {
+                if balance.amount == 0. {
+                    Some(0.)
+                } else {
+                    balance.usd_value()
+                }
+            };
+            let price_effect = if balance_before.amount == 0. {
+                Some(0.)
+            } else {
+                balance_before.usd_price.zip(balance_after.usd_price).map(
+                    |(price_before, price_after)| {
+                        balance_before.amount * (price_after - price_before)
+                    },
+                )
+            };
+            let quantity_effect = balance_after
+                .usd_price
+                .or(balance_before.usd_price)
+                .map(|price| (balance_after.amount - balance_before.amount) * price);
+
+            balance_changes.push(BalanceChange {
+                location: location.clone(),
+                coin: coin.clone(),
+                amount_before: balance_before.amount,
+                amount_after: balance_after.amount,
+                value_before: value_of(balance_before),
+                value_after: value_of(balance_after),
+                price_effect,
+                quantity_effect,
+                ledger_flows: flows
+                    .as_ref()
+                    .map(|flows| flows.get(coin).cloned().unwrap_or_default()),
+            });
+        }
     }
 
-    println!("Unwrapping {} for {}", wsol.ui_amount(amount), address);
-
-    transaction.partial_sign(&signers, recent_blockhash);
-    transaction.try_sign(&[&ephemeral_token_account], recent_blockhash)?;
+    let mut by_exchange = BTreeMap::<String, BalanceChangeTotal>::default();
+    let mut by_coin = BTreeMap::<String, BalanceChangeTotal>::default();
+    let mut total = BalanceChangeTotal::default();
+    for balance_change in &balance_changes {
+        by_exchange
+            .entry(balance_change.location.clone())
+            .or_default()
+            .add(balance_change);
+        by_coin
+            .entry(balance_change.coin.clone())
+            .or_default()
+            .add(balance_change);
+        total.add(balance_change);
+    }
 
-    let signature = transaction.signatures[0];
-    println!("Transaction signature: {signature}");
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "from": from.time,
+                "to": to.time,
+                "excluded": excluded,
+                "changes": balance_changes,
+                "by_exchange": by_exchange,
+                "by_coin": by_coin,
+                "total": total,
+            }))?
+        );
+        return Ok(());
+    }
 
-    db.record_transfer(
-        signature,
-        last_valid_block_height,
-        Some(amount),
-        address,
-        wsol.into(),
-        address,
-        sol,
-        lot_selection_method,
-        lot_numbers,
-    )?;
+    println!(
+        "Balance changes from {} to {}",
+        from.time.format("%Y-%m-%d %H:%M UTC"),
+        to.time.format("%Y-%m-%d %H:%M UTC")
+    );
+    for excluded in &excluded {
+        println!("Excluded {excluded}");
+    }
+    println!();
 
-    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-        db.cancel_transfer(signature)?;
-        return Err("Wrap failed".into());
+    if balance_changes.is_empty() {
+        println!("No balance changes");
+        return Ok(());
+    }
+    println!(
+        "{:<20} {:<8} {:>20} {:>16} {:>16} {:>16}",
+        "Location", "Coin", "Amount change", "Value change", "Price effect", "Quantity effect"
+    );
+    for balance_change in &balance_changes {
+        let amount_change = balance_change.amount_after - balance_change.amount_before;
+        println!(
+            "{:<20} {:<8} {:>20} {:>16} {:>16} {:>16}",
+            balance_change.location,
+            balance_change.coin,
+            format_amount_change(amount_change),
+            format_usd_change(balance_change.value_change()),
+            format_usd_change(balance_change.price_effect),
+            format_usd_change(balance_change.quantity_effect),
+        );
+        if balance_change.amount_before == 0. {
+            println!("    new since the start");
+        } else if balance_change.amount_after == 0. {
+            println!("    gone by the end");
+        }
+        if let Some(ledger_flows) = &balance_change.ledger_flows {
+            let unexplained =
+                amount_change - ledger_flows.transfers - ledger_flows.trades - ledger_flows.other;
+            println!(
+                "    transfers {}, trades {}, other {}, unexplained {}",
+                format_amount_change(ledger_flows.transfers),
+                format_amount_change(ledger_flows.trades),
+                format_amount_change(ledger_flows.other),
+                format_amount_change(unexplained),
+            );
+        }
     }
-    println!("Unwrap confirmed: {signature}");
-    let when = get_signature_date(rpc_client, signature).await?;
-    db.confirm_transfer(signature, when)?;
 
-    Ok(())
-}
-async fn process_account_sync_pending_transfers(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let block_height = rpc_client.get_epoch_info()?.block_height;
-    for PendingTransfer {
-        signature,
-        last_valid_block_height,
-        ..
-    } in db.pending_transfers()
-    {
-        let status = rpc_client.get_signature_status_with_commitment_and_history(
-            &signature,
-            rpc_client.commitment(),
-            true,
-        )?;
-        match status {
-            Some(result) => {
-                if result.is_ok() {
-                    println!("Pending transfer confirmed: {signature}");
-                    let when = get_signature_date(rpc_client, signature).await?;
-                    db.confirm_transfer(signature, when)?;
-                } else {
-                    println!("Pending transfer failed with {result:?}: {signature}");
-                    db.cancel_transfer(signature)?;
-                }
-            }
-            None => {
-                if block_height > last_valid_block_height {
-                    println!("Pending transfer cancelled: {signature}");
-                    db.cancel_transfer(signature)?;
-                } else {
-                    println!(
-                        "Transfer pending for at most {} blocks: {}",
-                        last_valid_block_height.saturating_sub(block_height),
-                        signature
-                    );
-                }
-            }
+    let print_total = |name: &str, total: &BalanceChangeTotal| {
+        println!(
+            "{:<29} {:>20} {:>16} {:>16} {:>16}",
+            name,
+            if total.unpriced { "plus unpriced" } else { "" },
+            format_usd_change(Some(total.value_change)),
+            format_usd_change(Some(total.price_effect)),
+            format_usd_change(Some(total.quantity_effect)),
+        );
+    };
+    for (title, totals) in [("By exchange", &by_exchange), ("By coin", &by_coin)] {
+        println!();
+        println!(
+            "{:<29} {:>20} {:>16} {:>16} {:>16}",
+            title, "", "Value change", "Price effect", "Quantity effect"
+        );
+        for (name, total) in totals {
+            print_total(name, total);
         }
     }
+    println!();
+    print_total("Total", &total);
     Ok(())
 }
 
-async fn process_account_sync_sweep(
-    db: &mut Db,
-    rpc_client: &RpcClient,
-    _notifier: &Notifier,
+// How many days either side of a day without a price to search for the nearest one
+// Journal entries listed under each divergent balance
+const BALANCE_DRIFT_NEAREST_ENTRIES: usize = 5;
+
+// Balances and order fees may use an exchange's own asset codes, which the journal and snapshots
+// are compared by their common symbol
+fn journal_coin(exchange: Exchange, coin: &str) -> String {
+    let coin = coin.to_uppercase();
+    match exchange {
+        Exchange::Kraken => exchange::normalize_kraken_asset(&coin).to_string(),
+        _ => coin,
+    }
+}
+
+#[derive(Serialize)]
+struct BalanceDrift {
+    exchange: Exchange,
+    coin: String,
+    snapshot_amount: f64,
+    journal_change: f64,
+    expected: f64,
+    actual: f64,
+    // Entries of the coin with an amount closest to the drift, the likeliest to be wrong or to be
+    // missing a counterpart
+    nearest_entries: Vec<ExchangeJournalEntry>,
+}
+
+impl BalanceDrift {
+    fn drift(&self) -> f64 {
+        self.actual - self.expected
+    }
+}
+
+async fn process_balance_snapshot_drift(
+    db: &Db,
+    from: Option<String>,
+    tolerance: f64,
+    json: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let token = MaybeToken::SOL();
+    let balance_snapshots = db.balance_snapshots();
+    let from = match from {
+        Some(from) => select_balance_snapshot(&balance_snapshots, &from, true)?,
+        None => balance_snapshots
+            .last()
+            .ok_or("No balance snapshots, record one with `sys balance-snapshot record`")?,
+    };
 
-    let transitory_sweep_stake_addresses = db.get_transitory_sweep_stake_addresses();
-    if transitory_sweep_stake_addresses.is_empty() {
-        return Ok(());
+    // The journal doesn't record which subaccount an operation used, so only main accounts are
+    // checked
+    let exchange_accounts = db
+        .get_default_accounts_from_configured_exchanges()
+        .into_iter()
+        .filter(|(exchange, _, _)| {
+            !exchange.is_defunct() && exchange.capabilities().supports_balances
+        })
+        .collect::<Vec<_>>();
+    if exchange_accounts.is_empty() {
+        return Err("No exchange credentials configured".into());
     }
 
-    let sweep_stake_account_info = db
-        .get_sweep_stake_account()
-        .ok_or("Sweep stake account is not configured")?;
+    let mut excluded = vec![];
+    let mut drifts = vec![];
+    for (exchange, exchange_credentials, exchange_account) in exchange_accounts {
+        let exchange_snapshot = match from.exchanges.iter().find(|exchange_snapshot| {
+            exchange_snapshot.exchange == exchange && exchange_snapshot.account == exchange_account
+        }) {
+            Some(exchange_snapshot) if exchange_snapshot.error.is_none() => exchange_snapshot,
+            Some(_) => {
+                excluded.push(format!(
+                    "{exchange:?}: unreachable when the snapshot was taken"
+                ));
+                continue;
+            }
+            None => {
+                excluded.push(format!("{exchange:?}: not in the snapshot"));
+                continue;
+            }
+        };
+        let live_balances = match exchange_client_new(exchange, exchange_credentials) {
+            Ok(exchange_client) => exchange_client.balances().await,
+            Err(err) => Err(err),
+        };
+        let live_balances = match live_balances {
+            Ok(live_balances) => live_balances,
+            Err(err) => {
+                excluded.push(format!("{exchange:?}: {err}"));
+                continue;
+            }
+        };
+        let journal = db.exchange_journal(exchange, from.time);
 
-    let sweep_stake_account_authority_keypair =
-        read_keypair_file(&sweep_stake_account_info.stake_authority).map_err(|err| {
-            format!(
-                "Failed to read {}: {}",
-                sweep_stake_account_info.stake_authority.display(),
-                err
-            )
-        })?;
+        let mut snapshot_amounts = HashMap::<String, f64>::default();
+        for (coin, balance) in &exchange_snapshot.balances {
+            *snapshot_amounts
+                .entry(journal_coin(exchange, coin))
+                .or_default() += balance.amount;
+        }
+        let mut journal_changes = HashMap::<String, f64>::default();
+        for entry in &journal {
+            *journal_changes
+                .entry(journal_coin(exchange, &entry.coin))
+                .or_default() += entry.amount;
+        }
+        let mut actual_amounts = HashMap::<String, f64>::default();
+        for (coin, balance) in live_balances {
+            *actual_amounts
+                .entry(journal_coin(exchange, &coin))
+                .or_default() += balance.total;
+        }
 
-    let sweep_stake_account = rpc_client
-        .get_account_with_commitment(&sweep_stake_account_info.address, rpc_client.commitment())?
-        .value
-        .ok_or("Sweep stake account does not exist")?;
+        let coins = snapshot_amounts
+            .keys()
+            .chain(journal_changes.keys())
+            .chain(actual_amounts.keys())
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        for coin in coins {
+            let snapshot_amount = snapshot_amounts.get(&coin).copied().unwrap_or_default();
+            let journal_change = journal_changes.get(&coin).copied().unwrap_or_default();
+            let actual = actual_amounts.get(&coin).copied().unwrap_or_default();
+            let expected = snapshot_amount + journal_change;
+            let drift = actual - expected;
+            if drift.abs() <= tolerance {
+                continue;
+            }
 
-    let sweep_stake_activation = rpc_client
-        .get_stake_activation(sweep_stake_account_info.address, None)
-        .map_err(|err| {
-            format!(
-                "Unable to get activation information for sweep stake account: {}: {}",
-                sweep_stake_account_info.address, err
-            )
-        })?;
+            let distance = |entry: &ExchangeJournalEntry| (entry.amount.abs() - drift.abs()).abs();
+            let mut nearest_entries = journal
+                .iter()
+                .filter(|entry| journal_coin(exchange, &entry.coin) == coin)
+                .cloned()
+                .collect::<Vec<_>>();
+            nearest_entries.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+            nearest_entries.truncate(BALANCE_DRIFT_NEAREST_ENTRIES);
 
-    if sweep_stake_activation.state != StakeActivationState::Active {
-        println!(
-            "Sweep stake account is not active, unable to continue: {sweep_stake_activation:?}"
-        );
-        return Ok(());
+            drifts.push(BalanceDrift {
+                exchange,
+                coin,
+                snapshot_amount,
+                journal_change,
+                expected,
+                actual,
+                nearest_entries,
+            });
+        }
     }
 
-    for transitory_sweep_stake_address in transitory_sweep_stake_addresses {
-        println!("Considering merging transitory stake {transitory_sweep_stake_address}");
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "from": from.time,
+                "tolerance": tolerance,
+                "excluded": excluded,
+                "drifts": drifts,
+            }))?
+        );
+    } else {
+        println!(
+            "Balances expected from the snapshot taken at {} and the journal since",
+            from.time.format("%Y-%m-%d %H:%M UTC")
+        );
+        for excluded in &excluded {
+            println!("Excluded {excluded}");
+        }
+        println!();
 
-        let transitory_sweep_stake_account = match rpc_client
-            .get_account_with_commitment(&transitory_sweep_stake_address, rpc_client.commitment())?
-            .value
-        {
-            None => {
+        if drifts.is_empty() {
+            println!("All balances within {tolerance} of the journal");
+        } else {
+            println!(
+                "{:<12} {:<8} {:>20} {:>20} {:>20} {:>20} {:>20}",
+                "Exchange", "Coin", "Snapshot", "Journal", "Expected", "Actual", "Drift"
+            );
+        }
+        for drift in &drifts {
+            println!(
+                "{:<12} {:<8} {:>20} {:>20} {:>20} {:>20} {:>20}",
+                format!("{:?}", drift.exchange),
+                drift.coin,
+                drift.snapshot_amount.separated_string_with_fixed_place(8),
+                format_amount_change(drift.journal_change),
+                drift.expected.separated_string_with_fixed_place(8),
+                drift.actual.separated_string_with_fixed_place(8),
+                format_amount_change(drift.drift()),
+            );
+            if drift.nearest_entries.is_empty() {
+                println!("    no journal entries since the snapshot");
+            }
+            for entry in &drift.nearest_entries {
                 println!(
-                    "  Transitory sweep stake account does not exist, removing it: {transitory_sweep_stake_address}"
+                    "    {} {:<10} {:>20} {}",
+                    entry.time.format("%Y-%m-%d %H:%M UTC"),
+                    entry.kind,
+                    format_amount_change(entry.amount),
+                    entry.reference
                 );
+            }
+        }
+    }
 
-                if let Some(tracked_account) = db.get_account(transitory_sweep_stake_address, token)
-                {
-                    if tracked_account.last_update_balance > 0 || !tracked_account.lots.is_empty() {
-                        panic!("Tracked account is not empty: {tracked_account:?}");
+    if drifts.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} balances diverge from the journal by more than {tolerance}",
+            drifts.len()
+        )
+        .into())
+    }
+}
 
-                        // TODO: Simulate a transfer to move the lots into the sweep account in
-                        // this case?
-                        /*
-                        let signature = Signature::default();
-                        db.record_transfer(
-                            signature,
-                            None,
-                            transitory_sweep_stake_address,
-                            sweep_stake_account_info.address,
-                            None,
-                        )?;
-                        db.confirm_transfer(signature)?;
-                        */
-                    }
-                }
-                db.remove_transitory_sweep_stake_address(transitory_sweep_stake_address)?;
-                continue;
-            }
-            Some(x) => x,
+// Account history is backfilled a window at a time, saving progress after each so that an
+// interrupted backfill picks up where it left off
+const BACKFILL_WINDOW_DAYS: i64 = 30;
+
+// Journal entries for `history` of `category`, with any fee as a separate entry
+fn backfill_journal_entries(
+    exchange: Exchange,
+    category: HistoryCategory,
+    history: Vec<AccountHistoryEntry>,
+) -> Vec<ExchangeJournalEntry> {
+    let kind = match category {
+        HistoryCategory::Deposits => ExchangeJournalKind::Deposit,
+        HistoryCategory::Withdrawals => ExchangeJournalKind::Withdrawal,
+        HistoryCategory::Trades => ExchangeJournalKind::Fill,
+        HistoryCategory::Conversions => ExchangeJournalKind::Conversion,
+        HistoryCategory::Interest => ExchangeJournalKind::Interest,
+    };
+
+    let mut entries = vec![];
+    for history_entry in history {
+        let entry = |kind, amount| ExchangeJournalEntry {
+            time: history_entry.time,
+            exchange,
+            kind,
+            coin: history_entry.coin.clone(),
+            amount,
+            reference: history_entry.reference.clone(),
+            backfilled: true,
         };
+        if history_entry.amount != 0. {
+            entries.push(entry(kind, history_entry.amount));
+        }
+        if history_entry.fee != 0. {
+            entries.push(entry(ExchangeJournalKind::Fee, -history_entry.fee));
+        }
+    }
+    entries
+}
 
-        let transient_stake_activation = rpc_client
-            .get_stake_activation(transitory_sweep_stake_address, None)
-            .map_err(|err| {
-                format!(
-                    "Unable to get activation information for transient stake: {transitory_sweep_stake_address}: {err}"
-                )
-            })?;
+async fn backfill_exchange_history(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    start: DateTime<Utc>,
+) -> Result<Vec<HistoryBackfill>, Box<dyn std::error::Error>> {
+    // The journal is complete from the first entry recorded as it happened
+    let end = db
+        .exchange_journal(exchange, chrono::MIN_DATETIME)
+        .into_iter()
+        .find(|entry| !entry.backfilled)
+        .map(|entry| entry.time)
+        .unwrap_or_else(Utc::now);
+
+    let mut backfills = vec![];
+    for &category in HISTORY_CATEGORIES {
+        let mut backfill = db
+            .history_backfill(exchange, category)
+            .unwrap_or(HistoryBackfill {
+                exchange,
+                category,
+                start,
+                end,
+                completed_through: start,
+                records: 0,
+                unavailable: vec![],
+            });
+        // Until the journal records the exchange, each run also backfills what happened since
+        // the last
+        backfill.end = backfill.end.max(end);
+        if backfill.completed() {
+            backfills.push(backfill);
+            continue;
+        }
 
-        if transient_stake_activation.state != StakeActivationState::Active {
-            println!("  Transitory stake is not yet active: {transient_stake_activation:?}");
+        if !exchange
+            .capabilities()
+            .history_categories
+            .contains(&category)
+        {
+            backfill.skip_unavailable(backfill.end, format!("not provided by {exchange:?}"));
+            db.record_history_backfill(&mut backfill, vec![])?;
+            backfills.push(backfill);
             continue;
         }
 
-        if !rpc_client_utils::stake_accounts_have_same_credits_observed(
-            &sweep_stake_account,
-            &transitory_sweep_stake_account,
-        )? {
-            println!(
-                "  Transitory stake credits observed mismatch with sweep stake account: {transitory_sweep_stake_address}"
-            );
+        if let Some(lookback) = exchange_client.history_lookback(category) {
+            let earliest = (Utc::now() - lookback).min(backfill.end);
+            if backfill.completed_through < earliest {
+                backfill.skip_unavailable(
+                    earliest,
+                    format!(
+                        "older than the {} days {exchange:?} serves",
+                        lookback.num_days()
+                    ),
+                );
+                db.record_history_backfill(&mut backfill, vec![])?;
+            }
+        }
+
+        if backfill.completed() {
+            backfills.push(backfill);
             continue;
         }
-        println!("  Merging into sweep stake account");
+        while !backfill.completed() {
+            let window_start = backfill.completed_through;
+            let window_end =
+                (window_start + chrono::Duration::days(BACKFILL_WINDOW_DAYS)).min(backfill.end);
+            let history = exchange_client
+                .account_history(category, window_start, window_end)
+                .await?
+                .into_iter()
+                // Consecutive windows share a boundary, keep entries at it for the later window
+                .filter(|entry| entry.time >= window_start && entry.time < window_end)
+                .collect();
+
+            backfill.completed_through = window_end;
+            db.record_history_backfill(
+                &mut backfill,
+                backfill_journal_entries(exchange, category, history),
+            )?;
+            eprint!(
+                "\r{exchange:?} {category}: backfilled through {}, {} records",
+                window_end.date().naive_local(),
+                backfill.records
+            );
+        }
+        eprintln!();
+        backfills.push(backfill);
+    }
+    Ok(backfills)
+}
 
-        let message = Message::new(
-            &solana_sdk::stake::instruction::merge(
-                &sweep_stake_account_info.address,
-                &transitory_sweep_stake_address,
-                &sweep_stake_account_authority_keypair.pubkey(),
-            ),
-            Some(&sweep_stake_account_authority_keypair.pubkey()),
-        );
-        let mut transaction = Transaction::new_unsigned(message);
+// Fetches every cached or pinned deposit address afresh, refreshing the cache, and reports those
+// the exchange now reports differently.  Fails if any changed or couldn't be fetched
+async fn process_verify_deposit_addresses(db: &mut Db) -> Result<(), Box<dyn std::error::Error>> {
+    let mut deposit_addresses = db
+        .cached_deposit_addresses()
+        .into_iter()
+        .map(|cached| (cached.exchange, cached.account, cached.token))
+        .chain(
+            db.pinned_deposit_addresses()
+                .into_iter()
+                .map(|pinned| (pinned.exchange, pinned.account, pinned.token)),
+        )
+        .unique()
+        .collect::<Vec<_>>();
+    deposit_addresses.sort_by_key(|(exchange, account, token)| {
+        (format!("{exchange:?}"), account.clone(), token.to_string())
+    });
+    if deposit_addresses.is_empty() {
+        println!("No deposit addresses cached or pinned");
+        return Ok(());
+    }
 
-        let (recent_blockhash, last_valid_block_height) =
-            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+    let mut changed = 0;
+    let mut unverified = 0;
+    for (exchange, account, token) in deposit_addresses {
+        let location = if account.is_empty() {
+            format!("{exchange:?} {token}")
+        } else {
+            format!("{exchange:?} ({account}) {token}")
+        };
+        let exchange_credentials = match db.get_exchange_credentials(exchange, &account) {
+            Some(exchange_credentials) => exchange_credentials,
+            None => {
+                db.uncache_deposit_addresses(exchange, &account)?;
+                println!("{location}: no API key set, not verified");
+                unverified += 1;
+                continue;
+            }
+        };
+        let deposit_address = match exchange_client_new(exchange, exchange_credentials)?
+            .deposit_address(token)
+            .await
+        {
+            Ok(deposit_address) => deposit_address,
+            Err(err) => {
+                println!(
+                    "{}",
+                    style(format!("{location}: unable to verify: {err}")).yellow()
+                );
+                unverified += 1;
+                continue;
+            }
+        };
 
-        transaction.message.recent_blockhash = recent_blockhash;
-        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
-        if simulation_result.err.is_some() {
-            return Err(format!("Simulation failure: {simulation_result:?}").into());
+        let cached = db.cached_deposit_address(exchange, &account, token);
+        let pinned = db.pinned_deposit_address(exchange, &account, token);
+        db.cache_deposit_address(exchange, &account, token, deposit_address)?;
+        match (cached, pinned) {
+            (_, Some(pinned)) if pinned.address != deposit_address => {
+                changed += 1;
+                println!(
+                    "{}",
+                    style(format!(
+                        "{location}: {deposit_address} CHANGED, {} was pinned {}. Deposits are \
+                         refused until the new address is confirmed with the exchange and \
+                         accepted with `sys {} address {token} --accept {deposit_address}`",
+                        pinned.address,
+                        HumanTime::from(pinned.pinned_at),
+                        exchange.name(),
+                    ))
+                    .red()
+                );
+            }
+            (Some(cached), _) if cached.address != deposit_address => {
+                changed += 1;
+                println!(
+                    "{}",
+                    style(format!(
+                        "{location}: {deposit_address} CHANGED, {} was cached {}",
+                        cached.address,
+                        HumanTime::from(cached.verified_at),
+                    ))
+                    .red()
+                );
+            }
+            _ => println!("{location}: {deposit_address} unchanged"),
         }
+    }
 
-        transaction.sign(&[&sweep_stake_account_authority_keypair], recent_blockhash);
+    match (changed, unverified) {
+        (0, 0) => Ok(()),
+        (0, unverified) => Err(format!("{unverified} deposit addresses not verified").into()),
+        (changed, _) => Err(format!("{changed} deposit addresses changed").into()),
+    }
+}
 
-        let signature = transaction.signatures[0];
-        println!("Transaction signature: {signature}");
-        db.record_transfer(
-            signature,
-            last_valid_block_height,
-            None,
-            transitory_sweep_stake_address,
-            token,
-            sweep_stake_account_info.address,
-            token,
-            LotSelectionMethod::default(),
-            None,
-        )?;
+async fn process_backfill(
+    db: &mut Db,
+    exchange: Option<Exchange>,
+    start: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exchange_clients = configured_exchange_clients(db, |capabilities| {
+        !capabilities.history_categories.is_empty()
+    })?
+    .into_iter()
+    .filter(|(configured_exchange, _)| {
+        exchange.map_or(true, |exchange| exchange == *configured_exchange)
+    })
+    .collect::<Vec<_>>();
+    if exchange_clients.is_empty() {
+        return Err("No configured exchange provides account history to backfill".into());
+    }
 
-        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
-            db.cancel_transfer(signature)?;
-            return Err("Merge failed".into());
+    let mut backfills = vec![];
+    let mut failures = vec![];
+    for (exchange, exchange_client) in &exchange_clients {
+        let result =
+            backfill_exchange_history(db, *exchange, exchange_client.as_ref(), start).await;
+        match record_exchange_query(*exchange, "", result) {
+            Ok(exchange_backfills) => backfills.extend(exchange_backfills),
+            Err(failure) => failures.push(failure),
         }
-        let when = get_signature_date(rpc_client, signature).await?;
-        db.confirm_transfer(signature, when)?;
-        db.remove_transitory_sweep_stake_address(transitory_sweep_stake_address)?;
+    }
+
+    println!(
+        "{:<10} {:<12} {:>8}  Unavailable",
+        "Exchange", "Category", "Records"
+    );
+    for backfill in &backfills {
+        let unavailable = backfill
+            .unavailable
+            .iter()
+            .map(|unavailable| {
+                format!(
+                    "{} to {}: {}",
+                    unavailable.start.date().naive_local(),
+                    unavailable.end.date().naive_local(),
+                    unavailable.reason
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        println!(
+            "{:<10} {:<12} {:>8}  {}",
+            format!("{:?}", backfill.exchange),
+            backfill.category.to_string(),
+            backfill.records,
+            unavailable
+        );
+    }
+    for failure in &failures {
+        println!("{failure}");
+    }
+    if !failures.is_empty() {
+        println!("Run the backfill again to resume where it stopped");
     }
     Ok(())
 }
 
-fn lot_numbers_of(matches: &ArgMatches<'_>, name: &str) -> Option<HashSet<usize>> {
-    values_t!(matches, name, usize)
-        .ok()
-        .map(|x| x.into_iter().collect())
-}
+const LENDING_INTEREST_PRICE_SEARCH_DAYS: i64 = 7;
 
-fn lot_numbers_arg<'a, 'b>() -> Arg<'a, 'b> {
-    Arg::with_name("lot_numbers")
-        .long("lot")
-        .value_name("LOT NUMBER")
-        .takes_value(true)
-        .multiple(true)
-        .validator(is_parsable::<usize>)
-        .help("Lot to fund the wrap from")
+#[derive(Serialize)]
+struct LendingInterest {
+    date: NaiveDate,
+    exchange: Exchange,
+    coin: String,
+    amount: f64,
+    usd_price: Option<f64>,
+    // The day `usd_price` is from, which differs from `date` when that day's price was unavailable
+    price_date: Option<NaiveDate>,
 }
 
-fn lot_selection_arg<'a, 'b>() -> Arg<'a, 'b> {
-    Arg::with_name("lot_selection")
-        .long("lot-selection")
-        .value_name("METHOD")
-        .takes_value(true)
-        .validator(is_parsable::<LotSelectionMethod>)
-        .default_value(POSSIBLE_LOT_SELECTION_METHOD_VALUES[0])
-        .possible_values(POSSIBLE_LOT_SELECTION_METHOD_VALUES)
-        .help("Lot selection method")
+impl LendingInterest {
+    fn usd_value(&self) -> Option<f64> {
+        self.usd_price.map(|usd_price| usd_price * self.amount)
+    }
+
+    fn priced_from_nearest_day(&self) -> bool {
+        self.price_date
+            .map_or(false, |price_date| price_date != self.date)
+    }
 }
 
-fn is_tax_rate(s: String) -> Result<(), String> {
-    is_parsable::<f64>(s.clone())?;
-    let f = s.parse::<f64>().unwrap();
-    if (0. ..=1.).contains(&f) {
-        Ok(())
+// USD price of `coin` on `when`, from the historical price lookup for tokens and from the lending
+// exchange's own markets for anything else
+async fn lending_coin_usd_price(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    exchange_client: &dyn ExchangeClient,
+    coin: &str,
+    when: NaiveDate,
+) -> Result<f64, Box<dyn std::error::Error>> {
+    if exchange::is_usd_equivalent(coin) {
+        return Ok(1.);
+    }
+
+    let token = if coin == "SOL" {
+        Some(MaybeToken::SOL())
     } else {
-        Err(format!("rate must be in the range [0,1]: {f}"))
+        Token::from_str(coin).ok().map(MaybeToken::from)
+    };
+    if let Some(token) = token {
+        let (price, _source) =
+            get_historical_price_from_exchanges(db, rpc_client, token, when).await?;
+        return price
+            .to_f64()
+            .ok_or_else(|| format!("Invalid {coin} price: {price}").into());
     }
+
+    let trading_pairs = exchange_client.trading_pairs().await?;
+    let pair = exchange::usd_preference_order()
+        .iter()
+        .map(|quote| TradingPair::new(coin, quote))
+        .find(|pair| trading_pairs.contains(pair))
+        .ok_or_else(|| format!("No USD-quoted market available for {coin}"))?;
+    exchange_client.price_on(&pair, when).await
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    solana_logger::setup_with_default("solana=info");
-    let default_db_path = "sell-your-sol";
-    let default_json_rpc_url = "https://api.mainnet-beta.solana.com";
-    let default_when = {
-        let today = Local::now().date();
-        format!("{}/{}/{}", today.year(), today.month(), today.day())
+// Lending of one coin over one week.  The amount offered is summed over the days it stood, so that
+// the rates come out as averages weighted by both amount and time
+#[derive(Default)]
+struct LendingWeek {
+    offered_days: f64,
+    estimated_days: f64,
+    interest: f64,
+}
+
+impl LendingWeek {
+    // Annual percentages, none for a week in which nothing stood on offer
+    fn estimated_rate(&self) -> Option<f64> {
+        (self.offered_days > 0.).then_some(self.estimated_days / self.offered_days)
+    }
+
+    fn realized_rate(&self) -> Option<f64> {
+        (self.offered_days > 0.).then_some(self.interest / self.offered_days * 365. * 100.)
+    }
+}
+
+// Compares the lending rates estimated when offers were made against the interest they earned,
+// by coin and week.  Interest on a day is set against the offer standing at the start of that
+// day, and weeks where the realized rate fell short of the estimate by more than `shortfall`, a
+// fraction of the estimate, are flagged
+async fn process_exchange_lending_performance(
+    db: &Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    weeks: u32,
+    shortfall: f64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let lending_offers = db.lending_offers(exchange);
+    if lending_offers.is_empty() {
+        println!(
+            "No {exchange:?} lending offers recorded yet, `lend` records them as it makes them"
+        );
+        return Ok(());
+    }
+
+    let time_zone = reporting_time_zone()?;
+    // Today's interest is still accruing
+    let end_date = time_zone.date_of(Utc::now()).pred();
+    let week_start = |date: NaiveDate| {
+        date - chrono::Duration::days(date.weekday().num_days_from_monday().into())
     };
-    let exchanges = ["binance", "binanceus", "coinbase", "ftx", "ftxus", "kraken"];
+    let start_date = week_start(end_date) - chrono::Duration::weeks(i64::from(weeks) - 1);
+    let daily_lending_history = exchange_client
+        .get_daily_lending_history(start_date, end_date, time_zone)
+        .await?;
 
-    let app_version = &*app_version();
-    let mut app = App::new(crate_name!())
-        .about(crate_description!())
-        .version(app_version)
-        .setting(AppSettings::SubcommandRequiredElseHelp)
-        .setting(AppSettings::VersionlessSubcommands)
-        .setting(AppSettings::InferSubcommands)
-        .arg(
-            Arg::with_name("db_path")
-                .long("db-path")
-                .value_name("PATH")
-                .takes_value(true)
-                .default_value(default_db_path)
-                .global(true)
-                .help("Database path"),
-        )
-        .arg(
-            Arg::with_name("json_rpc_url")
-                .short("u")
-                .long("url")
-                .value_name("URL")
-                .takes_value(true)
-                .global(true)
-                .validator(is_url_or_moniker)
-                .default_value(default_json_rpc_url)
-                .help("JSON RPC URL for the cluster"),
-        )
-        .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .long("verbose")
-                .takes_value(false)
-                .global(true)
+    let coins = lending_offers
+        .iter()
+        .map(|lending_offer| lending_offer.coin.clone())
+        .unique()
+        .collect::<Vec<_>>();
+    let mut lending_weeks = BTreeMap::<(NaiveDate, String), LendingWeek>::default();
+    for (date, interest) in daily_lending_history {
+        let day_start = time_zone.day_start(date);
+        for coin in &coins {
+            let (amount, estimate_rate) = lending_offers
+                .iter()
+                .rev()
+                .find(|lending_offer| lending_offer.coin == *coin && lending_offer.time < day_start)
+                .map_or((0., 0.), |lending_offer| {
+                    (lending_offer.amount, lending_offer.estimate_rate)
+                });
+            let interest = interest.get(coin).copied().unwrap_or_default();
+            if amount == 0. && interest == 0. {
+                continue;
+            }
+            let lending_week = lending_weeks
+                .entry((week_start(date), coin.clone()))
+                .or_default();
+            lending_week.offered_days += amount;
+            lending_week.estimated_days += amount * estimate_rate;
+            lending_week.interest += interest;
+        }
+    }
+
+    if lending_weeks.is_empty() {
+        println!("Nothing on offer from {start_date} to {end_date}");
+        return Ok(());
+    }
+    println!(
+        "{:<10}  {:<6} {:>20} {:>16} {:>10} {:>10}",
+        "Week", "Coin", "Average offered", "Interest", "Estimated", "Realized"
+    );
+    let mut flagged = 0;
+    for ((week, coin), lending_week) in &lending_weeks {
+        let days = ((end_date - *week).num_days() + 1).min(7) as f64;
+        let format_rate =
+            |rate: Option<f64>| rate.map_or_else(|| "-".into(), |rate| format!("{rate:.1}%"));
+        let short = match (lending_week.estimated_rate(), lending_week.realized_rate()) {
+            (Some(estimated_rate), Some(realized_rate)) => {
+                realized_rate < estimated_rate * (1. - shortfall)
+            }
+            _ => false,
+        };
+        let line = format!(
+            "{week:<10}  {coin:<6} {:>20} {:>16} {:>10} {:>10}",
+            format_coin_amount(exchange, coin, lending_week.offered_days / days, true),
+            format_coin_amount(exchange, coin, lending_week.interest, true),
+            format_rate(lending_week.estimated_rate()),
+            format_rate(lending_week.realized_rate()),
+        );
+        if short {
+            flagged += 1;
+            println!("{}", style(format!("{line}  SHORT")).yellow());
+        } else {
+            println!("{line}");
+        }
+    }
+    if flagged > 0 {
+        println!(
+            "{flagged} of {} weeks realized more than {}% under the estimated rate",
+            lending_weeks.len(),
+            shortfall * 100.
+        );
+    }
+    Ok(())
+}
+
+async fn process_lending_interest(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    start_date: NaiveDate,
+    end_date: NaiveDate,
+    csv: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exchange_clients =
+        configured_exchange_clients(db, |capabilities| capabilities.supports_lending)?;
+    if exchange_clients.is_empty() {
+        return Err("No configured exchange supports lending".into());
+    }
+
+    let time_zone = reporting_time_zone()?;
+    let mut lending_interest = vec![];
+    let mut usd_prices = HashMap::<(String, NaiveDate), Option<f64>>::default();
+    for (exchange, exchange_client) in &exchange_clients {
+        let daily_lending_history = match exchange_client
+            .get_daily_lending_history(start_date, end_date, time_zone)
+            .await
+        {
+            Ok(daily_lending_history) => daily_lending_history,
+            Err(err) => {
+                eprintln!("{exchange:?}: {err}");
+                continue;
+            }
+        };
+
+        for (date, proceeds) in daily_lending_history {
+            let mut proceeds = proceeds
+                .into_iter()
+                .filter(|(_, amount)| *amount != 0.)
+                .collect::<Vec<_>>();
+            proceeds.sort_by(|a, b| a.0.cmp(&b.0));
+
+            for (coin, amount) in proceeds {
+                // Closest days first, never looking past today
+                let mut usd_price = None;
+                'search: for offset in 0..=LENDING_INTEREST_PRICE_SEARCH_DAYS {
+                    for when in [
+                        date - chrono::Duration::days(offset),
+                        date + chrono::Duration::days(offset),
+                    ] {
+                        if when > today() {
+                            continue;
+                        }
+                        let price = match usd_prices.get(&(coin.clone(), when)) {
+                            Some(price) => *price,
+                            None => {
+                                let price = lending_coin_usd_price(
+                                    db,
+                                    rpc_client,
+                                    exchange_client.as_ref(),
+                                    &coin,
+                                    when,
+                                )
+                                .await
+                                .ok();
+                                usd_prices.insert((coin.clone(), when), price);
+                                price
+                            }
+                        };
+                        if let Some(price) = price {
+                            usd_price = Some((price, when));
+                            break 'search;
+                        }
+                    }
+                }
+                if usd_price.is_none() {
+                    eprintln!(
+                        "No {coin} price within {LENDING_INTEREST_PRICE_SEARCH_DAYS} days of {date}"
+                    );
+                }
+
+                lending_interest.push(LendingInterest {
+                    date,
+                    exchange: *exchange,
+                    coin,
+                    amount,
+                    usd_price: usd_price.map(|(price, _)| price),
+                    price_date: usd_price.map(|(_, when)| when),
+                });
+            }
+        }
+    }
+
+    if csv {
+        println!("date,exchange,coin,amount,usd_price,price_date,usd_value");
+        for interest in &lending_interest {
+            println!(
+                "{},{:?},{},{},{},{},{}",
+                interest.date,
+                interest.exchange,
+                interest.coin,
+                interest.amount,
+                interest
+                    .usd_price
+                    .map(|usd_price| usd_price.to_string())
+                    .unwrap_or_default(),
+                interest
+                    .price_date
+                    .map(|price_date| price_date.to_string())
+                    .unwrap_or_default(),
+                interest
+                    .usd_value()
+                    .map(|usd_value| usd_value.to_string())
+                    .unwrap_or_default(),
+            );
+        }
+        return Ok(());
+    }
+
+    if lending_interest.is_empty() {
+        println!("No lending interest earned from {start_date} to {end_date}");
+        return Ok(());
+    }
+
+    let mut by_exchange = BTreeMap::<String, f64>::default();
+    let mut by_coin = BTreeMap::<String, (f64, f64)>::default();
+    let mut by_month = BTreeMap::<String, f64>::default();
+    let mut total = 0.;
+    let mut unpriced = false;
+    println!(
+        "{:<10}  {:<10} {:<8} {:>20} {:>14} {:>14}",
+        "Date", "Exchange", "Coin", "Amount", "USD price", "USD value"
+    );
+    for interest in &lending_interest {
+        let exchange = format!("{:?}", interest.exchange);
+        let usd_value = interest.usd_value();
+        println!(
+            "{:<10}  {:<10} {:<8} {:>20} {:>14} {:>14}{}",
+            interest.date,
+            exchange,
+            interest.coin,
+            interest.amount.separated_string_with_fixed_place(8),
+            interest.usd_price.map_or_else(
+                || "unpriced".into(),
+                |usd_price| format!("${}", usd_price.separated_string_with_fixed_place(4))
+            ),
+            usd_value.map_or_else(
+                || "unpriced".into(),
+                |usd_value| format!("${}", usd_value.separated_string_with_fixed_place(2))
+            ),
+            if interest.priced_from_nearest_day() {
+                format!(" *priced on {}", interest.price_date.unwrap())
+            } else {
+                "".into()
+            }
+        );
+
+        let usd_value = usd_value.unwrap_or_else(|| {
+            unpriced = true;
+            0.
+        });
+        *by_exchange.entry(exchange).or_default() += usd_value;
+        let coin_total = by_coin.entry(interest.coin.clone()).or_default();
+        coin_total.0 += interest.amount;
+        coin_total.1 += usd_value;
+        *by_month
+            .entry(interest.date.format("%Y-%m").to_string())
+            .or_default() += usd_value;
+        total += usd_value;
+    }
+
+    println!();
+    println!("By exchange:");
+    for (exchange, usd_value) in &by_exchange {
+        println!(
+            "  {exchange:<10} ${}",
+            usd_value.separated_string_with_fixed_place(2)
+        );
+    }
+    println!("By coin:");
+    for (coin, (amount, usd_value)) in &by_coin {
+        println!(
+            "  {coin:<10} {} (${})",
+            amount.separated_string_with_fixed_place(8),
+            usd_value.separated_string_with_fixed_place(2)
+        );
+    }
+    println!("By month:");
+    for (month, usd_value) in &by_month {
+        println!(
+            "  {month:<10} ${}",
+            usd_value.separated_string_with_fixed_place(2)
+        );
+    }
+    println!(
+        "Total: ${}{}",
+        total.separated_string_with_fixed_place(2),
+        if unpriced {
+            " plus unpriced interest"
+        } else {
+            ""
+        }
+    );
+    if lending_interest
+        .iter()
+        .any(LendingInterest::priced_from_nearest_day)
+    {
+        println!("* No price was available that day, so the nearest day's price was used");
+    }
+    Ok(())
+}
+
+struct RebalanceTrade {
+    exchange: Exchange,
+    token: MaybeToken,
+    pair: TradingPair,
+    side: OrderSide,
+    price: f64,
+    amount: f64,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_rebalance(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    targets: Vec<(String, f64)>,
+    tolerance: f64,
+    slippage_bps: u64,
+    lot_selection_method: LotSelectionMethod,
+    execute: bool,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let exchange_clients = configured_exchange_clients(db, |capabilities| {
+        capabilities.supports_balances && capabilities.supports_trading
+    })?;
+    let (portfolio, failures, _) =
+        get_portfolio(db, rpc_client, &exchange_clients, false, false).await;
+    // Allocations computed without an exchange's balances would trade toward the wrong targets
+    if let Some(failure) = failures.first() {
+        return Err(failure.to_string().into());
+    }
+
+    // Only exchange balances can be traded, so on-chain holdings are excluded from the allocation
+    let exchange_amount = |asset: &str, exchange: Option<Exchange>| {
+        portfolio
+            .get(asset)
+            .map(|holding| {
+                holding
+                    .locations
+                    .iter()
+                    .filter(|(location, _)| match exchange {
+                        Some(exchange) => **location == format!("{exchange:?}"),
+                        None => *location != "On-chain",
+                    })
+                    .map(|(_, amount)| amount)
+                    .sum::<f64>()
+            })
+            .unwrap_or_default()
+    };
+
+    let mut total_value = 0.;
+    let mut prices = HashMap::new();
+    for (asset, _) in &targets {
+        let price = match portfolio.get(asset) {
+            None => 0.,
+            Some(holding) => holding
+                .price
+                .ok_or_else(|| format!("Unable to rebalance unpriced token: {asset}"))?,
+        };
+        prices.insert(asset.clone(), price);
+        total_value += exchange_amount(asset, None) * price;
+    }
+    if total_value <= 0. {
+        return Err("Nothing to rebalance".into());
+    }
+
+    let mut trades = vec![];
+    for (asset, weight) in &targets {
+        if asset == "USD" {
+            continue;
+        }
+        let token = if asset == "SOL" {
+            MaybeToken::SOL()
+        } else {
+            MaybeToken::from(Token::from_str(asset).map_err(|_| format!("Unknown token: {asset}"))?)
+        };
+
+        let current_value = exchange_amount(asset, None) * prices[asset];
+        let drift = current_value - total_value * weight / 100.;
+        println!(
+            "{}: {:.2}% of ${} (target {:.2}%)",
+            asset,
+            current_value / total_value * 100.,
+            total_value.separated_string_with_fixed_place(2),
+            weight
+        );
+        if (drift / total_value * 100.).abs() <= tolerance {
+            continue;
+        }
+
+        let side = if drift > 0. {
+            OrderSide::Sell
+        } else {
+            OrderSide::Buy
+        };
+
+        let mut venues = vec![];
+        for (exchange, exchange_client) in &exchange_clients {
+            let pair = match exchange_client.preferred_usd_pair(token).await {
+                Ok(pair) => pair,
+                Err(_) => continue,
+            };
+            let bid_ask = exchange_client.bid_ask(&pair).await?;
+            let (price, capacity) = match side {
+                OrderSide::Sell => (bid_ask.ask_price, exchange_amount(asset, Some(*exchange))),
+                OrderSide::Buy => {
+                    let quote_available = exchange_client
+                        .balances()
+                        .await?
+                        .get(&pair.quote)
+                        .map(|balance| balance.available)
+                        .unwrap_or_default();
+                    (
+                        bid_ask.bid_price,
+                        quote_available
+                            / (bid_ask.bid_price * (1. + exchange_client.maker_fee_rate())),
+                    )
+                }
+            };
+            venues.push((*exchange, pair, price, capacity));
+        }
+
+        // Fill from the venues with the best price first so that most rebalances need one trade
+        venues.sort_by(|a, b| match side {
+            OrderSide::Sell => b.2.total_cmp(&a.2),
+            OrderSide::Buy => a.2.total_cmp(&b.2),
+        });
+        let mut remaining = drift.abs() / prices[asset];
+        for (exchange, pair, price, capacity) in venues {
+            if remaining <= 0. {
+                break;
+            }
+            let amount = remaining.min(capacity);
+            if amount > 0. {
+                trades.push(RebalanceTrade {
+                    exchange,
+                    token,
+                    pair,
+                    side,
+                    price,
+                    amount,
+                });
+                remaining -= amount;
+            }
+        }
+        if remaining > 0. {
+            println!(
+                "Warning: insufficient exchange balances to {} the remaining {}{}",
+                side.to_string().to_lowercase(),
+                token.symbol(),
+                remaining
+            );
+        }
+    }
+
+    if trades.is_empty() {
+        println!("Allocation is within tolerance, no trades required");
+        return Ok(());
+    }
+
+    for trade in &trades {
+        println!(
+            "{:?}: {} {}{} at {}",
+            trade.exchange,
+            trade.side,
+            trade.token.symbol(),
+            trade.amount,
+            format_quote_amount(&trade.price.to_string(), &trade.pair.quote)
+        );
+    }
+    if !execute {
+        return Ok(());
+    }
+
+    let slippage = slippage_bps as f64 / 10_000.;
+    for trade in trades {
+        let exchange_client = &exchange_clients
+            .iter()
+            .find(|(exchange, _)| *exchange == trade.exchange)
+            .expect("exchange client")
+            .1;
+
+        // The pre-flight checks in the buy/sell helpers re-check balances before each order
+        match trade.side {
+            OrderSide::Sell => {
+                process_exchange_sell(
+                    db,
+                    trade.exchange,
+                    exchange_client.as_ref(),
+                    trade.token,
+                    trade.pair,
+                    OrderAmount::Base(trade.amount),
+                    LimitOrderPrice::AmountOverAsk(0.),
+                    None,
+                    None,
+                    false,
+                    Some(trade.price * (1. - slippage)),
+                    lot_selection_method.clone(),
+                    None,
+                    false,
+                    OrderRounding::default(),
+                    false,
+                    None,
+                    false,
+                    None,
+                    "rebalance",
+                    notifier,
+                )
+                .await?;
+            }
+            OrderSide::Buy => {
+                let bid_ask = exchange_client.bid_ask(&trade.pair).await?;
+                process_exchange_buy(
+                    db,
+                    trade.exchange,
+                    exchange_client.as_ref(),
+                    trade.token,
+                    trade.pair,
+                    Some(OrderAmount::Base(trade.amount)),
+                    LimitOrderPrice::At(bid_ask.bid_price.min(trade.price * (1. + slippage))),
+                    None,
+                    false,
+                    OrderRounding::default(),
+                    false,
+                    None,
+                    false,
+                    "rebalance",
+                    notifier,
+                )
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn parse_exchange_pair(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    symbol: &str,
+) -> Result<TradingPair, Box<dyn std::error::Error>> {
+    match TradingPair::from_exchange_symbol(exchange, symbol) {
+        Ok(pair) => Ok(pair),
+        Err(err) => {
+            let suggestions =
+                exchange::suggest_trading_pairs(symbol, &exchange_client.trading_pairs().await?);
+            if suggestions.is_empty() {
+                Err(err.into())
+            } else {
+                Err(ExchangeError::InvalidPair {
+                    pair: symbol.into(),
+                    suggestions,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+// Market given by `--pair`, otherwise the token's preferred market in the `--quote` currency
+async fn selected_pair(
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    arg_matches: &ArgMatches<'_>,
+    token: MaybeToken,
+) -> Result<TradingPair, Box<dyn std::error::Error>> {
+    match arg_matches.value_of("pair") {
+        Some(pair) => parse_exchange_pair(exchange, exchange_client, pair).await,
+        None => {
+            let quote = arg_matches.value_of("quote").unwrap_or("USD");
+            exchange_client.preferred_pair(token, quote).await
+        }
+    }
+}
+
+// Rounds a limit price to `decimals` places in a dollar or fiat quote.  A crypto-quoted price such
+// as SOL/BTC is left to the pair's tick size, as it's a fraction of a unit
+fn round_limit_price(price: f64, quote: &str, decimals: i32) -> f64 {
+    if is_fiat(quote) || is_usd_equivalent(quote) {
+        let scale = 10_f64.powi(decimals);
+        (price * scale).round() / scale
+    } else {
+        price
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_buy(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: TradingPair,
+    amount: Option<OrderAmount>,
+    price: LimitOrderPrice,
+    if_balance_exceeds: Option<f64>,
+    exact: bool,
+    rounding: OrderRounding,
+    skip_preflight: bool,
+    expiry: Option<DateTime<Utc>>,
+    override_limits: bool,
+    strategy: &str,
+    notifier: &Notifier,
+) -> Result<Option<OrderId>, Box<dyn std::error::Error>> {
+    let bid_ask = exchange_client.bid_ask(&pair).await?;
+    println!(
+        "{} | Ask: {}, Bid: {}, Spread: {:.3}%",
+        pair,
+        format_quote_amount(&bid_ask.ask_price.to_string(), &pair.quote),
+        format_quote_amount(&bid_ask.bid_price.to_string(), &pair.quote),
+        bid_ask.spread() * 100.
+    );
+
+    let deposit_address = exchange_client.deposit_address(token).await?;
+    let deposit_account = db.get_account(deposit_address, token).ok_or_else(|| {
+        format!(
+            "Exchange deposit account does not exist, run `sync` first: {deposit_address} ({token})",
+        )
+    })?;
+
+    let balances = exchange_client.balances().await?;
+    let quote_balance = balances
+        .get(&pair.quote)
+        .cloned()
+        .unwrap_or_default()
+        .available;
+
+    if let Some(if_balance_exceeds) = if_balance_exceeds {
+        if quote_balance < if_balance_exceeds {
+            println!(
+                "Order declined because {exchange:?} available balance is less than {}",
+                format_quote_amount(&if_balance_exceeds.to_string(), &pair.quote)
+            );
+            return Ok(None);
+        }
+    }
+
+    let price = match price {
+        LimitOrderPrice::At(price) => price,
+        LimitOrderPrice::AmountOverAsk(_) => panic!("Bug: AmountOverAsk invalid for a buy order"),
+        LimitOrderPrice::AmountUnderBid(extra) => bid_ask.bid_price - extra,
+    };
+    let price = round_limit_price(price, &pair.quote, 4);
+
+    if price > bid_ask.bid_price {
+        return Err(format!("Order price, {price}, is greater than bid price").into());
+    }
+
+    let order_amount = amount;
+    let amount = match order_amount {
+        None => (quote_balance / price).floor(),
+        Some(order_amount) => order_amount.base_amount(price),
+    };
+    let checks = OrderChecks {
+        rounding: (!exact).then_some(rounding),
+        preflight: !skip_preflight,
+    };
+    let (price, amount) = round_exchange_order(
+        exchange_client,
+        &pair,
+        OrderSide::Buy,
+        price,
+        amount,
+        checks.rounding,
+    )
+    .await?;
+    let rounding_description = match order_amount {
+        Some(order_amount) => {
+            order_amount.check_rounded(&pair, price, amount)?;
+            order_amount.describe_rounding(&pair, price, amount)
+        }
+        None => String::new(),
+    };
+
+    println!(
+        "Placing buy order for {}{} at {}{rounding_description}",
+        token.symbol(),
+        amount,
+        format_quote_amount(&price.to_string(), &pair.quote)
+    );
+
+    let (order_id, client_order_id) = outcome::record(
+        OperationKind::OrderPlaced,
+        describe_order(exchange, &pair, OrderSide::Buy, amount, price) + &rounding_description,
+        place_limited_order(
+            db,
+            exchange,
+            exchange_client,
+            token,
+            &pair,
+            OrderSide::Buy,
+            price,
+            amount,
+            expiry,
+            checks,
+            override_limits,
+            strategy,
+            notifier,
+        )
+        .await,
+        |(order_id, _)| order_id.clone(),
+    )?;
+    let msg = format!(
+        "Order created: {}: {:?} {}{} at {}{rounding_description}, id {}{}",
+        pair,
+        OrderSide::Buy,
+        token.symbol(),
+        amount,
+        format_quote_amount(&price.to_string(), &pair.quote),
+        order_id,
+        format_order_expiry(exchange, expiry),
+    );
+    db.open_order(
+        OrderSide::Buy,
+        deposit_account,
+        exchange,
+        pair.to_exchange_symbol(exchange),
+        price,
+        order_id.clone(),
+        Some(client_order_id),
+        vec![],
+        Some(amount),
+    )?;
+    if let Some(expiry) = expiry {
+        let native_expiry = exchange.capabilities().supports_native_order_expiry;
+        db.set_order_expiry(&order_id, expiry, native_expiry)?;
+    }
+    println!("{msg}");
+    notifier.send(&format!("{exchange:?}: {msg}")).await;
+    Ok(Some(order_id))
+}
+
+fn describe_order(
+    exchange: Exchange,
+    pair: &TradingPair,
+    side: OrderSide,
+    amount: f64,
+    price: f64,
+) -> String {
+    format!(
+        "{exchange:?} {pair}: {side} {amount} at {}",
+        format_quote_amount(&price.to_string(), &pair.quote)
+    )
+}
+
+fn format_order_expiry(exchange: Exchange, expiry: Option<DateTime<Utc>>) -> String {
+    match expiry {
+        Some(expiry) if exchange.capabilities().supports_native_order_expiry => {
+            format!(", expires {expiry} on {exchange:?}")
+        }
+        Some(expiry) => format!(", cancelled by the first sync after {expiry}"),
+        None => String::new(),
+    }
+}
+
+fn unwind_target_progress(
+    db: &Db,
+    name: &str,
+    open_fills: &HashMap<String, f64>,
+) -> Result<(UnwindTarget, UnwindProgress), Box<dyn std::error::Error>> {
+    let target = db
+        .unwind_target(name)
+        .ok_or_else(|| ConfigurationError(format!("No unwind target named {name}")))?;
+    let progress = db.unwind_progress(&target, open_fills);
+    Ok((target, progress))
+}
+
+// Refuses an order that would take the unwind target `name` past its amount were every order
+// placed within it to fill.  The exchange balance doesn't come into it, so funds that arrive from
+// elsewhere during the unwind are never sold as part of it
+fn check_unwind_order(
+    db: &Db,
+    name: &str,
+    exchange: Exchange,
+    token: MaybeToken,
+    amount: f64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (target, progress) = unwind_target_progress(db, name, &HashMap::new())?;
+    if target.exchange != exchange || target.token != token {
+        return Err(ConfigurationError(format!(
+            "Unwind target {name} sells {} on {:?}",
+            target.token, target.exchange
+        ))
+        .into());
+    }
+    if token.amount(amount) > token.amount(progress.uncommitted()) {
+        let symbol = token.symbol();
+        return Err(format!(
+            "Order for {symbol}{amount} would exceed unwind target {name}: {symbol}{} remaining, \
+             of which {symbol}{} is in open orders",
+            progress.remaining(),
+            progress.open,
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_sell(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: TradingPair,
+    amount: OrderAmount,
+    price: LimitOrderPrice,
+    if_balance_exceeds: Option<u64>,
+    if_price_over: Option<f64>,
+    if_price_over_basis: bool,
+    price_floor: Option<f64>,
+    lot_selection_method: LotSelectionMethod,
+    lot_numbers: Option<HashSet<usize>>,
+    exact: bool,
+    rounding: OrderRounding,
+    skip_preflight: bool,
+    expiry: Option<DateTime<Utc>>,
+    override_limits: bool,
+    unwind: Option<&str>,
+    strategy: &str,
+    notifier: &Notifier,
+) -> Result<Option<OrderId>, Box<dyn std::error::Error>> {
+    let bid_ask = exchange_client.bid_ask(&pair).await?;
+    println!(
+        "{} | Ask: {}, Bid: {}, Spread: {:.3}%",
+        pair,
+        format_quote_amount(&bid_ask.ask_price.to_string(), &pair.quote),
+        format_quote_amount(&bid_ask.bid_price.to_string(), &pair.quote),
+        bid_ask.spread() * 100.
+    );
+
+    let deposit_address = exchange_client.deposit_address(token).await?;
+    let mut deposit_account = db.get_account(deposit_address, token).ok_or_else(|| {
+        format!(
+            "Exchange deposit account does not exist, run `sync` first: {deposit_address} ({token})",
+        )
+    })?;
+
+    if let Some(if_balance_exceeds) = if_balance_exceeds {
+        if deposit_account.last_update_balance < if_balance_exceeds {
+            println!(
+                "Order declined because {:?} available balance is less than {}",
+                exchange,
+                token.ui_amount(if_balance_exceeds)
+            );
+            return Ok(None);
+        }
+    }
+
+    let price = match price {
+        LimitOrderPrice::At(price) => price,
+        LimitOrderPrice::AmountOverAsk(extra) => bid_ask.ask_price + extra,
+        LimitOrderPrice::AmountUnderBid(_) => {
+            panic!("Bug: AmountUnderBid invalid for a sell order")
+        }
+    };
+    let mut price = round_limit_price(price, &pair.quote, 2);
+
+    if let Some(if_price_over) = if_price_over {
+        if price <= if_price_over {
+            let msg = format!(
+                "Order declined because price, {}, is not greater than {}",
+                format_quote_amount(&price.to_string(), &pair.quote),
+                format_quote_amount(&if_price_over.to_string(), &pair.quote),
+            );
+            println!("{msg}");
+            notifier.send(&format!("{exchange:?}: {msg}")).await;
+            return Ok(None);
+        }
+    }
+
+    if let Some(price_floor) = price_floor {
+        if price < price_floor {
+            let msg = format!(
+                "Proposed price, {}, is beneath price floor. Adjusting upwards",
+                format_quote_amount(&price.to_string(), &pair.quote)
+            );
+            price = price_floor;
+            println!("{msg}");
+            notifier.send(&format!("{exchange:?}: {msg}")).await;
+        }
+    }
+
+    let order_amount = amount;
+    let checks = OrderChecks {
+        rounding: (!exact).then_some(rounding),
+        preflight: !skip_preflight,
+    };
+    let (price, amount) = round_exchange_order(
+        exchange_client,
+        &pair,
+        OrderSide::Sell,
+        price,
+        order_amount.base_amount(price),
+        checks.rounding,
+    )
+    .await?;
+    order_amount.check_rounded(&pair, price, amount)?;
+    let rounding_description = order_amount.describe_rounding(&pair, price, amount);
+    if let Some(price_floor) = price_floor {
+        if price < price_floor {
+            return Err(format!(
+                "Rounded price, {}, is beneath price floor",
+                format_quote_amount(&price.to_string(), &pair.quote)
+            )
+            .into());
+        }
+    }
+    if let Some(unwind) = unwind {
+        check_unwind_order(db, unwind, exchange, token, amount)?;
+    }
+
+    let order_lots = deposit_account.extract_lots(
+        db,
+        token.amount(amount),
+        lot_selection_method,
+        lot_numbers,
+    )?;
+    if if_price_over_basis {
+        // Lot basis is in USD, so the price is compared in USD rather than the quote currency
+        let quote_usd_rate = quote_usd_rate(exchange_client, &pair.quote).await?;
+        let usd_price = price * quote_usd_rate.rate;
+        if let Some(basis) = order_lots.iter().find_map(|lot| {
+            let basis = lot.acquisition.price();
+            if Decimal::from_f64(usd_price).unwrap() < basis {
+                Some(basis)
+            } else {
+                None
+            }
+        }) {
+            let msg = format!(
+                "Order declined because price, {} (${usd_price:.4}), is less than basis ${basis}",
+                format_quote_amount(&price.to_string(), &pair.quote),
+            );
+            println!("{msg}");
+            notifier.send(&format!("{exchange:?}: {msg}")).await;
+            return Ok(None);
+        }
+    }
+
+    if price < bid_ask.ask_price {
+        return Err("Order price is less than ask price".into());
+    }
+
+    println!(
+        "Placing sell order for {}{} at {}{rounding_description}",
+        token.symbol(),
+        amount,
+        format_quote_amount(&price.to_string(), &pair.quote)
+    );
+    // Lot gains are in USD, so a crypto-quoted price is shown at the quote's current USD value
+    let lot_price = if is_fiat(&pair.quote) || is_usd_equivalent(&pair.quote) {
+        Some(price)
+    } else {
+        quote_usd_rate(exchange_client, &pair.quote)
+            .await
+            .ok()
+            .map(|quote_usd_rate| price * quote_usd_rate.rate)
+    };
+    println!("Lots");
+    for lot in &order_lots {
+        println_lot(
+            deposit_account.token,
+            lot,
+            lot_price.and_then(Decimal::from_f64),
+            None,
+            &mut 0.,
+            &mut 0.,
+            &mut false,
+            &mut 0.,
+            None,
+            true,
+        )
+        .await;
+    }
+
+    let (order_id, client_order_id) = outcome::record(
+        OperationKind::OrderPlaced,
+        describe_order(exchange, &pair, OrderSide::Sell, amount, price) + &rounding_description,
+        place_limited_order(
+            db,
+            exchange,
+            exchange_client,
+            token,
+            &pair,
+            OrderSide::Sell,
+            price,
+            amount,
+            expiry,
+            checks,
+            override_limits,
+            strategy,
+            notifier,
+        )
+        .await,
+        |(order_id, _)| order_id.clone(),
+    )?;
+    if let Some(unwind) = unwind {
+        db.record_unwind_order(unwind, &order_id)?;
+    }
+    let msg = format!(
+        "Order created: {}: {:?} {}{} at {}{rounding_description}, id {}{}",
+        pair,
+        OrderSide::Sell,
+        token.symbol(),
+        amount,
+        format_quote_amount(&price.to_string(), &pair.quote),
+        order_id,
+        format_order_expiry(exchange, expiry),
+    );
+    db.open_order(
+        OrderSide::Sell,
+        deposit_account,
+        exchange,
+        pair.to_exchange_symbol(exchange),
+        price,
+        order_id.clone(),
+        Some(client_order_id),
+        order_lots,
+        None,
+    )?;
+    if let Some(expiry) = expiry {
+        let native_expiry = exchange.capabilities().supports_native_order_expiry;
+        db.set_order_expiry(&order_id, expiry, native_expiry)?;
+    }
+    println!("{msg}");
+    notifier.send(&format!("{exchange:?}: {msg}")).await;
+    Ok(Some(order_id))
+}
+
+/// Distance of a pegged order from the top of book on its own side, away from the spread
+#[derive(Debug, Clone, Copy)]
+enum PegOffset {
+    Ticks(u32),
+    Bps(f64),
+}
+
+impl PegOffset {
+    fn price_offset(&self, price: f64, tick_size: f64) -> f64 {
+        match self {
+            PegOffset::Ticks(ticks) => f64::from(*ticks) * tick_size,
+            PegOffset::Bps(bps) => price * bps / 10_000.,
+        }
+    }
+}
+
+// Open orders in `symbol` that `peg` or `market-make` keep pegged, oldest first
+fn format_delisting(
+    exchange: Exchange,
+    market: impl std::fmt::Display,
+    delist_time: DateTime<Utc>,
+) -> String {
+    format!(
+        "{exchange:?} delists {market} {} ({})",
+        HumanTime::from(delist_time),
+        delist_time.format("%Y-%m-%d %H:%M UTC")
+    )
+}
+
+// Strategies only start on a market that accepts maker orders, and warn when it's about to be
+// delisted
+async fn check_strategy_pair(
+    exchange: Exchange,
+    pair_info: &PairInfo,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    pair_info.check_status(exchange, SystemOperation::PlaceOrder { taker: false })?;
+    if let Some(delist_time) = pair_info.upcoming_delisting() {
+        let msg = format_delisting(exchange, &pair_info.pair, delist_time);
+        println!("{}", style(format!("Warning: {msg}")).yellow());
+        notifier.send(&msg).await;
+    }
+    Ok(())
+}
+
+fn pegged_orders(db: &Db, exchange: Exchange, symbol: &str, side: OrderSide) -> Vec<OpenOrder> {
+    db.open_orders(Some(exchange), Some(side))
+        .into_iter()
+        .filter(|order| order.pegged && order.pair == symbol)
+        .sorted_by_key(|order| order.creation_time)
+        .collect()
+}
+
+// Places a maker order and marks it pegged.  `None` if the order was declined
+#[allow(clippy::too_many_arguments)]
+async fn place_pegged_order(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: &TradingPair,
+    side: OrderSide,
+    amount: f64,
+    price: f64,
+    lot_selection_method: LotSelectionMethod,
+    unwind: Option<&str>,
+    strategy: &str,
+    notifier: &Notifier,
+) -> Result<Option<OpenOrder>, Box<dyn std::error::Error>> {
+    let order_id = match side {
+        OrderSide::Sell => {
+            process_exchange_sell(
+                db,
+                exchange,
+                exchange_client,
+                token,
+                pair.clone(),
+                OrderAmount::Base(amount),
+                LimitOrderPrice::At(price),
+                None,
+                None,
+                false,
+                None,
+                lot_selection_method,
+                None,
+                false,
+                OrderRounding::default(),
+                false,
+                None,
+                false,
+                unwind,
+                strategy,
+                notifier,
+            )
+            .await?
+        }
+        OrderSide::Buy => {
+            process_exchange_buy(
+                db,
+                exchange,
+                exchange_client,
+                token,
+                pair.clone(),
+                Some(OrderAmount::Base(amount)),
+                LimitOrderPrice::At(price),
+                None,
+                false,
+                OrderRounding::default(),
+                false,
+                None,
+                false,
+                strategy,
+                notifier,
+            )
+            .await?
+        }
+    };
+    let order_id = match order_id {
+        Some(order_id) => order_id,
+        None => return Ok(None),
+    };
+    db.set_order_pegged(&order_id)?;
+    Ok(
+        pegged_orders(db, exchange, &pair.to_exchange_symbol(exchange), side)
+            .into_iter()
+            .find(|order| order.order_id == order_id),
+    )
+}
+
+enum RepricedOrder {
+    // Amended in place
+    Moved(OpenOrder),
+    // Cancelled for the caller to replace, with its final status
+    Cancelled(OrderStatus),
+}
+
+// Moves a pegged order to `price`, in place where the exchange can amend orders
+async fn reprice_pegged_order(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    pair: &TradingPair,
+    order: OpenOrder,
+    price: f64,
+) -> Result<RepricedOrder, Box<dyn std::error::Error>> {
+    if !exchange.capabilities().supports_order_amendment {
+        return Ok(RepricedOrder::Cancelled(
+            cancel_open_order(db, exchange_client, pair, &order).await?,
+        ));
+    }
+
+    match managed_order_id(db, &order) {
+        Some(id) => {
+            managed_order::amend(db, exchange_client, &id, price).await?;
+        }
+        None => {
+            exchange_client
+                .amend_order(pair, &order.order_id, price)
+                .await?
+        }
+    }
+    db.update_order_price(&order.order_id, price)?;
+    println!(
+        "Moved order {} from {} to {}",
+        order.order_id,
+        format_quote_amount(&order.price.to_string(), &pair.quote),
+        format_quote_amount(&price.to_string(), &pair.quote),
+    );
+    Ok(RepricedOrder::Moved(OpenOrder { price, ..order }))
+}
+
+// A pegged order the exchange cancelled on its own, for example by self-trade prevention or in
+// cancel-only mode, stops the peg with an alert rather than being replaced into the same conditions
+async fn check_pegged_order_cancellation(
+    exchange: Exchange,
+    order: &OpenOrder,
+    order_status: &OrderStatus,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match order_status.exchange_cancel_reason() {
+        Some(reason) => {
+            let msg = format!(
+                "Peg stopped: order {} was cancelled by the exchange: {reason}",
+                order.order_id
+            );
+            notifier.send(&format!("{exchange:?}: {msg}")).await;
+            Err(msg.into())
+        }
+        None => Ok(()),
+    }
+}
+
+// Whether `order` has drifted more than `tolerance_ticks` from `target_price`.  An order at the top
+// of book sets the price it's pegged to, so it never counts as drifted
+fn pegged_order_drifted(
+    order: &OpenOrder,
+    bid_ask: &BidAsk,
+    target_price: f64,
+    tick_size: f64,
+    tolerance_ticks: u32,
+) -> bool {
+    let at_top_of_book = match order.side {
+        OrderSide::Sell => order.price <= bid_ask.ask_price,
+        OrderSide::Buy => order.price >= bid_ask.bid_price,
+    };
+    let drift_ticks = ((order.price - target_price).abs() / tick_size).round();
+    !at_top_of_book && drift_ticks > f64::from(tolerance_ticks)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_peg(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: TradingPair,
+    side: OrderSide,
+    amount: f64,
+    offset: PegOffset,
+    tolerance_ticks: u32,
+    price_limit: Option<f64>,
+    lot_selection_method: LotSelectionMethod,
+    interval: Duration,
+    unwind: Option<&str>,
+    rpc_client: &RpcClient,
+    cancellation: &Cancellation,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let symbol = pair.to_exchange_symbol(exchange);
+    let pair_info = exchange_client.pair_info(&pair).await?;
+    let tick_size = pair_info
+        .tick_size
+        .ok_or_else(|| format!("{exchange:?} does not report a tick size for {pair}"))?;
+    check_strategy_pair(exchange, &pair_info, notifier).await?;
+
+    // A previous run may have been interrupted with its order still resting.  Adopt the newest and
+    // cancel any others, so there is never more than one
+    let mut resumed_orders = pegged_orders(db, exchange, &symbol, side);
+    if let Some(order) = resumed_orders.pop() {
+        println!("Resuming pegged order {}", order.order_id);
+    }
+    for order in resumed_orders {
+        println!("Cancelling extra pegged order {}", order.order_id);
+        cancel_open_order(db, exchange_client, &pair, &order).await?;
+    }
+    process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier).await?;
+    let mut pegged_order = pegged_orders(db, exchange, &symbol, side).pop();
+
+    let mut filled_amount = 0.;
+    loop {
+        if let Err(err) = cancellation.check() {
+            if let Some(order) = pegged_order {
+                cancel_open_order(db, exchange_client, &pair, &order).await?;
+                process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier).await?;
+                println!("Peg stopped: {err}. Order {} cancelled", order.order_id);
+            } else {
+                println!("Peg stopped: {err}");
+            }
+            return Ok(());
+        }
+
+        // A market that stops trading, such as for a halt or a delisting, ends the peg instead of
+        // failing the next order
+        if let Err(err) = check_system_status(
+            exchange,
+            exchange_client,
+            Some(&pair),
+            SystemOperation::PlaceOrder { taker: false },
+        )
+        .await
+        {
+            let msg = match pegged_order {
+                Some(order) => match cancel_open_order(db, exchange_client, &pair, &order).await {
+                    Ok(_) => {
+                        process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier)
+                            .await?;
+                        format!("Peg stopped: {err}. Order {} cancelled", order.order_id)
+                    }
+                    Err(cancel_err) => format!(
+                        "Peg stopped: {err}. Order {} left open, it could not be cancelled: \
+                             {cancel_err}",
+                        order.order_id
+                    ),
+                },
+                None => format!("Peg stopped: {err}"),
+            };
+            println!("{msg}");
+            notifier.send(&msg).await;
+            return Ok(());
+        }
+
+        let bid_ask = exchange_client.bid_ask(&pair).await?;
+        // Beyond the price limit the order stops chasing, and rests at the limit instead
+        let target_price = match side {
+            OrderSide::Sell => {
+                let price = bid_ask.ask_price + offset.price_offset(bid_ask.ask_price, tick_size);
+                price_limit.map_or(price, |floor| price.max(floor))
+            }
+            OrderSide::Buy => {
+                let price = bid_ask.bid_price - offset.price_offset(bid_ask.bid_price, tick_size);
+                price_limit.map_or(price, |ceiling| price.min(ceiling))
+            }
+        };
+        let remaining_amount = amount - filled_amount;
+        let (target_price, _) = pair_info.round_order(
+            side,
+            target_price,
+            remaining_amount,
+            OrderRounding::default(),
+        )?;
+
+        match pegged_order.take() {
+            Some(order) => {
+                let order_status = open_order_status(db, exchange_client, &pair, &order).await?;
+                if !order_status.open {
+                    // Records the fill, and returns any unfilled lots to the account
+                    process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier)
+                        .await?;
+                    filled_amount += order_status.filled_amount;
+                    check_pegged_order_cancellation(exchange, &order, &order_status, notifier)
+                        .await?;
+                    if amount - filled_amount < f64::EPSILON {
+                        let msg = format!("Pegged {side} of {amount} {token} filled");
+                        println!("{msg}");
+                        notifier.send(&format!("{exchange:?}: {msg}")).await;
+                        return Ok(());
+                    }
+                    continue;
+                }
+
+                if !pegged_order_drifted(&order, &bid_ask, target_price, tick_size, tolerance_ticks)
+                {
+                    pegged_order = Some(order);
+                } else {
+                    match reprice_pegged_order(
+                        db,
+                        exchange,
+                        exchange_client,
+                        &pair,
+                        order.clone(),
+                        target_price,
+                    )
+                    .await?
+                    {
+                        RepricedOrder::Moved(order) => pegged_order = Some(order),
+                        RepricedOrder::Cancelled(order_status) => {
+                            process_sync_exchange(
+                                db,
+                                exchange,
+                                exchange_client,
+                                rpc_client,
+                                notifier,
+                            )
+                            .await?;
+                            filled_amount += order_status.filled_amount;
+                            check_pegged_order_cancellation(
+                                exchange,
+                                &order,
+                                &order_status,
+                                notifier,
+                            )
+                            .await?;
+                            // Replaced straight away, to keep the time off the book short
+                            continue;
+                        }
+                    }
+                }
+            }
+            None => {
+                // Within an unwind target, the order is no larger than what the target has left
+                // once the other orders placed within it fill
+                let order_amount = match unwind {
+                    Some(unwind) => {
+                        let (_, progress) = unwind_target_progress(db, unwind, &HashMap::new())?;
+                        if token.amount(progress.uncommitted()) == 0 {
+                            let msg = format!(
+                                "Peg stopped: unwind target {unwind} has nothing left to sell"
+                            );
+                            println!("{msg}");
+                            notifier.send(&format!("{exchange:?}: {msg}")).await;
+                            return Ok(());
+                        }
+                        remaining_amount.min(progress.uncommitted())
+                    }
+                    None => remaining_amount,
+                };
+                pegged_order = place_pegged_order(
+                    db,
+                    exchange,
+                    exchange_client,
+                    token,
+                    &pair,
+                    side,
+                    order_amount,
+                    target_price,
+                    lot_selection_method.clone(),
+                    unwind,
+                    "peg",
+                    notifier,
+                )
+                .await?;
+                if pegged_order.is_none() {
+                    return Ok(());
+                }
+            }
+        }
+
+        let _ = cancellation.sleep(interval).await;
+    }
+}
+
+// Fees of a quoting session in the quote currency.  Fees charged in any other currency, such as
+// BNB, are listed in the report but left out of the profit
+fn quoting_fees(session: &QuotingSession, pair: &TradingPair) -> f64 {
+    session
+        .fills
+        .iter()
+        .filter_map(|fill| match &fill.fee {
+            Some((amount, coin)) if *coin == pair.quote => Some(*amount),
+            Some((amount, coin)) if *coin == pair.base => Some(amount * fill.price),
+            _ => None,
+        })
+        .sum()
+}
+
+fn quoting_pnl(session: &QuotingSession, pair: &TradingPair, mid_price: f64) -> f64 {
+    session.cash_flow() + session.inventory() * mid_price - quoting_fees(session, pair)
+}
+
+fn print_quoting_report(session: &QuotingSession, pair: &TradingPair, mid_price: Option<f64>) {
+    println!(
+        "Quoting session on {:?} {pair} since {}",
+        session.exchange,
+        HumanTime::from(session.start_time)
+    );
+    for fill in &session.fills {
+        println!(
+            "  {} {:<4} {} at {}{} | id {}",
+            fill.time.format("%Y-%m-%d %H:%M:%S"),
+            fill.side,
+            fill.amount,
+            format_quote_amount(&fill.price.to_string(), &pair.quote),
+            match &fill.fee {
+                Some((amount, coin)) if *amount > 0. => format!(" (fee: {amount} {coin})"),
+                _ => String::new(),
+            },
+            fill.order_id,
+        );
+    }
+
+    let totals = |side| {
+        session.fills.iter().filter(|fill| fill.side == side).fold(
+            (0., 0.),
+            |(amount, quote_amount), fill| {
+                (
+                    amount + fill.amount,
+                    quote_amount + fill.amount * fill.price,
+                )
+            },
+        )
+    };
+    let (bought, paid) = totals(OrderSide::Buy);
+    let (sold, received) = totals(OrderSide::Sell);
+    println!(
+        "Bought: {bought}, sold: {sold}, inventory: {}",
+        session.inventory()
+    );
+    if bought > 0. && sold > 0. {
+        let spread = received / sold - paid / bought;
+        println!(
+            "Realized spread: {} on {} round trip",
+            format_quote_amount(&(spread * bought.min(sold)).to_string(), &pair.quote),
+            bought.min(sold)
+        );
+    }
+    let other_fees = session
+        .fills
+        .iter()
+        .filter_map(|fill| fill.fee.as_ref())
+        .filter(|(_, coin)| *coin != pair.base && *coin != pair.quote)
+        .fold(BTreeMap::<&str, f64>::new(), |mut fees, (amount, coin)| {
+            *fees.entry(coin).or_default() += amount;
+            fees
+        });
+    println!(
+        "Fees: {}{}",
+        format_quote_amount(&quoting_fees(session, pair).to_string(), &pair.quote),
+        other_fees
+            .iter()
+            .map(|(coin, amount)| format!(", {amount} {coin}"))
+            .collect::<String>()
+    );
+    if let Some(mid_price) = mid_price {
+        println!(
+            "Profit at the mid price of {}: {}",
+            format_quote_amount(&mid_price.to_string(), &pair.quote),
+            format_quote_amount(
+                &quoting_pnl(session, pair, mid_price).to_string(),
+                &pair.quote
+            )
+        );
+    }
+    if let Some(halted) = &session.halted {
+        println!("Halted: {halted}");
+    }
+}
+
+// Adds the fills of a closed quote to the session, once.  A quote the exchange cancelled on its own
+// halts the session, rather than being replaced into whatever caused the cancel
+async fn record_quoting_fill(
+    db: &mut Db,
+    session: &mut QuotingSession,
+    order: &OpenOrder,
+    order_status: &OrderStatus,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(reason) = order_status.exchange_cancel_reason() {
+        session.halted = Some(format!(
+            "{} quote {} was cancelled by the exchange: {reason}",
+            order.side, order.order_id
+        ));
+    }
+    if order_status.filled_amount < f64::EPSILON
+        || session
+            .fills
+            .iter()
+            .any(|fill| fill.order_id == order.order_id)
+    {
+        return Ok(());
+    }
+    session.fills.push(QuotingFill {
+        time: Utc::now(),
+        order_id: order.order_id.clone(),
+        side: order.side,
+        amount: order_status.filled_amount,
+        price: order_status.fill_price(),
+        fee: order_status.fee.clone(),
+    });
+    db.set_quoting_session(session.clone())?;
+
+    let msg = format!(
+        "Quote filled: {} {} {} at {}, inventory {}",
+        order.pair,
+        order.side,
+        order_status.filled_amount,
+        order_status.fill_price(),
+        session.inventory()
+    );
+    println!("{msg}");
+    notifier.send(&format!("{:?}: {msg}", order.exchange)).await;
+    Ok(())
+}
+
+// Keeps one side of a quoting session at `target_price`, or withdraws it when there's no target.
+// A quote that closed is recorded and left for the next pass to replace
+#[allow(clippy::too_many_arguments)]
+async fn maintain_quote(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: &TradingPair,
+    session: &mut QuotingSession,
+    quote: &mut Option<OpenOrder>,
+    side: OrderSide,
+    size: f64,
+    target_price: Option<f64>,
+    bid_ask: &BidAsk,
+    tick_size: f64,
+    tolerance_ticks: u32,
+    lot_selection_method: &LotSelectionMethod,
+    rpc_client: &RpcClient,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(order) = quote.take() {
+        let order_status = exchange_client.order_status(pair, &order.order_id).await?;
+        let closed_status = if !order_status.open {
+            Some(order_status)
+        } else {
+            match target_price {
+                None => Some(cancel_open_order(db, exchange_client, pair, &order).await?),
+                Some(target_price)
+                    if pegged_order_drifted(
+                        &order,
+                        bid_ask,
+                        target_price,
+                        tick_size,
+                        tolerance_ticks,
+                    ) =>
+                {
+                    match reprice_pegged_order(
+                        db,
+                        exchange,
+                        exchange_client,
+                        pair,
+                        order.clone(),
+                        target_price,
+                    )
+                    .await?
+                    {
+                        RepricedOrder::Moved(order) => {
+                            *quote = Some(order);
+                            None
+                        }
+                        RepricedOrder::Cancelled(order_status) => {
+                            record_quoting_fill(db, session, &order, &order_status, notifier)
+                                .await?;
+                            process_sync_exchange(
+                                db,
+                                exchange,
+                                exchange_client,
+                                rpc_client,
+                                notifier,
+                            )
+                            .await?;
+                            None
+                        }
+                    }
+                }
+                Some(_) => {
+                    *quote = Some(order.clone());
+                    None
+                }
+            }
+        };
+
+        if let Some(order_status) = closed_status {
+            record_quoting_fill(db, session, &order, &order_status, notifier).await?;
+            process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier).await?;
+            return Ok(());
+        }
+    }
+
+    if let (None, Some(target_price), None) = (&quote, target_price, &session.halted) {
+        *quote = place_pegged_order(
+            db,
+            exchange,
+            exchange_client,
+            token,
+            pair,
+            side,
+            size,
+            target_price,
+            lot_selection_method.clone(),
+            None,
+            "market-make",
+            notifier,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_exchange_market_make(
+    db: &mut Db,
+    exchange: Exchange,
+    exchange_client: &dyn ExchangeClient,
+    token: MaybeToken,
+    pair: TradingPair,
+    size: f64,
+    offset: PegOffset,
+    tolerance_ticks: u32,
+    max_inventory: f64,
+    max_loss: f64,
+    lot_selection_method: LotSelectionMethod,
+    interval: Duration,
+    rpc_client: &RpcClient,
+    cancellation: &Cancellation,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let symbol = pair.to_exchange_symbol(exchange);
+    let pair_info = exchange_client.pair_info(&pair).await?;
+    let tick_size = pair_info
+        .tick_size
+        .ok_or_else(|| format!("{exchange:?} does not report a tick size for {pair}"))?;
+    check_strategy_pair(exchange, &pair_info, notifier).await?;
+
+    let mut session = db
+        .quoting_session(exchange, &symbol)
+        .unwrap_or_else(|| QuotingSession {
+            exchange,
+            pair: symbol.clone(),
+            start_time: Utc::now(),
+            fills: vec![],
+            halted: None,
+        });
+    if let Some(halted) = &session.halted {
+        return Err(format!(
+            "Quoting {pair} on {exchange:?} was halted: {halted}. Start a new session to resume"
+        )
+        .into());
+    }
+    db.set_quoting_session(session.clone())?;
+
+    // Quotes left by a previous run are adopted, one per side, after recording any fills
+    let mut quotes = vec![];
+    for side in [OrderSide::Buy, OrderSide::Sell] {
+        let mut resumed_quote = None;
+        for order in pegged_orders(db, exchange, &symbol, side).into_iter().rev() {
+            let order_status = open_order_status(db, exchange_client, &pair, &order).await?;
+            if order_status.open && resumed_quote.is_none() {
+                println!("Resuming {side} quote {}", order.order_id);
+                resumed_quote = Some(order);
+                continue;
+            }
+            let order_status = if order_status.open {
+                println!("Cancelling extra {side} quote {}", order.order_id);
+                cancel_open_order(db, exchange_client, &pair, &order).await?
+            } else {
+                order_status
+            };
+            record_quoting_fill(db, &mut session, &order, &order_status, notifier).await?;
+        }
+        quotes.push(resumed_quote);
+    }
+    process_sync_exchange(db, exchange, exchange_client, rpc_client, notifier).await?;
+    let (mut bid_quote, mut ask_quote) = (quotes[0].take(), quotes[1].take());
+
+    loop {
+        let bid_ask = exchange_client.bid_ask(&pair).await?;
+        let mid_price = (bid_ask.bid_price + bid_ask.ask_price) / 2.;
+
+        let stop = match (cancellation.check(), session.halted.clone()) {
+            (Err(err), _) => Some(format!("Quoting stopped: {err}")),
+            (Ok(()), Some(halted)) => Some(format!("Quoting halted: {halted}")),
+            (Ok(()), None) => {
+                // A market that stops trading withdraws the quotes without halting the session,
+                // so quoting can resume once it trades again
+                match check_system_status(
+                    exchange,
+                    exchange_client,
+                    Some(&pair),
+                    SystemOperation::PlaceOrder { taker: false },
+                )
+                .await
+                {
+                    Err(err) => Some(format!("Quoting stopped: {err}")),
+                    Ok(()) => {
+                        let pnl = quoting_pnl(&session, &pair, mid_price);
+                        (pnl < -max_loss).then(|| {
+                            let halted =
+                                format!("loss of {:.2} {} exceeded the limit", -pnl, pair.quote);
+                            session.halted = Some(halted.clone());
+                            format!("Quoting halted: {halted}")
+                        })
+                    }
+                }
+            }
+        };
+
+        // Quotes never cross the book, and a side that would take the position past the
+        // inventory limit is withdrawn
+        let inventory = session.inventory();
+        let offset_price = offset.price_offset(mid_price, tick_size);
+        let bid_price = (stop.is_none() && inventory + size <= max_inventory + f64::EPSILON)
+            .then(|| (mid_price - offset_price).min(bid_ask.bid_price));
+        let ask_price = (stop.is_none() && inventory - size >= -max_inventory - f64::EPSILON)
+            .then(|| (mid_price + offset_price).max(bid_ask.ask_price));
+
+        let pair_info = exchange_client.pair_info(&pair).await?;
+        for (side, quote, target_price) in [
+            (OrderSide::Buy, &mut bid_quote, bid_price),
+            (OrderSide::Sell, &mut ask_quote, ask_price),
+        ] {
+            let target_price = target_price
+                .map(|price| pair_info.round_order(side, price, size, OrderRounding::default()))
+                .transpose()?
+                .map(|(price, _)| price);
+            maintain_quote(
+                db,
+                exchange,
+                exchange_client,
+                token,
+                &pair,
+                &mut session,
+                quote,
+                side,
+                size,
+                target_price,
+                &bid_ask,
+                tick_size,
+                tolerance_ticks,
+                &lot_selection_method,
+                rpc_client,
+                notifier,
+            )
+            .await?;
+        }
+
+        if let Some(stop) = stop {
+            db.set_quoting_session(session.clone())?;
+            println!("{stop}");
+            notifier.send(&format!("{exchange:?}: {stop}")).await;
+            print_quoting_report(&session, &pair, Some(mid_price));
+            return Ok(());
+        }
+
+        // A halt found during this pass withdraws the remaining quotes straight away
+        if session.halted.is_none() {
+            let _ = cancellation.sleep(interval).await;
+        }
+    }
+}
+
+fn println_jup_quote(from_token: Token, to_token: Token, quote: &jup_ag::Quote) {
+    let route = quote
+        .route_plan
+        .iter()
+        .map(|route_plan| route_plan.swap_info.label.clone().unwrap_or_default())
+        .join(", ");
+    println!(
+        "Swap {}{} for {}{} (min: {}{}) via {}",
+        from_token.symbol(),
+        from_token.ui_amount(quote.in_amount),
+        to_token.symbol(),
+        to_token.ui_amount(quote.out_amount),
+        to_token.symbol(),
+        to_token.ui_amount(quote.other_amount_threshold),
+        route,
+    );
+}
+
+async fn process_jup_quote(
+    from_token: Token,
+    to_token: Token,
+    ui_amount: f64,
+    slippage_bps: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let quote = jup_ag::quote(
+        from_token.mint(),
+        to_token.mint(),
+        from_token.amount(ui_amount),
+        jup_ag::QuoteConfig {
+            slippage_bps: Some(slippage_bps),
+            ..jup_ag::QuoteConfig::default()
+        },
+    )
+    .await?;
+
+    println_jup_quote(from_token, to_token, &quote);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_jup_swap<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    address: Pubkey,
+    from_token: Token,
+    to_token: Token,
+    ui_amount: Option<f64>,
+    slippage_bps: u64,
+    lot_selection_method: LotSelectionMethod,
+    signers: T,
+    existing_signature: Option<Signature>,
+    if_from_balance_exceeds: Option<u64>,
+    for_no_less_than: Option<f64>,
+    max_coingecko_value_percentage_loss: f64,
+    compute_unit_price_micro_lamports: Option<u64>,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let from_account = db
+        .get_account(address, from_token.into())
+        .ok_or_else(|| format!("{from_token} account does not exist for {address}"))?;
+
+    let from_token_price = from_token.get_current_price(rpc_client).await?;
+    let to_token_price = to_token.get_current_price(rpc_client).await?;
+
+    if let Some(existing_signature) = existing_signature {
+        db.record_swap(
+            existing_signature,
+            0, /*last_valid_block_height*/
+            address,
+            from_token.into(),
+            from_token_price,
+            to_token.into(),
+            to_token_price,
+            lot_selection_method,
+        )?;
+    } else {
+        let amount = match ui_amount {
+            Some(ui_amount) => from_token.amount(ui_amount),
+            None => from_account.last_update_balance,
+        };
+
+        if from_account.last_update_balance < amount {
+            return Err(format!(
+                "Insufficient {} balance in {}. Tracked balance is {}",
+                from_token,
+                address,
+                from_token.ui_amount(from_account.last_update_balance)
+            )
+            .into());
+        }
+
+        let swap_prefix = format!("Swap {}->{}", from_token, to_token);
+
+        if let Some(if_from_balance_exceeds) = if_from_balance_exceeds {
+            if from_account.last_update_balance < if_from_balance_exceeds {
+                println!(
+                    "{swap_prefix} declined because {} ({}) balance is less than {}{}",
+                    address,
+                    from_token.name(),
+                    from_token.symbol(),
+                    from_token.ui_amount(if_from_balance_exceeds)
+                );
+                return Ok(());
+            }
+        }
+
+        let _ = to_token.balance(rpc_client, &address).map_err(|err| {
+            format!(
+                "{} account does not exist for {}. \
+                To create it, run `spl-token create-account {} --owner {}: {}",
+                to_token,
+                address,
+                to_token.mint(),
+                address,
+                err
+            )
+        })?;
+
+        println!("Fetching best {from_token}->{to_token} quote...");
+        let quote = jup_ag::quote(
+            from_token.mint(),
+            to_token.mint(),
+            amount,
+            jup_ag::QuoteConfig {
+                slippage_bps: Some(slippage_bps),
+                ..jup_ag::QuoteConfig::default()
+            },
+        )
+        .await?;
+
+        println_jup_quote(from_token, to_token, &quote);
+
+        let from_value =
+            from_token_price * Decimal::from_f64(from_token.ui_amount(quote.in_amount)).unwrap();
+        let min_to_value = to_token_price
+            * Decimal::from_f64(to_token.ui_amount(quote.other_amount_threshold)).unwrap();
+
+        let swap_value_percentage_loss = Decimal::from_usize(100).unwrap()
+            - min_to_value / from_value * Decimal::from_usize(100).unwrap();
+
+        println!("Coingecko value loss: {swap_value_percentage_loss:.2}%");
+        if swap_value_percentage_loss
+            > Decimal::from_f64(max_coingecko_value_percentage_loss).unwrap()
+        {
+            return Err(format!(
+                "{swap_prefix} exceeds the max value loss ({max_coingecko_value_percentage_loss:2}%) relative to CoinGecko token price"
+            )
+            .into());
+        }
+
+        if let Some(for_no_less_than) = for_no_less_than {
+            let to_token_amount = to_token.ui_amount(quote.other_amount_threshold);
+
+            if to_token_amount < for_no_less_than {
+                let to_token_symbol = to_token.symbol();
+                let msg = format!("{swap_prefix} would not result in at least {to_token_symbol}{for_no_less_than} tokens, only would have received {to_token_symbol}{to_token_amount}");
+                println!("{msg}");
+                notifier.send(&msg).await;
+                return Ok(());
+            }
+        }
+
+        println!("Generating {swap_prefix} Transaction...");
+        let mut swap_request = jup_ag::SwapRequest::new(address, quote.clone());
+        swap_request.wrap_and_unwrap_sol = Some(false);
+        swap_request.compute_unit_price_micro_lamports = compute_unit_price_micro_lamports;
+
+        let mut transaction = jup_ag::swap(swap_request).await?.swap_transaction;
+
+        let (recent_blockhash, last_valid_block_height) =
+            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+        transaction.message.set_recent_blockhash(recent_blockhash);
+
+        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+        if simulation_result.err.is_some() {
+            return Err(
+                format!("Swap transaction simulation failure: {simulation_result:?}").into(),
+            );
+        }
+
+        assert_eq!(transaction.signatures[0], Signature::default());
+        let signatures = signers.try_sign_message(&transaction.message.serialize())?;
+        assert_eq!(signatures.len(), 1);
+        let signature = signatures[0];
+        transaction.signatures[0] = signature;
+
+        if db.get_account(address, to_token.into()).is_none() {
+            let epoch = rpc_client.get_epoch_info()?.epoch;
+            db.add_account(TrackedAccount {
+                address,
+                token: to_token.into(),
+                description: from_account.description,
+                last_update_epoch: epoch,
+                last_update_balance: 0,
+                lots: vec![],
+                no_sync: None,
+            })?;
+        }
+        db.record_swap(
+            signature,
+            last_valid_block_height,
+            address,
+            from_token.into(),
+            from_token_price,
+            to_token.into(),
+            to_token_price,
+            lot_selection_method,
+        )?;
+
+        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+            db.cancel_swap(signature)?;
+            return Err("Swap failed".into());
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_tulip_deposit<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    liquidity_token: MaybeToken,
+    collateral_token: Token,
+    liquidity_amount: Option<u64>,
+    address: Pubkey,
+    lot_selection_method: LotSelectionMethod,
+    signers: T,
+    existing_signature: Option<Signature>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sol = MaybeToken::SOL();
+    let minimum_lamport_balance = sol.amount(0.01);
+    let from_account_lamports = sol.balance(rpc_client, &address)?;
+    if from_account_lamports < minimum_lamport_balance {
+        return Err(format!(
+            "From account (SOL), {}, has insufficient funds ({}{} required)",
+            address,
+            sol.symbol(),
+            sol.ui_amount(minimum_lamport_balance)
+        )
+        .into());
+    }
+
+    let liquidity_tracked_account = db
+        .get_account(address, liquidity_token)
+        .ok_or_else(|| format!("Unknown account {address} ({liquidity_token})"))?;
+    let liquidity_account_balance = liquidity_tracked_account.last_update_balance;
+
+    let max_liquidity_amount = if liquidity_token.is_sol() {
+        liquidity_account_balance.saturating_sub(minimum_lamport_balance * 2)
+    } else {
+        liquidity_account_balance
+    };
+    let liquidity_amount = liquidity_amount.unwrap_or(max_liquidity_amount);
+
+    if liquidity_amount > max_liquidity_amount {
+        return Err(format!(
+            "Deposit amount is too large: {0}{1} (max: {0}{2})",
+            liquidity_token.symbol(),
+            liquidity_token.ui_amount(liquidity_amount),
+            liquidity_token.ui_amount(max_liquidity_amount)
+        )
+        .into());
+    }
+    if liquidity_amount == 0 {
+        return Err("Nothing to deposit".into());
+    }
+
+    let liquidity_token_price = liquidity_token.get_current_price(rpc_client).await?;
+    let collateral_token_price = collateral_token.get_current_price(rpc_client).await?;
+    let liquidity_token_ui_amount = liquidity_token.ui_amount(liquidity_amount);
+
+    println!("{address}: {liquidity_token} -> {collateral_token}");
+    println!(
+        "Estimated deposit amount: {}{} (${})",
+        liquidity_token.symbol(),
+        liquidity_token.ui_amount(liquidity_amount),
+        liquidity_token_price * Decimal::from_f64(liquidity_token_ui_amount).unwrap()
+    );
+
+    if db.get_account(address, collateral_token.into()).is_none() {
+        let epoch = rpc_client.get_epoch_info()?.epoch;
+        db.add_account(TrackedAccount {
+            address,
+            token: collateral_token.into(),
+            description: liquidity_tracked_account.description,
+            last_update_epoch: epoch,
+            last_update_balance: 0,
+            lots: vec![],
+            no_sync: Some(true),
+        })?;
+    }
+
+    if let Some(existing_signature) = existing_signature {
+        db.record_swap(
+            existing_signature,
+            0, /*last_valid_block_height*/
+            address,
+            liquidity_token,
+            liquidity_token_price,
+            collateral_token.into(),
+            collateral_token_price,
+            lot_selection_method,
+        )?;
+    } else {
+        let instructions = tulip::deposit(
+            rpc_client,
+            address,
+            liquidity_token,
+            collateral_token,
+            liquidity_amount,
+        )?;
+
+        let (recent_blockhash, last_valid_block_height) =
+            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+        let mut message = Message::new(&instructions, Some(&address));
+        message.recent_blockhash = recent_blockhash;
+
+        let mut transaction = Transaction::new_unsigned(message);
+        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+        if simulation_result.err.is_some() {
+            return Err(format!("Simulation failure: {simulation_result:?}").into());
+        }
+
+        transaction.try_sign(&signers, recent_blockhash)?;
+        let signature = transaction.signatures[0];
+        println!("Transaction signature: {signature}");
+
+        db.record_swap(
+            signature,
+            last_valid_block_height,
+            address,
+            liquidity_token,
+            liquidity_token_price,
+            collateral_token.into(),
+            collateral_token_price,
+            lot_selection_method,
+        )?;
+
+        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+            db.cancel_swap(signature).expect("cancel_swap");
+            return Err("Swap failed".into());
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_tulip_withdraw<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    liquidity_token: MaybeToken,
+    collateral_token: Token,
+    liquidity_amount: Option<u64>,
+    address: Pubkey,
+    lot_selection_method: LotSelectionMethod,
+    signers: T,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let collateral_tracked_account = db
+        .get_account(address, collateral_token.into())
+        .ok_or_else(|| format!("Unknown account {address} ({collateral_token})"))?;
+    let collateral_account_balance = collateral_tracked_account.last_update_balance;
+
+    let collateral_amount = match liquidity_amount {
+        None => collateral_account_balance,
+        Some(liquidity_amount) => collateral_token.amount(
+            f64::try_from(
+                Decimal::from_f64(liquidity_token.ui_amount(liquidity_amount)).unwrap()
+                    / tulip::get_current_liquidity_token_rate(rpc_client, &collateral_token)
+                        .await?,
+            )
+            .unwrap(),
+        ),
+    };
+
+    if collateral_amount > collateral_account_balance {
+        return Err(format!(
+            "Withdraw amount is too large: {0}{1} (max: {0}{2})",
+            collateral_token.symbol(),
+            collateral_token.ui_amount(collateral_amount),
+            collateral_token.ui_amount(collateral_account_balance)
+        )
+        .into());
+    }
+    if collateral_amount == 0 {
+        return Err("Nothing to withdraw".into());
+    }
+
+    let liquidity_token_price = liquidity_token.get_current_price(rpc_client).await?;
+    let collateral_token_price = collateral_token.get_current_price(rpc_client).await?;
+    let collateral_token_ui_amount = collateral_token.ui_amount(collateral_amount);
+
+    println!("{address}: {collateral_token} -> {liquidity_token}");
+    println!(
+        "Estimated withdraw amount: {}{} (${})",
+        collateral_token.symbol(),
+        collateral_token.ui_amount(collateral_amount),
+        collateral_token_price * Decimal::from_f64(collateral_token_ui_amount).unwrap()
+    );
+
+    let instructions = tulip::withdraw(
+        rpc_client,
+        address,
+        liquidity_token,
+        collateral_token,
+        collateral_amount,
+    )?;
+
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+    let mut message = Message::new(&instructions, Some(&address));
+    message.recent_blockhash = recent_blockhash;
+
+    let mut transaction = Transaction::new_unsigned(message);
+    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+    if simulation_result.err.is_some() {
+        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    }
+
+    transaction.try_sign(&signers, recent_blockhash)?;
+    let signature = transaction.signatures[0];
+    println!("Transaction signature: {signature}");
+
+    db.record_swap(
+        signature,
+        last_valid_block_height,
+        address,
+        collateral_token.into(),
+        collateral_token_price,
+        liquidity_token,
+        liquidity_token_price,
+        lot_selection_method,
+    )?;
+
+    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+        db.cancel_swap(signature).expect("cancel_swap");
+        return Err("Swap failed".into());
+    }
+
+    Ok(())
+}
+
+async fn process_sync_swaps(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let block_height = rpc_client.get_epoch_info()?.block_height;
+
+    for PendingSwap {
+        signature,
+        last_valid_block_height,
+        address,
+        from_token,
+        to_token,
+        ..
+    } in db.pending_swaps()
+    {
+        let swap = format!("swap ({address}: {from_token} -> {to_token})");
+
+        let status = rpc_client.get_signature_status_with_commitment_and_history(
+            &signature,
+            rpc_client.commitment(),
+            true,
+        )?;
+        match status {
+            Some(result) => {
+                if result.is_ok() {
+                    println!("Pending {swap} confirmed: {signature}");
+                    let result = rpc_client.get_transaction_with_config(
+                        &signature,
+                        RpcTransactionConfig {
+                            commitment: Some(rpc_client.commitment()),
+                            max_supported_transaction_version: Some(0),
+                            ..RpcTransactionConfig::default()
+                        },
+                    )?;
+
+                    let block_time = result
+                        .block_time
+                        .ok_or("Transaction block time not available")?;
+
+                    let when = Local.timestamp(block_time, 0);
+                    let when = NaiveDate::from_ymd(when.year(), when.month(), when.day());
+
+                    let transaction_status_meta = result.transaction.meta.unwrap();
+
+                    let pre_token_balances =
+                        Option::<Vec<_>>::from(transaction_status_meta.pre_token_balances)
+                            .unwrap_or_default();
+                    let post_token_balances =
+                        Option::<Vec<_>>::from(transaction_status_meta.post_token_balances)
+                            .unwrap_or_default();
+
+                    let token_amount_diff = |owner: Pubkey, mint: Pubkey| {
+                        let owner = owner.to_string();
+                        let mint = mint.to_string();
+
+                        let num_token_balances = pre_token_balances
+                            .iter()
+                            .filter(|token_balance| token_balance.mint == mint)
+                            .count();
+                        assert_eq!(
+                            num_token_balances,
+                            post_token_balances
+                                .iter()
+                                .filter(|token_balance| token_balance.mint == mint)
+                                .count()
+                        );
+
+                        let pre = pre_token_balances
+                            .iter()
+                            .filter_map(|token_balance| {
+                                if (num_token_balances == 1
+                                    || token_balance.owner.as_ref() == Some(&owner).into())
+                                    && token_balance.mint == mint
+                                {
+                                    Some(
+                                        token_balance
+                                            .ui_token_amount
+                                            .amount
+                                            .parse::<u64>()
+                                            .expect("amount"),
+                                    )
+                                } else {
+                                    None
+                                }
+                            })
+                            .next()
+                            .unwrap_or_else(|| {
+                                panic!(
+                                    "pre_token_balance not found for owner {address}, mint {mint}"
+                                )
+                            });
+                        let post = post_token_balances
+                            .iter()
+                            .filter_map(|token_balance| {
+                                if (num_token_balances == 1
+                                    || token_balance.owner.as_ref() == Some(&owner).into())
+                                    && token_balance.mint == mint
+                                {
+                                    Some(
+                                        token_balance
+                                            .ui_token_amount
+                                            .amount
+                                            .parse::<u64>()
+                                            .expect("amount"),
+                                    )
+                                } else {
+                                    None
+                                }
+                            })
+                            .next()
+                            .unwrap_or_else(|| {
+                                panic!(
+                                    "post_token_balance not found for owner {address},  mint {mint}"
+                                )
+                            });
+                        (post as i64 - pre as i64).unsigned_abs()
+                    };
+
+                    let from_amount = token_amount_diff(address, from_token.mint());
+                    let to_amount = token_amount_diff(address, to_token.mint());
+                    let msg = format!(
+                        "Swapped {}{} into {}{} at {}{} per {}1",
+                        from_token.symbol(),
+                        from_token
+                            .ui_amount(from_amount)
+                            .separated_string_with_fixed_place(2),
+                        to_token.symbol(),
+                        to_token
+                            .ui_amount(to_amount)
+                            .separated_string_with_fixed_place(2),
+                        to_token.symbol(),
+                        (to_token.ui_amount(to_amount) / from_token.ui_amount(from_amount))
+                            .separated_string_with_fixed_place(2),
+                        from_token.symbol(),
+                    );
+                    db.confirm_swap(signature, when, from_amount, to_amount)?;
+                    notifier.send(&msg).await;
+                    println!("{msg}");
+                } else {
+                    println!("Pending {swap} failed with {result:?}: {signature}");
+                    db.cancel_swap(signature)?;
+                }
+            }
+            None => {
+                if block_height > last_valid_block_height {
+                    println!("Pending {swap} cancelled: {signature}");
+                    db.cancel_swap(signature)?;
+                } else {
+                    println!(
+                        "{} pending for at most {} blocks: {}",
+                        swap,
+                        last_valid_block_height.saturating_sub(block_height),
+                        signature
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+struct LiquidityTokenInfo {
+    liquidity_token: MaybeToken,
+    current_liquidity_token_rate: Decimal,
+    current_apr: Option<f64>,
+}
+
+fn liquidity_token_ui_amount(
+    acquisition_liquidity_ui_amount: Option<f64>,
+    ui_amount: f64,
+    liquidity_token_info: Option<&LiquidityTokenInfo>,
+    include_apr: bool,
+) -> (String, String) {
+    liquidity_token_info
+        .map(
+            |LiquidityTokenInfo {
+                 liquidity_token,
+                 current_liquidity_token_rate,
+                 current_apr,
+             }| {
+                let liquidity_ui_amount = f64::try_from(
+                    Decimal::from_f64(ui_amount).unwrap() * current_liquidity_token_rate,
+                )
+                .unwrap();
+
+                (
+                    format!(
+                        " [{}{}]",
+                        liquidity_token.format_ui_amount(liquidity_ui_amount),
+                        match current_apr {
+                            Some(current_apr) if include_apr => format!(", {current_apr:.2}% APR"),
+                            _ => String::new(),
+                        }
+                    ),
+                    acquisition_liquidity_ui_amount
+                        .map(|acquisition_liquidity_ui_amount| {
+                            format!(
+                                "[{}{}]",
+                                liquidity_token.symbol(),
+                                (liquidity_ui_amount - acquisition_liquidity_ui_amount)
+                                    .separated_string_with_fixed_place(2)
+                            )
+                        })
+                        .unwrap_or_default(),
+                )
+            },
+        )
+        .unwrap_or_default()
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn println_lot(
+    token: MaybeToken,
+    lot: &Lot,
+    current_price: Option<Decimal>,
+    liquidity_token_info: Option<&LiquidityTokenInfo>,
+    total_income: &mut f64,
+    total_cap_gain: &mut f64,
+    long_term_cap_gain: &mut bool,
+    total_current_value: &mut f64,
+    notifier: Option<&Notifier>,
+    verbose: bool,
+) {
+    let current_value = current_price.map(|current_price| {
+        f64::try_from(Decimal::from_f64(token.ui_amount(lot.amount)).unwrap() * current_price)
+            .unwrap()
+    });
+    let income = lot.income(token);
+    let cap_gain = lot.cap_gain(token, current_price.unwrap_or_default());
+
+    let mut acquisition_liquidity_ui_amount = None;
+    if let Some(LiquidityTokenInfo {
+        liquidity_token, ..
+    }) = liquidity_token_info
+    {
+        if let LotAcquistionKind::Swap { token, amount, .. } = lot.acquisition.kind {
+            if !token.fiat_fungible() && token == *liquidity_token {
+                if let Some(amount) = amount {
+                    acquisition_liquidity_ui_amount = Some(token.ui_amount(amount));
+                }
+            }
+        }
+    }
+
+    *total_income += income;
+    *total_cap_gain += cap_gain;
+    *total_current_value += current_value.unwrap_or_default();
+    *long_term_cap_gain = is_long_term_cap_gain(lot.acquisition.when, None);
+
+    let ui_amount = token.ui_amount(lot.amount);
+    let (liquidity_ui_amount, liquidity_token_cap_gain) = liquidity_token_ui_amount(
+        acquisition_liquidity_ui_amount,
+        ui_amount,
+        liquidity_token_info,
+        false,
+    );
+
+    let current_value = current_value
+        .map(|current_value| {
+            format!(
+                "value: ${}{}",
+                current_value.separated_string_with_fixed_place(2),
+                liquidity_ui_amount
+            )
+        })
+        .unwrap_or_else(|| "value: ?".into());
+
+    let description = if verbose {
+        format!("| {}", lot.acquisition.kind,)
+    } else {
+        String::new()
+    };
+
+    let msg = format!(
+        "{:>5}. {} | {:>20} at ${:<6} | {:<35} | income: ${:<11} | {} gain: ${:<14}{} {}",
+        lot.lot_number,
+        lot.acquisition.when,
+        token.format_ui_amount(ui_amount),
+        f64::try_from(lot.acquisition.price())
+            .unwrap()
+            .separated_string_with_fixed_place(2),
+        current_value,
+        income.separated_string_with_fixed_place(2),
+        if *long_term_cap_gain {
+            " long"
+        } else {
+            "short"
+        },
+        cap_gain.separated_string_with_fixed_place(2),
+        liquidity_token_cap_gain,
+        description,
+    );
+
+    if !token.fiat_fungible() {
+        if let Some(notifier) = notifier {
+            notifier.send(&msg).await;
+        }
+
+        println!("{msg}");
+    }
+}
+
+fn format_disposed_lot(
+    disposed_lot: &DisposedLot,
+    total_income: &mut f64,
+    total_cap_gain: &mut f64,
+    long_term_cap_gain: &mut bool,
+    total_current_value: &mut f64,
+    verbose: bool,
+) -> String {
+    #![allow(clippy::to_string_in_format_args)]
+    let cap_gain = disposed_lot
+        .lot
+        .cap_gain(disposed_lot.token, disposed_lot.price());
+    let income = disposed_lot.lot.income(disposed_lot.token);
+
+    *long_term_cap_gain =
+        is_long_term_cap_gain(disposed_lot.lot.acquisition.when, Some(disposed_lot.when));
+    *total_income += income;
+    *total_current_value += income + cap_gain;
+    *total_cap_gain += cap_gain;
+
+    let description = if verbose {
+        format!(
+            "| {} | {}",
+            disposed_lot.lot.acquisition.kind, disposed_lot.kind
+        )
+    } else {
+        String::new()
+    };
+
+    format!(
+        "{:>5}. {} | {:<7} | {:<17} at ${:<6} | income: ${:<11} | sold {} at ${:6} | {} gain: ${:<14} {}",
+        disposed_lot.lot.lot_number,
+        disposed_lot.lot.acquisition.when,
+        disposed_lot.token.to_string(),
+        disposed_lot.token.format_amount(disposed_lot.lot.amount),
+        f64::try_from(disposed_lot.lot.acquisition.price()).unwrap().separated_string_with_fixed_place(2),
+        income.separated_string_with_fixed_place(2),
+        disposed_lot.when,
+        f64::try_from(disposed_lot.price()).unwrap().separated_string_with_fixed_place(2),
+        if *long_term_cap_gain {
+            " long"
+        } else {
+            "short"
+        },
+        cap_gain.separated_string_with_fixed_place(2),
+        description,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_add(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    address: Pubkey,
+    token: MaybeToken,
+    description: String,
+    when: Option<NaiveDate>,
+    price: Option<f64>,
+    income: bool,
+    signature: Option<Signature>,
+    no_sync: bool,
+    ui_amount: Option<f64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (when, amount, last_update_epoch, kind) = match signature {
+        Some(signature) => {
+            assert!(ui_amount.is_none()); // argument parsing should have asserted this already
+            let (address, address_is_token) = match token.token() {
+                Some(token) => (token.ata(&address), true),
+                None => (address, false),
+            };
+
+            let GetTransactionAddrssBalanceChange {
+                post_amount,
+                slot,
+                when: block_time,
+                ..
+            } = get_transaction_balance_change(rpc_client, &signature, &address, address_is_token)?;
+
+            let when = block_time.map(|dt| dt.date()).or_else(|| {
+                println!(
+                    "Block time not available for slot {slot}, using `--when` argument instead"
+                );
+                when
+            });
+
+            let epoch_schdule = rpc_client.get_epoch_schedule()?;
+            let last_update_epoch = epoch_schdule
+                .get_epoch_and_slot_index(slot)
+                .0
+                .saturating_sub(1);
+
+            (
+                when,
+                post_amount,
+                last_update_epoch,
+                LotAcquistionKind::Transaction { slot, signature },
+            )
+        }
+        None => {
+            let amount = match ui_amount {
+                Some(ui_amount) => token.amount(ui_amount),
+                None => token.balance(rpc_client, &address)?,
+            };
+
+            let last_update_epoch = rpc_client.get_epoch_info()?.epoch.saturating_sub(1);
+            (
+                when,
+                amount,
+                last_update_epoch,
+                if income {
+                    LotAcquistionKind::NotAvailable
+                } else {
+                    LotAcquistionKind::Fiat
+                },
+            )
+        }
+    };
+
+    println!("Adding {address} (token: {token})");
+
+    let current_price = token.get_current_price(rpc_client).await?;
+    let decimal_price = match price {
+        Some(price) => Decimal::from_f64(price).unwrap(),
+        None => match when {
+            Some(when) => token.get_historical_price(rpc_client, when).await?,
+            None => current_price,
+        },
+    };
+
+    let mut lots = vec![];
+    if amount > 0 {
+        let lot = Lot {
+            lot_number: db.next_lot_number(),
+            acquisition: LotAcquistion::new(when.unwrap_or_else(today), decimal_price, kind),
+            amount,
+        };
+        println_lot(
+            token,
+            &lot,
+            Some(current_price),
+            None,
+            &mut 0.,
+            &mut 0.,
+            &mut false,
+            &mut 0.,
+            None,
+            true,
+        )
+        .await;
+
+        lots.push(lot);
+    }
+
+    let account = TrackedAccount {
+        address,
+        token,
+        description,
+        last_update_epoch,
+        last_update_balance: amount,
+        lots,
+        no_sync: Some(no_sync),
+    };
+    db.add_account(account)?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_dispose(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    address: Pubkey,
+    token: MaybeToken,
+    ui_amount: f64,
+    description: String,
+    when: Option<NaiveDate>,
+    price: Option<f64>,
+    lot_selection_method: LotSelectionMethod,
+    lot_numbers: Option<HashSet<usize>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let price = match price {
+        Some(price) => Decimal::from_f64(price).unwrap(),
+        None => match when {
+            Some(when) => token.get_historical_price(rpc_client, when).await?,
+            None => token.get_current_price(rpc_client).await?,
+        },
+    };
+
+    let disposed_lots = db.record_disposal(
+        address,
+        token,
+        token.amount(ui_amount),
+        description,
+        when.unwrap_or_else(today),
+        price,
+        lot_selection_method,
+        lot_numbers,
+    )?;
+    if !disposed_lots.is_empty() {
+        println!("Disposed Lots:");
+        for disposed_lot in disposed_lots {
+            println!(
+                "{}",
+                format_disposed_lot(&disposed_lot, &mut 0., &mut 0., &mut false, &mut 0., true)
+            );
+        }
+        println!();
+    }
+    Ok(())
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct RealizedGain {
+    income: f64,
+    short_term_cap_gain: f64,
+    long_term_cap_gain: f64,
+}
+
+#[derive(Default)]
+struct AnnualRealizedGain {
+    by_quarter: [RealizedGain; 4],
+    by_payment_period: [RealizedGain; 4],
+}
+
+impl AnnualRealizedGain {
+    const MONTH_TO_PAYMENT_PERIOD: [usize; 12] = [0, 0, 0, 1, 1, 2, 2, 2, 3, 3, 3, 3];
+
+    fn record_income(&mut self, month: usize, income: f64) {
+        self.by_quarter[month / 3].income += income;
+        self.by_payment_period[Self::MONTH_TO_PAYMENT_PERIOD[month]].income += income;
+    }
+
+    fn record_short_term_cap_gain(&mut self, month: usize, cap_gain: f64) {
+        self.by_quarter[month / 3].short_term_cap_gain += cap_gain;
+        self.by_payment_period[Self::MONTH_TO_PAYMENT_PERIOD[month]].short_term_cap_gain +=
+            cap_gain;
+    }
+
+    fn record_long_term_cap_gain(&mut self, month: usize, cap_gain: f64) {
+        self.by_quarter[month / 3].long_term_cap_gain += cap_gain;
+        self.by_payment_period[Self::MONTH_TO_PAYMENT_PERIOD[month]].long_term_cap_gain += cap_gain;
+    }
+}
+
+async fn process_account_cost_basis(
+    db: &Db,
+    when: NaiveDate,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut held_tokens =
+        BTreeMap::<MaybeToken, Vec<(/*amount: */ u64, /*price: */ Decimal)>>::default();
+
+    println!("Average Cost Basis on {when}");
+    for disposed_lot in db.disposed_lots() {
+        if disposed_lot.lot.acquisition.when > when || disposed_lot.when < when {
+            continue;
+        }
+        held_tokens
+            .entry(disposed_lot.token)
+            .or_insert_with(Vec::new)
+            .push((
+                disposed_lot.lot.amount,
+                disposed_lot.lot.acquisition.price(),
+            ));
+    }
+
+    for account in db.get_accounts() {
+        let held_token = held_tokens.entry(account.token).or_insert_with(Vec::new);
+        for lot in account.lots {
+            if lot.acquisition.when <= when {
+                held_token.push((lot.amount, lot.acquisition.price()));
+            }
+        }
+    }
+
+    // Merge wSOL lots into SOL
+    if let Some(mut lots) = held_tokens.remove(&Token::wSOL.into()) {
+        held_tokens
+            .entry(MaybeToken::SOL())
+            .or_insert_with(Vec::new)
+            .append(&mut lots);
+    }
+
+    for (token, lots) in held_tokens {
+        if lots.is_empty() || token.fiat_fungible() {
+            continue;
+        }
+
+        let mut total_amount = 0;
+        let mut total_price = Decimal::default();
+
+        for (amount, price) in lots {
+            total_amount += amount;
+            total_price += Decimal::from_f64(token.ui_amount(amount)).unwrap() * price;
+        }
+        println!(
+            "  {:>7}: {:<20} at ${} ; ${:.2} per {}",
+            token.to_string(),
+            token.format_amount(total_amount),
+            TryInto::<f64>::try_into(total_price)
+                .unwrap()
+                .separated_string_with_fixed_place(2),
+            total_price / Decimal::from_f64(token.ui_amount(total_amount)).unwrap(),
+            token.name()
+        );
+    }
+    Ok(())
+}
+
+async fn process_account_list(
+    db: &Db,
+    rpc_client: &RpcClient,
+    account_filter: Option<Pubkey>,
+    show_all_disposed_lots: bool,
+    summary_only: bool,
+    notifier: &Notifier,
+    verbose: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut annual_realized_gains = BTreeMap::<usize, AnnualRealizedGain>::default();
+    let mut held_tokens = BTreeMap::<
+        MaybeToken,
+        (
+            /*price*/ Option<Decimal>,
+            /*amount*/ u64,
+            RealizedGain,
+        ),
+    >::default();
+
+    // hacky: display a unified rate if the long and short term rate is equal
+    let unified_tax_rate = db
+        .get_tax_rate()
+        .map(|tax_rate| tax_rate.short_term_gain - tax_rate.long_term_gain <= f64::EPSILON)
+        .unwrap_or(false);
+
+    let mut accounts = db.get_accounts();
+    accounts.sort_by(|a, b| {
+        let mut result = a.last_update_balance.cmp(&b.last_update_balance);
+        if result == std::cmp::Ordering::Equal {
+            result = a.address.cmp(&b.address);
+        }
+        if result == std::cmp::Ordering::Equal {
+            result = a.description.cmp(&b.description);
+        }
+        result
+    });
+    if accounts.is_empty() {
+        println!("No accounts");
+    } else {
+        let mut total_income = 0.;
+        let mut total_unrealized_short_term_gain = 0.;
+        let mut total_unrealized_long_term_gain = 0.;
+        let mut total_current_value = 0.;
+
+        let open_orders = db.open_orders(None, None);
+
+        for account in accounts {
+            if let Some(ref account_filter) = account_filter {
+                if account.address != *account_filter {
+                    continue;
+                }
+            }
+
+            if let std::collections::btree_map::Entry::Vacant(e) = held_tokens.entry(account.token)
+            {
+                e.insert((
+                    account.token.get_current_price(rpc_client).await.ok(),
+                    0,
+                    RealizedGain::default(),
+                ));
+            }
+
+            let held_token = held_tokens.get_mut(&account.token).unwrap();
+            let current_token_price = held_token.0;
+            held_token.1 += account.last_update_balance;
+
+            let ui_amount = account.token.ui_amount(account.last_update_balance);
+
+            let liquidity_token_info =
+                if let Some(liquidity_token) = account.token.liquidity_token() {
+                    if let Ok(current_liquidity_token_rate) = account
+                        .token
+                        .get_current_liquidity_token_rate(rpc_client)
+                        .await
+                    {
+                        Some(LiquidityTokenInfo {
+                            liquidity_token,
+                            current_liquidity_token_rate,
+                            current_apr: tulip::get_current_lending_apr(rpc_client, &account.token)
+                                .await
+                                .ok(),
+                        })
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                };
+
+            let (liquidity_ui_amount, _) =
+                liquidity_token_ui_amount(None, ui_amount, liquidity_token_info.as_ref(), true);
+            let msg = format!(
+                "{} ({}): {}{}{} - {}",
+                account.address,
+                account.token,
+                account.token.symbol(),
+                ui_amount.separated_string_with_fixed_place(2),
+                liquidity_ui_amount,
+                account.description
+            );
+            println!("{msg}");
+            if ui_amount > 0.01 {
+                notifier.send(&msg).await;
+            }
+            account.assert_lot_balance();
+
+            if summary_only {
+                continue;
+            }
+
+            let open_orders = open_orders
+                .iter()
+                .filter(|oo| oo.deposit_address == account.address && oo.token == account.token)
+                .collect::<Vec<_>>();
+
+            if !account.lots.is_empty() || !open_orders.is_empty() {
+                let mut lots = account.lots.iter().collect::<Vec<_>>();
+                lots.sort_by_key(|lot| lot.acquisition.when);
+
+                let mut account_income = 0.;
+                let mut account_current_value = 0.;
+                let mut account_unrealized_short_term_gain = 0.;
+                let mut account_unrealized_long_term_gain = 0.;
+
+                for lot in lots {
+                    let mut account_unrealized_gain = 0.;
+                    let mut long_term_cap_gain = false;
+                    println_lot(
+                        account.token,
+                        lot,
+                        current_token_price,
+                        liquidity_token_info.as_ref(),
+                        &mut account_income,
+                        &mut account_unrealized_gain,
+                        &mut long_term_cap_gain,
+                        &mut account_current_value,
+                        None,
+                        verbose,
+                    )
+                    .await;
+
+                    annual_realized_gains
+                        .entry(lot.acquisition.when.year() as usize)
+                        .or_default()
+                        .record_income(
+                            lot.acquisition.when.month0() as usize,
+                            lot.income(account.token),
+                        );
+
+                    if long_term_cap_gain {
+                        account_unrealized_long_term_gain += account_unrealized_gain;
+                    } else {
+                        account_unrealized_short_term_gain += account_unrealized_gain;
+                    }
+                }
+
+                for open_order in open_orders {
+                    let mut lots = open_order.lots.iter().collect::<Vec<_>>();
+                    lots.sort_by_key(|lot| lot.acquisition.when);
+                    let ui_amount = open_order.ui_amount.unwrap_or_else(|| {
+                        account
+                            .token
+                            .ui_amount(lots.iter().map(|lot| lot.amount).sum::<u64>())
+                    });
+                    let quote =
+                        TradingPair::from_exchange_symbol(open_order.exchange, &open_order.pair)
+                            .map_or_else(|_| "USD".into(), |pair| pair.quote);
+                    println!(
+                        " [Open {}: {} {} at {} | id {} created {}]",
+                        open_order.pair,
+                        format_order_side(open_order.side),
+                        account.token.format_ui_amount(ui_amount),
+                        format_quote_amount(&open_order.price.to_string(), &quote),
+                        open_order.order_id,
+                        HumanTime::from(open_order.creation_time),
+                    );
+                    for lot in lots {
+                        let mut account_unrealized_gain = 0.;
+                        let mut long_term_cap_gain = false;
+                        println_lot(
+                            account.token,
+                            lot,
+                            current_token_price,
+                            liquidity_token_info.as_ref(),
+                            &mut account_income,
+                            &mut account_unrealized_gain,
+                            &mut long_term_cap_gain,
+                            &mut account_current_value,
+                            None,
+                            verbose,
+                        )
+                        .await;
+
+                        annual_realized_gains
+                            .entry(lot.acquisition.when.year() as usize)
+                            .or_default()
+                            .record_income(
+                                lot.acquisition.when.month0() as usize,
+                                lot.income(account.token),
+                            );
+
+                        if long_term_cap_gain {
+                            account_unrealized_long_term_gain += account_unrealized_gain;
+                        } else {
+                            account_unrealized_short_term_gain += account_unrealized_gain;
+                        }
+                    }
+                }
+
+                println!(
+                    "    Value: ${}{}",
+                    account_current_value.separated_string_with_fixed_place(2),
+                    if account.token.fiat_fungible() {
+                        "".into()
+                    } else {
+                        format!(
+                            ", income: ${}, {}",
+                            account_income.separated_string_with_fixed_place(2),
+                            if unified_tax_rate {
+                                format!(
+                                    "unrealized cap gain: ${}",
+                                    (account_unrealized_short_term_gain
+                                        + account_unrealized_long_term_gain)
+                                        .separated_string_with_fixed_place(2)
+                                )
+                            } else {
+                                format!("unrealized short-term cap gain: ${}, unrealized long-term cap gain: ${}",
+                                    account_unrealized_short_term_gain.separated_string_with_fixed_place(2),
+                                    account_unrealized_long_term_gain.separated_string_with_fixed_place(2)
+                                )
+                            }
+                        )
+                    }
+                );
+
+                total_unrealized_short_term_gain += account_unrealized_short_term_gain;
+                total_unrealized_long_term_gain += account_unrealized_long_term_gain;
+                total_income += account_income;
+                total_current_value += account_current_value;
+
+                held_token.2.short_term_cap_gain += account_unrealized_short_term_gain;
+                held_token.2.long_term_cap_gain += account_unrealized_long_term_gain;
+            } else {
+                println!("  No lots");
+            }
+            println!();
+        }
+
+        if account_filter.is_some() || summary_only {
+            return Ok(());
+        }
+
+        let mut disposed_lots = db.disposed_lots();
+        disposed_lots.sort_by_key(|lot| lot.when);
+        if !disposed_lots.is_empty() {
+            println!("Disposed ({} lots):", disposed_lots.len());
+
+            let mut disposed_income = 0.;
+            let mut disposed_short_term_cap_gain = 0.;
+            let mut disposed_long_term_cap_gain = 0.;
+            let mut disposed_value = 0.;
+
+            for (i, disposed_lot) in disposed_lots.iter().enumerate() {
+                let mut long_term_cap_gain = false;
+                let mut disposed_cap_gain = 0.;
+                let msg = format_disposed_lot(
+                    disposed_lot,
+                    &mut disposed_income,
+                    &mut disposed_cap_gain,
+                    &mut long_term_cap_gain,
+                    &mut disposed_value,
+                    verbose,
+                );
+
+                if show_all_disposed_lots {
+                    println!("{msg}");
+                } else {
+                    if disposed_lots.len() > 5 && i == disposed_lots.len().saturating_sub(5) {
+                        println!("...");
+                    }
+                    if i > disposed_lots.len().saturating_sub(5) {
+                        println!("{msg}");
+                    }
+                }
+
+                annual_realized_gains
+                    .entry(disposed_lot.lot.acquisition.when.year() as usize)
+                    .or_default()
+                    .record_income(
+                        disposed_lot.lot.acquisition.when.month0() as usize,
+                        disposed_lot.lot.income(disposed_lot.token),
+                    );
+
+                let annual_realized_gain = annual_realized_gains
+                    .entry(disposed_lot.when.year() as usize)
+                    .or_default();
+
+                if long_term_cap_gain {
+                    disposed_long_term_cap_gain += disposed_cap_gain;
+                    annual_realized_gain.record_long_term_cap_gain(
+                        disposed_lot.when.month0() as usize,
+                        disposed_cap_gain,
+                    );
+                } else {
+                    disposed_short_term_cap_gain += disposed_cap_gain;
+                    annual_realized_gain.record_short_term_cap_gain(
+                        disposed_lot.when.month0() as usize,
+                        disposed_cap_gain,
+                    );
+                }
+            }
+            println!(
+                "    Disposed value: ${} (income: ${}, {})",
+                disposed_value.separated_string_with_fixed_place(2),
+                disposed_income.separated_string_with_fixed_place(2),
+                if unified_tax_rate {
+                    format!(
+                        "cap gain: ${}",
+                        (disposed_short_term_cap_gain + disposed_long_term_cap_gain)
+                            .separated_string_with_fixed_place(2)
+                    )
+                } else {
+                    format!(
+                        "short-term cap gain: ${}, long-term cap gain: ${}",
+                        disposed_short_term_cap_gain.separated_string_with_fixed_place(2),
+                        disposed_long_term_cap_gain.separated_string_with_fixed_place(2)
+                    )
+                }
+            );
+            println!();
+        }
+
+        if let Some(sweep_stake_account) = db.get_sweep_stake_account() {
+            println!("Sweep stake account: {}", sweep_stake_account.address);
+            println!(
+                "Stake authority: {}",
+                sweep_stake_account.stake_authority.display()
+            );
+            println!();
+        }
+
+        let tax_rate = db.get_tax_rate();
+        println!("Realized Gains");
+        if unified_tax_rate {
+            println!("  Year    | Income          |       Cap gain | Estimated Tax ");
+        } else {
+            println!(
+                "  Year    | Income          | Short-term gain | Long-term gain | Estimated Tax "
+            );
+        }
+        for (year, annual_realized_gain) in annual_realized_gains {
+            let (symbol, realized_gains) = {
+                ('P', annual_realized_gain.by_payment_period)
+                // TODO: Add user option to restore `by_quarter` display
+                //('Q', annual_realized_gains.by_quarter)
+            };
+            for (q, realized_gain) in realized_gains.iter().enumerate() {
+                if *realized_gain != RealizedGain::default() {
+                    let tax = if let Some(tax_rate) = tax_rate {
+                        let tax = [
+                            realized_gain.income * tax_rate.income,
+                            realized_gain.short_term_cap_gain * tax_rate.short_term_gain
+                                + realized_gain.long_term_cap_gain * tax_rate.long_term_gain,
+                        ]
+                        .into_iter()
+                        .map(|x| x.max(0.))
+                        .sum::<f64>();
+
+                        if tax > 0. {
+                            format!("${}", tax.separated_string_with_fixed_place(2))
+                        } else {
+                            String::new()
+                        }
+                    } else {
+                        "-".into()
+                    };
+
+                    println!(
+                        "  {} {}{} | ${:14} | {}| {}",
+                        year,
+                        symbol,
+                        q + 1,
+                        realized_gain.income.separated_string_with_fixed_place(2),
+                        if unified_tax_rate {
+                            format!(
+                                "${:14}",
+                                (realized_gain.short_term_cap_gain
+                                    + realized_gain.long_term_cap_gain)
+                                    .separated_string_with_fixed_place(2)
+                            )
+                        } else {
+                            format!(
+                                "${:14} | ${:14}",
+                                realized_gain
+                                    .short_term_cap_gain
+                                    .separated_string_with_fixed_place(2),
+                                realized_gain
+                                    .long_term_cap_gain
+                                    .separated_string_with_fixed_place(2)
+                            )
+                        },
+                        tax
+                    );
+                }
+            }
+        }
+        println!();
+
+        println!("Current Holdings");
+        for (held_token, (current_token_price, total_held_amount, unrealized_gain)) in held_tokens {
+            if total_held_amount == 0 {
+                continue;
+            }
+
+            let estimated_tax = tax_rate
+                .and_then(|tax_rate| {
+                    let tax = unrealized_gain.short_term_cap_gain * tax_rate.short_term_gain
+                        + unrealized_gain.long_term_cap_gain * tax_rate.long_term_gain;
+
+                    if tax > 0. {
+                        Some(format!(
+                            "; ${} estimated tax",
+                            tax.separated_string_with_fixed_place(2)
+                        ))
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or_default();
+
+            println!(
+                "  {:<7}       {:<20} (${:14} ; ${} per {}{})",
+                held_token.to_string(),
+                held_token.format_amount(total_held_amount),
+                current_token_price
+                    .map(|current_token_price| f64::try_from(
+                        Decimal::from_f64(held_token.ui_amount(total_held_amount)).unwrap()
+                            * current_token_price
+                    )
+                    .unwrap()
+                    .separated_string_with_fixed_place(2))
+                    .unwrap_or_else(|| "?".into()),
+                current_token_price
+                    .map(|current_token_price| f64::try_from(current_token_price)
+                        .unwrap()
+                        .separated_string_with_fixed_place(3))
+                    .unwrap_or_else(|| "?".into()),
+                held_token,
+                estimated_tax,
+            );
+        }
+        println!();
+
+        println!("Summary");
+        println!(
+            "  Current Value:       ${}",
+            total_current_value.separated_string_with_fixed_place(2)
+        );
+        println!(
+            "  Income:              ${} (realized)",
+            total_income.separated_string_with_fixed_place(2)
+        );
+        if unified_tax_rate {
+            println!(
+                "  Cap gain:            ${} (unrealized)",
+                (total_unrealized_short_term_gain + total_unrealized_long_term_gain)
+                    .separated_string_with_fixed_place(2)
+            );
+        } else {
+            println!(
+                "  Short-term cap gain: ${} (unrealized)",
+                total_unrealized_short_term_gain.separated_string_with_fixed_place(2)
+            );
+            println!(
+                "  Long-term cap gain:  ${} (unrealized)",
+                total_unrealized_long_term_gain.separated_string_with_fixed_place(2)
+            );
+        }
+
+        let pending_deposits = db.pending_deposits(None).len();
+        let pending_withdrawals = db.pending_withdrawals(None).len();
+        let pending_transfers = db.pending_transfers().len();
+        let pending_swaps = db.pending_swaps().len();
+
+        if pending_deposits + pending_withdrawals + pending_transfers + pending_swaps > 0 {
+            println!();
+        }
+        if pending_deposits > 0 {
+            println!("  !! Pending deposits: {pending_deposits}");
+        }
+        if pending_withdrawals > 0 {
+            println!("  !! Pending withdrawals: {pending_withdrawals}");
+        }
+        if pending_transfers > 0 {
+            println!("  !! Pending transfers: {pending_transfers}");
+        }
+        if pending_swaps > 0 {
+            println!("  !! Pending swaps: {pending_swaps}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn process_account_xls(
+    db: &Db,
+    outfile: &str,
+    filter_by_year: Option<i32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use simple_excel_writer::*;
+
+    let mut workbook = Workbook::create(outfile);
+
+    let mut sheet = workbook.create_sheet(&match filter_by_year {
+        Some(year) => format!("Disposed in {year}"),
+        None => "Disposed".into(),
+    });
+    sheet.add_column(Column { width: 12. });
+    sheet.add_column(Column { width: 15. });
+    sheet.add_column(Column { width: 12. });
+    sheet.add_column(Column { width: 12. });
+    sheet.add_column(Column { width: 10. });
+    sheet.add_column(Column { width: 40. });
+    sheet.add_column(Column { width: 12. });
+    sheet.add_column(Column { width: 10. });
+    sheet.add_column(Column { width: 10. });
+    sheet.add_column(Column { width: 10. });
+    sheet.add_column(Column { width: 10. });
+    sheet.add_column(Column { width: 40. });
+
+    let mut disposed_lots = db.disposed_lots();
+    disposed_lots.sort_by_key(|lot| lot.when);
+
+    if let Some(year) = filter_by_year {
+        // Exclude disposed lots that were neither acquired nor disposed of in the filter year
+        disposed_lots.retain(|disposed_lot| {
+            (disposed_lot.lot.acquisition.when.year() == year
+                && disposed_lot.lot.income(disposed_lot.token) > 0.)
+                || disposed_lot.when.year() == year
+        })
+    }
+
+    workbook.write_sheet(&mut sheet, |sheet_writer| {
+        sheet_writer.append_row(row![
+            "Token",
+            "Amount",
+            "Income (USD)",
+            "Acq. Date",
+            "Acq. Price (USD)",
+            "Acquisition Description",
+            "Cap Gain (USD)",
+            "Cap Gain Type",
+            "Sale Date",
+            "Sale Price (USD)",
+            "Fee",
+            "Fee Currency",
+            "Fee (USD)",
+            "Fee (USD) Price",
+            "Sale Description"
+        ])?;
+
+        for disposed_lot in disposed_lots {
+            let long_term_cap_gain =
+                is_long_term_cap_gain(disposed_lot.lot.acquisition.when, Some(disposed_lot.when));
+
+            let fee = disposed_lot.kind.fee();
+            // Fees recorded before they were valued in USD only have a USD amount if charged in USD
+            let usd_fee = disposed_lot.kind.usd_fee().cloned().or_else(|| {
+                fee.filter(|(_, currency)| is_usd_equivalent(currency))
+                    .map(|(amount, _)| UsdFee {
+                        amount: *amount,
+                        daily_close: false,
+                    })
+            });
+            let usd_fee = usd_fee.as_ref();
+
+            let mut income = disposed_lot.lot.income(disposed_lot.token);
+            if let Some(year) = filter_by_year {
+                if disposed_lot.lot.acquisition.when.year() != year {
+                    income = 0. // Exclude income from other years
+                }
+            }
+
+            sheet_writer.append_row(row![
+                disposed_lot.token.to_string(),
+                disposed_lot.token.ui_amount(disposed_lot.lot.amount),
+                income,
+                disposed_lot.lot.acquisition.when.to_string(),
+                disposed_lot.lot.acquisition.price().to_string(),
+                disposed_lot.lot.acquisition.kind.to_string(),
+                disposed_lot
+                    .lot
+                    .cap_gain(disposed_lot.token, disposed_lot.price()),
+                if long_term_cap_gain { "Long" } else { "Short" },
+                disposed_lot.when.to_string(),
+                disposed_lot.price().to_string(),
+                fee.map(|(amount, _)| *amount).unwrap_or_default(),
+                fee.map(|(_, currency)| currency.clone())
+                    .unwrap_or_default(),
+                usd_fee.map(|usd_fee| usd_fee.amount).unwrap_or_default(),
+                match (fee, usd_fee) {
+                    (_, Some(usd_fee)) if usd_fee.daily_close => "Daily close",
+                    (_, Some(_)) => "Minute",
+                    (Some((amount, _)), None) if *amount > 0. => "Unavailable",
+                    _ => "",
+                },
+                disposed_lot.kind.to_string()
+            ])?;
+        }
+        Ok(())
+    })?;
+
+    let mut current_holdings_rows = vec![];
+    let mut current_holdings_by_year_rows = vec![];
+
+    #[derive(Clone)]
+    enum R {
+        Number(f64),
+        Text(String),
+    }
+
+    impl ToCellValue for R {
+        fn to_cell_value(&self) -> CellValue {
+            match self {
+                R::Number(x) => x.to_cell_value(),
+                R::Text(x) => x.to_cell_value(),
+            }
+        }
+    }
+
+    for account in db.get_accounts() {
+        for lot in account.lots.iter() {
+            let row = (
+                lot.acquisition.when,
+                vec![
+                    R::Text(account.token.to_string()),
+                    R::Number(account.token.ui_amount(lot.amount)),
+                    R::Number(lot.income(account.token)),
+                    R::Text(lot.acquisition.when.to_string()),
+                    R::Text(lot.acquisition.price().to_string()),
+                    R::Text(lot.acquisition.kind.to_string()),
+                    R::Text(account.description.clone()),
+                    R::Text(account.address.to_string()),
+                ],
+            );
+            current_holdings_rows.push(row.clone());
+            if let Some(year) = filter_by_year {
+                if lot.acquisition.when.year() == year {
+                    current_holdings_by_year_rows.push(row);
+                    continue;
+                }
+            }
+        }
+    }
+
+    for open_order in db.open_orders(None, Some(OrderSide::Sell)) {
+        for lot in open_order.lots.iter() {
+            let row = (
+                lot.acquisition.when,
+                vec![
+                    R::Text(open_order.token.to_string()),
+                    R::Number(open_order.token.ui_amount(lot.amount)),
+                    R::Number(lot.income(open_order.token)),
+                    R::Text(lot.acquisition.when.to_string()),
+                    R::Text(lot.acquisition.price().to_string()),
+                    R::Text(lot.acquisition.kind.to_string()),
+                    R::Text(format!(
+                        "Open Order: {:?} {}",
+                        open_order.exchange, open_order.pair
+                    )),
+                    R::Text(open_order.deposit_address.to_string()),
+                ],
+            );
+            current_holdings_rows.push(row.clone());
+            if let Some(year) = filter_by_year {
+                if lot.acquisition.when.year() == year {
+                    current_holdings_by_year_rows.push(row);
+                    continue;
+                }
+            }
+        }
+    }
+    current_holdings_rows.sort_by_key(|row| row.0);
+    current_holdings_by_year_rows.sort_by_key(|row| row.0);
+
+    let mut write_holdings = |name: String, rows: Vec<(_, Vec<R>)>| {
+        let mut sheet = workbook.create_sheet(&name);
+
+        sheet.add_column(Column { width: 12. });
+        sheet.add_column(Column { width: 15. });
+        sheet.add_column(Column { width: 12. });
+        sheet.add_column(Column { width: 12. });
+        sheet.add_column(Column { width: 10. });
+        sheet.add_column(Column { width: 40. });
+        sheet.add_column(Column { width: 40. });
+        sheet.add_column(Column { width: 50. });
+
+        workbook.write_sheet(&mut sheet, |sheet_writer| {
+            sheet_writer.append_row(row![
+                "Token",
+                "Amount",
+                "Income (USD)",
+                "Acq. Date",
+                "Acq. Price (USD)",
+                "Acquisition Description",
+                "Account Description",
+                "Account Address"
+            ])?;
+
+            for (_, row) in rows {
+                sheet_writer.append_row(Row::from_iter(row.into_iter()))?;
+            }
+
+            Ok(())
+        })
+    };
+    if let Some(year) = filter_by_year {
+        write_holdings(
+            format!("Holdings acquired in {year}"),
+            current_holdings_by_year_rows,
+        )?;
+    }
+    write_holdings("All Holdings".to_string(), current_holdings_rows)?;
+
+    workbook.close()?;
+    println!("Wrote {outfile}");
+
+    Ok(())
+}
+
+async fn process_account_merge<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    from_address: Pubkey,
+    into_address: Pubkey,
+    authority_address: Pubkey,
+    signers: T,
+    existing_signature: Option<Signature>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = MaybeToken::SOL(); // TODO: Support merging tokens one day
+
+    if let Some(existing_signature) = existing_signature {
+        db.record_transfer(
+            existing_signature,
+            0, /*last_valid_block_height*/
+            None,
+            from_address,
+            token,
+            into_address,
+            token,
+            LotSelectionMethod::default(),
+            None,
+        )?;
+    } else {
+        let (recent_blockhash, last_valid_block_height) =
+            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+        let from_account = rpc_client
+            .get_account_with_commitment(&from_address, rpc_client.commitment())?
+            .value
+            .ok_or_else(|| format!("From account, {from_address}, does not exist"))?;
+
+        let from_tracked_account = db
+            .get_account(from_address, token)
+            .ok_or_else(|| format!("Account, {from_address}, is not tracked"))?;
+
+        let into_account = rpc_client
+            .get_account_with_commitment(&into_address, rpc_client.commitment())?
+            .value
+            .ok_or_else(|| format!("From account, {into_address}, does not exist"))?;
+
+        let authority_account = if from_address == authority_address {
+            from_account.clone()
+        } else {
+            rpc_client
+                .get_account_with_commitment(&authority_address, rpc_client.commitment())?
+                .value
+                .ok_or_else(|| format!("Authority account, {authority_address}, does not exist"))?
+        };
+
+        let amount = from_tracked_account.last_update_balance;
+
+        let instructions = if from_account.owner == solana_sdk::stake::program::id()
+            && into_account.owner == solana_sdk::stake::program::id()
+        {
+            solana_sdk::stake::instruction::merge(&into_address, &from_address, &authority_address)
+        } else if from_account.owner == solana_sdk::stake::program::id()
+            && into_account.owner == system_program::id()
+        {
+            vec![solana_sdk::stake::instruction::withdraw(
+                &from_address,
+                &authority_address,
+                &into_address,
+                amount,
+                None,
+            )]
+        } else {
+            return Err(format!(
+                "Unsupported merge from {} account to {} account",
+                from_account.owner, into_account.owner
+            )
+            .into());
+        };
+
+        println!("Merging {from_address} into {into_address}");
+        if from_address != authority_address {
+            println!("Authority address: {authority_address}");
+        }
+
+        let mut message = Message::new(&instructions, Some(&authority_address));
+        message.recent_blockhash = recent_blockhash;
+        if rpc_client.get_fee_for_message(&message)? > authority_account.lamports {
+            return Err("Insufficient funds for transaction fee".into());
+        }
+
+        let mut transaction = Transaction::new_unsigned(message);
+        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+        if simulation_result.err.is_some() {
+            return Err(format!("Simulation failure: {simulation_result:?}").into());
+        }
+
+        transaction.try_sign(&signers, recent_blockhash)?;
+        let signature = transaction.signatures[0];
+        println!("Transaction signature: {signature}");
+
+        db.record_transfer(
+            signature,
+            last_valid_block_height,
+            Some(amount),
+            from_address,
+            token,
+            into_address,
+            token,
+            LotSelectionMethod::default(),
+            None,
+        )?;
+
+        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+            db.cancel_transfer(signature)?;
+            return Err("Merge failed".into());
+        }
+        let when = get_signature_date(rpc_client, signature).await?;
+        db.confirm_transfer(signature, when)?;
+        db.remove_account(from_address, token)?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_sweep<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    from_address: Pubkey,
+    token: MaybeToken,
+    retain_amount: u64,
+    no_sweep_ok: bool,
+    from_authority_address: Pubkey,
+    signers: T,
+    to_address: Option<Pubkey>,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+    let fee_calculator = get_deprecated_fee_calculator(rpc_client)?;
+
+    let from_account = rpc_client
+        .get_account_with_commitment(&from_address, rpc_client.commitment())?
+        .value
+        .ok_or_else(|| format!("Account, {from_address}, does not exist"))?;
+
+    let from_tracked_account = db
+        .get_account(from_address, token)
+        .ok_or_else(|| format!("Account, {from_address}, is not tracked"))?;
+
+    let authority_account = if from_address == from_authority_address {
+        from_account.clone()
+    } else {
+        rpc_client
+            .get_account_with_commitment(&from_authority_address, rpc_client.commitment())?
+            .value
+            .ok_or_else(|| format!("Authority account, {from_authority_address}, does not exist"))?
+    };
+
+    let mut num_transaction_signatures = 1; // from_address_authority
+
+    let (to_address, via_transitory_stake) = if let Some(to_address) = to_address {
+        let _ = db
+            .get_account(to_address, token)
+            .ok_or_else(|| format!("Account {to_address} ({token}) does not exist"))?;
+        (to_address, None)
+    } else {
+        if !token.is_sol() {
+            return Err("--to <ADDRESS> must be provided for token sweeps".into());
+        }
+
+        let transitory_stake_account = Keypair::new();
+
+        let sweep_stake_account = db
+            .get_sweep_stake_account()
+            .ok_or("Sweep stake account not configured")?;
+        let sweep_stake_authority_keypair = read_keypair_file(&sweep_stake_account.stake_authority)
+            .map_err(|err| {
+                format!(
+                    "Failed to read {}: {}",
+                    sweep_stake_account.stake_authority.display(),
+                    err
+                )
+            })?;
+
+        num_transaction_signatures += 1; // transitory_stake_account
+        if from_authority_address != sweep_stake_authority_keypair.pubkey() {
+            num_transaction_signatures += 1;
+        }
+
+        (
+            transitory_stake_account.pubkey(),
+            Some((
+                transitory_stake_account,
+                sweep_stake_authority_keypair,
+                sweep_stake_account.address,
+            )),
+        )
+    };
+
+    if authority_account.lamports
+        < num_transaction_signatures * fee_calculator.lamports_per_signature
+    {
+        return Err(format!(
+            "Authority has insufficient funds for the transaction fee of {}",
+            token.ui_amount(num_transaction_signatures * fee_calculator.lamports_per_signature)
+        )
+        .into());
+    }
+
+    let (mut instructions, sweep_amount) = if token.is_sol() {
+        if from_account.lamports < from_tracked_account.last_update_balance {
+            return Err(format!(
+                "{}: On-chain account balance ({}) less than tracked balance ({})",
+                from_address,
+                token.ui_amount(from_account.lamports),
+                token.ui_amount(from_tracked_account.last_update_balance)
+            )
+            .into());
+        }
+
+        if from_account.owner == system_program::id() {
+            let lamports = if from_address == from_authority_address {
+                from_tracked_account.last_update_balance.saturating_sub(
+                    num_transaction_signatures * fee_calculator.lamports_per_signature
+                        + retain_amount,
+                )
+            } else {
+                from_tracked_account
+                    .last_update_balance
+                    .saturating_sub(retain_amount)
+            };
+
+            (
+                vec![system_instruction::transfer(
+                    &from_address,
+                    &to_address,
+                    lamports,
+                )],
+                lamports,
+            )
+        } else if from_account.owner == solana_vote_program::id() {
+            let minimum_balance = rpc_client.get_minimum_balance_for_rent_exemption(
+                solana_vote_program::vote_state::VoteState::size_of(),
+            )?;
+
+            let lamports = from_tracked_account
+                .last_update_balance
+                .saturating_sub(minimum_balance + retain_amount);
+
+            (
+                vec![solana_vote_program::vote_instruction::withdraw(
+                    &from_address,
+                    &from_authority_address,
+                    lamports,
+                    &to_address,
+                )],
+                lamports,
+            )
+        } else if from_account.owner == solana_sdk::stake::program::id() {
+            let lamports = from_tracked_account
+                .last_update_balance
+                .saturating_sub(retain_amount);
+
+            (
+                vec![solana_sdk::stake::instruction::withdraw(
+                    &from_address,
+                    &from_authority_address,
+                    &to_address,
+                    lamports,
+                    None,
+                )],
+                lamports,
+            )
+        } else {
+            return Err(format!("Unsupported `from` account owner: {}", from_account.owner).into());
+        }
+    } else {
+        let token = token.token().unwrap();
+
+        let lamports = from_tracked_account
+            .last_update_balance
+            .saturating_sub(retain_amount);
+
+        (
+            vec![spl_token::instruction::transfer_checked(
+                &spl_token::id(),
+                &token.ata(&from_address),
+                &token.mint(),
+                &token.ata(&to_address),
+                &from_authority_address,
+                &[],
+                lamports,
+                token.decimals(),
+            )
+            .unwrap()],
+            lamports,
+        )
+    };
+
+    if sweep_amount < token.amount(1.) {
+        let msg = format!(
+            "{} has less than {}1 to sweep ({})",
+            from_address,
+            token.symbol(),
+            token.ui_amount(sweep_amount)
+        );
+        return if no_sweep_ok {
+            println!("{msg}");
+            Ok(())
+        } else {
+            Err(msg.into())
+        };
+    }
+
+    println!("From address: {from_address}");
+    if from_address != from_authority_address {
+        println!("Authority address: {from_authority_address}");
+    }
+    println!("Destination address: {to_address}");
+    println!(
+        "Sweep amount: {}{}",
+        token.symbol(),
+        token.ui_amount(sweep_amount)
+    );
+
+    let msg = if let Some((
+        transitory_stake_account,
+        sweep_stake_authority_keypair,
+        sweep_stake_address,
+    )) = via_transitory_stake.as_ref()
+    {
+        assert_eq!(to_address, transitory_stake_account.pubkey());
+
+        let (sweep_stake_authorized, sweep_stake_vote_account_address) =
+            rpc_client_utils::get_stake_authorized(rpc_client, *sweep_stake_address)?;
+
+        if sweep_stake_authorized.staker != sweep_stake_authority_keypair.pubkey() {
+            return Err("Stake authority mismatch".into());
+        }
+
+        instructions.append(&mut vec![
+            system_instruction::allocate(
+                &transitory_stake_account.pubkey(),
+                std::mem::size_of::<solana_sdk::stake::state::StakeState>() as u64,
+            ),
+            system_instruction::assign(
+                &transitory_stake_account.pubkey(),
+                &solana_sdk::stake::program::id(),
+            ),
+            solana_sdk::stake::instruction::initialize(
+                &transitory_stake_account.pubkey(),
+                &sweep_stake_authorized,
+                &solana_sdk::stake::state::Lockup::default(),
+            ),
+            solana_sdk::stake::instruction::delegate_stake(
+                &transitory_stake_account.pubkey(),
+                &sweep_stake_authority_keypair.pubkey(),
+                &sweep_stake_vote_account_address,
+            ),
+        ]);
+        format!(
+            "Sweeping {}{} from {} into {} (via {})",
+            token.symbol(),
+            token.ui_amount(sweep_amount),
+            from_address,
+            sweep_stake_address,
+            to_address
+        )
+    } else {
+        format!(
+            "Sweeping {} from {} into {}",
+            token.ui_amount(sweep_amount),
+            from_address,
+            to_address
+        )
+    };
+
+    let mut message = Message::new(&instructions, Some(&from_authority_address));
+    message.recent_blockhash = recent_blockhash;
+    assert_eq!(
+        rpc_client.get_fee_for_message(&message)?,
+        num_transaction_signatures * fee_calculator.lamports_per_signature
+    );
+
+    let mut transaction = Transaction::new_unsigned(message);
+    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+    if simulation_result.err.is_some() {
+        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    }
+
+    transaction.partial_sign(&signers, recent_blockhash);
+    if let Some((transitory_stake_account, sweep_stake_authority_keypair, ..)) =
+        via_transitory_stake.as_ref()
+    {
+        transaction.try_sign(
+            &[transitory_stake_account, sweep_stake_authority_keypair],
+            recent_blockhash,
+        )?;
+    }
+
+    let signature = transaction.signatures[0];
+    println!("Transaction signature: {signature}");
+
+    let epoch = rpc_client.get_epoch_info()?.epoch;
+    if let Some((transitory_stake_account, ..)) = via_transitory_stake.as_ref() {
+        db.add_transitory_sweep_stake_address(transitory_stake_account.pubkey(), epoch)?;
+    }
+    db.record_transfer(
+        signature,
+        last_valid_block_height,
+        Some(sweep_amount),
+        from_address,
+        token,
+        to_address,
+        token,
+        LotSelectionMethod::default(),
+        None,
+    )?;
+
+    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+        db.cancel_transfer(signature)?;
+        if let Some((transitory_stake_account, ..)) = via_transitory_stake.as_ref() {
+            db.remove_transitory_sweep_stake_address(transitory_stake_account.pubkey())?;
+        }
+        return Err("Sweep failed".into());
+    }
+    println!("Confirming sweep: {signature}");
+    let when = get_signature_date(rpc_client, signature).await?;
+    db.confirm_transfer(signature, when)?;
+
+    notifier.send(&msg).await;
+    println!("{msg}");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_split<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    from_address: Pubkey,
+    amount: Option<u64>,
+    description: Option<String>,
+    lot_selection_method: LotSelectionMethod,
+    lot_numbers: Option<HashSet<usize>>,
+    authority_address: Pubkey,
+    signers: T,
+    into_keypair: Option<Keypair>,
+    if_balance_exceeds: Option<f64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // TODO: Support splitting two system accounts? Tokens? Otherwise at least error cleanly when it's attempted
+    let token = MaybeToken::SOL(); // TODO: Support splitting tokens one day
+
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+    let into_keypair = into_keypair.unwrap_or_else(Keypair::new);
+    if db.get_account(into_keypair.pubkey(), token).is_some() {
+        return Err(format!(
+            "Account {} ({}) already exists",
+            into_keypair.pubkey(),
+            token
+        )
+        .into());
+    }
+
+    let from_account = db
+        .get_account(from_address, MaybeToken::SOL())
+        .ok_or_else(|| format!("SOL account does not exist for {from_address}"))?;
+
+    let (split_all, amount, description) = match amount {
+        None => (
+            true,
+            from_account.last_update_balance,
+            description.unwrap_or(from_account.description),
+        ),
+        Some(amount) => (
+            false,
+            amount,
+            description.unwrap_or_else(|| format!("Split at {}", Local::now())),
+        ),
+    };
+
+    if let Some(if_balance_exceeds) = if_balance_exceeds {
+        if token.ui_amount(amount) < if_balance_exceeds {
+            println!(
+                "Split declined because {:?} balance is less than {}",
+                from_address,
+                token.format_ui_amount(if_balance_exceeds)
+            );
+            return Ok(());
+        }
+    }
+
+    let instructions = solana_sdk::stake::instruction::split(
+        &from_address,
+        &authority_address,
+        amount,
+        &into_keypair.pubkey(),
+    );
+
+    let message = Message::new(&instructions, Some(&authority_address));
+
+    let mut transaction = Transaction::new_unsigned(message);
+    transaction.message.recent_blockhash = recent_blockhash;
+    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+    if simulation_result.err.is_some() {
+        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    }
+
+    println!(
+        "Splitting {} from {} into {}",
+        token.ui_amount(amount),
+        from_address,
+        into_keypair.pubkey(),
+    );
+
+    transaction.partial_sign(&signers, recent_blockhash);
+    transaction.try_sign(&[&into_keypair], recent_blockhash)?;
+
+    let signature = transaction.signatures[0];
+    println!("Transaction signature: {signature}");
+
+    let epoch = rpc_client.get_epoch_info()?.epoch;
+    db.add_account(TrackedAccount {
+        address: into_keypair.pubkey(),
+        token,
+        description,
+        last_update_epoch: epoch.saturating_sub(1),
+        last_update_balance: 0,
+        lots: vec![],
+        no_sync: from_account.no_sync,
+    })?;
+    db.record_transfer(
+        signature,
+        last_valid_block_height,
+        Some(amount),
+        from_address,
+        token,
+        into_keypair.pubkey(),
+        token,
+        lot_selection_method,
+        lot_numbers,
+    )?;
+
+    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+        db.cancel_transfer(signature)?;
+        db.remove_account(into_keypair.pubkey(), MaybeToken::SOL())?;
+        return Err("Split failed".into());
+    }
+    println!("Split confirmed: {signature}");
+    let when = get_signature_date(rpc_client, signature).await?;
+    db.confirm_transfer(signature, when)?;
+    if split_all {
+        // TODO: This `remove_account` is racy and won't work in all cases. Consider plumbing the
+        // removal through `confirm_transfer` instead
+        let from_account = db.get_account(from_address, MaybeToken::SOL()).unwrap();
+        assert!(from_account.lots.is_empty());
+        db.remove_account(from_address, MaybeToken::SOL())?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_redelegate<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    from_address: Pubkey,
+    vote_account_address: Pubkey,
+    lot_selection_method: LotSelectionMethod,
+    authority_address: Pubkey,
+    signers: &T,
+    into_keypair: Option<Keypair>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+    let minimum_stake_account_balance = rpc_client
+        .get_minimum_balance_for_rent_exemption(solana_sdk::stake::state::StakeState::size_of())?;
+
+    let into_keypair = into_keypair.unwrap_or_else(Keypair::new);
+    if db
+        .get_account(into_keypair.pubkey(), MaybeToken::SOL())
+        .is_some()
+    {
+        return Err(format!(
+            "Account {} ({}) already exists",
+            into_keypair.pubkey(),
+            MaybeToken::SOL()
+        )
+        .into());
+    }
+
+    let from_account = db
+        .get_account(from_address, MaybeToken::SOL())
+        .ok_or_else(|| format!("SOL account does not exist for {from_address}"))?;
+
+    if from_account.last_update_balance < minimum_stake_account_balance * 2 {
+        return Err(format!(
+            "Account {} ({}) has insufficient balance",
+            into_keypair.pubkey(),
+            MaybeToken::SOL()
+        )
+        .into());
+    }
+    let redelegated_amount = from_account.last_update_balance - minimum_stake_account_balance;
+
+    let instructions = solana_sdk::stake::instruction::redelegate(
+        &from_address,
+        &authority_address,
+        &vote_account_address,
+        &into_keypair.pubkey(),
+    );
+
+    let message = Message::new(&instructions, Some(&authority_address));
+
+    let mut transaction = Transaction::new_unsigned(message);
+    transaction.message.recent_blockhash = recent_blockhash;
+    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+    if simulation_result.err.is_some() {
+        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    }
+
+    println!(
+        "Relegating {} to {} via{}",
+        from_address,
+        vote_account_address,
+        into_keypair.pubkey(),
+    );
+
+    transaction.partial_sign(signers, recent_blockhash);
+    transaction.try_sign(&[&into_keypair], recent_blockhash)?;
+
+    let signature = transaction.signatures[0];
+    println!("Transaction signature: {signature}");
+
+    let epoch = rpc_client.get_epoch_info()?.epoch;
+    db.add_account(TrackedAccount {
+        address: into_keypair.pubkey(),
+        token: MaybeToken::SOL(),
+        description: from_account.description,
+        last_update_epoch: epoch.saturating_sub(1),
+        last_update_balance: 0,
+        lots: vec![],
+        no_sync: None,
+    })?;
+    db.record_transfer(
+        signature,
+        last_valid_block_height,
+        Some(redelegated_amount),
+        from_address,
+        MaybeToken::SOL(),
+        into_keypair.pubkey(),
+        MaybeToken::SOL(),
+        lot_selection_method,
+        None,
+    )?;
+
+    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+        db.cancel_transfer(signature)?;
+        db.remove_account(into_keypair.pubkey(), MaybeToken::SOL())?;
+        return Err("Redelegate failed".into());
+    }
+    println!("Redelegation confirmed: {signature}");
+    let when = get_signature_date(rpc_client, signature).await?;
+    db.confirm_transfer(signature, when)?;
+
+    Ok(())
+}
+
+async fn process_account_sync(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    address: Option<Pubkey>,
+    max_epochs_to_process: Option<u64>,
+    reconcile_no_sync_account_balances: bool,
+    notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    process_account_sync_pending_transfers(db, rpc_client).await?;
+    process_account_sync_sweep(db, rpc_client, notifier).await?;
+
+    let (mut accounts, mut no_sync_accounts): (_, Vec<_>) = match address {
+        Some(address) => {
+            // sync all tokens for the given address...
+            let accounts = db.get_account_tokens(address);
+            if accounts.is_empty() {
+                return Err(format!("{address} does not exist").into());
+            }
+            accounts
+        }
+        None => db.get_accounts(),
+    }
+    .into_iter()
+    .partition(|account| !account.no_sync.unwrap_or_default());
+
+    if reconcile_no_sync_account_balances {
+        for account in no_sync_accounts.iter_mut() {
+            if account.lots.is_empty() {
+                continue;
+            }
+
+            let current_balance = account.token.balance(rpc_client, &account.address)?;
+
+            match current_balance.cmp(&account.last_update_balance) {
+                std::cmp::Ordering::Less => {
+                    println!(
+                        "\nWarning: {} ({}) balance is less than expected. Actual: {}{}, expected: {}{}\n",
+                        account.address,
+                        account.token,
+                        account.token.symbol(),
+                        account.token.ui_amount(current_balance),
+                        account.token.symbol(),
+                        account.token.ui_amount(account.last_update_balance)
+                    );
+                }
+                std::cmp::Ordering::Greater => {
+                    // sort by lowest basis
+                    account
+                        .lots
+                        .sort_by(|a, b| a.acquisition.price().cmp(&b.acquisition.price()));
+
+                    let lowest_basis_lot = &mut account.lots[0];
+                    let additional_balance = current_balance - account.last_update_balance;
+                    lowest_basis_lot.amount += additional_balance;
+
+                    let msg = format!(
+                        "{} ({}): Additional {}{} added",
+                        account.address,
+                        account.token,
+                        account.token.symbol(),
+                        account.token.ui_amount(additional_balance)
+                    );
+                    notifier.send(&msg).await;
+                    println!("{msg}");
+
+                    account.last_update_balance = current_balance;
+                    db.update_account(account.clone())?;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let current_sol_price = MaybeToken::SOL().get_current_price(rpc_client).await?;
+
+    let addresses: Vec<Pubkey> = accounts
+        .iter()
+        .map(|TrackedAccount { address, .. }| *address)
+        .collect::<Vec<_>>();
+
+    let epoch_info = rpc_client.get_epoch_info()?;
+    let mut stop_epoch = epoch_info.epoch.saturating_sub(1);
+
+    let start_epoch = accounts
+        .iter()
+        .map(
+            |TrackedAccount {
+                 last_update_epoch, ..
+             }| last_update_epoch,
+        )
+        .min()
+        .unwrap_or(&stop_epoch)
+        + 1;
+
+    if start_epoch > stop_epoch {
+        println!("Processed up to epoch {stop_epoch}");
+        return Ok(());
+    }
+
+    if let Some(max_epochs_to_process) = max_epochs_to_process {
+        if max_epochs_to_process == 0 {
+            return Ok(());
+        }
+        stop_epoch = stop_epoch.min(start_epoch.saturating_add(max_epochs_to_process - 1));
+    }
+
+    // Look for inflationary rewards
+    for epoch in start_epoch..=stop_epoch {
+        let msg = format!("Processing epoch: {epoch}");
+        notifier.send(&msg).await;
+        println!("{msg}");
+
+        let inflation_rewards = rpc_client.get_inflation_reward(&addresses, Some(epoch))?;
+
+        for (inflation_reward, address, account) in
+            itertools::izip!(inflation_rewards, addresses.iter(), accounts.iter_mut(),)
+        {
+            assert_eq!(*address, account.address);
+            if account.last_update_epoch >= epoch {
+                continue;
+            }
+
+            if let Some(inflation_reward) = inflation_reward {
+                assert!(!account.token.is_token()); // Only SOL accounts can receive inflationary rewards
+
+                account.last_update_balance += inflation_reward.amount;
+
+                let slot = inflation_reward.effective_slot;
+                let (when, price) =
+                    get_block_date_and_price(rpc_client, slot, account.token).await?;
+                let lot = Lot {
+                    lot_number: db.next_lot_number(),
+                    acquisition: LotAcquistion::new(
+                        when,
+                        price,
+                        LotAcquistionKind::EpochReward { epoch, slot },
+                    ),
+                    amount: inflation_reward.amount,
+                };
+
+                let msg = format!("{}: {}", account.address, account.description);
+                notifier.send(&msg).await;
+                println!("{msg}");
+
+                println_lot(
+                    account.token,
+                    &lot,
+                    Some(current_sol_price),
+                    None,
+                    &mut 0.,
+                    &mut 0.,
+                    &mut false,
+                    &mut 0.,
+                    Some(notifier),
+                    true,
+                )
+                .await;
+                account.lots.push(lot);
+            }
+        }
+    }
+
+    // Look for unexpected balance changes (such as transaction and rent rewards)
+    for account in accounts.iter_mut() {
+        account.last_update_epoch = stop_epoch;
+
+        let current_balance = account.token.balance(rpc_client, &account.address)?;
+        if current_balance < account.last_update_balance {
+            println!(
+                "\nWarning: {} ({}) balance is less than expected. Actual: {}{}, expected: {}{}\n",
+                account.address,
+                account.token,
+                account.token.symbol(),
+                account.token.ui_amount(current_balance),
+                account.token.symbol(),
+                account.token.ui_amount(account.last_update_balance)
+            );
+        } else if current_balance > account.last_update_balance + account.token.amount(0.005) {
+            let slot = epoch_info.absolute_slot;
+            let current_token_price = account.token.get_current_price(rpc_client).await?;
+            let (when, decimal_price) =
+                get_block_date_and_price(rpc_client, slot, account.token).await?;
+            let amount = current_balance - account.last_update_balance;
+
+            let lot = Lot {
+                lot_number: db.next_lot_number(),
+                acquisition: LotAcquistion::new(
+                    when,
+                    decimal_price,
+                    LotAcquistionKind::NotAvailable,
+                ),
+                amount,
+            };
+
+            let msg = format!(
+                "{} ({}): {}",
+                account.address, account.token, account.description
+            );
+            notifier.send(&msg).await;
+            println!("{msg}");
+
+            println_lot(
+                account.token,
+                &lot,
+                Some(current_token_price),
+                None,
+                &mut 0.,
+                &mut 0.,
+                &mut false,
+                &mut 0.,
+                Some(notifier),
+                true,
+            )
+            .await;
+            account.lots.push(lot);
+            account.last_update_balance = current_balance;
+        }
+
+        db.update_account(account.clone())?;
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_wrap<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    address: Pubkey,
+    amount: Option<u64>,
+    if_source_balance_exceeds: Option<u64>,
+    lot_selection_method: LotSelectionMethod,
+    lot_numbers: Option<HashSet<usize>>,
+    authority_address: Pubkey,
+    signers: T,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sol = MaybeToken::SOL();
+    let wsol = Token::wSOL;
+    let wsol_address = wsol.ata(&address);
+
+    let from_account = db
+        .get_account(address, sol)
+        .ok_or_else(|| format!("SOL account does not exist for {address}"))?;
+    let amount = amount.unwrap_or(from_account.last_update_balance);
+
+    if let Some(if_source_balance_exceeds) = if_source_balance_exceeds {
+        if from_account.last_update_balance < if_source_balance_exceeds {
+            println!(
+                "wrap declined because {} balance is less than {}{}",
+                address,
+                sol.symbol(),
+                sol.ui_amount(if_source_balance_exceeds)
+            );
+            return Ok(());
+        }
+    }
+
+    if amount == 0 {
+        println!("Nothing to wrap");
+        return Ok(());
+    }
+
+    if db.get_account(address, wsol.into()).is_none() {
+        let epoch = rpc_client.get_epoch_info()?.epoch;
+        db.add_account(TrackedAccount {
+            address,
+            token: wsol.into(),
+            description: from_account.description,
+            last_update_epoch: epoch,
+            last_update_balance: 0,
+            lots: vec![],
+            no_sync: None,
+        })?;
+    }
+
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+    let mut instructions = vec![];
+
+    // TODO: replace the following block with
+    // `spl_associated_token_account::instruction::create_associated_token_account_idempotent()`
+    // when it ships to mainnet
+    if rpc_client
+        .get_account_with_commitment(&wsol_address, rpc_client.commitment())?
+        .value
+        .is_none()
+    {
+        instructions.push(
+            spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                &authority_address,
+                &address,
+                &wsol.mint(),
+                &spl_token::id(),
+            ),
+        );
+    }
+    instructions.extend([
+        system_instruction::transfer(&address, &wsol_address, amount),
+        spl_token::instruction::sync_native(&spl_token::id(), &wsol_address).unwrap(),
+    ]);
+
+    let message = Message::new(&instructions, Some(&authority_address));
+
+    let mut transaction = Transaction::new_unsigned(message);
+    transaction.message.recent_blockhash = recent_blockhash;
+    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+    if simulation_result.err.is_some() {
+        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    }
+
+    println!("Wrapping {} for {}", wsol.ui_amount(amount), address);
+
+    transaction.try_sign(&signers, recent_blockhash)?;
+
+    let signature = transaction.signatures[0];
+    println!("Transaction signature: {signature}");
+
+    db.record_transfer(
+        signature,
+        last_valid_block_height,
+        Some(amount),
+        address,
+        sol,
+        address,
+        wsol.into(),
+        lot_selection_method,
+        lot_numbers,
+    )?;
+
+    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+        db.cancel_transfer(signature)?;
+        return Err("Wrap failed".into());
+    }
+    println!("Wrap confirmed: {signature}");
+    let when = get_signature_date(rpc_client, signature).await?;
+    db.confirm_transfer(signature, when)?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn process_account_unwrap<T: Signers>(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    address: Pubkey,
+    amount: Option<u64>,
+    lot_selection_method: LotSelectionMethod,
+    lot_numbers: Option<HashSet<usize>>,
+    authority_address: Pubkey,
+    signers: T,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sol = MaybeToken::SOL();
+    let wsol = Token::wSOL;
+
+    let from_account = db
+        .get_account(address, wsol.into())
+        .ok_or_else(|| format!("Wrapped SOL account does not exist for {address}"))?;
+    let amount = amount.unwrap_or(from_account.last_update_balance);
+
+    let _to_account = db
+        .get_account(address, sol)
+        .ok_or_else(|| format!("SOL account does not exist for {address}"))?;
+
+    let (recent_blockhash, last_valid_block_height) =
+        rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+    let ephemeral_token_account = Keypair::new();
+
+    let instructions = [
+        spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+            &authority_address,
+            &ephemeral_token_account.pubkey(),
+            &wsol.mint(),
+            &spl_token::id(),
+        ),
+        spl_token::instruction::transfer_checked(
+            &spl_token::id(),
+            &wsol.ata(&address),
+            &wsol.mint(),
+            &wsol.ata(&ephemeral_token_account.pubkey()),
+            &authority_address,
+            &[],
+            amount,
+            wsol.decimals(),
+        )
+        .unwrap(),
+        spl_token::instruction::close_account(
+            &spl_token::id(),
+            &wsol.ata(&ephemeral_token_account.pubkey()),
+            &address,
+            &ephemeral_token_account.pubkey(),
+            &[],
+        )
+        .unwrap(),
+    ];
+
+    let message = Message::new(&instructions, Some(&authority_address));
+
+    let mut transaction = Transaction::new_unsigned(message);
+    transaction.message.recent_blockhash = recent_blockhash;
+    let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+    if simulation_result.err.is_some() {
+        return Err(format!("Simulation failure: {simulation_result:?}").into());
+    }
+
+    println!("Unwrapping {} for {}", wsol.ui_amount(amount), address);
+
+    transaction.partial_sign(&signers, recent_blockhash);
+    transaction.try_sign(&[&ephemeral_token_account], recent_blockhash)?;
+
+    let signature = transaction.signatures[0];
+    println!("Transaction signature: {signature}");
+
+    db.record_transfer(
+        signature,
+        last_valid_block_height,
+        Some(amount),
+        address,
+        wsol.into(),
+        address,
+        sol,
+        lot_selection_method,
+        lot_numbers,
+    )?;
+
+    if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+        db.cancel_transfer(signature)?;
+        return Err("Wrap failed".into());
+    }
+    println!("Unwrap confirmed: {signature}");
+    let when = get_signature_date(rpc_client, signature).await?;
+    db.confirm_transfer(signature, when)?;
+
+    Ok(())
+}
+async fn process_account_sync_pending_transfers(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let block_height = rpc_client.get_epoch_info()?.block_height;
+    for PendingTransfer {
+        signature,
+        last_valid_block_height,
+        ..
+    } in db.pending_transfers()
+    {
+        let status = rpc_client.get_signature_status_with_commitment_and_history(
+            &signature,
+            rpc_client.commitment(),
+            true,
+        )?;
+        match status {
+            Some(result) => {
+                if result.is_ok() {
+                    println!("Pending transfer confirmed: {signature}");
+                    let when = get_signature_date(rpc_client, signature).await?;
+                    db.confirm_transfer(signature, when)?;
+                } else {
+                    println!("Pending transfer failed with {result:?}: {signature}");
+                    db.cancel_transfer(signature)?;
+                }
+            }
+            None => {
+                if block_height > last_valid_block_height {
+                    println!("Pending transfer cancelled: {signature}");
+                    db.cancel_transfer(signature)?;
+                } else {
+                    println!(
+                        "Transfer pending for at most {} blocks: {}",
+                        last_valid_block_height.saturating_sub(block_height),
+                        signature
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn process_account_sync_sweep(
+    db: &mut Db,
+    rpc_client: &RpcClient,
+    _notifier: &Notifier,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let token = MaybeToken::SOL();
+
+    let transitory_sweep_stake_addresses = db.get_transitory_sweep_stake_addresses();
+    if transitory_sweep_stake_addresses.is_empty() {
+        return Ok(());
+    }
+
+    let sweep_stake_account_info = db
+        .get_sweep_stake_account()
+        .ok_or("Sweep stake account is not configured")?;
+
+    let sweep_stake_account_authority_keypair =
+        read_keypair_file(&sweep_stake_account_info.stake_authority).map_err(|err| {
+            format!(
+                "Failed to read {}: {}",
+                sweep_stake_account_info.stake_authority.display(),
+                err
+            )
+        })?;
+
+    let sweep_stake_account = rpc_client
+        .get_account_with_commitment(&sweep_stake_account_info.address, rpc_client.commitment())?
+        .value
+        .ok_or("Sweep stake account does not exist")?;
+
+    let sweep_stake_activation = rpc_client
+        .get_stake_activation(sweep_stake_account_info.address, None)
+        .map_err(|err| {
+            format!(
+                "Unable to get activation information for sweep stake account: {}: {}",
+                sweep_stake_account_info.address, err
+            )
+        })?;
+
+    if sweep_stake_activation.state != StakeActivationState::Active {
+        println!(
+            "Sweep stake account is not active, unable to continue: {sweep_stake_activation:?}"
+        );
+        return Ok(());
+    }
+
+    for transitory_sweep_stake_address in transitory_sweep_stake_addresses {
+        println!("Considering merging transitory stake {transitory_sweep_stake_address}");
+
+        let transitory_sweep_stake_account = match rpc_client
+            .get_account_with_commitment(&transitory_sweep_stake_address, rpc_client.commitment())?
+            .value
+        {
+            None => {
+                println!(
+                    "  Transitory sweep stake account does not exist, removing it: {transitory_sweep_stake_address}"
+                );
+
+                if let Some(tracked_account) = db.get_account(transitory_sweep_stake_address, token)
+                {
+                    if tracked_account.last_update_balance > 0 || !tracked_account.lots.is_empty() {
+                        panic!("Tracked account is not empty: {tracked_account:?}");
+
+                        // TODO: Simulate a transfer to move the lots into the sweep account in
+                        // this case?
+                        /*
+                        let signature = Signature::default();
+                        db.record_transfer(
+                            signature,
+                            None,
+                            transitory_sweep_stake_address,
+                            sweep_stake_account_info.address,
+                            None,
+                        )?;
+                        db.confirm_transfer(signature)?;
+                        */
+                    }
+                }
+                db.remove_transitory_sweep_stake_address(transitory_sweep_stake_address)?;
+                continue;
+            }
+            Some(x) => x,
+        };
+
+        let transient_stake_activation = rpc_client
+            .get_stake_activation(transitory_sweep_stake_address, None)
+            .map_err(|err| {
+                format!(
+                    "Unable to get activation information for transient stake: {transitory_sweep_stake_address}: {err}"
+                )
+            })?;
+
+        if transient_stake_activation.state != StakeActivationState::Active {
+            println!("  Transitory stake is not yet active: {transient_stake_activation:?}");
+            continue;
+        }
+
+        if !rpc_client_utils::stake_accounts_have_same_credits_observed(
+            &sweep_stake_account,
+            &transitory_sweep_stake_account,
+        )? {
+            println!(
+                "  Transitory stake credits observed mismatch with sweep stake account: {transitory_sweep_stake_address}"
+            );
+            continue;
+        }
+        println!("  Merging into sweep stake account");
+
+        let message = Message::new(
+            &solana_sdk::stake::instruction::merge(
+                &sweep_stake_account_info.address,
+                &transitory_sweep_stake_address,
+                &sweep_stake_account_authority_keypair.pubkey(),
+            ),
+            Some(&sweep_stake_account_authority_keypair.pubkey()),
+        );
+        let mut transaction = Transaction::new_unsigned(message);
+
+        let (recent_blockhash, last_valid_block_height) =
+            rpc_client.get_latest_blockhash_with_commitment(rpc_client.commitment())?;
+
+        transaction.message.recent_blockhash = recent_blockhash;
+        let simulation_result = rpc_client.simulate_transaction(&transaction)?.value;
+        if simulation_result.err.is_some() {
+            return Err(format!("Simulation failure: {simulation_result:?}").into());
+        }
+
+        transaction.sign(&[&sweep_stake_account_authority_keypair], recent_blockhash);
+
+        let signature = transaction.signatures[0];
+        println!("Transaction signature: {signature}");
+        db.record_transfer(
+            signature,
+            last_valid_block_height,
+            None,
+            transitory_sweep_stake_address,
+            token,
+            sweep_stake_account_info.address,
+            token,
+            LotSelectionMethod::default(),
+            None,
+        )?;
+
+        if !send_transaction_until_expired(rpc_client, &transaction, last_valid_block_height) {
+            db.cancel_transfer(signature)?;
+            return Err("Merge failed".into());
+        }
+        let when = get_signature_date(rpc_client, signature).await?;
+        db.confirm_transfer(signature, when)?;
+        db.remove_transitory_sweep_stake_address(transitory_sweep_stake_address)?;
+    }
+    Ok(())
+}
+
+fn lot_numbers_of(matches: &ArgMatches<'_>, name: &str) -> Option<HashSet<usize>> {
+    values_t!(matches, name, usize)
+        .ok()
+        .map(|x| x.into_iter().collect())
+}
+
+fn lot_numbers_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("lot_numbers")
+        .long("lot")
+        .value_name("LOT NUMBER")
+        .takes_value(true)
+        .multiple(true)
+        .validator(is_parsable::<usize>)
+        .help("Lot to fund the wrap from")
+}
+
+fn lot_selection_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("lot_selection")
+        .long("lot-selection")
+        .value_name("METHOD")
+        .takes_value(true)
+        .validator(is_parsable::<LotSelectionMethod>)
+        .default_value(POSSIBLE_LOT_SELECTION_METHOD_VALUES[0])
+        .possible_values(POSSIBLE_LOT_SELECTION_METHOD_VALUES)
+        .help("Lot selection method")
+}
+
+fn is_spending_window(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(hours) if hours > 0 && hours <= MAX_SPENDING_WINDOW_HOURS => Ok(()),
+        _ => Err(format!(
+            "Window must be between 1 and {MAX_SPENDING_WINDOW_HOURS} hours"
+        )),
+    }
+}
+
+fn override_limits_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("override_limits")
+        .long("override-limits")
+        .takes_value(false)
+        .help(
+            "Proceed even if the operation exceeds a per-operation spending limit. \
+             Each limit bypassed is recorded in the audit log; rolling-window limits still apply",
+        )
+}
+
+fn request_metrics_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("request_metrics")
+        .long("request-metrics")
+        .value_name("HOURS")
+        .takes_value(true)
+        .validator(is_parsable::<u32>)
+        .conflicts_with("json")
+        .help(
+            "Also summarize exchange request counts, errors, retries, rate budget waits and \
+             latency over the last HOURS",
+        )
+}
+
+fn order_rounding_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("round_amount")
+            .long("round-amount")
+            .value_name("DIRECTION")
+            .takes_value(true)
+            .possible_values(POSSIBLE_AMOUNT_ROUNDING_VALUES)
+            .default_value("down")
+            .conflicts_with("exact")
+            .help(
+                "Round the amount to the lot size of the pair: down, up to acquire at least the \
+                 amount, or exact to refuse an amount that isn't already a multiple",
+            ),
+        Arg::with_name("round_price")
+            .long("round-price")
+            .value_name("DIRECTION")
+            .takes_value(true)
+            .possible_values(POSSIBLE_PRICE_ROUNDING_VALUES)
+            .default_value("passive")
+            .conflicts_with("exact")
+            .help(
+                "Round the price to the tick size of the pair: passive, away from the other \
+                 side of the book, or aggressive, towards it",
+            ),
+    ]
+}
+
+fn quote_amount_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("quote_amount")
+            .long("quote-amount")
+            .value_name("AMOUNT")
+            .takes_value(true)
+            .conflicts_with("amount")
+            .validator(is_amount)
+            .help(
+                "Size the order as this amount of the quote currency at the order price, such as \
+                 5000 for $5,000 worth, rather than as AMOUNT tokens",
+            ),
+        Arg::with_name("quote_tolerance")
+            .long("quote-tolerance")
+            .value_name("PERCENT")
+            .takes_value(true)
+            .requires("quote_amount")
+            .validator(is_parsable::<f64>)
+            .help(
+                "Refuse the order if rounding it to the lot size of the pair leaves it worth more \
+                 than this percentage away from --quote-amount [default: 1]",
+            ),
+    ]
+}
+
+fn quote_order_amount_of(arg_matches: &ArgMatches) -> Option<OrderAmount> {
+    value_t!(arg_matches, "quote_amount", f64)
+        .ok()
+        .map(|amount| OrderAmount::Quote {
+            amount,
+            tolerance: value_t!(arg_matches, "quote_tolerance", f64).unwrap_or(1.) / 100.,
+        })
+}
+
+fn order_rounding_of(arg_matches: &ArgMatches) -> OrderRounding {
+    OrderRounding {
+        price: value_t_or_exit!(arg_matches, "round_price", PriceRounding),
+        amount: value_t_or_exit!(arg_matches, "round_amount", AmountRounding),
+    }
+}
+
+fn two_factor_code_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::with_name("code")
+            .long("code")
+            .value_name("CODE")
+            .takes_value(true)
+            .help("2FA withdrawal code"),
+        Arg::with_name("prompt_code")
+            .long("prompt-code")
+            .takes_value(false)
+            .conflicts_with("code")
+            .help("Prompt for the 2FA withdrawal code when it is needed"),
+        Arg::with_name("totp_secret_file")
+            .long("totp-secret-file")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with_all(&["code", "prompt_code"])
+            .help("Compute the 2FA withdrawal code from the base32 TOTP secret in this file"),
+    ]
+}
+
+fn proceeds_subcommand<'a, 'b>(about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name("proceeds")
+        .about(about)
+        .arg(
+            Arg::with_name("amount")
+                .value_name("AMOUNT")
+                .takes_value(true)
+                .validator(is_amount)
+                .required(true)
+                .help("The amount to sell, in tokens"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .value_name("SOL or SPL Token")
+                .takes_value(true)
+                .validator(is_valid_token_or_sol)
+                .default_value("SOL")
+                .help("Token to sell"),
+        )
+        .arg(
+            Arg::with_name("withdrawal_token")
+                .long("withdraw")
+                .value_name("TOKEN")
+                .takes_value(true)
+                .validator(is_valid_token_or_sol)
+                .default_value("USDC")
+                .help("Stablecoin the proceeds are withdrawn as"),
+        )
+        .arg(
+            Arg::with_name("maker")
+                .long("maker")
+                .takes_value(false)
+                .help("Apply the maker rather than the taker fee rate"),
+        )
+        .arg(
+            Arg::with_name("depth")
+                .long("depth")
+                .value_name("LEVELS")
+                .takes_value(true)
+                .validator(is_parsable::<usize>)
+                .default_value("100")
+                .help("Order book levels to estimate the fill from"),
+        )
+        .arg(
+            Arg::with_name("max_age")
+                .long("max-age")
+                .value_name("SECONDS")
+                .takes_value(true)
+                .validator(is_parsable::<f64>)
+                .default_value("3")
+                .help("Refuse to estimate from an order book snapshot older than this"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .takes_value(false)
+                .help("Output the estimate as JSON"),
+        )
+}
+
+fn paper_order_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("amount")
+                .value_name("AMOUNT")
+                .takes_value(true)
+                .validator(is_amount)
+                .required(true)
+                .help("The amount to trade, in tokens"),
+        )
+        .arg(
+            Arg::with_name("at")
+                .long("at")
+                .value_name("PRICE")
+                .takes_value(true)
+                .validator(is_parsable::<f64>)
+                .required(true)
+                .help("Limit price"),
+        )
+        .arg(
+            Arg::with_name("pair")
+                .long("pair")
+                .value_name("TRADING_PAIR")
+                .takes_value(true)
+                .help("Market to place the order in [default: preferred USD pair for the token on the exchange]"),
+        )
+        .arg(
+            Arg::with_name("quote")
+                .long("quote")
+                .value_name("COIN")
+                .takes_value(true)
+                .conflicts_with("pair")
+                .help("Quote currency of the market, such as BTC [default: USD]"),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .value_name("SOL or SPL Token")
+                .takes_value(true)
+                .validator(is_valid_token_or_sol)
+                .default_value("SOL")
+                .help("Token to trade"),
+        )
+        .arg(
+            Arg::with_name("taker")
+                .long("taker")
+                .takes_value(false)
+                .help("Fill immediately against the current book at the taker fee rate, \
+                       cancelling the order if the limit price is not reached"),
+        )
+}
+
+fn two_factor_code_of(
+    matches: &ArgMatches<'_>,
+    exchange: Exchange,
+) -> Result<Option<Box<dyn TwoFactorCodeProvider>>, Box<dyn std::error::Error>> {
+    if let Some(code) = matches.value_of("code") {
+        Ok(Some(Box::new(StaticCode(code.to_string()))))
+    } else if matches.is_present("prompt_code") {
+        Ok(Some(Box::new(PromptCode { exchange })))
+    } else if let Some(totp_secret_file) = matches.value_of("totp_secret_file") {
+        let secret = std::fs::read_to_string(totp_secret_file)
+            .map_err(|err| format!("Unable to read {totp_secret_file}: {err}"))?;
+        Ok(Some(Box::new(TotpCode::new(&secret)?)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn staking_coin_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("coin")
+        .long("coin")
+        .value_name("COIN")
+        .takes_value(true)
+        .default_value("SOL")
+        .help("The coin to stake")
+}
+
+fn staking_wait_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("wait")
+        .long("wait")
+        .takes_value(false)
+        .help("Wait for the exchange to complete the request")
+}
+
+fn staking_timeout_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("timeout")
+        .long("timeout")
+        .value_name("MINUTES")
+        .takes_value(true)
+        .default_value("60")
+        .validator(is_parsable::<u64>)
+        .help("With --wait, give up waiting after this many minutes")
+}
+
+fn parse_rebalance_target(s: &str) -> Result<(String, f64), String> {
+    let (token, percent) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected TOKEN=PERCENT, provided: {s}"))?;
+    let percent = percent
+        .parse::<f64>()
+        .map_err(|err| format!("Invalid percentage for {token}: {err}"))?;
+    let token = if exchange::is_usd_equivalent(token) {
+        "USD".into()
+    } else {
+        token.to_string()
+    };
+    Ok((token, percent))
+}
+
+fn is_rebalance_target(s: String) -> Result<(), String> {
+    parse_rebalance_target(&s).map(|_| ())
+}
+
+fn is_tax_rate(s: String) -> Result<(), String> {
+    is_parsable::<f64>(s.clone())?;
+    let f = s.parse::<f64>().unwrap();
+    if (0. ..=1.).contains(&f) {
+        Ok(())
+    } else {
+        Err(format!("rate must be in the range [0,1]: {f}"))
+    }
+}
+
+// The subcommands given, such as "kraken sell"
+fn command_name(matches: &ArgMatches<'_>) -> String {
+    let mut names = vec![];
+    let mut matches = matches;
+    while let (name, Some(subcommand_matches)) = matches.subcommand() {
+        names.push(name);
+        matches = subcommand_matches;
+    }
+    names.join(" ")
+}
+
+// How often long-running commands write out their request metrics
+const REQUEST_METRICS_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+// Writes this run's exchange request metrics, and pushes them to the pushgateway in the
+// metrics-pushgateway setting if set
+async fn flush_request_metrics() {
+    if let Err(err) = request_metrics::flush() {
+        log::warn!("Unable to write request metrics: {err}");
+    }
+    if let Some(url) = settings::get(Setting::MetricsPushgateway) {
+        if let Err(err) = request_metrics::push_to_gateway(&url).await {
+            log::warn!("Unable to push request metrics: {err}");
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let result = run().await;
+    if let Err(err) = &result {
+        eprintln!("Error: {err:?}");
+    }
+    flush_request_metrics().await;
+    match outcome::finish(&result) {
+        Ok(exit_code) => exit(exit_code),
+        Err(err) => {
+            eprintln!("Unable to write summary: {err}");
+            exit(outcome::EXIT_FAILURE);
+        }
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
+    solana_logger::setup_with_default("solana=info");
+    let default_db_path = "sell-your-sol";
+    let default_json_rpc_url = "https://api.mainnet-beta.solana.com";
+    let default_when = {
+        let today = Local::now().date();
+        format!("{}/{}/{}", today.year(), today.month(), today.day())
+    };
+    let exchanges = Exchange::all()
+        .iter()
+        .map(|exchange| exchange.name())
+        .collect::<Vec<_>>();
+    let debug_capture_exchanges = debug_capture::SUPPORTED_EXCHANGES
+        .iter()
+        .map(|exchange| exchange.name())
+        .collect::<Vec<_>>();
+
+    let app_version = &*app_version();
+    let mut app = App::new(crate_name!())
+        .about(crate_description!())
+        .version(app_version)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .setting(AppSettings::InferSubcommands)
+        .arg(
+            Arg::with_name("db_path")
+                .long("db-path")
+                .value_name("PATH")
+                .takes_value(true)
+                .default_value(default_db_path)
+                .global(true)
+                .help("Database path"),
+        )
+        .arg(
+            Arg::with_name("json_rpc_url")
+                .short("u")
+                .long("url")
+                .value_name("URL")
+                .takes_value(true)
+                .global(true)
+                .validator(is_url_or_moniker)
+                .default_value(default_json_rpc_url)
+                .help("JSON RPC URL for the cluster"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .takes_value(false)
+                .global(true)
                 .help("Show additional information"),
         )
+        .arg(
+            Arg::with_name("rate_limit_dir")
+                .long("rate-limit-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .global(true)
+                .help("Share exchange API request budgets with other sys processes given the same \
+                       directory"),
+        )
+        .arg(
+            Arg::with_name("debug_capture")
+                .long("debug-capture")
+                .value_name("EXCHANGE,...")
+                .takes_value(true)
+                .use_delimiter(true)
+                .possible_values(&debug_capture_exchanges)
+                .global(true)
+                .help("Write every request these exchanges are sent, and the response, to a \
+                       file for a bug report. Credentials and signatures are scrubbed"),
+        )
+        .arg(
+            Arg::with_name("debug_capture_dir")
+                .long("debug-capture-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .default_value(".")
+                .global(true)
+                .help("Directory to write --debug-capture files to"),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .value_name("PATH")
+                .takes_value(true)
+                .global(true)
+                .help("Write a JSON summary of each exchange operation performed and its result \
+                       to this file, or to stdout if \"-\". The exit code is 0 on success, 1 on \
+                       failure, 2 for a configuration error such as missing credentials, and 3 \
+                       when only some operations succeeded"),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .takes_value(false)
+                .global(true)
+                .help("Carry out withdrawals, order cancellations and evacuations without \
+                       confirming them. Without it, they're refused when there's no terminal to \
+                       confirm them on"),
+        )
+        .subcommand(
+            SubCommand::with_name("price")
+                .about("Get token price")
+                .arg(
+                    Arg::with_name("token")
+                        .value_name("SOL or SPL Token")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(is_valid_token_or_sol)
+                        .default_value("SOL")
+                        .help("Token type"),
+                )
+                .arg(
+                    Arg::with_name("when")
+                        .value_name("YY/MM/DD")
+                        .takes_value(true)
+                        .required(false)
+                        .validator(|value| naivedate_of(&value).map(|_| ()))
+                        .help("Date to fetch the price for [default: current spot price]"),
+                )
+                .arg(
+                    Arg::with_name("exchanges")
+                        .long("exchanges")
+                        .takes_value(false)
+                        .requires("when")
+                        .help("Prefer the daily close from configured exchanges over CoinGecko"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("compare")
+                .about("Compare token prices across all configured exchanges")
+                .arg(
+                    Arg::with_name("amount")
+                        .value_name("AMOUNT")
+                        .takes_value(true)
+                        .validator(is_amount)
+                        .required(true)
+                        .help("The amount to trade, in tokens"),
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .long("token")
+                        .value_name("SOL or SPL Token")
+                        .takes_value(true)
+                        .validator(is_valid_token_or_sol)
+                        .default_value("SOL")
+                        .help("Token type"),
+                )
+                .arg(
+                    Arg::with_name("buy")
+                        .long("buy")
+                        .takes_value(false)
+                        .help("Compare the cost of buying rather than the proceeds of selling"),
+                )
+                .arg(
+                    Arg::with_name("withdrawal_fee")
+                        .long("withdrawal-fee")
+                        .value_name("AMOUNT")
+                        .takes_value(true)
+                        .validator(is_parsable::<f64>)
+                        .default_value("0")
+                        .help("Withdrawal fee, in USD, required to move the funds off the exchange"),
+                )
+                .arg(
+                    Arg::with_name("tiers")
+                        .long("tiers")
+                        .takes_value(false)
+                        .help("Note exchanges where the trade would reach the next 30-day volume \
+                               fee tier"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .takes_value(false)
+                        .help("Output the comparison as JSON"),
+                )
+                .arg(request_metrics_arg())
+        )
+        .subcommand(proceeds_subcommand(
+            "Estimate the net proceeds of a sale on every configured exchange, after trading \
+             and withdrawal fees",
+        ))
+        .subcommand(
+            SubCommand::with_name("book")
+                .about("Show the order book for a token consolidated across all configured exchanges")
+                .arg(
+                    Arg::with_name("token")
+                        .value_name("SOL or SPL Token")
+                        .takes_value(true)
+                        .validator(is_valid_token_or_sol)
+                        .default_value("SOL")
+                        .help("Token type"),
+                )
+                .arg(
+                    Arg::with_name("amount")
+                        .value_name("AMOUNT")
+                        .takes_value(true)
+                        .validator(is_amount)
+                        .help("Also show the best executable price for this amount, in tokens, \
+                               and which exchange each part would fill on"),
+                )
+                .arg(
+                    Arg::with_name("buy")
+                        .long("buy")
+                        .takes_value(false)
+                        .help("Price buying AMOUNT rather than selling it"),
+                )
+                .arg(
+                    Arg::with_name("depth")
+                        .long("depth")
+                        .value_name("LEVELS")
+                        .takes_value(true)
+                        .validator(is_parsable::<usize>)
+                        .default_value("20")
+                        .help("Levels to fetch from each side of each market"),
+                )
+                .arg(
+                    Arg::with_name("max_age")
+                        .long("max-age")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .validator(is_parsable::<f64>)
+                        .default_value("2")
+                        .help("Leave out order book snapshots older than this"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .takes_value(false)
+                        .help("Output the consolidated book as JSON"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("arbitrage")
+                .about("Detect price gaps between the configured exchanges")
+                .arg(
+                    Arg::with_name("token")
+                        .value_name("SOL or SPL Token")
+                        .takes_value(true)
+                        .multiple(true)
+                        .validator(is_valid_token_or_sol)
+                        .default_value("SOL")
+                        .help("Tokens to monitor"),
+                )
+                .arg(
+                    Arg::with_name("min_spread")
+                        .long("min-spread")
+                        .value_name("PERCENT")
+                        .takes_value(true)
+                        .validator(is_parsable::<f64>)
+                        .default_value("0.5")
+                        .help("Only report opportunities with at least this spread after fees"),
+                )
+                .arg(
+                    Arg::with_name("max_amount")
+                        .long("max-amount")
+                        .value_name("AMOUNT")
+                        .takes_value(true)
+                        .validator(is_amount)
+                        .help("Maximum amount of tokens to trade per opportunity"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .validator(is_parsable::<u64>)
+                        .help("Keep monitoring, polling the exchanges at this interval"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("MINUTES")
+                        .takes_value(true)
+                        .requires("interval")
+                        .validator(is_parsable::<u64>)
+                        .help("Stop monitoring after this many minutes"),
+                )
+                .arg(
+                    Arg::with_name("execute")
+                        .long("execute")
+                        .takes_value(false)
+                        .help("Place both legs of each opportunity with taker orders, \
+                               journaling each leg's fill against the exchange lots"),
+                )
+                .arg(lot_selection_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("portfolio")
+                .about("Summarize token holdings across all exchanges and accounts")
+                .arg(
+                    Arg::with_name("currency")
+                        .long("currency")
+                        .value_name("CURRENCY")
+                        .takes_value(true)
+                        .help("Present values in this currency, such as EUR \
+                               [default: reporting-currency setting, or USD]"),
+                )
+                .arg(
+                    Arg::with_name("min_value")
+                        .long("min-value")
+                        .value_name("AMOUNT")
+                        .takes_value(true)
+                        .validator(is_parsable::<f64>)
+                        .default_value("0")
+                        .help("Hide priced holdings worth less than this amount"),
+                )
+                .arg(
+                    Arg::with_name("all_subaccounts")
+                        .long("all-subaccounts")
+                        .takes_value(false)
+                        .help("Include every subaccount reachable with the exchange credentials"),
+                )
+                .arg(
+                    Arg::with_name("cached_fallback")
+                        .long("cached-fallback")
+                        .takes_value(false)
+                        .help("Use the balances of the latest balance snapshot for an exchange \
+                               that can't be reached, marked with their age"),
+                )
+                .arg(
+                    Arg::with_name("export")
+                        .long("export")
+                        .value_name("CSV_FILE")
+                        .takes_value(true)
+                        .help("Also export exchange balances valued in USD to this CSV file, \
+                               replacing any rows already exported today"),
+                )
+                .arg(
+                    Arg::with_name("export_dated")
+                        .long("export-dated")
+                        .takes_value(false)
+                        .requires("export")
+                        .help("Treat --export as a directory, and write a file per day to it"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .takes_value(false)
+                        .help("Output the summary as JSON"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("rebalance")
+                .about("Rebalance exchange holdings towards a target allocation")
+                .arg(
+                    Arg::with_name("target")
+                        .value_name("TOKEN=PERCENT")
+                        .takes_value(true)
+                        .multiple(true)
+                        .required(true)
+                        .validator(is_rebalance_target)
+                        .help("Target allocation, such as SOL=60 USD=40. USD includes all USD coins"),
+                )
+                .arg(
+                    Arg::with_name("tolerance")
+                        .long("tolerance")
+                        .value_name("PERCENT")
+                        .takes_value(true)
+                        .validator(is_parsable::<f64>)
+                        .default_value("5")
+                        .help("Skip tokens whose allocation is within this many percentage points of the target"),
+                )
+                .arg(
+                    Arg::with_name("slippage_bps")
+                        .long("slippage")
+                        .value_name("BPS")
+                        .takes_value(true)
+                        .validator(is_parsable::<u64>)
+                        .default_value("50")
+                        .help("Maximum slippage bps from the planned price when executing"),
+                )
+                .arg(lot_selection_arg())
+                .arg(
+                    Arg::with_name("execute")
+                        .long("execute")
+                        .takes_value(false)
+                        .help("Place the orders rather than only printing the plan"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("exchanges")
+                .about("List supported exchanges and what each can do")
+                .arg(
+                    Arg::with_name("token")
+                        .long("token")
+                        .value_name("SOL or SPL Token")
+                        .takes_value(true)
+                        .validator(is_valid_token_or_sol)
+                        .help("Instead show whether each configured exchange moves this token \
+                               over the Solana network"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .takes_value(false)
+                        .help("Output the list as JSON"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("conformance")
+                .about("Check an exchange integration against the exchange client contract, \
+                        replaying recorded responses instead of calling the exchange")
+                .arg(
+                    Arg::with_name("exchange")
+                        .value_name("EXCHANGE")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&exchanges)
+                        .help("Exchange integration to check"),
+                )
+                .arg(
+                    Arg::with_name("recordings")
+                        .long("recordings")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .help("Directory of recorded responses, one file per check \
+                               [default: conformance/EXCHANGE]"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("health-check")
+                .about("Verify the API key of every configured exchange account: authentication, \
+                        permissions, latency and clock skew. Exits non-zero if any fail")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .takes_value(false)
+                        .help("Output the results as JSON"),
+                )
+                .arg(request_metrics_arg())
+        )
+        .subcommand(
+            SubCommand::with_name("verify-deposit-addresses")
+                .about("Fetch every cached or pinned exchange deposit address afresh, refreshing \
+                        the cache, and report those that changed. Exits non-zero if any changed \
+                        or couldn't be fetched"),
+        )
+        .subcommand(
+            SubCommand::with_name("lending-interest")
+                .about("Total the lending interest earned in USD across every exchange that \
+                        supports lending, by exchange, coin and month")
+                .arg(
+                    Arg::with_name("start_date")
+                        .value_name("YY/MM/DD")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(|value| naivedate_of(&value).map(|_| ()))
+                        .help("Start date, inclusive")
+                )
+                .arg(
+                    Arg::with_name("end_date")
+                        .value_name("YY/MM/DD")
+                        .takes_value(true)
+                        .default_value(&default_when)
+                        .validator(|value| naivedate_of(&value).map(|_| ()))
+                        .help("End date, inclusive")
+                )
+                .arg(
+                    Arg::with_name("csv")
+                        .long("csv")
+                        .takes_value(false)
+                        .help("Output the daily interest as CSV instead of a table"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("backfill")
+                .about("Journal the deposits, withdrawals, trades, conversions and interest each \
+                        configured exchange reports from before the journal began recording it. \
+                        An interrupted backfill resumes where it stopped, and running it again \
+                        adds nothing twice")
+                .arg(
+                    Arg::with_name("exchange")
+                        .long("exchange")
+                        .value_name("EXCHANGE")
+                        .takes_value(true)
+                        .possible_values(&exchanges)
+                        .help("Only backfill this exchange"),
+                )
+                .arg(
+                    Arg::with_name("since")
+                        .long("since")
+                        .value_name("YY/MM/DD")
+                        .takes_value(true)
+                        .default_value("20/01/01")
+                        .validator(|value| naivedate_of(&value).map(|_| ()))
+                        .help("Earliest history to backfill, on the first run only"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("balance-snapshot")
+                .about("Record and compare snapshots of every configured exchange's balances")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("record")
+                        .about("Record the balances and USD values of every configured exchange \
+                                account. Unreachable exchanges are marked and the snapshot is \
+                                kept as partial")
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output the snapshot as JSON"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List recorded balance snapshots")
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output the snapshots as JSON"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("report")
+                        .about("Report per-coin and per-exchange balance changes between two \
+                                snapshots, separating price moves from amount changes")
+                        .arg(
+                            Arg::with_name("from")
+                                .long("from")
+                                .value_name("NUMBER or YY/MM/DD")
+                                .takes_value(true)
+                                .validator(is_balance_snapshot_selector)
+                                .help("Starting snapshot, by number or the first taken on or \
+                                       after a date [default: the latest a week before the \
+                                       ending snapshot]"),
+                        )
+                        .arg(
+                            Arg::with_name("to")
+                                .long("to")
+                                .value_name("NUMBER or YY/MM/DD")
+                                .takes_value(true)
+                                .validator(is_balance_snapshot_selector)
+                                .help("Ending snapshot, by number or the last taken on or \
+                                       before a date [default: the latest]"),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output the report as JSON"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("drift")
+                        .about("Compare each exchange's balances with those expected from a \
+                                snapshot and the fills, fees, deposits and withdrawals \
+                                journaled since. Only main accounts are checked, and nothing \
+                                is changed")
+                        .arg(
+                            Arg::with_name("from")
+                                .long("from")
+                                .value_name("NUMBER or YY/MM/DD")
+                                .takes_value(true)
+                                .validator(is_balance_snapshot_selector)
+                                .help("Starting snapshot, by number or the first taken on or \
+                                       after a date [default: the latest]"),
+                        )
+                        .arg(
+                            Arg::with_name("tolerance")
+                                .long("tolerance")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .default_value("0.01")
+                                .validator(is_parsable::<f64>)
+                                .help("Report balances that differ from the expected amount \
+                                       by more than this"),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output the divergences as JSON"),
+                        )
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("transfer")
+                .about("Withdraw from one exchange and wait for another to credit the deposit")
+                .arg(
+                    Arg::with_name("from_exchange")
+                        .value_name("FROM_EXCHANGE")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&exchanges)
+                        .help("Exchange to withdraw from"),
+                )
+                .arg(
+                    Arg::with_name("to_exchange")
+                        .value_name("TO_EXCHANGE")
+                        .takes_value(true)
+                        .required(true)
+                        .possible_values(&exchanges)
+                        .help("Exchange to deposit into"),
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .value_name("SOL or SPL Token")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(is_valid_token_or_sol)
+                        .help("Token type"),
+                )
+                .arg(
+                    Arg::with_name("amount")
+                        .value_name("AMOUNT")
+                        .takes_value(true)
+                        .validator(is_amount)
+                        .required(true)
+                        .help("The amount to transfer; accepts keyword ALL"),
+                )
+                .arg(
+                    Arg::with_name("from_account")
+                        .long("from-account")
+                        .value_name("SUBACCOUNT")
+                        .takes_value(true)
+                        .help("Source exchange account"),
+                )
+                .arg(
+                    Arg::with_name("to_account")
+                        .long("to-account")
+                        .value_name("SUBACCOUNT")
+                        .takes_value(true)
+                        .help("Destination exchange account"),
+                )
+                .arg(lot_selection_arg())
+                .args(&two_factor_code_args())
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .value_name("WITHDRAWAL_ID")
+                        .takes_value(true)
+                        .help("Resume waiting on a previously submitted withdrawal"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("MINUTES")
+                        .takes_value(true)
+                        .default_value("120")
+                        .validator(is_parsable::<u64>)
+                        .help("Give up waiting after this many minutes"),
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("sync")
+                .about("Synchronize with all exchanges and accounts"))
+                .arg(
+                    Arg::with_name("max_epochs_to_process")
+                        .long("max-epochs-to-process")
+                        .value_name("NUMBER")
+                        .takes_value(true)
+                        .validator(is_parsable::<u64>)
+                        .help("Only process up to this number of epochs for account balance changes [default: all]"),
+                )
+        .subcommand(
+            SubCommand::with_name("db")
+                .about("Database management")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("import")
+                        .about("Import another database")
+                        .arg(
+                            Arg::with_name("other_db_path")
+                                .value_name("PATH")
+                                .takes_value(true)
+                                .help("Path to the database to import"),
+                        )
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("influxdb")
+                .about("InfluxDb metrics management")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("clear")
+                        .about("Clear InfluxDb configuration")
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show InfluxDb configuration")
+                )
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Set InfluxDb configuration")
+                        .arg(
+                            Arg::with_name("url")
+                                .value_name("URL")
+                                .takes_value(true)
+                                .required(true)
+                                .help("InfluxDb URL"),
+                        )
+                        .arg(
+                            Arg::with_name("token")
+                                .value_name("TOKEN")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Access Token"),
+                        )
+                        .arg(
+                            Arg::with_name("org_id")
+                                .value_name("ORGANIZATION ID")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Organization Id"),
+                        )
+                        .arg(
+                            Arg::with_name("bucket")
+                                .value_name("BUCKET")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Bucket name"),
+                        )
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("withdrawal-approval")
+                .about("Approve withdrawals held back by the approval threshold")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Require approval for exchange withdrawals worth at least this much")
+                        .arg(
+                            Arg::with_name("threshold")
+                                .value_name("USD")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_parsable::<f64>)
+                                .help("Withdrawal value in USD at which approval is required"),
+                        )
+                        .arg(
+                            Arg::with_name("expiry")
+                                .long("expiry")
+                                .value_name("HOURS")
+                                .takes_value(true)
+                                .default_value("24")
+                                .validator(is_parsable::<u32>)
+                                .help("Hours a withdrawal intent may wait for approval"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show the withdrawal approval threshold")
+                )
+                .subcommand(
+                    SubCommand::with_name("clear")
+                        .about("Stop requiring approval for withdrawals")
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List withdrawal intents awaiting approval")
+                        .arg(
+                            Arg::with_name("all")
+                                .long("all")
+                                .help("Include executed, failed, cancelled and expired intents"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("approve")
+                        .about("Approve a withdrawal intent and request the withdrawal")
+                        .arg(
+                            Arg::with_name("code")
+                                .value_name("CODE")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Confirmation code of the withdrawal intent"),
+                        )
+                        .args(&two_factor_code_args())
+                )
+                .subcommand(
+                    SubCommand::with_name("cancel")
+                        .about("Cancel a withdrawal intent")
+                        .arg(
+                            Arg::with_name("code")
+                                .value_name("CODE")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Confirmation code of the withdrawal intent"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("log")
+                        .about("Show the withdrawal approval audit log")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("spending-limits")
+                .about("Limit how much orders and withdrawals may move")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Set a spending limit, replacing any on the same operation, token \
+                                and window")
+                        .arg(
+                            Arg::with_name("operation")
+                                .value_name("OPERATION")
+                                .takes_value(true)
+                                .required(true)
+                                .possible_values(POSSIBLE_LIMITED_OPERATION_VALUES)
+                                .help("Operation to limit; \"order\" covers both buys and sells"),
+                        )
+                        .arg(
+                            Arg::with_name("max")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_parsable::<f64>)
+                                .help("Largest amount allowed, in USD unless --token is given"),
+                        )
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Limit the amount of this token rather than the USD value"),
+                        )
+                        .arg(
+                            Arg::with_name("window")
+                                .long("window")
+                                .value_name("HOURS")
+                                .takes_value(true)
+                                .validator(is_spending_window)
+                                .help("Limit the total over this many trailing hours rather than \
+                                       each operation"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show the spending limits and how much of each window is used")
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Remove a spending limit")
+                        .arg(
+                            Arg::with_name("number")
+                                .value_name("NUMBER")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_parsable::<usize>)
+                                .help("Number of the limit, as shown by `spending-limits show`"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("log")
+                        .about("Show the spending limit audit log")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("settings")
+                .about("Show and change the settings that affect valuation and order placement")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("set")
+                        .about("Change a setting")
+                        .arg(
+                            Arg::with_name("setting")
+                                .value_name("SETTING")
+                                .takes_value(true)
+                                .required(true)
+                                .possible_values(POSSIBLE_SETTING_VALUES)
+                                .help("Setting to change"),
+                        )
+                        .arg(
+                            Arg::with_name("value")
+                                .value_name("VALUE")
+                                .takes_value(true)
+                                .required(true)
+                                .help("New value, as described by `settings show`"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("unset")
+                        .about("Return a setting to its default")
+                        .arg(
+                            Arg::with_name("setting")
+                                .value_name("SETTING")
+                                .takes_value(true)
+                                .required(true)
+                                .possible_values(POSSIBLE_SETTING_VALUES)
+                                .help("Setting to return to its default"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show every setting, its value and what it does")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("evacuation-address")
+                .about("Allow-list the addresses an exchange evacuation withdraws to")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Allow-list the evacuation address of a token, replacing any \
+                                other")
+                        .arg(
+                            Arg::with_name("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Token type"),
+                        )
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Tracked account to evacuate the token to"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Remove the evacuation address of a token")
+                        .arg(
+                            Arg::with_name("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Token type"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("List the allow-listed evacuation addresses")
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("account")
+                .about("Account management")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Register an account")
+                        .arg(
+                            Arg::with_name("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Token type"),
+                        )
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Account address to add"),
+                        )
+                        .arg(
+                            Arg::with_name("description")
+                                .short("d")
+                                .long("description")
+                                .value_name("TEXT")
+                                .takes_value(true)
+                                .help("Account description"),
+                        )
+                        .arg(
+                            Arg::with_name("when")
+                                .short("w")
+                                .long("when")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Date acquired (ignored if the --transaction argument is provided) [default: now]"),
+                        )
+                        .arg(
+                            Arg::with_name("transaction")
+                                .short("t")
+                                .long("transaction")
+                                .value_name("SIGNATURE")
+                                .takes_value(true)
+                                .validator(is_parsable::<Signature>)
+                                .help("Acquisition transaction signature"),
+                        )
+                        .arg(
+                            Arg::with_name("price")
+                                .short("p")
+                                .long("price")
+                                .value_name("USD")
+                                .takes_value(true)
+                                .validator(is_parsable::<f64>)
+                                .help("Acquisition price per SOL/token [default: market price on acquisition date]"),
+                        )
+                        .arg(
+                            Arg::with_name("income")
+                                .long("income")
+                                .takes_value(false)
+                                .conflicts_with("transaction")
+                                .help("Consider the acquisition value to be subject to income tax [default: post-tax fiat]"),
+                        )
+                        .arg(
+                            Arg::with_name("no_sync")
+                                .long("no-sync")
+                                .takes_value(false)
+                                .help("Never synchronize this account with the on-chain state (advanced; uncommon)"),
+                        )
+                        .arg(
+                            Arg::with_name("amount")
+                                .long("amount")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_parsable::<f64>)
+                                .conflicts_with("transaction")
+                                .help("Consider the account to have this amount of tokens rather than \
+                                       using the current value on chain (advanced; uncommon)"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("dispose")
+                        .about("Manually record the disposal of SOL/tokens from an account")
+                        .arg(
+                            Arg::with_name("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Token type"),
+                        )
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Account that the SOL/tokens was/where disposed from"),
+                        )
+                        .arg(
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount)
+                                .required(true)
+                                .help("Amount of SOL/tokens that was/where disposed from the account"),
+                        )
+                        .arg(
+                            Arg::with_name("description")
+                                .short("d")
+                                .long("description")
+                                .value_name("TEXT")
+                                .takes_value(true)
+                                .help("Description to associate with the disposal event"),
+                        )
+                        .arg(
+                            Arg::with_name("when")
+                                .short("w")
+                                .long("when")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Disposal date [default: now]"),
+                        )
+                        .arg(
+                            Arg::with_name("price")
+                                .short("p")
+                                .long("price")
+                                .value_name("USD")
+                                .takes_value(true)
+                                .validator(is_parsable::<f64>)
+                                .help("Disposal price per SOL/token [default: market price on disposal date]"),
+                        )
+                        .arg(lot_selection_arg())
+                        .arg(lot_numbers_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("ls")
+                        .about("List registered accounts")
+                        .alias("sl")
+                        .arg(
+                            Arg::with_name("all")
+                                .short("a")
+                                .long("all")
+                                .help("Display all lots")
+                        )
+                        .arg(
+                            Arg::with_name("account")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .validator(is_valid_pubkey)
+                                .help("Limit output to this address"),
+                        )
+                        .arg(
+                            Arg::with_name("summary")
+                                .long("summary")
+                                .takes_value(false)
+                                .help("Limit output to summary line"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("cost-basis")
+                        .about("Display average cost basis of holdings")
+                        .arg(
+                            Arg::with_name("when")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .required(false)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .default_value(&default_when)
+                                .help("Date to calculate cost basis for")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("xls")
+                        .about("Export an Excel spreadsheet file")
+                        .arg(
+                            Arg::with_name("outfile")
+                                .value_name("FILEPATH")
+                                .takes_value(true)
+                                .help(".xls file to write"),
+                        )
+                        .arg(
+                            Arg::with_name("year")
+                                .long("year")
+                                .value_name("YYYY")
+                                .takes_value(true)
+                                .validator(is_parsable::<usize>)
+                                .help("Limit export to realized gains affecting the given year"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Unregister an account")
+                        .arg(
+                            Arg::with_name("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token_or_sol)
+                                .help("Token type"),
+                        )
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Account address to remove"),
+                        )
+                        .arg(
+                            Arg::with_name("confirm")
+                                .long("confirm")
+                                .takes_value(false)
+                                .help("Confirm the operation"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("set-sweep-stake-account")
+                        .about("Set the sweep stake account")
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Sweep stake account address"),
+                        )
+                        .arg(
+                            Arg::with_name("stake_authority")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .required(true)
+                                .help("Stake authority keypair"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("set-tax-rate")
+                        .about("Set entity tax rate for account listing")
+                        .arg(
+                            Arg::with_name("income")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_tax_rate)
+                                .help("Income tax rate")
+                        )
+                        .arg(
+                            Arg::with_name("short-term-gain")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_tax_rate)
+                                .help("Short-term capital gain tax rate")
+                        )
+                        .arg(
+                            Arg::with_name("long-term-gain")
+                                .takes_value(true)
+                                .validator(is_tax_rate)
+                                .help("Long-term capital gain tax rate (default: short-term rate)")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("tax-rate")
+                        .about("Show entity tax rate for account listing")
+                )
+                .subcommand(
+                    SubCommand::with_name("merge")
+                        .about("Merge one stake account into another")
+                        .arg(
+                            Arg::with_name("from_address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Source address")
+                        )
+                        .arg(
+                            Arg::with_name("into_address")
+                                .long("into")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Destination address")
+                        )
+                        .arg(
+                            Arg::with_name("by")
+                                .long("by")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_valid_signer)
+                                .help("Optional authority for the merge"),
+                        )
+                        .arg(
+                            Arg::with_name("transaction")
+                                .long("transaction")
+                                .value_name("SIGNATURE")
+                                .takes_value(true)
+                                .validator(is_parsable::<Signature>)
+                                .help("Existing merge transaction signature that succeeded but \
+                                      due to RPC infrastructure limitations the local database \
+                                      considered it to have failed. Careful!")
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("sweep")
+                        .about("Sweep SOL into the sweep stake account")
+                        .arg(
+                            Arg::with_name("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token type"),
+                        )
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Source address to sweep from"),
+                        )
+                        .arg(
+                            Arg::with_name("authority")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_signer)
+                                .help("Source account authority keypair"),
+                        )
+                        .arg(
+                            Arg::with_name("to")
+                                .long("to")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .validator(is_valid_pubkey)
+                                .help("Sweep destination address [default: sweep stake account]")
+                        )
+                        .arg(
+                            Arg::with_name("no_sweep_ok")
+                                .long("no-sweep-ok")
+                                .takes_value(false)
+                                .help("Exit successfully if a sweep is not possible due to low source account balance"),
+                        )
+                        .arg(
+                            Arg::with_name("retain")
+                                .short("r")
+                                .long("retain")
+                                .value_name("SOL")
+                                .takes_value(true)
+                                .validator(is_parsable::<f64>)
+                                .help("Amount of SOL to retain in the source account [default: 0]"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("split")
+                        .about("Split a stake account")
+                        .arg(
+                            Arg::with_name("from_address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Address of the stake account to split")
+                        )
+                        .arg(
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount_or_all)
+                                .required(true)
+                                .help("The amount to wrap, in SOL; accepts keyword ALL"),
+                        )
+                        .arg(
+                            Arg::with_name("description")
+                                .short("d")
+                                .long("description")
+                                .value_name("TEXT")
+                                .takes_value(true)
+                                .help("Description of the new account"),
+                        )
+                        .arg(
+                            Arg::with_name("by")
+                                .long("by")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_valid_signer)
+                                .help("Optional authority for the split"),
+                        )
+                        .arg(
+                            Arg::with_name("into_keypair")
+                                .long("into")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_keypair)
+                                .help("Optional keypair of the split destination [default: randomly generated]"),
+                        )
+                        .arg(
+                            Arg::with_name("if_balance_exceeds")
+                                .long("if-balance-exceeds")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount)
+                                .help(
+                                    "Exit successfully without performing the split if \
+                                       the account balance is less than this amount",
+                                ),
+                        )
+                        .arg(lot_selection_arg())
+                        .arg(lot_numbers_arg())
+                )
+                .subcommand(
+                    SubCommand::with_name("redelegate")
+                        .about("Redelegate a stake account to another validator")
+                        .arg(
+                            Arg::with_name("from_address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Address of the stake account to redelegate")
+                        )
+                        .arg(
+                            Arg::with_name("vote_account_address")
+                                .long("to")
+                                .value_name("VOTE ACCOUNT")
+                                .takes_value(true)
+                                .validator(is_valid_pubkey)
+                                .required(true)
+                                .help("Address of the redelegated validator vote account"),
+                        )
+                        .arg(
+                            Arg::with_name("by")
+                                .long("by")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_valid_signer)
+                                .help("Optional authority for the redelegation"),
+                        )
+                        .arg(
+                            Arg::with_name("into_keypair")
+                                .long("into")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_keypair)
+                                .help("Optional keypair for the redelegated stake account [default: randomly generated]"),
+                        )
+                        .arg(lot_selection_arg())
+                )
+                .subcommand(
+                    SubCommand::with_name("sync")
+                        .about("Synchronize an account address")
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(false)
+                                .validator(is_valid_pubkey)
+                                .help("Account to synchronize"),
+                        )
+                        .arg(
+                            Arg::with_name("max_epochs_to_process")
+                                .long("max-epochs-to-process")
+                                .value_name("NUMBER")
+                                .takes_value(true)
+                                .validator(is_parsable::<u64>)
+                                .help("Only process up to this number of epochs for account balance changes [default: all]"),
+                        )
+                        .arg(
+                            Arg::with_name("reconcile_no_sync_account_balances")
+                                .long("reconcile-no-sync-account-balances")
+                                .takes_value(false)
+                                .help("Reconcile local account balances with on-chain state for --no-sync accounts (advanced; uncommon)"),
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("wrap")
+                        .about("Wrap SOL into wSOL")
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Address of the account to wrap")
+                        )
+                        .arg(
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount_or_all)
+                                .required(true)
+                                .help("The amount to wrap, in SOL; accepts keyword ALL"),
+                        )
+                        .arg(
+                            Arg::with_name("by")
+                                .long("by")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_valid_signer)
+                                .help("Optional authority for the wrap"),
+                        )
+                        .arg(
+                            Arg::with_name("if_source_balance_exceeds")
+                                .long("if-source-balance-exceeds")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount)
+                                .help(
+                                    "Exit successfully without wrapping if the \
+                                       source account balance is less than this amount",
+                                ),
+                        )
+                        .arg(lot_selection_arg())
+                        .arg(lot_numbers_arg())
+                )
+                .subcommand(
+                    SubCommand::with_name("unwrap")
+                        .about("Unwrap SOL from wSOL")
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_pubkey)
+                                .help("Address of the account to unwrap")
+                        )
+                        .arg(
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount_or_all)
+                                .required(true)
+                                .help("The amount to unwrap, in SOL; accepts keyword ALL"),
+                        )
+                        .arg(
+                            Arg::with_name("by")
+                                .long("by")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .validator(is_valid_signer)
+                                .help("Optional authority for the unwrap"),
+                        )
+                        .arg(lot_selection_arg())
+                        .arg(lot_numbers_arg())
+                )
+                .subcommand(
+                    SubCommand::with_name("lot")
+                        .about("Account lot management")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .setting(AppSettings::InferSubcommands)
+                        .subcommand(
+                            SubCommand::with_name("swap")
+                                .about("Swap lots in the local database only")
+                                .arg(
+                                    Arg::with_name("lot_number1")
+                                        .value_name("LOT NUMBER")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_parsable::<usize>)
+                                        .help("First lot number"),
+                                )
+                                .arg(
+                                    Arg::with_name("lot_number2")
+                                        .value_name("LOT NUMBER")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_parsable::<usize>)
+                                        .help("Second lot number"),
+                                )
+                        )
+                        .subcommand(
+                            SubCommand::with_name("delete")
+                                .about("Delete a lot from the local database only. \
+                                        Useful if the on-chain state is out of sync with the database")
+                                .arg(
+                                    Arg::with_name("lot_number")
+                                        .value_name("LOT NUMBER")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_parsable::<usize>)
+                                        .help("Lot number to delete. Must not be a disposed lot"),
+                                )
+                                .arg(
+                                    Arg::with_name("confirm")
+                                        .long("confirm")
+                                        .takes_value(false)
+                                        .help("Confirm the operation"),
+                                )
+                        )
+                        .subcommand(
+                            SubCommand::with_name("move")
+                                .about("Move a lot to a new addresses in the local database only. \
+                                        Useful if the on-chain state is out of sync with the database")
+                                .arg(
+                                    Arg::with_name("lot_number")
+                                        .value_name("LOT NUMBER")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_parsable::<usize>)
+                                        .help("Lot number to move. Must not be a disposed lot"),
+                                )
+                                .arg(
+                                    Arg::with_name("to_address")
+                                        .value_name("RECIPIENT_ADDRESS")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_valid_pubkey)
+                                        .help("Address to receive the lot"),
+                                )
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("jup")
+                .about("jup.ag")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .subcommand(
+                    SubCommand::with_name("quote")
+                        .about("Get swap quotes")
+                        .arg(
+                            Arg::with_name("from_token")
+                                .value_name("SOURCE TOKEN")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token)
+                                .default_value("wSOL")
+                                .help("Source token"),
+                        )
+                        .arg(
+                            Arg::with_name("to_token")
+                                .value_name("DESTINATION TOKEN")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token)
+                                .default_value("USDC")
+                                .help("Destination token"),
+                        )
+                        .arg(
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount)
+                                .required(true)
+                                .default_value("1")
+                                .help("Amount of the source token to swap"),
+                        )
+                        .arg(
+                            Arg::with_name("slippage_bps")
+                                .long("slippage")
+                                .value_name("BPS")
+                                .takes_value(true)
+                                .validator(is_parsable::<u64>)
+                                .default_value("100")
+                                .help("Maximum slippage bps"),
+                        )
+                        .arg(
+                            Arg::with_name("max_quotes")
+                                .short("n")
+                                .value_name("LIMIT")
+                                .takes_value(true)
+                                .validator(is_parsable::<usize>)
+                                .help("Limit to this number of quotes [default: all quotes]"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("swap")
+                        .about("Swap tokens")
+                        .arg(
+                            Arg::with_name("address")
+                                .value_name("KEYPAIR")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_signer)
+                                .help("Address of the account holding the tokens to swap")
+                        )
+                        .arg(
+                            Arg::with_name("from_token")
+                                .value_name("SOURCE TOKEN")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token)
+                                .help("Source token"),
+                        )
+                        .arg(
+                            Arg::with_name("to_token")
+                                .value_name("DESTINATION TOKEN")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(is_valid_token)
+                                .help("Destination token"),
+                        )
+                        .arg(
+                            Arg::with_name("amount")
+                                .value_name("SOURCE TOKEN AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount_or_all)
+                                .required(true)
+                                .help("Amount of tokens to swap; accepts ALL keyword"),
+                        )
+                        .arg(
+                            Arg::with_name("slippage_bps")
+                                .long("slippage")
+                                .value_name("BPS")
+                                .takes_value(true)
+                                .validator(is_parsable::<u64>)
+                                .default_value("100")
+                                .help("Maximum slippage bps"),
+                        )
+                        .arg(
+                            Arg::with_name("if_from_balance_exceeds")
+                                .long("if-source-balance-exceeds")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount)
+                                .help(
+                                    "Exit successfully without placing a swap if the \
+                                       source account balance is less than this amount",
+                                ),
+                        )
+                        .arg(
+                            Arg::with_name("for_no_less_than")
+                                .long("for-no-less-than")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_parsable::<f64>)
+                                .conflicts_with("at")
+                                .help(
+                                    "Exit successfully without swapping if \
+                                       the swap would result in less than \
+                                       this amount of destination tokens",
+                                ),
+                        )
+                        .arg(
+                            Arg::with_name("max_coingecko_value_percentage_loss")
+                                .long("max-coingecko-value-percentage-loss")
+                                .value_name("PERCENT")
+                                .takes_value(true)
+                                .validator(is_parsable::<f64>)
+                                .default_value("5")
+                                .help("Reject if the value lost relative to CoinGecko token
+                                      price exceeds this percentage"),
+                        )
+                        .arg(
+                            Arg::with_name("compute_unit_price_micro_lamports")
+                                .long("compute-unit-price")
+                                .value_name("MICROLAMPORTS")
+                                .takes_value(true)
+                                .validator(is_parsable::<usize>)
+                                .help("Set a compute unit price in micro-lamports to pay \
+                                      a higher transaction fee for higher transaction \
+                                      prioritization"),
+                        )
+                        .arg(lot_selection_arg())
+                        .arg(
+                            Arg::with_name("transaction")
+                                .long("transaction")
+                                .value_name("SIGNATURE")
+                                .takes_value(true)
+                                .validator(is_parsable::<Signature>)
+                                .help("Existing swap transaction signature that succeeded but \
+                                      due to RPC infrastructure limitations the local database \
+                                      considered it to have failed. Careful!")
+                        )
+                )
+        )
         .subcommand(
-            SubCommand::with_name("price")
-                .about("Get token price")
+            SubCommand::with_name("stake-spreader")
+                .alias("ss")
+                .about("Spread stake across the top-performing validators")
+                .after_help("\
+                    The stake spreader will not explicitly split or merge stake accounts. \
+                    Instead the normal `account split` and `account merge` subcommands should be \
+                    used to shape your stake accounts as desired, at any time. The spreader will \
+                    then aim to delegate those accounts equally over the number of validators \
+                    specified, favouring the more performant validators. \
+                ")
                 .arg(
-                    Arg::with_name("token")
-                        .value_name("SOL or SPL Token")
+                    Arg::with_name("stake_authority")
+                        .long("stake-authority")
+                        .value_name("KEYPAIR")
                         .takes_value(true)
                         .required(true)
-                        .validator(is_valid_token_or_sol)
-                        .default_value("SOL")
-                        .help("Token type"),
+                        .validator(is_valid_signer)
+                        .help("Stake authority of the stake accounts to be spread"),
                 )
                 .arg(
-                    Arg::with_name("when")
-                        .value_name("YY/MM/DD")
+                    Arg::with_name("epoch_completed_percentage")
+                        .long("epoch-completed-percentage")
+                        .value_name("PERCENT")
                         .takes_value(true)
-                        .required(false)
-                        .validator(|value| naivedate_of(&value).map(|_| ()))
-                        .help("Date to fetch the price for [default: current spot price]"),
+                        .validator(is_valid_percentage)
+                        .required(true)
+                        .default_value("90")
+                        .help("Wait until the current epoch is this percent complete before rebalancing stake"),
                 )
-        )
-        .subcommand(
-            SubCommand::with_name("sync")
-                .about("Synchronize with all exchanges and accounts"))
                 .arg(
-                    Arg::with_name("max_epochs_to_process")
-                        .long("max-epochs-to-process")
-                        .value_name("NUMBER")
+                    Arg::with_name("epoch_history")
+                        .long("epoch-history")
+                        .value_name("COUNT")
                         .takes_value(true)
-                        .validator(is_parsable::<u64>)
-                        .help("Only process up to this number of epochs for account balance changes [default: all]"),
+                        .validator(is_parsable::<usize>)
+                        .required(true)
+                        .default_value("2")
+                        .help("Consider validator performance over the previous COUNT epochs"),
+                )
+                .arg(
+                    Arg::with_name("num_validators")
+                        .long("validators")
+                        .value_name("COUNT")
+                        .takes_value(true)
+                        .validator(is_parsable::<usize>)
+                        .required(true)
+                        .default_value("10")
+                        .help("Number of validators to spread the stake over"),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .value_name("VOTE ACCOUNT ADDRESS")
+                        .takes_value(true)
+                        .multiple(true)
+                        .validator(is_valid_pubkey)
+                        .help("Always select this this vote account for a delegation"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .value_name("VOTE ACCOUNT ADDRESS")
+                        .takes_value(true)
+                        .multiple(true)
+                        .validator(is_valid_pubkey)
+                        .help("Never select this this vote account for a delegation"),
                 )
+                .arg(
+                    Arg::with_name("danger")
+                        .long("danger")
+                        .takes_value(false)
+                        .help("NOT WELL TESTED YET, MIGHT SCREW UP YOUR DELEGATIONS"),
+                ),
+        )
         .subcommand(
-            SubCommand::with_name("db")
-                .about("Database management")
+            SubCommand::with_name("tulip")
+                .about("tulip.garden")
                 .setting(AppSettings::SubcommandRequiredElseHelp)
                 .setting(AppSettings::InferSubcommands)
                 .subcommand(
-                    SubCommand::with_name("import")
-                        .about("Import another database")
+                    SubCommand::with_name("apr")
+                        .about("Display the spot APR for a given collateral token")
                         .arg(
-                            Arg::with_name("other_db_path")
-                                .value_name("PATH")
+                            Arg::with_name("collateral_token")
+                                .value_name("COLLATERAL_TOKEN")
                                 .takes_value(true)
-                                .help("Path to the database to import"),
+                                .required(true)
+                                .validator(is_valid_token)
+                                .possible_values(&["tuSOL", "tumSOL", "tustSOL", "tuUSDC"])
+                                .help("Collateral token"),
                         )
                 )
-        )
-        .subcommand(
-            SubCommand::with_name("influxdb")
-                .about("InfluxDb metrics management")
-                .setting(AppSettings::SubcommandRequiredElseHelp)
-                .setting(AppSettings::InferSubcommands)
-                .subcommand(
-                    SubCommand::with_name("clear")
-                        .about("Clear InfluxDb configuration")
-                )
-                .subcommand(
-                    SubCommand::with_name("show")
-                        .about("Show InfluxDb configuration")
-                )
                 .subcommand(
-                    SubCommand::with_name("set")
-                        .about("Set InfluxDb configuration")
+                    SubCommand::with_name("deposit")
+                        .about("Deposit liquidity")
                         .arg(
-                            Arg::with_name("url")
-                                .value_name("URL")
+                            Arg::with_name("collateral_token")
+                                .value_name("COLLATERAL_TOKEN")
                                 .takes_value(true)
                                 .required(true)
-                                .help("InfluxDb URL"),
+                                .validator(is_valid_token)
+                                .possible_values(&["tuSOL", "tumSOL", "tustSOL", "tuUSDC"])
+                                .help("Collateral token"),
                         )
                         .arg(
-                            Arg::with_name("token")
-                                .value_name("TOKEN")
+                            Arg::with_name("amount")
+                                .value_name("LIQUIDITY_AMOUNT")
                                 .takes_value(true)
+                                .validator(is_amount_or_all)
                                 .required(true)
-                                .help("Access Token"),
+                                .help("Amount of liquidity tokens to deposit; accepts keywords ALL"),
                         )
                         .arg(
-                            Arg::with_name("org_id")
-                                .value_name("ORGANIZATION ID")
+                            Arg::with_name("liquidity_token")
+                                .value_name("LIQUIDITY_TOKEN")
                                 .takes_value(true)
-                                .required(true)
-                                .help("Organization Id"),
+                                .validator(is_valid_token)
+                                .help("Override the liquidity token for the given collateral token. \
+                                       Typically used to specify `wSOL` instead of `SOL` \
+                                       [default: deduced from the collateral token]"),
                         )
                         .arg(
-                            Arg::with_name("bucket")
-                                .value_name("BUCKET")
+                            Arg::with_name("from")
+                                .long("from")
+                                .value_name("FROM_ADDRESS")
                                 .takes_value(true)
                                 .required(true)
-                                .help("Bucket name"),
+                                .validator(is_valid_signer)
+                                .help("Source account of funds"),
+                        )
+                        .arg(lot_selection_arg())
+                        .arg(
+                            Arg::with_name("transaction")
+                                .long("transaction")
+                                .value_name("SIGNATURE")
+                                .takes_value(true)
+                                .validator(is_parsable::<Signature>)
+                                .help("Existing deposit transaction signature that succeeded but \
+                                      due to RPC infrastructure limitations the local database \
+                                      considered it to have failed. Careful!")
                         )
                 )
-        )
-        .subcommand(
-            SubCommand::with_name("account")
-                .about("Account management")
-                .setting(AppSettings::SubcommandRequiredElseHelp)
-                .setting(AppSettings::InferSubcommands)
                 .subcommand(
-                    SubCommand::with_name("add")
-                        .about("Register an account")
+                    SubCommand::with_name("withdraw")
+                        .about("Withdraw liquidity")
                         .arg(
-                            Arg::with_name("token")
-                                .value_name("SOL or SPL Token")
+                            Arg::with_name("collateral_token")
+                                .value_name("COLLATERAL_TOKEN")
                                 .takes_value(true)
                                 .required(true)
-                                .validator(is_valid_token_or_sol)
-                                .help("Token type"),
+                                .validator(is_valid_token)
+                                .possible_values(&["tuSOL", "tumSOL", "tustSOL", "tuUSDC"])
+                                .help("Collateral token"),
                         )
-                        .arg(
-                            Arg::with_name("address")
-                                .value_name("ADDRESS")
+                        .arg(
+                            Arg::with_name("to")
+                                .value_name("RECIPIENT_ADDRESS")
                                 .takes_value(true)
                                 .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Account address to add"),
+                                .validator(is_valid_signer)
+                                .help("Address to receive the withdrawal of funds"),
                         )
                         .arg(
-                            Arg::with_name("description")
-                                .short("d")
-                                .long("description")
-                                .value_name("TEXT")
+                            Arg::with_name("amount")
+                                .value_name("LIQUIDITY_AMOUNT")
                                 .takes_value(true)
-                                .help("Account description"),
+                                .validator(is_amount_or_all)
+                                .required(true)
+                                .help("Amount of liquidity tokens to withdraw; accepts keyword ALL"),
                         )
                         .arg(
-                            Arg::with_name("when")
-                                .short("w")
-                                .long("when")
-                                .value_name("YY/MM/DD")
+                            Arg::with_name("liquidity_token")
+                                .value_name("LIQUIDITY_TOKEN")
                                 .takes_value(true)
-                                .validator(|value| naivedate_of(&value).map(|_| ()))
-                                .help("Date acquired (ignored if the --transaction argument is provided) [default: now]"),
+                                .validator(is_valid_token)
+                                .help("Override the liquidity token for the given collateral token. \
+                                       Typically used to specify `wSOL` instead of `SOL` \
+                                       [default: deduced from the collateral token]"),
                         )
+                        .arg(lot_selection_arg())
+                )
+        );
+
+    for exchange in &exchanges {
+        app = app.subcommand(
+            SubCommand::with_name(exchange)
+                .about("Exchange interactions")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .setting(AppSettings::InferSubcommands)
+                .arg(
+                    Arg::with_name("exchange_account")
+                        .long("account")
+                        .value_name("ACCOUNT_NAME")
+                        .takes_value(true)
+                        .global(true)
+                        .help("User-defined name of the exchange account to use \
+                              (extra help: specify this option BEFORE the subcomand name, \
+                               not after as indicated by --help) [default: '']"),
+                )
+                .arg(
+                    Arg::with_name("paper")
+                        .long("paper")
+                        .takes_value(false)
+                        .global(true)
+                        .help("Run against a simulated paper trading account backed by live \
+                               market data. Supported by the balance, market, fees and paper \
+                               commands (extra help: specify this option BEFORE the subcomand name)"),
+                )
+                .subcommand(
+                    SubCommand::with_name("balance")
+                        .about("Get exchange balance")
                         .arg(
-                            Arg::with_name("transaction")
-                                .short("t")
-                                .long("transaction")
-                                .value_name("SIGNATURE")
-                                .takes_value(true)
-                                .validator(is_parsable::<Signature>)
-                                .help("Acquisition transaction signature"),
+                            Arg::with_name("available_only")
+                                .long("available")
+                                .takes_value(false)
+                                .help("Only display available balance")
                         )
                         .arg(
-                            Arg::with_name("price")
-                                .short("p")
-                                .long("price")
-                                .value_name("USD")
-                                .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .help("Acquisition price per SOL/token [default: market price on acquisition date]"),
+                            Arg::with_name("total_only")
+                                .long("total")
+                                .takes_value(false)
+                                .conflicts_with("available_only")
+                                .help("Only display total balance")
                         )
                         .arg(
-                            Arg::with_name("income")
-                                .long("income")
+                            Arg::with_name("integer")
+                                .long("integer")
                                 .takes_value(false)
-                                .conflicts_with("transaction")
-                                .help("Consider the acquisition value to be subject to income tax [default: post-tax fiat]"),
+                                .help("Output integer values with no currency symbols")
                         )
                         .arg(
-                            Arg::with_name("no_sync")
-                                .long("no-sync")
+                            Arg::with_name("json")
+                                .long("json")
                                 .takes_value(false)
-                                .help("Never synchronize this account with the on-chain state (advanced; uncommon)"),
+                                .conflicts_with_all(&["available_only", "total_only", "integer"])
+                                .help("Output all balances as JSON")
                         )
                         .arg(
-                            Arg::with_name("amount")
-                                .long("amount")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .conflicts_with("transaction")
-                                .help("Consider the account to have this amount of tokens rather than \
-                                       using the current value on chain (advanced; uncommon)"),
+                            Arg::with_name("by_wallet")
+                                .long("by-wallet")
+                                .takes_value(false)
+                                .conflicts_with_all(&["available_only", "total_only", "integer"])
+                                .help("Break down balances by the exchange wallet holding them")
                         )
                 )
                 .subcommand(
-                    SubCommand::with_name("dispose")
-                        .about("Manually record the disposal of SOL/tokens from an account")
+                    SubCommand::with_name("address")
+                        .about("Show deposit address")
                         .arg(
                             Arg::with_name("token")
                                 .value_name("SOL or SPL Token")
                                 .takes_value(true)
                                 .required(true)
                                 .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
                                 .help("Token type"),
                         )
                         .arg(
-                            Arg::with_name("address")
+                            Arg::with_name("accept")
+                                .long("accept")
                                 .value_name("ADDRESS")
                                 .takes_value(true)
-                                .required(true)
                                 .validator(is_valid_pubkey)
-                                .help("Account that the SOL/tokens was/where disposed from"),
+                                .help("Accept a new deposit address reported by the exchange, \
+                                       replacing the pinned address"),
                         )
+                )
+                .subcommand(
+                    SubCommand::with_name("deposit-requirements")
+                        .about("Show the confirmations and minimum the exchange requires before \
+                                crediting a deposit, and when a deposit is reported as overdue")
                         .arg(
-                            Arg::with_name("amount")
-                                .value_name("AMOUNT")
+                            Arg::with_name("token")
+                                .value_name("SOL or SPL Token")
                                 .takes_value(true)
-                                .validator(is_amount)
-                                .required(true)
-                                .help("Amount of SOL/tokens that was/where disposed from the account"),
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token type"),
                         )
                         .arg(
-                            Arg::with_name("description")
-                                .short("d")
-                                .long("description")
-                                .value_name("TEXT")
-                                .takes_value(true)
-                                .help("Description to associate with the disposal event"),
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output the requirements as JSON"),
                         )
+                )
+                .subcommand(
+                    SubCommand::with_name("market")
+                        .about("Display market info for a given trading pair")
                         .arg(
-                            Arg::with_name("when")
-                                .short("w")
-                                .long("when")
-                                .value_name("YY/MM/DD")
+                            Arg::with_name("pair")
+                                .value_name("TRADING_PAIR")
                                 .takes_value(true)
-                                .validator(|value| naivedate_of(&value).map(|_| ()))
-                                .help("Disposal date [default: now]"),
+                                .help("[default: preferred USD pair for the token on the exchange]")
                         )
                         .arg(
-                            Arg::with_name("price")
-                                .short("p")
-                                .long("price")
-                                .value_name("USD")
+                            Arg::with_name("quote")
+                                .long("quote")
+                                .value_name("COIN")
                                 .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .help("Disposal price per SOL/token [default: market price on disposal date]"),
+                                .conflicts_with("pair")
+                                .help("Quote currency of the market, such as BTC [default: USD]"),
                         )
-                        .arg(lot_selection_arg())
-                        .arg(lot_numbers_arg()),
-                )
-                .subcommand(
-                    SubCommand::with_name("ls")
-                        .about("List registered accounts")
-                        .alias("sl")
                         .arg(
-                            Arg::with_name("all")
-                                .short("a")
-                                .long("all")
-                                .help("Display all lots")
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token to display market info for"),
                         )
                         .arg(
-                            Arg::with_name("account")
-                                .value_name("ADDRESS")
-                                .takes_value(true)
-                                .validator(is_valid_pubkey)
-                                .help("Limit output to this address"),
+                            Arg::with_name("ask")
+                                .long("ask")
+                                .takes_value(false)
+                                .help("Only display the current asking price")
                         )
                         .arg(
-                            Arg::with_name("summary")
-                                .long("summary")
+                            Arg::with_name("weighted_24h_average_price")
+                                .long("weighted-24h-average-price")
                                 .takes_value(false)
-                                .help("Limit output to summary line"),
-                        ),
-                )
-                .subcommand(
-                    SubCommand::with_name("cost-basis")
-                        .about("Display average cost basis of holdings")
+                                .conflicts_with("ask")
+                                .help("Only display the weighted average price for the previous 24 hours"),
+                        )
                         .arg(
-                            Arg::with_name("when")
-                                .value_name("YY/MM/DD")
-                                .takes_value(true)
-                                .required(false)
-                                .validator(|value| naivedate_of(&value).map(|_| ()))
-                                .default_value(&default_when)
-                                .help("Date to calculate cost basis for")
+                            Arg::with_name("hourly")
+                                .long("hourly")
+                                .takes_value(false)
+                                .conflicts_with_all(&["ask", "weighted_24h_average_price"])
+                                .help("Display hourly price information for the previous 24 hours"),
                         )
-                )
-                .subcommand(
-                    SubCommand::with_name("xls")
-                        .about("Export an Excel spreadsheet file")
                         .arg(
-                            Arg::with_name("outfile")
-                                .value_name("FILEPATH")
+                            Arg::with_name("interval")
+                                .long("interval")
+                                .value_name("MINUTES")
                                 .takes_value(true)
-                                .help(".xls file to write"),
+                                .validator(is_parsable::<u32>)
+                                .conflicts_with_all(&["ask", "weighted_24h_average_price"])
+                                .help("Display price information in candles of this many minutes \
+                                       [default: 60]"),
                         )
                         .arg(
-                            Arg::with_name("year")
-                                .long("year")
-                                .value_name("YYYY")
+                            Arg::with_name("count")
+                                .long("count")
+                                .value_name("CANDLES")
                                 .takes_value(true)
                                 .validator(is_parsable::<usize>)
-                                .help("Limit export to realized gains affecting the given year"),
+                                .conflicts_with_all(&["ask", "weighted_24h_average_price"])
+                                .help("Display this many of the most recent candles [default: 24]"),
+                        )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .conflicts_with_all(&[
+                                    "ask",
+                                    "weighted_24h_average_price",
+                                    "hourly",
+                                    "interval",
+                                    "count",
+                                ])
+                                .help("Display the 24 hour ticker statistics as JSON"),
                         ),
                 )
                 .subcommand(
-                    SubCommand::with_name("remove")
-                        .about("Unregister an account")
+                    SubCommand::with_name("api")
+                        .about("API Management")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .setting(AppSettings::InferSubcommands)
+                        .subcommand(
+                            SubCommand::with_name("set")
+                                .about("Set API key")
+                                .arg(Arg::with_name("api_key").required(true).takes_value(true))
+                                .arg(Arg::with_name("secret").required(true).takes_value(true))
+                                .arg(
+                                    Arg::with_name("subaccount")
+                                        .takes_value(true)
+                                        .help("Subaccount name; for Coinbase, the portfolio name or uuid"),
+                                ),
+                        )
+                        .subcommand(SubCommand::with_name("show").about("Show API key"))
+                        .subcommand(SubCommand::with_name("clear").about("Clear API key")),
+                )
+                .subcommand(
+                    SubCommand::with_name("deposit")
+                        .about("Deposit SOL or SPL Tokens")
                         .arg(
                             Arg::with_name("token")
                                 .value_name("SOL or SPL Token")
@@ -4415,1205 +15434,1431 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 .help("Token type"),
                         )
                         .arg(
-                            Arg::with_name("address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
+                                .validator(is_amount_or_all_or_half)
                                 .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Account address to remove"),
+                                .help("Amount to deposit; accepts keywords ALL and HALF"),
                         )
+                        .arg(lot_selection_arg())
+                        .arg(lot_numbers_arg())
                         .arg(
-                            Arg::with_name("confirm")
-                                .long("confirm")
-                                .takes_value(false)
-                                .help("Confirm the operation"),
-                        ),
-                )
-                .subcommand(
-                    SubCommand::with_name("set-sweep-stake-account")
-                        .about("Set the sweep stake account")
-                        .arg(
-                            Arg::with_name("address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("from")
+                                .long("from")
+                                .value_name("FROM_ADDRESS")
                                 .takes_value(true)
                                 .required(true)
                                 .validator(is_valid_pubkey)
-                                .help("Sweep stake account address"),
+                                .help("Source account of funds"),
                         )
                         .arg(
-                            Arg::with_name("stake_authority")
+                            Arg::with_name("by")
+                                .long("by")
                                 .value_name("KEYPAIR")
                                 .takes_value(true)
-                                .required(true)
-                                .help("Stake authority keypair"),
+                                .validator(is_valid_signer)
+                                .help("Optional authority of the FROM_ADDRESS"),
                         )
-                )
-                .subcommand(
-                    SubCommand::with_name("set-tax-rate")
-                        .about("Set entity tax rate for account listing")
                         .arg(
-                            Arg::with_name("income")
+                            Arg::with_name("if_source_balance_exceeds")
+                                .long("if-source-balance-exceeds")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_tax_rate)
-                                .help("Income tax rate")
+                                .validator(is_amount)
+                                .help(
+                                    "Exit successfully without depositing if the \
+                                       source account balance is less than this amount",
+                                ),
                         )
                         .arg(
-                            Arg::with_name("short-term-gain")
+                            Arg::with_name("if_exchange_balance_less_than")
+                                .long("if-exchange-balance-less-than")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_tax_rate)
-                                .help("Short-term capital gain tax rate")
+                                .validator(is_amount)
+                                .help(
+                                    "Exit successfully without depositing if the \
+                                        exchange SOL balance is less than this amount",
+                                ),
                         )
                         .arg(
-                            Arg::with_name("long-term-gain")
-                                .takes_value(true)
-                                .validator(is_tax_rate)
-                                .help("Long-term capital gain tax rate (default: short-term rate)")
+                            Arg::with_name("keep_reserve")
+                                .long("keep-reserve")
+                                .takes_value(false)
+                                .help(
+                                    "Deposit everything except a SOL reserve for rent and future \
+                                     transaction fees. SPL tokens are deposited in full while the \
+                                     reserve is kept in the authority's SOL balance. \
+                                     Requires an AMOUNT of ALL",
+                                ),
                         )
-                )
-                .subcommand(
-                    SubCommand::with_name("tax-rate")
-                        .about("Show entity tax rate for account listing")
-                )
-                .subcommand(
-                    SubCommand::with_name("merge")
-                        .about("Merge one stake account into another")
                         .arg(
-                            Arg::with_name("from_address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("reserve")
+                                .long("reserve")
+                                .value_name("SOL")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Source address")
+                                .validator(is_amount)
+                                .default_value("0.05")
+                                .help(
+                                    "SOL kept back by --keep-reserve, in addition to the fee \
+                                     for the deposit transaction",
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("fund-from-stake")
+                        .about("Deactivate a stake account and deposit its SOL once inactive")
+                        .long_about(
+                            "Deactivate a stake account, withdraw it to the authority's wallet \
+                             once the stake is inactive, and deposit it to the exchange. Spans \
+                             at least one epoch boundary; run again with the same arguments \
+                             to continue from the last completed step",
                         )
                         .arg(
-                            Arg::with_name("into_address")
-                                .long("into")
-                                .value_name("ADDRESS")
+                            Arg::with_name("stake_address")
+                                .value_name("STAKE_ADDRESS")
                                 .takes_value(true)
                                 .required(true)
                                 .validator(is_valid_pubkey)
-                                .help("Destination address")
+                                .help("Stake account to fund the exchange from"),
                         )
                         .arg(
                             Arg::with_name("by")
                                 .long("by")
                                 .value_name("KEYPAIR")
                                 .takes_value(true)
+                                .required(true)
                                 .validator(is_valid_signer)
-                                .help("Optional authority for the merge"),
+                                .help(
+                                    "Stake and withdraw authority of the stake account, whose \
+                                     system account receives the SOL before the deposit",
+                                ),
                         )
                         .arg(
-                            Arg::with_name("transaction")
-                                .long("transaction")
-                                .value_name("SIGNATURE")
+                            Arg::with_name("reserve")
+                                .long("reserve")
+                                .value_name("SOL")
                                 .takes_value(true)
-                                .validator(is_parsable::<Signature>)
-                                .help("Existing merge transaction signature that succeeded but \
-                                      due to RPC infrastructure limitations the local database \
-                                      considered it to have failed. Careful!")
+                                .validator(is_amount)
+                                .default_value("0.05")
+                                .help(
+                                    "SOL kept in the authority's wallet for rent and future \
+                                     transaction fees",
+                                ),
+                        )
+                        .arg(
+                            Arg::with_name("wait")
+                                .long("wait")
+                                .takes_value(false)
+                                .help(
+                                    "Wait for deactivation and the exchange to credit the \
+                                     deposit instead of exiting when a step has to wait",
+                                ),
                         )
+                        .arg(lot_selection_arg()),
                 )
                 .subcommand(
-                    SubCommand::with_name("sweep")
-                        .about("Sweep SOL into the sweep stake account")
+                    SubCommand::with_name("withdraw")
+                        .about("Withdraw SOL or SPL Tokens")
                         .arg(
                             Arg::with_name("token")
                                 .value_name("SOL or SPL Token")
                                 .takes_value(true)
                                 .required(true)
                                 .validator(is_valid_token_or_sol)
-                                .default_value("SOL")
                                 .help("Token type"),
                         )
                         .arg(
-                            Arg::with_name("address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("to")
+                                .value_name("RECIPIENT_ADDRESS")
                                 .takes_value(true)
                                 .required(true)
                                 .validator(is_valid_pubkey)
-                                .help("Source address to sweep from"),
+                                .help("Address to receive the withdrawal of funds"),
                         )
                         .arg(
-                            Arg::with_name("authority")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
+                                .validator(is_amount)
                                 .required(true)
-                                .validator(is_valid_signer)
-                                .help("Source account authority keypair"),
-                        )
-                        .arg(
-                            Arg::with_name("to")
-                                .long("to")
-                                .value_name("ADDRESS")
-                                .takes_value(true)
-                                .validator(is_valid_pubkey)
-                                .help("Sweep destination address [default: sweep stake account]")
+                                .help("The amount to withdraw; accepts keyword ALL"),
                         )
+                        .arg(lot_selection_arg())
+                        .arg(lot_numbers_arg())
+                        .args(&two_factor_code_args())
+                        .arg(override_limits_arg())
                         .arg(
-                            Arg::with_name("no_sweep_ok")
-                                .long("no-sweep-ok")
+                            Arg::with_name("redeem_lending")
+                                .long("redeem-lending")
                                 .takes_value(false)
-                                .help("Exit successfully if a sweep is not possible due to low source account balance"),
+                                .help("If the available balance is short but funds are offered \
+                                       for lending, redeem enough to cover the withdrawal and \
+                                       wait for them to be released"),
                         )
                         .arg(
-                            Arg::with_name("retain")
-                                .short("r")
-                                .long("retain")
-                                .value_name("SOL")
+                            Arg::with_name("redeem_lending_timeout")
+                                .long("redeem-lending-timeout")
+                                .value_name("MINUTES")
                                 .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .help("Amount of SOL to retain in the source account [default: 0]"),
-                        ),
+                                .default_value("65")
+                                .validator(is_parsable::<u64>)
+                                .help("Give up on the withdrawal if redeemed funds are not \
+                                       released within this many minutes"),
+                        )
                 )
                 .subcommand(
-                    SubCommand::with_name("split")
-                        .about("Split a stake account")
-                        .arg(
-                            Arg::with_name("from_address")
-                                .value_name("ADDRESS")
-                                .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Address of the stake account to split")
-                        )
-                        .arg(
-                            Arg::with_name("amount")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_amount_or_all)
-                                .required(true)
-                                .help("The amount to wrap, in SOL; accepts keyword ALL"),
-                        )
+                    SubCommand::with_name("evacuate")
+                        .about("Empty a compromised account: cancel all open orders, redeem all \
+                                lending and withdraw each token to its allow-listed evacuation \
+                                address")
                         .arg(
-                            Arg::with_name("description")
-                                .short("d")
-                                .long("description")
-                                .value_name("TEXT")
-                                .takes_value(true)
-                                .help("Description of the new account"),
+                            Arg::with_name("dry_run")
+                                .long("dry-run")
+                                .takes_value(false)
+                                .help("Rehearse the evacuation without cancelling, redeeming or \
+                                       withdrawing anything"),
                         )
+                        .arg(lot_selection_arg())
+                        .args(&two_factor_code_args())
+                        .arg(override_limits_arg())
+                )
+                .subcommand(
+                    SubCommand::with_name("cancel")
+                        .about("Cancel orders")
                         .arg(
-                            Arg::with_name("by")
-                                .long("by")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("order_id")
+                                .value_name("ORDER ID")
                                 .takes_value(true)
-                                .validator(is_valid_signer)
-                                .help("Optional authority for the split"),
+                                .multiple(true)
+                                .help("The order id to cancel"),
                         )
                         .arg(
-                            Arg::with_name("into_keypair")
-                                .long("into")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("age")
+                                .long("age")
+                                .value_name("HOURS")
                                 .takes_value(true)
-                                .validator(is_keypair)
-                                .help("Optional keypair of the split destination [default: randomly generated]"),
+                                .validator(is_parsable::<u32>)
+                                .conflicts_with("order_id")
+                                .help("Cancel orders older than this number of hours"),
                         )
                         .arg(
-                            Arg::with_name("if_balance_exceeds")
-                                .long("if-balance-exceeds")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_amount)
-                                .help(
-                                    "Exit successfully without performing the split if \
-                                       the account balance is less than this amount",
-                                ),
+                            Arg::with_name("side")
+                                .long("side")
+                                .required(true)
+                                .default_value("both")
+                                .possible_values(&["both", "buy", "sell"])
+                                .help("Restrict to only buy or sell orders")
                         )
-                        .arg(lot_selection_arg())
-                        .arg(lot_numbers_arg())
                 )
                 .subcommand(
-                    SubCommand::with_name("redelegate")
-                        .about("Redelegate a stake account to another validator")
+                    SubCommand::with_name("buy")
+                        .about("Place an order to buy SOL or SPL Tokens")
                         .arg(
-                            Arg::with_name("from_address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Address of the stake account to redelegate")
+                                .validator(is_amount_or_all)
+                                .required_unless("quote_amount")
+                                .help("The amount to buy, in tokens; accepts keyword ALL"),
                         )
+                        .args(&quote_amount_args())
                         .arg(
-                            Arg::with_name("vote_account_address")
-                                .long("to")
-                                .value_name("VOTE ACCOUNT")
+                            Arg::with_name("at")
+                                .long("at")
+                                .value_name("PRICE")
                                 .takes_value(true)
-                                .validator(is_valid_pubkey)
-                                .required(true)
-                                .help("Address of the redelegated validator vote account"),
+                                .validator(is_parsable::<f64>)
+                                .help("Place a limit order at this price"),
                         )
                         .arg(
-                            Arg::with_name("by")
-                                .long("by")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("bid_minus")
+                                .long("bid-minus")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_valid_signer)
-                                .help("Optional authority for the redelegation"),
+                                .conflicts_with("at")
+                                .validator(is_parsable::<f64>)
+                                .help("Place a limit order at this amount under the current bid"),
                         )
                         .arg(
-                            Arg::with_name("into_keypair")
-                                .long("into")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("pair")
+                                .long("pair")
+                                .value_name("TRADING_PAIR")
                                 .takes_value(true)
-                                .validator(is_keypair)
-                                .help("Optional keypair for the redelegated stake account [default: randomly generated]"),
+                                .help("Market to place the order in [default: preferred USD pair for the token on the exchange]"),
                         )
-                        .arg(lot_selection_arg())
-                )
-                .subcommand(
-                    SubCommand::with_name("sync")
-                        .about("Synchronize an account address")
                         .arg(
-                            Arg::with_name("address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("quote")
+                                .long("quote")
+                                .value_name("COIN")
                                 .takes_value(true)
-                                .required(false)
-                                .validator(is_valid_pubkey)
-                                .help("Account to synchronize"),
+                                .conflicts_with("pair")
+                                .help("Quote currency of the market, such as BTC [default: USD]"),
                         )
                         .arg(
-                            Arg::with_name("max_epochs_to_process")
-                                .long("max-epochs-to-process")
-                                .value_name("NUMBER")
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
                                 .takes_value(true)
-                                .validator(is_parsable::<u64>)
-                                .help("Only process up to this number of epochs for account balance changes [default: all]"),
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token to buy"),
                         )
                         .arg(
-                            Arg::with_name("reconcile_no_sync_account_balances")
-                                .long("reconcile-no-sync-account-balances")
+                            Arg::with_name("skip_preflight")
+                                .long("skip-preflight")
                                 .takes_value(false)
-                                .help("Reconcile local account balances with on-chain state for --no-sync accounts (advanced; uncommon)"),
+                                .help("Skip the local minimum notional and available balance \
+                                       checks before placing the order"),
                         )
-                )
-                .subcommand(
-                    SubCommand::with_name("wrap")
-                        .about("Wrap SOL into wSOL")
+                        .arg(override_limits_arg())
                         .arg(
-                            Arg::with_name("address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("exact")
+                                .long("exact")
+                                .takes_value(false)
+                                .help("Submit the price and amount exactly as given rather than \
+                                       rounding them to the tick and lot size of the pair"),
+                        )
+                        .args(&order_rounding_args())
+                        .arg(
+                            Arg::with_name("expire_after")
+                                .long("expire-after")
+                                .value_name("HOURS")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Address of the account to wrap")
+                                .validator(is_parsable::<u32>)
+                                .help("Cancel the order if it is still open after this number \
+                                       of hours, on the exchange where supported and otherwise \
+                                       at the first sync after"),
                         )
+                        .arg(
+                            Arg::with_name("if_balance_exceeds")
+                                .long("if-balance-exceeds")
+                                .value_name("AMOUNT")
+                                .takes_value(true)
+                                .validator(is_amount)
+                                .help(
+                                    "Exit successfully without placing a buy order if the \
+                                       exchange available balance is less than this amount",
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("sell")
+                        .about("Place an order to sell SOL or SPL Tokens")
                         .arg(
                             Arg::with_name("amount")
                                 .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_amount_or_all)
-                                .required(true)
-                                .help("The amount to wrap, in SOL; accepts keyword ALL"),
+                                .validator(is_amount)
+                                .required_unless("quote_amount")
+                                .help("The amount to sell, in tokens"),
                         )
+                        .args(&quote_amount_args())
                         .arg(
-                            Arg::with_name("by")
-                                .long("by")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("at")
+                                .long("at")
+                                .value_name("PRICE")
                                 .takes_value(true)
-                                .validator(is_valid_signer)
-                                .help("Optional authority for the wrap"),
+                                .validator(is_parsable::<f64>)
+                                .help("Place a limit order at this price"),
                         )
                         .arg(
-                            Arg::with_name("if_source_balance_exceeds")
-                                .long("if-source-balance-exceeds")
+                            Arg::with_name("ask_plus")
+                                .long("ask-plus")
                                 .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_amount)
-                                .help(
-                                    "Exit successfully without wrapping if the \
-                                       source account balance is less than this amount",
-                                ),
+                                .conflicts_with("at")
+                                .validator(is_parsable::<f64>)
+                                .help("Place a limit order at this amount over the current ask"),
                         )
                         .arg(lot_selection_arg())
                         .arg(lot_numbers_arg())
-                )
-                .subcommand(
-                    SubCommand::with_name("unwrap")
-                        .about("Unwrap SOL from wSOL")
                         .arg(
-                            Arg::with_name("address")
-                                .value_name("ADDRESS")
+                            Arg::with_name("pair")
+                                .long("pair")
+                                .value_name("TRADING_PAIR")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Address of the account to unwrap")
+                                .help("Market to place the order in [default: preferred USD pair for the token on the exchange]"),
                         )
                         .arg(
-                            Arg::with_name("amount")
-                                .value_name("AMOUNT")
+                            Arg::with_name("quote")
+                                .long("quote")
+                                .value_name("COIN")
                                 .takes_value(true)
-                                .validator(is_amount_or_all)
-                                .required(true)
-                                .help("The amount to unwrap, in SOL; accepts keyword ALL"),
+                                .conflicts_with("pair")
+                                .help("Quote currency of the market, such as BTC [default: USD]"),
                         )
                         .arg(
-                            Arg::with_name("by")
-                                .long("by")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
                                 .takes_value(true)
-                                .validator(is_valid_signer)
-                                .help("Optional authority for the unwrap"),
-                        )
-                        .arg(lot_selection_arg())
-                        .arg(lot_numbers_arg())
-                )
-                .subcommand(
-                    SubCommand::with_name("lot")
-                        .about("Account lot management")
-                        .setting(AppSettings::SubcommandRequiredElseHelp)
-                        .setting(AppSettings::InferSubcommands)
-                        .subcommand(
-                            SubCommand::with_name("swap")
-                                .about("Swap lots in the local database only")
-                                .arg(
-                                    Arg::with_name("lot_number1")
-                                        .value_name("LOT NUMBER")
-                                        .takes_value(true)
-                                        .required(true)
-                                        .validator(is_parsable::<usize>)
-                                        .help("First lot number"),
-                                )
-                                .arg(
-                                    Arg::with_name("lot_number2")
-                                        .value_name("LOT NUMBER")
-                                        .takes_value(true)
-                                        .required(true)
-                                        .validator(is_parsable::<usize>)
-                                        .help("Second lot number"),
-                                )
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token to sell"),
                         )
-                        .subcommand(
-                            SubCommand::with_name("delete")
-                                .about("Delete a lot from the local database only. \
-                                        Useful if the on-chain state is out of sync with the database")
-                                .arg(
-                                    Arg::with_name("lot_number")
-                                        .value_name("LOT NUMBER")
-                                        .takes_value(true)
-                                        .required(true)
-                                        .validator(is_parsable::<usize>)
-                                        .help("Lot number to delete. Must not be a disposed lot"),
-                                )
-                                .arg(
-                                    Arg::with_name("confirm")
-                                        .long("confirm")
-                                        .takes_value(false)
-                                        .help("Confirm the operation"),
-                                )
+                        .arg(
+                            Arg::with_name("skip_preflight")
+                                .long("skip-preflight")
+                                .takes_value(false)
+                                .help("Skip the local minimum notional and available balance \
+                                       checks before placing the order"),
                         )
-                        .subcommand(
-                            SubCommand::with_name("move")
-                                .about("Move a lot to a new addresses in the local database only. \
-                                        Useful if the on-chain state is out of sync with the database")
-                                .arg(
-                                    Arg::with_name("lot_number")
-                                        .value_name("LOT NUMBER")
-                                        .takes_value(true)
-                                        .required(true)
-                                        .validator(is_parsable::<usize>)
-                                        .help("Lot number to move. Must not be a disposed lot"),
-                                )
-                                .arg(
-                                    Arg::with_name("to_address")
-                                        .value_name("RECIPIENT_ADDRESS")
-                                        .takes_value(true)
-                                        .required(true)
-                                        .validator(is_valid_pubkey)
-                                        .help("Address to receive the lot"),
-                                )
-                        ),
-                ),
-        )
-        .subcommand(
-            SubCommand::with_name("jup")
-                .about("jup.ag")
-                .setting(AppSettings::SubcommandRequiredElseHelp)
-                .setting(AppSettings::InferSubcommands)
-                .subcommand(
-                    SubCommand::with_name("quote")
-                        .about("Get swap quotes")
+                        .arg(override_limits_arg())
                         .arg(
-                            Arg::with_name("from_token")
-                                .value_name("SOURCE TOKEN")
+                            Arg::with_name("unwind")
+                                .long("unwind")
+                                .value_name("NAME")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_token)
-                                .default_value("wSOL")
-                                .help("Source token"),
+                                .help("Place the order within this unwind target, refusing it \
+                                       if the target's orders could then sell more than its \
+                                       amount"),
                         )
                         .arg(
-                            Arg::with_name("to_token")
-                                .value_name("DESTINATION TOKEN")
+                            Arg::with_name("exact")
+                                .long("exact")
+                                .takes_value(false)
+                                .help("Submit the price and amount exactly as given rather than \
+                                       rounding them to the tick and lot size of the pair"),
+                        )
+                        .args(&order_rounding_args())
+                        .arg(
+                            Arg::with_name("expire_after")
+                                .long("expire-after")
+                                .value_name("HOURS")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_token)
-                                .default_value("USDC")
-                                .help("Destination token"),
+                                .validator(is_parsable::<u32>)
+                                .help("Cancel the order if it is still open after this number \
+                                       of hours, on the exchange where supported and otherwise \
+                                       at the first sync after"),
                         )
                         .arg(
-                            Arg::with_name("amount")
+                            Arg::with_name("if_balance_exceeds")
+                                .long("if-balance-exceeds")
                                 .value_name("AMOUNT")
                                 .takes_value(true)
                                 .validator(is_amount)
-                                .required(true)
-                                .default_value("1")
-                                .help("Amount of the source token to swap"),
+                                .help(
+                                    "Exit successfully without placing a sell order if the \
+                                       exchange available balance is less than this amount",
+                                ),
                         )
                         .arg(
-                            Arg::with_name("slippage_bps")
-                                .long("slippage")
-                                .value_name("BPS")
+                            Arg::with_name("if_price_over")
+                                .long("if-price-over")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_parsable::<u64>)
-                                .default_value("100")
-                                .help("Maximum slippage bps"),
+                                .validator(is_parsable::<f64>)
+                                .conflicts_with("at")
+                                .help(
+                                    "Exit successfully without placing a sell order if the \
+                                       order would be placed at a price that is less than \
+                                       or equal to this amount",
+                                ),
                         )
                         .arg(
-                            Arg::with_name("max_quotes")
-                                .short("n")
-                                .value_name("LIMIT")
+                            Arg::with_name("if_price_over_basis")
+                                .long("if-price-over-basis")
+                                .takes_value(false)
+                                .help(
+                                    "Exit successfully without placing a sell order if the \
+                                       order price would be less than the basis",
+                                ),
+                        )
+                        .arg(
+                            Arg::with_name("price_floor")
+                                .long("price-floor")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_parsable::<usize>)
-                                .help("Limit to this number of quotes [default: all quotes]"),
+                                .validator(is_parsable::<f64>)
+                                .conflicts_with("if_price_over")
+                                .help(
+                                    "If the computed price is less than this amount then \
+                                       use this amount instead",
+                                ),
                         ),
                 )
                 .subcommand(
-                    SubCommand::with_name("swap")
-                        .about("Swap tokens")
-                        .arg(
-                            Arg::with_name("address")
-                                .value_name("KEYPAIR")
-                                .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_signer)
-                                .help("Address of the account holding the tokens to swap")
-                        )
-                        .arg(
-                            Arg::with_name("from_token")
-                                .value_name("SOURCE TOKEN")
-                                .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_token)
-                                .help("Source token"),
-                        )
+                    SubCommand::with_name("peg")
+                        .about("Keep an order resting a fixed distance from the top of the book \
+                                until it fills")
                         .arg(
-                            Arg::with_name("to_token")
-                                .value_name("DESTINATION TOKEN")
+                            Arg::with_name("side")
+                                .value_name("SIDE")
                                 .takes_value(true)
                                 .required(true)
-                                .validator(is_valid_token)
-                                .help("Destination token"),
+                                .possible_values(&["buy", "sell"])
+                                .help("Order side"),
                         )
                         .arg(
                             Arg::with_name("amount")
-                                .value_name("SOURCE TOKEN AMOUNT")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_amount_or_all)
+                                .validator(is_amount)
                                 .required(true)
-                                .help("Amount of tokens to swap; accepts ALL keyword"),
+                                .help("The amount to buy or sell, in tokens"),
                         )
                         .arg(
-                            Arg::with_name("slippage_bps")
-                                .long("slippage")
-                                .value_name("BPS")
+                            Arg::with_name("offset_ticks")
+                                .long("offset-ticks")
+                                .value_name("TICKS")
                                 .takes_value(true)
-                                .validator(is_parsable::<u64>)
-                                .default_value("100")
-                                .help("Maximum slippage bps"),
+                                .validator(is_parsable::<u32>)
+                                .help("Distance from the best ask when selling, or the best bid \
+                                       when buying, away from the spread [default: 1]"),
                         )
                         .arg(
-                            Arg::with_name("if_from_balance_exceeds")
-                                .long("if-source-balance-exceeds")
-                                .value_name("AMOUNT")
+                            Arg::with_name("offset_bps")
+                                .long("offset-bps")
+                                .value_name("BPS")
                                 .takes_value(true)
-                                .validator(is_amount)
-                                .help(
-                                    "Exit successfully without placing a swap if the \
-                                       source account balance is less than this amount",
-                                ),
+                                .validator(is_parsable::<f64>)
+                                .conflicts_with("offset_ticks")
+                                .help("Distance from the top of the book in basis points"),
                         )
                         .arg(
-                            Arg::with_name("for_no_less_than")
-                                .long("for-no-less-than")
-                                .value_name("AMOUNT")
+                            Arg::with_name("tolerance")
+                                .long("tolerance")
+                                .value_name("TICKS")
                                 .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .conflicts_with("at")
-                                .help(
-                                    "Exit successfully without swapping if \
-                                       the swap would result in less than \
-                                       this amount of destination tokens",
-                                ),
+                                .validator(is_parsable::<u32>)
+                                .default_value("1")
+                                .help("Move the order only once it is further than this from \
+                                       its target price"),
                         )
                         .arg(
-                            Arg::with_name("max_coingecko_value_percentage_loss")
-                                .long("max-coingecko-value-percentage-loss")
-                                .value_name("PERCENT")
+                            Arg::with_name("price_limit")
+                                .long("price-limit")
+                                .value_name("PRICE")
                                 .takes_value(true)
                                 .validator(is_parsable::<f64>)
-                                .default_value("5")
-                                .help("Reject if the value lost relative to CoinGecko token
-                                      price exceeds this percentage"),
+                                .help("Lowest price to sell at, or highest to buy at. The order \
+                                       rests here rather than chasing the market further"),
                         )
                         .arg(
-                            Arg::with_name("compute_unit_price_micro_lamports")
-                                .long("compute-unit-price")
-                                .value_name("MICROLAMPORTS")
+                            Arg::with_name("interval")
+                                .long("interval")
+                                .value_name("SECONDS")
                                 .takes_value(true)
-                                .validator(is_parsable::<usize>)
-                                .help("Set a compute unit price in micro-lamports to pay \
-                                      a higher transaction fee for higher transaction \
-                                      prioritization"),
+                                .validator(is_parsable::<u64>)
+                                .default_value("10")
+                                .help("Check the order against the book at this interval"),
                         )
                         .arg(lot_selection_arg())
                         .arg(
-                            Arg::with_name("transaction")
-                                .long("transaction")
-                                .value_name("SIGNATURE")
+                            Arg::with_name("pair")
+                                .long("pair")
+                                .value_name("TRADING_PAIR")
                                 .takes_value(true)
-                                .validator(is_parsable::<Signature>)
-                                .help("Existing swap transaction signature that succeeded but \
-                                      due to RPC infrastructure limitations the local database \
-                                      considered it to have failed. Careful!")
+                                .help("Market to place the order in [default: preferred USD pair for the token on the exchange]"),
                         )
-                )
-        )
-        .subcommand(
-            SubCommand::with_name("stake-spreader")
-                .alias("ss")
-                .about("Spread stake across the top-performing validators")
-                .after_help("\
-                    The stake spreader will not explicitly split or merge stake accounts. \
-                    Instead the normal `account split` and `account merge` subcommands should be \
-                    used to shape your stake accounts as desired, at any time. The spreader will \
-                    then aim to delegate those accounts equally over the number of validators \
-                    specified, favouring the more performant validators. \
-                ")
-                .arg(
-                    Arg::with_name("stake_authority")
-                        .long("stake-authority")
-                        .value_name("KEYPAIR")
-                        .takes_value(true)
-                        .required(true)
-                        .validator(is_valid_signer)
-                        .help("Stake authority of the stake accounts to be spread"),
-                )
-                .arg(
-                    Arg::with_name("epoch_completed_percentage")
-                        .long("epoch-completed-percentage")
-                        .value_name("PERCENT")
-                        .takes_value(true)
-                        .validator(is_valid_percentage)
-                        .required(true)
-                        .default_value("90")
-                        .help("Wait until the current epoch is this percent complete before rebalancing stake"),
-                )
-                .arg(
-                    Arg::with_name("epoch_history")
-                        .long("epoch-history")
-                        .value_name("COUNT")
-                        .takes_value(true)
-                        .validator(is_parsable::<usize>)
-                        .required(true)
-                        .default_value("2")
-                        .help("Consider validator performance over the previous COUNT epochs"),
-                )
-                .arg(
-                    Arg::with_name("num_validators")
-                        .long("validators")
-                        .value_name("COUNT")
-                        .takes_value(true)
-                        .validator(is_parsable::<usize>)
-                        .required(true)
-                        .default_value("10")
-                        .help("Number of validators to spread the stake over"),
-                )
-                .arg(
-                    Arg::with_name("include")
-                        .long("include")
-                        .value_name("VOTE ACCOUNT ADDRESS")
-                        .takes_value(true)
-                        .multiple(true)
-                        .validator(is_valid_pubkey)
-                        .help("Always select this this vote account for a delegation"),
-                )
-                .arg(
-                    Arg::with_name("exclude")
-                        .long("exclude")
-                        .value_name("VOTE ACCOUNT ADDRESS")
-                        .takes_value(true)
-                        .multiple(true)
-                        .validator(is_valid_pubkey)
-                        .help("Never select this this vote account for a delegation"),
-                )
-                .arg(
-                    Arg::with_name("danger")
-                        .long("danger")
-                        .takes_value(false)
-                        .help("NOT WELL TESTED YET, MIGHT SCREW UP YOUR DELEGATIONS"),
-                ),
-        )
-        .subcommand(
-            SubCommand::with_name("tulip")
-                .about("tulip.garden")
-                .setting(AppSettings::SubcommandRequiredElseHelp)
-                .setting(AppSettings::InferSubcommands)
-                .subcommand(
-                    SubCommand::with_name("apr")
-                        .about("Display the spot APR for a given collateral token")
                         .arg(
-                            Arg::with_name("collateral_token")
-                                .value_name("COLLATERAL_TOKEN")
+                            Arg::with_name("quote")
+                                .long("quote")
+                                .value_name("COIN")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_token)
-                                .possible_values(&["tuSOL", "tumSOL", "tustSOL", "tuUSDC"])
-                                .help("Collateral token"),
+                                .conflicts_with("pair")
+                                .help("Quote currency of the market, such as BTC [default: USD]"),
                         )
-                )
-                .subcommand(
-                    SubCommand::with_name("deposit")
-                        .about("Deposit liquidity")
                         .arg(
-                            Arg::with_name("collateral_token")
-                                .value_name("COLLATERAL_TOKEN")
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_token)
-                                .possible_values(&["tuSOL", "tumSOL", "tustSOL", "tuUSDC"])
-                                .help("Collateral token"),
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token to buy or sell"),
                         )
                         .arg(
-                            Arg::with_name("amount")
-                                .value_name("LIQUIDITY_AMOUNT")
+                            Arg::with_name("unwind")
+                                .long("unwind")
+                                .value_name("NAME")
                                 .takes_value(true)
-                                .validator(is_amount_or_all)
-                                .required(true)
-                                .help("Amount of liquidity tokens to deposit; accepts keywords ALL"),
+                                .help("Sell within this unwind target, each order no larger \
+                                       than the target has left once its other orders fill"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("unwind")
+                        .about("Sell a declared amount through any number of orders, which \
+                                together never sell more than it")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .subcommand(
+                            SubCommand::with_name("create")
+                                .about("Declare an unwind target")
+                                .arg(
+                                    Arg::with_name("name")
+                                        .value_name("NAME")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Name of the target, for the --unwind argument \
+                                               of `sell` and `peg`"),
+                                )
+                                .arg(
+                                    Arg::with_name("amount")
+                                        .value_name("AMOUNT")
+                                        .takes_value(true)
+                                        .validator(is_amount)
+                                        .required(true)
+                                        .help("Total amount to sell, in tokens"),
+                                )
+                                .arg(
+                                    Arg::with_name("token")
+                                        .long("token")
+                                        .value_name("SOL or SPL Token")
+                                        .takes_value(true)
+                                        .validator(is_valid_token_or_sol)
+                                        .default_value("SOL")
+                                        .help("Token to sell"),
+                                ),
                         )
-                        .arg(
-                            Arg::with_name("liquidity_token")
-                                .value_name("LIQUIDITY_TOKEN")
-                                .takes_value(true)
-                                .validator(is_valid_token)
-                                .help("Override the liquidity token for the given collateral token. \
-                                       Typically used to specify `wSOL` instead of `SOL` \
-                                       [default: deduced from the collateral token]"),
+                        .subcommand(
+                            SubCommand::with_name("status")
+                                .about("Show what unwind targets have sold and have left, \
+                                        including the fills of their open orders")
+                                .arg(
+                                    Arg::with_name("name")
+                                        .value_name("NAME")
+                                        .takes_value(true)
+                                        .help("Unwind target to show [default: all]"),
+                                ),
                         )
+                        .subcommand(
+                            SubCommand::with_name("remove")
+                                .about("Forget an unwind target. Orders placed within it stay \
+                                        open")
+                                .arg(
+                                    Arg::with_name("name")
+                                        .value_name("NAME")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Unwind target to remove"),
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("market-make")
+                        .about("Quote both sides of a market with post-only orders around the \
+                                mid price, within an inventory limit")
                         .arg(
-                            Arg::with_name("from")
-                                .long("from")
-                                .value_name("FROM_ADDRESS")
+                            Arg::with_name("size")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_signer)
-                                .help("Source account of funds"),
+                                .validator(is_amount)
+                                .required_unless("report")
+                                .help("Size of each quote, in tokens"),
                         )
-                        .arg(lot_selection_arg())
                         .arg(
-                            Arg::with_name("transaction")
-                                .long("transaction")
-                                .value_name("SIGNATURE")
+                            Arg::with_name("offset_ticks")
+                                .long("offset-ticks")
+                                .value_name("TICKS")
                                 .takes_value(true)
-                                .validator(is_parsable::<Signature>)
-                                .help("Existing deposit transaction signature that succeeded but \
-                                      due to RPC infrastructure limitations the local database \
-                                      considered it to have failed. Careful!")
+                                .validator(is_parsable::<u32>)
+                                .help("Distance of each quote from the mid price. Quotes never \
+                                       cross the best bid or ask [default: 1]"),
                         )
-                )
-                .subcommand(
-                    SubCommand::with_name("withdraw")
-                        .about("Withdraw liquidity")
                         .arg(
-                            Arg::with_name("collateral_token")
-                                .value_name("COLLATERAL_TOKEN")
+                            Arg::with_name("offset_bps")
+                                .long("offset-bps")
+                                .value_name("BPS")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_token)
-                                .possible_values(&["tuSOL", "tumSOL", "tustSOL", "tuUSDC"])
-                                .help("Collateral token"),
+                                .validator(is_parsable::<f64>)
+                                .conflicts_with("offset_ticks")
+                                .help("Distance of each quote from the mid price in basis points"),
                         )
                         .arg(
-                            Arg::with_name("to")
-                                .value_name("RECIPIENT_ADDRESS")
+                            Arg::with_name("tolerance")
+                                .long("tolerance")
+                                .value_name("TICKS")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_signer)
-                                .help("Address to receive the withdrawal of funds"),
+                                .validator(is_parsable::<u32>)
+                                .default_value("1")
+                                .help("Move a quote only once it is further than this from its \
+                                       target price"),
                         )
                         .arg(
-                            Arg::with_name("amount")
-                                .value_name("LIQUIDITY_AMOUNT")
+                            Arg::with_name("max_inventory")
+                                .long("max-inventory")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_amount_or_all)
-                                .required(true)
-                                .help("Amount of liquidity tokens to withdraw; accepts keyword ALL"),
+                                .validator(is_amount)
+                                .required_unless("report")
+                                .help("Stop quoting the side that would take the net position \
+                                       of the session beyond this many tokens, long or short"),
                         )
                         .arg(
-                            Arg::with_name("liquidity_token")
-                                .value_name("LIQUIDITY_TOKEN")
+                            Arg::with_name("max_loss")
+                                .long("max-loss")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_valid_token)
-                                .help("Override the liquidity token for the given collateral token. \
-                                       Typically used to specify `wSOL` instead of `SOL` \
-                                       [default: deduced from the collateral token]"),
+                                .validator(is_amount)
+                                .required_unless("report")
+                                .help("Halt the session once its loss at the mid price, after \
+                                       fees, exceeds this amount of the quote currency"),
+                        )
+                        .arg(
+                            Arg::with_name("interval")
+                                .long("interval")
+                                .value_name("SECONDS")
+                                .takes_value(true)
+                                .validator(is_parsable::<u64>)
+                                .default_value("10")
+                                .help("Check the quotes against the book at this interval"),
                         )
-                        .arg(lot_selection_arg())
-                )
-        );
-
-    for exchange in &exchanges {
-        app = app.subcommand(
-            SubCommand::with_name(exchange)
-                .about("Exchange interactions")
-                .setting(AppSettings::SubcommandRequiredElseHelp)
-                .setting(AppSettings::InferSubcommands)
-                .arg(
-                    Arg::with_name("exchange_account")
-                        .long("account")
-                        .value_name("ACCOUNT_NAME")
-                        .takes_value(true)
-                        .global(true)
-                        .help("User-defined name of the exchange account to use \
-                              (extra help: specify this option BEFORE the subcomand name, \
-                               not after as indicated by --help) [default: '']"),
-                )
-                .subcommand(
-                    SubCommand::with_name("balance")
-                        .about("Get exchange balance")
                         .arg(
-                            Arg::with_name("available_only")
-                                .long("available")
+                            Arg::with_name("report")
+                                .long("report")
                                 .takes_value(false)
-                                .help("Only display available balance")
+                                .help("Display the fills and profit of the current session and \
+                                       exit"),
                         )
                         .arg(
-                            Arg::with_name("total_only")
-                                .long("total")
+                            Arg::with_name("new_session")
+                                .long("new-session")
                                 .takes_value(false)
-                                .conflicts_with("available_only")
-                                .help("Only display total balance")
+                                .conflicts_with("report")
+                                .help("Start a new session, discarding the fills and any halt of \
+                                       the previous one"),
                         )
+                        .arg(lot_selection_arg())
                         .arg(
-                            Arg::with_name("integer")
-                                .long("integer")
-                                .takes_value(false)
-                                .help("Output integer values with no currency symbols")
+                            Arg::with_name("pair")
+                                .long("pair")
+                                .value_name("TRADING_PAIR")
+                                .takes_value(true)
+                                .help("Market to quote [default: preferred USD pair for the token on the exchange]"),
+                        )
+                        .arg(
+                            Arg::with_name("quote")
+                                .long("quote")
+                                .value_name("COIN")
+                                .takes_value(true)
+                                .conflicts_with("pair")
+                                .help("Quote currency of the market, such as BTC [default: USD]"),
                         )
-                )
-                .subcommand(
-                    SubCommand::with_name("address")
-                        .about("Show deposit address")
                         .arg(
                             Arg::with_name("token")
+                                .long("token")
                                 .value_name("SOL or SPL Token")
                                 .takes_value(true)
-                                .required(true)
                                 .validator(is_valid_token_or_sol)
                                 .default_value("SOL")
-                                .help("Token type"),
-                        )
+                                .help("Token to quote"),
+                        ),
                 )
                 .subcommand(
-                    SubCommand::with_name("market")
-                        .about("Display market info for a given trading pair")
+                    SubCommand::with_name("pending-deposits")
+                        .about("Display deposits sent on-chain that the exchange has not \
+                                credited yet")
                         .arg(
-                            Arg::with_name("pair")
-                                .value_name("TRADING_PAIR")
+                            Arg::with_name("quiet")
+                                .long("quiet")
+                                .takes_value(false)
+                                .help(
+                                    "Disable output and exit with a non-zero status code \
+                                        if any deposits are pending"
+                                ),
+                        )
+                        .arg(
+                            Arg::with_name("add")
+                                .long("add")
+                                .value_name("SIGNATURE")
                                 .takes_value(true)
-                                .help("[default: preferred SOL/USD pair for the exchange]")
+                                .validator(is_parsable::<Signature>)
+                                .conflicts_with("remove")
+                                .help("Track a deposit sent outside of sys until the exchange \
+                                       credits it"),
                         )
                         .arg(
-                            Arg::with_name("ask")
-                                .long("ask")
-                                .takes_value(false)
-                                .help("Only display the current asking price")
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .default_value("SOL")
+                                .validator(is_valid_token_or_sol)
+                                .help("Token deposited by the --add transaction"),
                         )
                         .arg(
-                            Arg::with_name("weighted_24h_average_price")
-                                .long("weighted-24h-average-price")
-                                .takes_value(false)
-                                .conflicts_with("ask")
-                                .help("Only display the weighted average price for the previous 24 hours"),
+                            Arg::with_name("remove")
+                                .long("remove")
+                                .value_name("SIGNATURE")
+                                .takes_value(true)
+                                .validator(is_parsable::<Signature>)
+                                .help("Stop tracking a deposit added with --add"),
                         )
                         .arg(
-                            Arg::with_name("hourly")
-                                .long("hourly")
+                            Arg::with_name("credit_window")
+                                .long("credit-window")
+                                .value_name("HOURS")
+                                .takes_value(true)
+                                .validator(is_parsable::<u32>)
+                                .help("Warn about deposits the exchange has not credited this \
+                                       many hours after they were sent, instead of the \
+                                       exchange's default"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("pending-withdrawals")
+                        .about("Display pending withdrawals")
+                        .arg(
+                            Arg::with_name("quiet")
+                                .long("quiet")
                                 .takes_value(false)
-                                .conflicts_with_all(&["ask", "weighted_24h_average_price"])
-                                .help("Display hourly price information for the previous 24 hours"),
+                                .help(
+                                    "Disable output and exit with a non-zero status code \
+                                        if any withdrawals are pending"
+                                ),
                         ),
                 )
                 .subcommand(
-                    SubCommand::with_name("api")
-                        .about("API Management")
+                    SubCommand::with_name("fiat-withdraw")
+                        .about("Withdraw fiat currency to a registered bank account")
                         .setting(AppSettings::SubcommandRequiredElseHelp)
-                        .setting(AppSettings::InferSubcommands)
                         .subcommand(
-                            SubCommand::with_name("set")
-                                .about("Set API key")
-                                .arg(Arg::with_name("api_key").required(true).takes_value(true))
-                                .arg(Arg::with_name("secret").required(true).takes_value(true))
-                                .arg(Arg::with_name("subaccount").takes_value(true)),
+                            SubCommand::with_name("methods")
+                                .about("List the bank accounts registered for withdrawals")
+                                .arg(
+                                    Arg::with_name("currency")
+                                        .value_name("CURRENCY")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Fiat currency, such as USD or EUR"),
+                                ),
                         )
-                        .subcommand(SubCommand::with_name("show").about("Show API key"))
-                        .subcommand(SubCommand::with_name("clear").about("Clear API key")),
+                        .subcommand(
+                            SubCommand::with_name("request")
+                                .about("Request a withdrawal")
+                                .arg(
+                                    Arg::with_name("amount")
+                                        .value_name("AMOUNT")
+                                        .takes_value(true)
+                                        .validator(is_amount_or_all)
+                                        .required(true)
+                                        .help("The amount to withdraw; accepts keyword ALL"),
+                                )
+                                .arg(
+                                    Arg::with_name("currency")
+                                        .value_name("CURRENCY")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Fiat currency, such as USD or EUR"),
+                                )
+                                .arg(
+                                    Arg::with_name("method")
+                                        .long("method")
+                                        .value_name("METHOD_ID")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Bank account to withdraw to, as listed by the \
+                                               `methods` subcommand"),
+                                )
+                                .arg(override_limits_arg()),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("status")
+                                .about("Refresh and display fiat withdrawals")
+                                .arg(
+                                    Arg::with_name("all")
+                                        .long("all")
+                                        .takes_value(false)
+                                        .help("Include settled, cancelled and failed withdrawals"),
+                                ),
+                        ),
                 )
                 .subcommand(
-                    SubCommand::with_name("deposit")
-                        .about("Deposit SOL or SPL Tokens")
+                    SubCommand::with_name("deposits")
+                        .about("Display recent deposits reported by the exchange")
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output as JSON"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("reconcile-deposits")
+                        .about("Match deposits sent on-chain against those credited by the \
+                                exchange, reporting what changed since the last run")
+                        .arg(
+                            Arg::with_name("signature")
+                                .long("signature")
+                                .value_name("SIGNATURE")
+                                .takes_value(true)
+                                .multiple(true)
+                                .validator(is_parsable::<Signature>)
+                                .help("Also reconcile this deposit transaction, such as one not \
+                                       sent by sys"),
+                        )
                         .arg(
                             Arg::with_name("token")
+                                .long("token")
                                 .value_name("SOL or SPL Token")
                                 .takes_value(true)
-                                .required(true)
+                                .default_value("SOL")
                                 .validator(is_valid_token_or_sol)
-                                .help("Token type"),
+                                .help("Token deposited by the --signature transactions"),
                         )
                         .arg(
-                            Arg::with_name("amount")
-                                .value_name("AMOUNT")
+                            Arg::with_name("max_age")
+                                .long("max-age")
+                                .value_name("HOURS")
                                 .takes_value(true)
-                                .validator(is_amount_or_all_or_half)
-                                .required(true)
-                                .help("Amount to deposit; accepts keywords ALL and HALF"),
+                                .default_value("24")
+                                .validator(is_parsable::<u32>)
+                                .help("Warn about deposits sent longer ago than this that the \
+                                       exchange has not credited"),
                         )
-                        .arg(lot_selection_arg())
-                        .arg(lot_numbers_arg())
                         .arg(
-                            Arg::with_name("from")
-                                .long("from")
-                                .value_name("FROM_ADDRESS")
-                                .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Source account of funds"),
+                            Arg::with_name("all")
+                                .long("all")
+                                .takes_value(false)
+                                .help("Report every deposit, not only those that changed"),
                         )
                         .arg(
-                            Arg::with_name("by")
-                                .long("by")
-                                .value_name("KEYPAIR")
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output as JSON"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("withdrawals")
+                        .about("Display recent withdrawals reported by the exchange")
+                        .arg(
+                            Arg::with_name("start_date")
+                                .long("start")
+                                .value_name("YY/MM/DD")
                                 .takes_value(true)
-                                .validator(is_valid_signer)
-                                .help("Optional authority of the FROM_ADDRESS"),
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Display withdrawals requested on or after this date \
+                                       instead of only recent withdrawals")
                         )
                         .arg(
-                            Arg::with_name("if_source_balance_exceeds")
-                                .long("if-source-balance-exceeds")
-                                .value_name("AMOUNT")
+                            Arg::with_name("end_date")
+                                .long("end")
+                                .value_name("YY/MM/DD")
                                 .takes_value(true)
-                                .validator(is_amount)
-                                .help(
-                                    "Exit successfully without depositing if the \
-                                       source account balance is less than this amount",
-                                ),
+                                .default_value(&default_when)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Display withdrawals requested on or before this date")
                         )
                         .arg(
-                            Arg::with_name("if_exchange_balance_less_than")
-                                .long("if-exchange-balance-less-than")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_amount)
-                                .help(
-                                    "Exit successfully without depositing if the \
-                                        exchange SOL balance is less than this amount",
-                                ),
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output as JSON"),
                         ),
                 )
                 .subcommand(
-                    SubCommand::with_name("withdraw")
-                        .about("Withdraw SOL or SPL Tokens")
+                    SubCommand::with_name("import-orders")
+                        .about("Import orders placed outside of sys, such as through the \
+                                exchange's website, into the journal. Orders that disagree with \
+                                the journal are listed rather than imported")
                         .arg(
-                            Arg::with_name("token")
-                                .value_name("SOL or SPL Token")
+                            Arg::with_name("start_date")
+                                .long("start")
+                                .value_name("YY/MM/DD")
                                 .takes_value(true)
                                 .required(true)
-                                .validator(is_valid_token_or_sol)
-                                .help("Token type"),
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Import orders created on or after this date"),
                         )
                         .arg(
-                            Arg::with_name("to")
-                                .value_name("RECIPIENT_ADDRESS")
+                            Arg::with_name("end_date")
+                                .long("end")
+                                .value_name("YY/MM/DD")
                                 .takes_value(true)
-                                .required(true)
-                                .validator(is_valid_pubkey)
-                                .help("Address to receive the withdrawal of funds"),
+                                .default_value(&default_when)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Import orders created on or before this date"),
                         )
                         .arg(
-                            Arg::with_name("amount")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_amount)
-                                .required(true)
-                                .help("The amount to withdraw; accepts keyword ALL"),
+                            Arg::with_name("dry_run")
+                                .long("dry-run")
+                                .takes_value(false)
+                                .help("Only display the orders that would be imported"),
                         )
                         .arg(lot_selection_arg())
-                        .arg(lot_numbers_arg())
                         .arg(
-                            Arg::with_name("code")
-                                .long("code")
-                                .value_name("CODE")
+                            Arg::with_name("pair")
+                                .long("pair")
+                                .value_name("TRADING_PAIR")
+                                .takes_value(true)
+                                .help("Market to import orders from [default: preferred USD pair for the token on the exchange]"),
+                        )
+                        .arg(
+                            Arg::with_name("quote")
+                                .long("quote")
+                                .value_name("COIN")
+                                .takes_value(true)
+                                .conflicts_with("pair")
+                                .help("Quote currency of the market, such as BTC [default: USD]"),
+                        )
+                        .arg(
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
+                                .takes_value(true)
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token bought or sold"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("order-status")
+                        .about("Display the status of an open order")
+                        .arg(
+                            Arg::with_name("order_id")
+                                .value_name("ORDER_ID")
                                 .takes_value(true)
-                                .help("2FA withdrawal code"),
+                                .required(true)
+                                .help("The order id"),
                         )
+                        .arg(
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output as JSON"),
+                        ),
                 )
                 .subcommand(
-                    SubCommand::with_name("cancel")
-                        .about("Cancel orders")
+                    SubCommand::with_name("lend")
+                        .about("Make a lending offer")
                         .arg(
-                            Arg::with_name("order_id")
-                                .value_name("ORDER ID")
+                            Arg::with_name("coin")
+                                .value_name("COIN")
                                 .takes_value(true)
-                                .multiple(true)
-                                .help("The order id to cancel"),
+                                .required(true)
+                                .help("The coin to lend"),
                         )
                         .arg(
-                            Arg::with_name("age")
-                                .long("age")
-                                .value_name("HOURS")
+                            Arg::with_name("amount")
+                                .value_name("AMOUNT")
                                 .takes_value(true)
-                                .validator(is_parsable::<u32>)
-                                .conflicts_with("order_id")
-                                .help("Cancel orders older than this number of hours"),
+                                .validator(is_amount_or_all)
+                                .help("The amount to lend; accepts keyword ALL"),
                         )
                         .arg(
-                            Arg::with_name("side")
-                                .long("side")
-                                .required(true)
-                                .default_value("both")
-                                .possible_values(&["both", "buy", "sell"])
-                                .help("Restrict to only buy or sell orders")
+                            Arg::with_name("available")
+                                .short("a")
+                                .long("available")
+                                .requires("amount")
+                                .takes_value(false)
+                                .help("Invert AMOUNT to mean, the amount to keep available and lend the rest"),
                         )
                 )
                 .subcommand(
-                    SubCommand::with_name("buy")
-                        .about("Place an order to buy SOL")
+                    SubCommand::with_name("lending-rates")
+                        .about("Display lending info for every coin with a lendable balance or \
+                                active offer, best estimated rate first")
                         .arg(
-                            Arg::with_name("amount")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_amount_or_all)
-                                .required(true)
-                                .help("The amount to buy, in SOL; accepts keyword ALL"),
+                            Arg::with_name("json")
+                                .long("json")
+                                .takes_value(false)
+                                .help("Output the lending info as JSON"),
                         )
+                )
+                .subcommand(
+                    SubCommand::with_name("lending-performance")
+                        .about("Compare the lending rates estimated when offers were made \
+                                against the interest they earned, by coin and week")
                         .arg(
-                            Arg::with_name("at")
-                                .long("at")
-                                .value_name("PRICE")
+                            Arg::with_name("weeks")
+                                .long("weeks")
+                                .value_name("COUNT")
                                 .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .help("Place a limit order at this price"),
+                                .default_value("8")
+                                .validator(is_parsable::<u32>)
+                                .help("Number of weeks to compare, ending with the current one"),
                         )
                         .arg(
-                            Arg::with_name("bid_minus")
-                                .long("bid-minus")
-                                .value_name("AMOUNT")
+                            Arg::with_name("shortfall")
+                                .long("shortfall")
+                                .value_name("PERCENT")
                                 .takes_value(true)
-                                .conflicts_with("at")
+                                .default_value("25")
                                 .validator(is_parsable::<f64>)
-                                .help("Place a limit order at this amount under the current bid"),
+                                .help("Flag weeks where the realized rate was more than this \
+                                       percentage under the estimated rate"),
                         )
-                        .arg(
-                            Arg::with_name("pair")
-                                .long("pair")
-                                .value_name("TRADING_PAIR")
-                                .takes_value(true)
-                                .help("Market to place the order in [default: preferred SOL/USD pair for the exchange]"),
+                )
+                .subcommand(
+                    SubCommand::with_name("lending-history")
+                        .about("Display lending history")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .setting(AppSettings::InferSubcommands)
+                        .subcommand(
+                            SubCommand::with_name("range")
+                                .about("Display lending history for the given date range")
+                                .arg(
+                                    Arg::with_name("start_date")
+                                        .value_name("YY/MM/DD")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(|value| naivedate_of(&value).map(|_| ()))
+                                        .help("Start date, inclusive")
+                                )
+                                .arg(
+                                    Arg::with_name("end_date")
+                                        .value_name("YY/MM/DD")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .default_value(&default_when)
+                                        .validator(|value| naivedate_of(&value).map(|_| ()))
+                                        .help("End date, inclusive")
+                                )
+                        )
+                        .subcommand(
+                            SubCommand::with_name("previous")
+                                .about("Display lending history for previous days")
+                                .arg(
+                                    Arg::with_name("days")
+                                        .value_name("DAYS")
+                                        .default_value("1")
+                                        .validator(is_parsable::<usize>)
+                                        .help("Number of days, including today")
+                                )
                         )
-                        .arg(
-                            Arg::with_name("if_balance_exceeds")
-                                .long("if-balance-exceeds")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_amount)
-                                .help(
-                                    "Exit successfully without placing a buy order if the \
-                                       exchange available balance is less than this amount",
-                                ),
-                        ),
                 )
                 .subcommand(
-                    SubCommand::with_name("sell")
-                        .about("Place an order to sell SOL")
+                    SubCommand::with_name("subaccounts")
+                        .about("Manage subaccounts, such as Coinbase portfolios")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .setting(AppSettings::InferSubcommands)
+                        .subcommand(
+                            SubCommand::with_name("list")
+                                .about("List subaccounts")
+                                .arg(
+                                    Arg::with_name("json")
+                                        .long("json")
+                                        .takes_value(false)
+                                        .help("Output as JSON"),
+                                )
+                        )
+                        .subcommand(
+                            SubCommand::with_name("transfer")
+                                .about("Move funds between subaccounts")
+                                .arg(
+                                    Arg::with_name("amount")
+                                        .value_name("AMOUNT")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_parsable::<f64>)
+                                        .help("The amount to move"),
+                                )
+                                .arg(
+                                    Arg::with_name("coin")
+                                        .value_name("COIN")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("The coin to move"),
+                                )
+                                .arg(
+                                    Arg::with_name("from")
+                                        .long("from")
+                                        .value_name("SUBACCOUNT")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Source subaccount name or id. \
+                                               On Coinbase \"retail\" is the retail wallets"),
+                                )
+                                .arg(
+                                    Arg::with_name("to")
+                                        .long("to")
+                                        .value_name("SUBACCOUNT")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Destination subaccount name or id. \
+                                               On Coinbase \"retail\" is the retail wallets"),
+                                )
+                        )
+                )
+                .subcommand(
+                    SubCommand::with_name("fees")
+                        .about("Display the trading fee rates for this account")
+                )
+                .subcommand(proceeds_subcommand(
+                    "Estimate the net proceeds of a sale after trading and withdrawal fees",
+                ))
+                .subcommand(
+                    SubCommand::with_name("quote")
+                        .about("Request a firm quote for a large order and compare it against \
+                                executing through the order book")
                         .arg(
                             Arg::with_name("amount")
                                 .value_name("AMOUNT")
                                 .takes_value(true)
                                 .validator(is_amount)
                                 .required(true)
-                                .help("The amount to sell, in SOL"),
-                        )
-                        .arg(
-                            Arg::with_name("at")
-                                .long("at")
-                                .value_name("PRICE")
-                                .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .help("Place a limit order at this price"),
+                                .help("The amount to trade, in tokens"),
                         )
                         .arg(
-                            Arg::with_name("ask_plus")
-                                .long("ask-plus")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .conflicts_with("at")
-                                .validator(is_parsable::<f64>)
-                                .help("Place a limit order at this amount over the current ask"),
+                            Arg::with_name("buy")
+                                .long("buy")
+                                .takes_value(false)
+                                .help("Quote buying rather than selling"),
                         )
-                        .arg(lot_selection_arg())
-                        .arg(lot_numbers_arg())
                         .arg(
                             Arg::with_name("pair")
                                 .long("pair")
                                 .value_name("TRADING_PAIR")
                                 .takes_value(true)
-                                .help("Market to place the order in [default: preferred SOL/USD pair for the exchange]"),
+                                .help("Market to quote [default: preferred USD pair for the token on the exchange]"),
                         )
                         .arg(
-                            Arg::with_name("if_balance_exceeds")
-                                .long("if-balance-exceeds")
-                                .value_name("AMOUNT")
+                            Arg::with_name("quote")
+                                .long("quote")
+                                .value_name("COIN")
                                 .takes_value(true)
-                                .validator(is_amount)
-                                .help(
-                                    "Exit successfully without placing a sell order if the \
-                                       exchange available balance is less than this amount",
-                                ),
+                                .conflicts_with("pair")
+                                .help("Quote currency of the market, such as BTC [default: USD]"),
                         )
                         .arg(
-                            Arg::with_name("if_price_over")
-                                .long("if-price-over")
-                                .value_name("AMOUNT")
+                            Arg::with_name("token")
+                                .long("token")
+                                .value_name("SOL or SPL Token")
                                 .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .conflicts_with("at")
-                                .help(
-                                    "Exit successfully without placing a sell order if the \
-                                       order would be placed at a price that is less than \
-                                       or equal to this amount",
-                                ),
+                                .validator(is_valid_token_or_sol)
+                                .default_value("SOL")
+                                .help("Token to trade"),
                         )
+                )
+                .subcommand(
+                    SubCommand::with_name("ledger")
+                        .about("Export the account ledger as CSV")
                         .arg(
-                            Arg::with_name("if_price_over_basis")
-                                .long("if-price-over-basis")
-                                .takes_value(false)
-                                .help(
-                                    "Exit successfully without placing a sell order if the \
-                                       order price would be less than the basis",
-                                ),
+                            Arg::with_name("start_date")
+                                .value_name("YY/MM/DD")
+                                .takes_value(true)
+                                .required(true)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("Start date, inclusive")
                         )
                         .arg(
-                            Arg::with_name("price_floor")
-                                .long("price-floor")
-                                .value_name("AMOUNT")
+                            Arg::with_name("end_date")
+                                .value_name("YY/MM/DD")
                                 .takes_value(true)
-                                .validator(is_parsable::<f64>)
-                                .conflicts_with("if_price_over")
-                                .help(
-                                    "If the computed price is less than this amount then \
-                                       use this amount instead",
-                                ),
-                        ),
-                )
-                .subcommand(
-                    SubCommand::with_name("pending-deposits")
-                        .about("Display pending deposits")
+                                .required(true)
+                                .default_value(&default_when)
+                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                .help("End date, inclusive")
+                        )
                         .arg(
-                            Arg::with_name("quiet")
-                                .long("quiet")
+                            Arg::with_name("json")
+                                .long("json")
                                 .takes_value(false)
-                                .help(
-                                    "Disable output and exit with a non-zero status code \
-                                        if any deposits are pending"
-                                ),
-                        ),
-                )
-                .subcommand(
-                    SubCommand::with_name("pending-withdrawals")
-                        .about("Display pending withdrawals")
+                                .help("Output as JSON instead of CSV"),
+                        )
                         .arg(
-                            Arg::with_name("quiet")
-                                .long("quiet")
-                                .takes_value(false)
-                                .help(
-                                    "Disable output and exit with a non-zero status code \
-                                        if any withdrawals are pending"
-                                ),
-                        ),
+                            Arg::with_name("output")
+                                .long("output")
+                                .value_name("FILE")
+                                .takes_value(true)
+                                .help("Write the export to this file instead of stdout. An \
+                                       interrupted export resumes where it left off when rerun \
+                                       with the same arguments"),
+                        )
                 )
                 .subcommand(
-                    SubCommand::with_name("lend")
-                        .about("Make a lending offer")
-                        .arg(
-                            Arg::with_name("coin")
-                                .value_name("COIN")
-                                .takes_value(true)
-                                .required(true)
-                                .help("The coin to lend"),
+                    SubCommand::with_name("stake")
+                        .about("Manage on-exchange staking")
+                        .setting(AppSettings::SubcommandRequiredElseHelp)
+                        .setting(AppSettings::InferSubcommands)
+                        .subcommand(
+                            SubCommand::with_name("info")
+                                .about("Display staking strategies and current allocations")
+                                .arg(staking_coin_arg())
+                                .arg(
+                                    Arg::with_name("json")
+                                        .long("json")
+                                        .takes_value(false)
+                                        .help("Output as JSON"),
+                                )
                         )
-                        .arg(
-                            Arg::with_name("amount")
-                                .value_name("AMOUNT")
-                                .takes_value(true)
-                                .validator(is_amount_or_all)
-                                .help("The amount to lend; accepts keyword ALL"),
+                        .subcommand(
+                            SubCommand::with_name("allocate")
+                                .about("Stake an amount")
+                                .arg(
+                                    Arg::with_name("amount")
+                                        .value_name("AMOUNT")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_parsable::<f64>)
+                                        .help("The amount to stake"),
+                                )
+                                .arg(staking_coin_arg())
+                                .arg(staking_wait_arg())
+                                .arg(staking_timeout_arg())
                         )
-                        .arg(
-                            Arg::with_name("available")
-                                .short("a")
-                                .long("available")
-                                .requires("amount")
-                                .takes_value(false)
-                                .help("Invert AMOUNT to mean, the amount to keep available and lend the rest"),
+                        .subcommand(
+                            SubCommand::with_name("deallocate")
+                                .about("Request to unstake an amount")
+                                .arg(
+                                    Arg::with_name("amount")
+                                        .value_name("AMOUNT")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .validator(is_parsable::<f64>)
+                                        .help("The amount to unstake"),
+                                )
+                                .arg(staking_coin_arg())
+                                .arg(staking_wait_arg())
+                                .arg(staking_timeout_arg())
+                        )
+                        .subcommand(
+                            SubCommand::with_name("rewards")
+                                .about("Display staking rewards")
+                                .setting(AppSettings::SubcommandRequiredElseHelp)
+                                .setting(AppSettings::InferSubcommands)
+                                .arg(staking_coin_arg().global(true))
+                                .arg(
+                                    Arg::with_name("by_month")
+                                        .long("by-month")
+                                        .takes_value(false)
+                                        .global(true)
+                                        .help("Total the rewards by month instead of by day"),
+                                )
+                                .subcommand(
+                                    SubCommand::with_name("range")
+                                        .about("Display staking rewards for the given date range")
+                                        .arg(
+                                            Arg::with_name("start_date")
+                                                .value_name("YY/MM/DD")
+                                                .takes_value(true)
+                                                .required(true)
+                                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                                .help("Start date, inclusive")
+                                        )
+                                        .arg(
+                                            Arg::with_name("end_date")
+                                                .value_name("YY/MM/DD")
+                                                .takes_value(true)
+                                                .required(true)
+                                                .default_value(&default_when)
+                                                .validator(|value| naivedate_of(&value).map(|_| ()))
+                                                .help("End date, inclusive")
+                                        )
+                                )
+                                .subcommand(
+                                    SubCommand::with_name("previous")
+                                        .about("Display staking rewards for previous days")
+                                        .arg(
+                                            Arg::with_name("days")
+                                                .value_name("DAYS")
+                                                .default_value("30")
+                                                .validator(is_parsable::<usize>)
+                                                .help("Number of days, including today")
+                                        )
+                                )
                         )
                 )
+                .subcommand(SubCommand::with_name("sync").about("Synchronize exchange"))
                 .subcommand(
-                    SubCommand::with_name("lending-history")
-                        .about("Display lending history")
+                    SubCommand::with_name("paper")
+                        .about("Paper trading against live market data")
                         .setting(AppSettings::SubcommandRequiredElseHelp)
                         .setting(AppSettings::InferSubcommands)
                         .subcommand(
-                            SubCommand::with_name("range")
-                                .about("Display lending history for the given date range")
+                            SubCommand::with_name("fund")
+                                .about("Simulate a deposit into the paper account")
                                 .arg(
-                                    Arg::with_name("start_date")
-                                        .value_name("YY/MM/DD")
+                                    Arg::with_name("coin")
+                                        .value_name("COIN")
                                         .takes_value(true)
                                         .required(true)
-                                        .validator(|value| naivedate_of(&value).map(|_| ()))
-                                        .help("Start date, inclusive")
+                                        .help("Coin to deposit, such as SOL or USD"),
                                 )
                                 .arg(
-                                    Arg::with_name("end_date")
-                                        .value_name("YY/MM/DD")
+                                    Arg::with_name("amount")
+                                        .value_name("AMOUNT")
                                         .takes_value(true)
+                                        .validator(is_amount)
                                         .required(true)
-                                        .default_value(&default_when)
-                                        .validator(|value| naivedate_of(&value).map(|_| ()))
-                                        .help("End date, inclusive")
-                                )
+                                        .help("The amount to deposit"),
+                                ),
                         )
                         .subcommand(
-                            SubCommand::with_name("previous")
-                                .about("Display lending history for previous days")
+                            SubCommand::with_name("withdraw")
+                                .about("Simulate a withdrawal out of the paper account")
                                 .arg(
-                                    Arg::with_name("days")
-                                        .value_name("DAYS")
-                                        .default_value("1")
-                                        .validator(is_parsable::<usize>)
-                                        .help("Number of days, including today")
+                                    Arg::with_name("coin")
+                                        .value_name("COIN")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("Coin to withdraw, such as SOL or USD"),
                                 )
+                                .arg(
+                                    Arg::with_name("amount")
+                                        .value_name("AMOUNT")
+                                        .takes_value(true)
+                                        .validator(is_amount)
+                                        .required(true)
+                                        .help("The amount to withdraw"),
+                                ),
                         )
-                )
-                .subcommand(SubCommand::with_name("sync").about("Synchronize exchange")),
+                        .subcommand(paper_order_subcommand("buy", "Place a paper buy order"))
+                        .subcommand(paper_order_subcommand("sell", "Place a paper sell order"))
+                        .subcommand(
+                            SubCommand::with_name("cancel")
+                                .about("Cancel an open paper order")
+                                .arg(
+                                    Arg::with_name("order_id")
+                                        .value_name("ORDER_ID")
+                                        .takes_value(true)
+                                        .required(true)
+                                        .help("The paper order id to cancel"),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("orders")
+                                .about("List paper orders")
+                                .arg(
+                                    Arg::with_name("all")
+                                        .long("all")
+                                        .takes_value(false)
+                                        .help("Include closed orders"),
+                                ),
+                        )
+                        .subcommand(
+                            SubCommand::with_name("report")
+                                .about("Show simulated profit and loss against buy and hold")
+                                .arg(
+                                    Arg::with_name("json")
+                                        .long("json")
+                                        .takes_value(false)
+                                        .help("Output in JSON format"),
+                                ),
+                        ),
+                ),
         );
     }
 
-    let app_matches = app.get_matches();
+    let app_matches = app.get_matches_safe().unwrap_or_else(|err| match err.kind {
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => err.exit(),
+        _ => {
+            eprintln!("{}", err.message);
+            exit(outcome::EXIT_CONFIGURATION_ERROR);
+        }
+    });
+    if let Some(summary_path) = app_matches.value_of("summary") {
+        outcome::write_summary_to(&command_name(&app_matches), summary_path);
+    }
+    confirmation::set_assume_yes(app_matches.is_present("yes"));
     let db_path = value_t_or_exit!(app_matches, "db_path", PathBuf);
     let verbose = app_matches.is_present("verbose");
+    if let Some(exchanges) = app_matches.values_of("debug_capture") {
+        debug_capture::enable(
+            exchanges
+                .map(|exchange| exchange.parse())
+                .collect::<Result<_, _>>()?,
+            &value_t_or_exit!(app_matches, "debug_capture_dir", PathBuf),
+        );
+    }
+    rate_limit::set_verbose(verbose);
+    if let Ok(rate_limit_dir) = value_t!(app_matches, "rate_limit_dir", PathBuf) {
+        rate_limit::set_default_state_dir(&rate_limit_dir);
+    }
     let rpc_client = RpcClient::new_with_timeout_and_commitment(
         normalize_to_url_if_moniker(value_t_or_exit!(app_matches, "json_rpc_url", String)),
         std::time::Duration::from_secs(120),
@@ -5625,6 +16870,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if !db_path.exists() {
         fs::create_dir_all(&db_path)?;
     }
+    stream_session::set_default_dir(&db_path);
+    request_metrics::set_dir(&db_path.join("request-metrics"));
+    tokio::spawn(async {
+        loop {
+            tokio::time::sleep(REQUEST_METRICS_FLUSH_INTERVAL).await;
+            flush_request_metrics().await;
+        }
+    });
 
     let mut db_fd_lock = fd_lock::RwLock::new(fs::File::open(&db_path).unwrap());
     let _db_write_lock = loop {
@@ -5645,6 +16898,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         eprintln!("Failed to open {}: {}", db_path.display(), err);
         exit(1)
     });
+    settings::install(db.settings());
 
     match app_matches.subcommand() {
         ("price", Some(arg_matches)) => {
@@ -5653,7 +16907,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .ok();
             let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
 
-            let (price, verbose_msg) = if let Some(when) = when {
+            let (price, verbose_msg) = if let (Some(when), true) =
+                (when, arg_matches.is_present("exchanges"))
+            {
+                let (price, source) =
+                    get_historical_price_from_exchanges(&mut db, &rpc_client, token, when).await?;
+                (
+                    price,
+                    format!("Historical {token} price on {when} ({source})"),
+                )
+            } else if let Some(when) = when {
                 (
                     token.get_historical_price(&rpc_client, when).await?,
                     format!("Historical {token} price on {when}"),
@@ -5681,12 +16944,260 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 println!("{price:.2}");
             }
         }
+        ("compare", Some(arg_matches)) => {
+            let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+            let amount = value_t_or_exit!(arg_matches, "amount", f64);
+            let side = if arg_matches.is_present("buy") {
+                OrderSide::Buy
+            } else {
+                OrderSide::Sell
+            };
+            let withdrawal_fee = value_t_or_exit!(arg_matches, "withdrawal_fee", f64);
+            let tiers = arg_matches.is_present("tiers");
+            let json = arg_matches.is_present("json");
+
+            process_exchange_compare(&db, token, amount, side, withdrawal_fee, tiers, json).await?;
+            if let Ok(hours) = value_t!(arg_matches, "request_metrics", i64) {
+                print_request_metrics(hours);
+            }
+        }
+        ("proceeds", Some(arg_matches)) => {
+            let exchange_clients =
+                configured_exchange_clients(&db, |capabilities| capabilities.supports_trading)?;
+            process_proceeds_matches(&exchange_clients, arg_matches).await?;
+        }
+        ("book", Some(arg_matches)) => {
+            let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+            let amount = value_t!(arg_matches, "amount", f64).ok();
+            let side = if arg_matches.is_present("buy") {
+                OrderSide::Buy
+            } else {
+                OrderSide::Sell
+            };
+            let depth = value_t_or_exit!(arg_matches, "depth", usize);
+            let max_age = chrono::Duration::milliseconds(
+                (value_t_or_exit!(arg_matches, "max_age", f64) * 1000.) as i64,
+            );
+            let json = arg_matches.is_present("json");
+
+            process_exchange_book(&db, token, amount, side, depth, max_age, json).await?;
+        }
+        ("arbitrage", Some(arg_matches)) => {
+            let tokens = values_t!(arg_matches, "token", String)?
+                .into_iter()
+                .map(|token| MaybeToken::from(Token::from_str(&token).ok()))
+                .collect::<Vec<_>>();
+            let min_spread = value_t_or_exit!(arg_matches, "min_spread", f64);
+            let max_amount = value_t!(arg_matches, "max_amount", f64).ok();
+            let interval = value_t!(arg_matches, "interval", u64)
+                .ok()
+                .map(Duration::from_secs);
+            let execute = arg_matches.is_present("execute");
+            let lot_selection_method =
+                value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+            let cancellation = Cancellation::on_ctrl_c();
+            let cancellation = match value_t!(arg_matches, "timeout", u64) {
+                Ok(timeout) => cancellation.with_timeout(Duration::from_secs(timeout * 60)),
+                Err(_) => cancellation,
+            };
+
+            process_exchange_arbitrage(
+                &mut db,
+                &rpc_client,
+                tokens,
+                min_spread,
+                max_amount,
+                interval,
+                execute,
+                lot_selection_method,
+                &cancellation,
+                &notifier,
+            )
+            .await?;
+        }
+        ("portfolio", Some(arg_matches)) => {
+            let currency = arg_matches
+                .value_of("currency")
+                .map(|currency| currency.to_ascii_uppercase())
+                .unwrap_or_else(exchange::reporting_currency);
+            let min_value = value_t_or_exit!(arg_matches, "min_value", f64);
+            let all_subaccounts = arg_matches.is_present("all_subaccounts");
+            let cached_fallback = arg_matches.is_present("cached_fallback");
+            let export = value_t!(arg_matches, "export", PathBuf)
+                .ok()
+                .map(|path| (path, arg_matches.is_present("export_dated")));
+            let json = arg_matches.is_present("json");
+
+            process_portfolio(
+                &db,
+                &rpc_client,
+                &currency,
+                min_value,
+                all_subaccounts,
+                cached_fallback,
+                export,
+                json,
+                verbose,
+            )
+            .await?;
+        }
+        ("rebalance", Some(arg_matches)) => {
+            let targets = values_t!(arg_matches, "target", String)?
+                .iter()
+                .map(|target| parse_rebalance_target(target))
+                .collect::<Result<Vec<_>, _>>()?;
+            let total_weight = targets.iter().map(|(_, weight)| weight).sum::<f64>();
+            if (total_weight - 100.).abs() > f64::EPSILON {
+                return Err(format!("Target allocation sums to {total_weight}%, not 100%").into());
+            }
+            let tolerance = value_t_or_exit!(arg_matches, "tolerance", f64);
+            let slippage_bps = value_t_or_exit!(arg_matches, "slippage_bps", u64);
+            let lot_selection_method =
+                value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+            let execute = arg_matches.is_present("execute");
+
+            process_rebalance(
+                &mut db,
+                &rpc_client,
+                targets,
+                tolerance,
+                slippage_bps,
+                lot_selection_method,
+                execute,
+                &notifier,
+            )
+            .await?;
+        }
+        ("exchanges", Some(arg_matches)) => {
+            let json = arg_matches.is_present("json");
+            if arg_matches.is_present("token") {
+                let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                process_exchanges_token_support(&db, token, json).await?;
+            } else {
+                process_exchanges(&db, json)?;
+            }
+        }
+        ("conformance", Some(arg_matches)) => {
+            let exchange = value_t_or_exit!(arg_matches, "exchange", Exchange);
+            let recordings = value_t!(arg_matches, "recordings", PathBuf)
+                .unwrap_or_else(|_| Path::new("conformance").join(exchange.name()));
+            process_conformance(exchange, &recordings).await?;
+        }
+        ("health-check", Some(arg_matches)) => {
+            let json = arg_matches.is_present("json");
+            let result = process_health_check(&db, json).await;
+            if let Ok(hours) = value_t!(arg_matches, "request_metrics", i64) {
+                println!();
+                print_request_metrics(hours);
+            }
+            result?;
+        }
+        ("verify-deposit-addresses", Some(_)) => {
+            process_verify_deposit_addresses(&mut db).await?;
+        }
+        ("lending-interest", Some(arg_matches)) => {
+            let start_date =
+                naivedate_of(&value_t_or_exit!(arg_matches, "start_date", String)).unwrap();
+            let end_date =
+                naivedate_of(&value_t_or_exit!(arg_matches, "end_date", String)).unwrap();
+            let csv = arg_matches.is_present("csv");
+            process_lending_interest(&mut db, &rpc_client, start_date, end_date, csv).await?;
+        }
+        ("backfill", Some(arg_matches)) => {
+            let exchange = value_t!(arg_matches, "exchange", Exchange).ok();
+            let since = naivedate_of(&value_t_or_exit!(arg_matches, "since", String)).unwrap();
+            let start = DateTime::from_utc(since.and_hms(0, 0, 0), Utc);
+            process_backfill(&mut db, exchange, start).await?;
+        }
+        ("balance-snapshot", Some(balance_snapshot_matches)) => {
+            match balance_snapshot_matches.subcommand() {
+                ("record", Some(arg_matches)) => {
+                    let json = arg_matches.is_present("json");
+                    process_balance_snapshot_record(&mut db, &rpc_client, json).await?;
+                }
+                ("list", Some(arg_matches)) => {
+                    let json = arg_matches.is_present("json");
+                    process_balance_snapshot_list(&db, json)?;
+                }
+                ("report", Some(arg_matches)) => {
+                    let from = value_t!(arg_matches, "from", String).ok();
+                    let to = value_t!(arg_matches, "to", String).ok();
+                    let json = arg_matches.is_present("json");
+                    process_balance_snapshot_report(&db, from, to, json).await?;
+                }
+                ("drift", Some(arg_matches)) => {
+                    let from = value_t!(arg_matches, "from", String).ok();
+                    let tolerance = value_t_or_exit!(arg_matches, "tolerance", f64);
+                    let json = arg_matches.is_present("json");
+                    process_balance_snapshot_drift(&db, from, tolerance, json).await?;
+                }
+                _ => unreachable!(),
+            }
+        }
+        ("transfer", Some(arg_matches)) => {
+            let from_exchange = value_t_or_exit!(arg_matches, "from_exchange", Exchange);
+            let to_exchange = value_t_or_exit!(arg_matches, "to_exchange", Exchange);
+            let from_account = value_t!(arg_matches, "from_account", String)
+                .ok()
+                .unwrap_or_default();
+            let to_account = value_t!(arg_matches, "to_account", String)
+                .ok()
+                .unwrap_or_default();
+            if from_exchange == to_exchange && from_account == to_account {
+                return Err("Source and destination are the same exchange account".into());
+            }
+            let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+            let amount = match arg_matches.value_of("amount").unwrap() {
+                "ALL" => None,
+                amount => Some(token.amount(amount.parse().unwrap())),
+            };
+            let lot_selection_method =
+                value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+            let withdrawal_code = two_factor_code_of(arg_matches, from_exchange)?;
+            let resume_tag = value_t!(arg_matches, "tag", String).ok();
+            let timeout = Duration::from_secs(value_t_or_exit!(arg_matches, "timeout", u64) * 60);
+
+            let exchange_client = |exchange, exchange_account: &str| {
+                let exchange_credentials = db
+                    .get_exchange_credentials(exchange, exchange_account)
+                    .ok_or_else(|| {
+                        ConfigurationError(format!("No API key set for {exchange:?}"))
+                    })?;
+                exchange_client_new(exchange, exchange_credentials)
+            };
+            let from_client = exchange_client(from_exchange, &from_account)?;
+            let to_client = exchange_client(to_exchange, &to_account)?;
+
+            process_exchange_transfer(
+                &mut db,
+                from_exchange,
+                &from_account,
+                from_client.as_ref(),
+                to_exchange,
+                &to_account,
+                to_client.as_ref(),
+                token,
+                amount,
+                lot_selection_method,
+                withdrawal_code.as_deref(),
+                resume_tag,
+                &Cancellation::on_ctrl_c().with_timeout(timeout),
+                &rpc_client,
+                &notifier,
+            )
+            .await?;
+        }
         ("sync", Some(arg_matches)) => {
             let max_epochs_to_process = value_t!(arg_matches, "max_epochs_to_process", u64).ok();
             process_sync_swaps(&mut db, &rpc_client, &notifier).await?;
+            process_expire_withdrawal_intents(&mut db, &notifier).await?;
             for (exchange, exchange_credentials, exchange_account) in
                 db.get_default_accounts_from_configured_exchanges()
             {
+                if exchange.is_defunct() {
+                    println!("Skipping {exchange:?}: defunct, historical records only");
+                    continue;
+                }
                 println!("Synchronizing {exchange:?} {exchange_account}...");
                 let exchange_client = exchange_client_new(exchange, exchange_credentials)?;
                 process_sync_exchange(
@@ -5743,30 +17254,283 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 db.clear_metrics_config()?;
                 println!("Cleared InfluxDb configuration");
             }
-            ("show", Some(_arg_matches)) => match db.get_metrics_config() {
-                None => {
-                    println!("No InfluxDb configuration");
+            ("show", Some(_arg_matches)) => match db.get_metrics_config() {
+                None => {
+                    println!("No InfluxDb configuration");
+                }
+                Some(MetricsConfig {
+                    url,
+                    token: _,
+                    org_id,
+                    bucket,
+                }) => {
+                    println!("Url: {url}");
+                    println!("Token: ********");
+                    println!("Organization Id: {org_id}");
+                    println!("Bucket: {bucket}");
+                }
+            },
+            ("set", Some(arg_matches)) => {
+                db.set_metrics_config(MetricsConfig {
+                    url: value_t_or_exit!(arg_matches, "url", String),
+                    token: value_t_or_exit!(arg_matches, "token", String),
+                    org_id: value_t_or_exit!(arg_matches, "org_id", String),
+                    bucket: value_t_or_exit!(arg_matches, "bucket", String),
+                })?;
+                println!("InfluxDb configuration set");
+            }
+            _ => unreachable!(),
+        },
+        ("withdrawal-approval", Some(approval_matches)) => match approval_matches.subcommand() {
+            ("set", Some(arg_matches)) => {
+                let threshold_usd = value_t_or_exit!(arg_matches, "threshold", f64);
+                let expiry_hours = value_t_or_exit!(arg_matches, "expiry", u32);
+                db.set_withdrawal_approval_policy(Some(WithdrawalApprovalPolicy {
+                    threshold_usd,
+                    expiry_hours,
+                }))?;
+                println!(
+                    "Withdrawals of ${} or more require approval within {expiry_hours} hours",
+                    threshold_usd.separated_string_with_fixed_place(2)
+                );
+            }
+            ("show", Some(_arg_matches)) => match db.get_withdrawal_approval_policy() {
+                None => println!("Withdrawals do not require approval"),
+                Some(WithdrawalApprovalPolicy {
+                    threshold_usd,
+                    expiry_hours,
+                }) => println!(
+                    "Withdrawals of ${} or more require approval within {expiry_hours} hours",
+                    threshold_usd.separated_string_with_fixed_place(2)
+                ),
+            },
+            ("clear", Some(_arg_matches)) => {
+                db.set_withdrawal_approval_policy(None)?;
+                println!("Withdrawals no longer require approval");
+            }
+            ("list", Some(arg_matches)) => {
+                let all = arg_matches.is_present("all");
+                process_expire_withdrawal_intents(&mut db, &notifier).await?;
+                let intents = db
+                    .withdrawal_intents()
+                    .into_iter()
+                    .filter(|intent| all || intent.state.is_pending())
+                    .collect::<Vec<_>>();
+                if intents.is_empty() {
+                    println!("No withdrawal intents awaiting approval");
+                }
+                for intent in intents {
+                    println!(
+                        "{}: {:?} {} {}{} to {}, created {}, expires {}: {}",
+                        intent.code,
+                        intent.exchange,
+                        intent.exchange_account,
+                        intent.amount_description(),
+                        intent
+                            .usd_value
+                            .map(|usd_value| format!(
+                                " (${})",
+                                usd_value.separated_string_with_fixed_place(2)
+                            ))
+                            .unwrap_or_default(),
+                        intent.destination(),
+                        HumanTime::from(intent.created_at),
+                        HumanTime::from(intent.expires_at),
+                        intent.state,
+                    );
+                }
+            }
+            ("approve", Some(arg_matches)) => {
+                let code = value_t_or_exit!(arg_matches, "code", String);
+                let exchange = db
+                    .get_withdrawal_intent(&code)
+                    .ok_or_else(|| format!("Unknown withdrawal intent: {code}"))?
+                    .exchange;
+                let withdrawal_code = two_factor_code_of(arg_matches, exchange)?;
+                process_approve_withdrawal_intent(
+                    &mut db,
+                    &code,
+                    withdrawal_code.as_deref(),
+                    &rpc_client,
+                    &notifier,
+                )
+                .await?;
+            }
+            ("cancel", Some(arg_matches)) => {
+                let code = value_t_or_exit!(arg_matches, "code", String);
+                process_expire_withdrawal_intents(&mut db, &notifier).await?;
+                let intent = db
+                    .get_withdrawal_intent(&code)
+                    .ok_or_else(|| format!("Unknown withdrawal intent: {code}"))?;
+                if !intent.state.is_pending() {
+                    return Err(
+                        format!("Withdrawal intent {} is {}", intent.code, intent.state).into(),
+                    );
+                }
+                db.update_withdrawal_intent(
+                    &intent.code,
+                    WithdrawalIntentState::Cancelled,
+                    WithdrawalAuditEvent::Cancelled,
+                    String::new(),
+                )?;
+                println!("Cancelled withdrawal intent {}", intent.code);
+            }
+            ("log", Some(_arg_matches)) => {
+                let audit_log = db.withdrawal_audit_log();
+                if audit_log.is_empty() {
+                    println!("No withdrawal approvals recorded");
+                }
+                for entry in audit_log {
+                    println!(
+                        "{} {} {:?} by {}{}",
+                        entry.time.to_rfc3339(),
+                        entry.code,
+                        entry.event,
+                        entry.actor,
+                        if entry.detail.is_empty() {
+                            String::new()
+                        } else {
+                            format!(": {}", entry.detail)
+                        }
+                    );
+                }
+            }
+            _ => unreachable!(),
+        },
+        ("spending-limits", Some(limits_matches)) => match limits_matches.subcommand() {
+            ("set", Some(arg_matches)) => {
+                let limit = SpendingLimit {
+                    operation: value_t_or_exit!(arg_matches, "operation", LimitedOperation),
+                    token: arg_matches
+                        .value_of("token")
+                        .map(|token| MaybeToken::from(Token::from_str(token).ok())),
+                    max: value_t_or_exit!(arg_matches, "max", f64),
+                    window_hours: value_t!(arg_matches, "window", u32).ok(),
+                };
+                println!("Set the {limit}");
+                db.set_spending_limit(limit)?;
+            }
+            ("show", Some(_arg_matches)) => {
+                let spending_limits = db.spending_limits();
+                if spending_limits.is_empty() {
+                    println!("No spending limits set");
+                }
+                for (i, limit) in spending_limits.iter().enumerate() {
+                    match db.spending_limit_used(limit, Utc::now()) {
+                        Some(usage) => match value_spending_records(&usage.unvalued).await {
+                            Ok(unvalued) => {
+                                let used = usage.used + unvalued;
+                                println!(
+                                    "{}. {limit}: {} used ({:.1}%)",
+                                    i + 1,
+                                    limit.format_value(used),
+                                    used / limit.max * 100.
+                                )
+                            }
+                            Err(err) => println!(
+                                "{}. {limit}: {} used, and {} operations that can't be valued \
+                                 ({err})",
+                                i + 1,
+                                limit.format_value(usage.used),
+                                usage.unvalued.len()
+                            ),
+                        },
+                        None => println!("{}. {limit}", i + 1),
+                    }
+                }
+            }
+            ("remove", Some(arg_matches)) => {
+                let number = value_t_or_exit!(arg_matches, "number", usize);
+                match db.remove_spending_limit(number.saturating_sub(1))? {
+                    Some(limit) => println!("Removed the {limit}"),
+                    None => return Err(format!("No spending limit {number}").into()),
+                }
+            }
+            ("log", Some(_arg_matches)) => {
+                let audit_log = db.spending_audit_log();
+                if audit_log.is_empty() {
+                    println!("No spending limit changes or overrides recorded");
+                }
+                for entry in audit_log {
+                    println!(
+                        "{} {:?} by {}: {}",
+                        entry.time.to_rfc3339(),
+                        entry.event,
+                        entry.actor,
+                        entry.detail
+                    );
+                }
+            }
+            _ => unreachable!(),
+        },
+        ("settings", Some(settings_matches)) => match settings_matches.subcommand() {
+            ("set", Some(arg_matches)) => {
+                let setting = value_t_or_exit!(arg_matches, "setting", Setting);
+                let value = value_t_or_exit!(arg_matches, "value", String);
+                setting
+                    .validate(&value)
+                    .map_err(|err| format!("Invalid {} setting: {err}", setting.name()))?;
+                db.set_setting(setting, value.clone())?;
+                println!("Set {} to {value}", setting.name());
+            }
+            ("unset", Some(arg_matches)) => {
+                let setting = value_t_or_exit!(arg_matches, "setting", Setting);
+                match (db.unset_setting(setting)?, setting.default_value()) {
+                    (Some(value), Some(default_value)) => println!(
+                        "Returned {} from {value} to its default, {default_value}",
+                        setting.name()
+                    ),
+                    (Some(value), None) => println!("Unset {}, which was {value}", setting.name()),
+                    (None, _) => println!("{} is not set", setting.name()),
+                }
+            }
+            ("show", Some(_arg_matches)) => {
+                let values = db.settings();
+                for setting in Setting::all() {
+                    match (values.get(setting), setting.default_value()) {
+                        (Some(value), _) => println!("{}: {value}", setting.name()),
+                        (None, Some(default_value)) => {
+                            println!("{}: {default_value} (default)", setting.name())
+                        }
+                        (None, None) => println!("{}: not set", setting.name()),
+                    }
+                    println!("  {}", setting.description());
+                }
+            }
+            _ => unreachable!(),
+        },
+        ("evacuation-address", Some(evacuation_matches)) => match evacuation_matches.subcommand() {
+            ("add", Some(arg_matches)) => {
+                let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                let address = pubkey_of_signer(arg_matches, "address", &mut wallet_manager)?
+                    .expect("address");
+                db.set_evacuation_address(token, address)?;
+                println!("Allow-listed {address} as the {token} evacuation address");
+            }
+            ("remove", Some(arg_matches)) => {
+                let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                match db.remove_evacuation_address(token)? {
+                    Some(removed) => println!(
+                        "Removed {} as the {token} evacuation address",
+                        removed.address
+                    ),
+                    None => return Err(format!("No {token} evacuation address").into()),
+                }
+            }
+            ("list", Some(_arg_matches)) => {
+                let evacuation_addresses = db.evacuation_addresses();
+                if evacuation_addresses.is_empty() {
+                    println!("No evacuation addresses allow-listed, `evacuate` will refuse to run");
                 }
-                Some(MetricsConfig {
-                    url,
-                    token: _,
-                    org_id,
-                    bucket,
-                }) => {
-                    println!("Url: {url}");
-                    println!("Token: ********");
-                    println!("Organization Id: {org_id}");
-                    println!("Bucket: {bucket}");
+                for evacuation_address in evacuation_addresses {
+                    println!(
+                        "{:<6} {} (added {} by {})",
+                        evacuation_address.token.to_string(),
+                        evacuation_address.address,
+                        evacuation_address.added.format("%Y-%m-%d"),
+                        evacuation_address.added_by
+                    );
                 }
-            },
-            ("set", Some(arg_matches)) => {
-                db.set_metrics_config(MetricsConfig {
-                    url: value_t_or_exit!(arg_matches, "url", String),
-                    token: value_t_or_exit!(arg_matches, "token", String),
-                    org_id: value_t_or_exit!(arg_matches, "org_id", String),
-                    bucket: value_t_or_exit!(arg_matches, "bucket", String),
-                })?;
-                println!("InfluxDb configuration set");
             }
             _ => unreachable!(),
         },
@@ -6367,43 +18131,256 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .ok()
                 .unwrap_or_default();
 
-            let exchange_client = || {
+            let paper = exchange_matches.is_present("paper")
+                || exchange_matches.subcommand_name() == Some("paper");
+            if paper
+                && !matches!(
+                    exchange_matches.subcommand_name(),
+                    Some("balance" | "market" | "fees" | "paper")
+                )
+            {
+                return Err(
+                    "--paper is only supported by the balance, market, fees and paper \
+                            commands, as the others record lots in the database"
+                        .into(),
+                );
+            }
+            let paper_state_path = db_path.join(if exchange_account.is_empty() {
+                format!("paper-{}.json", exchange.name())
+            } else {
+                format!("paper-{}-{exchange_account}.json", exchange.name())
+            });
+
+            // Market data for paper trading uses the account's API key when set, and otherwise
+            // only public endpoints
+            let paper_exchange_client = || {
                 let exchange_credentials = db
                     .get_exchange_credentials(exchange, &exchange_account)
-                    .ok_or_else(|| format!("No API key set for {exchange:?}"))?;
-                exchange_client_new(exchange, exchange_credentials)
+                    .unwrap_or(ExchangeCredentials {
+                        api_key: String::new(),
+                        secret: String::new(),
+                        subaccount: None,
+                    });
+                PaperExchangeClient::new(
+                    exchange,
+                    exchange_client_new(exchange, exchange_credentials)?,
+                    paper_state_path.clone(),
+                )
             };
 
+            let exchange_client =
+                || -> Result<Box<dyn ExchangeClient>, Box<dyn std::error::Error>> {
+                    if paper {
+                        return Ok(Box::new(paper_exchange_client()?));
+                    }
+                    let exchange_credentials = db
+                        .get_exchange_credentials(exchange, &exchange_account)
+                        .ok_or_else(|| {
+                            ConfigurationError(format!("No API key set for {exchange:?}"))
+                        })?;
+                    exchange_client_new(exchange, exchange_credentials)
+                };
+
             match exchange_matches.subcommand() {
                 ("address", Some(arg_matches)) => {
                     let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
-                    let deposit_address = exchange_client()?.deposit_address(token).await?;
+                    let exchange_client = exchange_client()?;
+
+                    if let Some(accept_address) = pubkey_of(arg_matches, "accept") {
+                        let deposit_address = exchange_client.deposit_address(token).await?;
+                        if deposit_address != accept_address {
+                            return Err(format!(
+                                "{exchange:?} reports {deposit_address} as the {token} deposit \
+                                 address, not {accept_address}"
+                            )
+                            .into());
+                        }
+                        let pinned = db.pinned_deposit_address(exchange, &exchange_account, token);
+                        db.pin_deposit_address(
+                            exchange,
+                            &exchange_account,
+                            token,
+                            deposit_address,
+                        )?;
+                        db.cache_deposit_address(
+                            exchange,
+                            &exchange_account,
+                            token,
+                            deposit_address,
+                        )?;
+                        match pinned {
+                            Some(pinned) if pinned.address != deposit_address => println!(
+                                "Accepted {token} deposit address {deposit_address}, replacing {}",
+                                pinned.address
+                            ),
+                            _ => println!("Pinned {token} deposit address: {deposit_address}"),
+                        }
+                        return Ok(());
+                    }
+
+                    let deposit_address = verified_deposit_address(
+                        &mut db,
+                        exchange,
+                        &exchange_account,
+                        exchange_client.as_ref(),
+                        token,
+                    )
+                    .await?;
                     println!("{token} deposit address: {deposit_address}");
+                    if let Some(pinned) =
+                        db.pinned_deposit_address(exchange, &exchange_account, token)
+                    {
+                        for replaced in pinned.replaced {
+                            println!(
+                                "  replaced {} (pinned {}, replaced {})",
+                                replaced.address,
+                                replaced.pinned_at.format("%Y-%m-%d"),
+                                replaced.replaced_at.format("%Y-%m-%d"),
+                            );
+                        }
+                    }
+                }
+                ("deposit-requirements", Some(arg_matches)) => {
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let exchange_client = exchange_client()?;
+                    let deposit_requirements = exchange_client.deposit_requirements(token).await?;
+                    if arg_matches.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&deposit_requirements)?);
+                        return Ok(());
+                    }
+
+                    println!(
+                        "{exchange:?} {token} deposits {}",
+                        if deposit_requirements.deposits_enabled {
+                            "enabled"
+                        } else {
+                            "DISABLED"
+                        }
+                    );
+                    println!("Finality:        {}", deposit_requirements.finality);
+                    println!(
+                        "Minimum:         {}",
+                        deposit_requirements
+                            .minimum
+                            .map(|minimum| format!("{}{minimum}", token.symbol()))
+                            .unwrap_or_else(|| "unpublished".into())
+                    );
+                    println!(
+                        "Expected credit: {}",
+                        deposit_requirements
+                            .expected_credit_time()
+                            .map(|expected_credit_time| format!(
+                                "within {}s of sending",
+                                expected_credit_time.num_seconds()
+                            ))
+                            .unwrap_or_else(|| "unknown".into())
+                    );
+                    println!(
+                        "Overdue after:   {}",
+                        format_credit_window(
+                            deposit_credit_window(
+                                &db,
+                                exchange,
+                                Some(exchange_client.as_ref()),
+                                token
+                            )
+                            .await
+                        )
+                    );
                 }
                 ("pending-deposits", Some(arg_matches)) => {
                     let quiet = arg_matches.is_present("quiet");
+                    // Only `--add` needs the exchange, the deposit requirements are a refinement
+                    let exchange_client = match exchange_client() {
+                        Ok(exchange_client) => Some(exchange_client),
+                        Err(err) if arg_matches.is_present("add") => return Err(err),
+                        Err(_) => None,
+                    };
+
+                    if let Ok(hours) = value_t!(arg_matches, "credit_window", u32) {
+                        db.set_deposit_credit_window(exchange, Some(hours))?;
+                        println!(
+                            "{exchange:?} deposits not credited within {hours} hours will be \
+                             reported as overdue"
+                        );
+                    }
+                    if let Ok(signature) = value_t!(arg_matches, "remove", Signature) {
+                        if !db.remove_external_deposit(signature)? {
+                            return Err(format!("Deposit {signature} is not tracked").into());
+                        }
+                        println!("Stopped tracking deposit {signature}");
+                    }
+                    if let (Ok(signature), Some(exchange_client)) =
+                        (value_t!(arg_matches, "add", Signature), &exchange_client)
+                    {
+                        let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                        let deposit_address = exchange_client.deposit_address(token).await?;
+                        let (address, address_is_token) = match token.token() {
+                            None => (deposit_address, false),
+                            Some(token) => (token.ata(&deposit_address), true),
+                        };
+                        let balance_change = get_transaction_balance_change(
+                            &rpc_client,
+                            &signature,
+                            &address,
+                            address_is_token,
+                        )?;
+                        let amount = balance_change
+                            .post_amount
+                            .saturating_sub(balance_change.pre_amount);
+                        if amount == 0 {
+                            return Err(format!(
+                                "{signature} did not deposit {token} to {deposit_address}"
+                            )
+                            .into());
+                        }
+                        db.add_external_deposit(ExternalDeposit {
+                            exchange,
+                            signature,
+                            token,
+                            amount,
+                            sent_at: balance_change
+                                .when
+                                .map(|when| DateTime::<Utc>::from_utc(when, Utc))
+                                .unwrap_or_else(Utc::now),
+                            overdue_warned: false,
+                        })?;
+                        println!(
+                            "Tracking {} {}{} deposit {signature}",
+                            token,
+                            token.symbol(),
+                            token.ui_amount(amount)
+                        );
+                    }
 
-                    let pending_deposits = db.pending_deposits(Some(exchange));
+                    let in_transit_deposits = db.in_transit_deposits(Some(exchange));
                     if quiet {
-                        if !pending_deposits.is_empty() {
+                        if !in_transit_deposits.is_empty() {
                             return Err(
-                                format!("{} deposits pending", pending_deposits.len()).into()
+                                format!("{} deposits pending", in_transit_deposits.len()).into()
                             );
                         }
                     } else {
-                        for pending_deposit in pending_deposits {
-                            let token = pending_deposit.transfer.to_token;
-                            assert_eq!(
-                                pending_deposit.transfer.from_token,
-                                pending_deposit.transfer.to_token
-                            );
-
+                        for deposit in in_transit_deposits {
+                            let credit_window = deposit_credit_window(
+                                &db,
+                                exchange,
+                                exchange_client.as_deref(),
+                                deposit.token,
+                            )
+                            .await;
                             println!(
-                                "{} deposit pending: {}{} (signature: {})",
-                                token,
-                                token.symbol(),
-                                token.ui_amount(pending_deposit.amount),
-                                pending_deposit.transfer.signature
+                                "{} deposit pending: {}{} (signature: {}{}){}",
+                                deposit.token,
+                                deposit.token.symbol(),
+                                deposit.token.ui_amount(deposit.amount),
+                                deposit.signature,
+                                if deposit.external {
+                                    ", sent outside of sys"
+                                } else {
+                                    ""
+                                },
+                                describe_deposit_transit(&deposit, credit_window),
                             );
                         }
                     }
@@ -6412,33 +18389,216 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let quiet = arg_matches.is_present("quiet");
 
                     let pending_withdrawals = db.pending_withdrawals(Some(exchange));
+                    let pending_fiat_withdrawals = db
+                        .fiat_withdrawals(Some(exchange))
+                        .into_iter()
+                        .filter(|fiat_withdrawal| !fiat_withdrawal.state.is_final())
+                        .collect::<Vec<_>>();
                     if quiet {
-                        if !pending_withdrawals.is_empty() {
-                            return Err(format!(
-                                "{} withdrawals pending",
-                                pending_withdrawals.len()
-                            )
-                            .into());
+                        let pending = pending_withdrawals.len() + pending_fiat_withdrawals.len();
+                        if pending > 0 {
+                            return Err(format!("{pending} withdrawals pending").into());
                         }
                     } else {
+                        print_fiat_withdrawals(&pending_fiat_withdrawals);
                         for pending_withdrawals in pending_withdrawals {
                             let token = pending_withdrawals.token;
                             println!(
                                 "{} withdrawal pending: {}{} (destination: {})",
                                 token,
                                 token.symbol(),
-                                token.ui_amount(pending_withdrawals.amount),
+                                format_token_amount(
+                                    token,
+                                    token.ui_amount(pending_withdrawals.amount),
+                                    true
+                                ),
                                 pending_withdrawals.to_address,
                             );
                         }
                     }
                 }
+                ("deposits", Some(arg_matches)) => {
+                    let deposits = exchange_client()?
+                        .recent_deposits()
+                        .await?
+                        .ok_or_else(|| format!("Recent deposits not available for {exchange:?}"))?;
+
+                    if arg_matches.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&deposits)?);
+                    } else {
+                        for deposit in deposits {
+                            println!("{} (tx id: {})", deposit.amount, deposit.tx_id);
+                        }
+                    }
+                }
+                ("reconcile-deposits", Some(arg_matches)) => {
+                    let signatures = values_t!(arg_matches, "signature", Signature)
+                        .ok()
+                        .unwrap_or_default();
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let max_age =
+                        chrono::Duration::hours(
+                            value_t_or_exit!(arg_matches, "max_age", u32) as i64
+                        );
+
+                    process_exchange_reconcile_deposits(
+                        &mut db,
+                        &rpc_client,
+                        exchange,
+                        exchange_client()?.as_ref(),
+                        token,
+                        signatures,
+                        max_age,
+                        arg_matches.is_present("all"),
+                        arg_matches.is_present("json"),
+                        &notifier,
+                    )
+                    .await?;
+                }
+                ("withdrawals", Some(arg_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    let withdrawals = match value_t!(arg_matches, "start_date", String) {
+                        Ok(start_date) => {
+                            let start_date = naivedate_of(&start_date).unwrap();
+                            let end_date =
+                                naivedate_of(&value_t_or_exit!(arg_matches, "end_date", String))
+                                    .unwrap();
+                            let (start, end) = reporting_time_zone()?.days(start_date, end_date);
+                            exchange_client.withdrawals(start, end).await?
+                        }
+                        Err(_) => exchange_client.recent_withdrawals().await?,
+                    };
+
+                    if arg_matches.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&withdrawals)?);
+                    } else {
+                        for withdrawal in withdrawals {
+                            println!(
+                                "{}{} to {} ({}, tag: {})",
+                                withdrawal.token.symbol(),
+                                format_token_amount(withdrawal.token, withdrawal.amount, true),
+                                withdrawal.address,
+                                match (withdrawal.completed, &withdrawal.tx_id) {
+                                    (false, _) => "pending".to_string(),
+                                    (true, None) => "cancelled".to_string(),
+                                    (true, Some(tx_id)) => format!("tx id: {tx_id}"),
+                                },
+                                withdrawal.tag
+                            );
+                        }
+                    }
+                }
+                ("import-orders", Some(arg_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let pair =
+                        selected_pair(exchange, exchange_client.as_ref(), arg_matches, token)
+                            .await?;
+                    let start_date =
+                        naivedate_of(&value_t_or_exit!(arg_matches, "start_date", String)).unwrap();
+                    let end_date =
+                        naivedate_of(&value_t_or_exit!(arg_matches, "end_date", String)).unwrap();
+                    let lot_selection_method =
+                        value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+
+                    process_exchange_import_orders(
+                        &mut db,
+                        exchange,
+                        &exchange_account,
+                        exchange_client.as_ref(),
+                        token,
+                        pair,
+                        Utc.from_utc_date(&start_date).and_hms(0, 0, 0),
+                        Utc.from_utc_date(&end_date.succ()).and_hms(0, 0, 0),
+                        lot_selection_method,
+                        arg_matches.is_present("dry_run"),
+                    )
+                    .await?;
+                }
+                ("order-status", Some(arg_matches)) => {
+                    let order_id = value_t_or_exit!(arg_matches, "order_id", String);
+                    let open_order = db
+                        .open_orders(Some(exchange), None)
+                        .into_iter()
+                        .find(|open_order| open_order.order_id == order_id)
+                        .ok_or_else(|| format!("Unknown {exchange:?} order: {order_id}"))?;
+                    let pair = TradingPair::from_exchange_symbol(exchange, &open_order.pair)?;
+
+                    let exchange_client = exchange_client()?;
+                    let order_status = exchange_client.order_status(&pair, &order_id).await?;
+                    if arg_matches.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&order_status)?);
+                    } else {
+                        let pair_info = exchange_client.pair_info(&pair).await?;
+                        println!(
+                            "{}: {:?} {} at {} ({}), filled {}{}, last update {}",
+                            pair,
+                            order_status.side,
+                            pair_info.format_amount(order_status.amount, true),
+                            format_quote_amount(
+                                &pair_info.format_price(order_status.price, true),
+                                &pair.quote
+                            ),
+                            match (&order_status.cancellation, order_status.open) {
+                                (_, true) => "open".to_string(),
+                                (Some(cancellation), false) => cancellation.to_string(),
+                                (None, false) => "closed".to_string(),
+                            },
+                            pair_info.format_amount(order_status.filled_amount, true),
+                            order_status
+                                .average_fill_price
+                                .map(|average_fill_price| format!(
+                                    " at {} average",
+                                    format_quote_amount(
+                                        &pair_info.format_price(average_fill_price, true),
+                                        &pair.quote
+                                    )
+                                ))
+                                .unwrap_or_default(),
+                            order_status.last_update,
+                        );
+                    }
+                }
                 ("balance", Some(arg_matches)) => {
                     let available_only = arg_matches.is_present("available_only");
                     let total_only = arg_matches.is_present("total_only");
                     let integer = arg_matches.is_present("integer");
 
-                    let balances = exchange_client()?.balances().await?;
+                    if arg_matches.is_present("by_wallet") {
+                        let wallet_balances = exchange_client()?.wallet_balances().await?;
+
+                        if arg_matches.is_present("json") {
+                            println!("{}", serde_json::to_string_pretty(&wallet_balances)?);
+                            return Ok(());
+                        }
+
+                        println!(
+                            "Wallet          Coin                  Total            Available"
+                        );
+                        for (wallet, balances) in &wallet_balances {
+                            let mut coins = balances.keys().collect::<Vec<_>>();
+                            coins.sort();
+                            for coin in coins {
+                                let balance = &balances[coin];
+                                if balance.total > 0. {
+                                    println!(
+                                        "{wallet:<15} {coin:<6} {:>20} {:>20}",
+                                        format_coin_amount(exchange, coin, balance.total, true),
+                                        format_coin_amount(exchange, coin, balance.available, true),
+                                    );
+                                }
+                            }
+                        }
+                        return Ok(());
+                    }
+
+                    let exchange_client = exchange_client()?;
+                    let balances = exchange_client.balances().await?;
+
+                    if arg_matches.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&balances)?);
+                        return Ok(());
+                    }
 
                     if !(available_only || total_only) {
                         println!("                   Total            Available")
@@ -6454,7 +18614,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             if integer {
                                 balance.available.floor().to_string()
                             } else {
-                                balance.available.separated_string_with_fixed_place(8)
+                                format_coin_amount(exchange, coin, balance.available, true)
                             }
                         );
 
@@ -6464,7 +18624,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             if integer {
                                 balance.total.floor().to_string()
                             } else {
-                                balance.total.separated_string_with_fixed_place(8)
+                                format_coin_amount(exchange, coin, balance.total, true)
                             }
                         );
 
@@ -6478,25 +18638,61 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     };
 
                     print_balance("SOL", "◎", &balance);
-                    for coin in exchange::USD_COINS {
-                        if let Some(balance) = balances.get(*coin) {
+                    for coin in exchange::usd_preference_order() {
+                        if let Some(balance) = balances.get(coin) {
                             if balance.total > 0. {
                                 print_balance(coin, "$", balance);
                             }
                         }
                     }
+
+                    // Sent but not yet credited, so in neither the exchange nor the wallet balance
+                    if !(available_only || total_only) {
+                        for deposit in db.in_transit_deposits(Some(exchange)) {
+                            let credit_window = deposit_credit_window(
+                                &db,
+                                exchange,
+                                Some(exchange_client.as_ref()),
+                                deposit.token,
+                            )
+                            .await;
+                            println!(
+                                "{} {:>20}  in transit ({}{})",
+                                deposit.token,
+                                format!(
+                                    "{}{}",
+                                    deposit.token.symbol(),
+                                    deposit.token.ui_amount(deposit.amount)
+                                ),
+                                deposit.signature,
+                                describe_deposit_transit(&deposit, credit_window),
+                            );
+                        }
+                    }
                 }
                 ("market", Some(arg_matches)) => {
                     let exchange_client = exchange_client()?;
-
-                    let pair = value_t!(arg_matches, "pair", String)
-                        .unwrap_or_else(|_| exchange_client.preferred_solusd_pair().into());
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let pair =
+                        selected_pair(exchange, exchange_client.as_ref(), arg_matches, token)
+                            .await?;
                     let format = if arg_matches.is_present("weighted_24h_average_price") {
                         MarketInfoFormat::Weighted24hAveragePrice
-                    } else if arg_matches.is_present("hourly") {
-                        MarketInfoFormat::Hourly
+                    } else if arg_matches.is_present("hourly")
+                        || arg_matches.is_present("interval")
+                        || arg_matches.is_present("count")
+                    {
+                        let default_range = CandleRange::default();
+                        MarketInfoFormat::Candles(CandleRange {
+                            interval_minutes: value_t!(arg_matches, "interval", u32)
+                                .unwrap_or(default_range.interval_minutes),
+                            count: value_t!(arg_matches, "count", usize)
+                                .unwrap_or(default_range.count),
+                        })
                     } else if arg_matches.is_present("ask") {
                         MarketInfoFormat::Ask
+                    } else if arg_matches.is_present("json") {
+                        MarketInfoFormat::Json
                     } else {
                         MarketInfoFormat::All
                     };
@@ -6517,6 +18713,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         value_t!(arg_matches, "if_exchange_balance_less_than", f64)
                             .ok()
                             .map(|x| token.amount(x));
+                    let sol_reserve = if arg_matches.is_present("keep_reserve") {
+                        if !matches!(amount, Amount::All) {
+                            return Err("--keep-reserve requires an AMOUNT of ALL".into());
+                        }
+                        let reserve = value_t_or_exit!(arg_matches, "reserve", f64);
+                        Some(MaybeToken::SOL().amount(reserve))
+                    } else {
+                        None
+                    };
                     let from_address =
                         pubkey_of_signer(arg_matches, "from", &mut wallet_manager)?.expect("from");
                     let lot_numbers = lot_numbers_of(arg_matches, "lot_numbers");
@@ -6537,7 +18742,17 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let authority_signer = authority_signer.expect("authority_signer");
 
                     let exchange_client = exchange_client()?;
-                    let deposit_address = exchange_client.deposit_address(token).await?;
+                    if let Ok(token_support) = exchange_client.supports_token(token).await {
+                        token_support.check_deposits(exchange, token)?;
+                    }
+                    let deposit_address = verified_deposit_address(
+                        &mut db,
+                        exchange,
+                        &exchange_account,
+                        exchange_client.as_ref(),
+                        token,
+                    )
+                    .await?;
                     add_exchange_deposit_address_to_db(
                         &mut db,
                         exchange,
@@ -6554,6 +18769,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         token,
                         deposit_address,
                         amount,
+                        sol_reserve,
                         from_address,
                         if_source_balance_exceeds,
                         if_exchange_balance_less_than,
@@ -6572,6 +18788,35 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     )
                     .await?;
                 }
+                ("fund-from-stake", Some(arg_matches)) => {
+                    let stake_address = pubkey_of(arg_matches, "stake_address").unwrap();
+                    let sol_reserve =
+                        MaybeToken::SOL().amount(value_t_or_exit!(arg_matches, "reserve", f64));
+                    let lot_selection_method =
+                        value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+                    let (authority_signer, authority_address) =
+                        signer_of(arg_matches, "by", &mut wallet_manager)?;
+                    let authority_address = authority_address.expect("authority_address");
+                    let authority_signer = authority_signer.expect("authority_signer");
+                    let cancellation = arg_matches.is_present("wait").then(Cancellation::on_ctrl_c);
+
+                    let exchange_client = exchange_client()?;
+                    process_exchange_fund_from_stake(
+                        &mut db,
+                        &rpc_client,
+                        exchange,
+                        &exchange_account,
+                        exchange_client.as_ref(),
+                        stake_address,
+                        sol_reserve,
+                        authority_address,
+                        &[authority_signer.as_ref()],
+                        lot_selection_method,
+                        cancellation.as_ref(),
+                        &notifier,
+                    )
+                    .await?;
+                }
                 ("withdraw", Some(arg_matches)) => {
                     let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
                     let amount = match arg_matches.value_of("amount").unwrap() {
@@ -6585,10 +18830,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
 
                     let withdrawal_password = None; // TODO: Support reading password from stdin
-                    let withdrawal_code = value_t!(arg_matches, "code", String).ok();
+                    let withdrawal_code = two_factor_code_of(arg_matches, exchange)?;
+                    let lending_redemption = arg_matches.is_present("redeem_lending").then(|| {
+                        Cancellation::on_ctrl_c().with_timeout(Duration::from_secs(
+                            value_t_or_exit!(arg_matches, "redeem_lending_timeout", u64) * 60,
+                        ))
+                    });
 
                     let exchange_client = exchange_client()?;
-                    let deposit_address = exchange_client.deposit_address(token).await?;
+                    if let Ok(token_support) = exchange_client.supports_token(token).await {
+                        token_support.check_withdrawals(exchange, token)?;
+                    }
+                    let deposit_address = exchange_deposit_address(
+                        &mut db,
+                        exchange,
+                        &exchange_account,
+                        exchange_client.as_ref(),
+                        token,
+                    )
+                    .await?;
                     add_exchange_deposit_address_to_db(
                         &mut db,
                         exchange,
@@ -6597,30 +18857,137 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         deposit_address,
                         &rpc_client,
                     )?;
+                    process_expire_withdrawal_intents(&mut db, &notifier).await?;
 
-                    process_exchange_withdraw(
+                    let resolved_amount = amount.unwrap_or_else(|| {
+                        db.get_account(deposit_address, token)
+                            .expect("unknown deposit address")
+                            .last_update_balance
+                    });
+                    if let Some((policy, usd_value)) =
+                        withdrawal_needing_approval(&db, token, resolved_amount, &rpc_client).await
+                    {
+                        process_create_withdrawal_intent(
+                            &mut db,
+                            exchange,
+                            &exchange_account,
+                            token,
+                            resolved_amount,
+                            to_address,
+                            lot_selection_method,
+                            lot_numbers,
+                            &policy,
+                            usd_value,
+                        )?;
+                    } else {
+                        process_exchange_withdraw(
+                            &mut db,
+                            exchange,
+                            exchange_client.as_ref(),
+                            token,
+                            deposit_address,
+                            amount,
+                            to_address,
+                            lot_selection_method,
+                            lot_numbers,
+                            withdrawal_password,
+                            withdrawal_code.as_deref(),
+                            &rpc_client,
+                            arg_matches.is_present("override_limits"),
+                            lending_redemption.as_ref(),
+                            "withdraw",
+                            &notifier,
+                        )
+                        .await?;
+                        process_sync_exchange(
+                            &mut db,
+                            exchange,
+                            exchange_client.as_ref(),
+                            &rpc_client,
+                            &notifier,
+                        )
+                        .await?;
+                    }
+                }
+                ("evacuate", Some(arg_matches)) => {
+                    let lot_selection_method =
+                        value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+                    let withdrawal_code = two_factor_code_of(arg_matches, exchange)?;
+                    let exchange_client = exchange_client()?;
+                    process_exchange_evacuate(
                         &mut db,
                         exchange,
+                        &exchange_account,
                         exchange_client.as_ref(),
-                        token,
-                        deposit_address,
-                        amount,
-                        to_address,
                         lot_selection_method,
-                        lot_numbers,
-                        withdrawal_password,
-                        withdrawal_code,
-                    )
-                    .await?;
-                    process_sync_exchange(
-                        &mut db,
-                        exchange,
-                        exchange_client.as_ref(),
+                        withdrawal_code.as_deref(),
                         &rpc_client,
+                        arg_matches.is_present("override_limits"),
+                        arg_matches.is_present("dry_run"),
                         &notifier,
                     )
                     .await?;
                 }
+                ("fiat-withdraw", Some(fiat_withdraw_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    match fiat_withdraw_matches.subcommand() {
+                        ("methods", Some(arg_matches)) => {
+                            let currency =
+                                value_t_or_exit!(arg_matches, "currency", String).to_uppercase();
+                            let methods =
+                                exchange_client.fiat_withdrawal_methods(&currency).await?;
+                            if methods.is_empty() {
+                                println!("No {currency} withdrawal methods registered");
+                            }
+                            for method in methods {
+                                println!("{}: {}", method.id, method.name);
+                            }
+                        }
+                        ("request", Some(arg_matches)) => {
+                            let currency =
+                                value_t_or_exit!(arg_matches, "currency", String).to_uppercase();
+                            let amount = match arg_matches.value_of("amount").unwrap() {
+                                "ALL" => None,
+                                amount => Some(amount.parse::<f64>().unwrap()),
+                            };
+                            let method_id = value_t_or_exit!(arg_matches, "method", String);
+
+                            process_exchange_fiat_withdraw(
+                                &mut db,
+                                exchange,
+                                exchange_client.as_ref(),
+                                &exchange_account,
+                                &currency,
+                                amount,
+                                &method_id,
+                                arg_matches.is_present("override_limits"),
+                                "fiat-withdraw",
+                                &notifier,
+                            )
+                            .await?;
+                        }
+                        ("status", Some(arg_matches)) => {
+                            let all = arg_matches.is_present("all");
+                            sync_fiat_withdrawals(
+                                &mut db,
+                                exchange,
+                                exchange_client.as_ref(),
+                                &notifier,
+                            )
+                            .await?;
+                            let fiat_withdrawals = db
+                                .fiat_withdrawals(Some(exchange))
+                                .into_iter()
+                                .filter(|fiat_withdrawal| all || !fiat_withdrawal.state.is_final())
+                                .collect::<Vec<_>>();
+                            if fiat_withdrawals.is_empty() {
+                                println!("No fiat withdrawals pending");
+                            }
+                            print_fiat_withdrawals(&fiat_withdrawals);
+                        }
+                        _ => unreachable!(),
+                    }
+                }
                 ("cancel", Some(arg_matches)) => {
                     let order_ids: HashSet<String> = values_t!(arg_matches, "order_id", String)
                         .ok()
@@ -6661,15 +19028,21 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 ("buy", Some(arg_matches)) => {
                     let exchange_client = exchange_client()?;
-                    let token = MaybeToken::SOL();
-                    let pair = value_t!(arg_matches, "pair", String)
-                        .unwrap_or_else(|_| exchange_client.preferred_solusd_pair().into());
-                    let amount = match arg_matches.value_of("amount").unwrap() {
-                        "ALL" => None,
-                        amount => Some(str::parse::<f64>(amount).unwrap()),
-                    };
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let pair =
+                        selected_pair(exchange, exchange_client.as_ref(), arg_matches, token)
+                            .await?;
+                    let amount = quote_order_amount_of(arg_matches).or_else(|| {
+                        match arg_matches.value_of("amount").unwrap() {
+                            "ALL" => None,
+                            amount => Some(OrderAmount::Base(str::parse::<f64>(amount).unwrap())),
+                        }
+                    });
 
                     let if_balance_exceeds = value_t!(arg_matches, "if_balance_exceeds", f64).ok();
+                    let expiry = value_t!(arg_matches, "expire_after", i64)
+                        .ok()
+                        .map(|hours| Utc::now() + chrono::Duration::hours(hours));
 
                     let price = if let Ok(price) = value_t!(arg_matches, "at", f64) {
                         LimitOrderPrice::At(price)
@@ -6688,6 +19061,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         amount,
                         price,
                         if_balance_exceeds,
+                        arg_matches.is_present("exact"),
+                        order_rounding_of(arg_matches),
+                        arg_matches.is_present("skip_preflight"),
+                        expiry,
+                        arg_matches.is_present("override_limits"),
+                        "buy",
                         &notifier,
                     )
                     .await?;
@@ -6702,14 +19081,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
                 ("sell", Some(arg_matches)) => {
                     let exchange_client = exchange_client()?;
-                    let token = MaybeToken::SOL();
-                    let pair = value_t!(arg_matches, "pair", String)
-                        .unwrap_or_else(|_| exchange_client.preferred_solusd_pair().into());
-                    let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let pair =
+                        selected_pair(exchange, exchange_client.as_ref(), arg_matches, token)
+                            .await?;
+                    let amount = quote_order_amount_of(arg_matches).unwrap_or_else(|| {
+                        OrderAmount::Base(value_t_or_exit!(arg_matches, "amount", f64))
+                    });
                     let if_balance_exceeds = value_t!(arg_matches, "if_balance_exceeds", f64)
                         .ok()
                         .map(|x| token.amount(x));
                     let if_price_over = value_t!(arg_matches, "if_price_over", f64).ok();
+                    let expiry = value_t!(arg_matches, "expire_after", i64)
+                        .ok()
+                        .map(|hours| Utc::now() + chrono::Duration::hours(hours));
                     let if_price_over_basis = arg_matches.is_present("if_price_over_basis");
                     let price_floor = value_t!(arg_matches, "price_floor", f64).ok();
                     let lot_numbers = lot_numbers_of(arg_matches, "lot_numbers");
@@ -6737,6 +19122,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         price_floor,
                         lot_selection_method,
                         lot_numbers,
+                        arg_matches.is_present("exact"),
+                        order_rounding_of(arg_matches),
+                        arg_matches.is_present("skip_preflight"),
+                        expiry,
+                        arg_matches.is_present("override_limits"),
+                        arg_matches.value_of("unwind"),
+                        "sell",
                         &notifier,
                     )
                     .await?;
@@ -6749,6 +19141,226 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     )
                     .await?;
                 }
+                ("peg", Some(arg_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let pair =
+                        selected_pair(exchange, exchange_client.as_ref(), arg_matches, token)
+                            .await?;
+                    let side = match arg_matches.value_of("side").unwrap() {
+                        "buy" => OrderSide::Buy,
+                        "sell" => OrderSide::Sell,
+                        _ => unreachable!(),
+                    };
+                    if side == OrderSide::Buy && arg_matches.is_present("unwind") {
+                        return Err(
+                            ConfigurationError("--unwind only applies to sells".into()).into()
+                        );
+                    }
+                    let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                    let offset = match value_t!(arg_matches, "offset_bps", f64) {
+                        Ok(bps) => PegOffset::Bps(bps),
+                        Err(_) => PegOffset::Ticks(
+                            value_t!(arg_matches, "offset_ticks", u32).unwrap_or(1),
+                        ),
+                    };
+                    let tolerance_ticks = value_t_or_exit!(arg_matches, "tolerance", u32);
+                    let price_limit = value_t!(arg_matches, "price_limit", f64).ok();
+                    let interval =
+                        Duration::from_secs(value_t_or_exit!(arg_matches, "interval", u64));
+                    let lot_selection_method =
+                        value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+
+                    process_exchange_peg(
+                        &mut db,
+                        exchange,
+                        exchange_client.as_ref(),
+                        token,
+                        pair,
+                        side,
+                        amount,
+                        offset,
+                        tolerance_ticks,
+                        price_limit,
+                        lot_selection_method,
+                        interval,
+                        arg_matches.value_of("unwind"),
+                        &rpc_client,
+                        &Cancellation::on_ctrl_c(),
+                        &notifier,
+                    )
+                    .await?;
+                }
+                ("unwind", Some(unwind_matches)) => match unwind_matches.subcommand() {
+                    ("create", Some(arg_matches)) => {
+                        let name = value_t_or_exit!(arg_matches, "name", String);
+                        let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                        let ui_amount = value_t_or_exit!(arg_matches, "amount", f64);
+                        if !db.add_unwind_target(UnwindTarget {
+                            name: name.clone(),
+                            exchange,
+                            token,
+                            ui_amount,
+                            created: Utc::now(),
+                            order_ids: vec![],
+                        })? {
+                            return Err(ConfigurationError(format!(
+                                "Unwind target {name} already exists"
+                            ))
+                            .into());
+                        }
+                        println!(
+                            "Unwind target {name} created: sell {}{ui_amount} on {exchange:?}",
+                            token.symbol()
+                        );
+                    }
+                    ("status", Some(arg_matches)) => {
+                        let name = arg_matches.value_of("name");
+                        let targets = db
+                            .unwind_targets()
+                            .into_iter()
+                            .filter(|target| {
+                                target.exchange == exchange
+                                    && name.map_or(true, |name| target.name == name)
+                            })
+                            .collect::<Vec<_>>();
+                        if targets.is_empty() {
+                            return match name {
+                                Some(name) => Err(ConfigurationError(format!(
+                                    "No unwind target named {name} on {exchange:?}"
+                                ))
+                                .into()),
+                                None => {
+                                    println!("No unwind targets on {exchange:?}");
+                                    Ok(())
+                                }
+                            };
+                        }
+
+                        let exchange_client = exchange_client()?;
+                        for target in targets {
+                            // The journal only records the fills of an order once it closes, so
+                            // the exchange is asked about the open ones
+                            let mut open_fills = HashMap::new();
+                            for order in db
+                                .open_orders(Some(exchange), Some(OrderSide::Sell))
+                                .into_iter()
+                                .filter(|order| target.order_ids.contains(&order.order_id))
+                            {
+                                let pair =
+                                    TradingPair::from_exchange_symbol(exchange, &order.pair)?;
+                                let order_status =
+                                    exchange_client.order_status(&pair, &order.order_id).await?;
+                                open_fills.insert(order.order_id, order_status.filled_amount);
+                            }
+                            let progress = db.unwind_progress(&target, &open_fills);
+
+                            let symbol = target.token.symbol();
+                            println!(
+                                "{}: sell {symbol}{} of {}, created {}",
+                                target.name,
+                                target.ui_amount,
+                                target.token,
+                                HumanTime::from(target.created)
+                            );
+                            println!(
+                                "  Sold: {symbol}{}, in {} open orders: {symbol}{}, remaining: \
+                                 {symbol}{}",
+                                progress.filled,
+                                open_fills.len(),
+                                progress.open,
+                                progress.remaining()
+                            );
+                        }
+                    }
+                    ("remove", Some(arg_matches)) => {
+                        let name = value_t_or_exit!(arg_matches, "name", String);
+                        match db.remove_unwind_target(&name)? {
+                            Some(target) => println!(
+                                "Removed unwind target {name}{}",
+                                if target.order_ids.is_empty() {
+                                    String::new()
+                                } else {
+                                    format!(", its orders were {}", target.order_ids.join(", "))
+                                }
+                            ),
+                            None => {
+                                return Err(ConfigurationError(format!(
+                                    "No unwind target named {name}"
+                                ))
+                                .into())
+                            }
+                        }
+                    }
+                    _ => unreachable!(),
+                },
+                ("market-make", Some(arg_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let pair =
+                        selected_pair(exchange, exchange_client.as_ref(), arg_matches, token)
+                            .await?;
+                    let symbol = pair.to_exchange_symbol(exchange);
+
+                    if arg_matches.is_present("report") {
+                        let session = db.quoting_session(exchange, &symbol).ok_or_else(|| {
+                            format!("No quoting session for {pair} on {exchange:?}")
+                        })?;
+                        let mid_price = exchange_client
+                            .bid_ask(&pair)
+                            .await
+                            .ok()
+                            .map(|bid_ask| (bid_ask.bid_price + bid_ask.ask_price) / 2.);
+                        print_quoting_report(&session, &pair, mid_price);
+                        return Ok(());
+                    }
+                    if arg_matches.is_present("new_session") {
+                        if let Some(session) = db.quoting_session(exchange, &symbol) {
+                            print_quoting_report(&session, &pair, None);
+                        }
+                        db.set_quoting_session(QuotingSession {
+                            exchange,
+                            pair: symbol,
+                            start_time: Utc::now(),
+                            fills: vec![],
+                            halted: None,
+                        })?;
+                    }
+
+                    let size = value_t_or_exit!(arg_matches, "size", f64);
+                    let offset = match value_t!(arg_matches, "offset_bps", f64) {
+                        Ok(bps) => PegOffset::Bps(bps),
+                        Err(_) => PegOffset::Ticks(
+                            value_t!(arg_matches, "offset_ticks", u32).unwrap_or(1),
+                        ),
+                    };
+                    let tolerance_ticks = value_t_or_exit!(arg_matches, "tolerance", u32);
+                    let max_inventory = value_t_or_exit!(arg_matches, "max_inventory", f64);
+                    let max_loss = value_t_or_exit!(arg_matches, "max_loss", f64);
+                    let interval =
+                        Duration::from_secs(value_t_or_exit!(arg_matches, "interval", u64));
+                    let lot_selection_method =
+                        value_t_or_exit!(arg_matches, "lot_selection", LotSelectionMethod);
+
+                    process_exchange_market_make(
+                        &mut db,
+                        exchange,
+                        exchange_client.as_ref(),
+                        token,
+                        pair,
+                        size,
+                        offset,
+                        tolerance_ticks,
+                        max_inventory,
+                        max_loss,
+                        lot_selection_method,
+                        interval,
+                        &rpc_client,
+                        &Cancellation::on_ctrl_c(),
+                        &notifier,
+                    )
+                    .await?;
+                }
                 ("lend", Some(arg_matches)) => {
                     let coin = value_t_or_exit!(arg_matches, "coin", String);
                     let amount = arg_matches.value_of("amount");
@@ -6786,6 +19398,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 lending_info.estimate_rate,
                             );
                             exchange_client.submit_lending_offer(&coin, amount).await?;
+                            db.record_lending_offer(LendingOfferRecord {
+                                exchange,
+                                coin: coin.clone(),
+                                amount,
+                                estimate_rate: lending_info.estimate_rate,
+                                time: Utc::now(),
+                            })?;
                             println!("{msg}");
                             notifier.send(&format!("{exchange:?}: {msg}")).await;
                         } else {
@@ -6814,6 +19433,46 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         println!("Previous rate: {:.1}%", lending_info.previous_rate);
                     }
                 }
+                ("lending-rates", Some(arg_matches)) => {
+                    let mut all_lending_info = exchange_client()?
+                        .all_lending_info()
+                        .await?
+                        .into_iter()
+                        .collect::<Vec<_>>();
+                    all_lending_info
+                        .sort_by(|a, b| b.1.estimate_rate.total_cmp(&a.1.estimate_rate));
+
+                    if arg_matches.is_present("json") {
+                        println!("{}", serde_json::to_string_pretty(&all_lending_info)?);
+                    } else {
+                        println!(
+                            "Coin   {:>20} {:>20} {:>20} {:>9} {:>9}",
+                            "Available", "Offered", "Locked", "Rate", "Previous"
+                        );
+                        for (coin, lending_info) in all_lending_info {
+                            println!(
+                                "{coin:<6} {:>20} {:>20} {:>20} {:>8.1}% {:>8.1}%",
+                                format_coin_amount(exchange, &coin, lending_info.lendable, true),
+                                format_coin_amount(exchange, &coin, lending_info.offered, true),
+                                format_coin_amount(exchange, &coin, lending_info.locked, true),
+                                lending_info.estimate_rate,
+                                lending_info.previous_rate,
+                            );
+                        }
+                    }
+                }
+                ("lending-performance", Some(arg_matches)) => {
+                    let weeks = value_t_or_exit!(arg_matches, "weeks", u32).max(1);
+                    let shortfall = value_t_or_exit!(arg_matches, "shortfall", f64) / 100.;
+                    process_exchange_lending_performance(
+                        &db,
+                        exchange,
+                        exchange_client()?.as_ref(),
+                        weeks,
+                        shortfall,
+                    )
+                    .await?;
+                }
                 ("lending-history", Some(lending_history_matches)) => {
                     let exchange_client = exchange_client()?;
                     let lending_history = match lending_history_matches.subcommand() {
@@ -6824,10 +19483,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             let end_date =
                                 naivedate_of(&value_t_or_exit!(arg_matches, "end_date", String))
                                     .unwrap();
-                            exchange_client.get_lending_history(LendingHistory::Range {
+                            exchange_client.get_lending_history(LendingHistory::days(
                                 start_date,
                                 end_date,
-                            })
+                                reporting_time_zone()?,
+                            ))
                         }
                         ("previous", Some(arg_matches)) => {
                             let days = value_t_or_exit!(arg_matches, "days", usize);
@@ -6841,6 +19501,229 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         println!("{}: {}", coin, amount.separated_string_with_fixed_place(2));
                     }
                 }
+                ("subaccounts", Some(subaccounts_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    match subaccounts_matches.subcommand() {
+                        ("list", Some(arg_matches)) => {
+                            let subaccounts = exchange_client.list_subaccounts().await?;
+
+                            if arg_matches.is_present("json") {
+                                println!("{}", serde_json::to_string_pretty(&subaccounts)?);
+                            } else {
+                                for subaccount in subaccounts {
+                                    println!(
+                                        "{} {} ({}){}",
+                                        if subaccount.selected { "*" } else { " " },
+                                        subaccount.name,
+                                        subaccount.id,
+                                        if subaccount.default { " [default]" } else { "" },
+                                    );
+                                }
+                            }
+                        }
+                        ("transfer", Some(arg_matches)) => {
+                            let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                            let coin = value_t_or_exit!(arg_matches, "coin", String);
+                            let from = value_t_or_exit!(arg_matches, "from", String);
+                            let to = value_t_or_exit!(arg_matches, "to", String);
+
+                            exchange_client
+                                .transfer_between_subaccounts(&coin, amount, &from, &to)
+                                .await?;
+                            let msg = format!("Moved {amount} {coin} from {from} to {to}");
+                            println!("{msg}");
+                            notifier.send(&format!("{exchange:?}: {msg}")).await;
+                        }
+                        _ => unreachable!(),
+                    }
+                }
+                ("proceeds", Some(arg_matches)) => {
+                    let exchange_clients = vec![(exchange, exchange_client()?)];
+                    process_proceeds_matches(&exchange_clients, arg_matches).await?;
+                }
+                ("fees", Some(_arg_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    let fee_schedule = exchange_client.fee_schedule().await?;
+
+                    println!("Maker: {:.3}%", fee_schedule.maker_rate * 100.);
+                    println!("Taker: {:.3}%", fee_schedule.taker_rate * 100.);
+                    if let Some(discount) = fee_schedule.discount {
+                        println!("Discount: {discount}");
+                    }
+
+                    if exchange.capabilities().supports_volume_tiers {
+                        let volume_tier = exchange_client.volume_tier_info().await?;
+                        println!(
+                            "30-day volume: ${}",
+                            volume_tier.volume_30d.separated_string_with_fixed_place(2)
+                        );
+                        match (&volume_tier.next_tier, volume_tier.volume_to_next_tier()) {
+                            (Some(next_tier), Some(volume_to_next_tier)) => println!(
+                                "Next tier: maker {:.3}%, taker {:.3}% from ${} of volume, \
+                                 ${} to go",
+                                next_tier.maker_rate * 100.,
+                                next_tier.taker_rate * 100.,
+                                next_tier
+                                    .volume_threshold
+                                    .separated_string_with_fixed_place(0),
+                                volume_to_next_tier.separated_string_with_fixed_place(2),
+                            ),
+                            _ => println!("Next tier: none reported"),
+                        }
+                    }
+                }
+                ("quote", Some(arg_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    let token = MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                    let pair =
+                        selected_pair(exchange, exchange_client.as_ref(), arg_matches, token)
+                            .await?;
+                    let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                    let side = if arg_matches.is_present("buy") {
+                        OrderSide::Buy
+                    } else {
+                        OrderSide::Sell
+                    };
+
+                    process_exchange_quote(exchange, exchange_client.as_ref(), pair, side, amount)
+                        .await?;
+                }
+                ("ledger", Some(arg_matches)) => {
+                    let start_date =
+                        naivedate_of(&value_t_or_exit!(arg_matches, "start_date", String)).unwrap();
+                    let end_date =
+                        naivedate_of(&value_t_or_exit!(arg_matches, "end_date", String)).unwrap();
+                    let json = arg_matches.is_present("json");
+                    let output = value_t!(arg_matches, "output", PathBuf).ok();
+                    let start_time = Local
+                        .from_local_date(&start_date)
+                        .unwrap()
+                        .and_hms(0, 0, 0)
+                        .with_timezone(&Utc);
+                    let end_time = Local
+                        .from_local_date(&end_date)
+                        .unwrap()
+                        .and_hms(23, 59, 59)
+                        .with_timezone(&Utc);
+
+                    let exchange_client = exchange_client()?;
+                    process_exchange_ledger(
+                        exchange,
+                        exchange_client.as_ref(),
+                        start_time,
+                        end_time,
+                        json,
+                        output,
+                    )
+                    .await?;
+                }
+                ("stake", Some(stake_matches)) => {
+                    let exchange_client = exchange_client()?;
+                    match stake_matches.subcommand() {
+                        ("info", Some(arg_matches)) => {
+                            let coin = value_t_or_exit!(arg_matches, "coin", String);
+                            let json = arg_matches.is_present("json");
+                            let staking_info = exchange_client.get_staking_info(&coin).await?;
+
+                            if json {
+                                println!("{}", serde_json::to_string_pretty(&staking_info)?);
+                            } else if staking_info.is_empty() {
+                                println!("No staking strategies available for {coin}");
+                            } else {
+                                print_staking_info(&staking_info);
+                            }
+                        }
+                        ("allocate", Some(arg_matches)) | ("deallocate", Some(arg_matches)) => {
+                            let operation = if stake_matches.subcommand_name() == Some("allocate") {
+                                StakingOperation::Allocate
+                            } else {
+                                StakingOperation::Deallocate
+                            };
+                            let coin = value_t_or_exit!(arg_matches, "coin", String);
+                            let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                            let wait = arg_matches.is_present("wait");
+                            let timeout = Duration::from_secs(
+                                value_t_or_exit!(arg_matches, "timeout", u64) * 60,
+                            );
+
+                            exchange_client
+                                .submit_staking_request(&coin, operation, amount)
+                                .await?;
+                            let msg =
+                                format!("Staking request submitted: {operation:?} {amount} {coin}");
+                            println!("{msg}");
+                            notifier.send(&format!("{exchange:?}: {msg}")).await;
+
+                            if wait {
+                                wait_for_staking_request(
+                                    exchange_client.as_ref(),
+                                    &coin,
+                                    operation,
+                                    &Cancellation::on_ctrl_c().with_timeout(timeout),
+                                )
+                                .await?;
+                            }
+                        }
+                        ("rewards", Some(rewards_matches)) => {
+                            let (staking_history, arg_matches) = match rewards_matches.subcommand()
+                            {
+                                ("range", Some(arg_matches)) => {
+                                    let start_date = naivedate_of(&value_t_or_exit!(
+                                        arg_matches,
+                                        "start_date",
+                                        String
+                                    ))
+                                    .unwrap();
+                                    let end_date = naivedate_of(&value_t_or_exit!(
+                                        arg_matches,
+                                        "end_date",
+                                        String
+                                    ))
+                                    .unwrap();
+                                    (
+                                        LendingHistory::days(
+                                            start_date,
+                                            end_date,
+                                            reporting_time_zone()?,
+                                        ),
+                                        arg_matches,
+                                    )
+                                }
+                                ("previous", Some(arg_matches)) => {
+                                    let days = value_t_or_exit!(arg_matches, "days", usize);
+                                    (LendingHistory::Previous { days }, arg_matches)
+                                }
+                                _ => unreachable!(),
+                            };
+                            let coin = value_t_or_exit!(arg_matches, "coin", String);
+                            let by_month = arg_matches.is_present("by_month");
+
+                            let rewards = exchange_client
+                                .get_staking_rewards(&coin, staking_history)
+                                .await?;
+
+                            let time_zone = reporting_time_zone()?;
+                            let mut periods = BTreeMap::<String, f64>::default();
+                            for reward in &rewards {
+                                let date = time_zone.date_of(reward.time);
+                                let period = if by_month {
+                                    date.format("%Y-%m").to_string()
+                                } else {
+                                    date.to_string()
+                                };
+                                *periods.entry(period).or_default() += reward.amount;
+                            }
+                            for (period, amount) in &periods {
+                                println!("{period}: {amount} {coin}");
+                            }
+                            println!(
+                                "Total: {} {coin}",
+                                rewards.iter().map(|reward| reward.amount).sum::<f64>()
+                            );
+                        }
+                        _ => unreachable!(),
+                    }
+                }
                 ("sync", Some(_arg_matches)) => {
                     let exchange_client = exchange_client()?;
                     process_sync_exchange(
@@ -6852,6 +19735,172 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     )
                     .await?;
                 }
+                ("paper", Some(paper_matches)) => {
+                    let paper_client = paper_exchange_client()?;
+                    match paper_matches.subcommand() {
+                        ("fund", Some(arg_matches)) => {
+                            let coin = value_t_or_exit!(arg_matches, "coin", String);
+                            let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                            paper_client.fund(&coin, amount).await?;
+                            println!(
+                                "Simulated a deposit of {amount} {}",
+                                coin.to_ascii_uppercase()
+                            );
+                        }
+                        ("withdraw", Some(arg_matches)) => {
+                            let coin = value_t_or_exit!(arg_matches, "coin", String);
+                            let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                            paper_client.withdraw(&coin, amount).await?;
+                            println!(
+                                "Simulated a withdrawal of {amount} {}",
+                                coin.to_ascii_uppercase()
+                            );
+                        }
+                        (side @ ("buy" | "sell"), Some(arg_matches)) => {
+                            let side = if side == "buy" {
+                                OrderSide::Buy
+                            } else {
+                                OrderSide::Sell
+                            };
+                            let token =
+                                MaybeToken::from(value_t!(arg_matches, "token", Token).ok());
+                            let pair =
+                                selected_pair(exchange, &paper_client, arg_matches, token).await?;
+                            let amount = value_t_or_exit!(arg_matches, "amount", f64);
+                            let price = value_t_or_exit!(arg_matches, "at", f64);
+
+                            // Paper orders move no funds, so skip the spending limits
+                            let (order_id, _client_order_id) = place_tagged_order(
+                                exchange,
+                                &paper_client,
+                                &pair,
+                                side,
+                                price,
+                                amount,
+                                arg_matches.is_present("taker"),
+                                None,
+                                OrderChecks::default(),
+                                "paper",
+                                &[&SpreadGuard::Live(&paper_client)],
+                            )
+                            .await?;
+                            let order_status = paper_client.order_status(&pair, &order_id).await?;
+                            println!(
+                                "Paper order {order_id}: {side} {} {pair} at {} ({}), \
+                                 filled {}",
+                                order_status.amount,
+                                format_quote_amount(&order_status.price.to_string(), &pair.quote),
+                                if order_status.open { "open" } else { "closed" },
+                                order_status.filled_amount,
+                            );
+                        }
+                        ("cancel", Some(arg_matches)) => {
+                            let order_id = value_t_or_exit!(arg_matches, "order_id", String);
+                            let pair = paper_client
+                                .orders()
+                                .await?
+                                .remove(&order_id)
+                                .map(|order| order.pair)
+                                .ok_or_else(|| format!("Unknown paper order: {order_id}"))?;
+                            let order_status =
+                                paper_client.cancel_order_final(&pair, &order_id).await?;
+                            println!(
+                                "Cancelled paper order {order_id}, filled {}",
+                                order_status.filled_amount
+                            );
+                        }
+                        ("orders", Some(arg_matches)) => {
+                            let all = arg_matches.is_present("all");
+                            for (order_id, order) in paper_client.orders().await? {
+                                if order.open || all {
+                                    let fee_usd = match order.fee_usd {
+                                        Some(fee_usd) if !is_usd_equivalent(&order.pair.quote) => {
+                                            format!(" (${})", format_decimals(fee_usd, 2, true))
+                                        }
+                                        _ => String::new(),
+                                    };
+                                    println!(
+                                        "{order_id}: {}: {} {} at {} ({}), filled {}, \
+                                         fee {} {}{}, last update {}",
+                                        order.pair,
+                                        order.side,
+                                        order.amount,
+                                        format_quote_amount(
+                                            &order.price.to_string(),
+                                            &order.pair.quote
+                                        ),
+                                        if order.open { "open" } else { "closed" },
+                                        order.filled_amount,
+                                        order.fee,
+                                        order.pair.quote,
+                                        fee_usd,
+                                        order.last_update,
+                                    );
+                                }
+                            }
+                        }
+                        ("report", Some(arg_matches)) => {
+                            let report = paper_client.report().await?;
+                            if arg_matches.is_present("json") {
+                                println!("{}", serde_json::to_string_pretty(&report)?);
+                                return Ok(());
+                            }
+
+                            for (coin, amount) in &report.balances {
+                                if *amount != 0. {
+                                    println!(
+                                        "{coin:<6} {:>20}",
+                                        amount.separated_string_with_fixed_place(8)
+                                    );
+                                }
+                            }
+                            println!();
+                            println!(
+                                "Invested:            ${}",
+                                report.invested_usd.separated_string_with_fixed_place(2)
+                            );
+                            println!(
+                                "Value:               ${}",
+                                report.value_usd.separated_string_with_fixed_place(2)
+                            );
+                            println!(
+                                "Profit/loss:         ${}{}",
+                                report.pnl_usd().separated_string_with_fixed_place(2),
+                                if report.invested_usd > 0. {
+                                    format!(
+                                        " ({:.2}%)",
+                                        report.pnl_usd() / report.invested_usd * 100.
+                                    )
+                                } else {
+                                    String::new()
+                                }
+                            );
+                            println!(
+                                "Buy and hold value:  ${}",
+                                report.buy_and_hold_usd.separated_string_with_fixed_place(2)
+                            );
+                            println!(
+                                "Versus buy and hold: ${}",
+                                report
+                                    .vs_buy_and_hold_usd()
+                                    .separated_string_with_fixed_place(2)
+                            );
+                            println!(
+                                "Fees paid:           ${}",
+                                report.fees_usd.separated_string_with_fixed_place(2)
+                            );
+                            for (coin, amount) in &report.fees {
+                                if *amount != 0. {
+                                    println!(
+                                        "  {coin:<6} {:>20}",
+                                        amount.separated_string_with_fixed_place(8)
+                                    );
+                                }
+                            }
+                        }
+                        _ => unreachable!(),
+                    }
+                }
                 ("api", Some(api_matches)) => {
                     match api_matches.subcommand() {
                         ("show", Some(_arg_matches)) => {
@@ -6907,3 +19956,56 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     metrics::send(db.get_metrics_config()).await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_changed_deposit_address_is_refused_until_accepted() {
+        let db_path =
+            std::env::temp_dir().join(format!("sys-deposit-address-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        let mut db = db::new(&db_path).unwrap();
+        let token = MaybeToken::SOL();
+        let pinned = Pubkey::from_str("CiwjEStzDKR8j1uQgVvU6hFtt8thAK3aZMDN3X6yzW5q").unwrap();
+        let rotated = Pubkey::new_unique();
+        let check = |db: &mut Db, address| {
+            check_deposit_address(db, Exchange::Kraken, "main", token, address)
+        };
+
+        // The first address seen is pinned, and is accepted from then on
+        assert_eq!(check(&mut db, pinned).unwrap(), pinned);
+        assert_eq!(check(&mut db, pinned).unwrap(), pinned);
+
+        // A different address is refused, naming both, and leaves the pin alone
+        let err = check(&mut db, rotated).unwrap_err().to_string();
+        assert!(err.contains(&pinned.to_string()), "{err}");
+        assert!(err.contains(&format!("--accept {rotated}")), "{err}");
+        assert_eq!(
+            db.pinned_deposit_address(Exchange::Kraken, "main", token)
+                .unwrap()
+                .address,
+            pinned
+        );
+        // Each account's address is pinned separately
+        assert_eq!(
+            check_deposit_address(&mut db, Exchange::Kraken, "other", token, rotated).unwrap(),
+            rotated
+        );
+
+        // Once accepted the new address is used, and the one it replaced is recorded
+        db.pin_deposit_address(Exchange::Kraken, "main", token, rotated)
+            .unwrap();
+        assert_eq!(check(&mut db, rotated).unwrap(), rotated);
+        assert!(check(&mut db, pinned).is_err());
+        let accepted = db::new(&db_path)
+            .unwrap()
+            .pinned_deposit_address(Exchange::Kraken, "main", token)
+            .unwrap();
+        std::fs::remove_dir_all(&db_path).unwrap();
+        assert_eq!(accepted.address, rotated);
+        assert_eq!(accepted.replaced.len(), 1);
+        assert_eq!(accepted.replaced[0].address, pinned);
+    }
+}
@@ -0,0 +1,607 @@
+//! One lifecycle for the orders strategies place: an order is recorded as an `Intent` before it's
+//! sent, then moves through `Submitted`, `Open` and `PartiallyFilled` to `Closed` as `Filled`,
+//! `Cancelled`, `Rejected` or `Expired`.  Transitions are driven by the order statuses the
+//! exchange reports, and each one is saved as it happens, so that `recover()` can reconcile the
+//! orders an interrupted run left unsettled without ever placing one twice
+use {
+    crate::db::{Db, DbResult, ManagedOrder, ManagedOrderOutcome, ManagedOrderState},
+    chrono::prelude::*,
+    std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    },
+    sys::{
+        exchange::{
+            new_client_id, place_order_with_client_id, Exchange, ExchangeClient, OrderChecks,
+            OrderId, OrderStatus, TradingPair,
+        },
+        pre_trade::{OrderIntent, PreTradeCheck, Rejection},
+    },
+};
+
+// Interval `await_terminal()` polls the exchange at
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records `intent` as a managed order that hasn't been sent.  `submit()` prepares, sends and
+/// records an order in one step, the separate steps let several orders be sent at once
+pub fn prepare(
+    db: &mut Db,
+    intent: &OrderIntent,
+    taker: bool,
+    expiry: Option<DateTime<Utc>>,
+) -> DbResult<ManagedOrder> {
+    let now = Utc::now();
+    let managed_order = ManagedOrder {
+        client_order_id: new_client_id(),
+        exchange: intent.exchange,
+        pair: intent.pair.to_exchange_symbol(intent.exchange),
+        side: intent.side,
+        price: intent.price,
+        amount: intent.amount,
+        taker,
+        strategy: intent.strategy.clone(),
+        expiry,
+        order_id: None,
+        filled_amount: 0.,
+        state: ManagedOrderState::Intent,
+        created: now,
+        updated: now,
+        detail: None,
+    };
+    db.set_managed_order(managed_order.clone())?;
+    Ok(managed_order)
+}
+
+/// Sends a prepared order to the exchange, tagged with its client order id, once
+/// `pre_trade_checks` pass it.  The order must already be rounded as `checks` would round it, so
+/// that it's sent as recorded
+pub async fn send(
+    exchange_client: &dyn ExchangeClient,
+    managed_order: &ManagedOrder,
+    checks: OrderChecks,
+    pre_trade_checks: &[&dyn PreTradeCheck],
+) -> Result<OrderId, Box<dyn std::error::Error>> {
+    place_order_with_client_id(
+        managed_order.exchange,
+        exchange_client,
+        &managed_pair(managed_order)?,
+        managed_order.side,
+        managed_order.price,
+        managed_order.amount,
+        managed_order.taker,
+        managed_order.expiry,
+        &managed_order.client_order_id,
+        checks,
+        &managed_order.strategy,
+        pre_trade_checks,
+    )
+    .await
+}
+
+/// Records the `result` of sending a prepared order.  A failed placement is looked up on the
+/// exchange, and only recorded as rejected once the exchange confirms it never received the
+/// order, unless a pre-trade check stopped it from being sent at all.  When that can't be
+/// confirmed the order stays an `Intent` for `recover()` to settle
+pub async fn record_sent(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    managed_order: ManagedOrder,
+    result: Result<OrderId, Box<dyn std::error::Error>>,
+) -> Result<ManagedOrder, Box<dyn std::error::Error>> {
+    let err = match result {
+        Ok(order_id) => {
+            let managed_order = ManagedOrder {
+                order_id: Some(order_id),
+                state: ManagedOrderState::Submitted,
+                updated: Utc::now(),
+                ..managed_order
+            };
+            db.set_managed_order(managed_order.clone())?;
+            return Ok(managed_order);
+        }
+        Err(err) => err,
+    };
+    if err.is::<Rejection>() {
+        reject(db, managed_order, err.to_string())?;
+        return Err(err);
+    }
+
+    match exchange_client
+        .order_status_by_client_id(
+            &managed_pair(&managed_order)?,
+            &managed_order.client_order_id,
+        )
+        .await
+    {
+        Ok(Some((order_id, order_status))) => Ok(observe(
+            db,
+            ManagedOrder {
+                order_id: Some(order_id),
+                ..managed_order
+            },
+            &order_status,
+        )?),
+        Ok(None) => {
+            reject(db, managed_order, err.to_string())?;
+            Err(err)
+        }
+        Err(lookup_err) => {
+            eprintln!(
+                "Unable to determine whether order {} was placed, it will be reconciled on the \
+                 next sync: {lookup_err}",
+                managed_order.client_order_id
+            );
+            Err(err)
+        }
+    }
+}
+
+/// Places the order described by `intent` once `pre_trade_checks` pass it, returning it once the
+/// exchange has accepted it
+pub async fn submit(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    intent: &OrderIntent,
+    taker: bool,
+    expiry: Option<DateTime<Utc>>,
+    checks: OrderChecks,
+    pre_trade_checks: &[&dyn PreTradeCheck],
+) -> Result<ManagedOrder, Box<dyn std::error::Error>> {
+    let managed_order = prepare(db, intent, taker, expiry)?;
+    let result = send(exchange_client, &managed_order, checks, pre_trade_checks).await;
+    record_sent(db, exchange_client, managed_order, result).await
+}
+
+/// Moves the resting order `id` to `price`, on exchanges that support amending orders
+pub async fn amend(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    id: &str,
+    price: f64,
+) -> Result<ManagedOrder, Box<dyn std::error::Error>> {
+    let managed_order = managed_order(db, id)?;
+    let order_id = match (&managed_order.order_id, managed_order.state.is_terminal()) {
+        (Some(order_id), false) => order_id,
+        _ => {
+            return Err(
+                format!("Order {id} is {}, it can't be amended", managed_order.state).into(),
+            )
+        }
+    };
+    exchange_client
+        .amend_order(&managed_pair(&managed_order)?, order_id, price)
+        .await?;
+    let managed_order = ManagedOrder {
+        price,
+        updated: Utc::now(),
+        ..managed_order
+    };
+    db.set_managed_order(managed_order.clone())?;
+    Ok(managed_order)
+}
+
+/// Cancels the order `id`, returning it closed with whatever filled before the cancel landed
+pub async fn cancel(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    id: &str,
+) -> Result<(ManagedOrder, OrderStatus), Box<dyn std::error::Error>> {
+    let (managed_order, _) = poll(db, exchange_client, id).await?;
+    let order_id = managed_order.order_id.clone().expect("placed");
+    let order_status = exchange_client
+        .cancel_order_final(&managed_pair(&managed_order)?, &order_id)
+        .await?;
+    Ok((observe(db, managed_order, &order_status)?, order_status))
+}
+
+/// The order `id` as the exchange currently reports it.  Fails for an order that was never placed
+pub async fn poll(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    id: &str,
+) -> Result<(ManagedOrder, OrderStatus), Box<dyn std::error::Error>> {
+    let managed_order = managed_order(db, id)?;
+    match refresh(db, exchange_client, managed_order).await? {
+        (managed_order, Some(order_status)) => Ok((managed_order, order_status)),
+        (managed_order, None) => Err(format!(
+            "Order {id} was never placed{}",
+            managed_order
+                .detail
+                .map(|detail| format!(": {detail}"))
+                .unwrap_or_default()
+        )
+        .into()),
+    }
+}
+
+/// Polls the order `id` until it closes, cancelling it once `timeout` passes or it expires
+pub async fn await_terminal(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    id: &str,
+    timeout: Duration,
+) -> Result<(ManagedOrder, OrderStatus), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    loop {
+        let (managed_order, order_status) = poll(db, exchange_client, id).await?;
+        if managed_order.state.is_terminal() {
+            return Ok((managed_order, order_status));
+        }
+        if start.elapsed() >= timeout || expired(&managed_order) {
+            return cancel(db, exchange_client, id).await;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Reconciles the unsettled managed orders on `exchange` against it, returning the ones that
+/// changed.  An order left an `Intent` by an interrupted run is looked up by its client order id,
+/// and recorded as rejected if the exchange never received it, so it's never placed again.  Orders
+/// that are also open orders in the database are left to be reconciled as those are synced
+pub async fn recover(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    exchange: Exchange,
+) -> Result<Vec<ManagedOrder>, Box<dyn std::error::Error>> {
+    let open_order_ids = db
+        .open_orders(Some(exchange), None)
+        .into_iter()
+        .map(|order| order.order_id)
+        .collect::<HashSet<_>>();
+    let mut recovered = vec![];
+    for managed_order in db.managed_orders(exchange, true) {
+        if matches!(&managed_order.order_id, Some(order_id) if open_order_ids.contains(order_id)) {
+            continue;
+        }
+        let id = managed_order.client_order_id.clone();
+        let previous_state = managed_order.state;
+        match refresh(db, exchange_client, managed_order).await {
+            Ok((managed_order, _)) if managed_order.state != previous_state => {
+                if let (ManagedOrderState::Intent, Some(order_id)) =
+                    (previous_state, &managed_order.order_id)
+                {
+                    eprintln!(
+                        "Order {order_id} was placed by an interrupted run before it was \
+                         recorded, import it with `import-orders` or cancel it"
+                    );
+                }
+                recovered.push(managed_order);
+            }
+            Ok(_) => {}
+            Err(err) => eprintln!("Unable to reconcile order {id}: {err}"),
+        }
+    }
+    Ok(recovered)
+}
+
+fn managed_order(db: &Db, id: &str) -> Result<ManagedOrder, Box<dyn std::error::Error>> {
+    db.managed_order(id)
+        .ok_or_else(|| format!("Unknown managed order: {id}").into())
+}
+
+fn managed_pair(managed_order: &ManagedOrder) -> Result<TradingPair, Box<dyn std::error::Error>> {
+    TradingPair::from_exchange_symbol(managed_order.exchange, &managed_order.pair)
+}
+
+fn expired(managed_order: &ManagedOrder) -> bool {
+    managed_order
+        .expiry
+        .map_or(false, |expiry| expiry <= Utc::now())
+}
+
+// The state `order_status` puts an order in.  A closed order that didn't fill completely was
+// cancelled, or expired if it was past its expiry
+fn next_state(managed_order: &ManagedOrder, order_status: &OrderStatus) -> ManagedOrderState {
+    if order_status.open {
+        if order_status.filled_amount > 0. {
+            ManagedOrderState::PartiallyFilled
+        } else {
+            ManagedOrderState::Open
+        }
+    } else if order_status.amount - order_status.filled_amount < f64::EPSILON {
+        ManagedOrderState::Closed(ManagedOrderOutcome::Filled)
+    } else if expired(managed_order) {
+        ManagedOrderState::Closed(ManagedOrderOutcome::Expired)
+    } else {
+        ManagedOrderState::Closed(ManagedOrderOutcome::Cancelled)
+    }
+}
+
+// Moves `managed_order` to the state `order_status` puts it in.  A closed order stays closed
+fn observe(
+    db: &mut Db,
+    managed_order: ManagedOrder,
+    order_status: &OrderStatus,
+) -> DbResult<ManagedOrder> {
+    if managed_order.state.is_terminal() {
+        return Ok(managed_order);
+    }
+    let observed = ManagedOrder {
+        state: next_state(&managed_order, order_status),
+        price: order_status.price,
+        filled_amount: order_status.filled_amount,
+        detail: order_status
+            .exchange_cancel_reason()
+            .map(|reason| reason.to_string()),
+        ..managed_order.clone()
+    };
+    if observed == managed_order {
+        return Ok(managed_order);
+    }
+    let observed = ManagedOrder {
+        updated: Utc::now(),
+        ..observed
+    };
+    db.set_managed_order(observed.clone())?;
+    Ok(observed)
+}
+
+fn reject(db: &mut Db, managed_order: ManagedOrder, detail: String) -> DbResult<ManagedOrder> {
+    let managed_order = ManagedOrder {
+        state: ManagedOrderState::Closed(ManagedOrderOutcome::Rejected),
+        updated: Utc::now(),
+        detail: Some(detail),
+        ..managed_order
+    };
+    db.set_managed_order(managed_order.clone())?;
+    Ok(managed_order)
+}
+
+// Fetches the status of `managed_order` and records it.  An order without an exchange order id
+// is looked up by its client order id, and rejected if the exchange never received it
+async fn refresh(
+    db: &mut Db,
+    exchange_client: &dyn ExchangeClient,
+    managed_order: ManagedOrder,
+) -> Result<(ManagedOrder, Option<OrderStatus>), Box<dyn std::error::Error>> {
+    let pair = managed_pair(&managed_order)?;
+    let (managed_order, order_status) = match &managed_order.order_id {
+        Some(order_id) => {
+            let order_status = exchange_client.order_status(&pair, order_id).await?;
+            (managed_order, order_status)
+        }
+        None if managed_order.state.is_terminal() => return Ok((managed_order, None)),
+        None => match exchange_client
+            .order_status_by_client_id(&pair, &managed_order.client_order_id)
+            .await?
+        {
+            Some((order_id, order_status)) => (
+                ManagedOrder {
+                    order_id: Some(order_id),
+                    ..managed_order
+                },
+                order_status,
+            ),
+            None => {
+                let managed_order = reject(db, managed_order, "Never reached the exchange".into())?;
+                return Ok((managed_order, None));
+            }
+        },
+    };
+    let managed_order = observe(db, managed_order, &order_status)?;
+    Ok((managed_order, Some(order_status)))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::db,
+        async_trait::async_trait,
+        solana_sdk::pubkey::Pubkey,
+        std::collections::HashMap,
+        sys::{exchange::*, token::MaybeToken, two_factor::TwoFactorCodeProvider},
+    };
+
+    // An exchange that knows the orders it's given, and nothing else
+    #[derive(Default)]
+    struct MockExchangeClient {
+        orders: HashMap<OrderId, OrderStatus>,
+        client_order_ids: HashMap<String, OrderId>,
+    }
+
+    fn unsupported<T>() -> Result<T, Box<dyn std::error::Error>> {
+        Err("Not supported by the mock exchange".into())
+    }
+
+    #[async_trait]
+    impl ExchangeClient for MockExchangeClient {
+        async fn deposit_address(
+            &self,
+            _token: MaybeToken,
+        ) -> Result<Pubkey, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn recent_deposits(
+            &self,
+        ) -> Result<Option<Vec<DepositInfo>>, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn withdrawals(
+            &self,
+            _start: DateTime<Utc>,
+            _end: DateTime<Utc>,
+        ) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn request_withdraw(
+            &self,
+            _address: Pubkey,
+            _token: MaybeToken,
+            _amount: u64,
+            _withdrawal_password: Option<String>,
+            _withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+            _client_reference: &str,
+        ) -> Result<(String, f64), Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn balances(
+            &self,
+        ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn print_market_info(
+            &self,
+            _pair: &TradingPair,
+            _format: MarketInfoFormat,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn bid_ask(&self, _pair: &TradingPair) -> Result<BidAsk, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn place_order(
+            &self,
+            _pair: &TradingPair,
+            _side: OrderSide,
+            _price: f64,
+            _amount: f64,
+            _client_order_id: &str,
+        ) -> Result<OrderId, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn cancel_order(
+            &self,
+            _pair: &TradingPair,
+            _order_id: &OrderId,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn order_status(
+            &self,
+            _pair: &TradingPair,
+            order_id: &OrderId,
+        ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+            self.orders
+                .get(order_id)
+                .cloned()
+                .ok_or_else(|| format!("Unknown order: {order_id}").into())
+        }
+        async fn find_order_by_client_id(
+            &self,
+            _pair: &TradingPair,
+            client_order_id: &str,
+        ) -> Result<Option<OrderId>, Box<dyn std::error::Error>> {
+            Ok(self.client_order_ids.get(client_order_id).cloned())
+        }
+        async fn get_lending_info(
+            &self,
+            _coin: &str,
+        ) -> Result<Option<LendingInfo>, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn all_lending_info(
+            &self,
+        ) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn get_lending_history(
+            &self,
+            _lending_history: LendingHistory,
+        ) -> Result<HashMap<String, f64>, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        async fn submit_lending_offer(
+            &self,
+            _coin: &str,
+            _size: f64,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            unsupported()
+        }
+        fn preferred_solusd_pair(&self) -> TradingPair {
+            TradingPair::new("SOL", "USD")
+        }
+        fn maker_fee_rate(&self) -> f64 {
+            0.
+        }
+        fn taker_fee_rate(&self) -> f64 {
+            0.
+        }
+        async fn pair_infos(&self) -> Result<Vec<PairInfo>, Box<dyn std::error::Error>> {
+            unsupported()
+        }
+    }
+
+    fn open_order_status(amount: f64, filled_amount: f64) -> OrderStatus {
+        OrderStatus {
+            open: true,
+            side: OrderSide::Buy,
+            price: 100.,
+            amount,
+            filled_amount,
+            average_fill_price: Some(100.),
+            cumulative_quote_amount: Some(filled_amount * 100.),
+            last_update: Utc::now().date().naive_local(),
+            fee: None,
+            cancellation: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn recover_reconciles_a_live_intent_and_a_partially_filled_order() {
+        let db_path =
+            std::env::temp_dir().join(format!("sys-managed-order-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&db_path);
+        let mut db = db::new(&db_path).unwrap();
+        let intent = OrderIntent {
+            exchange: Exchange::Kraken,
+            pair: TradingPair::new("SOL", "USD"),
+            side: OrderSide::Buy,
+            price: 100.,
+            amount: 2.,
+            strategy: "buy".into(),
+        };
+        let mut exchange_client = MockExchangeClient::default();
+
+        // Placed by an interrupted run before its order id was recorded
+        let live_intent = prepare(&mut db, &intent, false, None).unwrap();
+        exchange_client
+            .client_order_ids
+            .insert(live_intent.client_order_id.clone(), "live".into());
+        exchange_client
+            .orders
+            .insert("live".into(), open_order_status(2., 0.5));
+
+        // Recorded as submitted, and since partially filled
+        let submitted = ManagedOrder {
+            order_id: Some("submitted".into()),
+            state: ManagedOrderState::Submitted,
+            ..prepare(&mut db, &intent, false, None).unwrap()
+        };
+        db.set_managed_order(submitted.clone()).unwrap();
+        exchange_client
+            .orders
+            .insert("submitted".into(), open_order_status(2., 1.));
+
+        // Never reached the exchange
+        let lost_intent = prepare(&mut db, &intent, false, None).unwrap();
+
+        let recovered = recover(&mut db, &exchange_client, Exchange::Kraken)
+            .await
+            .unwrap();
+        assert_eq!(recovered.len(), 3);
+
+        let live_intent = db.managed_order(&live_intent.client_order_id).unwrap();
+        assert_eq!(live_intent.order_id.as_deref(), Some("live"));
+        assert_eq!(live_intent.state, ManagedOrderState::PartiallyFilled);
+        assert_eq!(live_intent.filled_amount, 0.5);
+
+        let submitted = db.managed_order(&submitted.client_order_id).unwrap();
+        assert_eq!(submitted.state, ManagedOrderState::PartiallyFilled);
+        assert_eq!(submitted.filled_amount, 1.);
+
+        let lost_intent = db.managed_order(&lost_intent.client_order_id).unwrap();
+        assert_eq!(lost_intent.order_id, None);
+        assert_eq!(
+            lost_intent.state,
+            ManagedOrderState::Closed(ManagedOrderOutcome::Rejected)
+        );
+
+        // Reconciled orders are left alone until the exchange reports a change
+        assert!(recover(&mut db, &exchange_client, Exchange::Kraken)
+            .await
+            .unwrap()
+            .is_empty());
+        let _ = std::fs::remove_dir_all(&db_path);
+    }
+}
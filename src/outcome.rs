@@ -0,0 +1,140 @@
+//! Outcome of each exchange operation a command performs, so that scripts can tell complete
+//! success from partial success and failure without parsing the output
+use {
+    serde::Serialize,
+    std::{fmt, fs, io, path::PathBuf, sync::Mutex},
+    sys::{confirmation::ConfirmationError, exchange::ExchangeError},
+    thiserror::Error,
+};
+
+pub const EXIT_SUCCESS: i32 = 0;
+/// Nothing the command set out to do was done
+pub const EXIT_FAILURE: i32 = 1;
+/// Missing credentials, an invalid argument or another problem to fix before trying again
+pub const EXIT_CONFIGURATION_ERROR: i32 = 2;
+/// Some operations succeeded and others failed, see the summary for which
+pub const EXIT_PARTIAL_SUCCESS: i32 = 3;
+
+/// A problem with how `sys` is set up rather than with the operation it was asked to perform
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct ConfigurationError(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OperationKind {
+    OrderPlaced,
+    WithdrawalRequested,
+    FiatWithdrawalRequested,
+    DepositSent,
+    DepositMatched,
+    LendingRedeemed,
+    /// One exchange's part of a command that aggregates several
+    ExchangeQueried,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Operation {
+    pub kind: OperationKind,
+    pub description: String,
+    /// Order id, withdrawal tag, transaction signature, redeemed coin or queried exchange, when
+    /// the operation succeeded
+    pub id: Option<String>,
+    pub succeeded: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub command: String,
+    pub exit_code: i32,
+    pub error: Option<String>,
+    pub operations: Vec<Operation>,
+}
+
+struct SummaryConfig {
+    command: String,
+    // `None` writes the summary to stdout
+    path: Option<PathBuf>,
+}
+
+lazy_static::lazy_static! {
+    static ref OPERATIONS: Mutex<Vec<Operation>> = Mutex::new(vec![]);
+    static ref SUMMARY_CONFIG: Mutex<Option<SummaryConfig>> = Mutex::new(None);
+}
+
+/// Write a JSON summary of `command` when it finishes, to `path` or to stdout if `path` is "-"
+pub fn write_summary_to(command: &str, path: &str) {
+    *SUMMARY_CONFIG.lock().unwrap() = Some(SummaryConfig {
+        command: command.to_string(),
+        path: (path != "-").then(|| PathBuf::from(path)),
+    });
+}
+
+/// Records the outcome of an operation, identified by `id` if it succeeded, returning `result`
+/// unchanged
+pub fn record<T, E: fmt::Display>(
+    kind: OperationKind,
+    description: impl Into<String>,
+    result: Result<T, E>,
+    id: impl FnOnce(&T) -> String,
+) -> Result<T, E> {
+    OPERATIONS.lock().unwrap().push(Operation {
+        kind,
+        description: description.into(),
+        id: result.as_ref().ok().map(id),
+        succeeded: result.is_ok(),
+        error: result.as_ref().err().map(|err| err.to_string()),
+    });
+    result
+}
+
+fn is_configuration_error(err: &(dyn std::error::Error + 'static)) -> bool {
+    err.is::<ConfigurationError>()
+        || matches!(
+            err.downcast_ref::<ConfirmationError>(),
+            Some(ConfirmationError::NotInteractive(_))
+        )
+        || matches!(
+            err.downcast_ref::<ExchangeError>(),
+            Some(ExchangeError::InvalidPair { .. })
+        )
+        || err
+            .downcast_ref::<ExchangeError>()
+            .and_then(|err| err.credentials_failure())
+            .is_some()
+}
+
+fn exit_code(result: &Result<(), Box<dyn std::error::Error>>, operations: &[Operation]) -> i32 {
+    let succeeded = operations.iter().any(|operation| operation.succeeded);
+    let failed = operations.iter().any(|operation| !operation.succeeded);
+    match result {
+        Err(err) if !succeeded && is_configuration_error(err.as_ref()) => EXIT_CONFIGURATION_ERROR,
+        Err(_) if succeeded => EXIT_PARTIAL_SUCCESS,
+        Err(_) => EXIT_FAILURE,
+        Ok(()) if failed && succeeded => EXIT_PARTIAL_SUCCESS,
+        Ok(()) if failed => EXIT_FAILURE,
+        Ok(()) => EXIT_SUCCESS,
+    }
+}
+
+/// Writes the summary if one was requested and returns the process exit code for `result`
+pub fn finish(result: &Result<(), Box<dyn std::error::Error>>) -> io::Result<i32> {
+    let operations = std::mem::take(&mut *OPERATIONS.lock().unwrap());
+    let exit_code = exit_code(result, &operations);
+
+    if let Some(config) = SUMMARY_CONFIG.lock().unwrap().take() {
+        let summary = Summary {
+            command: config.command,
+            exit_code,
+            error: result.as_ref().err().map(|err| err.to_string()),
+            operations,
+        };
+        let json = serde_json::to_string_pretty(&summary)?;
+        match config.path {
+            Some(path) => fs::write(path, json)?,
+            None => println!("{json}"),
+        }
+    }
+    Ok(exit_code)
+}
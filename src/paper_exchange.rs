@@ -0,0 +1,710 @@
+//! Simulated exchange account that trades against live market data from a real exchange.
+//!
+//! Limit orders rest until the live price crosses them and then fill in full at the limit price.
+//! Order book depth is not simulated.  Funds only move in and out through simulated deposits and
+//! withdrawals, never on-chain
+use {
+    crate::{exchange::*, token::MaybeToken, two_factor::TwoFactorCodeProvider},
+    async_trait::async_trait,
+    chrono::{DateTime, NaiveDate, Utc},
+    serde::{Deserialize, Serialize},
+    solana_sdk::pubkey::Pubkey,
+    std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        fs,
+        path::PathBuf,
+        sync::Mutex,
+    },
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaperOrder {
+    pub pair: TradingPair,
+    pub side: OrderSide,
+    pub price: f64,
+    pub amount: f64,
+    pub filled_amount: f64,
+    /// Quote currency paid or received for the fills, before fees
+    #[serde(default)]
+    pub quote_amount: f64,
+    pub open: bool,
+    pub client_order_id: String,
+    pub fee_rate: f64,
+    pub fee: f64, // in the quote currency
+    /// `fee` valued in USD at the time of the fill, when the quote currency's price was available
+    #[serde(default)]
+    pub fee_usd: Option<f64>,
+    pub last_update: NaiveDate,
+}
+
+impl PaperOrder {
+    // Balance held back while the order is open
+    fn reserved(&self) -> (&str, f64) {
+        let remaining = self.amount - self.filled_amount;
+        match self.side {
+            OrderSide::Buy => (
+                self.pair.quote.as_str(),
+                remaining * self.price * (1. + self.fee_rate),
+            ),
+            OrderSide::Sell => (self.pair.base.as_str(), remaining),
+        }
+    }
+}
+
+/// A simulated deposit (positive `amount`) or withdrawal (negative `amount`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaperTransfer {
+    pub coin: String,
+    pub amount: f64,
+    pub usd_price: f64,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PaperAccount {
+    balances: HashMap<String, f64>,
+    orders: BTreeMap<OrderId, PaperOrder>,
+    next_order_id: u64,
+    transfers: Vec<PaperTransfer>,
+    fees: HashMap<String, f64>,
+}
+
+impl PaperAccount {
+    fn available(&self, coin: &str) -> f64 {
+        let reserved = self
+            .orders
+            .values()
+            .filter(|order| order.open)
+            .map(|order| order.reserved())
+            .filter(|(reserved_coin, _)| *reserved_coin == coin)
+            .map(|(_, amount)| amount)
+            .sum::<f64>();
+        self.balances.get(coin).copied().unwrap_or_default() - reserved
+    }
+
+    fn check_available(&self, coin: &str, required: f64) -> Result<(), Box<dyn std::error::Error>> {
+        let available = self.available(coin);
+        if available < required {
+            return Err(format!(
+                "Insufficient paper {coin} balance: {available} available, {required} required"
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self, order_id: &str, price: f64, quote_usd_price: Option<f64>) {
+        let order = self.orders.get_mut(order_id).expect("order");
+        let amount = order.amount - order.filled_amount;
+        let quote_amount = amount * price;
+        let fee = quote_amount * order.fee_rate;
+
+        order.filled_amount = order.amount;
+        order.quote_amount += quote_amount;
+        order.open = false;
+        order.fee += fee;
+        order.fee_usd = quote_usd_price.map(|quote_usd_price| order.fee * quote_usd_price);
+        order.last_update = Utc::now().date().naive_local();
+
+        let (side, base, quote) = (
+            order.side,
+            order.pair.base.clone(),
+            order.pair.quote.clone(),
+        );
+        let (base_change, quote_change) = match side {
+            OrderSide::Buy => (amount, -(quote_amount + fee)),
+            OrderSide::Sell => (-amount, quote_amount - fee),
+        };
+        *self.balances.entry(base).or_default() += base_change;
+        *self.balances.entry(quote.clone()).or_default() += quote_change;
+        *self.fees.entry(quote).or_default() += fee;
+    }
+}
+
+/// Simulated results, with all values in USD at current prices unless noted
+#[derive(Debug, Serialize)]
+pub struct PaperReport {
+    pub balances: BTreeMap<String, f64>,
+    /// Fees paid, in the currency they were charged in
+    pub fees: BTreeMap<String, f64>,
+    /// Net deposits, valued at the time of each deposit or withdrawal
+    pub invested_usd: f64,
+    /// Current balances plus everything withdrawn
+    pub value_usd: f64,
+    /// Value had the deposited coins been held instead of traded
+    pub buy_and_hold_usd: f64,
+    /// Fees valued at the time of each fill
+    pub fees_usd: f64,
+}
+
+impl PaperReport {
+    pub fn pnl_usd(&self) -> f64 {
+        self.value_usd - self.invested_usd
+    }
+
+    pub fn vs_buy_and_hold_usd(&self) -> f64 {
+        self.value_usd - self.buy_and_hold_usd
+    }
+}
+
+pub struct PaperExchangeClient {
+    exchange: Exchange,
+    market: Box<dyn ExchangeClient>,
+    state_path: PathBuf,
+    account: Mutex<PaperAccount>,
+}
+
+impl PaperExchangeClient {
+    /// Market data is read from `market`, while the simulated account is persisted at `state_path`
+    pub fn new(
+        exchange: Exchange,
+        market: Box<dyn ExchangeClient>,
+        state_path: PathBuf,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let account = if state_path.exists() {
+            serde_json::from_str(&fs::read_to_string(&state_path)?).map_err(|err| {
+                format!(
+                    "Unable to read paper account from {}: {err}",
+                    state_path.display()
+                )
+            })?
+        } else {
+            PaperAccount::default()
+        };
+
+        Ok(Self {
+            exchange,
+            market,
+            state_path,
+            account: Mutex::new(account),
+        })
+    }
+
+    fn save(&self, account: &PaperAccount) -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_path = self.state_path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(account)?)?;
+        fs::rename(&tmp_path, &self.state_path)?;
+        Ok(())
+    }
+
+    async fn fee_rates(&self) -> (f64, f64) {
+        match self.market.fee_schedule().await {
+            Ok(fee_schedule) => (fee_schedule.maker_rate, fee_schedule.taker_rate),
+            Err(_) => (self.market.maker_fee_rate(), self.market.taker_fee_rate()),
+        }
+    }
+
+    /// Fills every open order that the live price has crossed since the last check
+    async fn fill_crossed_orders(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let pairs = self
+            .account
+            .lock()
+            .unwrap()
+            .orders
+            .values()
+            .filter(|order| order.open)
+            .map(|order| order.pair.clone())
+            .collect::<HashSet<_>>();
+
+        let mut bid_asks = vec![];
+        for pair in pairs {
+            let bid_ask = self.market.bid_ask(&pair).await?;
+            let quote_usd_price = self.quote_usd_price(&pair).await;
+            bid_asks.push((pair, bid_ask, quote_usd_price));
+        }
+        if bid_asks.is_empty() {
+            return Ok(());
+        }
+
+        let mut account = self.account.lock().unwrap();
+        let crossed_orders = account
+            .orders
+            .iter()
+            .filter(|(_, order)| order.open)
+            .filter_map(|(order_id, order)| {
+                let (_, bid_ask, quote_usd_price) =
+                    bid_asks.iter().find(|(pair, ..)| *pair == order.pair)?;
+                let crossed = match order.side {
+                    OrderSide::Buy => bid_ask.ask_price <= order.price,
+                    OrderSide::Sell => bid_ask.bid_price >= order.price,
+                };
+                crossed.then(|| (order_id.clone(), order.price, *quote_usd_price))
+            })
+            .collect::<Vec<_>>();
+
+        if !crossed_orders.is_empty() {
+            for (order_id, price, quote_usd_price) in crossed_orders {
+                account.fill(&order_id, price, quote_usd_price);
+            }
+            self.save(&account)?;
+        }
+        Ok(())
+    }
+
+    async fn usd_price(
+        &self,
+        coin: &str,
+        trading_pairs: &[TradingPair],
+    ) -> Result<f64, Box<dyn std::error::Error>> {
+        if is_usd_equivalent(coin) {
+            return Ok(1.);
+        }
+        let pair = usd_preference_order()
+            .iter()
+            .map(|quote| TradingPair::new(coin, quote))
+            .find(|pair| trading_pairs.contains(pair))
+            .ok_or_else(|| format!("No USD-quoted market available for {coin}"))?;
+        let bid_ask = self.market.bid_ask(&pair).await?;
+        Ok((bid_ask.bid_price + bid_ask.ask_price) / 2.)
+    }
+
+    // Best effort, a fill without the quote currency's price just goes unvalued
+    async fn quote_usd_price(&self, pair: &TradingPair) -> Option<f64> {
+        let trading_pairs = if is_usd_equivalent(&pair.quote) {
+            vec![]
+        } else {
+            self.market.trading_pairs().await.ok()?
+        };
+        self.usd_price(&pair.quote, &trading_pairs).await.ok()
+    }
+
+    async fn transfer(&self, coin: &str, amount: f64) -> Result<(), Box<dyn std::error::Error>> {
+        let coin = coin.to_ascii_uppercase();
+        let usd_price = self
+            .usd_price(&coin, &self.market.trading_pairs().await?)
+            .await?;
+
+        let mut account = self.account.lock().unwrap();
+        if amount < 0. {
+            account.check_available(&coin, -amount)?;
+        }
+        *account.balances.entry(coin.clone()).or_default() += amount;
+        account.transfers.push(PaperTransfer {
+            coin,
+            amount,
+            usd_price,
+            time: Utc::now(),
+        });
+        self.save(&account)
+    }
+
+    /// Simulates a deposit into the paper account
+    pub async fn fund(&self, coin: &str, amount: f64) -> Result<(), Box<dyn std::error::Error>> {
+        self.transfer(coin, amount).await
+    }
+
+    /// Simulates a withdrawal out of the paper account
+    pub async fn withdraw(
+        &self,
+        coin: &str,
+        amount: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.transfer(coin, -amount).await
+    }
+
+    pub async fn orders(
+        &self,
+    ) -> Result<BTreeMap<OrderId, PaperOrder>, Box<dyn std::error::Error>> {
+        self.fill_crossed_orders().await?;
+        Ok(self.account.lock().unwrap().orders.clone())
+    }
+
+    pub async fn report(&self) -> Result<PaperReport, Box<dyn std::error::Error>> {
+        self.fill_crossed_orders().await?;
+        let (balances, transfers, fees, orders) = {
+            let account = self.account.lock().unwrap();
+            (
+                account.balances.clone(),
+                account.transfers.clone(),
+                account.fees.clone(),
+                account.orders.clone(),
+            )
+        };
+
+        let trading_pairs = self.market.trading_pairs().await?;
+        let mut usd_prices = HashMap::new();
+        for coin in balances
+            .keys()
+            .chain(fees.keys())
+            .chain(transfers.iter().map(|transfer| &transfer.coin))
+        {
+            if !usd_prices.contains_key(coin) {
+                usd_prices.insert(coin.clone(), self.usd_price(coin, &trading_pairs).await?);
+            }
+        }
+
+        let mut held = HashMap::<&str, f64>::new();
+        let mut invested_usd = 0.;
+        for transfer in &transfers {
+            *held.entry(transfer.coin.as_str()).or_default() += transfer.amount;
+            invested_usd += transfer.amount * transfer.usd_price;
+        }
+        let withdrawn_usd = transfers
+            .iter()
+            .filter(|transfer| transfer.amount < 0.)
+            .map(|transfer| -transfer.amount * usd_prices[&transfer.coin])
+            .sum::<f64>();
+
+        Ok(PaperReport {
+            value_usd: balances
+                .iter()
+                .map(|(coin, amount)| amount * usd_prices[coin])
+                .sum::<f64>()
+                + withdrawn_usd,
+            buy_and_hold_usd: held
+                .iter()
+                .map(|(coin, amount)| amount * usd_prices[*coin])
+                .sum::<f64>()
+                + withdrawn_usd,
+            // Orders filled before fills were valued fall back to current prices
+            fees_usd: orders
+                .values()
+                .filter(|order| order.fee > 0.)
+                .map(|order| {
+                    order
+                        .fee_usd
+                        .unwrap_or_else(|| order.fee * usd_prices[&order.pair.quote])
+                })
+                .sum(),
+            fees: fees.into_iter().collect(),
+            invested_usd,
+            balances: balances.into_iter().collect(),
+        })
+    }
+
+    async fn place(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+        taker: bool,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        if !(price > 0. && amount > 0.) {
+            return Err("Order price and amount must be positive".into());
+        }
+        self.market.validate_pair(pair).await?;
+        let bid_ask = self.market.bid_ask(pair).await?;
+        let (maker_fee_rate, taker_fee_rate) = self.fee_rates().await;
+
+        let crossing_price = match side {
+            OrderSide::Buy => Some(bid_ask.ask_price).filter(|ask| *ask <= price),
+            OrderSide::Sell => Some(bid_ask.bid_price).filter(|bid| *bid >= price),
+        };
+        if crossing_price.is_some() && !taker {
+            return Err(format!(
+                "{side} order at {price} would take liquidity in {pair} \
+                 (bid {}, ask {})",
+                bid_ask.bid_price, bid_ask.ask_price
+            )
+            .into());
+        }
+
+        let mut order = PaperOrder {
+            pair: pair.clone(),
+            side,
+            price,
+            amount,
+            filled_amount: 0.,
+            quote_amount: 0.,
+            open: true,
+            client_order_id: client_order_id.to_string(),
+            fee_rate: if taker {
+                taker_fee_rate
+            } else {
+                maker_fee_rate
+            },
+            fee: 0.,
+            fee_usd: None,
+            last_update: Utc::now().date().naive_local(),
+        };
+        let quote_usd_price = match crossing_price {
+            Some(_) => self.quote_usd_price(pair).await,
+            None => None,
+        };
+
+        let mut account = self.account.lock().unwrap();
+        let (coin, required) = order.reserved();
+        account.check_available(coin, required)?;
+
+        account.next_order_id += 1;
+        let order_id = format!("paper-{}", account.next_order_id);
+        if taker {
+            // Immediate-or-cancel against the top of the book
+            order.open = crossing_price.is_some();
+        }
+        account.orders.insert(order_id.clone(), order);
+        if let Some(crossing_price) = crossing_price {
+            account.fill(&order_id, crossing_price, quote_usd_price);
+        }
+        self.save(&account)?;
+        Ok(order_id)
+    }
+}
+
+#[async_trait]
+impl ExchangeClient for PaperExchangeClient {
+    async fn deposit_address(
+        &self,
+        _token: MaybeToken,
+    ) -> Result<Pubkey, Box<dyn std::error::Error>> {
+        Err(format!(
+            "Paper accounts are funded with `sys {} paper fund`, not on-chain deposits",
+            self.exchange.name()
+        )
+        .into())
+    }
+
+    async fn supports_token(
+        &self,
+        token: MaybeToken,
+    ) -> Result<TokenSupport, Box<dyn std::error::Error>> {
+        Ok(TokenSupport {
+            listed: self.market.supports_token(token).await?.listed,
+            deposits_enabled: false,
+            withdrawals_enabled: false,
+        })
+    }
+
+    async fn recent_deposits(
+        &self,
+    ) -> Result<Option<Vec<DepositInfo>>, Box<dyn std::error::Error>> {
+        Ok(Some(vec![]))
+    }
+
+    async fn withdrawals(
+        &self,
+        _start: DateTime<Utc>,
+        _end: DateTime<Utc>,
+    ) -> Result<Vec<WithdrawalInfo>, Box<dyn std::error::Error>> {
+        Ok(vec![])
+    }
+
+    async fn request_withdraw(
+        &self,
+        _address: Pubkey,
+        _token: MaybeToken,
+        _amount: u64,
+        _withdrawal_password: Option<String>,
+        _withdrawal_code: Option<&dyn TwoFactorCodeProvider>,
+        _client_reference: &str,
+    ) -> Result<(String, f64), Box<dyn std::error::Error>> {
+        Err(format!(
+            "Paper accounts can't send on-chain withdrawals, use `sys {} paper withdraw`",
+            self.exchange.name()
+        )
+        .into())
+    }
+
+    async fn balances(
+        &self,
+    ) -> Result<HashMap<String, ExchangeBalance>, Box<dyn std::error::Error>> {
+        self.fill_crossed_orders().await?;
+        let account = self.account.lock().unwrap();
+        Ok(account
+            .balances
+            .iter()
+            .map(|(coin, total)| {
+                (
+                    coin.clone(),
+                    ExchangeBalance {
+                        available: account.available(coin),
+                        total: *total,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    async fn print_market_info(
+        &self,
+        pair: &TradingPair,
+        format: MarketInfoFormat,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.market.print_market_info(pair, format).await
+    }
+
+    async fn bid_ask(&self, pair: &TradingPair) -> Result<BidAsk, Box<dyn std::error::Error>> {
+        self.market.bid_ask(pair).await
+    }
+
+    async fn bid_asks(&self) -> Result<HashMap<TradingPair, BidAsk>, Box<dyn std::error::Error>> {
+        self.market.bid_asks().await
+    }
+
+    async fn server_time(&self) -> Result<DateTime<Utc>, Box<dyn std::error::Error>> {
+        self.market.server_time().await
+    }
+
+    async fn order_book(
+        &self,
+        pair: &TradingPair,
+        depth: usize,
+    ) -> Result<OrderBook, Box<dyn std::error::Error>> {
+        self.market.order_book(pair, depth).await
+    }
+
+    async fn ticker_stats(
+        &self,
+        pair: &TradingPair,
+    ) -> Result<TickerStats, Box<dyn std::error::Error>> {
+        self.market.ticker_stats(pair).await
+    }
+
+    async fn candles(
+        &self,
+        pair: &TradingPair,
+        range: CandleRange,
+    ) -> Result<Vec<Candle>, Box<dyn std::error::Error>> {
+        self.market.candles(pair, range).await
+    }
+
+    async fn price_on(
+        &self,
+        pair: &TradingPair,
+        date: NaiveDate,
+    ) -> Result<f64, Box<dyn std::error::Error>> {
+        self.market.price_on(pair, date).await
+    }
+
+    async fn place_order(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        self.place(pair, side, price, amount, client_order_id, false)
+            .await
+    }
+
+    async fn place_taker_order(
+        &self,
+        pair: &TradingPair,
+        side: OrderSide,
+        price: f64,
+        amount: f64,
+        client_order_id: &str,
+    ) -> Result<OrderId, Box<dyn std::error::Error>> {
+        self.place(pair, side, price, amount, client_order_id, true)
+            .await
+    }
+
+    async fn find_order_by_client_id(
+        &self,
+        _pair: &TradingPair,
+        client_order_id: &str,
+    ) -> Result<Option<OrderId>, Box<dyn std::error::Error>> {
+        Ok(self
+            .account
+            .lock()
+            .unwrap()
+            .orders
+            .iter()
+            .find(|(_, order)| order.client_order_id == client_order_id)
+            .map(|(order_id, _)| order_id.clone()))
+    }
+
+    async fn cancel_order(
+        &self,
+        _pair: &TradingPair,
+        order_id: &OrderId,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.fill_crossed_orders().await?;
+        let mut account = self.account.lock().unwrap();
+        let order = account
+            .orders
+            .get_mut(order_id)
+            .ok_or_else(|| format!("Unknown paper order: {order_id}"))?;
+        if order.open {
+            order.open = false;
+            order.last_update = Utc::now().date().naive_local();
+            self.save(&account)?;
+        }
+        Ok(())
+    }
+
+    async fn order_status(
+        &self,
+        _pair: &TradingPair,
+        order_id: &OrderId,
+    ) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+        self.fill_crossed_orders().await?;
+        let account = self.account.lock().unwrap();
+        let order = account
+            .orders
+            .get(order_id)
+            .ok_or_else(|| format!("Unknown paper order: {order_id}"))?;
+        let (average_fill_price, cumulative_quote_amount) =
+            fill_totals(order.filled_amount, order.quote_amount);
+        Ok(OrderStatus {
+            open: order.open,
+            side: order.side,
+            price: order.price,
+            amount: order.amount,
+            filled_amount: order.filled_amount,
+            average_fill_price,
+            cumulative_quote_amount,
+            last_update: order.last_update,
+            fee: Some((order.fee, order.pair.quote.clone())),
+            // Paper orders only close unfilled when cancelled
+            cancellation: (!order.open && order.filled_amount < order.amount)
+                .then_some(OrderCancellation::User),
+        })
+    }
+
+    async fn get_lending_info(
+        &self,
+        _coin: &str,
+    ) -> Result<Option<LendingInfo>, Box<dyn std::error::Error>> {
+        Ok(None)
+    }
+
+    async fn all_lending_info(
+        &self,
+    ) -> Result<HashMap<String, LendingInfo>, Box<dyn std::error::Error>> {
+        Ok(HashMap::new())
+    }
+
+    async fn get_lending_history(
+        &self,
+        _lending_history: LendingHistory,
+    ) -> Result<HashMap<String, f64>, Box<dyn std::error::Error>> {
+        Ok(HashMap::new())
+    }
+
+    async fn submit_lending_offer(
+        &self,
+        _coin: &str,
+        _size: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("Lending not supported by paper accounts".into())
+    }
+
+    fn preferred_solusd_pair(&self) -> TradingPair {
+        self.market.preferred_solusd_pair()
+    }
+
+    fn maker_fee_rate(&self) -> f64 {
+        self.market.maker_fee_rate()
+    }
+
+    fn taker_fee_rate(&self) -> f64 {
+        self.market.taker_fee_rate()
+    }
+
+    async fn fee_schedule(&self) -> Result<FeeSchedule, Box<dyn std::error::Error>> {
+        let (maker_rate, taker_rate) = self.fee_rates().await;
+        Ok(FeeSchedule {
+            maker_rate,
+            taker_rate,
+            discount: None,
+        })
+    }
+
+    async fn pair_infos(&self) -> Result<Vec<PairInfo>, Box<dyn std::error::Error>> {
+        self.market.pair_infos().await
+    }
+}
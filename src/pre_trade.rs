@@ -0,0 +1,182 @@
+//! Checks run before every order is placed and every withdrawal is requested.  Callers hand the
+//! checks that apply, such as the spread guard, spending limits and withdrawal allow-list, to
+//! `place_order_with_client_id()` and `request_tagged_withdraw()`, which run them before anything
+//! is sent.  The first check to reject an operation stops it
+use {
+    crate::{
+        exchange::{BidAsk, Exchange, ExchangeClient, OrderSide, TradingPair},
+        token::MaybeToken,
+    },
+    async_trait::async_trait,
+    solana_sdk::pubkey::Pubkey,
+    std::fmt,
+    thiserror::Error,
+};
+
+/// An order about to be placed
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderIntent {
+    pub exchange: Exchange,
+    pub pair: TradingPair,
+    pub side: OrderSide,
+    pub price: f64,
+    pub amount: f64,
+    /// The command or strategy placing the order, such as `sell` or `rebalance`
+    pub strategy: String,
+}
+
+impl fmt::Display for OrderIntent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} {:?} {} {} at {} ({})",
+            self.exchange, self.side, self.amount, self.pair, self.price, self.strategy
+        )
+    }
+}
+
+/// A withdrawal about to be requested
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithdrawalRequest {
+    pub exchange: Exchange,
+    pub token: MaybeToken,
+    pub amount: u64,
+    pub to_address: Pubkey,
+    /// The command or strategy requesting the withdrawal, such as `withdraw` or `evacuate`
+    pub strategy: String,
+}
+
+impl fmt::Display for WithdrawalRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} withdrawal of {}{} to {} ({})",
+            self.exchange,
+            self.token.symbol(),
+            self.token.ui_amount(self.amount),
+            self.to_address,
+            self.strategy
+        )
+    }
+}
+
+/// A fiat withdrawal about to be requested, to one of the account's registered withdrawal methods
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiatWithdrawalRequest {
+    pub exchange: Exchange,
+    pub currency: String,
+    pub amount: f64,
+    /// Name of the withdrawal method, such as a bank account
+    pub method: String,
+    /// The command or strategy requesting the withdrawal, such as `fiat-withdraw`
+    pub strategy: String,
+}
+
+impl fmt::Display for FiatWithdrawalRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:?} withdrawal of {} {} to {} ({})",
+            self.exchange, self.amount, self.currency, self.method, self.strategy
+        )
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Rejected by the {check} check: {reason}")]
+pub struct Rejection {
+    pub check: String,
+    pub reason: String,
+}
+
+impl Rejection {
+    pub fn new(check: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self {
+            check: check.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+#[async_trait]
+pub trait PreTradeCheck: Send + Sync {
+    async fn check(&self, intent: &OrderIntent) -> Result<(), Rejection>;
+}
+
+#[async_trait]
+pub trait PreWithdrawalCheck: Send + Sync {
+    async fn check(&self, request: &WithdrawalRequest) -> Result<(), Rejection>;
+
+    /// Fiat withdrawals pass checks that only concern tokens
+    async fn check_fiat(&self, _request: &FiatWithdrawalRequest) -> Result<(), Rejection> {
+        Ok(())
+    }
+}
+
+/// Runs `checks` on `intent` in order, stopping at the first rejection
+pub async fn check_order(
+    checks: &[&dyn PreTradeCheck],
+    intent: &OrderIntent,
+) -> Result<(), Rejection> {
+    for check in checks {
+        if let Err(rejection) = check.check(intent).await {
+            log::warn!("{intent}: {rejection}");
+            return Err(rejection);
+        }
+    }
+    Ok(())
+}
+
+/// Runs `checks` on `request` in order, stopping at the first rejection
+pub async fn check_withdrawal(
+    checks: &[&dyn PreWithdrawalCheck],
+    request: &WithdrawalRequest,
+) -> Result<(), Rejection> {
+    for check in checks {
+        if let Err(rejection) = check.check(request).await {
+            log::warn!("{request}: {rejection}");
+            return Err(rejection);
+        }
+    }
+    Ok(())
+}
+
+/// Runs `checks` on a fiat withdrawal `request` in order, stopping at the first rejection
+pub async fn check_fiat_withdrawal(
+    checks: &[&dyn PreWithdrawalCheck],
+    request: &FiatWithdrawalRequest,
+) -> Result<(), Rejection> {
+    for check in checks {
+        if let Err(rejection) = check.check_fiat(request).await {
+            log::warn!("{request}: {rejection}");
+            return Err(rejection);
+        }
+    }
+    Ok(())
+}
+
+/// Built-in guard against placing orders into a momentarily thin or dislocated book, see
+/// `BidAsk::check_spread`
+pub enum SpreadGuard<'a> {
+    /// Checks the book as the order is placed
+    Live(&'a dyn ExchangeClient),
+    /// Checks the quote the order was priced from
+    Quoted(&'a BidAsk),
+}
+
+#[async_trait]
+impl PreTradeCheck for SpreadGuard<'_> {
+    async fn check(&self, intent: &OrderIntent) -> Result<(), Rejection> {
+        let rejection = |reason: String| Rejection::new("spread", reason);
+        let bid_ask = match self {
+            Self::Live(exchange_client) => exchange_client
+                .bid_ask(&intent.pair)
+                .await
+                .map_err(|err| rejection(format!("Unable to check the spread: {err}")))?,
+            Self::Quoted(bid_ask) => (*bid_ask).clone(),
+        };
+        bid_ask
+            .check_spread(&intent.pair)
+            .map_err(|err| rejection(err.to_string()))
+    }
+}
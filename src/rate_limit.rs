@@ -0,0 +1,268 @@
+//! Request budgets for exchange APIs.  Every client built for the same exchange and API key shares
+//! one budget, and with a state directory configured so does every process on the machine
+use {
+    crate::exchange::Exchange,
+    serde::{Deserialize, Serialize},
+    sha1::{Digest, Sha1},
+    std::{
+        collections::{HashMap, VecDeque},
+        fs,
+        io::{self, Read, Seek, SeekFrom, Write},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+};
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Report every wait for rate budget on stderr, rather than only in the debug log
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+lazy_static::lazy_static! {
+    static ref DEFAULT_STATE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Share request budgets with other processes through state files in `dir`, for clients not given
+/// a directory of their own
+pub fn set_default_state_dir(dir: &Path) {
+    *DEFAULT_STATE_DIR.lock().unwrap() = Some(dir.to_path_buf());
+}
+
+/// The directory given to `set_default_state_dir`, if any
+pub fn default_state_dir() -> Option<PathBuf> {
+    DEFAULT_STATE_DIR.lock().unwrap().clone()
+}
+
+/// At most `weight` units of request weight in any `window`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub weight: u32,
+    pub window: Duration,
+}
+
+impl RateLimit {
+    /// The exchange's published limit for a single API key, rounded down for headroom
+    pub fn for_exchange(exchange: Exchange) -> Self {
+        let (weight, window_secs) = match exchange {
+            Exchange::Binance => (5_000, 60),
+            Exchange::BinanceUs => (1_000, 60),
+            // 10,000 requests an hour to the retail API, Advanced Trade has its own budget
+            Exchange::Coinbase => (9_000, 3_600),
+            Exchange::Ftx | Exchange::FtxUs => (1_500, 60),
+            // The private API counter allows a burst of 15 then decays by one every three seconds
+            Exchange::Kraken => (15, 45),
+        };
+        Self {
+            weight,
+            window: Duration::from_secs(window_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RateLimitUtilization {
+    pub used: u32,
+    pub budget: u32,
+}
+
+impl RateLimitUtilization {
+    pub fn fraction(&self) -> f64 {
+        f64::from(self.used) / f64::from(self.budget)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RateLimitState {
+    // Time in milliseconds since the epoch and weight of each request still inside the window,
+    // oldest first
+    requests: VecDeque<(u64, u32)>,
+}
+
+impl RateLimitState {
+    fn prune(&mut self, now: u64, window: Duration) {
+        let window_start = now.saturating_sub(window.as_millis() as u64);
+        while matches!(self.requests.front(), Some((time, _)) if *time <= window_start) {
+            self.requests.pop_front();
+        }
+    }
+
+    fn used(&self) -> u32 {
+        self.requests.iter().map(|(_, weight)| weight).sum()
+    }
+
+    // Records the request and returns `None` if it fits in the budget, otherwise how long until it
+    // will
+    fn reserve(&mut self, now: u64, weight: u32, rate_limit: RateLimit) -> Option<Duration> {
+        self.prune(now, rate_limit.window);
+
+        let mut excess = (self.used() + weight).saturating_sub(rate_limit.weight);
+        if excess == 0 {
+            self.requests.push_back((now, weight));
+            return None;
+        }
+        for (time, request_weight) in &self.requests {
+            excess = excess.saturating_sub(*request_weight);
+            if excess == 0 {
+                let expires = time + rate_limit.window.as_millis() as u64;
+                return Some(Duration::from_millis(expires.saturating_sub(now).max(1)));
+            }
+        }
+        unreachable!("weight is capped at the budget");
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+pub struct RateLimiter {
+    exchange: Exchange,
+    rate_limit: RateLimit,
+    state: Mutex<RateLimitState>,
+    // When set the state is kept in this file instead, locked while it's updated, so that other
+    // processes draw from the same budget
+    state_path: Option<PathBuf>,
+}
+
+impl RateLimiter {
+    pub fn new(exchange: Exchange, rate_limit: RateLimit, state_path: Option<PathBuf>) -> Self {
+        Self {
+            exchange,
+            rate_limit,
+            state: Mutex::new(RateLimitState::default()),
+            state_path,
+        }
+    }
+
+    /// The limiter for `exchange` and `api_key`, shared by every client in this process that uses
+    /// the same key.  With `state_dir`, the budget is also shared with other processes using the
+    /// same directory
+    pub fn shared(exchange: Exchange, api_key: &str, state_dir: Option<&Path>) -> Arc<Self> {
+        Self::shared_budget(
+            exchange,
+            None,
+            RateLimit::for_exchange(exchange),
+            api_key,
+            state_dir,
+        )
+    }
+
+    /// Like `shared`, for exchanges that limit a group of endpoints, named by `budget`, separately
+    /// from the rest of their API
+    pub fn shared_budget(
+        exchange: Exchange,
+        budget: Option<&str>,
+        rate_limit: RateLimit,
+        api_key: &str,
+        state_dir: Option<&Path>,
+    ) -> Arc<Self> {
+        type RateLimiterKey = (Exchange, String, Option<PathBuf>);
+        lazy_static::lazy_static! {
+            static ref RATE_LIMITERS: Mutex<HashMap<RateLimiterKey, Arc<RateLimiter>>> =
+                Mutex::new(HashMap::new());
+        }
+
+        // The key itself is never written to disk
+        let key_hash = Sha1::digest(api_key.as_bytes())
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let key_hash = match budget {
+            Some(budget) => format!("{budget}-{key_hash}"),
+            None => key_hash,
+        };
+        let state_path = state_dir.map(|state_dir| {
+            state_dir.join(format!("{}-{key_hash}.rate-limit.json", exchange.name()))
+        });
+
+        RATE_LIMITERS
+            .lock()
+            .unwrap()
+            .entry((exchange, key_hash, state_path.clone()))
+            .or_insert_with(|| Arc::new(Self::new(exchange, rate_limit, state_path)))
+            .clone()
+    }
+
+    // Runs `f` on the current state, locking the state file for the duration when there is one
+    fn with_state<T>(&self, f: impl FnOnce(&mut RateLimitState) -> T) -> io::Result<T> {
+        let state_path = match &self.state_path {
+            Some(state_path) => state_path,
+            None => return Ok(f(&mut self.state.lock().unwrap())),
+        };
+
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(state_path)?;
+        let mut lock = fd_lock::RwLock::new(file);
+        let mut file = lock.write()?;
+
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut state = serde_json::from_str(&contents).unwrap_or_default();
+        let result = f(&mut state);
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(serde_json::to_string(&state)?.as_bytes())?;
+        Ok(result)
+    }
+
+    pub fn utilization(&self) -> RateLimitUtilization {
+        let rate_limit = self.rate_limit;
+        let used = self
+            .with_state(|state| {
+                state.prune(now_millis(), rate_limit.window);
+                state.used()
+            })
+            .unwrap_or_default();
+        RateLimitUtilization {
+            used,
+            budget: rate_limit.weight,
+        }
+    }
+
+    /// Waits until `weight` more fits in the budget, then records it against the budget
+    pub async fn acquire(&self, weight: u32) {
+        let rate_limit = self.rate_limit;
+        let weight = weight.min(rate_limit.weight);
+        loop {
+            let reserved = self.with_state(|state| state.reserve(now_millis(), weight, rate_limit));
+            let wait = match reserved {
+                Ok(None) => return,
+                Ok(Some(wait)) => wait,
+                Err(err) => {
+                    log::warn!("{:?} rate limit state unavailable: {err}", self.exchange);
+                    return;
+                }
+            };
+
+            let utilization = self.utilization();
+            let msg = format!(
+                "waiting {}ms for {:?} rate budget ({} of {} used)",
+                wait.as_millis(),
+                self.exchange,
+                utilization.used,
+                utilization.budget
+            );
+            if VERBOSE.load(Ordering::Relaxed) {
+                eprintln!("{msg}");
+            } else {
+                log::debug!("{msg}");
+            }
+            crate::request_metrics::record_rate_limit_wait(self.exchange, wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
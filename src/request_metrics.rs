@@ -0,0 +1,513 @@
+//! Request counts, errors, retries, rate budget waits and latency of exchange requests, per
+//! exchange and class of endpoint.  Totals accumulate in memory and are written to a file per run,
+//! from which recent runs can be summarized, or pushed to a Prometheus pushgateway.  Recording is
+//! a few counter updates under a lock, and compiles to nothing without the `request-metrics`
+//! feature
+use {
+    crate::exchange::{Exchange, ExchangeError},
+    chrono::prelude::*,
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::{BTreeMap, HashMap},
+        fmt::Write,
+        fs, io,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+const ENABLED: bool = cfg!(feature = "request-metrics");
+
+/// Upper bounds of the latency histogram buckets in milliseconds.  Slower requests fall in a final
+/// unbounded bucket
+pub const LATENCY_BUCKETS_MS: [u64; 8] = [50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000];
+
+// Run files older than this are removed when a run is written
+const RETENTION_DAYS: i64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EndpointClass {
+    MarketData,
+    Account,
+    Trading,
+    Funding,
+}
+
+impl EndpointClass {
+    /// Classifies an endpoint by its name or path, such as "AddOrder" or "/api/v3/depth"
+    pub fn of(endpoint: &str) -> Self {
+        let endpoint = endpoint.to_ascii_lowercase();
+        let mentions = |words: &[&str]| words.iter().any(|word| endpoint.contains(word));
+        if mentions(&["withdraw", "deposit", "transfer", "transaction", "funding"]) {
+            Self::Funding
+        } else if mentions(&[
+            "ticker",
+            "depth",
+            "book",
+            "ohlc",
+            "candle",
+            "price",
+            "time",
+            "status",
+            "pair",
+            "exchangeinfo",
+            "product",
+            "market",
+            "asset",
+            "currencies",
+        ]) {
+            Self::MarketData
+        } else if mentions(&["order", "fill", "trade"]) {
+            Self::Trading
+        } else {
+            Self::Account
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::MarketData => "market-data",
+            Self::Account => "account",
+            Self::Trading => "trading",
+            Self::Funding => "funding",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCategory {
+    Timeout,
+    RateLimited,
+    Authentication,
+    /// The exchange answered with an error
+    Rejected,
+    /// No answer from the exchange, such as a connection failure
+    Network,
+    Other,
+}
+
+impl ErrorCategory {
+    pub fn of(err: &(dyn std::error::Error + 'static)) -> Self {
+        match err.downcast_ref::<ExchangeError>() {
+            Some(ExchangeError::Timeout { .. }) => Self::Timeout,
+            Some(ExchangeError::Api {
+                http_status,
+                code,
+                message,
+                ..
+            }) if *http_status == Some(429)
+                || code == "-1003"
+                || message.to_ascii_lowercase().contains("rate limit") =>
+            {
+                Self::RateLimited
+            }
+            Some(err) if err.credentials_failure().is_some() => Self::Authentication,
+            Some(ExchangeError::Api { .. }) => Self::Rejected,
+            Some(_) => Self::Other,
+            None if err.is::<reqwest::Error>() => Self::Network,
+            None => Self::Other,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::RateLimited => "rate-limited",
+            Self::Authentication => "authentication",
+            Self::Rejected => "rejected",
+            Self::Network => "network",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestStats {
+    pub requests: u64,
+    pub errors: BTreeMap<ErrorCategory, u64>,
+    pub retries: u64,
+    pub latency_ms_total: u64,
+    /// Requests in each of `LATENCY_BUCKETS_MS`, then in the unbounded bucket
+    pub latency_buckets: Vec<u64>,
+}
+
+impl RequestStats {
+    fn record(&mut self, latency: Duration, error: Option<ErrorCategory>) {
+        let latency_ms = latency.as_millis() as u64;
+        self.requests += 1;
+        self.latency_ms_total += latency_ms;
+        self.latency_buckets.resize(LATENCY_BUCKETS_MS.len() + 1, 0);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| latency_ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.latency_buckets[bucket] += 1;
+        if let Some(error) = error {
+            *self.errors.entry(error).or_default() += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.requests += other.requests;
+        for (category, count) in &other.errors {
+            *self.errors.entry(*category).or_default() += count;
+        }
+        self.retries += other.retries;
+        self.latency_ms_total += other.latency_ms_total;
+        self.latency_buckets.resize(LATENCY_BUCKETS_MS.len() + 1, 0);
+        for (bucket, count) in self.latency_buckets.iter_mut().zip(&other.latency_buckets) {
+            *bucket += count;
+        }
+    }
+
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            0.
+        } else {
+            self.error_count() as f64 / self.requests as f64
+        }
+    }
+
+    pub fn mean_latency_ms(&self) -> Option<u64> {
+        (self.requests > 0).then(|| self.latency_ms_total / self.requests)
+    }
+
+    /// The bucket bound that `quantile` of requests completed within, or `None` if there were no
+    /// requests or they fall in the unbounded bucket
+    pub fn latency_quantile_ms(&self, quantile: f64) -> Option<u64> {
+        let target = (self.requests as f64 * quantile).ceil() as u64;
+        let mut count = 0;
+        for (bound, bucket_count) in LATENCY_BUCKETS_MS.iter().zip(&self.latency_buckets) {
+            count += bucket_count;
+            if count >= target && count > 0 {
+                return Some(*bound);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExchangeMetrics {
+    pub rate_limit_waits: u64,
+    pub rate_limit_wait_ms: u64,
+    pub endpoints: BTreeMap<EndpointClass, RequestStats>,
+}
+
+impl ExchangeMetrics {
+    fn merge(&mut self, other: &Self) {
+        self.rate_limit_waits += other.rate_limit_waits;
+        self.rate_limit_wait_ms += other.rate_limit_wait_ms;
+        for (class, stats) in &other.endpoints {
+            self.endpoints.entry(*class).or_default().merge(stats);
+        }
+    }
+}
+
+/// One process's metrics, as written to its run file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunMetrics {
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub exchanges: HashMap<Exchange, ExchangeMetrics>,
+}
+
+struct State {
+    started: DateTime<Utc>,
+    exchanges: HashMap<Exchange, ExchangeMetrics>,
+    dir: Option<PathBuf>,
+}
+
+lazy_static::lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State {
+        started: Utc::now(),
+        exchanges: HashMap::new(),
+        dir: None,
+    });
+}
+
+/// Write run files to `dir`, and summarize recent runs from it
+pub fn set_dir(dir: &Path) {
+    STATE.lock().unwrap().dir = Some(dir.to_path_buf());
+}
+
+fn with_exchange(exchange: Exchange, f: impl FnOnce(&mut ExchangeMetrics)) {
+    if ENABLED {
+        f(STATE.lock().unwrap().exchanges.entry(exchange).or_default());
+    }
+}
+
+/// Records a request to `endpoint` that took `latency` and failed with `error`, if it failed
+pub fn record_request(
+    exchange: Exchange,
+    endpoint: &str,
+    latency: Duration,
+    error: Option<&(dyn std::error::Error + 'static)>,
+) {
+    with_exchange(exchange, |metrics| {
+        metrics
+            .endpoints
+            .entry(EndpointClass::of(endpoint))
+            .or_default()
+            .record(latency, error.map(ErrorCategory::of));
+    });
+}
+
+/// Times `request` and records its outcome
+pub async fn instrument<T>(
+    exchange: Exchange,
+    endpoint: &str,
+    request: impl std::future::Future<Output = Result<T, Box<dyn std::error::Error>>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let result = request.await;
+    record_request(
+        exchange,
+        endpoint,
+        started.elapsed(),
+        result.as_ref().err().map(|err| err.as_ref()),
+    );
+    result
+}
+
+pub fn record_retry(exchange: Exchange, endpoint: &str) {
+    with_exchange(exchange, |metrics| {
+        metrics
+            .endpoints
+            .entry(EndpointClass::of(endpoint))
+            .or_default()
+            .retries += 1;
+    });
+}
+
+pub fn record_rate_limit_wait(exchange: Exchange, wait: Duration) {
+    with_exchange(exchange, |metrics| {
+        metrics.rate_limit_waits += 1;
+        metrics.rate_limit_wait_ms += wait.as_millis() as u64;
+    });
+}
+
+/// Metrics recorded by this process so far
+pub fn snapshot() -> RunMetrics {
+    let state = STATE.lock().unwrap();
+    RunMetrics {
+        started: state.started,
+        finished: Utc::now(),
+        exchanges: state.exchanges.clone(),
+    }
+}
+
+fn run_file_name(started: DateTime<Utc>) -> String {
+    format!(
+        "{}-{}.json",
+        started.format("%Y%m%dT%H%M%S"),
+        std::process::id()
+    )
+}
+
+/// Writes this process's metrics to its run file, replacing what an earlier flush wrote, and
+/// removes run files past retention.  Returns the file written, if any
+pub fn flush() -> io::Result<Option<PathBuf>> {
+    let dir = match STATE.lock().unwrap().dir.clone() {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    let run = snapshot();
+    if run.exchanges.is_empty() {
+        return Ok(None);
+    }
+
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(run_file_name(run.started));
+    fs::write(&path, serde_json::to_string(&run)?)?;
+
+    let cutoff = Utc::now() - chrono::Duration::days(RETENTION_DAYS);
+    for (run_path, run) in read_runs(&dir) {
+        if run.finished < cutoff {
+            let _ = fs::remove_file(run_path);
+        }
+    }
+    Ok(Some(path))
+}
+
+fn read_runs(dir: &Path) -> Vec<(PathBuf, RunMetrics)> {
+    fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.extension()
+                .map_or(false, |extension| extension == "json")
+        })
+        .filter_map(|path| {
+            let run = serde_json::from_str(&fs::read_to_string(&path).ok()?).ok()?;
+            Some((path, run))
+        })
+        .collect()
+}
+
+/// Totals of the runs that finished since `since`, this one included
+pub fn recent(since: DateTime<Utc>) -> HashMap<Exchange, ExchangeMetrics> {
+    let current = snapshot();
+    let current_file_name = run_file_name(current.started);
+    let dir = STATE.lock().unwrap().dir.clone();
+
+    let mut exchanges = HashMap::<Exchange, ExchangeMetrics>::new();
+    let runs = dir
+        .map(|dir| read_runs(&dir))
+        .unwrap_or_default()
+        .into_iter()
+        // This run's own file is superseded by the snapshot
+        .filter(|(path, run)| {
+            run.finished >= since
+                && path
+                    .file_name()
+                    .map_or(true, |name| name != current_file_name.as_str())
+        })
+        .map(|(_, run)| run)
+        .chain(std::iter::once(current));
+    for run in runs {
+        for (exchange, metrics) in &run.exchanges {
+            exchanges.entry(*exchange).or_default().merge(metrics);
+        }
+    }
+    exchanges
+}
+
+/// This process's metrics in the Prometheus text exposition format
+pub fn prometheus_text() -> String {
+    let run = snapshot();
+    let mut exchanges = run.exchanges.into_iter().collect::<Vec<_>>();
+    exchanges.sort_by_key(|(exchange, _)| exchange.name());
+
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        let _ = writeln!(text, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(text, "{name}{{{labels}}} {value}");
+        }
+    };
+
+    let endpoint_samples = |sample: &dyn Fn(&str, &RequestStats) -> Vec<(String, String)>| {
+        exchanges
+            .iter()
+            .flat_map(|(exchange, metrics)| {
+                metrics.endpoints.iter().flat_map(move |(class, stats)| {
+                    sample(
+                        &format!(
+                            "exchange=\"{}\",class=\"{}\"",
+                            exchange.name(),
+                            class.name()
+                        ),
+                        stats,
+                    )
+                })
+            })
+            .collect::<Vec<_>>()
+    };
+    metric(
+        "sys_exchange_requests_total",
+        "counter",
+        "Requests sent to the exchange",
+        endpoint_samples(&|labels, stats| vec![(labels.into(), stats.requests.to_string())]),
+    );
+    metric(
+        "sys_exchange_request_errors_total",
+        "counter",
+        "Requests that failed, by category",
+        endpoint_samples(&|labels, stats| {
+            stats
+                .errors
+                .iter()
+                .map(|(category, count)| {
+                    (
+                        format!("{labels},category=\"{}\"", category.name()),
+                        count.to_string(),
+                    )
+                })
+                .collect()
+        }),
+    );
+    metric(
+        "sys_exchange_request_retries_total",
+        "counter",
+        "Requests sent again after a retryable failure",
+        endpoint_samples(&|labels, stats| vec![(labels.into(), stats.retries.to_string())]),
+    );
+    metric(
+        "sys_exchange_request_duration_seconds",
+        "histogram",
+        "Time from sending a request to receiving the whole response",
+        endpoint_samples(&|labels, stats| {
+            let mut count = 0;
+            let mut samples = vec![];
+            for (i, bound) in LATENCY_BUCKETS_MS
+                .iter()
+                .map(|bound| (*bound as f64 / 1000.).to_string())
+                .chain(std::iter::once("+Inf".to_string()))
+                .enumerate()
+            {
+                count += stats.latency_buckets.get(i).copied().unwrap_or_default();
+                samples.push((format!("{labels},le=\"{bound}\""), count.to_string()));
+            }
+            samples
+        }),
+    );
+    metric(
+        "sys_exchange_rate_limit_waits_total",
+        "counter",
+        "Times a request waited for rate budget",
+        exchanges
+            .iter()
+            .map(|(exchange, metrics)| {
+                (
+                    format!("exchange=\"{}\"", exchange.name()),
+                    metrics.rate_limit_waits.to_string(),
+                )
+            })
+            .collect(),
+    );
+    metric(
+        "sys_exchange_rate_limit_wait_seconds_total",
+        "counter",
+        "Time spent waiting for rate budget",
+        exchanges
+            .iter()
+            .map(|(exchange, metrics)| {
+                (
+                    format!("exchange=\"{}\"", exchange.name()),
+                    (metrics.rate_limit_wait_ms as f64 / 1000.).to_string(),
+                )
+            })
+            .collect(),
+    );
+    text
+}
+
+/// Pushes this process's metrics to the Prometheus pushgateway at `url`, replacing those it
+/// pushed before
+pub async fn push_to_gateway(url: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let url = format!(
+        "{}/metrics/job/sys/instance/{}",
+        url.trim_end_matches('/'),
+        std::process::id()
+    );
+    let response = reqwest::Client::new()
+        .put(&url)
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(prometheus_text())
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("Pushgateway {url}: HTTP {}", response.status()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Settings that change how holdings are valued and how orders are placed.  They're stored in the
+//! database, so they persist from one run to the next and `sys settings show` lists those in
+//! effect.  `install()` hands them to the library once the database is opened, and a setting that
+//! isn't set takes its default
+use {
+    crate::exchange,
+    serde::{Deserialize, Serialize},
+    std::{collections::BTreeMap, sync::RwLock},
+    strum::{EnumString, IntoStaticStr},
+};
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+    EnumString,
+    IntoStaticStr,
+)]
+#[strum(serialize_all = "kebab-case")]
+pub enum Setting {
+    UsdCoins,
+    BinanceBnbFeesInUsd,
+    MaxSpreadPercent,
+    QuotePreference,
+    FxRates,
+    ReportingCurrency,
+    PriceSources,
+    PriceMismatchPercent,
+    ReportingTimezone,
+    DepositAddressCacheHours,
+    MetricsPushgateway,
+    ExchangeProxy,
+}
+
+pub const POSSIBLE_SETTING_VALUES: &[&str] = &[
+    "usd-coins",
+    "binance-bnb-fees-in-usd",
+    "max-spread-percent",
+    "quote-preference",
+    "fx-rates",
+    "reporting-currency",
+    "price-sources",
+    "price-mismatch-percent",
+    "reporting-timezone",
+    "deposit-address-cache-hours",
+    "metrics-pushgateway",
+    "exchange-proxy",
+];
+
+impl Setting {
+    pub fn all() -> &'static [Setting] {
+        &[
+            Setting::UsdCoins,
+            Setting::BinanceBnbFeesInUsd,
+            Setting::MaxSpreadPercent,
+            Setting::QuotePreference,
+            Setting::FxRates,
+            Setting::ReportingCurrency,
+            Setting::PriceSources,
+            Setting::PriceMismatchPercent,
+            Setting::ReportingTimezone,
+            Setting::DepositAddressCacheHours,
+            Setting::MetricsPushgateway,
+            Setting::ExchangeProxy,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            Setting::UsdCoins => {
+                "Comma-separated dollar-denominated coins, in order of preference when selecting a \
+                 quote currency and totaling balances"
+            }
+            Setting::BinanceBnbFeesInUsd => {
+                "true to record Binance fees paid in BNB in USD, at the BNB price when the trade \
+                 was made, rather than in BNB"
+            }
+            Setting::MaxSpreadPercent => {
+                "Widest bid/ask spread, as a percentage of the mid price, that orders are placed \
+                 into"
+            }
+            Setting::QuotePreference => {
+                "Comma-separated quote currencies in order of preference when selecting a \
+                 market for a token, such as USDC,USD or EUR. When not set, each exchange's \
+                 preferred SOL market is used, followed by the usd-coins setting"
+            }
+            Setting::FxRates => {
+                "Comma-separated USD exchange rates such as EUR=1.08,GBP=1.27, which take \
+                 precedence over market rates when valuing a currency"
+            }
+            Setting::ReportingCurrency => {
+                "Currency that portfolio values are presented in, such as EUR, when not given \
+                 with --currency"
+            }
+            Setting::PriceSources => {
+                "Semicolon-separated ASSET=SOURCE,... lists of the price sources to value each \
+                 asset from, in order of priority, * being the list for assets without one of \
+                 their own. A source is coingecko, tulip, an exchange such as kraken or one of \
+                 its markets such as binance:SOL/USDC, optionally followed by @SECONDS to pass \
+                 over prices older than that"
+            }
+            Setting::PriceMismatchPercent => {
+                "Difference between two price sources, as a percentage of the price used, \
+                 beyond which valuation warns that one of them may be wrong"
+            }
+            Setting::ReportingTimezone => {
+                "Time zone whose days reports and date ranges are divided into: UTC, local or \
+                 an offset from UTC such as -05:00"
+            }
+            Setting::DepositAddressCacheHours => {
+                "Hours a deposit address fetched from an exchange is used before it's fetched \
+                 again. 0 fetches it every time"
+            }
+            Setting::MetricsPushgateway => {
+                "URL of a Prometheus pushgateway that each run's exchange request metrics are \
+                 pushed to, such as http://localhost:9091"
+            }
+            Setting::ExchangeProxy => {
+                "Comma-separated EXCHANGE=PROXY pairs such as kraken=socks5://localhost:1080 \
+                 that route an exchange's requests through an http:// or socks5:// proxy, or \
+                 connect directly with none. Exchanges not listed follow the HTTPS_PROXY and \
+                 ALL_PROXY environment variables"
+            }
+        }
+    }
+
+    /// `None` for a setting that does nothing unless it's set
+    pub fn default_value(&self) -> Option<String> {
+        match self {
+            Setting::UsdCoins => Some(exchange::DEFAULT_USD_COINS.join(",")),
+            Setting::BinanceBnbFeesInUsd => Some("false".into()),
+            Setting::MaxSpreadPercent => Some(exchange::DEFAULT_MAX_SPREAD_PERCENT.to_string()),
+            Setting::QuotePreference => None,
+            Setting::FxRates => None,
+            Setting::ReportingCurrency => Some("USD".into()),
+            Setting::PriceSources => None,
+            Setting::PriceMismatchPercent => {
+                Some(exchange::DEFAULT_PRICE_MISMATCH_PERCENT.to_string())
+            }
+            Setting::ReportingTimezone => Some("UTC".into()),
+            Setting::DepositAddressCacheHours => {
+                Some(exchange::DEFAULT_DEPOSIT_ADDRESS_CACHE_HOURS.to_string())
+            }
+            Setting::MetricsPushgateway => None,
+            Setting::ExchangeProxy => None,
+        }
+    }
+
+    /// Fails if `value` isn't a valid value for the setting
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        match self {
+            Setting::UsdCoins => {
+                if exchange::parse_coin_list(value).is_empty() {
+                    return Err("expected a comma-separated list of coins".into());
+                }
+            }
+            Setting::BinanceBnbFeesInUsd => {
+                value
+                    .parse::<bool>()
+                    .map_err(|_| "expected true or false".to_string())?;
+            }
+            Setting::MaxSpreadPercent => {
+                parse_percent(value)?;
+            }
+            Setting::QuotePreference => {
+                if exchange::parse_coin_list(value).is_empty() {
+                    return Err("expected a comma-separated list of currencies".into());
+                }
+            }
+            Setting::FxRates => {
+                if exchange::parse_fx_rates(value)?.is_empty() {
+                    return Err("expected comma-separated CURRENCY=RATE pairs".into());
+                }
+            }
+            Setting::ReportingCurrency => {
+                if value.trim().is_empty() || value.contains(',') {
+                    return Err("expected a currency such as EUR".into());
+                }
+            }
+            Setting::PriceSources => {
+                exchange::parse_price_sources(value)?;
+            }
+            Setting::PriceMismatchPercent => {
+                parse_percent(value)?;
+            }
+            Setting::ReportingTimezone => {
+                value.parse::<exchange::ReportingTimeZone>()?;
+            }
+            Setting::DepositAddressCacheHours => {
+                value
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|_| "expected a number of hours".to_string())?;
+            }
+            Setting::MetricsPushgateway => {
+                reqwest::Url::parse(value).map_err(|err| format!("expected a URL: {err}"))?;
+            }
+            Setting::ExchangeProxy => {
+                if exchange::parse_exchange_proxies(value)?.is_empty() {
+                    return Err("expected comma-separated EXCHANGE=PROXY pairs".into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A positive percentage such as `0.5`
+pub fn parse_percent(value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| percent.is_finite() && *percent > 0.)
+        .ok_or_else(|| format!("expected a positive percentage, not {value}"))
+}
+
+lazy_static::lazy_static! {
+    static ref SETTINGS: RwLock<BTreeMap<Setting, String>> = RwLock::default();
+}
+
+/// Makes `settings` the settings in effect.  Called once the database is opened, before anything
+/// reads a setting
+pub fn install(settings: BTreeMap<Setting, String>) {
+    *SETTINGS.write().unwrap() = settings;
+}
+
+/// The value of `setting`, `None` when it isn't set
+pub fn get(setting: Setting) -> Option<String> {
+    SETTINGS.read().unwrap().get(&setting).cloned()
+}
+
+/// Whether a true or false `setting` is true, false when it isn't set
+pub fn enabled(setting: Setting) -> bool {
+    get(setting).map_or(false, |value| value == "true")
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::str::FromStr};
+
+    #[test]
+    fn every_setting_is_listed_and_has_a_valid_default() {
+        assert_eq!(Setting::all().len(), POSSIBLE_SETTING_VALUES.len());
+        for (setting, name) in Setting::all().iter().zip(POSSIBLE_SETTING_VALUES) {
+            assert_eq!(setting.name(), *name);
+            assert_eq!(Setting::from_str(name), Ok(*setting));
+            if let Some(default_value) = setting.default_value() {
+                assert_eq!(setting.validate(&default_value), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn usd_coins_must_list_a_coin() {
+        assert_eq!(Setting::UsdCoins.validate("usdc, usd"), Ok(()));
+        assert!(Setting::UsdCoins.validate(" , ").is_err());
+    }
+
+    #[test]
+    fn percentages_must_be_positive() {
+        assert_eq!(parse_percent(" 0.25 "), Ok(0.25));
+        for value in ["0", "-1", "NaN", "inf", "half"] {
+            assert!(parse_percent(value).is_err(), "{value}");
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Session tokens for exchange streaming APIs, such as Kraken WebSocket tokens and Binance listen
+//! keys.  A token is kept with its expiry per exchange and API key, so that short-lived
+//! invocations reuse a live token instead of requesting a new one each run.  A live token grants
+//! access to the account's order flow, so once persisted it is kept next to the credentials and
+//! readable only by its owner
+use {
+    crate::exchange::Exchange,
+    chrono::prelude::*,
+    serde::{Deserialize, Serialize},
+    sha1::{Digest, Sha1},
+    std::{
+        collections::HashMap,
+        fs,
+        future::Future,
+        io::{self, Write},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+    },
+};
+
+// Tokens this close to expiry are refreshed before use, so they can't lapse mid-subscription
+const REFRESH_MARGIN_SECS: i64 = 5 * 60;
+
+lazy_static::lazy_static! {
+    static ref DEFAULT_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// Persist session tokens in `dir`, normally the database directory holding the credentials,
+/// for clients not given a directory of their own
+pub fn set_default_dir(dir: &Path) {
+    *DEFAULT_DIR.lock().unwrap() = Some(dir.to_path_buf());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamSession {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl StreamSession {
+    pub fn is_live(&self, now: DateTime<Utc>) -> bool {
+        now < self.expires_at
+    }
+
+    pub fn needs_refresh(&self, now: DateTime<Utc>) -> bool {
+        now + chrono::Duration::seconds(REFRESH_MARGIN_SECS) >= self.expires_at
+    }
+}
+
+pub struct StreamSessionStore {
+    exchange: Exchange,
+    session: Mutex<Option<StreamSession>>,
+    // When set the session is also kept in this file, so that it outlives the process
+    path: Option<PathBuf>,
+}
+
+impl StreamSessionStore {
+    pub fn new(exchange: Exchange, path: Option<PathBuf>) -> Self {
+        Self {
+            exchange,
+            session: Mutex::new(None),
+            path,
+        }
+    }
+
+    /// The store for `exchange` and `api_key`, shared by every client in this process that uses
+    /// the same key.  Persisted in `dir`, or the directory given to `set_default_dir`
+    pub fn shared(exchange: Exchange, api_key: &str, dir: Option<&Path>) -> Arc<Self> {
+        type StreamSessionKey = (Exchange, String, Option<PathBuf>);
+        lazy_static::lazy_static! {
+            static ref STREAM_SESSIONS: Mutex<HashMap<StreamSessionKey, Arc<StreamSessionStore>>> =
+                Mutex::new(HashMap::new());
+        }
+
+        // The key itself is never written to disk
+        let key_hash = Sha1::digest(api_key.as_bytes())
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let dir = dir
+            .map(Path::to_path_buf)
+            .or_else(|| DEFAULT_DIR.lock().unwrap().clone());
+        let path = dir.map(|dir| {
+            dir.join(format!(
+                "{}-{key_hash}.stream-session.json",
+                exchange.name()
+            ))
+        });
+
+        STREAM_SESSIONS
+            .lock()
+            .unwrap()
+            .entry((exchange, key_hash, path.clone()))
+            .or_insert_with(|| Arc::new(Self::new(exchange, path)))
+            .clone()
+    }
+
+    fn load(&self) -> Option<StreamSession> {
+        let mut session = self.session.lock().unwrap();
+        if session.is_none() {
+            *session = self
+                .path
+                .as_ref()
+                .and_then(|path| fs::read_to_string(path).ok())
+                .and_then(|contents| serde_json::from_str(&contents).ok());
+        }
+        session.clone()
+    }
+
+    fn save(&self, session: Option<StreamSession>) -> io::Result<()> {
+        *self.session.lock().unwrap() = session.clone();
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        match session {
+            Some(session) => {
+                let mut options = fs::OpenOptions::new();
+                options.create(true).write(true).truncate(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                options
+                    .open(path)?
+                    .write_all(serde_json::to_string(&session)?.as_bytes())
+            }
+            None => match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// A live session token.  The stored token is used until it nears expiry, then `fetch` is
+    /// given the expiring session, if it's still live, to extend or replace
+    pub async fn token<F, Fut>(&self, fetch: F) -> Result<String, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(Option<StreamSession>) -> Fut,
+        Fut: Future<Output = Result<StreamSession, Box<dyn std::error::Error>>>,
+    {
+        let now = Utc::now();
+        let session = self.load();
+        if let Some(session) = &session {
+            if !session.needs_refresh(now) {
+                return Ok(session.token.clone());
+            }
+        }
+
+        let session = fetch(session.filter(|session| session.is_live(now))).await?;
+        let token = session.token.clone();
+        if let Err(err) = self.save(Some(session)) {
+            log::warn!("{:?} stream session not persisted: {err}", self.exchange);
+        }
+        Ok(token)
+    }
+
+    /// Discards the stored token, after the exchange rejected it, so the next `token` requests a
+    /// new one
+    pub fn invalidate(&self) {
+        if let Err(err) = self.save(None) {
+            log::warn!("{:?} stream session not removed: {err}", self.exchange);
+        }
+    }
+}
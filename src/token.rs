@@ -227,6 +227,13 @@ impl MaybeToken {
         !self.is_token()
     }
 
+    pub fn decimals(&self) -> u8 {
+        match self.0 {
+            None => 9,
+            Some(token) => token.decimals(),
+        }
+    }
+
     pub fn ui_amount(&self, amount: u64) -> f64 {
         match self.0 {
             None => lamports_to_sol(amount),
@@ -0,0 +1,120 @@
+use {
+    crate::exchange::Exchange,
+    hmac::{Hmac, Mac},
+    sha1::Sha1,
+    std::{
+        io::Write,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+/// Supplies a two-factor code at the moment a request is sent, so that time-based codes are still
+/// valid when they reach the exchange
+pub trait TwoFactorCodeProvider: Send + Sync {
+    fn code(&self) -> Result<String, Box<dyn std::error::Error>>;
+
+    /// Whether a new code can be provided after the exchange rejects one
+    fn can_refresh(&self) -> bool {
+        true
+    }
+}
+
+/// A code fixed up front, such as one passed on the command line
+pub struct StaticCode(pub String);
+
+impl TwoFactorCodeProvider for StaticCode {
+    fn code(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self.0.clone())
+    }
+
+    fn can_refresh(&self) -> bool {
+        false
+    }
+}
+
+/// Prompts for a code on the terminal
+pub struct PromptCode {
+    pub exchange: Exchange,
+}
+
+impl TwoFactorCodeProvider for PromptCode {
+    fn code(&self) -> Result<String, Box<dyn std::error::Error>> {
+        eprint!("{:?} 2FA code: ", self.exchange);
+        std::io::stderr().flush()?;
+
+        let mut code = String::new();
+        std::io::stdin().read_line(&mut code)?;
+        let code = code.trim();
+        if code.is_empty() {
+            return Err("No 2FA code entered".into());
+        }
+        Ok(code.to_string())
+    }
+}
+
+/// Computes RFC 6238 codes (HMAC-SHA1, 30 second steps, 6 digits) from the base32 secret an
+/// exchange shows when setting up an authenticator app
+pub struct TotpCode {
+    secret: Vec<u8>,
+}
+
+const TOTP_STEP_SECONDS: u64 = 30;
+
+impl TotpCode {
+    pub fn new(base32_secret: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let secret = decode_base32(base32_secret)?;
+        if secret.is_empty() {
+            return Err("TOTP secret is empty".into());
+        }
+        Ok(Self { secret })
+    }
+
+    fn code_at(&self, unix_time: u64) -> String {
+        let mut mac =
+            Hmac::<Sha1>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(&(unix_time / TOTP_STEP_SECONDS).to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        // Dynamic truncation, RFC 4226 section 5.3
+        let offset = (hash[hash.len() - 1] & 0xf) as usize;
+        let binary = u32::from_be_bytes([
+            hash[offset],
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]) & 0x7fff_ffff;
+        format!("{:06}", binary % 1_000_000)
+    }
+}
+
+impl TwoFactorCodeProvider for TotpCode {
+    fn code(&self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(self.code_at(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()))
+    }
+}
+
+// RFC 4648 base32, ignoring case, whitespace and padding
+fn decode_base32(encoded: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut decoded = vec![];
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+    {
+        let value = match c {
+            'A'..='Z' => c as u32 - 'A' as u32,
+            '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return Err(format!("Invalid base32 character in TOTP secret: {c}").into()),
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Ok(decoded)
+}